@@ -0,0 +1,163 @@
+//! A client for a platform asuka has no client for, written only against
+//! the crate root's public API, as a bridge in another crate would be.
+//!
+//! The "platform" is stdin: each line is a direct message, and replies are
+//! printed. The models are fakes so the example runs offline; a real bridge
+//! would pass provider models such as rig's OpenAI ones.
+//!
+//! ```sh
+//! echo "what is vrf?" | cargo run -p asuka-core --example external_client
+//! ```
+
+use std::{
+    io::BufRead,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use asuka_core::{
+    Agent, Attention, AttentionConfig, ChannelType, Character, Client, Handled, IncomingMessage,
+    KnowledgeBase, Pipeline, ReplySink, Source,
+};
+use async_trait::async_trait;
+use rig::{
+    completion::{
+        CompletionError, CompletionModel, CompletionRequest, CompletionResponse, ModelChoice,
+    },
+    embeddings::{Embedding, EmbeddingError, EmbeddingModel},
+};
+
+/// Answers every prompt by echoing it.
+#[derive(Clone)]
+struct EchoModel;
+
+impl CompletionModel for EchoModel {
+    type Response = ();
+
+    async fn completion(
+        &self,
+        request: CompletionRequest,
+    ) -> Result<CompletionResponse<()>, CompletionError> {
+        Ok(CompletionResponse {
+            choice: ModelChoice::Message(format!("You asked: {}", request.prompt)),
+            raw_response: (),
+        })
+    }
+}
+
+/// Embeds every text as the same vector.
+#[derive(Clone)]
+struct FlatEmbeddingModel;
+
+impl EmbeddingModel for FlatEmbeddingModel {
+    const MAX_DOCUMENTS: usize = 64;
+
+    fn ndims(&self) -> usize {
+        4
+    }
+
+    async fn embed_texts(
+        &self,
+        texts: impl IntoIterator<Item = String> + Send,
+    ) -> Result<Vec<Embedding>, EmbeddingError> {
+        Ok(texts
+            .into_iter()
+            .map(|text| Embedding {
+                vec: vec![0.5; 4],
+                document: text,
+            })
+            .collect())
+    }
+}
+
+/// The terminal, as a platform with numbered messages.
+#[derive(Clone, Default)]
+struct Terminal {
+    last_id: Arc<AtomicU64>,
+}
+
+#[async_trait]
+impl ReplySink for Terminal {
+    type Handle = u64;
+    type Error = std::io::Error;
+
+    async fn send(&self, text: &str) -> Result<u64, std::io::Error> {
+        let id = self.last_id.fetch_add(1, Ordering::SeqCst) + 1;
+        println!("[{id}] {text}");
+        Ok(id)
+    }
+
+    async fn edit(&self, handle: &u64, text: &str) -> Result<(), std::io::Error> {
+        println!("[{handle}, edited] {text}");
+        Ok(())
+    }
+}
+
+impl Client for Terminal {
+    type Sink = Terminal;
+
+    fn source(&self) -> Source {
+        Source::Other("terminal".to_string())
+    }
+
+    fn account_id(&self) -> String {
+        "bot".to_string()
+    }
+
+    fn max_message_length(&self) -> usize {
+        280
+    }
+
+    fn sink(&self, _channel_id: &str) -> Terminal {
+        self.clone()
+    }
+}
+
+fn load_sqlite_vec() {
+    unsafe {
+        rusqlite::ffi::sqlite3_auto_extension(Some(std::mem::transmute::<
+            *const (),
+            unsafe extern "C" fn(
+                *mut rusqlite::ffi::sqlite3,
+                *mut *mut std::os::raw::c_char,
+                *const rusqlite::ffi::sqlite3_api_routines,
+            ) -> i32,
+        >(
+            sqlite_vec::sqlite3_vec_init as *const ()
+        )));
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    load_sqlite_vec();
+    let conn = tokio_rusqlite::Connection::open_in_memory().await?;
+    let knowledge = KnowledgeBase::new(conn, FlatEmbeddingModel).await?;
+
+    let character = Character::new("shinobi", "You help with Cartridge.");
+    let attention = Attention::new(
+        AttentionConfig {
+            bot_names: character.names(),
+            ..Default::default()
+        },
+        EchoModel,
+    );
+    let pipeline = Pipeline::new(Agent::new(character, EchoModel, knowledge), attention);
+
+    let terminal = Terminal::default();
+    for (n, line) in std::io::stdin().lock().lines().enumerate() {
+        let message = IncomingMessage::new(format!("line-{n}"), "stdin", "you", line?)
+            .with_channel_type(ChannelType::DirectMessage);
+        match pipeline.handle(&terminal, message).await? {
+            Handled::Replied { chunks, .. } => eprintln!("(sent {} messages)", chunks.len()),
+            Handled::Ignored(assessment) => eprintln!("(ignored: {:?})", assessment.decision),
+            Handled::Reacted(emoji) => eprintln!("(reacted {emoji})"),
+            Handled::Limited => eprintln!("(over the rate limit)"),
+            Handled::Deferred { queued, .. } => eprintln!("(deferred, queued: {queued})"),
+            Handled::Batched => {}
+        }
+    }
+    Ok(())
+}
@@ -1,36 +1,2080 @@
-use rig::{agent::AgentBuilder, completion::CompletionModel, embeddings::EmbeddingModel};
-use tracing::info;
+use std::{ops::Range, sync::Arc};
 
-use crate::{character::Character, knowledge::KnowledgeBase};
+use arc_swap::ArcSwap;
+use rig::{
+    agent::AgentBuilder,
+    completion::{CompletionModel, Prompt},
+    embeddings::EmbeddingModel,
+    tool::Tool,
+};
+use rig_sqlite::SqliteError;
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    attention::ResponseMode,
+    capabilities::{self, Capabilities, CapabilitiesConfig},
+    character::Character,
+    clients::describe_capabilities::DescribeCapabilities,
+    clock::Clock,
+    confidence::ConfidenceConfig,
+    conversation::ConversationStore,
+    corrections, escape,
+    experiments::{self, Assignment, ExperimentConfig},
+    familiarity::FamiliarityCache,
+    generation::GenerationParams,
+    history::{estimate_tokens, HistoryConfig},
+    hooks::{MessageContext, ResponseDraft, ResponseHook, ResponseHooks},
+    injection::{self, InjectionClassifier, InjectionConfig},
+    knowledge::{
+        fit_pins, ChannelType, DiversityConfig, Familiarity, GapConfig, KnowledgeBase, Message,
+        RetrievedChunk, Source, SourceRef, TopicBoost, Truncation,
+    },
+    language::{self, LocalizationConfig},
+    locale::{self, FormatPreferences, LocaleConfig, LocaleSettings},
+    memory::MemoryConfig,
+    onboarding::OnboardingStep,
+    prompt::{AssembledPrompt, IndexRetriever, Retriever, SegmentCache, StaticSegments},
+    quotes,
+    rewrite::{QueryRewriter, RetrievalQuery, RewriteConfig},
+    say,
+    startup::Readiness,
+    structured::{self, StructuredError},
+    templates,
+    tools::{ToolConfig, ToolGuard},
+    truncation::{self, TruncationConfig},
+};
+
+/// Combined size cap for pinned context, in characters.
+const DEFAULT_PINNED_CONTEXT_LIMIT: usize = 2000;
+
+/// Documents retrieved for each reply.
+const RETRIEVED_DOCUMENTS: usize = 2;
+
+/// Earlier exchanges compared with a message for a mention in its prompt.
+const RECALL_CANDIDATES: usize = 5;
+
+/// Instruction added to every reply.
+const LENGTH_INSTRUCTION: &str =
+    "Please keep your responses concise and under 2000 characters when possible.";
+
+/// Instruction for [ResponseMode::BriefAck] replies.
+const BRIEF_ACK_INSTRUCTION: &str =
+    "Reply in one short sentence, as a quick acknowledgement or greeting.";
+
+/// Most tokens of a [ResponseMode::BriefAck] reply.
+pub const BRIEF_ACK_MAX_TOKENS: u64 = 60;
 
 #[derive(Clone)]
 pub struct Agent<M: CompletionModel, E: EmbeddingModel + 'static> {
     pub character: Character,
     completion_model: M,
     knowledge: KnowledgeBase<E>,
+    pinned_context_limit: usize,
+    topic_boost: TopicBoost,
+    diversity: Option<DiversityConfig>,
+    gap_detection: Option<GapConfig>,
+    conversations: ConversationStore<E>,
+    response_hooks: ResponseHooks,
+    tool_config: ToolConfig,
+    confidence: Option<ConfidenceConfig>,
+    clock: Arc<dyn Clock>,
+    /// Replaces retrieval from the knowledge base, e.g. in tests.
+    retriever: Option<Arc<dyn Retriever>>,
+    memory: Option<MemoryConfig>,
+    history: HistoryConfig,
+    injection: Option<InjectionConfig>,
+    injection_classifier: Option<Arc<dyn InjectionClassifier>>,
+    experiments: Arc<Vec<ExperimentConfig>>,
+    /// Variant this agent answers with, see [Agent::for_variant].
+    variant: Option<Assignment>,
+    localization: Option<LocalizationConfig>,
+    locale: LocaleConfig,
+    readiness: Readiness,
+    query_rewrite: Option<RewriteConfig>,
+    query_rewriter: Option<Arc<dyn QueryRewriter>>,
+    /// Source the agent answers on, for [Character::generation].
+    source: Option<Source>,
+    familiarity_cache: FamiliarityCache,
+    /// How well the bot knows the user answered, see
+    /// [Agent::with_familiarity].
+    familiarity: Option<Familiarity>,
+    prompt_cache: SegmentCache,
+    truncation: Option<TruncationConfig>,
+    /// Interaction answered, see [Agent::with_interaction].
+    interaction_id: Option<i64>,
+    /// Shared by clones, see [Agent::update_capabilities].
+    capabilities: Option<(CapabilitiesConfig, Arc<ArcSwap<Capabilities>>)>,
 }
 
 impl<M: CompletionModel, E: EmbeddingModel> Agent<M, E> {
     pub fn new(character: Character, completion_model: M, knowledge: KnowledgeBase<E>) -> Self {
         info!(name = character.name, "Creating new agent");
 
+        let clock = knowledge.clock().clone();
         Self {
             character,
             completion_model,
+            conversations: ConversationStore::new(knowledge.clone()),
             knowledge,
+            pinned_context_limit: DEFAULT_PINNED_CONTEXT_LIMIT,
+            topic_boost: TopicBoost::default(),
+            diversity: None,
+            gap_detection: None,
+            response_hooks: ResponseHooks::default(),
+            tool_config: ToolConfig::default(),
+            confidence: None,
+            clock,
+            retriever: None,
+            memory: None,
+            history: HistoryConfig::default(),
+            injection: None,
+            injection_classifier: None,
+            experiments: Arc::default(),
+            variant: None,
+            localization: None,
+            locale: LocaleConfig::default(),
+            readiness: Readiness::default(),
+            query_rewrite: None,
+            query_rewriter: None,
+            source: None,
+            familiarity_cache: FamiliarityCache::default(),
+            familiarity: None,
+            prompt_cache: SegmentCache::default(),
+            truncation: None,
+            interaction_id: None,
+            capabilities: None,
+        }
+    }
+
+    pub fn with_pinned_context_limit(mut self, limit: usize) -> Self {
+        self.pinned_context_limit = limit;
+        self
+    }
+
+    pub fn with_topic_boost(mut self, boost: TopicBoost) -> Self {
+        self.topic_boost = boost;
+        self
+    }
+
+    /// Re-selects retrieved documents so parts of one document don't fill
+    /// the context, see [crate::knowledge::DiverseIndex].
+    pub fn with_diversity(mut self, config: DiversityConfig) -> Self {
+        self.diversity = Some(config);
+        self
+    }
+
+    /// Records questions the docs have no good answer for, see
+    /// [KnowledgeBase::detect_gap].
+    pub fn with_gap_detection(mut self, config: GapConfig) -> Self {
+        self.gap_detection = Some(config);
+        self
+    }
+
+    pub fn with_conversation_store(mut self, store: ConversationStore<E>) -> Self {
+        self.conversations = store;
+        self
+    }
+
+    /// Adds a hook run on every reply after the ones registered before it,
+    /// see [crate::hooks]. Streamed replies and reactions skip the hooks.
+    pub fn with_response_hook(mut self, hook: impl ResponseHook + 'static) -> Self {
+        self.response_hooks.push(Arc::new(hook));
+        self
+    }
+
+    pub fn with_tool_config(mut self, config: ToolConfig) -> Self {
+        self.tool_config = config;
+        self
+    }
+
+    /// Hedges or declines replies the docs do not support, see
+    /// [crate::confidence].
+    pub fn with_confidence(mut self, config: ConfidenceConfig) -> Self {
+        self.confidence = Some(config);
+        self
+    }
+
+    /// Clock for the current time in prompts and of everything built on the
+    /// agent, the knowledge base included. Defaults to the knowledge base's.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.knowledge = self.knowledge.with_clock(clock.clone());
+        self.clock = clock;
+        self
+    }
+
+    /// Retrieves the documents of [Agent::render_prompt] with `retriever`
+    /// instead of the knowledge base.
+    pub fn with_retriever(mut self, retriever: Arc<dyn Retriever>) -> Self {
+        self.retriever = Some(retriever);
+        self
+    }
+
+    /// Adds the channel's tiered memory to prompts, each tier within its
+    /// budget, see [crate::memory].
+    pub fn with_memory(mut self, config: MemoryConfig) -> Self {
+        self.memory = Some(config);
+        self
+    }
+
+    /// How channel history is written into prompts.
+    pub fn with_history(mut self, config: HistoryConfig) -> Self {
+        self.history = config;
+        self
+    }
+
+    /// Guards prompts and replies against injections, see [crate::injection].
+    pub fn with_injection(mut self, config: InjectionConfig) -> Self {
+        self.injection = Some(config);
+        // Segments rendered without it don't apply to this agent
+        self.prompt_cache = SegmentCache::default();
+        self
+    }
+
+    /// Classifier asked about documents when
+    /// [InjectionConfig::classifier] is on.
+    pub fn with_injection_classifier(mut self, classifier: Arc<dyn InjectionClassifier>) -> Self {
+        self.injection_classifier = Some(classifier);
+        self
+    }
+
+    /// Runs A/B experiments on replies, see [crate::experiments].
+    pub fn with_experiments(mut self, experiments: Vec<ExperimentConfig>) -> Self {
+        self.experiments = Arc::new(experiments);
+        self
+    }
+
+    /// The variant `user_id` gets in `channel_id` of `guild_id`, if an
+    /// experiment runs there.
+    pub fn assign(
+        &self,
+        guild_id: Option<&str>,
+        channel_id: &str,
+        user_id: &str,
+    ) -> Option<Assignment> {
+        experiments::assign(&self.experiments, guild_id, channel_id, user_id)
+    }
+
+    /// This agent answering with `assignment`'s variant: its preamble in
+    /// prompts, and its model and temperature in completion requests.
+    pub fn for_variant(&self, assignment: &Assignment) -> Self {
+        let mut agent = self.clone();
+        if let Some(preamble) = &assignment.variant.preamble {
+            agent.character.preamble = preamble.clone();
+        }
+        agent.variant = Some(assignment.clone());
+        agent
+    }
+
+    pub fn variant(&self) -> Option<&Assignment> {
+        self.variant.as_ref()
+    }
+
+    /// Answers with the character's sampling settings for `source`, see
+    /// [Character::source_generation].
+    pub fn with_source(mut self, source: Source) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// This agent answering a user it knows as well as `familiarity` says,
+    /// which the prompt mentions, see [crate::familiarity].
+    pub fn with_familiarity(mut self, familiarity: Option<Familiarity>) -> Self {
+        self.familiarity = familiarity;
+        self
+    }
+
+    pub(crate) fn familiarity_cache(&self) -> &FamiliarityCache {
+        &self.familiarity_cache
+    }
+
+    /// The static parts of this agent's prompts, shared by its clones.
+    /// Invalidate it when the character or config change.
+    pub fn prompt_cache(&self) -> &SegmentCache {
+        &self.prompt_cache
+    }
+
+    /// Cuts retrieved documents that don't fit the prompt down to their
+    /// most relevant part, see [crate::truncation].
+    pub fn with_truncation(mut self, config: TruncationConfig) -> Self {
+        self.truncation = Some(config);
+        self
+    }
+
+    /// This agent answering the interaction `interaction_id`, which the
+    /// truncated documents of its prompt are recorded with.
+    pub fn with_interaction(mut self, interaction_id: Option<i64>) -> Self {
+        self.interaction_id = interaction_id;
+        self
+    }
+
+    /// Tells the model what it can actually do when a message asks, see
+    /// [crate::capabilities].
+    pub fn with_capabilities(
+        mut self,
+        config: CapabilitiesConfig,
+        capabilities: Capabilities,
+    ) -> Self {
+        self.capabilities = Some((config, Arc::new(ArcSwap::from_pointee(capabilities))));
+        // Segments rendered without them don't apply to this agent
+        self.prompt_cache = SegmentCache::default();
+        self
+    }
+
+    /// Changes the capabilities of this agent and its clones, e.g. once a
+    /// tool is registered, and renders them again for the next prompt.
+    pub fn update_capabilities(&self, update: impl FnOnce(Capabilities) -> Capabilities) {
+        if let Some((_, capabilities)) = &self.capabilities {
+            capabilities.store(Arc::new(update(Capabilities::clone(&capabilities.load()))));
+            self.prompt_cache.invalidate();
+        }
+    }
+
+    /// Recounts the documents of the knowledge sources in the capabilities.
+    pub async fn refresh_capabilities(&self) -> Result<(), SqliteError> {
+        if self.capabilities.is_some() {
+            let sources = Capabilities::default()
+                .with_sources(&self.knowledge)
+                .await?
+                .sources;
+            self.update_capabilities(|capabilities| Capabilities {
+                sources,
+                ..capabilities
+            });
+        }
+        Ok(())
+    }
+
+    /// The tool describing the capabilities in detail, when there are any.
+    pub fn describe_capabilities(&self) -> Option<DescribeCapabilities> {
+        self.capabilities
+            .as_ref()
+            .map(|(_, capabilities)| DescribeCapabilities::new(capabilities.clone()))
+    }
+
+    /// Renders templates in the language of the user, see [crate::language].
+    pub fn with_localization(mut self, config: LocalizationConfig) -> Self {
+        self.localization = Some(config);
+        self
+    }
+
+    /// Writes times in the zone and format of each channel, see
+    /// [crate::locale].
+    pub fn with_locale(mut self, config: LocaleConfig) -> Self {
+        self.locale = config;
+        self
+    }
+
+    pub fn locale(&self) -> &LocaleConfig {
+        &self.locale
+    }
+
+    /// Answers knowledge questions with a notice until `readiness` is ready,
+    /// see [crate::startup].
+    pub fn with_readiness(mut self, readiness: Readiness) -> Self {
+        self.readiness = readiness;
+        self
+    }
+
+    pub fn readiness(&self) -> &Readiness {
+        &self.readiness
+    }
+
+    /// Rewrites messages lacking standalone meaning into search queries,
+    /// see [crate::rewrite]. Needs a rewriter to be set.
+    pub fn with_query_rewrite(mut self, config: RewriteConfig) -> Self {
+        self.query_rewrite = Some(config);
+        self
+    }
+
+    /// Rewriter asked when [Agent::with_query_rewrite] is set.
+    pub fn with_query_rewriter(mut self, rewriter: Arc<dyn QueryRewriter>) -> Self {
+        self.query_rewriter = Some(rewriter);
+        self
+    }
+
+    pub fn rewrite_config(&self) -> Option<&RewriteConfig> {
+        self.query_rewrite.as_ref()
+    }
+
+    pub(crate) fn query_rewriter(&self) -> Option<&Arc<dyn QueryRewriter>> {
+        self.query_rewriter.as_ref()
+    }
+
+    pub fn confidence_config(&self) -> Option<&ConfidenceConfig> {
+        self.confidence.as_ref()
+    }
+
+    pub fn injection_config(&self) -> Option<&InjectionConfig> {
+        self.injection.as_ref()
+    }
+
+    /// Limits for the tools of one interaction. Clients wrap every tool they
+    /// register with it, see [crate::tools].
+    pub fn tool_guard(&self, shutdown: CancellationToken) -> ToolGuard {
+        ToolGuard::new(self.tool_config.clone(), shutdown)
+    }
+
+    /// The character without retrieved documents.
+    pub(crate) fn base_builder(&self) -> AgentBuilder<M> {
+        self.completion_params(
+            AgentBuilder::new(self.completion_model.clone())
+                .preamble(&self.character.preamble)
+                .context(&format!("Your name: {}", self.character.name)),
+            None,
+        )
+    }
+
+    /// Sampling settings of a reply in `mode`: the character's for the
+    /// source, with the variant's temperature, if any. Brief acknowledgements
+    /// are capped at [BRIEF_ACK_MAX_TOKENS].
+    pub fn generation_params(&self, mode: Option<&ResponseMode>) -> GenerationParams {
+        let mut params = self.character.generation(self.source.as_ref());
+        if let Some(temperature) = self
+            .variant
+            .as_ref()
+            .and_then(|assignment| assignment.variant.temperature)
+        {
+            params.temperature = Some(temperature);
+        }
+        if mode == Some(&ResponseMode::BriefAck) {
+            params.max_tokens = Some(
+                params
+                    .max_tokens
+                    .map_or(BRIEF_ACK_MAX_TOKENS, |max| max.min(BRIEF_ACK_MAX_TOKENS)),
+            );
+        }
+        params
+    }
+
+    /// `builder` with [Agent::generation_params] and the model of the
+    /// variant, if any. The model is requested by name, overriding the
+    /// default's in the request.
+    fn completion_params(
+        &self,
+        builder: AgentBuilder<M>,
+        mode: Option<&ResponseMode>,
+    ) -> AgentBuilder<M> {
+        let mut extra = serde_json::Map::new();
+        if let Some(model) = self
+            .variant
+            .as_ref()
+            .and_then(|assignment| assignment.variant.model.as_ref())
+        {
+            extra.insert("model".to_string(), model.clone().into());
         }
+        self.generation_params(mode).apply(builder, extra)
     }
 
     pub fn builder(&self) -> AgentBuilder<M> {
-        let builder = AgentBuilder::new(self.completion_model.clone())
-            .preamble(&self.character.preamble)
-            .context(&format!("Your name: {}", self.character.name))
-            .dynamic_context(2, self.knowledge.clone().document_index());
+        let builder = self.base_builder();
+
+        let knowledge = self.knowledge.clone();
+        let diversity = self.diversity.clone();
+        if self.character.topics.is_empty() {
+            builder.dynamic_context(
+                RETRIEVED_DOCUMENTS,
+                knowledge.clone().diverse_index(
+                    knowledge.clone().fresh_index(knowledge.document_index()),
+                    diversity,
+                ),
+            )
+        } else {
+            builder.dynamic_context(
+                RETRIEVED_DOCUMENTS,
+                knowledge.clone().diverse_index(
+                    knowledge.clone().fresh_index(
+                        knowledge
+                            .topic_index(self.character.topics.clone(), self.topic_boost.clone()),
+                    ),
+                    diversity,
+                ),
+            )
+        }
+    }
+
+    /// The configured retriever, or the same index [Agent::builder] uses.
+    fn retriever(&self) -> Arc<dyn Retriever> {
+        if let Some(retriever) = &self.retriever {
+            return retriever.clone();
+        }
+        let knowledge = self.knowledge.clone();
+        let diversity = self.diversity.clone();
+        if self.character.topics.is_empty() {
+            Arc::new(IndexRetriever::new(
+                knowledge.clone(),
+                knowledge.clone().diverse_index(
+                    knowledge
+                        .clone()
+                        .fresh_index(knowledge.clone().document_index()),
+                    diversity,
+                ),
+            ))
+        } else {
+            Arc::new(IndexRetriever::new(
+                knowledge.clone(),
+                knowledge.clone().diverse_index(
+                    knowledge.clone().fresh_index(
+                        knowledge
+                            .clone()
+                            .topic_index(self.character.topics.clone(), self.topic_boost.clone()),
+                    ),
+                    diversity,
+                ),
+            ))
+        }
+    }
+
+    /// Like [Agent::builder], with the channel's pinned context and
+    /// conversation state added. Pins are curated, so they are placed ahead of
+    /// retrieved documents and only trimmed by their own size cap.
+    pub async fn channel_builder(&self, channel_id: &str) -> AgentBuilder<M> {
+        self.with_channel_context(self.builder(), channel_id).await
+    }
+
+    /// The prompt of a reply to `input` in a channel, shaped by the
+    /// attention's [ResponseMode]. Brief acknowledgements skip retrieval and
+    /// are kept short.
+    pub async fn render_prompt(
+        &self,
+        channel_id: &str,
+        mode: &ResponseMode,
+        input: &str,
+    ) -> AssembledPrompt {
+        self.render_prompt_with_query(channel_id, mode, input, &RetrievalQuery::raw(input))
+            .await
+    }
+
+    /// Like [Agent::render_prompt], retrieving documents with `query`, see
+    /// [Agent::retrieval_query].
+    pub async fn render_prompt_with_query(
+        &self,
+        channel_id: &str,
+        mode: &ResponseMode,
+        input: &str,
+        query: &RetrievalQuery,
+    ) -> AssembledPrompt {
+        let segments = self.static_segments();
+        let mut prompt = AssembledPrompt::from_segments(&segments, input);
+        prompt.push_segment(&segments, "name");
+        for (label, text) in self.channel_contexts(channel_id).await {
+            prompt.push(label, text);
+        }
+        let preferences = self.format_preferences(None, channel_id).await;
+        if *mode == ResponseMode::BriefAck {
+            prompt.push_segment(&segments, "instruction");
+        } else if let Some((label, text)) = self
+            .recalled_exchange(channel_id, input, &preferences)
+            .await
+        {
+            prompt.push(label, text);
+        }
+        if let Some(instruction) = self
+            .familiarity
+            .as_ref()
+            .and_then(|familiarity| self.familiarity_instruction(familiarity, &preferences))
+        {
+            prompt.push("familiarity", instruction);
+        }
+        prompt.push("time", preferences.current_time(self.clock.now_utc()));
+        prompt.push_segment(&segments, "length");
+        prompt.push_segment(&segments, "untrusted");
+        if asks_capabilities(mode, input) {
+            prompt.push_segment(&segments, "capabilities");
+        }
+
+        if *mode != ResponseMode::BriefAck {
+            match self
+                .retriever()
+                .retrieve(query.text(), RETRIEVED_DOCUMENTS)
+                .await
+            {
+                Ok(documents) => {
+                    let mut sources = Vec::new();
+                    let mut query_vector = None;
+                    for retrieved in documents {
+                        // Cited where it is now, the id keeps its first path
+                        let document = RetrievedChunk {
+                            parent_id: self.current_path(&retrieved.parent_id).await,
+                            ..retrieved.clone()
+                        };
+                        let Some((text, kept)) = self
+                            .fit_document(
+                                &retrieved,
+                                &document,
+                                prompt.tokens,
+                                query.text(),
+                                &mut query_vector,
+                            )
+                            .await
+                        else {
+                            continue;
+                        };
+                        sources.push(SourceRef {
+                            document_id: document.id.clone(),
+                            start: kept.start,
+                            end: kept.end,
+                        });
+                        prompt.push(format!("document {}", document.citation()), text);
+                    }
+                    self.conversations
+                        .update(channel_id, |state| state.last_sources = sources)
+                        .await;
+                }
+                Err(err) => error!(?err, "Failed to retrieve documents"),
+            }
+        }
+        debug!(
+            tokens = prompt.tokens,
+            static_tokens = segments.tokens(),
+            "Assembled prompt"
+        );
+        prompt
+    }
+
+    /// The parts of prompts that only depend on the character and config,
+    /// rendered once per variant until [Agent::prompt_cache] is invalidated.
+    fn static_segments(&self) -> Arc<StaticSegments> {
+        let variant = self
+            .variant
+            .as_ref()
+            .map(|assignment| format!("{}/{}", assignment.experiment, assignment.variant.name));
+        self.prompt_cache.get_or_render(variant, || {
+            let mut segments = StaticSegments::new(&self.character.preamble)
+                .with_context("name", format!("Your name: {}", self.character.name))
+                .with_context("instruction", BRIEF_ACK_INSTRUCTION)
+                .with_context("length", LENGTH_INSTRUCTION);
+            if self.injection.as_ref().is_some_and(|config| config.delimit) {
+                segments = segments.with_context("untrusted", injection::UNTRUSTED_INSTRUCTION);
+            }
+            if let Some((config, capabilities)) = &self.capabilities {
+                segments = segments.with_context(
+                    "capabilities",
+                    capabilities.load().render(config.max_tokens),
+                );
+            }
+            segments
+        })
+    }
+
+    /// A mention of the earlier exchange of a channel most like `input`, when
+    /// it is at least [MemoryConfig::recall_similarity] alike and older than
+    /// the [MemoryConfig::recent_messages] shown, labelled as in
+    /// [AssembledPrompt::contexts].
+    async fn recalled_exchange(
+        &self,
+        channel_id: &str,
+        input: &str,
+        preferences: &FormatPreferences,
+    ) -> Option<(String, String)> {
+        let config = self
+            .memory
+            .as_ref()
+            .filter(|config| config.recall_exchanges)?;
+        let window_start = match self
+            .knowledge
+            .get_recent_messages(channel_id, config.recent_messages)
+            .await
+        {
+            Ok(messages) => messages.iter().map(|message| message.created_at).min(),
+            Err(err) => {
+                error!(?err, "Failed to load recent messages");
+                None
+            }
+        };
+        let exchanges = match self
+            .knowledge
+            .similar_exchanges(channel_id, input, RECALL_CANDIDATES)
+            .await
+        {
+            Ok(exchanges) => exchanges,
+            Err(err) => {
+                error!(?err, "Failed to search earlier exchanges");
+                return None;
+            }
+        };
+
+        let (similarity, exchange) = exchanges.into_iter().find(|(similarity, exchange)| {
+            *similarity >= config.recall_similarity
+                && window_start.map_or(true, |start| exchange.created_at < start)
+        })?;
+        debug!(id = exchange.id, similarity, "Recalled earlier exchange");
+        Some((
+            format!("exchange {}", exchange.id),
+            format!(
+                "You previously discussed this on {}: {}",
+                preferences.date(exchange.created_at),
+                exchange.summary()
+            ),
+        ))
+    }
+
+    /// Current path of the document stored under `id`, which differs from
+    /// the path in the id once its file moved. See
+    /// [KnowledgeBase::resolve_document].
+    async fn current_path(&self, id: &str) -> String {
+        match self.knowledge.resolve_document(id).await {
+            Ok(Some(resolved)) => resolved.path,
+            Ok(None) => id.to_string(),
+            Err(err) => {
+                error!(?err, id, "Failed to resolve document path");
+                id.to_string()
+            }
+        }
+    }
+
+    /// The classifier's verdict on a retrieved document, if it is asked, see
+    /// [InjectionConfig::classifier].
+    async fn classify_document(&self, document: &RetrievedChunk) -> Option<bool> {
+        let config = self.injection.as_ref()?;
+        match &self.injection_classifier {
+            Some(classifier)
+                if config.classifier
+                    && config.detect != injection::Detection::Off
+                    && !injection::looks_like_injection(&document.content) =>
+            {
+                Some(classifier.is_injection(&document.content).await)
+            }
+            _ => None,
+        }
+    }
+
+    /// The text of a retrieved document as sent, `None` when it is dropped as
+    /// a likely injection.
+    fn screen_document(
+        &self,
+        document: &RetrievedChunk,
+        classified: Option<bool>,
+    ) -> Option<String> {
+        match &self.injection {
+            Some(config) => config.screen(document, classified),
+            None => Some(document.content.clone()),
+        }
+    }
+
+    /// The text sent for `document` in a prompt of `prompt_tokens` so far,
+    /// and the byte range of its content in it. Cut around its part most
+    /// relevant to `query` when it doesn't fit the
+    /// [TruncationConfig::prompt_tokens]. `None` when it is dropped as a
+    /// likely injection or no useful part of it fits. `retrieved` is the
+    /// document with the parent id it is stored under.
+    async fn fit_document(
+        &self,
+        retrieved: &RetrievedChunk,
+        document: &RetrievedChunk,
+        prompt_tokens: usize,
+        query: &str,
+        query_vector: &mut Option<Vec<f64>>,
+    ) -> Option<(String, Range<usize>)> {
+        let classified = self.classify_document(document).await;
+        let text = self.screen_document(document, classified)?;
+        let provenance = document.provenance();
+        let whole = (format!("{provenance}\n{text}"), 0..document.content.len());
+        let Some(config) = &self.truncation else {
+            return Some(whole);
+        };
+        let remaining = config.prompt_tokens.saturating_sub(prompt_tokens);
+        if estimate_tokens(&whole.0) <= remaining {
+            return Some(whole);
+        }
+
+        let kept = if remaining >= config.min_document_tokens {
+            // Room for the content next to the provenance and what screening
+            // adds to it
+            let overhead = provenance.chars().count()
+                + 1
+                + text
+                    .chars()
+                    .count()
+                    .saturating_sub(document.content.chars().count());
+            let max_chars = truncation::max_chars(remaining).saturating_sub(overhead);
+            self.focus_document(retrieved, query, max_chars, query_vector)
+                .await
+        } else {
+            0..0
+        };
+        self.record_truncation(document, kept.clone()).await;
+        if kept.is_empty() {
+            return None;
+        }
+        // A part of a flagged document is flagged too
+        let flagged =
+            injection::looks_like_injection(&document.content) || classified == Some(true);
+        let truncated = RetrievedChunk {
+            content: truncation::render(&document.content, kept.clone()),
+            ..document.clone()
+        };
+        let text = self.screen_document(&truncated, Some(flagged))?;
+        Some((format!("{provenance}\n{text}"), kept))
+    }
+
+    /// The byte range of `document`'s content to send within `max_chars`,
+    /// around its part most relevant to `query`, see [crate::truncation].
+    /// Without scores, e.g. while the embedding provider is down, its start
+    /// is kept.
+    async fn focus_document(
+        &self,
+        document: &RetrievedChunk,
+        query: &str,
+        max_chars: usize,
+        query_vector: &mut Option<Vec<f64>>,
+    ) -> Range<usize> {
+        let segments = truncation::segments(&document.content, max_chars);
+        let scores = match self
+            .knowledge
+            .score_segments(document, &segments, query, query_vector)
+            .await
+        {
+            Ok(scores) => scores,
+            Err(err) => {
+                error!(?err, id = %document.id, "Failed to score document segments");
+                vec![0.0; segments.len()]
+            }
+        };
+        truncation::focus(&document.content, &segments, &scores, max_chars)
+    }
+
+    /// Logs that `document` was cut to `kept`, or dropped when it is empty,
+    /// and records it with the interaction answered.
+    async fn record_truncation(&self, document: &RetrievedChunk, kept: Range<usize>) {
+        info!(
+            id = %document.id,
+            length = document.content.len(),
+            ?kept,
+            "Truncated document to fit the prompt"
+        );
+        let Some(interaction_id) = self.interaction_id else {
+            return;
+        };
+        let truncation = Truncation {
+            document_id: document.id.clone(),
+            length: document.content.len(),
+            kept_start: kept.start,
+            kept_end: kept.end,
+        };
+        if let Err(err) = self
+            .knowledge
+            .record_truncation(interaction_id, &truncation)
+            .await
+        {
+            error!(?err, "Failed to record truncation");
+        }
+    }
+
+    /// A builder sending [Agent::render_prompt]'s prompt, to be prompted with
+    /// `input`. For a likely tool, clients add [crate::tools::emphasis] when
+    /// registering it.
+    pub async fn response_builder(
+        &self,
+        channel_id: &str,
+        mode: &ResponseMode,
+        input: &str,
+    ) -> AgentBuilder<M> {
+        self.response_builder_with_query(channel_id, mode, input, &RetrievalQuery::raw(input))
+            .await
+    }
 
-        builder
+    /// Like [Agent::response_builder], retrieving documents with `query`.
+    pub async fn response_builder_with_query(
+        &self,
+        channel_id: &str,
+        mode: &ResponseMode,
+        input: &str,
+        query: &RetrievalQuery,
+    ) -> AgentBuilder<M> {
+        self.completion_params(
+            self.render_prompt_with_query(channel_id, mode, input, query)
+                .await
+                .builder(self.completion_model.clone()),
+            Some(mode),
+        )
+    }
+
+    async fn with_channel_context(
+        &self,
+        builder: AgentBuilder<M>,
+        channel_id: &str,
+    ) -> AgentBuilder<M> {
+        self.channel_contexts(channel_id)
+            .await
+            .iter()
+            .fold(builder, |builder, (_, text)| builder.context(text))
+    }
+
+    /// Conversation state and pinned notes of a channel, labelled as in
+    /// [AssembledPrompt::contexts].
+    async fn channel_contexts(&self, channel_id: &str) -> Vec<(String, String)> {
+        let mut contexts = Vec::new();
+        let state = self.conversations.get(channel_id).await;
+        if let Some(summary) = &state.summary {
+            contexts.push((
+                "summary".to_string(),
+                format!("Conversation so far: {}", escape::inline(summary)),
+            ));
+        }
+        for question in &state.open_questions {
+            contexts.push((
+                "open question".to_string(),
+                format!("Still unanswered: {}", escape::inline(question)),
+            ));
+        }
+        for follow_up in &state.pending_follow_ups {
+            contexts.push((
+                "follow-up".to_string(),
+                format!(
+                    "You said you would follow up on: {}",
+                    escape::inline(follow_up)
+                ),
+            ));
+        }
+
+        match self.knowledge.pinned_context(channel_id).await {
+            Ok(pins) => {
+                for pin in fit_pins(pins, self.pinned_context_limit) {
+                    contexts.push((
+                        format!("pin {}", pin.id),
+                        format!("Pinned note: {}", escape::inline(&pin.content)),
+                    ));
+                }
+            }
+            Err(err) => error!(?err, "Failed to load pinned context"),
+        }
+
+        if let Some(config) = &self.memory {
+            contexts.extend(self.memory_contexts(channel_id, config).await);
+        }
+        contexts
+    }
+
+    /// Facts, then session summaries oldest first, then the last raw
+    /// messages of a channel. Facts and summaries are added while they fit
+    /// their budget, the most often seen and the newest first.
+    async fn memory_contexts(
+        &self,
+        channel_id: &str,
+        config: &MemoryConfig,
+    ) -> Vec<(String, String)> {
+        let mut contexts = Vec::new();
+
+        match self.knowledge.consolidated_facts(channel_id).await {
+            Ok(facts) => {
+                let mut used = 0;
+                for fact in facts {
+                    used += fact.content.len();
+                    if used > config.fact_chars {
+                        break;
+                    }
+                    contexts.push((
+                        format!("fact {}", fact.id),
+                        format!(
+                            "Known from earlier sessions: {}",
+                            escape::inline(&fact.content)
+                        ),
+                    ));
+                }
+            }
+            Err(err) => error!(?err, "Failed to load consolidated facts"),
+        }
+
+        match self
+            .knowledge
+            .session_summaries(channel_id, config.consolidation_window)
+            .await
+        {
+            Ok(summaries) => {
+                let mut used = 0;
+                let mut sessions = Vec::new();
+                for summary in summaries {
+                    used += summary.summary.len();
+                    if used > config.summary_chars {
+                        break;
+                    }
+                    sessions.push((
+                        format!("session {}", summary.id),
+                        format!("Earlier session: {}", escape::inline(&summary.summary)),
+                    ));
+                }
+                contexts.extend(sessions.into_iter().rev());
+            }
+            Err(err) => error!(?err, "Failed to load session summaries"),
+        }
+
+        if config.recent_messages > 0 {
+            match self
+                .knowledge
+                .get_recent_messages(channel_id, config.recent_messages)
+                .await
+            {
+                Ok(mut messages) if !messages.is_empty() => {
+                    messages.reverse();
+                    let history = self
+                        .history
+                        .format_for(&messages[0].source)
+                        .format(&messages);
+                    debug!(
+                        tokens_before = history.tokens_before,
+                        tokens_after = history.tokens_after,
+                        "Formatted recent messages"
+                    );
+                    let text = match &self.injection {
+                        Some(config) if config.delimit => {
+                            injection::delimit("history", "", &history.text)
+                        }
+                        _ => history.text,
+                    };
+                    contexts.push((
+                        "recent messages".to_string(),
+                        format!("Recent messages:\n{text}"),
+                    ));
+                }
+                Ok(_) => {}
+                Err(err) => error!(?err, "Failed to load recent messages"),
+            }
+        }
+
+        contexts
+    }
+
+    /// Prompts the agent for a typed answer instead of prose. The schema of `T`
+    /// is rendered into the prompt and `context` is added alongside retrieved
+    /// documents; see [structured::prompt_structured] for the repair pass.
+    pub async fn prompt_structured<T: DeserializeOwned + JsonSchema>(
+        &self,
+        input: &str,
+        context: &[&str],
+    ) -> Result<T, StructuredError> {
+        let agent = context
+            .iter()
+            .fold(self.builder(), |builder, context| builder.context(context))
+            .build();
+
+        structured::prompt_structured(&agent, input).await
+    }
+
+    /// Verbatim excerpts of the documents the last answer in a channel was
+    /// given from, when `input` asks where it came from. Documents are looked
+    /// up again by id rather than searched, and only ones this agent's
+    /// knowledge base can retrieve are quoted. `None` when `input` is not such
+    /// a request or nothing is left to quote, so it is answered as usual.
+    pub async fn quote_sources(&self, channel_id: &str, input: &str) -> Option<String> {
+        if !quotes::is_quote_request(input) {
+            return None;
+        }
+        let sources = self.conversations.get(channel_id).await.last_sources;
+        if sources.is_empty() {
+            return None;
+        }
+        let answer = match self.knowledge.recent_replies(channel_id, 1).await {
+            Ok(mut replies) => replies.pop().unwrap_or_default(),
+            Err(err) => {
+                error!(?err, "Failed to load last reply");
+                String::new()
+            }
+        };
+
+        let mut quoted = Vec::new();
+        for source in sources {
+            let chunk = match self.knowledge.get_chunk(&source.document_id).await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => continue,
+                Err(err) => {
+                    error!(?err, id = %source.document_id, "Failed to load quoted document");
+                    continue;
+                }
+            };
+            let Some(passage) = chunk.content.get(source.start..source.end) else {
+                continue;
+            };
+            let excerpt = quotes::excerpt(passage, &answer, quotes::MAX_EXCERPT_CHARS);
+            let citation = RetrievedChunk {
+                parent_id: self.current_path(&chunk.parent_id).await,
+                ..chunk
+            }
+            .citation();
+            quoted.push(quotes::block_quote(&excerpt, &citation));
+        }
+        info!(channel_id, quoted = quoted.len(), "Quoting sources");
+        (!quoted.is_empty()).then(|| quoted.join("\n\n"))
+    }
+
+    /// The correction notice for `original`, one of the agent's answers in a
+    /// channel, that `correction` says was wrong. The correction is checked
+    /// against the docs like a question; `None` when the docs back the
+    /// original answer or generating fails, so the message is answered as
+    /// usual.
+    pub async fn correct_answer(
+        &self,
+        channel_id: &str,
+        original: &str,
+        correction: &str,
+    ) -> Option<String> {
+        let agent = self
+            .response_builder(channel_id, &ResponseMode::FullAnswer, correction)
+            .await
+            .context(&format!(
+                "{}\n\n{}",
+                corrections::CORRECTION_INSTRUCTION,
+                injection::delimit("answer", "", original)
+            ))
+            .build();
+        let corrected = match agent.prompt(correction).await {
+            Ok(corrected) => corrected,
+            Err(err) => {
+                error!(?err, "Failed to generate correction");
+                return None;
+            }
+        };
+        let corrected = corrected.trim();
+        if corrected.is_empty() || corrected.contains(corrections::NO_CORRECTION) {
+            info!(channel_id, "Correction not supported by the docs");
+            return None;
+        }
+        info!(channel_id, "Correcting answer");
+        Some(
+            self.character
+                .template(templates::CORRECTION, &[("correction", corrected)]),
+        )
+    }
+
+    /// `text`, an announcement an admin has the agent post with `/say`,
+    /// rewritten in the character's voice. Nothing is retrieved, as the
+    /// announcement is all there is to say. `None` when generating fails or
+    /// comes back empty, so the text is posted as given.
+    pub async fn restyle(&self, text: &str) -> Option<String> {
+        let agent = self
+            .base_builder()
+            .context(say::RESTYLE_INSTRUCTION)
+            .build();
+        match agent.prompt(text).await {
+            Ok(restyled) if !restyled.trim().is_empty() => Some(restyled.trim().to_string()),
+            Ok(_) => None,
+            Err(err) => {
+                error!(?err, "Failed to restyle announcement");
+                None
+            }
+        }
+    }
+
+    /// The [WARMING](templates::WARMING) notice to send instead of a reply
+    /// in `mode` to `message` while the knowledge base is still loading.
+    /// Brief acknowledgements don't draw on the docs and are answered.
+    pub async fn warming_notice(&self, mode: &ResponseMode, message: &Message) -> Option<String> {
+        if self.readiness.is_ready() || *mode == ResponseMode::BriefAck {
+            return None;
+        }
+        Some(
+            self.localized_template(
+                templates::WARMING,
+                Some(&message.account_id),
+                &message.channel_id,
+                &[],
+            )
+            .await,
+        )
+    }
+
+    /// Counts the language of `message`, a user's, towards the language its
+    /// author and channel are answered in. Does nothing without
+    /// localization or when the language can't be told.
+    pub async fn observe_language(&self, message: &Message) {
+        if self.localization.is_none() {
+            return;
+        }
+        let Some(detected) = language::detect(&message.content) else {
+            return;
+        };
+        if let Err(err) = self
+            .knowledge
+            .record_language(&message.account_id, &message.channel_id, detected)
+            .await
+        {
+            error!(?err, "Failed to record message language");
+        }
+    }
+
+    /// The language to address `account_id`, or everyone in `channel_id`
+    /// without an account, in. See [crate::language] for the precedence.
+    pub async fn language_for(&self, account_id: Option<&str>, channel_id: &str) -> String {
+        let Some(config) = &self.localization else {
+            return LocalizationConfig::default().default_language;
+        };
+
+        if let Some(account_id) = account_id {
+            match self.knowledge.user_facts(account_id).await {
+                Ok(facts) => {
+                    if let Some(code) = facts
+                        .iter()
+                        .find(|(key, _)| key == language::LANGUAGE_FACT)
+                        .and_then(|(_, value)| language::code(value))
+                    {
+                        return code;
+                    }
+                }
+                Err(err) => error!(?err, "Failed to get user facts"),
+            }
+            match self.knowledge.user_language(account_id).await {
+                Ok(Some(code)) => return code,
+                Ok(None) => {}
+                Err(err) => error!(?err, "Failed to get user language"),
+            }
+        }
+        match self.knowledge.channel_language(channel_id).await {
+            Ok(Some(code)) => code,
+            Ok(None) => config.default_language.clone(),
+            Err(err) => {
+                error!(?err, "Failed to get channel language");
+                config.default_language.clone()
+            }
+        }
+    }
+
+    /// How times are written in `channel_id`, for `account_id` in a direct
+    /// message. See [crate::locale] for the precedence.
+    pub async fn format_preferences(
+        &self,
+        account_id: Option<&str>,
+        channel_id: &str,
+    ) -> FormatPreferences {
+        let mut levels = vec![self.locale_settings(channel_id, None).await];
+        match self.knowledge.channel_guild(channel_id).await {
+            Ok(Some(guild_id)) => {
+                levels.push(self.locale_settings(channel_id, Some(&guild_id)).await)
+            }
+            Ok(None) if self.locale.infer_from_facts => {
+                let account_id = match account_id {
+                    Some(account_id) => Some(account_id.to_string()),
+                    None => self.direct_message_account(channel_id).await,
+                };
+                if let Some(account_id) = account_id {
+                    match self.knowledge.user_facts(&account_id).await {
+                        Ok(facts) => levels.push(LocaleSettings {
+                            timezone: facts
+                                .into_iter()
+                                .find(|(key, _)| key == locale::TIMEZONE_FACT)
+                                .map(|(_, value)| value),
+                            date_format: None,
+                        }),
+                        Err(err) => error!(?err, "Failed to get user facts"),
+                    }
+                }
+            }
+            Ok(None) => {}
+            Err(err) => error!(?err, "Failed to get channel guild"),
+        }
+        FormatPreferences::resolve(&levels, self.locale.preferences())
+    }
+
+    /// The locale settings of `channel_id`, or of its guild `guild_id`.
+    async fn locale_settings(&self, channel_id: &str, guild_id: Option<&str>) -> LocaleSettings {
+        let mut settings = LocaleSettings::default();
+        for (key, value) in [
+            (locale::TIMEZONE_SETTING, &mut settings.timezone),
+            (locale::DATE_FORMAT_SETTING, &mut settings.date_format),
+        ] {
+            let result = match guild_id {
+                Some(guild_id) => self.knowledge.guild_setting(guild_id, key).await,
+                None => self.knowledge.channel_setting(channel_id, key).await,
+            };
+            match result {
+                Ok(setting) => *value = setting,
+                Err(err) => error!(?err, key, "Failed to get locale setting"),
+            }
+        }
+        settings
+    }
+
+    /// The user the bot talks to in `channel_id`, if it is a direct message.
+    async fn direct_message_account(&self, channel_id: &str) -> Option<String> {
+        match self.knowledge.get_recent_messages(channel_id, 10).await {
+            Ok(messages) => messages
+                .into_iter()
+                .find(|message| {
+                    message.role == "user" && message.channel_type == ChannelType::DirectMessage
+                })
+                .map(|message| message.account_id),
+            Err(err) => {
+                error!(?err, "Failed to get recent messages");
+                None
+            }
+        }
+    }
+
+    /// Renders the template `name` for `account_id` in `channel_id`, in their
+    /// language when localization is configured, see [crate::language].
+    pub async fn localized_template(
+        &self,
+        name: &str,
+        account_id: Option<&str>,
+        channel_id: &str,
+        vars: &[(&str, &str)],
+    ) -> String {
+        let Some(config) = &self.localization else {
+            return self.character.template(name, vars);
+        };
+        let language = self.language_for(account_id, channel_id).await;
+
+        if let Some(text) = self.character.templates.localized(name, &language) {
+            return templates::interpolate(text, vars);
+        }
+        if language != config.default_language && config.translate {
+            if let Some(text) = self.translate_template(name, &language).await {
+                return templates::interpolate(&text, vars);
+            }
+        }
+        self.character.template(name, vars)
+    }
+
+    /// The template `name` translated into `language`, from the cache while
+    /// the template is unchanged. `None` when translating fails or loses a
+    /// placeholder, so the default template is used.
+    async fn translate_template(&self, name: &str, language: &str) -> Option<String> {
+        let source = self.character.templates.get(name)?;
+        let source_hash = language::source_hash(source);
+        match self
+            .knowledge
+            .template_translation(name, language, &source_hash)
+            .await
+        {
+            Ok(Some(text)) => return Some(text),
+            Ok(None) => {}
+            Err(err) => error!(?err, "Failed to get cached template translation"),
+        }
+
+        let agent = self
+            .base_builder()
+            .context(language::TRANSLATE_INSTRUCTION)
+            .context(&format!("Target language: {language}"))
+            .build();
+        let text = match agent.prompt(source).await {
+            Ok(text) if !text.trim().is_empty() => text.trim().to_string(),
+            Ok(_) => return None,
+            Err(err) => {
+                error!(?err, name, language, "Failed to translate template");
+                return None;
+            }
+        };
+        if !language::keeps_placeholders(source, &text) {
+            warn!(
+                name,
+                language, "Discarding template translation without its placeholders"
+            );
+            return None;
+        }
+
+        if let Err(err) = self
+            .knowledge
+            .store_template_translation(name, language, &source_hash, &text)
+            .await
+        {
+            error!(?err, "Failed to cache template translation");
+        }
+        Some(text)
+    }
+
+    /// Records `question` as a knowledge gap if gap detection is enabled and
+    /// retrieval finds nothing relevant. The answer is generated either way.
+    pub async fn detect_knowledge_gap(&self, question: &str, channel_id: &str) {
+        let Some(config) = &self.gap_detection else {
+            return;
+        };
+
+        if let Err(err) = self
+            .knowledge
+            .detect_gap(question, channel_id, config)
+            .await
+        {
+            error!(?err, "Failed to check for knowledge gap");
+        }
+    }
+
+    /// Advances the character's onboarding for a direct message, see
+    /// [OnboardingConfig::advance](crate::onboarding::OnboardingConfig::advance).
+    /// Messages are handled as usual when onboarding is not configured or
+    /// fails.
+    pub async fn onboard(&self, message: &Message) -> OnboardingStep {
+        let Some(onboarding) = &self.character.onboarding else {
+            return OnboardingStep::Continue;
+        };
+
+        onboarding
+            .advance(&self.character.name, &self.knowledge, message)
+            .await
+            .unwrap_or_else(|err| {
+                error!(?err, "Failed to advance onboarding");
+                OnboardingStep::Continue
+            })
+    }
+
+    /// Runs the response hooks on a generated reply before it is sent.
+    pub async fn process_response(
+        &self,
+        draft: ResponseDraft,
+        ctx: &MessageContext,
+    ) -> ResponseDraft {
+        self.response_hooks.run(draft, ctx).await
     }
 
     pub fn knowledge(&self) -> &KnowledgeBase<E> {
         &self.knowledge
     }
+
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    pub fn conversations(&self) -> &ConversationStore<E> {
+        &self.conversations
+    }
+}
+
+/// Whether `input` asks what the agent can do, or the attention expects the
+/// model to look it up.
+fn asks_capabilities(mode: &ResponseMode, input: &str) -> bool {
+    capabilities::is_capabilities_question(input)
+        || matches!(mode, ResponseMode::ToolLikely(hint)
+            if capabilities::normalize(hint) == DescribeCapabilities::NAME.replace('_', ""))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{
+        clients::describe_capabilities::DescribeCapabilitiesArgs,
+        knowledge::{ChannelType, DiscoveredChannel, Document, Source},
+        permissions::PermissionTier,
+        test_utils::{self, character, ScriptedCompletionModel},
+    };
+    use rig::completion::Prompt;
+
+    fn document(id: &str, title: &str, content: &str) -> Document {
+        Document {
+            id: id.to_string(),
+            source_id: "github".to_string(),
+            content: content.to_string(),
+            created_at: chrono::Utc::now(),
+            title: title.to_string(),
+            section: String::new(),
+            topics: vec![],
+            logical_id: None,
+            cleaned: None,
+            chunk: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_brief_ack_skips_retrieval() {
+        let mut knowledge = test_utils::knowledge_base().await;
+        knowledge
+            .add_documents(vec![document(
+                "keys.md",
+                "",
+                "Session keys expire after 7 days.",
+            )])
+            .await
+            .unwrap();
+        let model = ScriptedCompletionModel::new(["gm!", "After 7 days."]);
+        let agent = Agent::new(character(), model.clone(), knowledge);
+
+        for (mode, prompt) in [
+            (ResponseMode::BriefAck, "gm shinobi"),
+            (ResponseMode::FullAnswer, "when do session keys expire?"),
+        ] {
+            agent
+                .response_builder("c1", &mode, prompt)
+                .await
+                .build()
+                .prompt(prompt)
+                .await
+                .unwrap();
+        }
+
+        let requests = model.requests();
+        let retrieved = |documents: &[String]| {
+            documents
+                .iter()
+                .any(|document| document.contains("Session keys expire"))
+        };
+        assert!(!retrieved(&requests[0].documents));
+        assert!(requests[0]
+            .documents
+            .iter()
+            .any(|document| document == BRIEF_ACK_INSTRUCTION));
+        assert!(retrieved(&requests[1].documents));
+        assert!(!requests[1]
+            .documents
+            .iter()
+            .any(|document| document == BRIEF_ACK_INSTRUCTION));
+    }
+
+    #[tokio::test]
+    async fn test_variant_overrides_preamble_and_model() {
+        let model = ScriptedCompletionModel::new(["gm!", "gm"]);
+        let agent = Agent::new(
+            character(),
+            model.clone(),
+            test_utils::knowledge_base().await,
+        )
+        .with_experiments(vec![ExperimentConfig {
+            name: "terse".to_string(),
+            guilds: vec!["g1".to_string()],
+            variants: vec![experiments::VariantConfig {
+                name: "mini".to_string(),
+                weight: 1,
+                preamble: Some("You help with Cartridge, in one line.".to_string()),
+                model: Some("gpt-4o-mini".to_string()),
+                temperature: Some(0.2),
+            }],
+        }]);
+
+        assert!(agent.assign(Some("g2"), "c1", "alice").is_none());
+        let assignment = agent.assign(Some("g1"), "c1", "alice").unwrap();
+        let variant = agent.for_variant(&assignment);
+        assert_eq!(variant.variant(), Some(&assignment));
+        assert_eq!(agent.character.preamble, "You help with Cartridge.");
+
+        for agent in [&agent, &variant] {
+            agent
+                .response_builder("c1", &ResponseMode::BriefAck, "gm")
+                .await
+                .build()
+                .prompt("gm")
+                .await
+                .unwrap();
+        }
+
+        let requests = model.requests();
+        assert_eq!(
+            requests[0].preamble.as_deref(),
+            Some("You help with Cartridge.")
+        );
+        assert_eq!(requests[0].temperature, None);
+        assert_eq!(requests[0].additional_params, None);
+        assert_eq!(
+            requests[1].preamble.as_deref(),
+            Some("You help with Cartridge, in one line.")
+        );
+        assert_eq!(requests[1].temperature, Some(0.2));
+        assert_eq!(
+            requests[1].additional_params,
+            Some(serde_json::json!({ "model": "gpt-4o-mini" }))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_generation_params_reach_request() {
+        let model = ScriptedCompletionModel::new(["gm", "gm", "gm"]);
+        let mut character = character();
+        character.generation = GenerationParams {
+            temperature: Some(0.9),
+            max_tokens: Some(800),
+            stop: vec!["END".to_string()],
+            ..Default::default()
+        };
+        character.source_generation = HashMap::from([(
+            "telegram".to_string(),
+            GenerationParams::default().with_temperature(0.4),
+        )]);
+        let agent = Agent::new(character, model.clone(), test_utils::knowledge_base().await)
+            .with_source(Source::Discord);
+        let telegram = agent.clone().with_source(Source::Telegram);
+
+        for (agent, mode) in [
+            (&agent, ResponseMode::FullAnswer),
+            (&telegram, ResponseMode::FullAnswer),
+            (&agent, ResponseMode::BriefAck),
+        ] {
+            agent
+                .response_builder("c1", &mode, "gm")
+                .await
+                .build()
+                .prompt("gm")
+                .await
+                .unwrap();
+        }
+
+        let requests = model.requests();
+        assert_eq!(requests[0].temperature, Some(0.9));
+        assert_eq!(requests[0].max_tokens, Some(800));
+        assert_eq!(
+            requests[0].additional_params,
+            Some(serde_json::json!({ "stop": ["END"] }))
+        );
+        assert_eq!(requests[1].temperature, Some(0.4));
+        assert_eq!(requests[1].max_tokens, Some(800));
+        assert_eq!(requests[2].temperature, Some(0.9));
+        assert_eq!(requests[2].max_tokens, Some(BRIEF_ACK_MAX_TOKENS));
+    }
+
+    fn user_message(account_id: &str, channel_id: &str, content: &str) -> Message {
+        Message {
+            id: format!("{account_id}:{content}"),
+            source: Source::Discord,
+            source_id: account_id.to_string(),
+            channel_type: ChannelType::Text,
+            channel_id: channel_id.to_string(),
+            account_id: account_id.to_string(),
+            role: "user".to_string(),
+            content: content.to_string(),
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    async fn muted(
+        agent: &Agent<ScriptedCompletionModel, test_utils::FakeEmbeddingModel>,
+        account_id: Option<&str>,
+        channel_id: &str,
+    ) -> String {
+        agent
+            .localized_template(templates::MUTED_ACK, account_id, channel_id, &[])
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_template_language_precedence() {
+        let mut character = character();
+        character.templates = toml::from_str(
+            r#"
+            [muted_ack]
+            es = "Entendido, me quedo callado."
+            fr = "Compris, je me tais."
+            de = "Verstanden, ich bin still."
+            "#,
+        )
+        .unwrap();
+        let agent = Agent::new(
+            character,
+            ScriptedCompletionModel::new([]),
+            test_utils::knowledge_base().await,
+        );
+
+        // Without localization, nothing is detected and the default is used
+        agent
+            .observe_language(&user_message(
+                "alice",
+                "c1",
+                "¿Cómo puedo usar el paymaster?",
+            ))
+            .await;
+        assert_eq!(
+            muted(&agent, Some("alice"), "c1").await,
+            "Understood, I'll stay quiet here."
+        );
+
+        let agent = agent.with_localization(LocalizationConfig {
+            default_language: "de".to_string(),
+            translate: false,
+        });
+
+        // Nothing known: the default language
+        assert_eq!(
+            muted(&agent, Some("alice"), "c1").await,
+            "Verstanden, ich bin still."
+        );
+
+        // The language the user writes in, then the channel's for others
+        for _ in 0..2 {
+            agent
+                .observe_language(&user_message(
+                    "alice",
+                    "c1",
+                    "¿Cómo puedo usar el paymaster?",
+                ))
+                .await;
+        }
+        assert_eq!(
+            muted(&agent, Some("alice"), "c1").await,
+            "Entendido, me quedo callado."
+        );
+        assert_eq!(
+            muted(&agent, Some("bob"), "c1").await,
+            "Entendido, me quedo callado."
+        );
+        assert_eq!(
+            muted(&agent, None, "c1").await,
+            "Entendido, me quedo callado."
+        );
+        assert_eq!(
+            muted(&agent, Some("bob"), "c2").await,
+            "Verstanden, ich bin still."
+        );
+
+        // The language given during onboarding wins
+        agent
+            .knowledge()
+            .set_user_fact("alice", language::LANGUAGE_FACT, "French")
+            .await
+            .unwrap();
+        assert_eq!(
+            muted(&agent, Some("alice"), "c1").await,
+            "Compris, je me tais."
+        );
+
+        // A language without a text, and no translation, falls back to the default template
+        agent
+            .knowledge()
+            .set_user_fact("alice", language::LANGUAGE_FACT, "Japanese")
+            .await
+            .unwrap();
+        assert_eq!(
+            muted(&agent, Some("alice"), "c1").await,
+            "Understood, I'll stay quiet here."
+        );
+    }
+
+    #[tokio::test]
+    async fn test_format_preferences_precedence() {
+        let agent = Agent::new(
+            character(),
+            ScriptedCompletionModel::new([]),
+            test_utils::knowledge_base().await,
+        )
+        .with_locale(LocaleConfig {
+            timezone: "America/New_York".to_string(),
+            ..Default::default()
+        });
+        let knowledge = agent.knowledge();
+        knowledge
+            .upsert_guild_channels(
+                "g1",
+                vec![DiscoveredChannel {
+                    channel_id: "c1".to_string(),
+                    channel_type: "text".to_string(),
+                    name: "general".to_string(),
+                }],
+            )
+            .await
+            .unwrap();
+        let timezone = |account_id: Option<&'static str>, channel_id: &'static str| {
+            let agent = agent.clone();
+            async move {
+                let preferences = agent.format_preferences(account_id, channel_id).await;
+                preferences.timezone.name().to_string()
+            }
+        };
+
+        assert_eq!(timezone(None, "c1").await, "America/New_York");
+        knowledge
+            .set_guild_setting("g1", locale::TIMEZONE_SETTING, Some("Europe/Paris"))
+            .await
+            .unwrap();
+        assert_eq!(timezone(None, "c1").await, "Europe/Paris");
+        knowledge
+            .set_channel_setting("c1", locale::TIMEZONE_SETTING, Some("Asia/Tokyo"))
+            .await
+            .unwrap();
+        assert_eq!(timezone(None, "c1").await, "Asia/Tokyo");
+
+        // In direct messages, the zone the user told the bot applies
+        knowledge
+            .set_user_fact("alice", locale::TIMEZONE_FACT, "I'm in CET")
+            .await
+            .unwrap();
+        assert_eq!(timezone(Some("alice"), "dm1").await, "CET");
+        assert_eq!(timezone(Some("alice"), "c1").await, "Asia/Tokyo");
+    }
+
+    #[tokio::test]
+    async fn test_translated_template_is_cached() {
+        let model = ScriptedCompletionModel::new([
+            "Demasiadas preguntas, vuelve en {{retry_after}}.",
+            "Demasiadas preguntas, vuelve pronto.",
+        ]);
+        let agent = Agent::new(
+            character(),
+            model.clone(),
+            test_utils::knowledge_base().await,
+        )
+        .with_localization(LocalizationConfig {
+            default_language: "en".to_string(),
+            translate: true,
+        });
+        agent
+            .knowledge()
+            .set_user_fact("alice", language::LANGUAGE_FACT, "es")
+            .await
+            .unwrap();
+
+        for retry_after in ["5m", "2m"] {
+            let text = agent
+                .localized_template(
+                    templates::RATE_LIMITED,
+                    Some("alice"),
+                    "c1",
+                    &[("retry_after", retry_after)],
+                )
+                .await;
+            assert_eq!(
+                text,
+                format!("Demasiadas preguntas, vuelve en {retry_after}.")
+            );
+        }
+        let requests = model.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].prompt.contains("{{retry_after}}"));
+        assert!(requests[0]
+            .documents
+            .iter()
+            .any(|doc| doc.contains("Target language: es")));
+
+        // The default language is never translated
+        assert_eq!(
+            agent
+                .localized_template(templates::MUTED_ACK, Some("bob"), "c2", &[])
+                .await,
+            "Understood, I'll stay quiet here."
+        );
+        assert_eq!(model.requests().len(), 1);
+
+        // Changing the template invalidates the translation. One that drops a
+        // placeholder is discarded for the new text.
+        let mut agent = agent;
+        agent.character.templates =
+            toml::from_str(r#"rate_limited = "Slow down, try again in {{retry_after}}.""#).unwrap();
+        let text = agent
+            .localized_template(
+                templates::RATE_LIMITED,
+                Some("alice"),
+                "c1",
+                &[("retry_after", "5m")],
+            )
+            .await;
+        assert_eq!(text, "Slow down, try again in 5m.");
+        assert_eq!(model.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_quotes_sources_of_last_answer() {
+        let fees = "# VRF\n\nRequests cost a flat fee of 0.01 ETH.\n\nFees are refunded when a request fails.";
+        let mut knowledge = test_utils::knowledge_base().await;
+        knowledge
+            .add_documents(vec![document("vrf.md", "VRF", fees)])
+            .await
+            .unwrap();
+        let mut other = knowledge.clone().with_namespace("other");
+        other
+            .add_documents(vec![document("private.md", "", "Internal pricing notes.")])
+            .await
+            .unwrap();
+        let model = ScriptedCompletionModel::new(["The flat fee is 0.01 ETH per request."]);
+        let agent = Agent::new(character(), model.clone(), knowledge.clone());
+
+        let question = "what does a VRF request cost?";
+        let answer = agent
+            .response_builder("c1", &ResponseMode::FullAnswer, question)
+            .await
+            .build()
+            .prompt(question)
+            .await
+            .unwrap();
+        knowledge
+            .create_message(Message {
+                id: "m1:reply".to_string(),
+                source: Source::Discord,
+                source_id: "bot".to_string(),
+                channel_type: ChannelType::Text,
+                channel_id: "c1".to_string(),
+                account_id: "bot".to_string(),
+                role: "assistant".to_string(),
+                content: answer,
+                created_at: chrono::Utc::now(),
+            })
+            .await
+            .unwrap();
+        // Documents of another agent are never quoted
+        agent
+            .conversations()
+            .update("c1", |state| {
+                state.last_sources.push(SourceRef {
+                    document_id: "private.md".to_string(),
+                    start: 0,
+                    end: 23,
+                })
+            })
+            .await;
+
+        assert_eq!(agent.quote_sources("c1", "thanks!").await, None);
+        assert_eq!(
+            agent.quote_sources("c2", "where does it say that?").await,
+            None
+        );
+        let quote = agent
+            .quote_sources("c1", "where does it say that?")
+            .await
+            .unwrap();
+        insta::assert_snapshot!(quote, @r"
+        > # VRF
+        >
+        > Requests cost a flat fee of 0.01 ETH.
+        >
+        > Fees are refunded when a request fails.
+        — VRF
+        ");
+        assert!(!quote.contains("Internal pricing"));
+        // Answered without generating, or searching again
+        assert_eq!(model.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_document_keeps_its_relevant_part() {
+        let query = "how are paymaster fees refunded?";
+        let relevant = "Paymaster fees are refunded after each session.\n\n";
+        let model = test_utils::ControlledEmbeddingModel::default()
+            .with(query, [1.0])
+            .with(relevant, [1.0]);
+        let mut knowledge = test_utils::knowledge_base_with(model).await;
+        let content = (0..200)
+            .map(|i| match i {
+                120 => relevant.to_string(),
+                i => format!("Filler paragraph {i} about nothing in particular.\n\n"),
+            })
+            .collect::<String>();
+        knowledge
+            .add_documents(vec![document("guide.md", "", &content)])
+            .await
+            .unwrap();
+        let interaction_id = knowledge
+            .create_interaction("c1".to_string(), "alice".to_string(), Vec::new())
+            .await
+            .unwrap();
+        let agent = Agent::new(
+            character(),
+            ScriptedCompletionModel::new([]),
+            knowledge.clone(),
+        )
+        .with_interaction(Some(interaction_id));
+
+        let config = TruncationConfig {
+            prompt_tokens: 1000,
+            min_document_tokens: 100,
+        };
+        let prompt = agent
+            .clone()
+            .with_truncation(config.clone())
+            .render_prompt("c1", &ResponseMode::FullAnswer, query)
+            .await;
+        assert!(prompt.tokens <= config.prompt_tokens, "{}", prompt.tokens);
+        let text = prompt.context("document guide.md").unwrap();
+        assert!(text.contains(relevant.trim()), "{text}");
+        assert!(text.starts_with("Source: guide.md\n[…]\n\nFiller paragraph"));
+        assert!(text.ends_with(truncation::TRUNCATION_NOTE));
+
+        // The kept range is what the conversation cites, and is logged
+        let start = content.find(relevant).unwrap();
+        let truncations = knowledge
+            .interaction_truncations(interaction_id)
+            .await
+            .unwrap();
+        assert_eq!(truncations.len(), 1);
+        let kept = truncations[0].kept_start..truncations[0].kept_end;
+        assert!(kept.start < start && start + relevant.len() <= kept.end);
+        assert_eq!(truncations[0].length, content.len());
+        let sources = agent.conversations.get("c1").await.last_sources;
+        assert_eq!((sources[0].start, sources[0].end), (kept.start, kept.end));
+
+        // Without room for a useful part, dropped
+        let prompt = agent
+            .clone()
+            .with_truncation(TruncationConfig {
+                min_document_tokens: 990,
+                ..config
+            })
+            .render_prompt("c1", &ResponseMode::FullAnswer, query)
+            .await;
+        assert!(prompt.context("document guide.md").is_none());
+        let truncations = knowledge
+            .interaction_truncations(interaction_id)
+            .await
+            .unwrap();
+        assert_eq!(truncations.len(), 2);
+        assert_eq!(truncations[1].kept_start, truncations[1].kept_end);
+
+        // Sent whole without a budget
+        let prompt = agent
+            .render_prompt("c1", &ResponseMode::FullAnswer, query)
+            .await;
+        let text = prompt.context("document guide.md").unwrap();
+        assert!(text.contains("Filler paragraph 0 ") && text.contains("Filler paragraph 199 "));
+        assert!(!text.contains(truncation::TRUNCATION_NOTE));
+    }
+
+    #[tokio::test]
+    async fn test_capabilities_follow_registrations_and_reloads() {
+        let mut knowledge = test_utils::knowledge_base().await;
+        knowledge
+            .add_documents(vec![document(
+                "keys.md",
+                "",
+                "Session keys expire after 7 days.",
+            )])
+            .await
+            .unwrap();
+        let capabilities = Capabilities::default()
+            .with_platform("discord")
+            .with_sources(&knowledge)
+            .await
+            .unwrap();
+        let agent = Agent::new(
+            character(),
+            ScriptedCompletionModel::new([]),
+            knowledge.clone(),
+        )
+        .with_capabilities(CapabilitiesConfig::default(), capabilities);
+        let rendered = |mode: ResponseMode, input: &'static str| {
+            let agent = agent.clone();
+            async move {
+                agent
+                    .render_prompt("c1", &mode, input)
+                    .await
+                    .context("capabilities")
+                    .map(str::to_string)
+            }
+        };
+
+        let text = rendered(ResponseMode::FullAnswer, "what can you do?")
+            .await
+            .unwrap();
+        assert!(
+            text.contains("- Platforms: discord\n- Tools: none"),
+            "{text}"
+        );
+        assert!(text.contains("- Knowledge: github (1 document)"), "{text}");
+        assert!(
+            rendered(ResponseMode::FullAnswer, "when do session keys expire?")
+                .await
+                .is_none()
+        );
+        let hint = ResponseMode::ToolLikely("describe capabilities".to_string());
+        assert!(rendered(hint, "hm").await.is_some());
+
+        // A tool registered and documents added by a reload show up in the
+        // next prompt and in the tool's answers
+        let tool = agent.describe_capabilities().unwrap();
+        let own = capabilities::ToolCapability::of(&tool, PermissionTier::User).await;
+        agent.update_capabilities(|capabilities| capabilities.with_tool(own));
+        knowledge
+            .add_documents(vec![document(
+                "vrf.md",
+                "",
+                "VRF is verifiable randomness.",
+            )])
+            .await
+            .unwrap();
+        agent.refresh_capabilities().await.unwrap();
+        let text = rendered(ResponseMode::FullAnswer, "what can you do?")
+            .await
+            .unwrap();
+        assert!(text.contains("- Tools: describe_capabilities: Look up what you can"));
+        assert!(text.contains("- Knowledge: github (2 documents)"), "{text}");
+        let described = tool
+            .call(DescribeCapabilitiesArgs {
+                area: Some("knowledge".to_string()),
+            })
+            .await
+            .unwrap();
+        assert_eq!(described, "github (2 documents)");
+    }
 }
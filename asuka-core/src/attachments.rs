@@ -0,0 +1,339 @@
+//! Long code blocks sent as files instead of walls of chunked messages.
+//!
+//! [CodeAttachments] takes every fenced code block of a Discord or Telegram
+//! reply with at least [AttachmentConfig::min_lines] lines, or
+//! [AttachmentConfig::min_chars] characters, out of the text and into an
+//! [Attachment] named after the fence's language, e.g. `snippet.rs` for a
+//! `rust` block. The block is replaced by a line pointing to the file, and
+//! shorter blocks and the prose around them stay as generated. A file larger
+//! than the platform accepts is cut, and its line says so.
+//!
+//! ```toml
+//! [attachments]
+//! min_lines = 40
+//! min_chars = 2000
+//! ```
+
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use crate::{
+    hooks::{MessageContext, ResponseDraft, ResponseHook},
+    knowledge::Source,
+};
+
+/// A file sent along with a reply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attachment {
+    pub name: String,
+    pub content: String,
+    /// Whether the content was cut to fit the upload limit.
+    pub truncated: bool,
+}
+
+/// `[attachments]` section of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AttachmentConfig {
+    /// Lines from which a code block is sent as a file.
+    pub min_lines: usize,
+    /// Characters from which a code block is sent as a file, whatever its
+    /// lines.
+    pub min_chars: usize,
+    /// Largest file uploaded to Discord.
+    pub discord_max_bytes: usize,
+    /// Largest document uploaded to Telegram.
+    pub telegram_max_bytes: usize,
+}
+
+impl Default for AttachmentConfig {
+    fn default() -> Self {
+        Self {
+            min_lines: 40,
+            min_chars: 2000,
+            discord_max_bytes: 8 * 1024 * 1024,
+            telegram_max_bytes: 50 * 1024 * 1024,
+        }
+    }
+}
+
+impl AttachmentConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min_lines == 0 || self.min_chars == 0 {
+            return Err("min_lines and min_chars must be positive".to_string());
+        }
+        if self.discord_max_bytes == 0 || self.telegram_max_bytes == 0 {
+            return Err("discord_max_bytes and telegram_max_bytes must be positive".to_string());
+        }
+        Ok(())
+    }
+
+    /// Upload limit of `source`, `None` where replies can't carry files.
+    pub fn max_bytes(&self, source: &Source) -> Option<usize> {
+        match source {
+            Source::Discord => Some(self.discord_max_bytes),
+            Source::Telegram => Some(self.telegram_max_bytes),
+            _ => None,
+        }
+    }
+
+    fn is_oversized(&self, code: &str) -> bool {
+        code.lines().count() >= self.min_lines || code.chars().count() >= self.min_chars
+    }
+
+    /// Moves the oversized code blocks of `text` into attachments of at most
+    /// `max_bytes` each, and returns the text left with the attachments.
+    pub fn extract(&self, text: &str, max_bytes: usize) -> (String, Vec<Attachment>) {
+        let mut out = String::with_capacity(text.len());
+        let mut attachments = Vec::new();
+        // Fence line and language of the block being read, and its lines
+        let mut block: Option<(&str, &str, String)> = None;
+
+        for line in text.split_inclusive('\n') {
+            let fence = line.trim_start().strip_prefix("```");
+            match (block.take(), fence) {
+                (None, Some(info)) => {
+                    let language = info.split_whitespace().next().unwrap_or_default();
+                    block = Some((line, language, String::new()));
+                }
+                (None, None) => out.push_str(line),
+                (Some((open, language, code)), Some(_)) => {
+                    if !self.is_oversized(&code) {
+                        out.push_str(open);
+                        out.push_str(&code);
+                        out.push_str(line);
+                        continue;
+                    }
+                    let attachment =
+                        Attachment::new(file_name(language, attachments.len()), code, max_bytes);
+                    out.push_str(&attachment.reference(max_bytes));
+                    if line.ends_with('\n') {
+                        out.push('\n');
+                    }
+                    attachments.push(attachment);
+                }
+                (Some((open, language, mut code)), None) => {
+                    code.push_str(line);
+                    block = Some((open, language, code));
+                }
+            }
+        }
+        // An unclosed block is sent as generated
+        if let Some((open, _, code)) = block {
+            out.push_str(open);
+            out.push_str(&code);
+        }
+        (out, attachments)
+    }
+}
+
+impl Attachment {
+    /// An attachment of `content`, cut to `max_bytes` at a line break when
+    /// it can be.
+    fn new(name: String, mut content: String, max_bytes: usize) -> Self {
+        let truncated = content.len() > max_bytes;
+        if truncated {
+            let mut end = max_bytes;
+            while !content.is_char_boundary(end) {
+                end -= 1;
+            }
+            let end = content[..end].rfind('\n').map_or(end, |i| i + 1);
+            content.truncate(end);
+        }
+        Self {
+            name,
+            content,
+            truncated,
+        }
+    }
+
+    /// The line standing in for the code block in the reply.
+    fn reference(&self, max_bytes: usize) -> String {
+        if self.truncated {
+            format!(
+                "See the attached `{}`, cut to the first {} KB since the whole file is over the upload limit.",
+                self.name,
+                max_bytes / 1024
+            )
+        } else {
+            format!("See the attached `{}`.", self.name)
+        }
+    }
+}
+
+/// Names of `attachments` to remember with the reply, if there are any.
+pub fn describe(attachments: &[Attachment]) -> Option<String> {
+    if attachments.is_empty() {
+        return None;
+    }
+    let names = attachments
+        .iter()
+        .map(|attachment| attachment.name.as_str())
+        .collect::<Vec<_>>();
+    Some(format!("[attached: {}]", names.join(", ")))
+}
+
+/// Name of the `index`th attachment of a reply, from the fence's language.
+fn file_name(language: &str, index: usize) -> String {
+    let extension = extension(language);
+    match index {
+        0 => format!("snippet.{extension}"),
+        _ => format!("snippet-{}.{extension}", index + 1),
+    }
+}
+
+/// File extension of code in `language`, `txt` when it isn't known.
+fn extension(language: &str) -> &'static str {
+    match language.to_lowercase().as_str() {
+        "rust" | "rs" => "rs",
+        "cairo" => "cairo",
+        "solidity" | "sol" => "sol",
+        "typescript" | "ts" => "ts",
+        "tsx" => "tsx",
+        "javascript" | "js" => "js",
+        "jsx" => "jsx",
+        "python" | "py" => "py",
+        "go" | "golang" => "go",
+        "c" => "c",
+        "cpp" | "c++" => "cpp",
+        "java" => "java",
+        "json" => "json",
+        "toml" => "toml",
+        "yaml" | "yml" => "yaml",
+        "bash" | "sh" | "shell" | "zsh" | "console" => "sh",
+        "sql" => "sql",
+        "html" => "html",
+        "css" => "css",
+        "markdown" | "md" => "md",
+        "diff" | "patch" => "diff",
+        _ => "txt",
+    }
+}
+
+/// Sends long code blocks of Discord and Telegram replies as files, see the
+/// [module docs](self). Register it before
+/// [TelegramFormat](crate::hooks::TelegramFormat), which turns the blocks
+/// into HTML.
+pub struct CodeAttachments {
+    config: AttachmentConfig,
+}
+
+impl CodeAttachments {
+    pub fn new(config: AttachmentConfig) -> Self {
+        Self { config }
+    }
+}
+
+#[async_trait]
+impl ResponseHook for CodeAttachments {
+    async fn process(&self, mut resp: ResponseDraft, _ctx: &MessageContext) -> ResponseDraft {
+        let Some(max_bytes) = self.config.max_bytes(&resp.source) else {
+            return resp;
+        };
+        let (text, attachments) = self.config.extract(&resp.text, max_bytes);
+        resp.text = text;
+        resp.attachments.extend(attachments);
+        resp
+    }
+
+    fn applies_to(&self, source: &Source) -> bool {
+        self.config.max_bytes(source).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> AttachmentConfig {
+        AttachmentConfig {
+            min_lines: 5,
+            min_chars: 200,
+            ..Default::default()
+        }
+    }
+
+    fn code(lines: usize) -> String {
+        (0..lines).map(|i| format!("let x{i} = {i};\n")).collect()
+    }
+
+    #[test]
+    fn test_long_blocks_become_files() {
+        let text = format!(
+            "Here is the system:\n\n```rust\n{}```\n\nAnd a short one:\n```ts\nconst a = 1;\n```\nThen the config:\n```toml\n{}```\nDone.",
+            code(6),
+            "key = \"value\"\n".repeat(20),
+        );
+        let (out, attachments) = config().extract(&text, 1024 * 1024);
+
+        assert_eq!(
+            out,
+            "Here is the system:\n\nSee the attached `snippet.rs`.\n\nAnd a short one:\n```ts\nconst a = 1;\n```\nThen the config:\nSee the attached `snippet-2.toml`.\nDone."
+        );
+        assert_eq!(
+            attachments
+                .iter()
+                .map(|a| (a.name.as_str(), a.truncated))
+                .collect::<Vec<_>>(),
+            [("snippet.rs", false), ("snippet-2.toml", false)]
+        );
+        assert_eq!(attachments[0].content, code(6));
+        assert_eq!(
+            describe(&attachments).unwrap(),
+            "[attached: snippet.rs, snippet-2.toml]"
+        );
+
+        // Short blocks, unclosed blocks and plain text are left alone
+        let text = format!("```\nshort\n```\n```py\n{}", code(10));
+        assert_eq!(config().extract(&text, 1024), (text, Vec::new()));
+        assert_eq!(describe(&[]), None);
+    }
+
+    #[test]
+    fn test_file_names_follow_the_fence() {
+        assert_eq!(file_name("rust", 0), "snippet.rs");
+        assert_eq!(file_name("Python", 1), "snippet-2.py");
+        assert_eq!(file_name("cairo", 0), "snippet.cairo");
+        assert_eq!(file_name("", 2), "snippet-3.txt");
+        assert_eq!(file_name("brainfuck", 0), "snippet.txt");
+
+        let text = format!("```sh title=\"setup\"\n{}```", code(5));
+        let (_, attachments) = config().extract(&text, 1024);
+        assert_eq!(attachments[0].name, "snippet.sh");
+    }
+
+    #[test]
+    fn test_oversized_file_is_truncated() {
+        let text = format!("```json\n{}```\n", code(100));
+        let (out, attachments) = config().extract(&text, 2048);
+
+        let attachment = &attachments[0];
+        assert!(attachment.truncated);
+        assert!(attachment.content.len() <= 2048);
+        assert!(attachment.content.ends_with('\n'));
+        assert!(code(100).starts_with(&attachment.content));
+        assert_eq!(
+            out,
+            "See the attached `snippet.json`, cut to the first 2 KB since the whole file is over the upload limit.\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hook_skips_platforms_without_files() {
+        let hook = CodeAttachments::new(config());
+        let text = format!("```rust\n{}```", code(10));
+        assert!(!hook.applies_to(&Source::Twitter));
+
+        let ctx = MessageContext {
+            source: Source::Telegram,
+            channel_id: "c1".to_string(),
+            channel_type: crate::knowledge::ChannelType::Text,
+            account_id: "alice".to_string(),
+        };
+        let draft = hook
+            .process(ResponseDraft::new(text, Source::Telegram), &ctx)
+            .await;
+        assert_eq!(draft.text, "See the attached `snippet.rs`.");
+        assert_eq!(draft.attachments.len(), 1);
+    }
+}
@@ -1,35 +1,184 @@
-use rig::completion::{CompletionModel, ModelChoice};
-use tracing::debug;
+use arc_swap::ArcSwap;
+use rig::{agent::AgentBuilder, completion::CompletionModel};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info};
 
-use crate::knowledge::{ChannelType, Source};
-use std::collections::HashSet;
+use crate::{
+    clients::reactions::ReactionConfig,
+    escape,
+    generation::GenerationParams,
+    history,
+    knowledge::{ChannelType, Familiarity, Source},
+    logging::AUDIT_TARGET,
+    names::NameMatcher,
+    quoted,
+    structured::prompt_structured,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
 
-const RESPOND_COMMAND: &str = "[RESPOND]";
-const IGNORE_COMMAND: &str = "[IGNORE]";
-const STOP_COMMAND: &str = "[STOP]";
-
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
 pub enum AttentionCommand {
     Respond,
     Ignore,
     Stop,
 }
 
-#[derive(Debug)]
+/// How a message that gets a reply is answered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResponseMode {
+    /// An answer drawing on the knowledge base.
+    FullAnswer,
+    /// A short acknowledgement or greeting, without retrieval.
+    BriefAck,
+    /// An answer that likely needs the named tool.
+    ToolLikely(String),
+    /// An emoji reaction instead of a message.
+    ReactionOnly,
+}
+
+impl ResponseMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ResponseMode::FullAnswer => "full_answer",
+            ResponseMode::BriefAck => "brief_ack",
+            ResponseMode::ToolLikely(_) => "tool_likely",
+            ResponseMode::ReactionOnly => "reaction_only",
+        }
+    }
+}
+
+/// [ResponseMode] as the model names it.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum ModeChoice {
+    FullAnswer,
+    BriefAck,
+    ToolLikely,
+    ReactionOnly,
+}
+
+/// Shape of the model's answer when asked whether to reply.
+#[derive(Debug, Deserialize, JsonSchema)]
+struct AttentionDecision {
+    decision: AttentionCommand,
+    /// How to respond, when responding.
+    #[serde(default)]
+    mode: Option<ModeChoice>,
+    /// Tool the answer likely needs, with `tool_likely`.
+    #[serde(default)]
+    tool: Option<String>,
+}
+
+impl AttentionDecision {
+    fn mode(&self) -> Option<ResponseMode> {
+        match self.mode? {
+            ModeChoice::FullAnswer => Some(ResponseMode::FullAnswer),
+            ModeChoice::BriefAck => Some(ResponseMode::BriefAck),
+            ModeChoice::ToolLikely => self
+                .tool
+                .as_ref()
+                .filter(|tool| !tool.trim().is_empty())
+                .map(|tool| ResponseMode::ToolLikely(tool.trim().to_string())),
+            ModeChoice::ReactionOnly => Some(ResponseMode::ReactionOnly),
+        }
+    }
+}
+
+/// Whether to reply, and how.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assessment {
+    pub decision: AttentionCommand,
+    /// Only used for [AttentionCommand::Respond], except that
+    /// [ResponseMode::ReactionOnly] also marks thanks to the bot that need no
+    /// reply but can be acknowledged with a reaction.
+    pub mode: ResponseMode,
+}
+
+/// What a client does with an [Assessment].
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplyRoute {
+    Skip,
+    React(String),
+    Answer(ResponseMode),
+}
+
+impl Assessment {
+    /// Reactions go through `reactions` where it allows them in the channel.
+    /// Elsewhere a reaction-only reply becomes a brief acknowledgement, or
+    /// nothing when no reply was asked for.
+    pub fn route(
+        &self,
+        reactions: Option<&ReactionConfig>,
+        channel_type: &ChannelType,
+    ) -> ReplyRoute {
+        let reaction = reactions
+            .filter(|config| config.allows(channel_type))
+            .and_then(|config| config.emoji.first().cloned());
+
+        let respond = self.decision == AttentionCommand::Respond;
+        match &self.mode {
+            ResponseMode::ReactionOnly => match reaction {
+                Some(emoji) => ReplyRoute::React(emoji),
+                None if respond => ReplyRoute::Answer(ResponseMode::BriefAck),
+                None => ReplyRoute::Skip,
+            },
+            mode if respond => ReplyRoute::Answer(mode.clone()),
+            _ => ReplyRoute::Skip,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct AttentionContext {
     pub message_content: String,
     pub mentioned_names: HashSet<String>,
     pub history: Vec<(String, String)>,
+    /// The bot's own latest replies in the channel, oldest first.
+    pub recent_replies: Vec<String>,
+    pub channel_id: String,
     pub channel_type: ChannelType,
     pub source: Source,
+    /// Whether the message touches the character's topics, if it declares any.
+    pub topic_match: Option<bool>,
+    /// How well the bot knows the channel and the author, when looked up,
+    /// see [crate::familiarity]. First-time posters get an answer more
+    /// readily.
+    pub familiarity: Option<Familiarity>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct AttentionConfig {
+    /// Names the bot answers to, see [NameMatcher]. Set from the character's
+    /// name and aliases, and on Discord the bot's username and nicknames,
+    /// not config files.
+    #[serde(skip)]
     pub bot_names: Vec<String>,
     pub reply_threshold: f32,
     pub max_history_messages: i64,
+    /// Messages in a channel left unanswered after each reply, unless the bot
+    /// is addressed directly.
     pub cooldown_messages: i64,
+    /// Cooldowns of sources that differ from `cooldown_messages`, by source
+    /// name, including sources of external clients.
+    pub source_cooldowns: HashMap<String, i64>,
+    /// Phrases per language that, alone, only thank or acknowledge the bot.
+    /// Such messages are ignored without asking the model. Setting this
+    /// replaces the defaults for every language.
+    pub gratitude_phrases: HashMap<String, Vec<String>>,
+    /// Characters of a message shown to the model. Longer messages keep
+    /// their start and end around an elision marker.
+    pub max_content_chars: usize,
+    /// Mentioned names shown to the model, the bot's own and those the
+    /// message repeats first.
+    pub max_mentioned_names: usize,
+    /// Characters each history message is cut to, ending with `…`.
+    pub max_history_chars: usize,
 }
 
 impl Default for AttentionConfig {
@@ -39,48 +188,425 @@ impl Default for AttentionConfig {
             reply_threshold: 0.6,
             max_history_messages: 10,
             cooldown_messages: 3,
+            source_cooldowns: HashMap::new(),
+            gratitude_phrases: default_gratitude_phrases(),
+            max_content_chars: 2000,
+            max_mentioned_names: 10,
+            max_history_chars: 500,
+        }
+    }
+}
+
+fn default_gratitude_phrases() -> HashMap<String, Vec<String>> {
+    let phrases = |phrases: &[&str]| phrases.iter().map(|p| p.to_string()).collect();
+    HashMap::from([
+        (
+            "en".to_string(),
+            phrases(&[
+                "thanks",
+                "thank you",
+                "thx",
+                "ty",
+                "tysm",
+                "cheers",
+                "appreciate it",
+                "got it",
+                "makes sense",
+                "perfect",
+                "great",
+                "awesome",
+                "nice",
+                "ok",
+                "okay",
+            ]),
+        ),
+        (
+            "es".to_string(),
+            phrases(&["gracias", "muchas gracias", "vale", "perfecto"]),
+        ),
+        (
+            "fr".to_string(),
+            phrases(&["merci", "merci beaucoup", "parfait", "d'accord"]),
+        ),
+        (
+            "pt".to_string(),
+            phrases(&["obrigado", "obrigada", "valeu", "perfeito"]),
+        ),
+        ("zh".to_string(), phrases(&["谢谢", "多谢", "好的"])),
+    ])
+}
+
+/// Bot replies shown to the model when deciding whether to reply again.
+pub const RECENT_REPLIES: usize = 2;
+
+/// Stands for the middle of a message too long to show whole.
+const ELISION: &str = "\n[…]\n";
+
+/// Messages of at most this many words, besides the bot's names, that ask
+/// nothing are answered briefly, e.g. "gm shinobi".
+const BRIEF_ACK_WORDS: usize = 3;
+
+/// Words that may pad a thank-you without making it more than one.
+const GRATITUDE_FILLERS: &[&str] = &["so", "much", "a", "lot", "again", "very", "really", "all"];
+
+/// Whether `content` only thanks or acknowledges, e.g. "thanks!!" or "ok got
+/// it, thank you so much". Punctuation, emoji and the bot's names are ignored.
+pub fn is_gratitude(
+    content: &str,
+    names: &NameMatcher,
+    phrases: &HashMap<String, Vec<String>>,
+) -> bool {
+    let normalized = content
+        .to_lowercase()
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '\'' {
+                c
+            } else {
+                ' '
+            }
+        })
+        .collect::<String>();
+    let mut words = normalized
+        .split_whitespace()
+        .filter(|word| !names.is_name(word))
+        .collect::<Vec<_>>();
+    let mut phrases = phrases
+        .values()
+        .flatten()
+        .map(|phrase| phrase.split_whitespace().collect::<Vec<_>>())
+        .filter(|phrase| !phrase.is_empty())
+        .collect::<Vec<_>>();
+    // Longest first, so "thank you" is consumed whole rather than as "thank".
+    phrases.sort_by_key(|phrase| std::cmp::Reverse(phrase.len()));
+
+    let mut thanked = false;
+    while !words.is_empty() {
+        if let Some(phrase) = phrases.iter().find(|phrase| words.starts_with(phrase)) {
+            words.drain(..phrase.len());
+            thanked = true;
+        } else if GRATITUDE_FILLERS.contains(&words[0]) {
+            words.remove(0);
+        } else {
+            return false;
+        }
+    }
+    thanked
+}
+
+impl AttentionConfig {
+    /// Matcher over [AttentionConfig::bot_names].
+    pub fn names(&self) -> NameMatcher {
+        NameMatcher::new(&self.bot_names)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.reply_threshold) {
+            return Err(format!(
+                "reply_threshold must be between 0 and 1, got {}",
+                self.reply_threshold
+            ));
+        }
+        if self.max_history_messages < 1 {
+            return Err(format!(
+                "max_history_messages must be at least 1, got {}",
+                self.max_history_messages
+            ));
+        }
+        if self
+            .gratitude_phrases
+            .values()
+            .flatten()
+            .any(|phrase| phrase.trim().is_empty())
+        {
+            return Err("gratitude_phrases must not contain empty phrases".to_string());
+        }
+        if self.cooldown_messages < 0 {
+            return Err(format!(
+                "cooldown_messages must not be negative, got {}",
+                self.cooldown_messages
+            ));
+        }
+        for (name, cooldown) in &self.source_cooldowns {
+            if Source::from_str(name).is_none() {
+                return Err(format!("source_cooldowns: unknown source {name:?}"));
+            }
+            if *cooldown < 0 {
+                return Err(format!(
+                    "source_cooldowns.{name} must not be negative, got {cooldown}"
+                ));
+            }
+        }
+        if self.max_content_chars < 100 {
+            return Err(format!(
+                "max_content_chars must be at least 100, got {}",
+                self.max_content_chars
+            ));
+        }
+        if self.max_mentioned_names == 0 || self.max_history_chars == 0 {
+            return Err("max_mentioned_names and max_history_chars must be above 0".to_string());
+        }
+        Ok(())
+    }
+
+    /// Messages left unanswered after each reply on `source`.
+    pub fn cooldown_for(&self, source: &Source) -> i64 {
+        self.source_cooldowns
+            .get(source.as_str())
+            .copied()
+            .unwrap_or(self.cooldown_messages)
+    }
+
+    /// `context` within the limits of what is shown to the model, and
+    /// whether anything had to be cut.
+    pub fn bound(&self, context: &AttentionContext) -> (AttentionContext, bool) {
+        let mut bounded = context.clone();
+        let mut truncated = false;
+
+        if let Some(content) = elide(&context.message_content, self.max_content_chars) {
+            bounded.message_content = content;
+            truncated = true;
+        }
+
+        if context.mentioned_names.len() > self.max_mentioned_names {
+            let names = self.names();
+            let content = context.message_content.to_lowercase();
+            let mut mentioned = context.mentioned_names.iter().collect::<Vec<_>>();
+            mentioned.sort_by_cached_key(|name| {
+                (
+                    !names.contains(name),
+                    std::cmp::Reverse(content.matches(&name.to_lowercase()).count()),
+                    name.to_string(),
+                )
+            });
+            bounded.mentioned_names = mentioned
+                .into_iter()
+                .take(self.max_mentioned_names)
+                .cloned()
+                .collect();
+            truncated = true;
+        }
+
+        for (_, message) in &mut bounded.history {
+            if message.chars().count() > self.max_history_chars {
+                *message = history::truncate(message, self.max_history_chars);
+                truncated = true;
+            }
         }
+        (bounded, truncated)
+    }
+}
+
+/// The start and end of `text` around [ELISION], `max_chars` long in all,
+/// or `None` when `text` fits.
+fn elide(text: &str, max_chars: usize) -> Option<String> {
+    let chars = text.chars().count();
+    if chars <= max_chars {
+        return None;
+    }
+    let kept = max_chars.saturating_sub(ELISION.chars().count());
+    let head = text.chars().take(kept - kept / 2).collect::<String>();
+    let tail = text.chars().skip(chars - kept / 2).collect::<String>();
+    Some(format!("{}{ELISION}{}", head.trim_end(), tail.trim_start()))
+}
+
+/// Everything an [Attention] changes as it runs, shared by all its clones.
+#[derive(Debug)]
+struct AttentionState {
+    /// Read on every decision, so a swapped config applies to the next one.
+    config: ArcSwap<AttentionConfig>,
+    /// Decisions per channel since the bot last replied there.
+    since_reply: Mutex<HashMap<String, i64>>,
+}
+
+impl AttentionState {
+    /// The counters survive a panic while they are locked: each update is a
+    /// single write, so they are never left half changed.
+    fn since_reply(&self) -> MutexGuard<'_, HashMap<String, i64>> {
+        self.since_reply
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
     }
 }
 
+/// The state of an [Attention] at one point in time, e.g. for the audit log.
+#[derive(Debug, Clone)]
+pub struct AttentionSnapshot {
+    pub config: Arc<AttentionConfig>,
+    /// Decisions per channel since the bot last replied there, `i64::MAX`
+    /// for channels it has not replied in yet.
+    pub since_reply: HashMap<String, i64>,
+}
+
+impl AttentionSnapshot {
+    /// Channels where only messages addressing the bot get a reply for now.
+    pub fn cooling_down(&self) -> Vec<&str> {
+        let mut channels = self
+            .since_reply
+            .iter()
+            .filter(|(_, since_reply)| **since_reply <= self.config.cooldown_messages)
+            .map(|(channel_id, _)| channel_id.as_str())
+            .collect::<Vec<_>>();
+        channels.sort();
+        channels
+    }
+}
+
+/// Decides whether the bot replies to a message. Clones are cheap and share
+/// their config and cooldowns, so one character's clients stay in step: a
+/// reply through one starts the cooldown for all.
 #[derive(Clone)]
 pub struct Attention<M: CompletionModel> {
-    config: AttentionConfig,
     completion_model: M,
+    state: Arc<AttentionState>,
 }
 
 impl<M: CompletionModel> Attention<M> {
     pub fn new(config: AttentionConfig, completion_model: M) -> Self {
         Self {
-            config,
             completion_model,
+            state: Arc::new(AttentionState {
+                config: ArcSwap::from_pointee(config),
+                since_reply: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    pub fn config(&self) -> Arc<AttentionConfig> {
+        self.state.config.load_full()
+    }
+
+    /// Replaces the config of this attention and every clone of it.
+    pub fn set_config(&self, config: AttentionConfig) {
+        self.state.config.store(Arc::new(config));
+    }
+
+    pub fn snapshot(&self) -> AttentionSnapshot {
+        AttentionSnapshot {
+            config: self.config(),
+            since_reply: self.state.since_reply().clone(),
         }
     }
 
+    /// Adds names the bot answers to, such as its nickname in a guild, to
+    /// this attention and every clone of it.
+    pub fn add_names(&self, names: &[String]) {
+        self.state.config.rcu(|config| {
+            let mut config = AttentionConfig::clone(config);
+            for name in names {
+                if !config.names().contains(name) {
+                    config.bot_names.push(name.clone());
+                }
+            }
+            config
+        });
+    }
+
+    /// Notes a reply in a channel that did not go through [Attention::should_reply],
+    /// e.g. to a direct mention, so the cooldown starts from it.
+    pub fn record_reply(&self, channel_id: &str) {
+        self.state.since_reply().insert(channel_id.to_string(), 0);
+    }
+
+    fn count_since_reply(&self, channel_id: &str) -> i64 {
+        let mut since_reply = self.state.since_reply();
+        let count = since_reply
+            .entry(channel_id.to_string())
+            .or_insert(i64::MAX);
+        *count = count.saturating_add(1);
+        *count
+    }
+
     pub async fn should_reply(&self, context: &AttentionContext) -> AttentionCommand {
-        let content = context.message_content.to_lowercase();
+        self.assess(context).await.decision
+    }
+
+    /// Decides whether to reply, like [Attention::should_reply], and how.
+    /// Only the part of `context` within the config's limits is looked at,
+    /// see [AttentionConfig::bound].
+    pub async fn assess(&self, context: &AttentionContext) -> Assessment {
+        let (context, truncated) = self.config().bound(context);
+        let assessment = self.decide(&context).await;
+        if assessment.decision == AttentionCommand::Respond {
+            self.record_reply(&context.channel_id);
+        }
+        audit(&context, &assessment, truncated);
+        assessment
+    }
+
+    /// Assessment of a message that is always answered, such as a direct
+    /// mention, without asking the model.
+    pub fn addressed(&self, context: &AttentionContext) -> Assessment {
+        let (context, truncated) = self.config().bound(context);
+        self.record_reply(&context.channel_id);
+        let assessment = Assessment {
+            decision: AttentionCommand::Respond,
+            mode: self.infer_mode(&context),
+        };
+        audit(&context, &assessment, truncated);
+        assessment
+    }
+
+    fn infer_mode(&self, context: &AttentionContext) -> ResponseMode {
+        rule_mode(context, &self.config()).unwrap_or(ResponseMode::FullAnswer)
+    }
+
+    async fn decide(&self, context: &AttentionContext) -> Assessment {
+        let config = self.config();
+        // Quoted content is someone else's, addressing whoever they addressed
+        let own_text = quoted::own_text(&context.message_content);
+        let content = own_text.to_lowercase();
+        let addressed = config.names().find(&own_text, &context.mentioned_names);
+        let since_reply = self.count_since_reply(&context.channel_id);
+        let respond = |mode| Assessment {
+            decision: AttentionCommand::Respond,
+            mode,
+        };
+        let ignore = || Assessment {
+            decision: AttentionCommand::Ignore,
+            mode: ResponseMode::FullAnswer,
+        };
 
         // Always reply to DMs
         if context.channel_type == ChannelType::DirectMessage {
-            return AttentionCommand::Respond;
+            return respond(self.infer_mode(context));
+        }
+
+        // Thanks and acknowledgements need no reply, even when addressed,
+        // though thanks for the bot's own answer can get a reaction
+        if is_gratitude(
+            &context.message_content,
+            &config.names(),
+            &config.gratitude_phrases,
+        ) {
+            debug!("Message only thanks or acknowledges, ignoring");
+            let thanks_bot = !context.recent_replies.is_empty() || addressed.is_some();
+            return Assessment {
+                mode: if thanks_bot {
+                    ResponseMode::ReactionOnly
+                } else {
+                    ResponseMode::FullAnswer
+                },
+                ..ignore()
+            };
         }
 
         // Check for mentions or name references
-        for name in &self.config.bot_names {
-            let mentioned = context.mentioned_names.contains(name);
-            let name_in_content = content.contains(&name.to_lowercase());
-
-            debug!(
-                name = name,
-                mentioned = mentioned,
-                name_in_content = name_in_content,
-                "Checking if bot name was mentioned"
+        if let Some(matched) = addressed {
+            info!(
+                target: AUDIT_TARGET,
+                channel_id = context.channel_id,
+                name = matched.name,
+                source = matched.source.as_str(),
+                "Addressed by name"
             );
+            return respond(self.infer_mode(context));
+        }
 
-            if mentioned || name_in_content {
-                debug!("Bot name {} was mentioned, will reply", name);
-                return AttentionCommand::Respond;
-            }
+        // A forward without a word of the sender's isn't asking the bot
+        if quoted::is_bare_forward(&context.message_content) {
+            debug!("Message only forwards or quotes, ignoring");
+            return ignore();
         }
 
         // Check for stop/disengage phrases
@@ -105,46 +631,685 @@ impl<M: CompletionModel> Attention<M> {
         ];
 
         if stop_phrases.iter().any(|phrase| content.contains(phrase)) {
-            return AttentionCommand::Stop;
+            return Assessment {
+                decision: AttentionCommand::Stop,
+                ..ignore()
+            };
         }
 
         // Ignore very short messages
         if content.len() < 4 {
-            return AttentionCommand::Ignore;
-        }
-
-        // Use LLM to decide if we should respond
-        let prompt = format!(
-            "You are in a room with other users. You should only respond when addressed or when the conversation is relevant to you.\n\n\
-            Response options:\n\
-            {RESPOND_COMMAND} - Message is directed at you or conversation is relevant\n\
-            {IGNORE_COMMAND} - Message is not interesting or not directed at you\n\
-            {STOP_COMMAND} - User wants you to stop or conversation has concluded\n\n\
-            Recent messages:\n{}\n\nLatest message: {}\n\n\
-            Choose one response option:",
-            context.history.iter()
-                .map(|(_, msg)| format!("- {}", msg))
+            return ignore();
+        }
+
+        let cooldown = config.cooldown_for(&context.source);
+        if since_reply <= cooldown {
+            debug!(since_reply, cooldown, "Cooling down after a reply");
+            return ignore();
+        }
+
+        let prompt = render_prompt(context);
+        info!(
+            target: AUDIT_TARGET,
+            channel_id = context.channel_id,
+            prompt,
+            "Attention prompt"
+        );
+
+        // The same message should always get the same decision
+        let agent = GenerationParams::deterministic()
+            .apply(
+                AgentBuilder::new(self.completion_model.clone()),
+                serde_json::Map::new(),
+            )
+            .build();
+
+        match prompt_structured::<AttentionDecision, _>(&agent, &prompt).await {
+            // Clear cues in the message win over the model's choice of mode
+            Ok(decision) => Assessment {
+                decision: decision.decision,
+                mode: rule_mode(context, &config)
+                    .or_else(|| decision.mode())
+                    .unwrap_or(ResponseMode::FullAnswer),
+            },
+            Err(err) => {
+                error!(?err, "Failed to get attention decision");
+                ignore()
+            }
+        }
+    }
+}
+
+fn audit(context: &AttentionContext, assessment: &Assessment, truncated: bool) {
+    info!(
+        target: AUDIT_TARGET,
+        channel_id = context.channel_id,
+        truncated,
+        decision = ?assessment.decision,
+        mode = assessment.mode.as_str(),
+        tool_hint = match &assessment.mode {
+            ResponseMode::ToolLikely(tool) => Some(tool.as_str()),
+            _ => None,
+        },
+        "Attention decision"
+    );
+}
+
+/// Mode the message itself calls for, if it is clear: thanks get a
+/// reaction, on-topic questions a full answer and short remarks a brief one.
+fn rule_mode(context: &AttentionContext, config: &AttentionConfig) -> Option<ResponseMode> {
+    let content = &context.message_content;
+    let names = config.names();
+    if is_gratitude(content, &names, &config.gratitude_phrases) {
+        return Some(ResponseMode::ReactionOnly);
+    }
+    if content.contains('?') {
+        return (context.topic_match != Some(false)).then_some(ResponseMode::FullAnswer);
+    }
+
+    let words = content
+        .split_whitespace()
+        .map(|word| word.trim_matches(|c: char| !c.is_alphanumeric()))
+        .filter(|word| !names.is_name(word))
+        .count();
+    (words <= BRIEF_ACK_WORDS).then_some(ResponseMode::BriefAck)
+}
+
+/// The prompt asking the model whether to reply to the latest message.
+fn render_prompt(context: &AttentionContext) -> String {
+    // Off-topic messages in group channels lean toward ignoring
+    let topic_hint = if context.topic_match == Some(false) {
+        debug!("Message matches none of the character's topics");
+        "The latest message is outside the topics you cover, so prefer ignoring it unless it is clearly directed at you.\n\n"
+    } else {
+        ""
+    };
+    let newcomer_hint = if context
+        .familiarity
+        .as_ref()
+        .is_some_and(Familiarity::is_first_time)
+    {
+        "The latest message is from someone you have never answered before, so lean toward responding to a question from them.\n\n"
+    } else {
+        ""
+    };
+
+    // The bot's own words, quoted verbatim, so it can tell when the latest
+    // message only reacts to them
+    let replies = if context.recent_replies.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "Your recent replies in this channel (written by you, not by users):\n{}\n\n",
+            context
+                .recent_replies
+                .iter()
+                .map(|reply| format!("> {}", escape::tags(reply).replace('\n', "\n> ")))
                 .collect::<Vec<_>>()
-                .join("\n"),
-            context.message_content
+                .join("\n\n")
+        )
+    };
+
+    format!(
+        "You are in a room with other users. You should only respond when addressed or when the conversation is relevant to you.\n\n\
+        {topic_hint}\
+        {newcomer_hint}\
+        Decision options:\n\
+        respond - Message is directed at you or conversation is relevant\n\
+        ignore - Message is not interesting or not directed at you\n\
+        stop - User wants you to stop or conversation has concluded\n\n\
+        When responding, also choose a mode:\n\
+        full_answer - The message needs an answer from the docs\n\
+        brief_ack - A greeting or remark that a short acknowledgement covers\n\
+        tool_likely - Answering needs one of your tools, name it in tool\n\
+        reaction_only - An emoji reaction says all that is needed\n\n\
+        Do not answer the same question twice. If the latest message only thanks you, acknowledges your reply \
+        or restates what you already said, choose ignore.\n\n\
+        Examples:\n\
+        - You replied \"Session keys expire after 7 days.\" Latest message: \"ah ok, thanks a lot!\" -> ignore\n\
+        - You replied \"Session keys expire after 7 days.\" Latest message: \"so they last a week then\" -> ignore\n\
+        - You replied \"Session keys expire after 7 days.\" Latest message: \"how do I renew one?\" -> respond\n\n\
+        {replies}\
+        Recent messages:\n{}\n\nLatest message: {}\n\n\
+        Choose one decision, and a mode when responding.",
+        context.history.iter()
+            .map(|(_, msg)| format!("- {}", escape::inline(msg)))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        escape::inline(&context.message_content)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        quoted::QuotedContent,
+        test_utils::{self, assert_contained, ScriptedCompletionModel},
+    };
+
+    #[tokio::test]
+    async fn test_model_decision_is_parsed() {
+        let model = ScriptedCompletionModel::new([
+            "```json\n{\"decision\": \"maybe\"}\n```",
+            "{\"decision\": \"respond\"}",
+        ]);
+        let config = AttentionConfig {
+            cooldown_messages: 0,
+            ..Default::default()
+        };
+        let attention = Attention::new(config, model.clone());
+        let context = AttentionContext {
+            message_content: "how do session keys expire".to_string(),
+            mentioned_names: HashSet::new(),
+            history: Vec::new(),
+            recent_replies: Vec::new(),
+            channel_id: "c1".to_string(),
+            channel_type: ChannelType::Text,
+            source: Source::Discord,
+            topic_match: None,
+            familiarity: None,
+        };
+
+        assert_eq!(
+            attention.should_reply(&context).await,
+            AttentionCommand::Respond
+        );
+        assert_eq!(model.requests().len(), 2);
+        assert_eq!(model.requests()[0].temperature, Some(0.0));
+
+        // No replies left, so the model errors and the message is ignored.
+        assert_eq!(
+            attention.should_reply(&context).await,
+            AttentionCommand::Ignore
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cooldown_after_reply() {
+        let model = ScriptedCompletionModel::new(["{\"decision\": \"respond\"}"]);
+        let config = AttentionConfig {
+            cooldown_messages: 2,
+            ..Default::default()
+        };
+        let attention = Attention::new(config, model.clone());
+        let context = |content: &str| AttentionContext {
+            message_content: content.to_string(),
+            mentioned_names: HashSet::new(),
+            history: Vec::new(),
+            recent_replies: Vec::new(),
+            channel_id: "c1".to_string(),
+            channel_type: ChannelType::Text,
+            source: Source::Discord,
+            topic_match: None,
+            familiarity: None,
+        };
+
+        assert_eq!(
+            attention
+                .should_reply(&context("hey shinobi, what is vrf"))
+                .await,
+            AttentionCommand::Respond
         );
+        for _ in 0..2 {
+            assert_eq!(
+                attention.should_reply(&context("and the fees?")).await,
+                AttentionCommand::Ignore
+            );
+        }
+        assert!(model.requests().is_empty());
 
-        let builder = self.completion_model.completion_request(&prompt);
+        // Past the cooldown the model decides again.
+        assert_eq!(
+            attention.should_reply(&context("and the fees?")).await,
+            AttentionCommand::Respond
+        );
+        assert_eq!(model.requests().len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_clones_share_state_across_threads() {
+        let model = ScriptedCompletionModel::new(["{\"decision\": \"respond\"}"]);
+        let config = AttentionConfig {
+            cooldown_messages: 100,
+            ..Default::default()
+        };
+        let attention = Attention::new(config, model.clone());
 
-        match self.completion_model.completion(builder.build()).await {
-            Ok(response) => match response.choice {
-                ModelChoice::Message(text) => {
-                    if text.contains(RESPOND_COMMAND) {
-                        AttentionCommand::Respond
-                    } else if text.contains(STOP_COMMAND) {
-                        AttentionCommand::Stop
-                    } else {
-                        AttentionCommand::Ignore
+        // A reply through one clone cools down decisions through the others
+        attention.clone().record_reply("c1");
+        let tasks = (0..8)
+            .map(|_| {
+                let attention = attention.clone();
+                tokio::spawn(async move {
+                    let mut decisions = Vec::new();
+                    for _ in 0..10 {
+                        decisions
+                            .push(attention.should_reply(&context("and the fees?", &[])).await);
                     }
-                }
-                ModelChoice::ToolCall(_, _) => AttentionCommand::Ignore,
-            },
-            Err(_) => AttentionCommand::Ignore,
+                    decisions
+                })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            assert!(task
+                .await
+                .unwrap()
+                .iter()
+                .all(|decision| *decision == AttentionCommand::Ignore));
+        }
+        assert!(model.requests().is_empty());
+
+        // Every decision was counted exactly once
+        let snapshot = attention.snapshot();
+        assert_eq!(snapshot.since_reply["c1"], 80);
+        assert_eq!(snapshot.cooling_down(), ["c1"]);
+
+        // As is a config swapped through another clone
+        let swapped = attention.clone();
+        swapped.set_config(AttentionConfig {
+            cooldown_messages: 0,
+            ..Default::default()
+        });
+        assert_eq!(
+            attention.should_reply(&context("and the fees?", &[])).await,
+            AttentionCommand::Respond
+        );
+        assert_eq!(swapped.snapshot().since_reply["c1"], 0);
+    }
+
+    #[test]
+    fn test_state_survives_panic_while_locked() {
+        let attention = Attention::new(
+            AttentionConfig::default(),
+            ScriptedCompletionModel::new(Vec::<&str>::new()),
+        );
+        attention.record_reply("c1");
+
+        let state = attention.state.clone();
+        std::thread::spawn(move || {
+            let _since_reply = state.since_reply.lock().unwrap();
+            panic!("poisoning the lock");
+        })
+        .join()
+        .unwrap_err();
+
+        assert!(attention.state.since_reply.is_poisoned());
+        attention.record_reply("c2");
+        assert_eq!(attention.snapshot().cooling_down(), ["c1", "c2"]);
+    }
+
+    fn context(content: &str, recent_replies: &[&str]) -> AttentionContext {
+        AttentionContext {
+            message_content: content.to_string(),
+            mentioned_names: HashSet::new(),
+            history: vec![
+                ("u1".to_string(), "when do session keys expire?".to_string()),
+                (
+                    "bot".to_string(),
+                    "Session keys expire after 7 days.".to_string(),
+                ),
+            ],
+            recent_replies: recent_replies.iter().map(|r| r.to_string()).collect(),
+            channel_id: "c1".to_string(),
+            channel_type: ChannelType::Text,
+            source: Source::Discord,
+            topic_match: None,
+            familiarity: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bare_forward_is_ignored_unless_addressed() {
+        let model = ScriptedCompletionModel::new(["{\"decision\": \"respond\"}"]);
+        let config = AttentionConfig {
+            bot_names: vec!["shinobi".to_string()],
+            cooldown_messages: 0,
+            ..Default::default()
+        };
+        let attention = Attention::new(config, model.clone());
+
+        // Naming the bot in the forwarded text doesn't address it
+        let forward = quoted::annotate(
+            "",
+            &[QuotedContent::new("shinobi, how do I stop katana?").with_original_author("bob")],
+        );
+        assert_eq!(
+            attention.should_reply(&context(&forward, &[])).await,
+            AttentionCommand::Ignore
+        );
+        assert!(model.requests().is_empty());
+
+        let mut mentioned = context(&forward, &[]);
+        mentioned.mentioned_names = HashSet::from(["shinobi".to_string()]);
+        assert_eq!(
+            attention.should_reply(&mentioned).await,
+            AttentionCommand::Respond
+        );
+
+        // With a question of the sender's, it is up to the model
+        let asked = quoted::annotate(
+            "anyone know why this happens?",
+            &[QuotedContent::new("katana crashed")],
+        );
+        assert_eq!(
+            attention.should_reply(&context(&asked, &[])).await,
+            AttentionCommand::Respond
+        );
+        assert_eq!(model.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_recent_replies_in_prompt() {
+        let model = ScriptedCompletionModel::new([
+            "{\"decision\": \"ignore\"}",
+            "{\"decision\": \"respond\"}",
+        ]);
+        let config = AttentionConfig {
+            cooldown_messages: 0,
+            ..Default::default()
+        };
+        let attention = Attention::new(config, model.clone());
+
+        // The bot just answered, and the follow-up only restates the answer.
+        let answered = context(
+            "so they last a week then",
+            &["Session keys expire after 7 days.\nRenew them from the profile page."],
+        );
+        assert_eq!(
+            attention.should_reply(&answered).await,
+            AttentionCommand::Ignore
+        );
+        let prompt = &model.requests()[0].prompt;
+        assert!(prompt.contains(
+            "Your recent replies in this channel (written by you, not by users):\n\
+            > Session keys expire after 7 days.\n> Renew them from the profile page.\n\n"
+        ));
+        assert!(prompt.contains("only thanks you, acknowledges your reply"));
+
+        // A new question without recent replies gets no such section.
+        let question = context("how do I revoke a session key early?", &[]);
+        assert_eq!(
+            attention.should_reply(&question).await,
+            AttentionCommand::Respond
+        );
+        assert!(!model.requests()[1].prompt.contains("Your recent replies"));
+    }
+
+    #[test]
+    fn test_first_time_poster_hint() {
+        let mut question = context("how do I revoke a session key early?", &[]);
+        assert!(!render_prompt(&question).contains("never answered before"));
+
+        question.familiarity = Some(Familiarity::default());
+        assert!(render_prompt(&question).contains("never answered before"));
+
+        question.familiarity = Some(Familiarity {
+            user_interactions: 4,
+            ..Default::default()
+        });
+        assert!(!render_prompt(&question).contains("never answered before"));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_message_is_bounded() {
+        let model = ScriptedCompletionModel::new(["{\"decision\": \"respond\"}"]);
+        let config = AttentionConfig {
+            cooldown_messages: 0,
+            ..Default::default()
+        };
+        let attention = Attention::new(config, model.clone());
+
+        let words = (0..4000).map(|i| format!("word{i}")).collect::<Vec<_>>();
+        let mut oversized = context(&format!("{} any ideas?", words.join(" ")), &[]);
+        oversized.mentioned_names = (0..60).map(|i| format!("user{i}")).collect();
+        oversized.history = (0..10)
+            .map(|i| (format!("u{i}"), "x".repeat(5000)))
+            .collect();
+        assert!(oversized.message_content.len() > 30_000);
+
+        assert_eq!(
+            attention.should_reply(&oversized).await,
+            AttentionCommand::Respond
+        );
+        let prompt = &model.requests()[0].prompt;
+        assert!(prompt.chars().count() < 12_000);
+        assert!(prompt.contains("Latest message: word0 word1"));
+        assert!(prompt.contains("\n[…]\n"));
+        assert!(prompt.contains("word3999 any ideas?"));
+        assert!(prompt.contains(&format!("- {}…", "x".repeat(500))));
+    }
+
+    #[test]
+    fn test_bound() {
+        let config = AttentionConfig {
+            max_content_chars: 100,
+            max_mentioned_names: 2,
+            max_history_chars: 10,
+            ..Default::default()
+        };
+
+        // Within the limits nothing changes
+        let short = context("how do session keys work?", &[]);
+        let (bounded, truncated) = AttentionConfig::default().bound(&short);
+        assert!(!truncated);
+        assert_eq!(bounded.message_content, short.message_content);
+        assert_eq!(bounded.history, short.history);
+
+        let (bounded, truncated) = config.bound(&short);
+        assert!(truncated);
+        assert_eq!(bounded.history[0].1, "when do se…");
+
+        let mut long = context(&format!("hey user7 {} user7?", "a".repeat(200)), &[]);
+        long.history.clear();
+        long.mentioned_names = (0..20)
+            .map(|i| format!("user{i}"))
+            .chain(["Shinobi".to_string()])
+            .collect();
+        let (bounded, truncated) = config.bound(&long);
+        assert!(truncated);
+        assert_eq!(bounded.message_content.chars().count(), 100);
+        assert!(bounded.message_content.starts_with("hey user7 aaa"));
+        assert!(bounded.message_content.ends_with("aaa user7?"));
+        assert_eq!(
+            bounded.mentioned_names,
+            HashSet::from(["Shinobi".to_string(), "user7".to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_gratitude_is_ignored_without_model() {
+        let model = ScriptedCompletionModel::new(["{\"decision\": \"respond\"}"]);
+        let config = AttentionConfig {
+            cooldown_messages: 0,
+            ..Default::default()
+        };
+        let attention = Attention::new(config, model.clone());
+
+        for content in [
+            "thanks!!",
+            "Thanks!!!",
+            "thank you so much 🙏",
+            "thx shinobi",
+            "@shinobi ty!",
+            "ok got it, thanks a lot!",
+            "gracias!!",
+            "merci beaucoup",
+        ] {
+            assert_eq!(
+                attention.should_reply(&context(content, &[])).await,
+                AttentionCommand::Ignore,
+                "{content}"
+            );
+        }
+        assert!(model.requests().is_empty());
+
+        assert_eq!(
+            attention
+                .should_reply(&context("thanks, but how do I renew one?", &[]))
+                .await,
+            AttentionCommand::Respond
+        );
+        assert_eq!(model.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_response_modes() {
+        let model = ScriptedCompletionModel::new([
+            "{\"decision\": \"respond\", \"mode\": \"tool_likely\", \"tool\": \"post_tweet\"}",
+            "{\"decision\": \"respond\", \"mode\": \"brief_ack\"}",
+            "{\"decision\": \"respond\", \"mode\": \"full_answer\"}",
+            "{\"decision\": \"respond\", \"mode\": \"tool_likely\"}",
+        ]);
+        let config = AttentionConfig {
+            cooldown_messages: 0,
+            ..Default::default()
+        };
+        let attention = Attention::new(config, model.clone());
+        let assess = |content: &str, recent_replies: &[&str]| {
+            let context = context(content, recent_replies);
+            let attention = attention.clone();
+            async move { attention.assess(&context).await }
+        };
+
+        let tweet = assess("please announce the vrf launch on twitter", &[]).await;
+        assert_eq!(tweet.decision, AttentionCommand::Respond);
+        assert_eq!(
+            tweet.mode,
+            ResponseMode::ToolLikely("post_tweet".to_string())
+        );
+        assert!(model.requests()[0].prompt.contains("tool_likely - "));
+
+        // An on-topic question gets a full answer whatever the model says
+        let question = assess("how do I renew a session key?", &[]).await;
+        assert_eq!(question.mode, ResponseMode::FullAnswer);
+
+        // And a short remark a brief one
+        let greeting = assess("gm everyone", &[]).await;
+        assert_eq!(greeting.mode, ResponseMode::BriefAck);
+
+        // A tool mode without a tool falls back to a full answer
+        let unnamed = assess("please post the vrf launch news somewhere", &[]).await;
+        assert_eq!(unnamed.mode, ResponseMode::FullAnswer);
+
+        // Thanks for the bot's answer can get a reaction, other thanks nothing
+        let thanks = assess("thanks!", &["Session keys expire after 7 days."]).await;
+        assert_eq!(thanks.decision, AttentionCommand::Ignore);
+        assert_eq!(thanks.mode, ResponseMode::ReactionOnly);
+        let thanks = assess("thanks!", &[]).await;
+        assert_eq!(thanks.decision, AttentionCommand::Ignore);
+        assert_eq!(thanks.mode, ResponseMode::FullAnswer);
+        assert_eq!(model.requests().len(), 4);
+
+        let mut mention = context("shinobi gm", &[]);
+        mention.mentioned_names.insert("shinobi".to_string());
+        assert_eq!(
+            attention.addressed(&mention),
+            Assessment {
+                decision: AttentionCommand::Respond,
+                mode: ResponseMode::BriefAck,
+            }
+        );
+        let mut direct = context("thank you so much", &[]);
+        direct.channel_type = ChannelType::DirectMessage;
+        assert_eq!(
+            attention.assess(&direct).await.mode,
+            ResponseMode::ReactionOnly
+        );
+    }
+
+    #[tokio::test]
+    async fn test_addressed_by_alias_or_nickname() {
+        let model = ScriptedCompletionModel::new(["{\"decision\": \"ignore\"}"]);
+        let config = AttentionConfig {
+            bot_names: vec!["shinobi".to_string()],
+            cooldown_messages: 0,
+            ..Default::default()
+        };
+        let attention = Attention::new(config, model.clone());
+
+        // Not the bot's name, so the model decides
+        assert_eq!(
+            attention
+                .should_reply(&context("shinobigame is down again", &[]))
+                .await,
+            AttentionCommand::Ignore
+        );
+        assert_eq!(model.requests().len(), 1);
+
+        attention.add_names(&["Ninja Helper".to_string(), "SHINOBI".to_string()]);
+        assert_eq!(attention.config().bot_names, ["shinobi", "Ninja Helper"]);
+        assert_eq!(
+            attention
+                .should_reply(&context("ninja-helper where are the vrf docs", &[]))
+                .await,
+            AttentionCommand::Respond
+        );
+        assert_eq!(model.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_route() {
+        let reactions = ReactionConfig::default();
+        let assessment = |decision, mode| Assessment { decision, mode };
+        let text = ChannelType::Text;
+        let direct = ChannelType::DirectMessage;
+
+        let thanks = assessment(AttentionCommand::Ignore, ResponseMode::ReactionOnly);
+        assert_eq!(
+            thanks.route(Some(&reactions), &text),
+            ReplyRoute::React("👍".to_string())
+        );
+        assert_eq!(thanks.route(None, &text), ReplyRoute::Skip);
+
+        // Reactions are off in direct messages, so a brief reply is sent
+        let thanks = assessment(AttentionCommand::Respond, ResponseMode::ReactionOnly);
+        assert_eq!(
+            thanks.route(Some(&reactions), &direct),
+            ReplyRoute::Answer(ResponseMode::BriefAck)
+        );
+
+        let tool = ResponseMode::ToolLikely("post_tweet".to_string());
+        assert_eq!(
+            assessment(AttentionCommand::Respond, tool.clone()).route(Some(&reactions), &text),
+            ReplyRoute::Answer(tool)
+        );
+        assert_eq!(
+            assessment(AttentionCommand::Ignore, ResponseMode::FullAnswer)
+                .route(Some(&reactions), &text),
+            ReplyRoute::Skip
+        );
+    }
+
+    #[test]
+    fn test_gratitude_phrases_are_configurable() {
+        let phrases = HashMap::from([("de".to_string(), vec!["danke schön".to_string()])]);
+        let names = NameMatcher::new(["Shinobi"]);
+        assert!(is_gratitude("Danke schön, Shinobí!", &names, &phrases));
+        assert!(!is_gratitude("thanks!", &names, &phrases));
+        assert!(!is_gratitude("!!", &names, &phrases));
+
+        let config = AttentionConfig {
+            gratitude_phrases: HashMap::from([("en".to_string(), vec![" ".to_string()])]),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_user_content_stays_in_its_line() {
+        for text in test_utils::adversarial_strings() {
+            let mut context = context(&text, &[&text]);
+            context.history.push(("u2".to_string(), text.clone()));
+            let prompt = render_prompt(&context);
+
+            let latest = prompt
+                .lines()
+                .filter(|line| line.starts_with("Latest message: "))
+                .collect::<Vec<_>>();
+            assert_eq!(latest.len(), 1);
+            assert!(!prompt.lines().any(|line| line.starts_with("- admin")));
+            assert_contained(latest[0].trim_start_matches("Latest message: "));
         }
     }
 }
@@ -0,0 +1,456 @@
+//! What the agent can actually do, assembled from what is configured, so it
+//! answers "what can you do?" from facts instead of inventing features.
+//!
+//! [Capabilities] lists the registered tools with who may use them, the
+//! knowledge sources with their document counts, the platforms the agent
+//! runs on and the rate limits. With
+//! [Agent::with_capabilities](crate::Agent::with_capabilities) it is rendered
+//! into a prompt context of at most [CapabilitiesConfig::max_tokens], long
+//! tool lists summarized, which is added when a message asks about the
+//! agent, see [is_capabilities_question], or the attention hints at the
+//! [describe_capabilities](crate::clients::describe_capabilities) tool. The
+//! model calls that tool for the details of one area.
+//!
+//! The context is one of the prompt's static segments, so it is rendered
+//! again with the config generation: after a config reload, and after
+//! [Agent::update_capabilities](crate::Agent::update_capabilities) registers
+//! a tool or recounts the documents.
+//!
+//! ```toml
+//! [capabilities]
+//! max_tokens = 300
+//! ```
+
+use std::sync::OnceLock;
+
+use regex::Regex;
+use rig::{embeddings::EmbeddingModel, tool::Tool};
+use rig_sqlite::SqliteError;
+use serde::Deserialize;
+
+use crate::{
+    history::estimate_tokens, knowledge::KnowledgeBase, permissions::PermissionTier,
+    rate_limit::RateLimitConfig,
+};
+
+/// Heads the rendered capabilities.
+pub const CAPABILITIES_HEADER: &str =
+    "What you can actually do. Answer questions about your capabilities from this only, \
+     and don't offer anything it doesn't list:";
+
+/// Areas [Capabilities::describe] knows besides tool and source names.
+pub const AREAS: &[&str] = &[
+    "tools",
+    "knowledge",
+    "platforms",
+    "permissions",
+    "rate_limits",
+];
+
+/// Phrasings of questions about the agent itself.
+const QUESTION_PATTERNS: &[&str] = &[
+    r"\bwhat (else )?(can|could|do) you (do|help( me)? with|support)\b",
+    r"\bwhat are you (able|capable|for|good at)\b",
+    r"\byour (capabilities|abilities|features|skills|tools|commands|limits)\b",
+    r"\bwhat (tools|features|commands|platforms) (do you|can you|are)\b",
+    r"\bhow can you help\b",
+    r"\bwhat do you know about\b.*\b(docs|documentation)\b",
+];
+
+/// `[capabilities]` section of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CapabilitiesConfig {
+    /// Estimated tokens the rendered capabilities may take in a prompt.
+    pub max_tokens: usize,
+}
+
+impl Default for CapabilitiesConfig {
+    fn default() -> Self {
+        Self { max_tokens: 300 }
+    }
+}
+
+impl CapabilitiesConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_tokens < 50 {
+            return Err("capabilities.max_tokens must be at least 50".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// A tool the model may call, and the least trusted users it is offered to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ToolCapability {
+    pub name: String,
+    pub description: String,
+    pub tier: PermissionTier,
+}
+
+impl ToolCapability {
+    pub fn new(name: &str, description: &str, tier: PermissionTier) -> Self {
+        Self {
+            name: name.to_string(),
+            description: description.to_string(),
+            tier,
+        }
+    }
+
+    /// `tool` as its definition describes it.
+    pub async fn of<T: Tool>(tool: &T, tier: PermissionTier) -> Self {
+        let definition = tool.definition(String::new()).await;
+        Self {
+            name: definition.name,
+            description: definition.description,
+            tier,
+        }
+    }
+
+    fn label(&self) -> String {
+        match self.tier {
+            PermissionTier::User => self.name.clone(),
+            tier => format!("{} ({})", self.name, tier_name(tier)),
+        }
+    }
+}
+
+/// A knowledge source and the documents it holds.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceCapability {
+    pub source_id: String,
+    pub documents: usize,
+}
+
+/// Everything the agent is configured to do, see the [module docs](self).
+#[derive(Clone, Debug, Default)]
+pub struct Capabilities {
+    pub tools: Vec<ToolCapability>,
+    pub sources: Vec<SourceCapability>,
+    /// Platforms the agent answers on, such as `discord`.
+    pub platforms: Vec<String>,
+    pub rate_limit: Option<RateLimitConfig>,
+}
+
+impl Capabilities {
+    /// Registers `tool`, replacing a tool of the same name.
+    pub fn with_tool(mut self, tool: ToolCapability) -> Self {
+        self.tools.retain(|existing| existing.name != tool.name);
+        self.tools.push(tool);
+        self
+    }
+
+    pub fn with_platform(mut self, platform: impl Into<String>) -> Self {
+        let platform = platform.into();
+        if !self.platforms.contains(&platform) {
+            self.platforms.push(platform);
+        }
+        self
+    }
+
+    pub fn with_rate_limit(mut self, config: RateLimitConfig) -> Self {
+        self.rate_limit = Some(config);
+        self
+    }
+
+    /// The sources of `knowledge` with their current document counts.
+    pub async fn with_sources<E: EmbeddingModel>(
+        mut self,
+        knowledge: &KnowledgeBase<E>,
+    ) -> Result<Self, SqliteError> {
+        self.sources = knowledge
+            .document_counts()
+            .await?
+            .into_iter()
+            .map(|(source_id, documents)| SourceCapability {
+                source_id,
+                documents,
+            })
+            .collect();
+        Ok(self)
+    }
+
+    /// The prompt context, within `max_tokens`. When the tools with their
+    /// descriptions don't fit, they are listed by name, then only the first
+    /// ones with a count of the rest.
+    pub fn render(&self, max_tokens: usize) -> String {
+        let full = self.render_with(
+            self.tools
+                .iter()
+                .map(|tool| format!("{}: {}", tool.label(), first_sentence(&tool.description))),
+        );
+        if estimate_tokens(&full) <= max_tokens {
+            return full;
+        }
+
+        let names = self
+            .tools
+            .iter()
+            .map(ToolCapability::label)
+            .collect::<Vec<_>>();
+        let mut text = full;
+        for shown in (0..=names.len()).rev() {
+            let rest = names.len() - shown;
+            let more = (rest > 0).then(|| format!("{rest} more, see describe_capabilities"));
+            text = self.render_with(names[..shown].iter().cloned().chain(more));
+            if estimate_tokens(&text) <= max_tokens {
+                return text;
+            }
+        }
+        // Even the names of the sources don't fit
+        text.chars()
+            .take((max_tokens * 4).saturating_sub(1))
+            .collect::<String>()
+            + "…"
+    }
+
+    fn render_with(&self, tools: impl Iterator<Item = String>) -> String {
+        let mut lines = vec![CAPABILITIES_HEADER.to_string()];
+        if !self.platforms.is_empty() {
+            lines.push(format!("- Platforms: {}", self.platforms.join(", ")));
+        }
+        let tools = tools.collect::<Vec<_>>();
+        lines.push(match tools.is_empty() {
+            true => "- Tools: none, you can only answer in messages".to_string(),
+            false => format!("- Tools: {}", tools.join("; ")),
+        });
+        if !self.sources.is_empty() {
+            lines.push(format!("- Knowledge: {}", self.knowledge()));
+        }
+        if let Some(limits) = self.rate_limits() {
+            lines.push(format!("- Rate limits: {limits}"));
+        }
+        lines.join("\n")
+    }
+
+    /// Details of `area`, one of [AREAS] or the name of a tool or source,
+    /// for the [describe_capabilities](crate::clients::describe_capabilities)
+    /// tool. Everything, unabridged, without an area.
+    pub fn describe(&self, area: Option<&str>) -> String {
+        let Some(area) = area.map(normalize).filter(|area| !area.is_empty()) else {
+            return self.render(usize::MAX / 4);
+        };
+        if let Some(tool) = self.tools.iter().find(|tool| normalize(&tool.name) == area) {
+            return format!(
+                "{}, offered to {}: {}",
+                tool.name,
+                audience(tool.tier),
+                tool.description
+            );
+        }
+        if let Some(source) = self
+            .sources
+            .iter()
+            .find(|source| normalize(&source.source_id) == area)
+        {
+            return format!(
+                "The {} knowledge source holds {}.",
+                source.source_id,
+                documents(source.documents)
+            );
+        }
+
+        match area.as_str() {
+            "tools" if self.tools.is_empty() => "No tools are registered.".to_string(),
+            "tools" => self
+                .tools
+                .iter()
+                .map(|tool| format!("- {}: {}", tool.label(), tool.description))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            "knowledge" if self.sources.is_empty() => {
+                "No knowledge sources are loaded.".to_string()
+            }
+            "knowledge" => self.knowledge(),
+            "platforms" if self.platforms.is_empty() => "No platforms are configured.".to_string(),
+            "platforms" => self.platforms.join(", "),
+            "permissions" => [
+                PermissionTier::User,
+                PermissionTier::Trusted,
+                PermissionTier::Admin,
+            ]
+            .into_iter()
+            .filter_map(|tier| {
+                let tools = self
+                    .tools
+                    .iter()
+                    .filter(|tool| tool.tier == tier)
+                    .map(|tool| tool.name.as_str())
+                    .collect::<Vec<_>>();
+                (!tools.is_empty()).then(|| format!("{}: {}", audience(tier), tools.join(", ")))
+            })
+            .chain(std::iter::once(
+                "Admin commands are limited to admins.".to_string(),
+            ))
+            .collect::<Vec<_>>()
+            .join("\n"),
+            "ratelimits" => self
+                .rate_limits()
+                .unwrap_or_else(|| "No rate limits are configured.".to_string()),
+            _ => format!(
+                "Nothing is configured for {area:?}. Areas: {}, or a tool or source name.",
+                AREAS.join(", ")
+            ),
+        }
+    }
+
+    fn knowledge(&self) -> String {
+        self.sources
+            .iter()
+            .map(|source| format!("{} ({})", source.source_id, documents(source.documents)))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    fn rate_limits(&self) -> Option<String> {
+        self.rate_limit.as_ref().map(|config| {
+            format!(
+                "{} answers per user an hour, {} a minute",
+                config.per_hour, config.per_minute
+            )
+        })
+    }
+}
+
+/// Whether `text` asks what the agent can do.
+pub fn is_capabilities_question(text: &str) -> bool {
+    question_patterns()
+        .iter()
+        .any(|pattern| pattern.is_match(text))
+}
+
+fn question_patterns() -> &'static [Regex] {
+    static PATTERNS: OnceLock<Vec<Regex>> = OnceLock::new();
+    PATTERNS.get_or_init(|| {
+        QUESTION_PATTERNS
+            .iter()
+            .map(|pattern| Regex::new(&format!("(?i){pattern}")).unwrap())
+            .collect()
+    })
+}
+
+/// `name` lowercase with only its letters and digits, so `rate limits`,
+/// `rate_limits` and `Rate-Limits` match.
+pub(crate) fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+pub(crate) fn documents(count: usize) -> String {
+    match count {
+        1 => "1 document".to_string(),
+        count => format!("{count} documents"),
+    }
+}
+
+fn first_sentence(text: &str) -> &str {
+    text.find(". ").map_or(text, |end| &text[..=end])
+}
+
+fn tier_name(tier: PermissionTier) -> &'static str {
+    match tier {
+        PermissionTier::User => "everyone",
+        PermissionTier::Trusted => "trusted users",
+        PermissionTier::Admin => "admins",
+    }
+}
+
+fn audience(tier: PermissionTier) -> &'static str {
+    match tier {
+        PermissionTier::User => "Everyone",
+        PermissionTier::Trusted => "Trusted users and admins",
+        PermissionTier::Admin => "Admins",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tool(name: &str, tier: PermissionTier) -> ToolCapability {
+        ToolCapability {
+            name: name.to_string(),
+            description: format!("Does {name} things. Only when asked."),
+            tier,
+        }
+    }
+
+    fn capabilities() -> Capabilities {
+        Capabilities {
+            sources: vec![SourceCapability {
+                source_id: "github".to_string(),
+                documents: 42,
+            }],
+            ..Default::default()
+        }
+        .with_platform("discord")
+        .with_tool(tool("describe_capabilities", PermissionTier::User))
+        .with_tool(tool("post_tweet", PermissionTier::Trusted))
+        .with_rate_limit(RateLimitConfig::default())
+    }
+
+    #[test]
+    fn test_render_reflects_configuration() {
+        assert_eq!(
+            capabilities().render(300),
+            format!(
+                "{CAPABILITIES_HEADER}\n\
+                 - Platforms: discord\n\
+                 - Tools: describe_capabilities: Does describe_capabilities things.; \
+                 post_tweet (trusted users): Does post_tweet things.\n\
+                 - Knowledge: github (42 documents)\n\
+                 - Rate limits: 10 answers per user an hour, 3 a minute"
+            )
+        );
+
+        // Long tool lists are summarized within the cap
+        let many = (0..40).fold(capabilities(), |capabilities, i| {
+            capabilities.with_tool(tool(&format!("tool_{i}"), PermissionTier::User))
+        });
+        let text = many.render(150);
+        assert!(estimate_tokens(&text) <= 150, "{text}");
+        assert!(text.contains("- Tools: describe_capabilities; post_tweet (trusted users); "));
+        assert!(text.contains(" more, see describe_capabilities\n- Knowledge: github"));
+        assert!(estimate_tokens(&many.render(50)) <= 50);
+    }
+
+    #[test]
+    fn test_describe_areas() {
+        let capabilities = capabilities();
+        assert_eq!(
+            capabilities.describe(Some("Post Tweet")),
+            "post_tweet, offered to Trusted users and admins: Does post_tweet things. Only \
+             when asked."
+        );
+        assert_eq!(
+            capabilities.describe(Some("permissions")),
+            "Everyone: describe_capabilities\nTrusted users and admins: post_tweet\n\
+             Admin commands are limited to admins."
+        );
+        assert_eq!(
+            capabilities.describe(Some("rate limits")),
+            "10 answers per user an hour, 3 a minute"
+        );
+        assert!(capabilities
+            .describe(Some("payments"))
+            .starts_with("Nothing is configured for \"payments\""));
+        assert!(capabilities.describe(None).contains("Only when asked."));
+    }
+
+    #[test]
+    fn test_capabilities_questions() {
+        for question in [
+            "what can you do?",
+            "Shinobi, what are your capabilities",
+            "what tools do you have",
+            "how can you help me",
+        ] {
+            assert!(is_capabilities_question(question), "{question}");
+        }
+        for message in ["can you check my session key?", "what is a paymaster"] {
+            assert!(!is_capabilities_question(message), "{message}");
+        }
+    }
+}
@@ -1,26 +1,122 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 use tracing::{debug, info};
 
+use crate::{
+    familiarity::FamiliarityConfig,
+    generation::GenerationParams,
+    knowledge::{match_topics, Source},
+    onboarding::OnboardingConfig,
+    templates::Templates,
+};
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Character {
     pub name: String,
+    /// Other names the character answers to, such as `Shinobi-bot`.
+    #[serde(default)]
+    pub aliases: Vec<String>,
     pub preamble: String,
+    #[serde(default)]
+    pub templates: Templates,
+    #[serde(default)]
+    pub topics: Vec<String>,
+    /// Introduction for users who message the character directly.
+    #[serde(default)]
+    pub onboarding: Option<OnboardingConfig>,
+    /// Sampling settings of the character's replies.
+    #[serde(default)]
+    pub generation: GenerationParams,
+    /// Settings replacing [Character::generation]'s on a source, by source
+    /// name such as `telegram`.
+    #[serde(default)]
+    pub source_generation: HashMap<String, GenerationParams>,
+    /// When the character stops introducing itself, see
+    /// [crate::familiarity].
+    #[serde(default)]
+    pub familiarity: FamiliarityConfig,
     // pub lore: Vec<String>,
     // pub message_examples: Vec<Vec<Message>>,
     // pub post_examples: Vec<String>,
-    // pub topics: Vec<String>,
     // pub style: Style,
     // pub adjectives: Vec<String>,
 }
 
 impl Character {
+    /// A character named `name` with `preamble`, and every other setting at
+    /// its default.
+    pub fn new(name: &str, preamble: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            aliases: Vec::new(),
+            preamble: preamble.to_string(),
+            templates: Templates::default(),
+            topics: Vec::new(),
+            onboarding: None,
+            generation: GenerationParams::default(),
+            source_generation: HashMap::new(),
+            familiarity: FamiliarityConfig::default(),
+        }
+    }
+
     pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
         info!(path = path, "Loading character configuration");
         let content = std::fs::read_to_string(path)?;
         let character: Self = toml::from_str(&content)?;
+        character.validate()?;
         debug!(name = character.name, "Character loaded successfully");
         Ok(character)
     }
+
+    pub fn validate(&self) -> Result<(), String> {
+        self.generation
+            .validate()
+            .map_err(|err| format!("generation: {err}"))?;
+        for (source, params) in &self.source_generation {
+            if Source::from_str(source).is_none() {
+                return Err(format!("source_generation: unknown source {source:?}"));
+            }
+            params
+                .validate()
+                .map_err(|err| format!("source_generation.{source}: {err}"))?;
+        }
+        self.familiarity
+            .validate()
+            .map_err(|err| format!("familiarity: {err}"))
+    }
+
+    /// Sampling settings of replies on `source`.
+    pub fn generation(&self, source: Option<&Source>) -> GenerationParams {
+        match source.and_then(|source| self.source_generation.get(source.as_str())) {
+            Some(overrides) => self.generation.merge(overrides),
+            None => self.generation.clone(),
+        }
+    }
+
+    /// The name followed by the aliases, for [AttentionConfig::bot_names].
+    ///
+    /// [AttentionConfig::bot_names]: crate::attention::AttentionConfig::bot_names
+    pub fn names(&self) -> Vec<String> {
+        std::iter::once(&self.name)
+            .chain(&self.aliases)
+            .cloned()
+            .collect()
+    }
+
+    /// Renders a named outbound template, preferring this character's override.
+    pub fn template(&self, name: &str, vars: &[(&str, &str)]) -> String {
+        self.templates.render(name, vars)
+    }
+
+    /// Whether `text` touches one of this character's topics, or `None` when
+    /// the character does not declare any.
+    pub fn topic_match(&self, text: &str) -> Option<bool> {
+        if self.topics.is_empty() {
+            return None;
+        }
+        Some(!match_topics(text, &self.topics).is_empty())
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
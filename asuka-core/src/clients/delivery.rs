@@ -0,0 +1,527 @@
+//! Sends and edits of Discord messages, retried when Discord rate limits the
+//! bot or is briefly unavailable.
+//!
+//! A multi-chunk reply is sent in order and each chunk is retried on its
+//! own. When a chunk fails for good, the rest are not sent, so users never
+//! see a reply with a hole in it; the [Delivery] tells which chunks are
+//! still to be sent.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use serenity::{
+    builder::CreateAttachment,
+    http::{Http, HttpError},
+    model::id::{ChannelId, MessageId},
+};
+use thiserror::Error;
+use tracing::warn;
+
+use super::mentions::MentionPolicy;
+use crate::attachments::Attachment;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum SendError {
+    #[error("Rate limited by Discord")]
+    RateLimited { retry_after: Option<Duration> },
+    /// A server error or timeout, which may pass.
+    #[error("Discord is unavailable: {0}")]
+    Transient(String),
+    #[error("Discord rejected the message: {0}")]
+    Permanent(String),
+}
+
+impl From<serenity::Error> for SendError {
+    fn from(error: serenity::Error) -> Self {
+        match &error {
+            serenity::Error::Http(HttpError::UnsuccessfulRequest(response)) => {
+                match response.status_code.as_u16() {
+                    // serenity's error doesn't carry the Retry-After header,
+                    // its ratelimiter already waits on the 429s it sees
+                    429 => SendError::RateLimited { retry_after: None },
+                    500..=599 => SendError::Transient(error.to_string()),
+                    _ => SendError::Permanent(error.to_string()),
+                }
+            }
+            serenity::Error::Http(HttpError::Request(request))
+                if request.is_timeout() || request.is_connect() =>
+            {
+                SendError::Transient(error.to_string())
+            }
+            _ => SendError::Permanent(error.to_string()),
+        }
+    }
+}
+
+/// The Discord calls messages go out through.
+#[async_trait]
+pub trait DiscordHttp: Send + Sync {
+    async fn send(
+        &self,
+        channel_id: ChannelId,
+        text: &str,
+        mentions: &MentionPolicy,
+    ) -> Result<MessageId, SendError>;
+
+    async fn edit(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        text: &str,
+        mentions: &MentionPolicy,
+    ) -> Result<(), SendError>;
+
+    /// Sends `files` in one message with `text`, which may be empty.
+    async fn send_files(
+        &self,
+        channel_id: ChannelId,
+        text: &str,
+        files: &[Attachment],
+        mentions: &MentionPolicy,
+    ) -> Result<MessageId, SendError>;
+}
+
+#[async_trait]
+impl DiscordHttp for Http {
+    async fn send(
+        &self,
+        channel_id: ChannelId,
+        text: &str,
+        mentions: &MentionPolicy,
+    ) -> Result<MessageId, SendError> {
+        Ok(channel_id
+            .send_message(self, mentions.create_message(text))
+            .await?
+            .id)
+    }
+
+    async fn edit(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        text: &str,
+        mentions: &MentionPolicy,
+    ) -> Result<(), SendError> {
+        channel_id
+            .edit_message(self, message_id, mentions.edit_message(text))
+            .await?;
+        Ok(())
+    }
+
+    async fn send_files(
+        &self,
+        channel_id: ChannelId,
+        text: &str,
+        files: &[Attachment],
+        mentions: &MentionPolicy,
+    ) -> Result<MessageId, SendError> {
+        let message = mentions
+            .create_message(text)
+            .add_files(files.iter().map(|file| {
+                CreateAttachment::bytes(file.content.clone().into_bytes(), file.name.clone())
+            }));
+        Ok(channel_id.send_message(self, message).await?.id)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DeliveryPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Retries of one message before it counts as failed.
+    pub max_retries: u32,
+}
+
+impl Default for DeliveryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+            max_retries: 4,
+        }
+    }
+}
+
+impl DeliveryPolicy {
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff)
+    }
+}
+
+/// Counts of retried and failed sends, shared by clones.
+#[derive(Clone, Debug, Default)]
+pub struct DeliveryMetrics {
+    retries: Arc<AtomicU64>,
+    rate_limited: Arc<AtomicU64>,
+    failures: Arc<AtomicU64>,
+}
+
+impl DeliveryMetrics {
+    /// Sends and edits retried, for any reason.
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    /// Retries caused by rate limits.
+    pub fn rate_limited(&self) -> u64 {
+        self.rate_limited.load(Ordering::Relaxed)
+    }
+
+    /// Sends and edits given up on.
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+}
+
+/// Outcome of sending the chunks of a reply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delivery {
+    /// Messages of the chunks sent, in order.
+    pub sent: Vec<MessageId>,
+    /// Error of the first chunk that could not be sent. It and the chunks
+    /// after it were not sent.
+    pub error: Option<SendError>,
+}
+
+impl Delivery {
+    pub fn is_complete(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// Chunks still to be sent, to resume the reply.
+    pub fn remaining<'a>(&self, chunks: &'a [String]) -> &'a [String] {
+        &chunks[self.sent.len().min(chunks.len())..]
+    }
+}
+
+/// Sends through a [DiscordHttp] with retries.
+#[derive(Clone)]
+pub struct Outbound {
+    http: Arc<dyn DiscordHttp>,
+    policy: DeliveryPolicy,
+    metrics: DeliveryMetrics,
+}
+
+impl Outbound {
+    pub fn new(http: Arc<dyn DiscordHttp>) -> Self {
+        Self {
+            http,
+            policy: DeliveryPolicy::default(),
+            metrics: DeliveryMetrics::default(),
+        }
+    }
+
+    pub fn with_policy(mut self, policy: DeliveryPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Counts into `metrics` instead of counters of its own.
+    pub fn with_metrics(mut self, metrics: DeliveryMetrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn metrics(&self) -> &DeliveryMetrics {
+        &self.metrics
+    }
+
+    pub async fn send(
+        &self,
+        channel_id: ChannelId,
+        text: &str,
+        mentions: &MentionPolicy,
+    ) -> Result<MessageId, SendError> {
+        let mut attempt = 0;
+        loop {
+            match self.http.send(channel_id, text, mentions).await {
+                Ok(id) => return Ok(id),
+                Err(err) => self.wait_to_retry(err, &mut attempt).await?,
+            }
+        }
+    }
+
+    pub async fn edit(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        text: &str,
+        mentions: &MentionPolicy,
+    ) -> Result<(), SendError> {
+        let mut attempt = 0;
+        loop {
+            match self.http.edit(channel_id, message_id, text, mentions).await {
+                Ok(()) => return Ok(()),
+                Err(err) => self.wait_to_retry(err, &mut attempt).await?,
+            }
+        }
+    }
+
+    /// Sends `files` in one message after a reply's chunks.
+    pub async fn send_files(
+        &self,
+        channel_id: ChannelId,
+        files: &[Attachment],
+        mentions: &MentionPolicy,
+    ) -> Result<MessageId, SendError> {
+        let mut attempt = 0;
+        loop {
+            match self.http.send_files(channel_id, "", files, mentions).await {
+                Ok(id) => return Ok(id),
+                Err(err) => self.wait_to_retry(err, &mut attempt).await?,
+            }
+        }
+    }
+
+    /// Sends `chunks` in order, stopping at the first that fails for good.
+    pub async fn send_chunks(
+        &self,
+        channel_id: ChannelId,
+        chunks: &[String],
+        mentions: &MentionPolicy,
+    ) -> Delivery {
+        let mut sent = Vec::with_capacity(chunks.len());
+        for chunk in chunks {
+            match self.send(channel_id, chunk, mentions).await {
+                Ok(id) => sent.push(id),
+                Err(err) => {
+                    warn!(
+                        %err,
+                        sent = sent.len(),
+                        total = chunks.len(),
+                        "Aborted reply, later chunks were not sent"
+                    );
+                    return Delivery {
+                        sent,
+                        error: Some(err),
+                    };
+                }
+            }
+        }
+        Delivery { sent, error: None }
+    }
+
+    /// Waits before the next attempt of a failed call, or returns the error
+    /// when it is permanent or out of retries.
+    async fn wait_to_retry(&self, err: SendError, attempt: &mut u32) -> Result<(), SendError> {
+        let delay = match &err {
+            _ if *attempt >= self.policy.max_retries => None,
+            SendError::RateLimited { retry_after } => {
+                self.metrics.rate_limited.fetch_add(1, Ordering::Relaxed);
+                Some(retry_after.unwrap_or_else(|| self.policy.backoff(*attempt)))
+            }
+            SendError::Transient(_) => Some(self.policy.backoff(*attempt)),
+            SendError::Permanent(_) => None,
+        };
+        let Some(delay) = delay else {
+            self.metrics.failures.fetch_add(1, Ordering::Relaxed);
+            return Err(err);
+        };
+
+        self.metrics.retries.fetch_add(1, Ordering::Relaxed);
+        warn!(%err, attempt = *attempt, ?delay, "Retrying Discord call");
+        *attempt += 1;
+        tokio::time::sleep(delay).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, sync::Mutex};
+
+    use tokio::time::Instant;
+
+    use super::*;
+
+    /// Answers each call with the next scripted result, `Ok` once they run
+    /// out, and records the text of the calls that succeeded.
+    #[derive(Default)]
+    struct FakeHttp {
+        results: Mutex<VecDeque<Result<(), SendError>>>,
+        delivered: Mutex<Vec<String>>,
+        calls: Mutex<usize>,
+    }
+
+    impl FakeHttp {
+        fn new(results: impl IntoIterator<Item = Result<(), SendError>>) -> Self {
+            Self {
+                results: Mutex::new(results.into_iter().collect()),
+                ..Default::default()
+            }
+        }
+
+        fn call(&self, text: &str) -> Result<u64, SendError> {
+            *self.calls.lock().unwrap() += 1;
+            self.results.lock().unwrap().pop_front().unwrap_or(Ok(()))?;
+            let mut delivered = self.delivered.lock().unwrap();
+            delivered.push(text.to_string());
+            Ok(delivered.len() as u64)
+        }
+    }
+
+    #[async_trait]
+    impl DiscordHttp for FakeHttp {
+        async fn send(
+            &self,
+            _channel_id: ChannelId,
+            text: &str,
+            _mentions: &MentionPolicy,
+        ) -> Result<MessageId, SendError> {
+            self.call(text).map(MessageId::new)
+        }
+
+        async fn edit(
+            &self,
+            _channel_id: ChannelId,
+            _message_id: MessageId,
+            text: &str,
+            _mentions: &MentionPolicy,
+        ) -> Result<(), SendError> {
+            self.call(text).map(|_| ())
+        }
+
+        async fn send_files(
+            &self,
+            _channel_id: ChannelId,
+            _text: &str,
+            files: &[Attachment],
+            _mentions: &MentionPolicy,
+        ) -> Result<MessageId, SendError> {
+            let names = files.iter().map(|file| file.name.as_str());
+            self.call(&names.collect::<Vec<_>>().join(","))
+                .map(MessageId::new)
+        }
+    }
+
+    fn chunks() -> Vec<String> {
+        ["one", "two", "three"].map(String::from).to_vec()
+    }
+
+    fn outbound(http: &Arc<FakeHttp>) -> Outbound {
+        Outbound::new(http.clone()).with_policy(DeliveryPolicy {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(4),
+            max_retries: 3,
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limited_chunk_is_retried_in_order() {
+        let http = Arc::new(FakeHttp::new([
+            Ok(()),
+            Err(SendError::RateLimited {
+                retry_after: Some(Duration::from_secs(7)),
+            }),
+            Err(SendError::Transient("502 Bad Gateway".to_string())),
+        ]));
+        let outbound = outbound(&http);
+        let start = Instant::now();
+
+        let delivery = outbound
+            .send_chunks(ChannelId::new(1), &chunks(), &MentionPolicy::none())
+            .await;
+        assert!(delivery.is_complete());
+        assert_eq!(delivery.sent, [1, 2, 3].map(MessageId::new),);
+        assert_eq!(*http.delivered.lock().unwrap(), chunks());
+        // The retry after of the rate limit, then the second backoff step
+        assert_eq!(start.elapsed(), Duration::from_secs(7 + 2));
+        assert_eq!(outbound.metrics().retries(), 2);
+        assert_eq!(outbound.metrics().rate_limited(), 1);
+        assert_eq!(outbound.metrics().failures(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failed_chunk_aborts_the_rest() {
+        let http = Arc::new(FakeHttp::new([
+            Ok(()),
+            Err(SendError::Permanent("50035 Invalid Form Body".to_string())),
+        ]));
+        let outbound = outbound(&http);
+
+        let chunks = chunks();
+        let delivery = outbound
+            .send_chunks(ChannelId::new(1), &chunks, &MentionPolicy::none())
+            .await;
+        assert_eq!(delivery.sent, [MessageId::new(1)]);
+        assert!(matches!(delivery.error, Some(SendError::Permanent(_))));
+        assert_eq!(delivery.remaining(&chunks), ["two", "three"]);
+        assert_eq!(*http.delivered.lock().unwrap(), ["one"]);
+        assert_eq!(*http.calls.lock().unwrap(), 2);
+        assert_eq!(outbound.metrics().retries(), 0);
+        assert_eq!(outbound.metrics().failures(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retries_are_capped() {
+        let unavailable = || Err(SendError::Transient("503 Service Unavailable".to_string()));
+        let http = Arc::new(FakeHttp::new([
+            unavailable(),
+            unavailable(),
+            unavailable(),
+            unavailable(),
+        ]));
+        let outbound = outbound(&http);
+        let start = Instant::now();
+
+        let result = outbound
+            .edit(
+                ChannelId::new(1),
+                MessageId::new(9),
+                "edited",
+                &MentionPolicy::none(),
+            )
+            .await;
+        assert!(matches!(result, Err(SendError::Transient(_))));
+        // Backoff of 1, 2 and 4 seconds, capped at 4
+        assert_eq!(start.elapsed(), Duration::from_secs(1 + 2 + 4));
+        assert_eq!(*http.calls.lock().unwrap(), 4);
+        assert!(http.delivered.lock().unwrap().is_empty());
+        assert_eq!(outbound.metrics().retries(), 3);
+        assert_eq!(outbound.metrics().failures(), 1);
+
+        // The next call starts over
+        let sent = outbound
+            .send(ChannelId::new(1), "hello", &MentionPolicy::none())
+            .await;
+        assert_eq!(sent, Ok(MessageId::new(1)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_files_follow_the_chunks() {
+        let http = Arc::new(FakeHttp::new([
+            Ok(()),
+            Ok(()),
+            Ok(()),
+            Err(SendError::Transient("502 Bad Gateway".to_string())),
+        ]));
+        let outbound = outbound(&http);
+        let files = ["snippet.rs", "snippet-2.toml"].map(|name| Attachment {
+            name: name.to_string(),
+            content: "fn main() {}\n".to_string(),
+            truncated: false,
+        });
+
+        let delivery = outbound
+            .send_chunks(ChannelId::new(1), &chunks(), &MentionPolicy::none())
+            .await;
+        assert!(delivery.is_complete());
+        let sent = outbound
+            .send_files(ChannelId::new(1), &files, &MentionPolicy::none())
+            .await;
+        assert_eq!(sent, Ok(MessageId::new(4)));
+        assert_eq!(
+            *http.delivered.lock().unwrap(),
+            ["one", "two", "three", "snippet.rs,snippet-2.toml"]
+        );
+        assert_eq!(outbound.metrics().retries(), 1);
+    }
+}
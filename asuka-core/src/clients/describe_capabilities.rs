@@ -0,0 +1,92 @@
+//! Tool letting the model look up what the agent can do in one area, beyond
+//! the summary added to the prompt, see [crate::capabilities].
+
+use std::{convert::Infallible, sync::Arc};
+
+use arc_swap::ArcSwap;
+use rig::{completion::ToolDefinition, tool::Tool};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::capabilities::{Capabilities, AREAS};
+
+/// What the model is told the tool does, also listed with the tools of the
+/// [Capabilities].
+pub const DESCRIPTION: &str = "Look up what you can actually do: your tools and who may use \
+    them, your knowledge sources, platforms, permissions and rate limits. Use it before telling \
+    a user you can or can't do something.";
+
+#[derive(Debug, Deserialize)]
+pub struct DescribeCapabilitiesArgs {
+    /// One of [AREAS] or a tool or source name. Everything when missing.
+    #[serde(default)]
+    pub area: Option<String>,
+}
+
+/// Describes the agent's current [Capabilities], as updated by reloads and
+/// tool registrations.
+pub struct DescribeCapabilities {
+    capabilities: Arc<ArcSwap<Capabilities>>,
+}
+
+impl DescribeCapabilities {
+    pub fn new(capabilities: Arc<ArcSwap<Capabilities>>) -> Self {
+        Self { capabilities }
+    }
+}
+
+impl Tool for DescribeCapabilities {
+    const NAME: &'static str = "describe_capabilities";
+
+    type Error = Infallible;
+    type Args = DescribeCapabilitiesArgs;
+    type Output = String;
+
+    async fn definition(&self, _prompt: String) -> ToolDefinition {
+        ToolDefinition {
+            name: Self::NAME.to_string(),
+            description: DESCRIPTION.to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "area": {
+                        "type": "string",
+                        "description": format!(
+                            "One of {}, or the name of a tool or knowledge source. \
+                             Leave out for everything.",
+                            AREAS.join(", ")
+                        )
+                    }
+                }
+            }),
+        }
+    }
+
+    async fn call(&self, args: Self::Args) -> Result<String, Infallible> {
+        Ok(self.capabilities.load().describe(args.area.as_deref()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{capabilities::ToolCapability, permissions::PermissionTier};
+
+    #[tokio::test]
+    async fn test_describes_current_capabilities() {
+        let capabilities = Arc::new(ArcSwap::from_pointee(Capabilities::default()));
+        let tool = DescribeCapabilities::new(capabilities.clone());
+        let args = |area: &str| DescribeCapabilitiesArgs {
+            area: Some(area.to_string()),
+        };
+        assert_eq!(
+            tool.call(args("tools")).await.unwrap(),
+            "No tools are registered."
+        );
+
+        let own = ToolCapability::of(&tool, PermissionTier::User).await;
+        capabilities.store(Arc::new(Capabilities::default().with_tool(own)));
+        let described = tool.call(args("describe_capabilities")).await.unwrap();
+        assert!(described.starts_with("describe_capabilities, offered to Everyone: Look up"));
+    }
+}
@@ -1,47 +1,1007 @@
-use rig::{
-    completion::{CompletionModel, Prompt},
-    embeddings::EmbeddingModel,
-};
+use arc_swap::ArcSwap;
+use rig::{completion::CompletionModel, embeddings::EmbeddingModel};
+use serde::Deserialize;
 use serenity::async_trait;
-use serenity::model::channel::Message;
+use serenity::builder::GetMessages;
+use serenity::gateway::{ActivityData, GatewayError, ShardMessenger};
+use serenity::http::Http;
+use serenity::model::channel::{Channel, ChannelType, GuildChannel, Message, ReactionType};
+use serenity::model::event::ResumedEvent;
 use serenity::model::gateway::GatewayIntents;
 use serenity::model::gateway::Ready;
+use serenity::model::guild::{Guild, UnavailableGuild};
+use serenity::model::id::{ChannelId, MessageId, RoleId, UserId};
+use serenity::model::user::OnlineStatus;
 use serenity::prelude::*;
-use std::collections::HashSet;
-use tracing::{debug, error, info};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
-use crate::{agent::Agent, attention::AttentionCommand};
 use crate::{
-    attention::{Attention, AttentionContext},
-    knowledge,
+    agent::Agent,
+    attachments::Attachment,
+    attention::Attention,
+    clients::{
+        delivery::{DeliveryMetrics, DeliveryPolicy, Outbound, SendError},
+        external::{Client, IncomingMessage},
+        forum::ForumPost,
+        guilds::{self, GuildPolicy, LISTEN_SETTING},
+        mentions::{self, MentionPolicy},
+        post_tweet::TweetPoster,
+        presence::{Presence, PresenceConfig, PresenceManager, PresenceSink},
+        reactions::{ReactionConfig, ReactionError},
+        streaming::{ReplySink, StreamingCompletion, StreamingConfig},
+        supervisor::{self, ConnectionState, Gateway, ReconnectPolicy},
+    },
+    commands::{self, Command},
+    config::{ConfigError, ConfigFile},
+    corrections,
+    digest::DigestSink,
+    escalation::{EscalationConfig, EscalationNotice, EscalationPoster},
+    hooks::SuppressMassMentions,
+    knowledge::{self, ErasureOptions},
+    linking::{self, LinkingConfig},
+    outage::OutageNotices,
+    permissions::{PermissionTier, Permissions},
+    pipeline::{BatchConfig, Pipeline},
+    quoted::{self, QuotedContent},
+    rate_limit::RateLimitConfig,
+    reporting::ReportSink,
+    research::ResearchConfig,
+    say::{self, SayError, SayRequest},
+    startup::ProgressSink,
+    status::StatusBoard,
+    summarize::{SummarizeConfig, Summarizer},
+    templates,
 };
 
-const MIN_CHUNK_LENGTH: usize = 100;
-const MAX_MESSAGE_LENGTH: usize = 1500;
-const MAX_HISTORY_MESSAGES: i64 = 10;
+pub use super::chunks::{chunk_message, MIN_CHUNK_LENGTH};
+
+/// Longest chunk a reply is split into, below Discord's 2000 characters.
+pub const MAX_MESSAGE_LENGTH: usize = 1500;
+/// Longest message Discord accepts, which an edited message must fit in.
+pub const MESSAGE_LIMIT: usize = 2000;
+/// New forum posts remembered as answered, see [DiscordClient::claim_post].
+const ANSWERED_POSTS: usize = 256;
+/// Linked messages of one message fetched as quotes, see
+/// [DiscordClient::linked_quotes].
+const MAX_LINKED_MESSAGES: usize = 3;
+
+/// Settings read on every message, so a config reload applies straight away.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscordClientConfig {
+    /// Guild channel ids the bot listens in. Empty means every channel;
+    /// direct messages are always allowed.
+    pub allowed_channels: Vec<String>,
+    /// Forum channel ids whose new posts are always answered, without
+    /// waiting for the attention decision.
+    pub auto_answer_forums: Vec<String>,
+    /// Handling of guilds the bot joins and leaves.
+    pub guilds: GuildPolicy,
+    /// Role ids replies may ping, by guild id. Other roles, `@everyone` and
+    /// `@here` never ping.
+    pub mention_roles: HashMap<String, Vec<String>>,
+}
+
+impl DiscordClientConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let lists = [
+            ("allowed_channels", &self.allowed_channels),
+            ("auto_answer_forums", &self.auto_answer_forums),
+        ];
+        match lists.iter().find_map(|(name, ids)| {
+            ids.iter()
+                .find(|id| id.parse::<u64>().is_err())
+                .map(|id| (name, id))
+        }) {
+            Some((name, id)) => Err(format!("{name}: {id:?} is not a channel id")),
+            None => match self.mention_roles.iter().find_map(|(guild_id, roles)| {
+                std::iter::once(guild_id)
+                    .chain(roles)
+                    .find(|id| id.parse::<u64>().is_err())
+            }) {
+                Some(id) => Err(format!("mention_roles: {id:?} is not a guild or role id")),
+                None => self.guilds.validate(),
+            },
+        }
+    }
+
+    /// Roles replies in `guild_id` may ping.
+    pub fn mention_roles(&self, guild_id: Option<&str>) -> &[String] {
+        guild_id
+            .and_then(|id| self.mention_roles.get(id))
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    pub fn allows(&self, channel_id: &str, direct_message: bool) -> bool {
+        direct_message
+            || self.allowed_channels.is_empty()
+            || self.allowed_channels.iter().any(|id| id == channel_id)
+    }
+
+    pub fn auto_answers(&self, forum_id: &str) -> bool {
+        self.auto_answer_forums.iter().any(|id| id == forum_id)
+    }
+}
+
+/// Channels whose recent history is ingested after reconnecting, so messages
+/// sent while the bot was offline still reach the knowledge base.
+#[derive(Clone, Debug)]
+pub struct CatchUp {
+    pub channels: Vec<ChannelId>,
+    /// Most recent messages fetched per channel, at most 100.
+    pub limit: u8,
+}
 
 #[derive(Clone)]
 pub struct DiscordClient<M: CompletionModel, E: EmbeddingModel + 'static> {
-    agent: Agent<M, E>,
-    attention: Attention<M>,
+    pipeline: Pipeline<M, M, E>,
+    config: Arc<ArcSwap<DiscordClientConfig>>,
+    config_path: Option<PathBuf>,
+    /// Application owner, the only user allowed to reload the config.
+    owner: Arc<Mutex<Option<String>>>,
+    permissions: Permissions,
+    summarize: SummarizeConfig,
+    reconnect: ReconnectPolicy,
+    catch_up: Option<CatchUp>,
+    state: Arc<watch::Sender<ConnectionState>>,
+    shutdown: CancellationToken,
+    disconnected_at: Arc<Mutex<Option<chrono::DateTime<chrono::Utc>>>>,
+    linking: Option<LinkingConfig>,
+    answered_posts: Arc<Mutex<VecDeque<ChannelId>>>,
+    delivery: DeliveryPolicy,
+    delivery_metrics: DeliveryMetrics,
+    presence: Option<(PresenceConfig, StatusBoard)>,
+    /// Stops the presence manager of the previous session.
+    presence_task: Arc<Mutex<Option<CancellationToken>>>,
+    /// Stops the deferred answers of the previous session, see
+    /// [Pipeline::spawn_follow_ups].
+    follow_up_task: Arc<Mutex<Option<CancellationToken>>>,
 }
 
 impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> DiscordClient<M, E> {
     pub fn new(agent: Agent<M, E>, attention: Attention<M>) -> Self {
-        Self { agent, attention }
+        agent.update_capabilities(|capabilities| capabilities.with_platform("discord"));
+        let agent = agent
+            .with_response_hook(SuppressMassMentions)
+            .with_source(knowledge::Source::Discord);
+        Self {
+            pipeline: Pipeline::new(agent, attention),
+            config: Arc::new(ArcSwap::from_pointee(DiscordClientConfig::default())),
+            config_path: None,
+            owner: Arc::new(Mutex::new(None)),
+            permissions: Permissions::default(),
+            summarize: SummarizeConfig::default(),
+            reconnect: ReconnectPolicy::default(),
+            catch_up: None,
+            state: Arc::new(watch::channel(ConnectionState::Stopped).0),
+            shutdown: CancellationToken::new(),
+            disconnected_at: Arc::new(Mutex::new(None)),
+            linking: None,
+            answered_posts: Arc::new(Mutex::new(VecDeque::new())),
+            delivery: DeliveryPolicy::default(),
+            delivery_metrics: DeliveryMetrics::default(),
+            presence: None,
+            presence_task: Arc::new(Mutex::new(None)),
+            follow_up_task: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn with_config(self, config: DiscordClientConfig) -> Self {
+        self.config.store(Arc::new(config));
+        self
+    }
+
+    /// Config file re-read by the owner-only `/reload-config` command.
+    pub fn with_config_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config_path = Some(path.into());
+        self
+    }
+
+    /// Re-reads the attention and client settings from `path` and swaps them
+    /// in. On error the current settings stay in place.
+    pub fn reload_config(&self, path: &Path) -> Result<(), ConfigError> {
+        let file = ConfigFile::from_file(path)?;
+
+        let mut attention = file.attention;
+        attention.bot_names = self.pipeline.attention().config().bot_names.clone();
+        self.pipeline.attention().set_config(attention);
+        self.config.store(Arc::new(file.discord));
+        self.agent().prompt_cache().invalidate();
+
+        info!(path = %path.display(), "Reloaded config");
+        Ok(())
+    }
+
+    async fn reload_command(&self, author: &str) -> String {
+        if self.owner.lock().unwrap().as_deref() != Some(author) {
+            info!(author, "Ignoring config reload from non-owner");
+            return "Only the bot owner can reload the config.".to_string();
+        }
+        let Some(path) = &self.config_path else {
+            return "No config file to reload.".to_string();
+        };
+
+        match self.reload_config(path) {
+            Ok(()) => {
+                if let Err(err) = self.agent().refresh_capabilities().await {
+                    error!(?err, "Failed to recount documents of the capabilities");
+                }
+                "Config reloaded.".to_string()
+            }
+            Err(err) => {
+                error!(?err, "Failed to reload config");
+                format!("Config not reloaded: {err}")
+            }
+        }
+    }
+
+    /// Erases what is stored about a Discord user, or only previews it until
+    /// confirmed. `channel_id` is where the command was run.
+    async fn erase_user_command(
+        &self,
+        author: &str,
+        channel_id: &str,
+        user_id: &str,
+        confirm: bool,
+    ) -> String {
+        if self.owner.lock().unwrap().as_deref() != Some(author) {
+            info!(author, "Ignoring user erasure from non-owner");
+            return "Only the bot owner can erase users.".to_string();
+        }
+        let options = ErasureOptions {
+            dry_run: !confirm,
+            ..Default::default()
+        };
+
+        match self
+            .agent
+            .knowledge()
+            .erase_user(&knowledge::Source::Discord, user_id, &options)
+            .await
+        {
+            Ok(report) if confirm => {
+                info!(author, user_id, rows = report.total(), "Erased user");
+                format!("Erased user {user_id}.\n{report}")
+            }
+            Ok(report) if report.tables.is_empty() => report.to_string(),
+            Ok(report) => {
+                let command = format!("/erase-user {user_id} --confirm");
+                let confirm = self
+                    .agent
+                    .localized_template(
+                        templates::CONFIRM_ACTION,
+                        Some(author),
+                        channel_id,
+                        &[
+                            ("action", &format!("erase user {user_id}")),
+                            ("command", &command),
+                        ],
+                    )
+                    .await;
+                format!("{report}\n{confirm}")
+            }
+            Err(err) => {
+                error!(?err, "Failed to erase user");
+                self.agent()
+                    .localized_template(templates::ERROR_GENERIC, Some(author), channel_id, &[])
+                    .await
+            }
+        }
+    }
+
+    /// Discord user ids allowed to run admin commands.
+    pub fn with_admins(mut self, admins: impl IntoIterator<Item = String>) -> Self {
+        self.permissions.admins = admins.into_iter().collect();
+        self
+    }
+
+    /// Discord user ids allowed to trigger actions outside Discord, such as
+    /// posting tweets. Admins are always trusted.
+    pub fn with_trusted(mut self, trusted: impl IntoIterator<Item = String>) -> Self {
+        self.permissions.trusted = trusted.into_iter().collect();
+        self
+    }
+
+    /// Lets trusted users have the agent draft and post tweets.
+    pub fn with_tweet_poster(mut self, poster: Arc<dyn TweetPoster>) -> Self {
+        self.pipeline = self.pipeline.with_tweet_poster(poster);
+        self
+    }
+
+    /// Lets trusted users re-sync the sources of `registry` from chat.
+    pub fn with_refresh_registry(mut self, registry: knowledge::RefreshRegistry<E>) -> Self {
+        self.pipeline = self.pipeline.with_refresh_registry(registry);
+        self
+    }
+
+    /// Settings for `/summarize [hours]`.
+    pub fn with_summarize_config(mut self, config: SummarizeConfig) -> Self {
+        self.summarize = config;
+        self
+    }
+
+    /// Answers `/ask-deep`, and mentions asking to take the time, with deep
+    /// research, see [crate::research].
+    pub fn with_research(mut self, config: ResearchConfig) -> Self {
+        self.pipeline = self.pipeline.with_research(config);
+        self
+    }
+
+    /// Answers rapid-fire messages of a user together, see
+    /// [Debouncer](crate::pipeline::Debouncer).
+    pub fn with_batching(mut self, config: BatchConfig) -> Self {
+        self.pipeline = self.pipeline.with_batching(config);
+        self
+    }
+
+    pub fn with_reconnect_policy(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect = policy;
+        self
+    }
+
+    /// Retries of sends and edits Discord rate limits or fails to serve.
+    pub fn with_delivery_policy(mut self, policy: DeliveryPolicy) -> Self {
+        self.delivery = policy;
+        self
+    }
+
+    /// Keeps the bot's presence in line with `status`, see
+    /// [crate::clients::presence].
+    pub fn with_presence(mut self, config: PresenceConfig, status: StatusBoard) -> Self {
+        self.presence = Some((config, status));
+        self
+    }
+
+    /// Tells channels when answers are slow or fail for lack of provider
+    /// capacity, and answers them once it recovers, see [crate::outage].
+    pub fn with_outage_notices(mut self, outage: OutageNotices) -> Self {
+        self.pipeline = self.pipeline.with_outage_notices(outage);
+        self
     }
 
+    pub fn with_catch_up(mut self, catch_up: CatchUp) -> Self {
+        self.catch_up = Some(catch_up);
+        self
+    }
+
+    /// Streams replies into a single message that is edited as text arrives,
+    /// using `model` for the completion.
+    pub fn with_streaming(
+        mut self,
+        config: StreamingConfig,
+        model: Arc<dyn StreamingCompletion>,
+    ) -> Self {
+        self.pipeline = self.pipeline.with_streaming(config, model);
+        self
+    }
+
+    /// Lets the agent answer with a reaction instead of a message. Streamed
+    /// replies are always sent as text.
+    pub fn with_reactions(mut self, config: ReactionConfig) -> Self {
+        self.pipeline = self.pipeline.with_reactions(config);
+        self
+    }
+
+    /// Hands conversations to the support team, see [crate::escalation].
+    pub fn with_escalation(mut self, config: EscalationConfig) -> Self {
+        self.pipeline = self.pipeline.with_escalation(config);
+        self
+    }
+
+    /// Limits answers per user, see [crate::rate_limit].
+    pub fn with_rate_limit(mut self, config: RateLimitConfig) -> Self {
+        self.pipeline = self.pipeline.with_rate_limit(config);
+        self
+    }
+
+    /// Answers `/link` and `/unlink`, see [crate::linking].
+    pub fn with_linking(mut self, config: LinkingConfig) -> Self {
+        self.linking = Some(config);
+        self
+    }
+
+    /// Token that stops the bot once cancelled.
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.pipeline = self.pipeline.with_shutdown(shutdown.clone());
+        self.shutdown = shutdown;
+        self
+    }
+
+    fn agent(&self) -> &Agent<M, E> {
+        self.pipeline.agent()
+    }
+
+    pub fn connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.state.subscribe()
+    }
+
+    /// Retried and failed sends, across all messages.
+    pub fn delivery_metrics(&self) -> &DeliveryMetrics {
+        &self.delivery_metrics
+    }
+
+    /// Sends through `http`, with the retries of the delivery policy.
+    fn outbound(&self, http: &Arc<Http>) -> Outbound {
+        Outbound::new(http.clone())
+            .with_policy(self.delivery.clone())
+            .with_metrics(self.delivery_metrics.clone())
+    }
+
+    /// Runs the bot until shutdown, reconnecting after gateway failures.
     pub async fn start(&self, token: &str) -> Result<(), serenity::Error> {
-        let intents = GatewayIntents::GUILD_MESSAGES
+        let gateway = SerenityGateway {
+            client: self.clone(),
+            token: token.to_string(),
+        };
+
+        info!("Starting discord bot");
+        let result =
+            supervisor::supervise(&gateway, &self.reconnect, &self.state, &self.shutdown).await;
+        self.pipeline.close_batches();
+        self.agent().conversations().flush().await;
+        result
+    }
+
+    /// The forum post `channel_id` belongs to, if it is a thread in a forum
+    /// channel.
+    async fn forum_post(&self, ctx: &Context, channel_id: ChannelId) -> Option<ForumPost> {
+        let thread = match channel_id.to_channel(ctx).await {
+            Ok(Channel::Guild(thread)) => thread,
+            Ok(_) => return None,
+            Err(err) => {
+                error!(?err, "Failed to fetch channel");
+                return None;
+            }
+        };
+        let parent_id = thread.parent_id?;
+        match parent_id.to_channel(ctx).await {
+            Ok(Channel::Guild(parent)) => ForumPost::new(&thread, &parent),
+            Ok(_) => None,
+            Err(err) => {
+                error!(?err, "Failed to fetch parent channel");
+                None
+            }
+        }
+    }
+
+    /// Messages `msg` links to, as quotes. Only links within its server, or
+    /// its direct message channel, are followed, see [quoted::MessageLink::is_within].
+    async fn linked_quotes(&self, ctx: &Context, msg: &Message) -> Vec<QuotedContent> {
+        let guild_id = msg.guild_id.map(|id| id.get());
+        let mut quotes = Vec::new();
+        for link in quoted::message_links(&msg.content)
+            .into_iter()
+            .filter(|link| link.is_within(guild_id, msg.channel_id.get()))
+            .take(MAX_LINKED_MESSAGES)
+        {
+            let channel_id = ChannelId::new(link.channel_id);
+            let linked = match channel_id
+                .message(ctx, MessageId::new(link.message_id))
+                .await
+            {
+                Ok(linked) => linked,
+                Err(err) => {
+                    debug!(?err, ?link, "Failed to fetch linked message");
+                    continue;
+                }
+            };
+            if linked.content.trim().is_empty() {
+                continue;
+            }
+            let mut quote =
+                QuotedContent::new(linked.content).with_original_author(linked.author.name);
+            if let Ok(name) = channel_id.name(ctx).await {
+                quote = quote.with_original_channel(format!("#{name}"));
+            }
+            quotes.push(quote);
+        }
+        quotes
+    }
+
+    /// Claims the auto-answer of a new forum post. Its starter message can
+    /// come from both the message and the thread create event, only the
+    /// first claim answers it.
+    fn claim_post(&self, thread_id: ChannelId) -> bool {
+        let mut answered = self.answered_posts.lock().unwrap();
+        if answered.contains(&thread_id) {
+            return false;
+        }
+        if answered.len() == ANSWERED_POSTS {
+            answered.pop_front();
+        }
+        answered.push_back(thread_id);
+        true
+    }
+
+    /// Whether the bot listens in `channel_id`, see [LISTEN_SETTING].
+    async fn listening(&self, channel_id: ChannelId) -> bool {
+        let setting = self
+            .agent
+            .knowledge()
+            .channel_setting(&channel_id.to_string(), LISTEN_SETTING)
+            .await;
+        match setting {
+            Ok(value) => value.as_deref() != Some("off"),
+            Err(err) => {
+                error!(?err, "Failed to read listen setting");
+                true
+            }
+        }
+    }
+
+    /// Posts the `/say` announcement `request` and returns the reply to its
+    /// author. It is sent like an answer, but never pings anyone.
+    async fn say(&self, ctx: &Context, author: UserId, request: SayRequest) -> String {
+        if let Err(err) = say::authorize(self.permissions.tier(&request.invoked_by)) {
+            info!(author = request.invoked_by, "Ignoring /say from non-admin");
+            return err.to_string();
+        }
+        let Some(channel) = self
+            .postable_channel(ctx, author, &request.channel_id)
+            .await
+        else {
+            return SayError::NoAccess.to_string();
+        };
+
+        let announcement = match say::prepare(self.agent(), &request).await {
+            Ok(announcement) => announcement,
+            Err(err) => {
+                error!(?err, "Failed to prepare announcement");
+                return self
+                    .agent
+                    .localized_template(
+                        templates::ERROR_GENERIC,
+                        Some(&request.invoked_by),
+                        &request.channel_id,
+                        &[],
+                    )
+                    .await;
+            }
+        };
+        let chunks = chunk_message(
+            &mentions::sanitize(&announcement.text),
+            MAX_MESSAGE_LENGTH,
+            MIN_CHUNK_LENGTH,
+        );
+        let delivery = self
+            .outbound(&ctx.http)
+            .send_chunks(channel.id, &chunks, &MentionPolicy::none())
+            .await;
+        let sent = delivery
+            .sent
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        let channel_type = if channel.thread_metadata.is_some() {
+            knowledge::ChannelType::Thread
+        } else {
+            knowledge::ChannelType::Text
+        };
+        say::record_delivery(
+            self.agent().knowledge(),
+            &announcement,
+            knowledge::Source::Discord,
+            channel_type,
+            &ctx.cache.current_user().id.to_string(),
+            &chunks,
+            &sent,
+        )
+        .await;
+
+        match delivery.error {
+            None => format!("Posted in <#{}>.", channel.id),
+            Some(err) => format!(
+                "Posted {} of {} messages in <#{}>: {err}",
+                sent.len(),
+                chunks.len(),
+                channel.id
+            ),
+        }
+    }
+
+    /// The server channel `channel_id`, if `user_id` may read and send
+    /// messages in it.
+    async fn postable_channel(
+        &self,
+        ctx: &Context,
+        user_id: UserId,
+        channel_id: &str,
+    ) -> Option<GuildChannel> {
+        let channel_id = channel_id
+            .parse::<u64>()
+            .ok()
+            .filter(|id| *id != 0)
+            .map(ChannelId::new)?;
+        let channel = match channel_id.to_channel(ctx).await {
+            Ok(Channel::Guild(channel)) => channel,
+            Ok(_) => return None,
+            Err(err) => {
+                debug!(?err, "Failed to fetch channel");
+                return None;
+            }
+        };
+        let member = match channel.guild_id.member(ctx, user_id).await {
+            Ok(member) => member,
+            Err(err) => {
+                debug!(?err, "Failed to fetch member");
+                return None;
+            }
+        };
+        let permissions = ctx
+            .cache
+            .guild(channel.guild_id)?
+            .user_permissions_in(&channel, &member);
+        (permissions.view_channel() && permissions.send_messages()).then_some(channel)
+    }
+
+    async fn catch_up(&self, ctx: &Context, since: chrono::DateTime<chrono::Utc>) {
+        let Some(catch_up) = &self.catch_up else {
+            return;
+        };
+
+        for channel_id in &catch_up.channels {
+            let messages = match channel_id
+                .messages(&ctx.http, GetMessages::new().limit(catch_up.limit))
+                .await
+            {
+                Ok(messages) => messages,
+                Err(err) => {
+                    error!(?err, %channel_id, "Failed to fetch missed messages");
+                    continue;
+                }
+            };
+
+            // Messages are keyed by id, so storing one twice is harmless.
+            let missed = messages
+                .into_iter()
+                .filter(|msg| !msg.author.bot && *msg.timestamp >= since)
+                .collect::<Vec<_>>();
+            info!(%channel_id, count = missed.len(), "Catching up on missed messages");
+
+            for msg in missed {
+                if let Err(err) = self.agent().knowledge().create_message(msg.into()).await {
+                    error!(?err, "Failed to store missed message");
+                }
+            }
+        }
+    }
+}
+
+/// Posts escalation notices in guild channels.
+struct ChannelPoster {
+    outbound: Outbound,
+}
+
+#[async_trait]
+impl EscalationPoster for ChannelPoster {
+    async fn post(&self, notice: &EscalationNotice) -> Result<(), String> {
+        let channel_id = notice
+            .channel_id
+            .parse::<u64>()
+            .map_err(|_| format!("{:?} is not a channel id", notice.channel_id))?;
+        let account_id = notice
+            .account_id
+            .parse::<u64>()
+            .map_err(|_| format!("{:?} is not a user id", notice.account_id))?;
+        let mut mentions = MentionPolicy::participants(UserId::new(account_id), [], &[]);
+        if let Some(role_id) = notice.role_id.as_deref().and_then(|id| id.parse().ok()) {
+            mentions = mentions.with_role(RoleId::new(role_id));
+        }
+        self.outbound
+            .send(ChannelId::new(channel_id), &notice.text, &mentions)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Sends digests to a user as a DM, see [crate::digest].
+pub struct DmDigest {
+    http: Arc<Http>,
+    user_id: UserId,
+}
+
+impl DmDigest {
+    /// Sends as the bot with `token` to the user `user_id`.
+    pub fn new(token: &str, user_id: u64) -> Self {
+        Self {
+            http: Arc::new(Http::new(token)),
+            user_id: UserId::new(user_id),
+        }
+    }
+}
+
+#[async_trait]
+impl DigestSink for DmDigest {
+    async fn deliver(&self, digest: &str) -> Result<(), String> {
+        let channel = self
+            .user_id
+            .create_dm_channel(&self.http)
+            .await
+            .map_err(|e| e.to_string())?;
+        let chunks = chunk_message(digest, MAX_MESSAGE_LENGTH, MIN_CHUNK_LENGTH);
+        let delivery = Outbound::new(self.http.clone())
+            .send_chunks(channel.id, &chunks, &MentionPolicy::none())
+            .await;
+        delivery.error.map_or(Ok(()), |err| Err(err.to_string()))
+    }
+}
+
+/// Posts error reports to an operator channel, see [crate::reporting].
+pub struct ChannelReport {
+    http: Arc<Http>,
+    channel_id: ChannelId,
+}
+
+impl ChannelReport {
+    /// Sends as the bot with `token` to the channel `channel_id`.
+    pub fn new(token: &str, channel_id: u64) -> Self {
+        Self {
+            http: Arc::new(Http::new(token)),
+            channel_id: ChannelId::new(channel_id),
+        }
+    }
+}
+
+#[async_trait]
+impl ReportSink for ChannelReport {
+    async fn deliver(&self, report: &str) -> Result<(), String> {
+        Outbound::new(self.http.clone())
+            .send(self.channel_id, report, &MentionPolicy::none())
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Posts ingestion progress to an operator channel, editing it in place,
+/// see [crate::startup].
+pub struct ChannelProgress {
+    outbound: Outbound,
+    channel_id: ChannelId,
+}
+
+impl ChannelProgress {
+    /// Sends as the bot with `token` to the channel `channel_id`.
+    pub fn new(token: &str, channel_id: u64) -> Self {
+        Self {
+            outbound: Outbound::new(Arc::new(Http::new(token))),
+            channel_id: ChannelId::new(channel_id),
+        }
+    }
+}
+
+#[async_trait]
+impl ProgressSink for ChannelProgress {
+    async fn post(&self, text: &str) -> Result<String, String> {
+        self.outbound
+            .send(self.channel_id, text, &MentionPolicy::none())
+            .await
+            .map(|id| id.to_string())
+            .map_err(|e| e.to_string())
+    }
+
+    async fn edit(&self, id: &str, text: &str) -> Result<(), String> {
+        let id = id.parse::<u64>().map_err(|e| e.to_string())?;
+        self.outbound
+            .edit(
+                self.channel_id,
+                MessageId::new(id),
+                text,
+                &MentionPolicy::none(),
+            )
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[async_trait]
+impl PresenceSink for ShardMessenger {
+    async fn set_presence(&self, presence: &Presence) {
+        let status = if presence.idle {
+            OnlineStatus::Idle
+        } else {
+            OnlineStatus::Online
+        };
+        ShardMessenger::set_presence(
+            self,
+            Some(ActivityData::custom(presence.text.clone())),
+            status,
+        );
+    }
+}
+
+/// The channels the pipeline answers in, see [Pipeline::handle].
+struct Channels {
+    http: Arc<Http>,
+    outbound: Outbound,
+    mentions: MentionPolicy,
+    bot_id: String,
+    permissions: Permissions,
+    /// Application owner, who counts as an admin.
+    owner: Option<String>,
+}
+
+/// Parses a Discord id, which is never zero.
+fn parse_id(id: &str) -> Option<u64> {
+    id.parse::<u64>().ok().filter(|id| *id != 0)
+}
+
+#[async_trait]
+impl Client for Channels {
+    type Sink = ChannelSink;
+
+    fn source(&self) -> knowledge::Source {
+        knowledge::Source::Discord
+    }
+
+    fn account_id(&self) -> String {
+        self.bot_id.clone()
+    }
+
+    fn max_message_length(&self) -> usize {
+        MAX_MESSAGE_LENGTH
+    }
+
+    fn sink(&self, channel_id: &str) -> ChannelSink {
+        ChannelSink {
+            outbound: self.outbound.clone(),
+            channel_id: parse_id(channel_id).map(ChannelId::new),
+            mentions: self.mentions.clone(),
+        }
+    }
+
+    fn tier(&self, account_id: &str) -> PermissionTier {
+        match self.owner.as_deref() == Some(account_id) {
+            true => PermissionTier::Admin,
+            false => self.permissions.tier(account_id),
+        }
+    }
+
+    fn escalation_poster(&self) -> Option<Arc<dyn EscalationPoster>> {
+        Some(Arc::new(ChannelPoster {
+            outbound: self.outbound.clone(),
+        }))
+    }
+
+    async fn react(
+        &self,
+        channel_id: &str,
+        message_id: &str,
+        emoji: &str,
+    ) -> Result<(), ReactionError> {
+        let (Some(channel_id), Some(message_id)) = (parse_id(channel_id), parse_id(message_id))
+        else {
+            return Err(ReactionError::Unsupported);
+        };
+        ChannelId::new(channel_id)
+            .create_reaction(
+                &*self.http,
+                MessageId::new(message_id),
+                ReactionType::Unicode(emoji.to_string()),
+            )
+            .await
+            .map_err(|e| ReactionError::Failed(Box::new(e)))
+    }
+
+    async fn amend(
+        &self,
+        channel_id: &str,
+        message_id: &str,
+        original: &str,
+        notice: &str,
+    ) -> bool {
+        let (Some(channel_id), Some(message_id)) = (parse_id(channel_id), parse_id(message_id))
+        else {
+            return false;
+        };
+        let amended = amend_message(
+            &self.outbound,
+            ChannelId::new(channel_id),
+            MessageId::new(message_id),
+            original,
+            notice,
+        )
+        .await;
+        if let Err(why) = &amended {
+            warn!(
+                ?why,
+                "Failed to edit corrected answer, following up instead"
+            );
+        }
+        amended.is_ok()
+    }
+}
+
+struct ChannelSink {
+    outbound: Outbound,
+    /// None for an id that isn't a channel's, which nothing can be sent to.
+    channel_id: Option<ChannelId>,
+    mentions: MentionPolicy,
+}
+
+impl ChannelSink {
+    fn channel_id(&self) -> Result<ChannelId, SendError> {
+        self.channel_id
+            .ok_or_else(|| SendError::Permanent("not a channel id".to_string()))
+    }
+}
+
+#[async_trait]
+impl ReplySink for ChannelSink {
+    type Handle = MessageId;
+    type Error = SendError;
+
+    async fn send(&self, text: &str) -> Result<MessageId, SendError> {
+        self.outbound
+            .send(self.channel_id()?, text, &self.mentions)
+            .await
+    }
+
+    async fn edit(&self, handle: &MessageId, text: &str) -> Result<(), SendError> {
+        self.outbound
+            .edit(self.channel_id()?, *handle, text, &self.mentions)
+            .await
+    }
+
+    async fn send_files(&self, files: &[Attachment]) -> Result<Vec<MessageId>, SendError> {
+        self.outbound
+            .send_files(self.channel_id()?, files, &self.mentions)
+            .await
+            .map(|id| vec![id])
+    }
+}
+
+struct SerenityGateway<M: CompletionModel, E: EmbeddingModel + 'static> {
+    client: DiscordClient<M, E>,
+    token: String,
+}
+
+#[async_trait]
+impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> Gateway for SerenityGateway<M, E> {
+    type Error = serenity::Error;
+
+    async fn run(&self, shutdown: CancellationToken) -> Result<(), serenity::Error> {
+        let intents = GatewayIntents::GUILDS
+            | GatewayIntents::GUILD_MESSAGES
             | GatewayIntents::DIRECT_MESSAGES
             | GatewayIntents::MESSAGE_CONTENT;
 
-        let mut client = Client::builder(token, intents)
-            .event_handler(self.clone())
+        let mut client = Client::builder(&self.token, intents)
+            .event_handler(self.client.clone())
             .await?;
+        let shard_manager = client.shard_manager.clone();
 
-        info!("Starting discord bot");
-        client.start().await
+        let result = tokio::select! {
+            result = client.start_autosharded() => result,
+            _ = shutdown.cancelled() => {
+                shard_manager.shutdown_all().await;
+                Ok(())
+            }
+        };
+
+        if result.is_err() {
+            let mut disconnected_at = self.client.disconnected_at.lock().unwrap();
+            disconnected_at.get_or_insert_with(chrono::Utc::now);
+        }
+        result
+    }
+
+    fn is_fatal(&self, error: &serenity::Error) -> bool {
+        matches!(
+            error,
+            serenity::Error::Gateway(
+                GatewayError::InvalidAuthentication
+                    | GatewayError::InvalidGatewayIntents
+                    | GatewayError::DisallowedGatewayIntents
+            )
+        )
     }
 }
 
@@ -65,208 +1025,475 @@ impl From<Message> for knowledge::Message {
     }
 }
 
-#[async_trait]
-impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> EventHandler
-    for DiscordClient<M, E>
-{
-    async fn message(&self, ctx: Context, msg: Message) {
+impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> DiscordClient<M, E> {
+    async fn handle_message(&self, ctx: Context, msg: Message) {
         if msg.author.bot {
             return;
         }
 
-        let knowledge = self.agent.knowledge();
-        let knowledge_msg = knowledge::Message::from(msg.clone());
+        let outbound = self.outbound(&ctx.http);
+        // Replies may ping the people in the exchange, never the whole server
+        let mentions = MentionPolicy::participants(
+            msg.author.id,
+            msg.mentions
+                .iter()
+                .filter(|user| !user.bot)
+                .map(|user| user.id),
+            self.config
+                .load()
+                .mention_roles(msg.guild_id.map(|id| id.to_string()).as_deref()),
+        );
 
-        if let Err(err) = knowledge
-            .clone()
-            .create_message(knowledge_msg.clone())
-            .await
-        {
-            error!(?err, "Failed to store message");
+        if let Some(Ok(Command::ReloadConfig)) = Command::parse(&msg.content) {
+            let reply = self.reload_command(&msg.author.id.to_string()).await;
+            if let Err(why) = outbound.send(msg.channel_id, &reply, &mentions).await {
+                error!(?why, "Failed to send message");
+            }
             return;
         }
 
-        debug!("Fetching message history for channel {}", msg.channel_id);
-        let history = match knowledge
-            .channel_messages(&msg.channel_id.to_string(), MAX_HISTORY_MESSAGES)
-            .await
+        if let Some(Ok(Command::EraseUser { user_id, confirm })) = Command::parse(&msg.content) {
+            let reply = self
+                .erase_user_command(
+                    &msg.author.id.to_string(),
+                    &msg.channel_id.to_string(),
+                    &user_id,
+                    confirm,
+                )
+                .await;
+            if let Err(why) = outbound.send(msg.channel_id, &reply, &mentions).await {
+                error!(?why, "Failed to send message");
+            }
+            return;
+        }
+
+        if let Some(Ok(Command::Say {
+            channel_id,
+            text,
+            restyle,
+        })) = Command::parse(&msg.content)
         {
-            Ok(messages) => {
-                debug!(message_count = messages.len(), "Retrieved message history");
-                messages
+            let request = SayRequest {
+                source: knowledge::Source::Discord,
+                invoked_by: msg.author.id.to_string(),
+                channel_id,
+                text,
+                restyle,
+            };
+            let reply = self.say(&ctx, msg.author.id, request).await;
+            if let Err(why) = outbound.send(msg.channel_id, &reply, &mentions).await {
+                error!(?why, "Failed to send message");
             }
-            Err(err) => {
-                error!(?err, "Failed to fetch recent messages");
+            return;
+        }
+
+        let forum_post = match msg.guild_id {
+            Some(_) => self.forum_post(&ctx, msg.channel_id).await,
+            None => None,
+        };
+        let auto_answer = {
+            let config = self.config.load();
+            let allowed = config.allows(&msg.channel_id.to_string(), msg.guild_id.is_none())
+                || forum_post
+                    .as_ref()
+                    .is_some_and(|post| config.allows(&post.forum_id.to_string(), false));
+            if !allowed {
                 return;
             }
+            match &forum_post {
+                Some(post) if post.locked => {
+                    debug!("Ignoring message in locked forum post");
+                    return;
+                }
+                // A new post is a ticket, which always deserves an answer
+                Some(post)
+                    if post.is_starter(msg.id)
+                        && config.auto_answers(&post.forum_id.to_string()) =>
+                {
+                    if !self.claim_post(post.thread_id) {
+                        debug!("Forum post already answered");
+                        return;
+                    }
+                    true
+                }
+                _ => false,
+            }
         };
 
-        let mentioned_names: HashSet<String> =
-            msg.mentions.iter().map(|user| user.name.clone()).collect();
-        debug!(
-            mentioned_names = ?mentioned_names,
-            "Mentioned names in message"
-        );
-
-        let context = AttentionContext {
-            message_content: msg.content.clone(),
-            mentioned_names,
-            history,
-            channel_type: knowledge_msg.channel_type,
-            source: knowledge_msg.source,
-        };
-
-        debug!(?context, "Attention context");
+        // Forum posts follow their forum
+        let listen_channel = forum_post
+            .as_ref()
+            .map_or(msg.channel_id, |post| post.forum_id);
+        if msg.guild_id.is_some()
+            && !matches!(
+                Command::parse(&msg.content),
+                Some(Ok(Command::Listen { .. }))
+            )
+            && !self.listening(listen_channel).await
+        {
+            debug!("Ignoring message in channel the bot doesn't listen in");
+            return;
+        }
 
-        match self.attention.should_reply(&context).await {
-            AttentionCommand::Respond => {}
-            _ => {
-                debug!("Bot decided not to reply to message");
-                return;
-            }
+        if let Some(Ok(Command::Summarize { hours })) = Command::parse(&msg.content) {
+            let summary = Summarizer::new(self.agent().clone())
+                .with_config(self.summarize.clone())
+                .summarize(&msg.channel_id.to_string(), hours)
+                .await;
+            let chunks = chunk_message(&summary, MAX_MESSAGE_LENGTH, MIN_CHUNK_LENGTH);
+            outbound
+                .send_chunks(msg.channel_id, &chunks, &mentions)
+                .await;
+            return;
         }
 
-        let agent = self
-            .agent
-            .builder()
-            .context(&format!(
-                "Current time: {}",
-                chrono::Local::now().format("%I:%M:%S %p, %Y-%m-%d")
-            ))
-            .context("Please keep your responses concise and under 2000 characters when possible.")
-            .build();
-
-        let response = match agent.prompt(&msg.content).await {
-            Ok(response) => response,
+        let bot_id = ctx.cache.current_user().id;
+        let channels = self.channels(&ctx, outbound.clone(), mentions.clone());
+        let asked = IncomingMessage::from(knowledge::Message::from(msg.clone()))
+            .with_mentions_bot(msg.mentions_user_id(bot_id));
+        match self.pipeline.research(&channels, &asked).await {
+            Ok(None) => {}
+            Ok(Some(_)) => return,
             Err(err) => {
-                error!(?err, "Failed to generate response");
+                error!(?err, "Failed to research question");
                 return;
             }
-        };
+        }
 
-        debug!(response = %response, "Generated response");
+        let knowledge = self.agent().knowledge();
 
-        let chunks = chunk_message(&response, MAX_MESSAGE_LENGTH, MIN_CHUNK_LENGTH);
+        if let Some(reply) = linking::handle(
+            knowledge,
+            self.linking.as_ref(),
+            &knowledge::Message::from(msg.clone()),
+        )
+        .await
+        {
+            if let Err(why) = outbound.send(msg.channel_id, &reply, &mentions).await {
+                error!(?why, "Failed to send message");
+            }
+            return;
+        }
 
-        for chunk in chunks {
-            if let Err(why) = msg.channel_id.say(&ctx.http, chunk).await {
+        let muting = matches!(
+            Command::parse(&msg.content),
+            Some(Ok(Command::Listen { enabled: false }))
+        );
+        if let Some(reply) = commands::handle(
+            knowledge,
+            &self.permissions.admins,
+            &msg.channel_id.to_string(),
+            &msg.author.id.to_string(),
+            &msg.content,
+        )
+        .await
+        {
+            // Acknowledged in the character's words once the bot went quiet
+            let reply = match muting && !self.listening(msg.channel_id).await {
+                true => {
+                    self.agent()
+                        .localized_template(
+                            templates::MUTED_ACK,
+                            Some(&msg.author.id.to_string()),
+                            &msg.channel_id.to_string(),
+                            &[],
+                        )
+                        .await
+                }
+                false => reply,
+            };
+            if let Err(why) = outbound.send(msg.channel_id, &reply, &mentions).await {
                 error!(?why, "Failed to send message");
             }
+            return;
+        }
+
+        let message = match &forum_post {
+            Some(post) => post.to_message(msg.clone()),
+            None => knowledge::Message::from(msg.clone()),
+        };
+        let roles: Vec<String> = msg
+            .member
+            .as_ref()
+            .map(|member| member.roles.iter().map(|id| id.to_string()).collect())
+            .unwrap_or_default();
+        let mut incoming = IncomingMessage::from(message)
+            .with_mentioned_names(msg.mentions.iter().map(|user| user.name.clone()))
+            .with_mentions_bot(msg.mentions_user_id(bot_id))
+            .with_is_reply(msg.referenced_message.is_some())
+            .with_full_answer(auto_answer)
+            .with_link(msg.link())
+            .with_roles(roles);
+        for quote in self.linked_quotes(&ctx, &msg).await {
+            incoming = incoming.with_quoted(quote);
+        }
+        // Only the bot's own answers can be corrected
+        if let Some(replied) = msg
+            .referenced_message
+            .as_deref()
+            .filter(|replied| replied.author.id == bot_id)
+        {
+            incoming = incoming.with_replied(replied.id.to_string(), replied.content.clone());
+        }
+        if let Some(guild_id) = msg.guild_id {
+            incoming = incoming.with_guild_id(guild_id.to_string());
+        }
+        if let Some(post) = &forum_post {
+            incoming = incoming.with_context(post.context());
+        }
+
+        if let Err(err) = self.pipeline.handle(&channels, incoming).await {
+            error!(?err, "Failed to answer message");
         }
     }
 
-    async fn ready(&self, _: Context, ready: Ready) {
-        info!(name = self.agent.character.name, "Bot connected");
-        info!(guild_count = ready.guilds.len(), "Serving guilds");
+    /// Discord as the pipeline sees it, sending through `outbound`.
+    fn channels(&self, ctx: &Context, outbound: Outbound, mentions: MentionPolicy) -> Channels {
+        Channels {
+            http: ctx.http.clone(),
+            outbound,
+            mentions,
+            bot_id: ctx.cache.current_user().id.to_string(),
+            permissions: self.permissions.clone(),
+            owner: self.owner.lock().unwrap().clone(),
+        }
     }
 }
 
-pub fn chunk_message(text: &str, max_length: usize, min_chunk_length: usize) -> Vec<String> {
-    // Base case: if text is shorter than min_chunk_length, return as single chunk
-    if text.len() <= min_chunk_length {
-        return vec![text.to_string()];
+#[async_trait]
+impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> EventHandler
+    for DiscordClient<M, E>
+{
+    async fn message(&self, ctx: Context, msg: Message) {
+        // Errors logged while handling the message are reported with its id
+        let span = info_span!("message", correlation_id = %msg.id);
+        self.handle_message(ctx, msg).instrument(span).await
     }
 
-    let mut chunks = Vec::new();
+    async fn ready(&self, ctx: Context, ready: Ready) {
+        info!(name = self.agent().character.name, "Bot connected");
+        info!(guild_count = ready.guilds.len(), "Serving guilds");
+        self.state.send_replace(ConnectionState::Connected);
 
-    // Find split point for current chunk
-    let mut split_index = text.len();
-    let mut in_heading = false;
+        // Each session has its own shard, so the manager is restarted on it
+        if let Some((config, status)) = &self.presence {
+            let stop = self.shutdown.child_token();
+            if let Some(previous) = self.presence_task.lock().unwrap().replace(stop.clone()) {
+                previous.cancel();
+            }
+            let manager = PresenceManager::new(config.clone(), status.subscribe());
+            tokio::spawn(manager.run(Arc::new(ctx.shard.clone()), stop));
+        }
 
-    for (i, line) in text.lines().enumerate() {
-        let line = line.trim();
-        if line.is_empty() {
-            continue;
+        // Users also address the bot by its username and guild nicknames
+        let mut names = vec![ready.user.name.clone()];
+        names.extend(ready.user.global_name.clone());
+        for guild in &ready.guilds {
+            match ctx.http.get_member(guild.id, ready.user.id).await {
+                Ok(member) => names.extend(member.nick),
+                Err(err) => debug!(?err, guild_id = %guild.id, "Failed to fetch nickname"),
+            }
+        }
+        self.pipeline.attention().add_names(&names);
+
+        match ctx.http.get_current_application_info().await {
+            Ok(info) => {
+                let owner = info
+                    .team
+                    .map(|team| team.owner_user_id)
+                    .or(info.owner.map(|owner| owner.id));
+                *self.owner.lock().unwrap() = owner.map(|id| id.to_string());
+            }
+            Err(err) => error!(?err, "Failed to fetch application owner"),
         }
 
-        // Start new chunk on headings
-        if line.starts_with('#') {
-            if i > 0 {
-                split_index = text.find(line).unwrap_or(text.len());
-                in_heading = true;
-                break;
+        // Deferred answers go out through the current session too
+        let stop = self.shutdown.child_token();
+        let channels = self.channels(&ctx, self.outbound(&ctx.http), MentionPolicy::none());
+        if self
+            .pipeline
+            .spawn_follow_ups(channels, stop.clone())
+            .is_some()
+        {
+            if let Some(previous) = self.follow_up_task.lock().unwrap().replace(stop) {
+                previous.cancel();
             }
         }
 
-        // Check if adding this line would exceed max_length
-        let line_start = text.find(line).unwrap_or(text.len());
-        if line_start + line.len() > max_length && i > 0 {
-            split_index = line_start;
-            break;
+        let disconnected_at = self.disconnected_at.lock().unwrap().take();
+        if let Some(since) = disconnected_at {
+            self.catch_up(&ctx, since).await;
         }
     }
 
-    // Split text and recurse
-    if split_index < text.len() {
-        let (chunk, rest) = text.split_at(split_index);
-        let mut chunk = chunk.trim().to_string();
+    /// Answers new posts in auto-answer forums. The starter message can
+    /// arrive before its message event is seen, so it is fetched here too.
+    async fn thread_create(&self, ctx: Context, thread: GuildChannel) {
+        if thread.kind != ChannelType::PublicThread {
+            return;
+        }
+        let Some(forum_id) = thread.parent_id else {
+            return;
+        };
+        if !self.config.load().auto_answers(&forum_id.to_string()) {
+            return;
+        }
+
+        let starter_id = MessageId::new(thread.id.get());
+        match thread.id.message(&ctx, starter_id).await {
+            Ok(starter) => self.message(ctx, starter).await,
+            Err(err) => debug!(?err, "Forum post starter not available yet"),
+        }
+    }
+
+    /// Records the guild's channels and applies the default listen settings.
+    /// Also sent for every guild after connecting, with `is_new` false, in
+    /// which case settings already made are kept and no introduction is
+    /// posted.
+    async fn guild_create(&self, ctx: Context, guild: Guild, is_new: Option<bool>) {
+        let policy = self.config.load().guilds.clone();
+        let knowledge = self.agent().knowledge();
+        let guild_id = guild.id.to_string();
+
+        let channels = guilds::discover(guild.channels.values());
+        if let Err(err) = knowledge
+            .upsert_guild_channels(&guild_id, channels.clone())
+            .await
+        {
+            error!(?err, %guild.id, "Failed to store guild channels");
+            return;
+        }
+        for (channel_id, listen) in policy.default_listen(&channels).unwrap_or_default() {
+            let value = if listen { "on" } else { "off" };
+            if let Err(err) = knowledge
+                .init_channel_setting(&channel_id, LISTEN_SETTING, value)
+                .await
+            {
+                error!(?err, %channel_id, "Failed to apply default listen setting");
+            }
+        }
+        info!(%guild.id, channels = channels.len(), ?is_new, "Discovered guild");
+
+        if is_new != Some(true) {
+            return;
+        }
+        if let (Some(introduction), Some(channel_id)) =
+            (&policy.introduction, guild.system_channel_id)
+        {
+            let sent = self
+                .outbound(&ctx.http)
+                .send(channel_id, introduction, &MentionPolicy::none())
+                .await;
+            if let Err(why) = sent {
+                error!(?why, "Failed to send introduction");
+            }
+        }
+    }
 
-        // Add newline after chunk if we're not splitting on a heading
-        if !in_heading && !rest.trim().starts_with('#') {
-            chunk.push('\n');
+    /// Forgets a guild the bot was removed from. Guilds that only became
+    /// unavailable in an outage are kept.
+    async fn guild_delete(&self, _: Context, incomplete: UnavailableGuild, _: Option<Guild>) {
+        if incomplete.unavailable {
+            return;
         }
 
-        // Strip trailing newline if it's the last character
-        if chunk.ends_with('\n') {
-            chunk.pop();
+        let retention = self.config.load().guilds.retention();
+        if let Err(err) = self
+            .agent
+            .knowledge()
+            .delete_guild_data(&incomplete.id.to_string(), retention)
+            .await
+        {
+            error!(?err, "Failed to delete guild data");
         }
+    }
 
-        chunks.push(chunk);
-        chunks.extend(chunk_message(rest.trim(), max_length, min_chunk_length));
-    } else {
-        chunks.push(text.trim().to_string());
+    async fn resume(&self, _: Context, _: ResumedEvent) {
+        info!("Gateway session resumed");
+        self.state.send_replace(ConnectionState::Resumed);
     }
+}
 
-    chunks
+/// Edits the bot's message `message_id`, whose text is `original`, to end
+/// with the correction `notice`. The original is cut to keep the message
+/// within [MESSAGE_LIMIT].
+pub async fn amend_message(
+    outbound: &Outbound,
+    channel_id: ChannelId,
+    message_id: MessageId,
+    original: &str,
+    notice: &str,
+) -> Result<(), SendError> {
+    let edited = corrections::append_correction(original, notice, MESSAGE_LIMIT);
+    outbound
+        .edit(channel_id, message_id, &edited, &MentionPolicy::none())
+        .await
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        attention::{AttentionCommand, AttentionConfig, AttentionContext},
+        test_utils::{self, ScriptedCompletionModel},
+    };
+    use std::collections::HashSet;
+
+    #[tokio::test]
+    async fn test_reload_config_applies_to_next_decision() {
+        let model = ScriptedCompletionModel::new(["{\"decision\": \"respond\"}"]);
+        let character = test_utils::character();
+        let agent = Agent::new(character, model.clone(), test_utils::knowledge_base().await);
+        let config = AttentionConfig {
+            bot_names: vec!["shinobi".to_string()],
+            cooldown_messages: 5,
+            ..Default::default()
+        };
+        let client = DiscordClient::new(agent, Attention::new(config, model.clone()));
+        let attention = client.pipeline.attention().clone();
+        let context = AttentionContext {
+            message_content: "anyone know the vrf fees?".to_string(),
+            mentioned_names: HashSet::new(),
+            history: Vec::new(),
+            recent_replies: Vec::new(),
+            channel_id: "c1".to_string(),
+            channel_type: knowledge::ChannelType::Text,
+            source: knowledge::Source::Discord,
+            topic_match: None,
+            familiarity: None,
+        };
 
-    #[test]
-    fn test_chunk_message_single_chunk() {
-        let text = "This is a short message";
-        let chunks = chunk_message(text, 100, 1000);
-        assert_eq!(chunks.len(), 1);
-        assert_eq!(chunks[0], text);
-    }
-
-    #[test]
-    fn test_chunk_message_multiple_chunks() {
-        let text = "Line 1\nLine 2\nLine 3";
-        let chunks = chunk_message(text, 10, 5);
-        assert_eq!(chunks.len(), 3);
-        assert_eq!(chunks[0], "Line 1");
-        assert_eq!(chunks[1], "Line 2");
-        assert_eq!(chunks[2], "Line 3");
-    }
-
-    #[test]
-    fn test_chunk_message_empty_lines() {
-        let text = "Line 1\n\n\nLine 2";
-        let chunks = chunk_message(text, 100, 1000);
-        assert_eq!(chunks.len(), 1);
-        assert_eq!(chunks[0], "Line 1\n\n\nLine 2");
-    }
-
-    #[test]
-    fn test_chunk_message_markdown() {
-        let text = "# Heading 1\nSome text under heading 1\n## Heading 2\nMore text\n# Heading 3\nFinal text";
-        let chunks = chunk_message(text, 100, 50);
-        assert_eq!(chunks.len(), 2);
-        assert_eq!(chunks[0], "# Heading 1\nSome text under heading 1");
+        attention.record_reply("c1");
         assert_eq!(
-            chunks[1],
-            "## Heading 2\nMore text\n# Heading 3\nFinal text"
+            attention.should_reply(&context).await,
+            AttentionCommand::Ignore
         );
-    }
 
-    #[test]
-    fn test_no_chunking_under_min_length() {
-        let text = "This is a message that won't be chunked because it's under the minimum length";
-        let chunks = chunk_message(text, 10, 1000);
-        assert_eq!(chunks.len(), 1);
-        assert_eq!(chunks[0], text);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(
+            &path,
+            "[attention]\ncooldown_messages = 0\n[discord]\nallowed_channels = [\"42\"]",
+        )
+        .unwrap();
+        client.reload_config(&path).unwrap();
+
+        assert_eq!(
+            attention.should_reply(&context).await,
+            AttentionCommand::Respond
+        );
+        assert_eq!(model.requests().len(), 1);
+        assert_eq!(attention.config().bot_names, ["shinobi"]);
+        assert!(client.config.load().allows("42", false));
+        assert!(!client.config.load().allows("43", false));
+
+        // An invalid file leaves the current settings in place.
+        std::fs::write(&path, "[attention]\ncooldown_messages = -1").unwrap();
+        assert!(matches!(
+            client.reload_config(&path),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert_eq!(attention.config().cooldown_messages, 0);
+        assert!(!client.config.load().allows("43", false));
     }
 }
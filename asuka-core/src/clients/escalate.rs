@@ -0,0 +1,149 @@
+//! Tool letting the model hand a conversation to the support team, e.g. for
+//! account issues it has no way to resolve.
+
+use rig::{
+    completion::{CompletionModel, ToolDefinition},
+    embeddings::EmbeddingModel,
+    tool::Tool,
+};
+use serde::Deserialize;
+use serde_json::json;
+use thiserror::Error;
+
+use crate::escalation::{EscalationError, EscalationReason, EscalationRequest, Escalator};
+
+/// What the model is told the tool does, also listed in the agent's
+/// [capabilities](crate::capabilities).
+pub const DESCRIPTION: &str = "Ask the support team to step in, when the user needs something \
+    only a person can do, such as account or payment issues. Don't use it for questions you can \
+    answer.";
+
+#[derive(Error, Debug)]
+pub enum EscalateError {
+    #[error("Reason is empty")]
+    MissingReason,
+    #[error(transparent)]
+    Escalation(#[from] EscalationError),
+    #[error("Escalation task failed: {0}")]
+    Task(String),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EscalateArgs {
+    pub reason: String,
+}
+
+/// Escalates the conversation the tool was built for.
+pub struct Escalate<M: CompletionModel, E: EmbeddingModel + 'static> {
+    escalator: Escalator<M, E>,
+    request: EscalationRequest,
+}
+
+impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> Escalate<M, E> {
+    pub fn new(escalator: Escalator<M, E>, request: EscalationRequest) -> Self {
+        Self { escalator, request }
+    }
+}
+
+impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> Tool for Escalate<M, E> {
+    const NAME: &'static str = "escalate";
+
+    type Error = EscalateError;
+    type Args = EscalateArgs;
+    type Output = String;
+
+    async fn definition(&self, _prompt: String) -> ToolDefinition {
+        ToolDefinition {
+            name: Self::NAME.to_string(),
+            description: DESCRIPTION.to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "reason": {
+                        "type": "string",
+                        "description": "Short summary of what the user needs help with"
+                    }
+                },
+                "required": ["reason"]
+            }),
+        }
+    }
+
+    async fn call(&self, args: Self::Args) -> Result<Self::Output, Self::Error> {
+        let reason = args.reason.trim().to_string();
+        if reason.is_empty() {
+            return Err(EscalateError::MissingReason);
+        }
+
+        let escalator = self.escalator.clone();
+        let request = self.request.clone();
+        // Tool futures must be Sync, which the store futures are not, so the
+        // work runs on its own task.
+        let task = tokio::spawn(async move {
+            let outcome = escalator
+                .escalate(&request, EscalationReason::ModelRequest(reason))
+                .await?;
+            Ok(escalator
+                .acknowledgement(outcome)
+                .unwrap_or_else(|| "There is no team to escalate to here.".to_string()))
+        });
+        task.await.map_err(|e| EscalateError::Task(e.to_string()))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::escalation::tests::{config, request, setup};
+
+    #[tokio::test]
+    async fn test_model_escalates_once_per_cooldown() {
+        let (escalator, poster) = setup(config()).await;
+        let tool = Escalate::new(escalator.clone(), request(Some("1"), "10"));
+
+        let reply = tool
+            .call(EscalateArgs {
+                reason: "refund for a failed session key purchase".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            reply,
+            "I've asked the team to take a look, someone will follow up here."
+        );
+        let reply = tool
+            .call(EscalateArgs {
+                reason: "still waiting".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            reply,
+            "The team has already been notified and will be with you soon."
+        );
+
+        let posts = poster.0.lock().unwrap().clone();
+        assert_eq!(posts.len(), 1);
+        assert!(posts[0]
+            .1
+            .contains("(model: refund for a failed session key purchase)"));
+
+        assert!(matches!(
+            tool.call(EscalateArgs {
+                reason: " ".to_string()
+            })
+            .await,
+            Err(EscalateError::MissingReason)
+        ));
+        let uncovered = Escalate::new(escalator, request(Some("2"), "30"));
+        assert_eq!(
+            uncovered
+                .call(EscalateArgs {
+                    reason: "refund".to_string()
+                })
+                .await
+                .unwrap(),
+            "There is no team to escalate to here."
+        );
+    }
+}
@@ -0,0 +1,385 @@
+//! The contract for clients written outside this crate, such as a bridge to
+//! a platform asuka has no client for.
+//!
+//! A client turns platform events into [IncomingMessage]s and hands them to
+//! [Pipeline::handle](crate::pipeline::Pipeline::handle), which stores,
+//! batches, decides and answers them as it does for the built-in clients
+//! and sends the answer through the client's [ReplySink]. Messages of platforms
+//! without a variant of their own are stored as [Source::Other], so their
+//! history, config and sent messages work like any other source's.
+//!
+//! Everything here is re-exported at the crate root, which is the surface
+//! kept stable across releases.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use rig::{completion::CompletionModel, embeddings::EmbeddingModel};
+use rig_sqlite::SqliteError;
+
+use super::{
+    chunks::{chunk_message, MIN_CHUNK_LENGTH},
+    reactions::ReactionError,
+    streaming::ReplySink,
+};
+use crate::{
+    agent::Agent,
+    escalation::EscalationPoster,
+    knowledge::{ChannelType, Message, Source},
+    locale::FormatPreferences,
+    permissions::PermissionTier,
+    quoted::{self, QuotedContent},
+};
+
+/// A platform the bot talks on.
+///
+/// Only the required methods are needed for plain replies. The provided
+/// ones opt into what not every platform has, such as reactions.
+#[async_trait]
+pub trait Client: Send + Sync {
+    /// Where the client's replies are sent.
+    type Sink: ReplySink;
+
+    /// The platform, stored with every message of the client.
+    fn source(&self) -> Source;
+
+    /// Id of the bot's account on the platform, stored with its replies.
+    fn account_id(&self) -> String;
+
+    /// Longest message the platform accepts, in bytes. Longer replies are
+    /// sent in chunks.
+    fn max_message_length(&self) -> usize;
+
+    /// The conversation `channel_id`, to reply in.
+    fn sink(&self, channel_id: &str) -> Self::Sink;
+
+    /// The conversation `channel_id`, to send an answer in whose
+    /// [ResponseDraft](crate::hooks::ResponseDraft) came with `metadata`,
+    /// such as [PARSE_MODE](crate::hooks::PARSE_MODE). [Client::sink] by
+    /// default.
+    fn formatted_sink(&self, channel_id: &str, metadata: &HashMap<String, String>) -> Self::Sink {
+        let _ = metadata;
+        self.sink(channel_id)
+    }
+
+    /// What `account_id` may have the bot do, which decides the tools its
+    /// answers may use. Admins are not rate limited. Users by default.
+    fn tier(&self, account_id: &str) -> PermissionTier {
+        let _ = account_id;
+        PermissionTier::User
+    }
+
+    /// Where escalations to the support team are posted, see
+    /// [crate::escalation]. None by default, which turns escalation off.
+    fn escalation_poster(&self) -> Option<Arc<dyn EscalationPoster>> {
+        None
+    }
+
+    /// Reacts with `emoji` to the message `message_id` in `channel_id`.
+    /// Unsupported by default, in which case the emoji is sent as a reply.
+    async fn react(
+        &self,
+        channel_id: &str,
+        message_id: &str,
+        emoji: &str,
+    ) -> Result<(), ReactionError> {
+        let _ = (channel_id, message_id, emoji);
+        Err(ReactionError::Unsupported)
+    }
+
+    /// Edits the bot's message `message_id` in `channel_id`, whose text is
+    /// `original`, to carry the correction `notice`, see
+    /// [crate::corrections]. Returns whether it was edited; if not, the
+    /// correction is sent as a message of its own, which is the default.
+    async fn amend(
+        &self,
+        channel_id: &str,
+        message_id: &str,
+        original: &str,
+        notice: &str,
+    ) -> bool {
+        let _ = (channel_id, message_id, original, notice);
+        false
+    }
+}
+
+/// The bot's message a received message replies to.
+#[derive(Debug, Clone)]
+pub struct RepliedMessage {
+    pub id: String,
+    pub content: String,
+}
+
+/// A message received by a [Client], built with [IncomingMessage::new] and
+/// the `with_*` methods.
+#[derive(Debug, Clone)]
+pub struct IncomingMessage {
+    pub id: String,
+    pub channel_id: String,
+    pub account_id: String,
+    pub content: String,
+    pub channel_type: ChannelType,
+    pub created_at: DateTime<Utc>,
+    /// Names the message mentions through the platform, e.g. `@handles`.
+    pub mentioned_names: HashSet<String>,
+    /// Whether the platform marks the message as addressed to the bot, such
+    /// as a mention or a reply to its message. Mentions by name in the text
+    /// are found without it.
+    pub mentions_bot: bool,
+    /// Whether the message replies to another one, so it likely leans on
+    /// it, see [crate::rewrite].
+    pub is_reply: bool,
+    /// What the message forwards or quotes from others, stored after its
+    /// content as quoted blocks, see [crate::quoted].
+    pub quoted: Vec<QuotedContent>,
+    /// The bot's message it replies to, which it may correct, see
+    /// [crate::corrections].
+    pub replied: Option<RepliedMessage>,
+    /// Whether the message always gets a full answer, such as the one
+    /// opening a post in a support forum.
+    pub full_answer: bool,
+    /// Server or group the message was sent in, which experiments are
+    /// assigned by and escalations routed by.
+    pub guild_id: Option<String>,
+    /// Where the message can be viewed, linked from escalations.
+    pub link: Option<String>,
+    /// Roles of the sender, which may exempt them from the rate limit.
+    pub roles: Vec<String>,
+    /// Prompt context on where the message was sent, such as the title of
+    /// its forum post.
+    pub context: Vec<String>,
+}
+
+impl IncomingMessage {
+    /// A message in a text channel, received now.
+    pub fn new(
+        id: impl Into<String>,
+        channel_id: impl Into<String>,
+        account_id: impl Into<String>,
+        content: impl Into<String>,
+    ) -> Self {
+        Self {
+            id: id.into(),
+            channel_id: channel_id.into(),
+            account_id: account_id.into(),
+            content: content.into(),
+            channel_type: ChannelType::Text,
+            created_at: Utc::now(),
+            mentioned_names: HashSet::new(),
+            mentions_bot: false,
+            is_reply: false,
+            quoted: Vec::new(),
+            replied: None,
+            full_answer: false,
+            guild_id: None,
+            link: None,
+            roles: Vec::new(),
+            context: Vec::new(),
+        }
+    }
+
+    pub fn with_channel_type(mut self, channel_type: ChannelType) -> Self {
+        self.channel_type = channel_type;
+        self
+    }
+
+    pub fn with_created_at(mut self, created_at: DateTime<Utc>) -> Self {
+        self.created_at = created_at;
+        self
+    }
+
+    pub fn with_mentioned_names(
+        mut self,
+        names: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.mentioned_names = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn with_mentions_bot(mut self, mentions_bot: bool) -> Self {
+        self.mentions_bot = mentions_bot;
+        self
+    }
+
+    pub fn with_is_reply(mut self, is_reply: bool) -> Self {
+        self.is_reply = is_reply;
+        self
+    }
+
+    pub fn with_quoted(mut self, quoted: QuotedContent) -> Self {
+        self.quoted.push(quoted);
+        self
+    }
+
+    /// Marks the message as a reply to the bot's message `id`, whose text
+    /// is `content`.
+    pub fn with_replied(mut self, id: impl Into<String>, content: impl Into<String>) -> Self {
+        self.is_reply = true;
+        self.replied = Some(RepliedMessage {
+            id: id.into(),
+            content: content.into(),
+        });
+        self
+    }
+
+    pub fn with_full_answer(mut self, full_answer: bool) -> Self {
+        self.full_answer = full_answer;
+        self
+    }
+
+    pub fn with_guild_id(mut self, guild_id: impl Into<String>) -> Self {
+        self.guild_id = Some(guild_id.into());
+        self
+    }
+
+    pub fn with_link(mut self, link: impl Into<String>) -> Self {
+        self.link = Some(link.into());
+        self
+    }
+
+    pub fn with_roles(mut self, roles: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.roles = roles.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn with_context(mut self, context: impl Into<String>) -> Self {
+        self.context.push(context.into());
+        self
+    }
+
+    /// The message as stored, received on `source`.
+    pub fn to_message(&self, source: Source) -> Message {
+        Message {
+            id: self.id.clone(),
+            source,
+            source_id: self.account_id.clone(),
+            channel_type: self.channel_type.clone(),
+            channel_id: self.channel_id.clone(),
+            account_id: self.account_id.clone(),
+            role: "user".to_string(),
+            content: quoted::annotate(&self.content, &self.quoted),
+            created_at: self.created_at,
+        }
+    }
+}
+
+/// A message already in its stored form, as the built-in clients map
+/// theirs, received again.
+impl From<Message> for IncomingMessage {
+    fn from(message: Message) -> Self {
+        Self::new(
+            message.id,
+            message.channel_id,
+            message.account_id,
+            message.content,
+        )
+        .with_channel_type(message.channel_type)
+        .with_created_at(message.created_at)
+    }
+}
+
+/// What a client needs at send time beyond its [ReplySink], for messages it
+/// sends outside [Pipeline::handle](crate::pipeline::Pipeline::handle), such
+/// as notices and announcements.
+pub struct ClientContext<'a, M: CompletionModel, E: EmbeddingModel + 'static> {
+    agent: &'a Agent<M, E>,
+}
+
+impl<'a, M: CompletionModel, E: EmbeddingModel> ClientContext<'a, M, E> {
+    pub(crate) fn new(agent: &'a Agent<M, E>) -> Self {
+        Self { agent }
+    }
+
+    /// `text` split into messages of about `max_length` bytes at most, at
+    /// line breaks and before headings, see [chunk_message].
+    pub fn chunk(&self, text: &str, max_length: usize) -> Vec<String> {
+        chunk_message(text, max_length, MIN_CHUNK_LENGTH)
+    }
+
+    /// The character's template `name` with `vars` filled in, see
+    /// [crate::templates].
+    pub fn template(&self, name: &str, vars: &[(&str, &str)]) -> String {
+        self.agent.character.template(name, vars)
+    }
+
+    /// The template `name` in the language of `account_id` in `channel_id`,
+    /// see [Agent::localized_template].
+    pub async fn localized_template(
+        &self,
+        name: &str,
+        account_id: Option<&str>,
+        channel_id: &str,
+        vars: &[(&str, &str)],
+    ) -> String {
+        self.agent
+            .localized_template(name, account_id, channel_id, vars)
+            .await
+    }
+
+    /// How times are written for `account_id` in `channel_id`, for
+    /// template variables such as dates, see [Agent::format_preferences].
+    pub async fn format_preferences(
+        &self,
+        account_id: Option<&str>,
+        channel_id: &str,
+    ) -> FormatPreferences {
+        self.agent.format_preferences(account_id, channel_id).await
+    }
+
+    /// Records the platform ids of the messages an interaction's answer was
+    /// sent as, so reactions and replies to them find the interaction.
+    pub async fn record_sent(
+        &self,
+        interaction_id: i64,
+        source: &Source,
+        channel_id: &str,
+        message_ids: &[String],
+    ) -> Result<(), SqliteError> {
+        self.agent
+            .knowledge()
+            .record_sent_messages(interaction_id, source, channel_id, message_ids)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unknown_sources_are_other() {
+        assert_eq!(Source::from_str("Discord"), Some(Source::Discord));
+        assert_eq!(
+            Source::from_str("WhatsApp"),
+            Some(Source::Other("whatsapp".to_string()))
+        );
+        assert_eq!(Source::Other("whatsapp".to_string()).as_str(), "whatsapp");
+        assert_eq!(Source::from_str(""), None);
+        assert_eq!(Source::from_str("what's app"), None);
+    }
+
+    #[test]
+    fn test_incoming_message() {
+        let message = IncomingMessage::new("m1", "chat", "alice", "hey @shinobi")
+            .with_channel_type(ChannelType::DirectMessage)
+            .with_mentioned_names(["shinobi"])
+            .to_message(Source::Other("whatsapp".to_string()));
+
+        assert_eq!(message.source.as_str(), "whatsapp");
+        assert_eq!(message.channel_type, ChannelType::DirectMessage);
+        assert_eq!(message.account_id, "alice");
+        assert_eq!(message.role, "user");
+
+        let forward = IncomingMessage::new("m2", "chat", "alice", "")
+            .with_quoted(QuotedContent::new("gm").with_original_author("bob"))
+            .to_message(Source::Other("whatsapp".to_string()));
+        assert_eq!(
+            forward.content,
+            "[quoted from bob, not written by the sender]\n> gm"
+        );
+    }
+}
@@ -0,0 +1,728 @@
+//! Farcaster client: polls the mentions of and replies to the agent's account
+//! and answers in reply casts, chained into a thread when a response does not
+//! fit in one cast.
+//!
+//! The hub is reached through a [FarcasterApi], implemented for Neynar's API
+//! by [NeynarApi], so other providers can be swapped in.
+
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use rig::{
+    completion::{CompletionModel, Prompt},
+    embeddings::EmbeddingModel,
+};
+use serde::Deserialize;
+use serde_json::json;
+use thiserror::Error;
+use tracing::{debug, error, info};
+
+use crate::{
+    agent::Agent,
+    attention::{Attention, AttentionCommand, AttentionContext, RECENT_REPLIES},
+    hooks::{MessageContext, ResponseDraft},
+    knowledge::{ChannelType, Message, Source},
+};
+
+/// Farcaster limits cast text by bytes, not characters.
+pub const MAX_CAST_BYTES: usize = 320;
+
+/// Pages of older notifications read per poll when catching up.
+const MAX_PAGES: usize = 5;
+
+#[derive(Error, Debug)]
+pub enum FarcasterError {
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("HTTP {status} from {url}: {body}")]
+    Status {
+        status: u16,
+        url: String,
+        body: String,
+    },
+    #[error("Failed to store message: {0}")]
+    Store(String),
+}
+
+/// `[farcaster]` settings of the config file. The API key and signer are
+/// credentials, see [crate::config::Credentials].
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FarcasterConfig {
+    /// Farcaster id of the agent's account.
+    pub fid: Option<u64>,
+    /// Root of a Neynar-compatible API.
+    pub base_url: String,
+    pub poll_interval_secs: u64,
+}
+
+impl Default for FarcasterConfig {
+    fn default() -> Self {
+        Self {
+            fid: None,
+            base_url: "https://api.neynar.com".to_string(),
+            poll_interval_secs: 60,
+        }
+    }
+}
+
+impl FarcasterConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.poll_interval_secs == 0 {
+            return Err("farcaster.poll_interval_secs must be at least 1".to_string());
+        }
+        if reqwest::Url::parse(&self.base_url).is_err() {
+            return Err(format!(
+                "farcaster.base_url is not a URL: {:?}",
+                self.base_url
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct CastAuthor {
+    pub fid: u64,
+    #[serde(default)]
+    pub username: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Cast {
+    pub hash: String,
+    #[serde(default)]
+    pub parent_hash: Option<String>,
+    /// Hash of the cast that started the thread.
+    #[serde(default)]
+    pub thread_hash: Option<String>,
+    pub author: CastAuthor,
+    pub text: String,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+impl From<Cast> for Message {
+    fn from(cast: Cast) -> Self {
+        Self {
+            id: cast.hash.clone(),
+            source: Source::Farcaster,
+            source_id: cast.hash.clone(),
+            channel_type: ChannelType::Text,
+            channel_id: cast.thread_hash.unwrap_or(cast.hash),
+            account_id: cast.author.fid.to_string(),
+            role: "user".to_string(),
+            content: cast.text,
+            created_at: cast.timestamp,
+        }
+    }
+}
+
+/// A page of the mention feed, newest first.
+#[derive(Debug, Clone, Default)]
+pub struct MentionsPage {
+    pub casts: Vec<Cast>,
+    /// Cursor of the next, older page, if any.
+    pub next_cursor: Option<String>,
+}
+
+#[async_trait]
+pub trait FarcasterApi: Send + Sync {
+    /// Casts mentioning or replying to `fid`, newest first.
+    async fn mentions(
+        &self,
+        fid: u64,
+        cursor: Option<String>,
+    ) -> Result<MentionsPage, FarcasterError>;
+
+    /// Publishes `text` in reply to the cast `parent_hash` and returns the
+    /// hash of the new cast.
+    async fn publish_cast(&self, text: &str, parent_hash: &str) -> Result<String, FarcasterError>;
+}
+
+/// [FarcasterApi] over Neynar's v2 API, casting through a managed signer.
+pub struct NeynarApi {
+    http: reqwest::Client,
+    base_url: String,
+    api_key: String,
+    signer_uuid: String,
+}
+
+#[derive(Deserialize)]
+struct NotificationsResponse {
+    notifications: Vec<Notification>,
+    #[serde(default)]
+    next: Option<NextCursor>,
+}
+
+#[derive(Deserialize)]
+struct Notification {
+    #[serde(default)]
+    cast: Option<Cast>,
+}
+
+#[derive(Deserialize)]
+struct NextCursor {
+    cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PublishResponse {
+    cast: PublishedCast,
+}
+
+#[derive(Deserialize)]
+struct PublishedCast {
+    hash: String,
+}
+
+impl NeynarApi {
+    pub fn new(base_url: &str, api_key: &str, signer_uuid: &str) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key: api_key.to_string(),
+            signer_uuid: signer_uuid.to_string(),
+        }
+    }
+
+    async fn send<T: serde::de::DeserializeOwned>(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<T, FarcasterError> {
+        let response = request.header("x-api-key", &self.api_key).send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let url = response.url().to_string();
+            let body = response.text().await.unwrap_or_default();
+            return Err(FarcasterError::Status {
+                status: status.as_u16(),
+                url,
+                body: body.chars().take(200).collect(),
+            });
+        }
+        Ok(response.json().await?)
+    }
+}
+
+#[async_trait]
+impl FarcasterApi for NeynarApi {
+    async fn mentions(
+        &self,
+        fid: u64,
+        cursor: Option<String>,
+    ) -> Result<MentionsPage, FarcasterError> {
+        let mut query = vec![
+            ("fid", fid.to_string()),
+            ("type", "mentions,replies".to_string()),
+        ];
+        if let Some(cursor) = cursor {
+            query.push(("cursor", cursor));
+        }
+        let request = self
+            .http
+            .get(format!("{}/v2/farcaster/notifications", self.base_url))
+            .query(&query);
+
+        let response: NotificationsResponse = self.send(request).await?;
+        Ok(MentionsPage {
+            casts: response
+                .notifications
+                .into_iter()
+                .filter_map(|notification| notification.cast)
+                .collect(),
+            next_cursor: response.next.and_then(|next| next.cursor),
+        })
+    }
+
+    async fn publish_cast(&self, text: &str, parent_hash: &str) -> Result<String, FarcasterError> {
+        let request = self
+            .http
+            .post(format!("{}/v2/farcaster/cast", self.base_url))
+            .json(&json!({
+                "signer_uuid": self.signer_uuid,
+                "text": text,
+                "parent": parent_hash,
+            }));
+
+        let response: PublishResponse = self.send(request).await?;
+        Ok(response.cast.hash)
+    }
+}
+
+/// Splits `text` into casts of at most `max_bytes`, breaking between words.
+/// Words longer than a cast are broken between characters.
+pub fn split_cast(text: &str, max_bytes: usize) -> Vec<String> {
+    let mut casts = Vec::new();
+    let mut current = String::new();
+
+    let mut flush = |current: &mut String| {
+        let cast = current.trim();
+        if !cast.is_empty() {
+            casts.push(cast.to_string());
+        }
+        current.clear();
+    };
+
+    for piece in text.split_inclusive(char::is_whitespace) {
+        let word = piece.trim_end();
+        if current.len() + word.len() > max_bytes {
+            flush(&mut current);
+        }
+        if word.len() > max_bytes {
+            for c in piece.chars() {
+                if current.len() + c.len_utf8() > max_bytes {
+                    flush(&mut current);
+                }
+                current.push(c);
+            }
+            continue;
+        }
+        current.push_str(piece);
+    }
+    flush(&mut current);
+    casts
+}
+
+#[derive(Clone)]
+pub struct FarcasterClient<M: CompletionModel, E: EmbeddingModel + 'static> {
+    agent: Agent<M, E>,
+    attention: Attention<M>,
+    api: Arc<dyn FarcasterApi>,
+    fid: u64,
+    poll_interval: Duration,
+}
+
+impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> FarcasterClient<M, E> {
+    /// A client answering as the account `fid`.
+    pub fn new(
+        agent: Agent<M, E>,
+        attention: Attention<M>,
+        api: Arc<dyn FarcasterApi>,
+        fid: u64,
+    ) -> Self {
+        Self {
+            agent,
+            attention,
+            api,
+            fid,
+            poll_interval: Duration::from_secs(60),
+        }
+    }
+
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    pub async fn start(&self) -> Result<(), FarcasterError> {
+        info!(fid = self.fid, "Starting Farcaster bot");
+
+        loop {
+            match self.poll().await {
+                Ok(handled) => debug!(handled, "Polled Farcaster mentions"),
+                Err(err) => error!(?err, "Failed to poll Farcaster mentions"),
+            }
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+
+    /// Name of the mention feed cursor, the timestamp of the newest handled
+    /// cast.
+    fn feed(&self) -> String {
+        format!("farcaster:{}:mentions", self.fid)
+    }
+
+    /// Handles every cast since the last poll, oldest first, and returns how
+    /// many were new.
+    pub async fn poll(&self) -> Result<usize, FarcasterError> {
+        let knowledge = self.agent.knowledge();
+        let since = knowledge
+            .feed_cursor(&self.feed())
+            .await
+            .map_err(|e| FarcasterError::Store(format!("{e:?}")))?
+            .and_then(|cursor| chrono::DateTime::parse_from_rfc3339(&cursor).ok())
+            .map(|since| since.with_timezone(&chrono::Utc));
+
+        let mut casts = self.fetch_since(since).await?;
+        casts.reverse();
+
+        let mut handled = 0;
+        for cast in casts {
+            // Casts sharing the cursor's timestamp are fetched again.
+            let seen = knowledge
+                .message_exists(&cast.hash)
+                .await
+                .map_err(|e| FarcasterError::Store(format!("{e:?}")))?;
+            if !seen && cast.author.fid != self.fid {
+                self.handle_cast(cast.clone()).await?;
+                handled += 1;
+            }
+            knowledge
+                .set_feed_cursor(&self.feed(), &cast.timestamp.to_rfc3339())
+                .await
+                .map_err(|e| FarcasterError::Store(format!("{e:?}")))?;
+        }
+        Ok(handled)
+    }
+
+    /// Casts no older than `since`, newest first. Without a cursor only the
+    /// first page is read, so a new account does not answer its history.
+    async fn fetch_since(
+        &self,
+        since: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Result<Vec<Cast>, FarcasterError> {
+        let mut casts = Vec::new();
+        let mut cursor = None;
+
+        for _ in 0..MAX_PAGES {
+            let page = self.api.mentions(self.fid, cursor).await?;
+            let (count, before) = (page.casts.len(), casts.len());
+            casts.extend(
+                page.casts
+                    .into_iter()
+                    .take_while(|cast| since.map_or(true, |since| cast.timestamp >= since)),
+            );
+            if since.is_none() || casts.len() - before < count {
+                break;
+            }
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        Ok(casts)
+    }
+
+    async fn handle_cast(&self, cast: Cast) -> Result<(), FarcasterError> {
+        let knowledge = self.agent.knowledge();
+        let knowledge_msg = Message::from(cast.clone());
+
+        knowledge
+            .create_message(knowledge_msg.clone())
+            .await
+            .map_err(|e| FarcasterError::Store(e.to_string()))?;
+
+        let history = knowledge
+            .channel_messages(
+                &knowledge_msg.channel_id,
+                self.attention.config().max_history_messages,
+            )
+            .await
+            .unwrap_or_else(|err| {
+                error!(?err, "Failed to fetch thread history");
+                Vec::new()
+            });
+        let recent_replies = knowledge
+            .recent_replies(&knowledge_msg.channel_id, RECENT_REPLIES)
+            .await
+            .unwrap_or_else(|err| {
+                error!(?err, "Failed to fetch recent replies");
+                Vec::new()
+            });
+
+        let mentioned_names: HashSet<String> = cast
+            .text
+            .split_whitespace()
+            .filter_map(|word| word.strip_prefix('@'))
+            .map(|name| {
+                name.trim_end_matches(|c: char| !c.is_alphanumeric())
+                    .to_string()
+            })
+            .collect();
+
+        let context = AttentionContext {
+            message_content: cast.text.clone(),
+            mentioned_names,
+            history,
+            recent_replies,
+            channel_id: knowledge_msg.channel_id.clone(),
+            channel_type: knowledge_msg.channel_type.clone(),
+            source: knowledge_msg.source.clone(),
+            topic_match: self.agent.character.topic_match(&cast.text),
+            familiarity: None,
+        };
+
+        debug!(?context, "Attention context");
+
+        if self.attention.should_reply(&context).await != AttentionCommand::Respond {
+            debug!("Bot decided not to reply to cast");
+            return Ok(());
+        }
+
+        let agent = self
+            .agent
+            .builder()
+            .context(
+                &self
+                    .agent
+                    .locale()
+                    .preferences()
+                    .current_time(self.agent.clock().now_utc()),
+            )
+            .context("Please keep your responses concise and under 320 characters.")
+            .build();
+
+        let response = match agent.prompt(&cast.text).await {
+            Ok(response) => response,
+            Err(err) => {
+                error!(?err, "Failed to generate response");
+                return Ok(());
+            }
+        };
+
+        debug!(response = %response, "Generated response");
+
+        let draft = self
+            .agent
+            .process_response(
+                ResponseDraft::new(response, Source::Farcaster),
+                &MessageContext::from(&knowledge_msg),
+            )
+            .await;
+
+        // Each part replies to the previous one, so a long answer reads as a
+        // thread under the original cast.
+        let mut parent = cast.hash;
+        for text in split_cast(&draft.text, MAX_CAST_BYTES) {
+            let hash = self.api.publish_cast(&text, &parent).await?;
+            let reply = Message {
+                id: hash.clone(),
+                source: Source::Farcaster,
+                source_id: hash.clone(),
+                channel_type: ChannelType::Text,
+                channel_id: knowledge_msg.channel_id.clone(),
+                account_id: self.fid.to_string(),
+                role: "assistant".to_string(),
+                content: text,
+                created_at: chrono::Utc::now(),
+            };
+            if let Err(err) = knowledge.create_message(reply).await {
+                error!(?err, "Failed to store reply cast");
+            }
+            parent = hash;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        attention::AttentionConfig,
+        character::Character,
+        test_utils::{self, FakeEmbeddingModel, MockHttpServer, ScriptedCompletionModel},
+    };
+    use std::sync::Mutex;
+
+    const BOT_FID: u64 = 42;
+
+    #[derive(Default)]
+    struct FakeApi {
+        /// Pages served in order; the last one is served again once reached.
+        pages: Mutex<Vec<MentionsPage>>,
+        cursors: Mutex<Vec<Option<String>>>,
+        /// `(text, parent)` of each published cast.
+        published: Mutex<Vec<(String, String)>>,
+    }
+
+    #[async_trait]
+    impl FarcasterApi for FakeApi {
+        async fn mentions(
+            &self,
+            _fid: u64,
+            cursor: Option<String>,
+        ) -> Result<MentionsPage, FarcasterError> {
+            self.cursors.lock().unwrap().push(cursor);
+            let mut pages = self.pages.lock().unwrap();
+            if pages.len() > 1 {
+                Ok(pages.remove(0))
+            } else {
+                Ok(pages.first().cloned().unwrap_or_default())
+            }
+        }
+
+        async fn publish_cast(
+            &self,
+            text: &str,
+            parent_hash: &str,
+        ) -> Result<String, FarcasterError> {
+            let mut published = self.published.lock().unwrap();
+            published.push((text.to_string(), parent_hash.to_string()));
+            Ok(format!("0xreply{}", published.len()))
+        }
+    }
+
+    fn cast(hash: &str, fid: u64, text: &str, minute: u32) -> Cast {
+        Cast {
+            hash: hash.to_string(),
+            parent_hash: None,
+            thread_hash: Some("0xthread".to_string()),
+            author: CastAuthor {
+                fid,
+                username: format!("user{fid}"),
+            },
+            text: text.to_string(),
+            timestamp: chrono::DateTime::parse_from_rfc3339(&format!(
+                "2024-12-01T10:{minute:02}:00Z"
+            ))
+            .unwrap()
+            .with_timezone(&chrono::Utc),
+        }
+    }
+
+    async fn client(
+        replies: &[&str],
+        api: Arc<FakeApi>,
+    ) -> FarcasterClient<ScriptedCompletionModel, FakeEmbeddingModel> {
+        let character = Character::new("shinobi", "You answer questions about Cartridge.");
+        let model = ScriptedCompletionModel::new(replies.iter().copied());
+        let agent = Agent::new(character, model.clone(), test_utils::knowledge_base().await);
+        let attention = Attention::new(
+            AttentionConfig {
+                bot_names: vec!["shinobi".to_string()],
+                ..Default::default()
+            },
+            model,
+        );
+        FarcasterClient::new(agent, attention, api, BOT_FID)
+    }
+
+    #[test]
+    fn test_split_cast() {
+        assert_eq!(split_cast("gm", MAX_CAST_BYTES), ["gm"]);
+
+        let text = "忍者 ".repeat(100);
+        let casts = split_cast(&text, MAX_CAST_BYTES);
+        assert!(casts.iter().all(|cast| cast.len() <= MAX_CAST_BYTES));
+        assert_eq!(casts.join(" "), text.trim_end());
+        // 7 bytes per word with the space, so 45 words fit in a cast.
+        assert_eq!(casts[0].len(), 45 * 7 - 1);
+
+        let word = "a".repeat(700);
+        let casts = split_cast(&format!("see {word} ok"), MAX_CAST_BYTES);
+        assert!(casts.iter().all(|cast| cast.len() <= MAX_CAST_BYTES));
+        assert_eq!(casts.concat(), format!("see{word}ok"));
+    }
+
+    #[tokio::test]
+    async fn test_poll_converts_and_replies_in_thread() {
+        let api = Arc::new(FakeApi::default());
+        *api.pages.lock().unwrap() = vec![MentionsPage {
+            casts: vec![
+                cast("0xb", 7, "thanks @shinobi", 2),
+                cast("0xa", 7, "@shinobi how do session keys work?", 1),
+            ],
+            next_cursor: Some("older".to_string()),
+        }];
+        let long_answer = "Session keys let a game sign for you. ".repeat(12);
+        let client = client(&[long_answer.as_str()], api.clone()).await;
+
+        assert_eq!(client.poll().await.unwrap(), 2);
+
+        // Only the first page is read without a cursor.
+        assert_eq!(*api.cursors.lock().unwrap(), [None]);
+
+        let published = api.published.lock().unwrap().clone();
+        assert_eq!(published.len(), 2);
+        assert!(published
+            .iter()
+            .all(|(text, _)| text.len() <= MAX_CAST_BYTES));
+        assert_eq!(published[0].1, "0xa");
+        assert_eq!(published[1].1, "0xreply1");
+
+        let knowledge = client.agent.knowledge();
+        let stored = knowledge.channel_messages("0xthread", 10).await.unwrap();
+        assert_eq!(stored.len(), 4);
+        assert!(knowledge.message_exists("0xa").await.unwrap());
+        let replies = knowledge.recent_replies("0xthread", 5).await.unwrap();
+        assert_eq!(replies.concat().len(), long_answer.trim_end().len() - 1);
+    }
+
+    #[tokio::test]
+    async fn test_cursor_and_dedup() {
+        let api = Arc::new(FakeApi::default());
+        *api.pages.lock().unwrap() = vec![MentionsPage {
+            casts: vec![cast("0xa", 7, "@shinobi gm?", 1)],
+            next_cursor: None,
+        }];
+        let client = client(&["gm!", "A devnet."], api.clone()).await;
+        assert_eq!(client.poll().await.unwrap(), 1);
+        assert_eq!(
+            client
+                .agent
+                .knowledge()
+                .feed_cursor("farcaster:42:mentions")
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("2024-12-01T10:01:00+00:00")
+        );
+
+        // The same cast again, an older one, the bot's own cast and a new one
+        // spread over two pages.
+        *api.pages.lock().unwrap() = vec![
+            MentionsPage {
+                casts: vec![
+                    cast("0xd", 7, "@shinobi what is katana?", 3),
+                    cast("0xown", BOT_FID, "@user7 hi", 2),
+                ],
+                next_cursor: Some("page2".to_string()),
+            },
+            MentionsPage {
+                casts: vec![
+                    cast("0xa", 7, "@shinobi gm?", 1),
+                    cast("0xold", 7, "@shinobi old", 0),
+                ],
+                next_cursor: Some("page3".to_string()),
+            },
+        ];
+        assert_eq!(client.poll().await.unwrap(), 1);
+        assert_eq!(
+            api.cursors.lock().unwrap()[1..],
+            [None, Some("page2".to_string())]
+        );
+
+        let published = api.published.lock().unwrap().clone();
+        assert_eq!(published.len(), 2);
+        assert_eq!(published[1], ("A devnet.".to_string(), "0xd".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_neynar_api() {
+        let server = MockHttpServer::start([
+            (
+                200,
+                r#"{"notifications":[{"type":"mention","cast":{"hash":"0xa","thread_hash":"0xa","author":{"fid":7,"username":"alice"},"text":"@shinobi gm","timestamp":"2024-12-01T10:00:00.000Z"}},{"type":"follows"}],"next":{"cursor":"abc"}}"#,
+            ),
+            (200, r#"{"success":true,"cast":{"hash":"0xb"}}"#),
+            (401, r#"{"message":"Invalid API key"}"#),
+        ])
+        .await;
+        let api = NeynarApi::new(&server.url(), "key", "signer");
+
+        let page = api.mentions(42, Some("prev".to_string())).await.unwrap();
+        assert_eq!(page.casts.len(), 1);
+        assert_eq!(page.casts[0].author.username, "alice");
+        assert_eq!(page.next_cursor.as_deref(), Some("abc"));
+
+        assert_eq!(api.publish_cast("gm", "0xa").await.unwrap(), "0xb");
+
+        let err = api.mentions(42, None).await.unwrap_err();
+        assert!(matches!(err, FarcasterError::Status { status: 401, .. }));
+
+        let requests = server.requests();
+        assert_eq!(
+            requests[0].path,
+            "/v2/farcaster/notifications?fid=42&type=mentions%2Creplies&cursor=prev"
+        );
+        assert_eq!(requests[0].headers["x-api-key"], "key");
+        assert_eq!(requests[1].method, "POST");
+        let body: serde_json::Value = serde_json::from_str(&requests[1].body).unwrap();
+        assert_eq!(
+            body,
+            json!({"signer_uuid": "signer", "text": "gm", "parent": "0xa"})
+        );
+    }
+}
@@ -0,0 +1,211 @@
+//! Discord forum channels, where every post is a thread of its own.
+//!
+//! Each post is handled as a separate conversation scoped to its thread, and
+//! the post's title and tags are given to the agent as context.
+
+use serenity::model::channel::{ChannelType, GuildChannel, Message};
+use serenity::model::id::{ChannelId, MessageId};
+
+use crate::knowledge;
+
+/// A post in a forum channel.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ForumPost {
+    pub thread_id: ChannelId,
+    pub forum_id: ChannelId,
+    pub title: String,
+    /// Names of the tags applied to the post.
+    pub tags: Vec<String>,
+    /// Locked posts can't be replied to. Archived ones are unarchived by the
+    /// next message, so they are handled like any other post.
+    pub locked: bool,
+}
+
+impl ForumPost {
+    /// `None` unless `thread` is a post in `forum`.
+    pub fn new(thread: &GuildChannel, forum: &GuildChannel) -> Option<Self> {
+        if forum.kind != ChannelType::Forum || thread.parent_id != Some(forum.id) {
+            return None;
+        }
+
+        let tags = thread
+            .applied_tags
+            .iter()
+            .filter_map(|id| forum.available_tags.iter().find(|tag| tag.id == *id))
+            .map(|tag| tag.name.clone())
+            .collect();
+
+        Some(Self {
+            thread_id: thread.id,
+            forum_id: forum.id,
+            title: thread.name.clone(),
+            tags,
+            locked: thread
+                .thread_metadata
+                .as_ref()
+                .is_some_and(|metadata| metadata.locked),
+        })
+    }
+
+    /// The message that opened the post, which shares the thread's id.
+    pub fn is_starter(&self, message_id: MessageId) -> bool {
+        message_id.get() == self.thread_id.get()
+    }
+
+    /// Maps a message in the post to the pipeline. A starter message with
+    /// only attachments is stored with the title as its content, so there is
+    /// still a question to answer.
+    pub fn to_message(&self, msg: Message) -> knowledge::Message {
+        let starter = self.is_starter(msg.id);
+        let mut message = knowledge::Message::from(msg);
+        message.channel_type = knowledge::ChannelType::Thread;
+        message.channel_id = self.thread_id.to_string();
+        if starter && message.content.trim().is_empty() {
+            message.content = self.title.clone();
+        }
+        message
+    }
+
+    /// Prompt context describing the post.
+    pub fn context(&self) -> String {
+        if self.tags.is_empty() {
+            format!("Forum post title: {}", self.title)
+        } else {
+            format!(
+                "Forum post title: {}\nForum post tags: {}",
+                self.title,
+                self.tags.join(", ")
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn forum() -> GuildChannel {
+        serde_json::from_value(json!({
+            "id": "100",
+            "type": 15,
+            "guild_id": "1",
+            "name": "support",
+            "position": 0,
+            "permission_overwrites": [],
+            "nsfw": false,
+            "flags": 0,
+            "available_tags": [
+                {"id": "7", "name": "vrf", "moderated": false, "emoji_id": null, "emoji_name": null},
+                {"id": "8", "name": "paymaster", "moderated": false, "emoji_id": null, "emoji_name": null}
+            ]
+        }))
+        .unwrap()
+    }
+
+    fn thread(parent_id: &str, archived: bool, locked: bool) -> GuildChannel {
+        serde_json::from_value(json!({
+            "id": "200",
+            "type": 11,
+            "guild_id": "1",
+            "parent_id": parent_id,
+            "name": "VRF fees?",
+            "owner_id": "5",
+            "position": 0,
+            "permission_overwrites": [],
+            "nsfw": false,
+            "flags": 0,
+            "message_count": 1,
+            "member_count": 1,
+            "rate_limit_per_user": 0,
+            "applied_tags": ["8"],
+            "thread_metadata": {
+                "archived": archived,
+                "auto_archive_duration": 1440,
+                "archive_timestamp": "2024-11-01T12:00:00+00:00",
+                "locked": locked
+            }
+        }))
+        .unwrap()
+    }
+
+    fn message(id: &str, content: &str) -> Message {
+        serde_json::from_value(json!({
+            "id": id,
+            "channel_id": "200",
+            "guild_id": "1",
+            "author": {
+                "id": "5",
+                "username": "alice",
+                "discriminator": "0",
+                "global_name": null,
+                "avatar": null,
+                "bot": false
+            },
+            "content": content,
+            "timestamp": "2024-11-01T12:00:00+00:00",
+            "edited_timestamp": null,
+            "tts": false,
+            "mention_everyone": false,
+            "mentions": [],
+            "mention_roles": [],
+            "attachments": [{
+                "id": "9",
+                "filename": "trace.png",
+                "size": 1024,
+                "url": "https://cdn.discordapp.com/attachments/200/9/trace.png",
+                "proxy_url": "https://media.discordapp.net/attachments/200/9/trace.png"
+            }],
+            "embeds": [],
+            "pinned": false,
+            "type": 0,
+            "flags": 0
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_post_maps_to_thread_conversation() {
+        let post = ForumPost::new(&thread("100", false, false), &forum()).unwrap();
+        assert_eq!(post.title, "VRF fees?");
+        assert_eq!(post.tags, ["paymaster"]);
+        assert_eq!(
+            post.context(),
+            "Forum post title: VRF fees?\nForum post tags: paymaster"
+        );
+
+        let starter = post.to_message(message("200", "How much does a vrf request cost?"));
+        assert_eq!(starter.channel_type, knowledge::ChannelType::Thread);
+        assert_eq!(starter.channel_id, "200");
+        assert_eq!(starter.content, "How much does a vrf request cost?");
+        assert!(post.is_starter(MessageId::new(200)));
+        assert!(!post.is_starter(MessageId::new(201)));
+    }
+
+    #[test]
+    fn test_attachment_only_starter_uses_title() {
+        let post = ForumPost::new(&thread("100", false, false), &forum()).unwrap();
+
+        assert_eq!(post.to_message(message("200", "")).content, "VRF fees?");
+        // Later attachment-only messages are kept as they are
+        assert_eq!(post.to_message(message("201", "")).content, "");
+    }
+
+    #[test]
+    fn test_archived_and_locked_posts() {
+        let archived = ForumPost::new(&thread("100", true, false), &forum()).unwrap();
+        assert!(!archived.locked);
+
+        let locked = ForumPost::new(&thread("100", true, true), &forum()).unwrap();
+        assert!(locked.locked);
+    }
+
+    #[test]
+    fn test_threads_outside_forums_are_not_posts() {
+        assert_eq!(ForumPost::new(&thread("101", false, false), &forum()), None);
+
+        let mut text = forum();
+        text.kind = ChannelType::Text;
+        assert_eq!(ForumPost::new(&thread("100", false, false), &text), None);
+    }
+}
@@ -0,0 +1,142 @@
+//! What the Discord client does when it joins or leaves a guild.
+//!
+//! On joining, the guild's channels are recorded and each gets a default
+//! [LISTEN_SETTING] from the [GuildPolicy], which admins change with
+//! `/listen on|off`. On leaving, the guild's channels, settings and pins are
+//! deleted, and its messages too unless they are kept.
+
+use regex::Regex;
+use serde::Deserialize;
+use serenity::model::channel::{ChannelType, GuildChannel};
+
+pub use crate::commands::LISTEN_SETTING;
+use crate::knowledge::{DiscoveredChannel, MessageRetention};
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GuildPolicy {
+    /// Regex matched against channel names. When set, the bot only listens
+    /// in the matching channels of a guild it joins; otherwise in all of
+    /// them.
+    pub listen_pattern: Option<String>,
+    /// Posted in the guild's system channel right after joining.
+    pub introduction: Option<String>,
+    /// Delete the guild's messages when the bot leaves it.
+    pub delete_messages: bool,
+}
+
+impl GuildPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        match &self.listen_pattern {
+            Some(pattern) => Regex::new(pattern)
+                .map(|_| ())
+                .map_err(|err| format!("guilds.listen_pattern: {err}")),
+            None => Ok(()),
+        }
+    }
+
+    /// Default [LISTEN_SETTING] of each channel, or `None` when the bot
+    /// listens everywhere and there is nothing to set.
+    pub fn default_listen(&self, channels: &[DiscoveredChannel]) -> Option<Vec<(String, bool)>> {
+        // Validated when the config is loaded
+        let pattern = Regex::new(self.listen_pattern.as_ref()?).ok()?;
+        Some(
+            channels
+                .iter()
+                .map(|channel| (channel.channel_id.clone(), pattern.is_match(&channel.name)))
+                .collect(),
+        )
+    }
+
+    pub fn retention(&self) -> MessageRetention {
+        if self.delete_messages {
+            MessageRetention::Delete
+        } else {
+            MessageRetention::Keep
+        }
+    }
+}
+
+/// The channels of a guild the bot can talk in: text, announcement and forum
+/// channels.
+pub fn discover<'a>(
+    channels: impl IntoIterator<Item = &'a GuildChannel>,
+) -> Vec<DiscoveredChannel> {
+    let mut channels = channels
+        .into_iter()
+        .filter(|channel| {
+            matches!(
+                channel.kind,
+                ChannelType::Text | ChannelType::News | ChannelType::Forum
+            )
+        })
+        .collect::<Vec<_>>();
+    channels.sort_by_key(|channel| channel.id);
+    channels
+        .into_iter()
+        .map(|channel| DiscoveredChannel {
+            channel_id: channel.id.to_string(),
+            channel_type: channel.kind.name().to_string(),
+            name: channel.name.clone(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn channel(id: &str, kind: u8, name: &str) -> GuildChannel {
+        serde_json::from_value(json!({
+            "id": id,
+            "type": kind,
+            "guild_id": "1",
+            "name": name,
+            "position": 0,
+            "permission_overwrites": [],
+            "nsfw": false,
+            "flags": 0
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_discovers_channels_the_bot_can_talk_in() {
+        let channels = [
+            channel("12", 0, "general"),
+            channel("11", 15, "support"),
+            channel("13", 2, "voice"),
+            channel("14", 4, "category"),
+        ];
+
+        let discovered = discover(&channels);
+        let ids: Vec<_> = discovered
+            .iter()
+            .map(|c| (c.channel_id.as_str(), c.channel_type.as_str()))
+            .collect();
+        assert_eq!(ids, [("11", "forum"), ("12", "text")]);
+    }
+
+    #[test]
+    fn test_default_listen() {
+        let channels = discover(&[channel("11", 0, "general"), channel("12", 0, "ask-the-bot")]);
+        assert_eq!(GuildPolicy::default().default_listen(&channels), None);
+
+        let policy = GuildPolicy {
+            listen_pattern: Some("^ask-".to_string()),
+            ..Default::default()
+        };
+        assert!(policy.validate().is_ok());
+        assert_eq!(
+            policy.default_listen(&channels),
+            Some(vec![("11".to_string(), false), ("12".to_string(), true)])
+        );
+
+        let invalid = GuildPolicy {
+            listen_pattern: Some("(".to_string()),
+            ..Default::default()
+        };
+        assert!(invalid.validate().is_err());
+    }
+}
@@ -0,0 +1,161 @@
+//! Who a message the Discord client sends may ping.
+//!
+//! Every send and edit carries an explicit allowed mentions policy: never
+//! `@everyone` or `@here`, users only when they take part in the exchange,
+//! and roles only when whitelisted for the guild. The text is sanitized too,
+//! so a reply quoting `@everyone` can't ping even if a policy is missed.
+
+use serenity::builder::{CreateAllowedMentions, CreateMessage, EditMessage};
+use serenity::model::id::{RoleId, UserId};
+
+const ZERO_WIDTH_SPACE: char = '\u{200B}';
+
+/// Mentions that notify the whole server.
+const MASS_MENTIONS: [&str; 2] = ["@everyone", "@here"];
+
+/// Users and roles a message may ping.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MentionPolicy {
+    pub users: Vec<UserId>,
+    pub roles: Vec<RoleId>,
+}
+
+impl MentionPolicy {
+    /// Pings nobody, for messages that answer no one in particular.
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    /// Pings the author of a message and the users it mentions, and the
+    /// roles whitelisted for the guild. Role ids that don't parse are
+    /// skipped; they are validated with the config.
+    pub fn participants(
+        author: UserId,
+        mentioned: impl IntoIterator<Item = UserId>,
+        roles: &[String],
+    ) -> Self {
+        let mut users = vec![author];
+        for user in mentioned {
+            if !users.contains(&user) {
+                users.push(user);
+            }
+        }
+        Self {
+            users,
+            roles: roles
+                .iter()
+                .filter_map(|id| id.parse::<u64>().ok())
+                .map(RoleId::new)
+                .collect(),
+        }
+    }
+
+    pub fn with_role(mut self, role: RoleId) -> Self {
+        if !self.roles.contains(&role) {
+            self.roles.push(role);
+        }
+        self
+    }
+
+    pub fn allowed_mentions(&self) -> CreateAllowedMentions {
+        CreateAllowedMentions::new()
+            .everyone(false)
+            .all_users(false)
+            .all_roles(false)
+            .users(self.users.clone())
+            .roles(self.roles.clone())
+    }
+
+    /// A message with the sanitized `text`.
+    pub fn create_message(&self, text: &str) -> CreateMessage {
+        CreateMessage::new()
+            .content(sanitize(text))
+            .allowed_mentions(self.allowed_mentions())
+    }
+
+    /// An edit replacing the content with the sanitized `text`.
+    pub fn edit_message(&self, text: &str) -> EditMessage {
+        EditMessage::new()
+            .content(sanitize(text))
+            .allowed_mentions(self.allowed_mentions())
+    }
+}
+
+/// Breaks `@everyone` and `@here` with a zero-width space, so they show as
+/// written but ping nobody.
+pub fn sanitize(text: &str) -> String {
+    MASS_MENTIONS
+        .iter()
+        .fold(text.to_string(), |text, mention| {
+            text.replace(mention, &format!("@{ZERO_WIDTH_SPACE}{}", &mention[1..]))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn allowed_mentions(message: &impl serde::Serialize) -> serde_json::Value {
+        serde_json::to_value(message).unwrap()["allowed_mentions"].clone()
+    }
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(
+            sanitize("@everyone and @here ping all"),
+            "@\u{200B}everyone and @\u{200B}here ping all"
+        );
+        assert_eq!(sanitize("ask <@42> or <@&7>"), "ask <@42> or <@&7>");
+        assert_eq!(sanitize(&sanitize("@here")), "@\u{200B}here");
+    }
+
+    #[test]
+    fn test_participants_policy() {
+        let policy = MentionPolicy::participants(
+            UserId::new(42),
+            [UserId::new(43), UserId::new(42)],
+            &["7".to_string(), "not-a-role".to_string()],
+        );
+        assert_eq!(policy.users, [UserId::new(42), UserId::new(43)]);
+        assert_eq!(policy.roles, [RoleId::new(7)]);
+
+        let message = policy.create_message("@everyone <@42> <@99> <@&7>");
+        let value = serde_json::to_value(&message).unwrap();
+        assert_eq!(value["content"], "@\u{200B}everyone <@42> <@99> <@&7>");
+        assert_eq!(
+            allowed_mentions(&message),
+            json!({
+                "parse": [],
+                "users": ["42", "43"],
+                "roles": ["7"],
+            })
+        );
+    }
+
+    #[test]
+    fn test_edits_and_no_mentions() {
+        let edit = MentionPolicy::none().edit_message("Thanks @here!");
+        let value = serde_json::to_value(&edit).unwrap();
+        assert_eq!(value["content"], "Thanks @\u{200B}here!");
+        assert_eq!(
+            allowed_mentions(&edit),
+            json!({
+                "parse": [],
+                "users": [],
+                "roles": [],
+            })
+        );
+
+        let escalation =
+            MentionPolicy::participants(UserId::new(42), [], &[]).with_role(RoleId::new(8));
+        assert_eq!(
+            allowed_mentions(&escalation.create_message("<@&8> <@42> needs help")),
+            json!({
+                "parse": [],
+                "users": ["42"],
+                "roles": ["8"],
+            })
+        );
+    }
+}
@@ -1,3 +1,28 @@
+pub mod chunks;
+#[cfg(feature = "discord")]
+pub mod delivery;
+pub mod describe_capabilities;
+#[cfg(feature = "discord")]
 pub mod discord;
+pub mod escalate;
+pub mod external;
+#[cfg(feature = "farcaster")]
+pub mod farcaster;
+#[cfg(feature = "discord")]
+pub mod forum;
+#[cfg(feature = "discord")]
+pub mod guilds;
+#[cfg(feature = "discord")]
+pub mod mentions;
+pub mod poller;
+pub mod post_tweet;
+pub mod presence;
+pub mod reactions;
+pub mod recorded_tool;
+pub mod refresh_knowledge;
+pub mod streaming;
+pub mod supervisor;
+#[cfg(feature = "telegram")]
 pub mod telegram;
+#[cfg(feature = "twitter")]
 pub mod twitter;
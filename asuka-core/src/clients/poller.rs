@@ -0,0 +1,215 @@
+//! Interval control for clients that poll an API for new messages. Polls come
+//! faster while messages keep arriving and back off while it is quiet, never
+//! faster than the API's rate limit allows.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Clone, Debug)]
+pub struct PollerConfig {
+    pub min_interval: Duration,
+    pub max_interval: Duration,
+    /// Number of recent polls considered when deciding whether to back off.
+    pub window: usize,
+}
+
+impl Default for PollerConfig {
+    fn default() -> Self {
+        Self {
+            min_interval: Duration::from_secs(15),
+            max_interval: Duration::from_secs(600),
+            window: 5,
+        }
+    }
+}
+
+/// Rate limit state reported by the API with a response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Requests left in the current window.
+    pub remaining: u32,
+    /// Time until the window resets.
+    pub reset_in: Duration,
+}
+
+impl RateLimit {
+    /// Reads the `x-rate-limit-remaining` and `x-rate-limit-reset` headers,
+    /// the latter being a Unix timestamp in seconds.
+    pub fn from_headers(headers: &reqwest::header::HeaderMap) -> Option<Self> {
+        let header = |name: &str| headers.get(name)?.to_str().ok()?.trim().parse::<u64>().ok();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        Some(Self {
+            remaining: header("x-rate-limit-remaining")?.try_into().ok()?,
+            reset_in: Duration::from_secs(header("x-rate-limit-reset")?.saturating_sub(now)),
+        })
+    }
+
+    /// Shortest delay that spreads the remaining requests over the rest of
+    /// the window.
+    fn floor(&self) -> Duration {
+        match self.remaining {
+            0 => self.reset_in,
+            remaining => self.reset_in / remaining,
+        }
+    }
+}
+
+/// Current state of an [AdaptivePoller].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PollerStats {
+    /// Delay before the next poll.
+    pub interval: Duration,
+    pub rate_limit_remaining: Option<u32>,
+}
+
+/// Picks the delay before each poll from the results of the recent ones.
+///
+/// A poll that returned messages drops the interval to the minimum. Once
+/// every poll in the window came back empty, each further empty poll doubles
+/// it up to the maximum. The [RateLimit] reported with a poll is a hard floor
+/// for the delay after it, even above the maximum.
+#[derive(Clone, Debug)]
+pub struct AdaptivePoller {
+    config: PollerConfig,
+    recent: VecDeque<usize>,
+    interval: Duration,
+    rate_limit: Option<RateLimit>,
+    delay: Duration,
+}
+
+impl AdaptivePoller {
+    pub fn new(config: PollerConfig) -> Self {
+        Self {
+            interval: config.min_interval,
+            recent: VecDeque::with_capacity(config.window),
+            rate_limit: None,
+            delay: config.min_interval,
+            config,
+        }
+    }
+
+    /// Records a poll that returned `messages` and returns the delay before
+    /// the next one.
+    pub fn observe(&mut self, messages: usize, rate_limit: Option<RateLimit>) -> Duration {
+        if self.recent.len() == self.config.window.max(1) {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(messages);
+
+        if messages > 0 {
+            self.interval = self.config.min_interval;
+        } else if self.recent.iter().all(|count| *count == 0) {
+            self.interval = self
+                .interval
+                .saturating_mul(2)
+                .clamp(self.config.min_interval, self.config.max_interval);
+        }
+        self.delay = match &rate_limit {
+            Some(rate_limit) => self.interval.max(rate_limit.floor()),
+            None => self.interval,
+        };
+        if rate_limit.is_some() {
+            self.rate_limit = rate_limit;
+        }
+        self.delay
+    }
+
+    pub fn stats(&self) -> PollerStats {
+        PollerStats {
+            interval: self.delay,
+            rate_limit_remaining: self.rate_limit.map(|rate_limit| rate_limit.remaining),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn poller() -> AdaptivePoller {
+        AdaptivePoller::new(PollerConfig {
+            min_interval: Duration::from_secs(15),
+            max_interval: Duration::from_secs(600),
+            window: 3,
+        })
+    }
+
+    fn secs(delays: Vec<Duration>) -> Vec<u64> {
+        delays.into_iter().map(|delay| delay.as_secs()).collect()
+    }
+
+    #[test]
+    fn test_quiet_polls_back_off() {
+        let mut poller = poller();
+        let delays = (0..8).map(|_| poller.observe(0, None)).collect();
+        assert_eq!(secs(delays), [30, 60, 120, 240, 480, 600, 600, 600]);
+    }
+
+    #[test]
+    fn test_burst_speeds_up_and_holds() {
+        let mut poller = poller();
+        for _ in 0..6 {
+            poller.observe(0, None);
+        }
+        assert_eq!(poller.stats().interval, Duration::from_secs(600));
+
+        // Mentions spike, then dry up: the interval holds at the minimum
+        // until the window is empty again.
+        let delays = [4, 9, 1, 0, 0, 0, 0, 0]
+            .into_iter()
+            .map(|mentions| poller.observe(mentions, None))
+            .collect();
+        assert_eq!(secs(delays), [15, 15, 15, 15, 15, 30, 60, 120]);
+    }
+
+    #[test]
+    fn test_rate_limit_is_a_floor() {
+        let mut poller = poller();
+        let limit = RateLimit {
+            remaining: 10,
+            reset_in: Duration::from_secs(900),
+        };
+        assert_eq!(poller.observe(3, Some(limit)), Duration::from_secs(90));
+        assert_eq!(poller.observe(3, None), Duration::from_secs(15));
+        assert_eq!(poller.stats().rate_limit_remaining, Some(10));
+
+        let exhausted = RateLimit {
+            remaining: 0,
+            reset_in: Duration::from_secs(700),
+        };
+        assert_eq!(poller.observe(3, Some(exhausted)), Duration::from_secs(700));
+        assert_eq!(
+            poller.stats(),
+            PollerStats {
+                interval: Duration::from_secs(700),
+                rate_limit_remaining: Some(0),
+            }
+        );
+    }
+
+    #[test]
+    fn test_rate_limit_from_headers() {
+        let reset = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 120;
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert("x-rate-limit-remaining", "7".parse().unwrap());
+        headers.insert("x-rate-limit-reset", reset.to_string().parse().unwrap());
+
+        let limit = RateLimit::from_headers(&headers).unwrap();
+        assert_eq!(limit.remaining, 7);
+        assert!(limit.reset_in <= Duration::from_secs(120));
+        assert!(limit.reset_in >= Duration::from_secs(118));
+
+        headers.remove("x-rate-limit-reset");
+        assert_eq!(RateLimit::from_headers(&headers), None);
+    }
+}
@@ -0,0 +1,395 @@
+//! Tool letting trusted users have the agent draft and publish tweets.
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use rig::{
+    completion::{CompletionModel, Prompt, ToolDefinition},
+    embeddings::EmbeddingModel,
+    tool::Tool,
+};
+use serde::Deserialize;
+use serde_json::json;
+use thiserror::Error;
+use tracing::{error, info};
+#[cfg(feature = "twitter")]
+use twitter_v2::{authorization::Authorization, id::NumericId, TwitterApi};
+
+use crate::{
+    agent::Agent,
+    knowledge::{ChannelType, Message, Source},
+    permissions::PermissionTier,
+};
+
+pub const MAX_TWEET_LENGTH: usize = 280;
+
+/// What the model is told the tool does, also listed in the agent's
+/// [capabilities](crate::capabilities).
+pub const DESCRIPTION: &str = "Publish a tweet from the project account. Pass `topic` to get a \
+    draft to show the user; only pass `text` once the user has confirmed the exact wording.";
+
+/// Length Twitter counts for any link, whatever its actual length.
+const URL_LENGTH: usize = 23;
+
+#[derive(Error, Debug)]
+pub enum PostTweetError {
+    #[error("Only trusted users can post tweets")]
+    PermissionDenied,
+    #[error("Either text or topic is required")]
+    MissingArgs,
+    #[error("Tweet is empty")]
+    Empty,
+    #[error("Tweet is {0} characters, the limit is {MAX_TWEET_LENGTH}")]
+    TooLong(usize),
+    #[error("Tweet contains banned term {0:?}")]
+    Banned(String),
+    #[error("Failed to draft tweet: {0}")]
+    Draft(String),
+    #[error("Failed to post tweet: {0}")]
+    Api(String),
+}
+
+/// Publishes tweets. Implemented for `twitter_v2::TwitterApi` with the
+/// `twitter` feature and faked in tests.
+#[async_trait]
+pub trait TweetPoster: Send + Sync {
+    /// Posts `text` and returns the id of the new tweet.
+    async fn post(&self, text: &str) -> Result<String, PostTweetError>;
+
+    /// Posts `text` as a reply to `in_reply_to` and returns the id of the new
+    /// tweet.
+    async fn reply(&self, text: &str, in_reply_to: u64) -> Result<String, PostTweetError>;
+}
+
+#[cfg(feature = "twitter")]
+#[async_trait]
+impl<A: Authorization + Send + Sync> TweetPoster for TwitterApi<A> {
+    async fn post(&self, text: &str) -> Result<String, PostTweetError> {
+        let response = self
+            .post_tweet()
+            .text(text.to_string())
+            .send()
+            .await
+            .map_err(|e| PostTweetError::Api(e.to_string()))?;
+        response
+            .into_data()
+            .map(|tweet| tweet.id.to_string())
+            .ok_or_else(|| PostTweetError::Api("No tweet in response".to_string()))
+    }
+
+    async fn reply(&self, text: &str, in_reply_to: u64) -> Result<String, PostTweetError> {
+        let response = self
+            .post_tweet()
+            .in_reply_to_tweet_id(NumericId::new(in_reply_to))
+            .text(text.to_string())
+            .send()
+            .await
+            .map_err(|e| PostTweetError::Api(e.to_string()))?;
+        response
+            .into_data()
+            .map(|tweet| tweet.id.to_string())
+            .ok_or_else(|| PostTweetError::Api("No tweet in response".to_string()))
+    }
+}
+
+/// Length of `text` as Twitter counts it: links count as [URL_LENGTH] and
+/// characters outside the Latin ranges, such as CJK and emoji, count twice.
+pub fn tweet_length(text: &str) -> usize {
+    fn weight(c: char) -> usize {
+        match c as u32 {
+            0..=4351 | 8192..=8205 | 8208..=8223 | 8242..=8247 => 1,
+            _ => 2,
+        }
+    }
+
+    let total = text.chars().map(weight).sum::<usize>();
+    text.split_whitespace()
+        .filter(|word| word.starts_with("http://") || word.starts_with("https://"))
+        .fold(total, |total, url| {
+            total - url.chars().map(weight).sum::<usize>() + URL_LENGTH
+        })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PostTweetArgs {
+    pub text: Option<String>,
+    pub topic: Option<String>,
+}
+
+/// Posts `text` when given. With only a `topic` it drafts a tweet and returns
+/// it for the user to confirm; the model then calls again with the confirmed
+/// text to post it.
+pub struct PostTweet<M: CompletionModel, E: EmbeddingModel + 'static> {
+    agent: Agent<M, E>,
+    poster: Arc<dyn TweetPoster>,
+    caller: PermissionTier,
+    banned_terms: Arc<Vec<String>>,
+}
+
+impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> PostTweet<M, E> {
+    /// A tool acting for a user of tier `caller`.
+    pub fn new(agent: Agent<M, E>, poster: Arc<dyn TweetPoster>, caller: PermissionTier) -> Self {
+        Self {
+            agent,
+            poster,
+            caller,
+            banned_terms: Arc::new(Vec::new()),
+        }
+    }
+
+    /// Terms that must not appear in a posted tweet, matched case-insensitively.
+    pub fn with_banned_terms(mut self, terms: impl IntoIterator<Item = String>) -> Self {
+        self.banned_terms = Arc::new(terms.into_iter().map(|t| t.to_lowercase()).collect());
+        self
+    }
+
+    pub fn validate(&self, text: &str) -> Result<(), PostTweetError> {
+        if text.trim().is_empty() {
+            return Err(PostTweetError::Empty);
+        }
+        let length = tweet_length(text);
+        if length > MAX_TWEET_LENGTH {
+            return Err(PostTweetError::TooLong(length));
+        }
+        let lowercase = text.to_lowercase();
+        if let Some(term) = self
+            .banned_terms
+            .iter()
+            .find(|term| lowercase.contains(term.as_str()))
+        {
+            return Err(PostTweetError::Banned(term.clone()));
+        }
+        Ok(())
+    }
+
+    async fn draft(agent: Agent<M, E>, topic: String) -> Result<String, PostTweetError> {
+        let prompt = format!(
+            "Write a single tweet about: {topic}\n\
+            Stay under {MAX_TWEET_LENGTH} characters. Answer with the tweet text only."
+        );
+        let draft = agent
+            .builder()
+            .build()
+            .prompt(prompt.as_str())
+            .await
+            .map_err(|e| PostTweetError::Draft(e.to_string()))?;
+        Ok(draft.trim().trim_matches('"').to_string())
+    }
+
+    async fn publish(
+        agent: Agent<M, E>,
+        poster: Arc<dyn TweetPoster>,
+        text: String,
+    ) -> Result<String, PostTweetError> {
+        let id = poster.post(&text).await?;
+        info!(id, "Posted tweet");
+
+        let message = Message {
+            id: id.clone(),
+            source: Source::Twitter,
+            source_id: id.clone(),
+            channel_type: ChannelType::Text,
+            channel_id: id.clone(),
+            account_id: agent.character.name.clone(),
+            role: "assistant".to_string(),
+            content: text,
+            created_at: chrono::Utc::now(),
+        };
+        if let Err(err) = agent.knowledge().create_message(message).await {
+            error!(?err, "Failed to store posted tweet");
+        }
+
+        Ok(format!("Posted: https://x.com/i/status/{id}"))
+    }
+}
+
+impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> Tool for PostTweet<M, E> {
+    const NAME: &'static str = "post_tweet";
+
+    type Error = PostTweetError;
+    type Args = PostTweetArgs;
+    type Output = String;
+
+    async fn definition(&self, _prompt: String) -> ToolDefinition {
+        ToolDefinition {
+            name: Self::NAME.to_string(),
+            description: DESCRIPTION.to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "text": {
+                        "type": "string",
+                        "description": "Confirmed tweet text to post as is"
+                    },
+                    "topic": {
+                        "type": "string",
+                        "description": "What to tweet about, to draft a tweet for confirmation"
+                    }
+                }
+            }),
+        }
+    }
+
+    async fn call(&self, args: Self::Args) -> Result<Self::Output, Self::Error> {
+        if self.caller < PermissionTier::Trusted {
+            return Err(PostTweetError::PermissionDenied);
+        }
+
+        let agent = self.agent.clone();
+        // Tool futures must be Sync, which the store and model futures are
+        // not, so the work runs on its own task.
+        let task = match (args.text, args.topic) {
+            (Some(text), _) => {
+                self.validate(&text)?;
+                let poster = self.poster.clone();
+                tokio::spawn(async move { Self::publish(agent, poster, text).await })
+            }
+            (None, Some(topic)) => tokio::spawn(async move {
+                let draft = Self::draft(agent, topic).await?;
+                Ok(format!(
+                    "Draft tweet ({} characters):\n\n{draft}\n\nConfirm and I will post it.",
+                    tweet_length(&draft)
+                ))
+            }),
+            (None, None) => return Err(PostTweetError::MissingArgs),
+        };
+
+        task.await.map_err(|e| PostTweetError::Api(e.to_string()))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        character::Character,
+        test_utils::{self, ScriptedCompletionModel},
+    };
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct FakePoster {
+        posted: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl TweetPoster for FakePoster {
+        async fn post(&self, text: &str) -> Result<String, PostTweetError> {
+            let mut posted = self.posted.lock().unwrap();
+            posted.push(text.to_string());
+            Ok(format!("{}", 1000 + posted.len()))
+        }
+
+        async fn reply(&self, text: &str, _in_reply_to: u64) -> Result<String, PostTweetError> {
+            self.post(text).await
+        }
+    }
+
+    async fn tool(
+        replies: &[&str],
+        caller: PermissionTier,
+    ) -> (
+        PostTweet<ScriptedCompletionModel, test_utils::FakeEmbeddingModel>,
+        Arc<FakePoster>,
+    ) {
+        let character = Character::new("shinobi", "You speak for Cartridge.");
+        let agent = Agent::new(
+            character,
+            ScriptedCompletionModel::new(replies.iter().copied()),
+            test_utils::knowledge_base().await,
+        );
+        let poster = Arc::new(FakePoster::default());
+        let tool = PostTweet::new(agent, poster.clone(), caller)
+            .with_banned_terms(["airdrop".to_string()]);
+        (tool, poster)
+    }
+
+    #[test]
+    fn test_tweet_length() {
+        assert_eq!(tweet_length("gm"), 2);
+        let url = format!("v2 is out https://cartridge.gg/{}", "a".repeat(100));
+        assert_eq!(tweet_length(&url), 10 + URL_LENGTH);
+        assert_eq!(tweet_length("忍者"), 4);
+    }
+
+    #[tokio::test]
+    async fn test_validation() {
+        let (tool, poster) = tool(&[], PermissionTier::Trusted).await;
+
+        let long = "a".repeat(MAX_TWEET_LENGTH + 1);
+        let cases = [
+            ("  ", "Tweet is empty"),
+            (long.as_str(), "Tweet is 281 characters, the limit is 280"),
+            (
+                "Claim your AIRDROP now",
+                "Tweet contains banned term \"airdrop\"",
+            ),
+        ];
+        for (text, expected) in cases {
+            let args = PostTweetArgs {
+                text: Some(text.to_string()),
+                topic: None,
+            };
+            assert_eq!(tool.call(args).await.unwrap_err().to_string(), expected);
+        }
+        assert!(poster.posted.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_draft_then_confirm() {
+        let (tool, poster) = tool(&["\"Cartridge v2 is live!\""], PermissionTier::Admin).await;
+
+        let draft = tool
+            .call(PostTweetArgs {
+                text: None,
+                topic: Some("the v2 release".to_string()),
+            })
+            .await
+            .unwrap();
+        assert!(draft.contains("\n\nCartridge v2 is live!\n\n"));
+        assert!(poster.posted.lock().unwrap().is_empty());
+
+        let posted = tool
+            .call(PostTweetArgs {
+                text: Some("Cartridge v2 is live!".to_string()),
+                topic: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(posted, "Posted: https://x.com/i/status/1001");
+        assert_eq!(*poster.posted.lock().unwrap(), ["Cartridge v2 is live!"]);
+
+        let stored = tool
+            .agent
+            .knowledge()
+            .list_messages(None, 10)
+            .await
+            .unwrap();
+        let message = &stored.items[0];
+        assert_eq!(message.id, "1001");
+        assert_eq!(message.source, Source::Twitter);
+        assert_eq!(message.role, "assistant");
+    }
+
+    #[tokio::test]
+    async fn test_permission_denied() {
+        let (tool, poster) = tool(&["draft"], PermissionTier::User).await;
+
+        for args in [
+            PostTweetArgs {
+                text: Some("gm".to_string()),
+                topic: None,
+            },
+            PostTweetArgs {
+                text: None,
+                topic: Some("gm".to_string()),
+            },
+        ] {
+            assert!(matches!(
+                tool.call(args).await,
+                Err(PostTweetError::PermissionDenied)
+            ));
+        }
+        assert!(poster.posted.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,251 @@
+//! The bot's presence, kept in line with its [Status] so users and operators
+//! can tell at a glance whether it is healthy.
+//!
+//! [PresenceManager] shows the configured activity while healthy and the
+//! text of the most pressing [Condition] otherwise. Changes are applied at
+//! most once per [PresenceConfig::min_interval_secs], as Discord limits
+//! presence updates; a change arriving sooner is applied once the interval
+//! is up, together with any that follow it.
+//!
+//! ```toml
+//! [presence]
+//! activity = "Listening | !ask me about VRF"
+//! degraded = "degraded: answers may lack docs"
+//! ```
+
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::{sync::watch, time::Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+use crate::status::{Condition, Status};
+
+/// `[presence]` settings of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PresenceConfig {
+    /// Activity shown while healthy.
+    pub activity: String,
+    /// Shown while the knowledge base is first loaded.
+    pub warming: String,
+    /// Shown while the embedding provider is failing.
+    pub degraded: String,
+    /// Shown while the completion provider is rate limiting the bot.
+    pub rate_limited: String,
+    /// Shown while the completion budget is spent.
+    pub over_budget: String,
+    /// Seconds between presence updates.
+    pub min_interval_secs: u64,
+}
+
+impl Default for PresenceConfig {
+    fn default() -> Self {
+        Self {
+            activity: "Listening | ask me about the docs".to_string(),
+            warming: "Warming up: loading the docs".to_string(),
+            degraded: "degraded: answers may lack docs".to_string(),
+            rate_limited: "rate limited: answers may be slow".to_string(),
+            over_budget: "resting until tomorrow".to_string(),
+            min_interval_secs: 15,
+        }
+    }
+}
+
+impl PresenceConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let texts = [
+            ("activity", &self.activity),
+            ("warming", &self.warming),
+            ("degraded", &self.degraded),
+            ("rate_limited", &self.rate_limited),
+            ("over_budget", &self.over_budget),
+        ];
+        if let Some((name, _)) = texts.iter().find(|(_, text)| text.trim().is_empty()) {
+            return Err(format!("presence.{name} must not be empty"));
+        }
+        // Discord cuts custom statuses off at 128 characters
+        if let Some((name, _)) = texts.iter().find(|(_, text)| text.chars().count() > 128) {
+            return Err(format!("presence.{name} is longer than 128 characters"));
+        }
+        if self.min_interval_secs == 0 {
+            return Err("presence.min_interval_secs must be positive".to_string());
+        }
+        Ok(())
+    }
+
+    /// The presence to show in `status`.
+    pub fn presence(&self, status: &Status) -> Presence {
+        match status.headline() {
+            None => Presence {
+                text: self.activity.clone(),
+                idle: false,
+            },
+            Some(condition) => Presence {
+                text: match condition {
+                    Condition::Warming => self.warming.clone(),
+                    Condition::Degraded => self.degraded.clone(),
+                    Condition::RateLimited => self.rate_limited.clone(),
+                    Condition::OverBudget => self.over_budget.clone(),
+                },
+                idle: true,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Presence {
+    pub text: String,
+    /// Shown as idle rather than online.
+    pub idle: bool,
+}
+
+/// Where the presence is set, the shard's gateway connection on Discord.
+#[async_trait]
+pub trait PresenceSink: Send + Sync {
+    async fn set_presence(&self, presence: &Presence);
+}
+
+pub struct PresenceManager {
+    config: PresenceConfig,
+    status: watch::Receiver<Status>,
+}
+
+impl PresenceManager {
+    pub fn new(config: PresenceConfig, status: watch::Receiver<Status>) -> Self {
+        Self { config, status }
+    }
+
+    /// Sets the presence for the current status, then again on every change
+    /// until `shutdown` is cancelled or the status is no longer published.
+    pub async fn run(mut self, sink: Arc<dyn PresenceSink>, shutdown: CancellationToken) {
+        let interval = Duration::from_secs(self.config.min_interval_secs);
+        let mut shown: Option<Presence> = None;
+        loop {
+            let presence = self.config.presence(&self.status.borrow_and_update());
+            if shown.as_ref() != Some(&presence) {
+                debug!(
+                    text = presence.text,
+                    idle = presence.idle,
+                    "Setting presence"
+                );
+                sink.set_presence(&presence).await;
+                shown = Some(presence);
+            }
+            let next_allowed = Instant::now() + interval;
+
+            tokio::select! {
+                changed = self.status.changed() => {
+                    if changed.is_err() {
+                        warn!("Status no longer published, keeping presence");
+                        return;
+                    }
+                }
+                _ = shutdown.cancelled() => return,
+            }
+            tokio::select! {
+                _ = tokio::time::sleep_until(next_allowed) => {}
+                _ = shutdown.cancelled() => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::status::StatusBoard;
+
+    #[derive(Default)]
+    struct RecordingShard {
+        presences: Mutex<Vec<(Instant, Presence)>>,
+    }
+
+    #[async_trait]
+    impl PresenceSink for RecordingShard {
+        async fn set_presence(&self, presence: &Presence) {
+            self.presences
+                .lock()
+                .unwrap()
+                .push((Instant::now(), presence.clone()));
+        }
+    }
+
+    impl RecordingShard {
+        fn texts(&self) -> Vec<String> {
+            self.presences
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(_, presence)| presence.text.clone())
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_presence_for_each_state() {
+        let config = PresenceConfig {
+            activity: "Listening | !ask me about VRF".to_string(),
+            ..Default::default()
+        };
+        let board = StatusBoard::default();
+        let presence = || config.presence(&board.status());
+
+        assert_eq!(
+            presence(),
+            Presence {
+                text: "Listening | !ask me about VRF".to_string(),
+                idle: false
+            }
+        );
+        board.set(Condition::Warming, true);
+        assert_eq!(presence().text, "Warming up: loading the docs");
+        board.set(Condition::Degraded, true);
+        assert_eq!(presence().text, "degraded: answers may lack docs");
+        board.set(Condition::RateLimited, true);
+        assert_eq!(presence().text, "rate limited: answers may be slow");
+        board.set(Condition::OverBudget, true);
+        assert_eq!(presence().text, "resting until tomorrow");
+        assert!(presence().idle);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_updates_are_rate_limited() {
+        let board = StatusBoard::default();
+        board.set(Condition::Warming, true);
+        let shard = Arc::new(RecordingShard::default());
+        let shutdown = CancellationToken::new();
+        let manager = PresenceManager::new(PresenceConfig::default(), board.subscribe());
+        let task = tokio::spawn(manager.run(shard.clone(), shutdown.clone()));
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(shard.texts(), ["Warming up: loading the docs"]);
+
+        // Both changes land within the interval, only the last is shown
+        board.set(Condition::Degraded, true);
+        board.set(Condition::Warming, false);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        board.set(Condition::Degraded, false);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(shard.texts().len(), 1);
+
+        tokio::time::sleep(Duration::from_secs(15)).await;
+        assert_eq!(
+            shard.texts(),
+            [
+                "Warming up: loading the docs",
+                "Listening | ask me about the docs"
+            ]
+        );
+        let presences = shard.presences.lock().unwrap().clone();
+        assert!(presences[1].0 - presences[0].0 >= Duration::from_secs(15));
+
+        shutdown.cancel();
+        task.await.unwrap();
+    }
+}
@@ -0,0 +1,197 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+use thiserror::Error;
+use tracing::debug;
+
+use crate::knowledge::{ChannelType, Message};
+
+/// Emoji the agent may react with unless configured otherwise.
+pub const DEFAULT_REACTIONS: &[&str] = &["👍", "🎉", "❤", "🔥", "👀", "🙏", "💯", "😁"];
+
+/// Invisible selector that follows many emoji and is dropped before matching.
+const VARIATION_SELECTOR: char = '\u{FE0F}';
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ReactionConfig {
+    /// Emoji a reply may consist of to be sent as a reaction.
+    pub emoji: Vec<String>,
+    /// Channel types where reaction-only replies are allowed. Direct messages
+    /// are left out by default, as a lone reaction reads as a brush-off there.
+    pub channel_types: Vec<ChannelType>,
+}
+
+impl Default for ReactionConfig {
+    fn default() -> Self {
+        Self {
+            emoji: DEFAULT_REACTIONS.iter().map(|e| e.to_string()).collect(),
+            channel_types: vec![ChannelType::Text, ChannelType::Thread],
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReplyOutcome {
+    Reply(String),
+    React(String),
+}
+
+impl ReplyOutcome {
+    /// How the outcome appears in stored history.
+    pub fn history_content(&self) -> String {
+        match self {
+            ReplyOutcome::Reply(text) => text.clone(),
+            ReplyOutcome::React(emoji) => format!("[reacted with {emoji}]"),
+        }
+    }
+
+    /// The assistant message recording this outcome as an answer to
+    /// `replying_to`.
+    pub fn to_message(&self, replying_to: &Message, account_id: &str) -> Message {
+        Message {
+            id: format!("{}:reply", replying_to.id),
+            source_id: account_id.to_string(),
+            account_id: account_id.to_string(),
+            role: "assistant".to_string(),
+            content: self.history_content(),
+            created_at: chrono::Utc::now(),
+            ..replying_to.clone()
+        }
+    }
+}
+
+fn normalize(text: &str) -> String {
+    text.trim().replace(VARIATION_SELECTOR, "")
+}
+
+impl ReactionConfig {
+    pub fn allows(&self, channel_type: &ChannelType) -> bool {
+        !self.emoji.is_empty() && self.channel_types.contains(channel_type)
+    }
+
+    /// Prompt context telling the model it may answer with a reaction.
+    pub fn instruction(&self) -> String {
+        format!(
+            "If a reaction says all that is needed, answer with exactly one of these emoji and nothing else: {}",
+            self.emoji.join(" ")
+        )
+    }
+
+    /// Maps a generated response to a reaction when it consists of a single
+    /// allowed emoji and reactions are allowed in the channel.
+    pub fn classify(&self, response: &str, channel_type: &ChannelType) -> ReplyOutcome {
+        if self.allows(channel_type) {
+            let response = normalize(response);
+            if self.emoji.iter().any(|emoji| normalize(emoji) == response) {
+                return ReplyOutcome::React(response);
+            }
+        }
+        ReplyOutcome::Reply(response.to_string())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ReactionError {
+    #[error("Reactions are not supported here")]
+    Unsupported,
+    #[error("Failed to deliver reply: {0}")]
+    Failed(Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// The message being answered, on a platform that may support reactions.
+#[async_trait]
+pub trait ReactionTarget: Send + Sync {
+    async fn react(&self, emoji: &str) -> Result<(), ReactionError>;
+    async fn reply(&self, text: &str) -> Result<(), ReactionError>;
+}
+
+/// Reacts with `emoji`, falling back to sending it as a short reply where
+/// the platform or chat does not support the reaction. Returns what was sent.
+pub async fn react_or_reply<T: ReactionTarget>(
+    target: &T,
+    emoji: &str,
+) -> Result<ReplyOutcome, ReactionError> {
+    match target.react(emoji).await {
+        Ok(()) => Ok(ReplyOutcome::React(emoji.to_string())),
+        Err(ReactionError::Unsupported) => {
+            debug!(emoji, "Reaction unsupported, replying instead");
+            target.reply(emoji).await?;
+            Ok(ReplyOutcome::Reply(emoji.to_string()))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_classify() {
+        let config = ReactionConfig::default();
+        let text = ChannelType::Text;
+
+        assert_eq!(
+            config.classify(" 👍\n", &text),
+            ReplyOutcome::React("👍".to_string())
+        );
+        // The variation selector some models emit does not matter.
+        assert_eq!(
+            config.classify("❤\u{FE0F}", &text),
+            ReplyOutcome::React("❤".to_string())
+        );
+        assert_eq!(
+            config.classify("👍 sounds good", &text),
+            ReplyOutcome::Reply("👍 sounds good".to_string())
+        );
+        assert_eq!(
+            config.classify("🦀", &text),
+            ReplyOutcome::Reply("🦀".to_string())
+        );
+        assert_eq!(
+            config.classify("🎉", &ChannelType::DirectMessage),
+            ReplyOutcome::Reply("🎉".to_string())
+        );
+    }
+
+    struct FakeTarget {
+        supported: bool,
+        sent: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl ReactionTarget for FakeTarget {
+        async fn react(&self, emoji: &str) -> Result<(), ReactionError> {
+            if !self.supported {
+                return Err(ReactionError::Unsupported);
+            }
+            self.sent.lock().unwrap().push(format!("react {emoji}"));
+            Ok(())
+        }
+
+        async fn reply(&self, text: &str) -> Result<(), ReactionError> {
+            self.sent.lock().unwrap().push(format!("reply {text}"));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_to_reply() {
+        let target = FakeTarget {
+            supported: true,
+            sent: Mutex::new(Vec::new()),
+        };
+        let outcome = react_or_reply(&target, "🎉").await.unwrap();
+        assert_eq!(outcome, ReplyOutcome::React("🎉".to_string()));
+        assert_eq!(outcome.history_content(), "[reacted with 🎉]");
+
+        let target = FakeTarget {
+            supported: false,
+            sent: Mutex::new(Vec::new()),
+        };
+        let outcome = react_or_reply(&target, "🎉").await.unwrap();
+        assert_eq!(outcome, ReplyOutcome::Reply("🎉".to_string()));
+        assert_eq!(*target.sent.lock().unwrap(), ["reply 🎉"]);
+    }
+}
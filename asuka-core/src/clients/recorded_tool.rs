@@ -0,0 +1,327 @@
+//! Wrapper recording every call of a tool, so the transcript behind a reply
+//! can be shown with `/toolcalls`.
+
+use std::time::Instant;
+
+use rig::{completion::ToolDefinition, embeddings::EmbeddingModel, tool::Tool};
+use serde_json::Value;
+use thiserror::Error;
+use tracing::error;
+
+use crate::{
+    events::Event,
+    knowledge::{KnowledgeBase, ToolCall},
+};
+
+/// Longest arguments or output stored, in bytes.
+const DEFAULT_MAX_RECORDED_BYTES: usize = 4000;
+
+const REDACTED: &str = "[redacted]";
+
+#[derive(Error, Debug)]
+pub enum RecordedToolError<E: std::error::Error> {
+    #[error("Invalid tool arguments: {0}")]
+    Args(#[from] serde_json::Error),
+    #[error(transparent)]
+    Tool(E),
+}
+
+/// Behaves like the wrapped tool and stores each call under the interaction
+/// being answered.
+///
+/// Arguments whose property in the tool definition has `"sensitive": true`
+/// are stored as `[redacted]`. Arguments and output longer than the limit are
+/// cut short with a marker.
+pub struct RecordedTool<T: Tool, E: EmbeddingModel + 'static> {
+    tool: T,
+    knowledge: KnowledgeBase<E>,
+    interaction_id: i64,
+    max_bytes: usize,
+}
+
+impl<T: Tool, E: EmbeddingModel + 'static> RecordedTool<T, E> {
+    pub fn new(tool: T, knowledge: KnowledgeBase<E>, interaction_id: i64) -> Self {
+        Self {
+            tool,
+            knowledge,
+            interaction_id,
+            max_bytes: DEFAULT_MAX_RECORDED_BYTES,
+        }
+    }
+
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+}
+
+/// Replaces the values of sensitive properties in `value`, following nested
+/// objects and arrays of `schema`.
+pub fn redact(schema: &Value, value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            let Some(properties) = schema.get("properties").and_then(Value::as_object) else {
+                return;
+            };
+            for (name, field) in fields.iter_mut() {
+                let Some(property) = properties.get(name) else {
+                    continue;
+                };
+                if property.get("sensitive") == Some(&Value::Bool(true)) {
+                    *field = Value::String(REDACTED.to_string());
+                } else {
+                    redact(property, field);
+                }
+            }
+        }
+        Value::Array(items) => {
+            if let Some(item_schema) = schema.get("items") {
+                items.iter_mut().for_each(|item| redact(item_schema, item));
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Cuts `text` to at most `max_bytes` plus a marker noting the full size.
+fn truncate(text: String, max_bytes: usize) -> (String, bool) {
+    if text.len() <= max_bytes {
+        return (text, false);
+    }
+    let mut end = max_bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let marker = format!("… [truncated, {} bytes total]", text.len());
+    (format!("{}{marker}", &text[..end]), true)
+}
+
+impl<T: Tool, E: EmbeddingModel + 'static> Tool for RecordedTool<T, E>
+where
+    T::Output: Send + Sync,
+{
+    const NAME: &'static str = T::NAME;
+
+    type Error = RecordedToolError<T::Error>;
+    type Args = Value;
+    type Output = T::Output;
+
+    fn name(&self) -> String {
+        self.tool.name()
+    }
+
+    async fn definition(&self, prompt: String) -> ToolDefinition {
+        self.tool.definition(prompt).await
+    }
+
+    async fn call(&self, args: Self::Args) -> Result<Self::Output, Self::Error> {
+        let start = Instant::now();
+        let result = match serde_json::from_value::<T::Args>(args.clone()) {
+            Ok(parsed) => self
+                .tool
+                .call(parsed)
+                .await
+                .map_err(RecordedToolError::Tool),
+            Err(err) => Err(RecordedToolError::Args(err)),
+        };
+        let duration_ms = start.elapsed().as_millis() as i64;
+
+        let mut args = args;
+        redact(
+            &self.tool.definition(String::new()).await.parameters,
+            &mut args,
+        );
+        let (args, args_truncated) = truncate(args.to_string(), self.max_bytes);
+        let (output, output_truncated) = match &result {
+            Ok(output) => {
+                let output = serde_json::to_string(output).unwrap_or_else(|e| e.to_string());
+                let (output, truncated) = truncate(output, self.max_bytes);
+                (Ok(output), truncated)
+            }
+            Err(err) => {
+                let (error, truncated) = truncate(err.to_string(), self.max_bytes);
+                (Err(error), truncated)
+            }
+        };
+
+        let call = ToolCall {
+            interaction_id: self.interaction_id,
+            tool_name: self.tool.name(),
+            args,
+            output,
+            duration_ms,
+            truncated: args_truncated || output_truncated,
+        };
+        // Store futures are not Sync, which tool futures must be, so the
+        // write runs on its own task. A failed write never fails the call.
+        let knowledge = self.knowledge.clone();
+        match tokio::spawn(async move { knowledge.record_tool_call(call).await }).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) => error!(?err, "Failed to record tool call"),
+            Err(err) => error!(?err, "Failed to record tool call"),
+        }
+        self.knowledge.events().publish(Event::ToolExecuted {
+            interaction_id: self.interaction_id,
+            tool_name: self.tool.name(),
+            duration_ms,
+            success: result.is_ok(),
+        });
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+    use serde::Deserialize;
+    use serde_json::json;
+
+    #[derive(Error, Debug)]
+    #[error("Simulation failed: {0}")]
+    struct SimulateError(String);
+
+    #[derive(Deserialize)]
+    struct SimulateArgs {
+        to: String,
+        amount: u64,
+    }
+
+    /// Echoes transfers, failing for zero amounts.
+    struct SimulateTransfer;
+
+    impl Tool for SimulateTransfer {
+        const NAME: &'static str = "simulate_transfer";
+
+        type Error = SimulateError;
+        type Args = SimulateArgs;
+        type Output = String;
+
+        async fn definition(&self, _prompt: String) -> ToolDefinition {
+            ToolDefinition {
+                name: Self::NAME.to_string(),
+                description: "Simulates a transfer".to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "to": { "type": "string" },
+                        "amount": { "type": "integer" },
+                        "signer": {
+                            "type": "object",
+                            "properties": {
+                                "address": { "type": "string" },
+                                "private_key": { "type": "string", "sensitive": true }
+                            }
+                        }
+                    }
+                }),
+            }
+        }
+
+        async fn call(&self, args: Self::Args) -> Result<String, SimulateError> {
+            if args.amount == 0 {
+                return Err(SimulateError("amount is zero".to_string()));
+            }
+            Ok(format!(
+                "{} to {}: ok {}",
+                args.amount,
+                args.to,
+                "x".repeat(100)
+            ))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_calls_are_recorded() {
+        let knowledge = test_utils::knowledge_base().await;
+        let interaction_id = knowledge
+            .create_interaction(
+                "c1".to_string(),
+                "alice".to_string(),
+                vec!["m1".to_string()],
+            )
+            .await
+            .unwrap();
+        let tool = RecordedTool::new(SimulateTransfer, knowledge.clone(), interaction_id)
+            .with_max_bytes(100);
+        let mut events = knowledge.events().subscribe();
+
+        let output = tool
+            .call(json!({
+                "to": "0xabc",
+                "amount": 5,
+                "signer": { "address": "0x1", "private_key": "0xsecret" }
+            }))
+            .await
+            .unwrap();
+        assert_eq!(output, format!("5 to 0xabc: ok {}", "x".repeat(100)));
+
+        let err = tool
+            .call(json!({"to": "0xabc", "amount": 0}))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Simulation failed: amount is zero");
+        assert!(matches!(
+            tool.call(json!({"to": "0xabc"})).await,
+            Err(RecordedToolError::Args(_))
+        ));
+
+        let successes = (0..3)
+            .map(|_| match events.try_recv().unwrap() {
+                Event::ToolExecuted { success, .. } => success,
+                event => panic!("unexpected {event:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(successes, [true, false, false]);
+
+        let calls = knowledge.tool_calls(interaction_id).await.unwrap();
+        assert_eq!(calls.len(), 3);
+        assert!(calls
+            .iter()
+            .all(|call| call.tool_name == "simulate_transfer"));
+
+        let args: Value = serde_json::from_str(&calls[0].args).unwrap();
+        assert_eq!(
+            args,
+            json!({
+                "to": "0xabc",
+                "amount": 5,
+                "signer": { "address": "0x1", "private_key": "[redacted]" }
+            })
+        );
+        let output = calls[0].output.as_ref().unwrap();
+        assert!(output.starts_with("\"5 to 0xabc: ok xxx"));
+        assert!(output.ends_with("… [truncated, 117 bytes total]"));
+        assert!(calls[0].truncated);
+
+        assert_eq!(
+            calls[1].output,
+            Err("Simulation failed: amount is zero".to_string())
+        );
+        assert!(!calls[1].truncated);
+        assert!(calls[2]
+            .output
+            .as_ref()
+            .unwrap_err()
+            .starts_with("Invalid tool arguments: missing field `amount`"));
+    }
+
+    #[test]
+    fn test_redact_arrays() {
+        let schema = json!({
+            "properties": {
+                "keys": {
+                    "type": "array",
+                    "items": { "properties": { "secret": { "sensitive": true } } }
+                }
+            }
+        });
+        let mut value = json!({"keys": [{"secret": "a", "id": 1}, {"secret": "b"}]});
+        redact(&schema, &mut value);
+        assert_eq!(
+            value,
+            json!({"keys": [{"secret": "[redacted]", "id": 1}, {"secret": "[redacted]"}]})
+        );
+    }
+}
@@ -0,0 +1,295 @@
+//! Tool letting trusted users re-sync a knowledge source from chat, e.g.
+//! right after docs were merged.
+
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use rig::{completion::ToolDefinition, embeddings::EmbeddingModel, tool::Tool};
+use serde::Deserialize;
+use serde_json::json;
+use thiserror::Error;
+use tokio::sync::oneshot;
+
+use crate::{
+    knowledge::{RefreshError, RefreshRegistry},
+    permissions::PermissionTier,
+};
+
+/// What the model is told the tool does, also listed in the agent's
+/// [capabilities](crate::capabilities).
+pub const DESCRIPTION: &str = "Re-sync a knowledge source so answers use its latest content, \
+    e.g. after the docs were updated. Only use it when asked to.";
+
+/// How long a call waits for the refresh before answering that it started.
+const DEFAULT_WAIT: Duration = Duration::from_secs(5);
+
+#[derive(Error, Debug)]
+pub enum RefreshKnowledgeError {
+    #[error("Only trusted users can refresh knowledge")]
+    PermissionDenied,
+    #[error(transparent)]
+    Refresh(#[from] RefreshError),
+    #[error("Refresh task failed: {0}")]
+    Task(String),
+}
+
+/// Posts the outcome of a refresh that outlived the tool call, through the
+/// client the request came from.
+#[async_trait]
+pub trait FollowUp: Send + Sync {
+    async fn send(&self, text: String);
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshKnowledgeArgs {
+    pub source: String,
+}
+
+/// Refreshes a source of a [RefreshRegistry]. Slow refreshes keep running
+/// after the call answers, and their outcome is sent as a [FollowUp].
+pub struct RefreshKnowledge<E: EmbeddingModel + 'static> {
+    registry: RefreshRegistry<E>,
+    caller: PermissionTier,
+    follow_up: Arc<dyn FollowUp>,
+    wait: Duration,
+}
+
+impl<E: EmbeddingModel + 'static> RefreshKnowledge<E> {
+    /// A tool acting for a user of tier `caller`.
+    pub fn new(
+        registry: RefreshRegistry<E>,
+        caller: PermissionTier,
+        follow_up: Arc<dyn FollowUp>,
+    ) -> Self {
+        Self {
+            registry,
+            caller,
+            follow_up,
+            wait: DEFAULT_WAIT,
+        }
+    }
+
+    /// How long to wait for the refresh before answering that it started.
+    pub fn with_wait(mut self, wait: Duration) -> Self {
+        self.wait = wait;
+        self
+    }
+
+    async fn run(
+        registry: RefreshRegistry<E>,
+        follow_up: Arc<dyn FollowUp>,
+        wait: Duration,
+        source: String,
+    ) -> Result<String, RefreshKnowledgeError> {
+        let (tx, mut rx) = oneshot::channel();
+        let name = source.clone();
+        tokio::spawn(async move {
+            let result = registry
+                .refresh(&name)
+                .await
+                .map(|summary| format!("Refreshed {name}: {summary}"));
+            // The call already answered, so report through the client.
+            if let Err(result) = tx.send(result) {
+                let text = match result {
+                    Ok(text) => text,
+                    Err(err) => format!("Refresh of {name} failed: {err}"),
+                };
+                follow_up.send(text).await;
+            }
+        });
+
+        if let Ok(result) = tokio::time::timeout(wait, &mut rx).await {
+            return result
+                .map_err(|e| RefreshKnowledgeError::Task(e.to_string()))?
+                .map_err(RefreshKnowledgeError::from);
+        }
+        // Closing first means a result is either received here or sent as a
+        // follow-up, never lost in between.
+        rx.close();
+        match rx.try_recv() {
+            Ok(result) => result.map_err(RefreshKnowledgeError::from),
+            Err(_) => Ok(format!(
+                "Refresh of {source} started. I will post the result here when it is done."
+            )),
+        }
+    }
+}
+
+impl<E: EmbeddingModel + 'static> Tool for RefreshKnowledge<E> {
+    const NAME: &'static str = "refresh_knowledge";
+
+    type Error = RefreshKnowledgeError;
+    type Args = RefreshKnowledgeArgs;
+    type Output = String;
+
+    async fn definition(&self, _prompt: String) -> ToolDefinition {
+        ToolDefinition {
+            name: Self::NAME.to_string(),
+            description: DESCRIPTION.to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "source": {
+                        "type": "string",
+                        "enum": self.registry.names(),
+                        "description": "Name of the source to refresh"
+                    }
+                },
+                "required": ["source"]
+            }),
+        }
+    }
+
+    async fn call(&self, args: Self::Args) -> Result<Self::Output, Self::Error> {
+        if self.caller < PermissionTier::Trusted {
+            return Err(RefreshKnowledgeError::PermissionDenied);
+        }
+
+        // Tool futures must be Sync, which the store futures are not, so the
+        // work runs on its own task.
+        let task = tokio::spawn(Self::run(
+            self.registry.clone(),
+            self.follow_up.clone(),
+            self.wait,
+            args.source,
+        ));
+        task.await
+            .map_err(|e| RefreshKnowledgeError::Task(e.to_string()))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        knowledge::{Document, RefreshSource},
+        test_utils::{self, FakeEmbeddingModel},
+    };
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    };
+    use tokio::sync::{mpsc, Semaphore};
+
+    /// Returns `pages` on every sync, after a permit is available when gated.
+    struct FakeSource {
+        pages: Mutex<Vec<(&'static str, &'static str)>>,
+        syncs: Arc<AtomicUsize>,
+        gate: Option<Arc<Semaphore>>,
+    }
+
+    #[async_trait]
+    impl RefreshSource for FakeSource {
+        async fn sync_changes(&self) -> anyhow::Result<Vec<Document>> {
+            if let Some(gate) = &self.gate {
+                gate.acquire().await?.forget();
+            }
+            self.syncs.fetch_add(1, Ordering::SeqCst);
+            let pages = self.pages.lock().unwrap().clone();
+            Ok(pages
+                .into_iter()
+                .map(|(id, content)| Document {
+                    id: id.to_string(),
+                    source_id: "docs".to_string(),
+                    content: content.to_string(),
+                    created_at: chrono::Utc::now(),
+                    title: String::new(),
+                    section: String::new(),
+                    topics: Vec::new(),
+                    logical_id: None,
+                    cleaned: None,
+                    chunk: None,
+                })
+                .collect())
+        }
+    }
+
+    struct ChannelFollowUp(mpsc::UnboundedSender<String>);
+
+    #[async_trait]
+    impl FollowUp for ChannelFollowUp {
+        async fn send(&self, text: String) {
+            self.0.send(text).unwrap();
+        }
+    }
+
+    async fn tool(
+        caller: PermissionTier,
+        gate: Option<Arc<Semaphore>>,
+    ) -> (
+        RefreshKnowledge<FakeEmbeddingModel>,
+        Arc<AtomicUsize>,
+        mpsc::UnboundedReceiver<String>,
+    ) {
+        let syncs = Arc::new(AtomicUsize::new(0));
+        let source = FakeSource {
+            pages: Mutex::new(vec![("katana.md", "Katana"), ("torii.md", "Torii")]),
+            syncs: syncs.clone(),
+            gate,
+        };
+        let registry = RefreshRegistry::new(test_utils::knowledge_base().await)
+            .with_source("docs", source)
+            .with_interval(Duration::from_secs(30 * 60));
+        let (tx, rx) = mpsc::unbounded_channel();
+        let tool = RefreshKnowledge::new(registry, caller, Arc::new(ChannelFollowUp(tx)));
+        (tool, syncs, rx)
+    }
+
+    fn args(source: &str) -> RefreshKnowledgeArgs {
+        RefreshKnowledgeArgs {
+            source: source.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_permission_denied() {
+        let (tool, syncs, _) = tool(PermissionTier::User, None).await;
+
+        assert!(matches!(
+            tool.call(args("docs")).await,
+            Err(RefreshKnowledgeError::PermissionDenied)
+        ));
+        assert_eq!(syncs.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let (tool, syncs, _) = tool(PermissionTier::Trusted, None).await;
+
+        let reply = tool.call(args("docs")).await.unwrap();
+        assert_eq!(reply, "Refreshed docs: 2 added, 0 updated");
+
+        let err = tool.call(args("docs")).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "docs was refreshed recently, try again in 30 minutes"
+        );
+        assert_eq!(syncs.load(Ordering::SeqCst), 1);
+
+        let err = tool.call(args("blog")).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown source \"blog\", expected one of: docs"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_slow_refresh_sends_follow_up() {
+        let gate = Arc::new(Semaphore::new(0));
+        let (tool, _, mut follow_ups) = tool(PermissionTier::Admin, Some(gate.clone())).await;
+        let tool = tool.with_wait(Duration::from_millis(20));
+
+        let reply = tool.call(args("docs")).await.unwrap();
+        assert_eq!(
+            reply,
+            "Refresh of docs started. I will post the result here when it is done."
+        );
+
+        gate.add_permits(1);
+        let follow_up = tokio::time::timeout(Duration::from_secs(5), follow_ups.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(follow_up, "Refreshed docs: 2 added, 0 updated");
+    }
+}
@@ -0,0 +1,324 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::{stream::BoxStream, Stream, StreamExt};
+use rig::completion::{CompletionError, CompletionRequest};
+use thiserror::Error;
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::attachments::Attachment;
+
+/// Text deltas of a completion, in order.
+pub type TextStream = BoxStream<'static, Result<String, CompletionError>>;
+
+/// Completion models that can stream their answer. rig's completion models
+/// only return whole responses, so providers opt in by implementing this.
+#[async_trait]
+pub trait StreamingCompletion: Send + Sync {
+    async fn stream(&self, request: CompletionRequest) -> Result<TextStream, CompletionError>;
+}
+
+#[derive(Clone, Debug)]
+pub struct StreamingConfig {
+    /// Minimum time between edits of one message. Discord allows about five
+    /// edits per five seconds.
+    pub edit_interval: Duration,
+    /// Hard length limit of a message; longer replies continue in a new one.
+    pub max_length: usize,
+    /// Content of a reply before any text has arrived.
+    pub placeholder: String,
+}
+
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        Self {
+            edit_interval: Duration::from_secs(2),
+            max_length: 2000,
+            placeholder: "…".to_string(),
+        }
+    }
+}
+
+/// Where a streamed reply is shown.
+#[async_trait]
+pub trait ReplySink: Send + Sync {
+    type Handle: Send + Sync;
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    async fn send(&self, text: &str) -> Result<Self::Handle, Self::Error>;
+    async fn edit(&self, handle: &Self::Handle, text: &str) -> Result<(), Self::Error>;
+
+    /// Sends `files` after a reply, see [crate::attachments]. Returns the
+    /// handles of what was sent, nothing where files are not supported.
+    async fn send_files(&self, files: &[Attachment]) -> Result<Vec<Self::Handle>, Self::Error> {
+        let _ = files;
+        Ok(Vec::new())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum StreamingError {
+    #[error("Completion error: {0}")]
+    Completion(#[from] CompletionError),
+    #[error("Failed to deliver reply: {0}")]
+    Sink(Box<dyn std::error::Error + Send + Sync>),
+}
+
+fn sink_error<E: std::error::Error + Send + Sync + 'static>(err: E) -> StreamingError {
+    StreamingError::Sink(Box::new(err))
+}
+
+/// Splits `text` at the last line break or space that keeps the head within
+/// `max_length` bytes, or hard at the limit when there is none.
+fn split_at_limit(text: &str, max_length: usize) -> (&str, &str) {
+    let mut limit = max_length.min(text.len());
+    while !text.is_char_boundary(limit) {
+        limit -= 1;
+    }
+
+    let index = text[..limit]
+        .rfind('\n')
+        .or_else(|| text[..limit].rfind(' '))
+        .filter(|&index| index > 0)
+        .unwrap_or(limit);
+    (text[..index].trim_end(), text[index..].trim_start())
+}
+
+/// The message currently being edited.
+struct Current<H> {
+    handle: H,
+    text: String,
+    shown: String,
+    last_edit: Instant,
+}
+
+/// Shows a streamed reply by editing a single message as text arrives, at
+/// most once per [StreamingConfig::edit_interval]. Text beyond
+/// [StreamingConfig::max_length] continues in a follow-up message. Returns
+/// the final content of every message sent.
+pub async fn stream_reply<S, St>(
+    sink: &S,
+    mut deltas: St,
+    config: &StreamingConfig,
+) -> Result<Vec<String>, StreamingError>
+where
+    S: ReplySink,
+    St: Stream<Item = Result<String, CompletionError>> + Unpin,
+{
+    let mut finished = Vec::new();
+    let mut current = Current {
+        handle: sink.send(&config.placeholder).await.map_err(sink_error)?,
+        text: String::new(),
+        shown: config.placeholder.clone(),
+        last_edit: Instant::now(),
+    };
+
+    let result = loop {
+        let dirty = !current.text.is_empty() && current.text != current.shown;
+        let next = tokio::select! {
+            next = deltas.next() => next,
+            _ = tokio::time::sleep_until(current.last_edit + config.edit_interval), if dirty => {
+                flush(sink, &mut current).await?;
+                continue;
+            }
+        };
+
+        match next {
+            Some(Ok(delta)) => current.text.push_str(&delta),
+            Some(Err(err)) => break Err(err.into()),
+            None => break Ok(()),
+        }
+
+        while current.text.len() > config.max_length {
+            let (head, rest) = split_at_limit(&current.text, config.max_length);
+            let (head, rest) = (head.to_string(), rest.to_string());
+            debug!(
+                length = head.len(),
+                "Reply over the length limit, continuing"
+            );
+
+            current.text = head;
+            tokio::time::sleep_until(current.last_edit + config.edit_interval).await;
+            flush(sink, &mut current).await?;
+            finished.push(std::mem::take(&mut current.text));
+
+            let shown = if rest.is_empty() {
+                config.placeholder.clone()
+            } else {
+                rest.clone()
+            };
+            current = Current {
+                handle: sink.send(&shown).await.map_err(sink_error)?,
+                text: rest,
+                shown,
+                last_edit: Instant::now(),
+            };
+        }
+
+        if Instant::now() >= current.last_edit + config.edit_interval {
+            flush(sink, &mut current).await?;
+        }
+    };
+
+    // The final edit still respects the interval.
+    if current.text != current.shown && !current.text.is_empty() {
+        tokio::time::sleep_until(current.last_edit + config.edit_interval).await;
+        flush(sink, &mut current).await?;
+    }
+    finished.push(current.text);
+
+    result.map(|_| finished)
+}
+
+async fn flush<S: ReplySink>(
+    sink: &S,
+    current: &mut Current<S::Handle>,
+) -> Result<(), StreamingError> {
+    if current.text == current.shown || current.text.is_empty() {
+        return Ok(());
+    }
+
+    sink.edit(&current.handle, &current.text)
+        .await
+        .map_err(sink_error)?;
+    current.shown = current.text.clone();
+    current.last_edit = Instant::now();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+    use std::sync::Mutex;
+
+    #[derive(Debug, Error)]
+    #[error("unreachable")]
+    struct Never;
+
+    #[derive(Debug, PartialEq)]
+    enum Call {
+        Send(u64, String),
+        Edit(u64, usize, String),
+    }
+
+    struct FakeSink {
+        origin: Instant,
+        calls: Mutex<Vec<Call>>,
+    }
+
+    impl FakeSink {
+        fn new() -> Self {
+            Self {
+                origin: Instant::now(),
+                calls: Mutex::new(Vec::new()),
+            }
+        }
+
+        fn millis(&self) -> u64 {
+            self.origin.elapsed().as_millis() as u64
+        }
+    }
+
+    #[async_trait]
+    impl ReplySink for FakeSink {
+        type Handle = usize;
+        type Error = Never;
+
+        async fn send(&self, text: &str) -> Result<usize, Never> {
+            let mut calls = self.calls.lock().unwrap();
+            let handle = calls
+                .iter()
+                .filter(|call| matches!(call, Call::Send(..)))
+                .count();
+            calls.push(Call::Send(self.millis(), text.to_string()));
+            Ok(handle)
+        }
+
+        async fn edit(&self, handle: &usize, text: &str) -> Result<(), Never> {
+            let call = Call::Edit(self.millis(), *handle, text.to_string());
+            self.calls.lock().unwrap().push(call);
+            Ok(())
+        }
+    }
+
+    /// Yields `words` one every 600ms.
+    fn words(words: &[&str]) -> TextStream {
+        let words = words
+            .iter()
+            .map(|word| word.to_string())
+            .collect::<Vec<_>>();
+        stream::iter(words)
+            .then(|word| async move {
+                tokio::time::sleep(Duration::from_millis(600)).await;
+                Ok(word)
+            })
+            .boxed()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_edits_are_rate_limited() {
+        let sink = FakeSink::new();
+        let deltas = words(&["a ", "b ", "c ", "d ", "e ", "f ", "g ", "h ", "i"]);
+
+        let messages = stream_reply(&sink, deltas, &StreamingConfig::default())
+            .await
+            .unwrap();
+
+        assert_eq!(messages, ["a b c d e f g h i"]);
+        assert_eq!(
+            *sink.calls.lock().unwrap(),
+            [
+                Call::Send(0, "…".to_string()),
+                Call::Edit(2000, 0, "a b c ".to_string()),
+                Call::Edit(4000, 0, "a b c d e f ".to_string()),
+                Call::Edit(6000, 0, "a b c d e f g h i".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_long_reply_continues_in_new_message() {
+        let sink = FakeSink::new();
+        let deltas = words(&["one two ", "three four ", "five six ", "seven"]);
+        let config = StreamingConfig {
+            max_length: 16,
+            ..Default::default()
+        };
+
+        let messages = stream_reply(&sink, deltas, &config).await.unwrap();
+
+        assert_eq!(messages, ["one two three", "four five six", "seven"]);
+        assert_eq!(messages.join(" "), "one two three four five six seven");
+
+        let calls = sink.calls.lock().unwrap();
+        let sends = calls
+            .iter()
+            .filter(|call| matches!(call, Call::Send(..)))
+            .count();
+        assert_eq!(sends, 3);
+        // Every message is edited at most once per interval.
+        for handle in 0..3 {
+            let times = calls
+                .iter()
+                .filter_map(|call| match call {
+                    Call::Edit(at, h, _) if *h == handle => Some(*at),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            assert!(times.windows(2).all(|pair| pair[1] - pair[0] >= 2000));
+        }
+    }
+
+    #[test]
+    fn test_split_at_limit() {
+        assert_eq!(split_at_limit("hello world", 8), ("hello", "world"));
+        assert_eq!(
+            split_at_limit("line\nnext words", 12),
+            ("line", "next words")
+        );
+        assert_eq!(split_at_limit("abcdefgh", 4), ("abcd", "efgh"));
+        assert_eq!(split_at_limit("ééé", 3), ("é", "éé"));
+    }
+}
@@ -0,0 +1,256 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    /// The gateway resumed the previous session, so no events were lost.
+    Resumed,
+    Disconnected,
+    Stopped,
+}
+
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Consecutive failed sessions tolerated before giving up. A session that
+    /// reached [ConnectionState::Connected] resets the count.
+    pub max_retries: u32,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(300),
+            max_retries: 10,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff)
+    }
+}
+
+/// One gateway session, from connecting until the connection ends.
+#[async_trait]
+pub trait Gateway: Send + Sync {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// Runs a session. Returns `Ok` once it was closed through `shutdown`.
+    async fn run(&self, shutdown: CancellationToken) -> Result<(), Self::Error>;
+
+    /// Errors that retrying cannot fix, such as a rejected token.
+    fn is_fatal(&self, _error: &Self::Error) -> bool {
+        false
+    }
+}
+
+/// Keeps a gateway running, reconnecting with exponential backoff after
+/// failures until `shutdown` is cancelled or the retry budget runs out.
+/// The gateway reports `Connected` and `Resumed` itself; this loop reports
+/// `Connecting`, `Disconnected` and `Stopped`.
+pub async fn supervise<G: Gateway>(
+    gateway: &G,
+    policy: &ReconnectPolicy,
+    state: &watch::Sender<ConnectionState>,
+    shutdown: &CancellationToken,
+) -> Result<(), G::Error> {
+    let mut failures = 0;
+
+    while !shutdown.is_cancelled() {
+        state.send_replace(ConnectionState::Connecting);
+
+        let err = match gateway.run(shutdown.clone()).await {
+            Ok(()) => break,
+            Err(err) => err,
+        };
+
+        let was_connected = matches!(
+            *state.borrow(),
+            ConnectionState::Connected | ConnectionState::Resumed
+        );
+        state.send_replace(ConnectionState::Disconnected);
+        if was_connected {
+            failures = 0;
+        }
+
+        if gateway.is_fatal(&err) || failures >= policy.max_retries {
+            state.send_replace(ConnectionState::Stopped);
+            return Err(err);
+        }
+
+        let delay = policy.backoff(failures);
+        failures += 1;
+        warn!(
+            ?err,
+            ?delay,
+            attempt = failures,
+            "Gateway disconnected, reconnecting"
+        );
+
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = shutdown.cancelled() => break,
+        }
+    }
+
+    info!("Gateway stopped");
+    state.send_replace(ConnectionState::Stopped);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+    };
+    use tokio::time::Instant;
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("connection reset")]
+    struct Reset;
+
+    enum Session {
+        Fail,
+        /// Reports `Connected`, then fails.
+        ConnectThenFail,
+        /// Runs until shutdown.
+        Serve,
+    }
+
+    struct FakeGateway {
+        sessions: Mutex<VecDeque<Session>>,
+        state: Arc<watch::Sender<ConnectionState>>,
+        started: Mutex<Vec<Duration>>,
+        origin: Instant,
+    }
+
+    impl FakeGateway {
+        fn new(
+            sessions: impl IntoIterator<Item = Session>,
+            state: Arc<watch::Sender<ConnectionState>>,
+        ) -> Self {
+            Self {
+                sessions: Mutex::new(sessions.into_iter().collect()),
+                state,
+                started: Mutex::new(Vec::new()),
+                origin: Instant::now(),
+            }
+        }
+
+        fn started(&self) -> Vec<u64> {
+            let started = self.started.lock().unwrap();
+            started.iter().map(|at| at.as_secs()).collect()
+        }
+    }
+
+    #[async_trait]
+    impl Gateway for FakeGateway {
+        type Error = Reset;
+
+        async fn run(&self, shutdown: CancellationToken) -> Result<(), Reset> {
+            self.started.lock().unwrap().push(self.origin.elapsed());
+            let session = self.sessions.lock().unwrap().pop_front();
+            match session {
+                Some(Session::Fail) | None => Err(Reset),
+                Some(Session::ConnectThenFail) => {
+                    self.state.send_replace(ConnectionState::Connected);
+                    Err(Reset)
+                }
+                Some(Session::Serve) => {
+                    self.state.send_replace(ConnectionState::Connected);
+                    shutdown.cancelled().await;
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    fn policy(max_retries: u32) -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(4),
+            max_retries,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reconnects_with_backoff_until_shutdown() {
+        let state = Arc::new(watch::channel(ConnectionState::Stopped).0);
+        let mut states = state.subscribe();
+        let gateway = FakeGateway::new(
+            [
+                Session::Fail,
+                Session::Fail,
+                Session::Fail,
+                Session::Fail,
+                Session::Serve,
+            ],
+            state.clone(),
+        );
+        let shutdown = CancellationToken::new();
+
+        let policy = policy(10);
+        let supervisor = supervise(&gateway, &policy, &state, &shutdown);
+        let stop = async {
+            states
+                .wait_for(|state| *state == ConnectionState::Connected)
+                .await
+                .unwrap();
+            shutdown.cancel();
+        };
+        let (result, _) = tokio::join!(supervisor, stop);
+
+        assert!(result.is_ok());
+        // Backoff doubles from 1s and is capped at 4s.
+        assert_eq!(gateway.started(), [0, 1, 3, 7, 11]);
+        assert_eq!(*state.borrow(), ConnectionState::Stopped);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_gives_up_after_retry_budget() {
+        let state = Arc::new(watch::channel(ConnectionState::Stopped).0);
+        let gateway = FakeGateway::new(
+            [Session::Fail, Session::ConnectThenFail, Session::Fail],
+            state.clone(),
+        );
+
+        let result = supervise(&gateway, &policy(1), &state, &CancellationToken::new()).await;
+
+        assert!(result.is_err());
+        // The connected session reset the budget, so one more retry ran.
+        assert_eq!(gateway.started(), [0, 1, 2]);
+        assert_eq!(*state.borrow(), ConnectionState::Stopped);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shutdown_interrupts_backoff() {
+        let state = Arc::new(watch::channel(ConnectionState::Stopped).0);
+        let gateway = FakeGateway::new([Session::Fail], state.clone());
+        let shutdown = CancellationToken::new();
+
+        let policy = policy(10);
+        let supervisor = supervise(&gateway, &policy, &state, &shutdown);
+        let stop = async {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            shutdown.cancel();
+        };
+        let (result, _) = tokio::join!(supervisor, stop);
+
+        assert!(result.is_ok());
+        assert_eq!(gateway.started(), [0]);
+    }
+}
@@ -1,33 +1,116 @@
 use anyhow::Result;
-use rig::{
-    completion::{CompletionModel, Prompt},
-    embeddings::EmbeddingModel,
-};
-use std::collections::HashSet;
+use rig::{completion::CompletionModel, embeddings::EmbeddingModel};
+use std::collections::HashMap;
 use teloxide::{
     dispatching::UpdateFilterExt,
     dptree,
+    payloads::{SendMessageSetters, SetMessageReactionSetters},
     prelude::{LoggingErrorHandler, Requester},
+    types::{ChatId, InputFile, MessageId, MessageOrigin, ParseMode, ReactionType, UserId},
+    RequestError,
 };
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info};
 
-use crate::{agent::Agent, attention::AttentionCommand};
 use crate::{
-    attention::{Attention, AttentionContext},
+    agent::Agent,
+    attachments::Attachment,
+    attention::Attention,
+    clients::{
+        chunks::{chunk_message, MIN_CHUNK_LENGTH},
+        external::{Client, IncomingMessage},
+        reactions::{ReactionConfig, ReactionError},
+        streaming::ReplySink,
+    },
+    commands,
+    digest::DigestSink,
+    hooks::PARSE_MODE,
     knowledge,
+    linking::{self, LinkingConfig},
+    outage::OutageNotices,
+    permissions::{PermissionTier, Permissions},
+    pipeline::{BatchConfig, Pipeline},
+    quoted::{self, QuotedContent},
+    rate_limit::RateLimitConfig,
+    reporting::ReportSink,
+    say::{self, SayError, SayRequest},
+    summarize::{SummarizeConfig, Summarizer},
+    templates,
 };
 
-const MAX_HISTORY_MESSAGES: i64 = 10;
+/// Emoji bots can react with. Others are sent as a text reply instead.
+const TELEGRAM_REACTIONS: &[&str] = &[
+    "👍", "👎", "❤", "🔥", "🥰", "👏", "😁", "🤔", "🤯", "😱", "🤬", "😢", "🎉", "🤩", "🤮", "💩",
+    "🙏", "👌", "🕊", "🤡", "🥱", "🥴", "😍", "🐳", "❤‍🔥", "🌚", "🌭", "💯", "🤣", "⚡", "🍌",
+    "🏆", "💔", "🤨", "😐", "🍓", "🍾", "💋", "🖕", "😈", "😴", "😭", "🤓", "👻", "👨‍💻", "👀",
+    "🎃", "🙈", "😇", "😨", "🤝", "✍", "🤗", "🫡", "🎅", "🎄", "☃", "💅", "🤪", "🗿", "🆒", "💘",
+    "🙉", "🦄", "😘", "💊", "🙊", "😎", "👾", "🤷‍♂", "🤷", "🤷‍♀", "😡",
+];
+
+/// Longest message Telegram accepts.
+pub const MAX_MESSAGE_LENGTH: usize = 4096;
 
 #[derive(Clone)]
 pub struct TelegramClient<M: CompletionModel, E: EmbeddingModel + 'static> {
-    agent: Agent<M, E>,
-    attention: Attention<M>,
+    pipeline: Pipeline<M, M, E>,
+    permissions: Permissions,
+    summarize: SummarizeConfig,
+    linking: Option<LinkingConfig>,
 }
 
 impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> TelegramClient<M, E> {
     pub fn new(agent: Agent<M, E>, attention: Attention<M>) -> Self {
-        Self { agent, attention }
+        let agent = agent.with_source(knowledge::Source::Telegram);
+        Self {
+            pipeline: Pipeline::new(agent, attention),
+            permissions: Permissions::default(),
+            summarize: SummarizeConfig::default(),
+            linking: None,
+        }
+    }
+
+    /// Telegram user ids allowed to run admin commands.
+    pub fn with_admins(mut self, admins: impl IntoIterator<Item = String>) -> Self {
+        self.permissions.admins = admins.into_iter().collect();
+        self
+    }
+
+    /// Answers rapid-fire messages of a user together, see
+    /// [Debouncer](crate::pipeline::Debouncer).
+    pub fn with_batching(mut self, config: BatchConfig) -> Self {
+        self.pipeline = self.pipeline.with_batching(config);
+        self
+    }
+
+    /// Settings for `/summarize [hours]`.
+    pub fn with_summarize_config(mut self, config: SummarizeConfig) -> Self {
+        self.summarize = config;
+        self
+    }
+
+    /// Lets the agent answer with a reaction instead of a message.
+    pub fn with_reactions(mut self, config: ReactionConfig) -> Self {
+        self.pipeline = self.pipeline.with_reactions(config);
+        self
+    }
+
+    /// Limits answers per user, see [crate::rate_limit]. Admins are exempt.
+    pub fn with_rate_limit(mut self, config: RateLimitConfig) -> Self {
+        self.pipeline = self.pipeline.with_rate_limit(config);
+        self
+    }
+
+    /// Tells chats when answers are slow or fail for lack of provider
+    /// capacity, and answers them once it recovers, see [crate::outage].
+    pub fn with_outage_notices(mut self, outage: OutageNotices) -> Self {
+        self.pipeline = self.pipeline.with_outage_notices(outage);
+        self
+    }
+
+    /// Answers `/link` and `/unlink`, see [crate::linking].
+    pub fn with_linking(mut self, config: LinkingConfig) -> Self {
+        self.linking = Some(config);
+        self
     }
 
     pub async fn start(&self, token: &str) -> Result<()> {
@@ -60,115 +143,517 @@ impl From<teloxide::types::Message> for knowledge::Message {
             channel_id: msg.chat.id.to_string(),
             account_id: user_id,
             role: "user".to_string(),
-            content: msg.text().unwrap_or_default().to_string(),
+            content: match forwarded(&msg) {
+                Some(forward) => quoted::annotate("", &[forward]),
+                None => msg.text().unwrap_or_default().to_string(),
+            },
             created_at: msg.date,
         }
     }
 }
 
+/// What `msg` forwards, with who and where from as far as Telegram tells.
+/// A forward is a message of its own, so it has no text of the sender's.
+fn forwarded(msg: &teloxide::types::Message) -> Option<QuotedContent> {
+    let (author, chat) = match msg.forward_origin()? {
+        MessageOrigin::User { sender_user, .. } => (Some(sender_user.full_name()), None),
+        MessageOrigin::HiddenUser {
+            sender_user_name, ..
+        } => (Some(sender_user_name.clone()), None),
+        MessageOrigin::Chat {
+            sender_chat,
+            author_signature,
+            ..
+        } => (author_signature.clone(), Some(sender_chat)),
+        MessageOrigin::Channel {
+            chat,
+            author_signature,
+            ..
+        } => (author_signature.clone(), Some(chat)),
+    };
+    let text = msg.text().or(msg.caption()).unwrap_or_default();
+    let mut forward = QuotedContent::new(text);
+    if let Some(author) = author {
+        forward = forward.with_original_author(author);
+    }
+    if let Some(title) = chat.and_then(|chat| chat.title()) {
+        forward = forward.with_original_channel(title);
+    }
+    Some(forward)
+}
+
+/// The chats the pipeline answers in, see [Pipeline::handle].
+struct Chats {
+    bot: teloxide::Bot,
+    bot_id: String,
+    permissions: Permissions,
+}
+
+#[async_trait::async_trait]
+impl Client for Chats {
+    type Sink = ChatSink;
+
+    fn source(&self) -> knowledge::Source {
+        knowledge::Source::Telegram
+    }
+
+    fn account_id(&self) -> String {
+        self.bot_id.clone()
+    }
+
+    fn max_message_length(&self) -> usize {
+        MAX_MESSAGE_LENGTH
+    }
+
+    fn sink(&self, channel_id: &str) -> ChatSink {
+        ChatSink {
+            bot: self.bot.clone(),
+            chat_id: ChatId(channel_id.parse().unwrap_or_default()),
+            html: false,
+        }
+    }
+
+    fn formatted_sink(&self, channel_id: &str, metadata: &HashMap<String, String>) -> ChatSink {
+        ChatSink {
+            html: metadata.get(PARSE_MODE).map(String::as_str) == Some("HTML"),
+            ..self.sink(channel_id)
+        }
+    }
+
+    fn tier(&self, account_id: &str) -> PermissionTier {
+        self.permissions.tier(account_id)
+    }
+
+    async fn react(
+        &self,
+        channel_id: &str,
+        message_id: &str,
+        emoji: &str,
+    ) -> Result<(), ReactionError> {
+        let (Ok(chat_id), Ok(message_id)) = (channel_id.parse(), message_id.parse()) else {
+            return Err(ReactionError::Unsupported);
+        };
+        if !TELEGRAM_REACTIONS.contains(&emoji) {
+            return Err(ReactionError::Unsupported);
+        }
+
+        let reaction = ReactionType::Emoji {
+            emoji: emoji.to_string(),
+        };
+        match self
+            .bot
+            .set_message_reaction(ChatId(chat_id), MessageId(message_id))
+            .reaction([reaction])
+            .await
+        {
+            Ok(_) => Ok(()),
+            // The chat may have reactions turned off.
+            Err(RequestError::Api(err)) => {
+                debug!(?err, "Reaction rejected");
+                Err(ReactionError::Unsupported)
+            }
+            Err(err) => Err(ReactionError::Failed(Box::new(err))),
+        }
+    }
+}
+
+/// A chat answers are sent to, as HTML if `html`.
+struct ChatSink {
+    bot: teloxide::Bot,
+    chat_id: ChatId,
+    html: bool,
+}
+
+#[async_trait::async_trait]
+impl ReplySink for ChatSink {
+    type Handle = MessageId;
+    type Error = RequestError;
+
+    async fn send(&self, text: &str) -> Result<MessageId, RequestError> {
+        self.bot.send_text(self.chat_id, text, self.html).await
+    }
+
+    async fn edit(&self, handle: &MessageId, text: &str) -> Result<(), RequestError> {
+        self.bot
+            .edit_message_text(self.chat_id, *handle, text)
+            .await
+            .map(|_| ())
+    }
+
+    async fn send_files(&self, files: &[Attachment]) -> Result<Vec<MessageId>, RequestError> {
+        send_documents(&self.bot, self.chat_id, files).await
+    }
+}
+
+/// The Telegram call answers go out through.
+#[async_trait::async_trait]
+pub trait ChatSender: Send + Sync {
+    /// Sends `text` to `chat_id`, parsed as HTML if `html`.
+    async fn send_text(
+        &self,
+        chat_id: ChatId,
+        text: &str,
+        html: bool,
+    ) -> Result<MessageId, RequestError>;
+
+    /// Uploads `file` to `chat_id` as a document.
+    async fn send_document(
+        &self,
+        chat_id: ChatId,
+        file: &Attachment,
+    ) -> Result<MessageId, RequestError>;
+}
+
+#[async_trait::async_trait]
+impl ChatSender for teloxide::Bot {
+    async fn send_text(
+        &self,
+        chat_id: ChatId,
+        text: &str,
+        html: bool,
+    ) -> Result<MessageId, RequestError> {
+        let mut request = self.send_message(chat_id, text);
+        if html {
+            request = request.parse_mode(ParseMode::Html);
+        }
+        Ok(request.await?.id)
+    }
+
+    async fn send_document(
+        &self,
+        chat_id: ChatId,
+        file: &Attachment,
+    ) -> Result<MessageId, RequestError> {
+        let document =
+            InputFile::memory(file.content.clone().into_bytes()).file_name(file.name.clone());
+        Ok(Requester::send_document(self, chat_id, document).await?.id)
+    }
+}
+
+/// The outcome of [send_answer].
+#[derive(Debug)]
+pub struct ChatDelivery {
+    /// The messages sent, one per chunk, in order.
+    pub sent: Vec<MessageId>,
+    /// Why the chunk after the last sent one failed.
+    pub error: Option<RequestError>,
+}
+
+/// Sends `chunks` to `chat_id` in order, each as its own message, and
+/// records the messages sent as the answer to `interaction_id`. Stops at the
+/// first chunk that fails, so only what actually went out is recorded.
+pub async fn send_answer<E: EmbeddingModel>(
+    sender: &dyn ChatSender,
+    knowledge: &knowledge::KnowledgeBase<E>,
+    interaction_id: Option<i64>,
+    chat_id: ChatId,
+    chunks: &[String],
+    html: bool,
+) -> ChatDelivery {
+    let mut delivery = ChatDelivery {
+        sent: Vec::new(),
+        error: None,
+    };
+    for chunk in chunks {
+        match sender.send_text(chat_id, chunk, html).await {
+            Ok(id) => delivery.sent.push(id),
+            Err(err) => {
+                delivery.error = Some(err);
+                break;
+            }
+        }
+    }
+
+    if let (Some(interaction_id), false) = (interaction_id, delivery.sent.is_empty()) {
+        let message_ids = delivery
+            .sent
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        if let Err(err) = knowledge
+            .record_sent_messages(
+                interaction_id,
+                &knowledge::Source::Telegram,
+                &chat_id.to_string(),
+                &message_ids,
+            )
+            .await
+        {
+            error!(?err, "Failed to record sent messages");
+        }
+    }
+    delivery
+}
+
+/// Uploads `files` to `chat_id` after the chunks of an answer, stopping at
+/// the first that fails.
+pub async fn send_documents(
+    sender: &dyn ChatSender,
+    chat_id: ChatId,
+    files: &[Attachment],
+) -> Result<Vec<MessageId>, RequestError> {
+    let mut sent = Vec::with_capacity(files.len());
+    for file in files {
+        sent.push(sender.send_document(chat_id, file).await?);
+    }
+    Ok(sent)
+}
+
+/// Posts the `/say` announcement `request` as the bot `bot_id` and returns
+/// the reply to its author, who must be an admin able to post in the chat.
+async fn say<M: CompletionModel, E: EmbeddingModel>(
+    bot: &teloxide::Bot,
+    agent: &Agent<M, E>,
+    permissions: &Permissions,
+    bot_id: &str,
+    request: SayRequest,
+) -> String {
+    if let Err(err) = say::authorize(permissions.tier(&request.invoked_by)) {
+        info!(author = request.invoked_by, "Ignoring /say from non-admin");
+        return err.to_string();
+    }
+    let (Ok(chat_id), Ok(user_id)) = (
+        request.channel_id.parse().map(ChatId),
+        request.invoked_by.parse().map(UserId),
+    ) else {
+        return SayError::NoAccess.to_string();
+    };
+    match bot.get_chat_member(chat_id, user_id).await {
+        Ok(member) if member.is_present() && member.can_send_messages() => {}
+        Ok(_) => return SayError::NoAccess.to_string(),
+        Err(err) => {
+            debug!(?err, "Failed to fetch chat member");
+            return SayError::NoAccess.to_string();
+        }
+    }
+
+    let announcement = match say::prepare(agent, &request).await {
+        Ok(announcement) => announcement,
+        Err(err) => {
+            error!(?err, "Failed to prepare announcement");
+            return agent
+                .localized_template(
+                    templates::ERROR_GENERIC,
+                    Some(&request.invoked_by),
+                    &request.channel_id,
+                    &[],
+                )
+                .await;
+        }
+    };
+    let chunks = chunk_message(&announcement.text, MAX_MESSAGE_LENGTH, MIN_CHUNK_LENGTH);
+    // Sent messages are recorded along with the announcement itself
+    let delivery = send_answer(bot, agent.knowledge(), None, chat_id, &chunks, false).await;
+    let sent = delivery
+        .sent
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    let channel_type = if chat_id.is_user() {
+        knowledge::ChannelType::DirectMessage
+    } else {
+        knowledge::ChannelType::Text
+    };
+    say::record_delivery(
+        agent.knowledge(),
+        &announcement,
+        knowledge::Source::Telegram,
+        channel_type,
+        bot_id,
+        &chunks,
+        &sent,
+    )
+    .await;
+
+    match delivery.error {
+        None => "Posted.".to_string(),
+        Some(err) => format!("Posted {} of {} messages: {err}", sent.len(), chunks.len()),
+    }
+}
+
+/// Sends digests to a chat, see [crate::digest].
+pub struct ChatDigest {
+    bot: teloxide::Bot,
+    chat_id: ChatId,
+}
+
+impl ChatDigest {
+    /// Sends as the bot with `token` to the chat `chat_id`.
+    pub fn new(token: &str, chat_id: i64) -> Self {
+        Self {
+            bot: teloxide::Bot::new(token),
+            chat_id: ChatId(chat_id),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl DigestSink for ChatDigest {
+    async fn deliver(&self, digest: &str) -> Result<(), String> {
+        self.bot
+            .send_message(self.chat_id, digest)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Sends error reports to an operator chat, see [crate::reporting].
+pub struct ChatReport {
+    bot: teloxide::Bot,
+    chat_id: ChatId,
+}
+
+impl ChatReport {
+    /// Sends as the bot with `token` to the chat `chat_id`.
+    pub fn new(token: &str, chat_id: i64) -> Self {
+        Self {
+            bot: teloxide::Bot::new(token),
+            chat_id: ChatId(chat_id),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ReportSink for ChatReport {
+    async fn deliver(&self, report: &str) -> Result<(), String> {
+        self.bot
+            .send_message(self.chat_id, report)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
 impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> TelegramClient<M, E> {
     async fn run(&self, bot: teloxide::Bot) -> Result<()> {
-        let knowledge = self.agent.knowledge().clone();
-        let attention = self.attention.clone();
-        let agent = self.agent.clone();
+        let pipeline = self.pipeline.clone();
+        let permissions = self.permissions.clone();
+        let summarize = self.summarize.clone();
+        let linking = self.linking.clone();
+        let bot_id = bot.get_me().await?.id.to_string();
+
+        // Answers deferred for lack of provider capacity, while polling
+        let follow_ups = CancellationToken::new();
+        let chats = Chats {
+            bot: bot.clone(),
+            bot_id: bot_id.clone(),
+            permissions: permissions.clone(),
+        };
+        self.pipeline.spawn_follow_ups(chats, follow_ups.clone());
 
-        let handler = dptree::entry()
-            .branch(teloxide::types::Update::filter_message().endpoint(move |bot: teloxide::Bot, msg: teloxide::types::Message| {
-                let knowledge = knowledge.clone();
-                let attention = attention.clone();
-                let agent = agent.clone();
+        let handler = dptree::entry().branch(teloxide::types::Update::filter_message().endpoint(
+            move |bot: teloxide::Bot, msg: teloxide::types::Message| {
+                let pipeline = pipeline.clone();
+                let permissions = permissions.clone();
+                let summarize = summarize.clone();
+                let linking = linking.clone();
+                let bot_id = bot_id.clone();
 
                 async move {
+                    let agent = pipeline.agent();
+                    let knowledge = agent.knowledge();
                     let knowledge_msg = knowledge::Message::from(msg.clone());
 
-                    if let Err(err) = knowledge.create_message(knowledge_msg.clone()).await {
-                        error!(?err, "Failed to store message");
-                        return Err(anyhow::anyhow!(err));
+                    if let Some(Ok(commands::Command::Summarize { hours })) =
+                        commands::Command::parse(&knowledge_msg.content)
+                    {
+                        let summary = Summarizer::new(agent.clone())
+                            .with_config(summarize)
+                            .summarize(&knowledge_msg.channel_id, hours)
+                            .await;
+                        if let Err(why) = bot.send_message(msg.chat.id, summary).await {
+                            error!(?why, "Failed to send message");
+                        }
+                        return Ok(());
                     }
 
-                    debug!("Fetching message history for channel {}", msg.chat.id);
-                    let history = match knowledge
-                        .channel_messages(&msg.chat.id.to_string(), MAX_HISTORY_MESSAGES)
-                        .await
+                    if let Some(Ok(commands::Command::Say {
+                        channel_id,
+                        text,
+                        restyle,
+                    })) = commands::Command::parse(&knowledge_msg.content)
                     {
-                        Ok(messages) => {
-                            debug!(message_count = messages.len(), "Retrieved message history");
-                            messages
+                        let request = SayRequest {
+                            source: knowledge::Source::Telegram,
+                            invoked_by: knowledge_msg.source_id.clone(),
+                            channel_id,
+                            text,
+                            restyle,
+                        };
+                        let reply = say(&bot, agent, &permissions, &bot_id, request).await;
+                        if let Err(why) = bot.send_message(msg.chat.id, reply).await {
+                            error!(?why, "Failed to send message");
                         }
-                        Err(err) => {
-                            error!(?err, "Failed to fetch recent messages");
-                            return Err(anyhow::anyhow!(err));
-                        }
-                    };
-
-                    let mentioned_names: HashSet<String> = msg.text()
-                        .map(|text| {
-                            text.split_whitespace()
-                                .filter_map(|word| {
-                                    if word.starts_with('@') {
-                                        Some(word[1..].to_string())
-                                    } else {
-                                        None
-                                    }
-                                })
-                                .collect()
-                        })
-                        .unwrap_or_default();
-
-                    debug!(
-                        mentioned_names = ?mentioned_names,
-                        "Mentioned names in message"
-                    );
-
-                    let context = AttentionContext {
-                        message_content: msg.text().unwrap_or_default().to_string(),
-                        mentioned_names,
-                        history,
-                        channel_type: knowledge_msg.channel_type,
-                        source: knowledge_msg.source,
-                    };
-
-                    debug!(?context, "Attention context");
+                        return Ok(());
+                    }
 
-                    match attention.should_reply(&context).await {
-                        AttentionCommand::Respond => {}
-                        _ => {
-                            debug!("Bot decided not to reply to message");
-                            return Ok(());
+                    if let Some(reply) =
+                        linking::handle(knowledge, linking.as_ref(), &knowledge_msg).await
+                    {
+                        if let Err(why) = bot.send_message(msg.chat.id, reply).await {
+                            error!(?why, "Failed to send message");
                         }
+                        return Ok(());
                     }
 
-                    let agent = agent
-                        .builder()
-                        .context(&format!(
-                            "Current time: {}",
-                            chrono::Local::now().format("%I:%M:%S %p, %Y-%m-%d")
-                        ))
-                        .context("Please keep your responses concise and under 2000 characters when possible.")
-                        .build();
-
-                    let response = match agent.prompt(msg.text().unwrap_or_default()).await {
-                        Ok(response) => response,
-                        Err(err) => {
-                            error!(?err, "Failed to generate response");
-                            return Err(anyhow::anyhow!(err));
+                    if let Some(reply) = commands::handle(
+                        knowledge,
+                        &permissions.admins,
+                        &knowledge_msg.channel_id,
+                        &knowledge_msg.source_id,
+                        &knowledge_msg.content,
+                    )
+                    .await
+                    {
+                        if let Err(why) = bot.send_message(msg.chat.id, reply).await {
+                            error!(?why, "Failed to send message");
                         }
-                    };
-
-                    debug!(response = %response, "Generated response");
+                        return Ok(());
+                    }
 
-                    if let Err(why) = bot.send_message(msg.chat.id, response).await {
-                        error!(?why, "Failed to send message");
-                        return Err(anyhow::anyhow!(why));
+                    let mentioned_names: Vec<String> = knowledge_msg
+                        .content
+                        .split_whitespace()
+                        .filter_map(|word| word.strip_prefix('@'))
+                        .map(str::to_string)
+                        .collect();
+                    let mut incoming = IncomingMessage::from(knowledge_msg)
+                        .with_mentioned_names(mentioned_names)
+                        .with_is_reply(msg.reply_to_message().is_some())
+                        // Chats stand in for guilds in experiments
+                        .with_guild_id(msg.chat.id.to_string());
+                    // Only the bot's own answers can be corrected, by a
+                    // follow-up as an edit wouldn't notify the chat
+                    if let Some(replied) = msg.reply_to_message().filter(|replied| {
+                        replied
+                            .from
+                            .as_ref()
+                            .is_some_and(|from| from.id.to_string() == bot_id)
+                    }) {
+                        incoming = incoming.with_replied(
+                            replied.id.to_string(),
+                            replied.text().unwrap_or_default(),
+                        );
                     }
 
+                    let chats = Chats {
+                        bot,
+                        bot_id,
+                        permissions,
+                    };
+                    pipeline.handle(&chats, incoming).await?;
                     Ok(())
                 }
-            }));
+            },
+        ));
 
         let listener = teloxide::update_listeners::polling_default(bot.clone()).await;
 
+        // Updates are handled concurrently rather than per chat in order, so
+        // follow-up messages can join a batch whose owner is still waiting.
         teloxide::dispatching::Dispatcher::builder(bot, handler)
+            .distribution_function(|_| None::<std::convert::Infallible>)
             .build()
             .dispatch_with_listener(
                 listener,
@@ -176,6 +661,201 @@ impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> TelegramClient<M
             )
             .await;
 
+        follow_ups.cancel();
+        self.pipeline.close_batches();
+        self.pipeline.agent().conversations().flush().await;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::test_utils;
+
+    /// Sends with ids from 100, failing the chunk at `fail_at`.
+    struct FakeSender {
+        sends: AtomicUsize,
+        fail_at: Option<usize>,
+    }
+
+    #[async_trait::async_trait]
+    impl ChatSender for FakeSender {
+        async fn send_text(
+            &self,
+            _chat_id: ChatId,
+            _text: &str,
+            _html: bool,
+        ) -> Result<MessageId, RequestError> {
+            self.send()
+        }
+
+        async fn send_document(
+            &self,
+            _chat_id: ChatId,
+            _file: &Attachment,
+        ) -> Result<MessageId, RequestError> {
+            self.send()
+        }
+    }
+
+    impl FakeSender {
+        fn send(&self) -> Result<MessageId, RequestError> {
+            let index = self.sends.fetch_add(1, Ordering::SeqCst);
+            if Some(index) == self.fail_at {
+                return Err(RequestError::Api(teloxide::ApiError::BotBlocked));
+            }
+            Ok(MessageId(100 + index as i32))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_answer_records_sent_chunks() {
+        let knowledge = test_utils::knowledge_base().await;
+        let chunks = ["one", "two", "three"].map(String::from);
+
+        let complete = knowledge
+            .create_interaction("7".to_string(), "alice".to_string(), vec![])
+            .await
+            .unwrap();
+        let sender = FakeSender {
+            sends: AtomicUsize::new(0),
+            fail_at: None,
+        };
+        let delivery = send_answer(
+            &sender,
+            &knowledge,
+            Some(complete),
+            ChatId(7),
+            &chunks,
+            false,
+        )
+        .await;
+        assert!(delivery.error.is_none());
+        assert_eq!(
+            delivery.sent,
+            [MessageId(100), MessageId(101), MessageId(102)]
+        );
+        let sent = knowledge.sent_messages(complete).await.unwrap();
+        assert_eq!(
+            sent.iter()
+                .map(|sent| (sent.chunk_index, sent.platform_message_id.as_str()))
+                .collect::<Vec<_>>(),
+            [(0, "100"), (1, "101"), (2, "102")]
+        );
+        assert!(sent.iter().all(|sent| sent.channel_id == "7"));
+
+        // Only the chunks before the failed one are recorded
+        let partial = knowledge
+            .create_interaction("7".to_string(), "bob".to_string(), vec![])
+            .await
+            .unwrap();
+        let sender = FakeSender {
+            sends: AtomicUsize::new(0),
+            fail_at: Some(1),
+        };
+        let delivery = send_answer(
+            &sender,
+            &knowledge,
+            Some(partial),
+            ChatId(7),
+            &chunks,
+            false,
+        )
+        .await;
+        assert!(delivery.error.is_some());
+        assert_eq!(delivery.sent, [MessageId(100)]);
+        assert_eq!(knowledge.sent_messages(partial).await.unwrap().len(), 1);
+        assert_eq!(
+            knowledge
+                .interaction_for_platform_message(&knowledge::Source::Telegram, "101")
+                .await
+                .unwrap(),
+            Some(complete)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_documents_stops_at_failure() {
+        let files = ["snippet.rs", "snippet-2.py", "snippet-3.sh"].map(|name| Attachment {
+            name: name.to_string(),
+            content: "print(1)\n".to_string(),
+            truncated: false,
+        });
+        let sender = FakeSender {
+            sends: AtomicUsize::new(0),
+            fail_at: None,
+        };
+        let sent = send_documents(&sender, ChatId(7), &files).await.unwrap();
+        assert_eq!(sent, [MessageId(100), MessageId(101), MessageId(102)]);
+
+        let sender = FakeSender {
+            sends: AtomicUsize::new(0),
+            fail_at: Some(1),
+        };
+        assert!(send_documents(&sender, ChatId(7), &files).await.is_err());
+        assert_eq!(sender.sends.load(Ordering::SeqCst), 2);
+    }
+
+    /// A group message from the Bot API, with `extra` fields merged in.
+    fn message(extra: serde_json::Value) -> teloxide::types::Message {
+        let mut message = serde_json::json!({
+            "message_id": 5,
+            "date": 1_700_000_000,
+            "chat": {"id": -100, "type": "supergroup", "title": "Dojo"},
+            "from": {"id": 42, "is_bot": false, "first_name": "Alice"},
+        });
+        message
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(message).unwrap()
+    }
+
+    #[test]
+    fn test_forwards_are_quoted() {
+        let own = knowledge::Message::from(message(serde_json::json!({"text": "gm"})));
+        assert_eq!(own.content, "gm");
+
+        let from_user = knowledge::Message::from(message(serde_json::json!({
+            "text": "katana crashed on startup",
+            "forward_origin": {
+                "type": "user",
+                "date": 1_699_999_000,
+                "sender_user": {"id": 7, "is_bot": false, "first_name": "Bob", "last_name": "Smith"},
+            },
+        })));
+        assert_eq!(
+            from_user.content,
+            "[quoted from Bob Smith, not written by the sender]\n> katana crashed on startup"
+        );
+        assert_eq!(from_user.account_id, "42");
+        assert!(quoted::is_bare_forward(&from_user.content));
+
+        let from_channel = knowledge::Message::from(message(serde_json::json!({
+            "caption": "Torii 1.0 is out",
+            "photo": [{"file_id": "f", "file_unique_id": "u", "width": 1, "height": 1}],
+            "forward_origin": {
+                "type": "channel",
+                "date": 1_699_999_000,
+                "chat": {"id": -1001, "type": "channel", "title": "Dojo Announcements"},
+                "message_id": 9,
+                "author_signature": "Tarrence",
+            },
+        })));
+        assert_eq!(
+            from_channel.content,
+            "[quoted from Tarrence in Dojo Announcements, not written by the sender]\n\
+             > Torii 1.0 is out"
+        );
+
+        let hidden = knowledge::Message::from(message(serde_json::json!({
+            "text": "ping",
+            "forward_origin": {"type": "hidden_user", "date": 1_699_999_000, "sender_user_name": "Carol"},
+        })));
+        assert_eq!(quoted::own_text(&hidden.content), "");
+        assert!(hidden.content.starts_with("[quoted from Carol,"));
+    }
+}
@@ -1,15 +1,25 @@
 use crate::{
     agent::Agent,
     attention::{Attention, AttentionCommand, AttentionContext},
-    knowledge::{ChannelType, Message, Source},
+    clients::{
+        poller::{AdaptivePoller, PollerConfig, PollerStats, RateLimit},
+        post_tweet::TweetPoster,
+    },
+    hooks::{MessageContext, ResponseDraft},
+    knowledge::{ChannelType, KnowledgeBase, Message, Source},
+    templates,
 };
 
 use rig::{
     completion::{CompletionModel, Prompt},
     embeddings::EmbeddingModel,
 };
-use std::collections::HashSet;
-use tracing::{debug, error, info};
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::{debug, error, info, warn};
 use twitter::{authorization::Authorization, TwitterApi};
 use twitter_v2::{self as twitter, authorization::{BearerToken, Oauth1aToken}};
 use twitter_v2::data::ReferencedTweetKind;
@@ -17,11 +27,49 @@ use twitter_v2::data::ReferencedTweetKind;
 const MAX_TWEET_LENGTH: usize = 280;
 const MAX_HISTORY_TWEETS: i64 = 10;
 
+/// Length of a Twitter rate limit window, waited out after a rejected poll.
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(15 * 60);
+
 #[derive(Clone)]
 pub struct TwitterClient<M: CompletionModel, E: EmbeddingModel + 'static, A: Authorization> {
     agent: Agent<M, E>,
     attention: Attention<M>,
     api: TwitterApi<A>,
+    poller: Arc<Mutex<AdaptivePoller>>,
+}
+
+/// Posts `chunks` in order as replies to `in_reply_to` and records the tweets
+/// posted as the answer to `interaction_id`. Stops at the first chunk that
+/// fails, so a thread never has a hole in it, and returns how many were
+/// posted.
+pub async fn post_replies<E: EmbeddingModel>(
+    poster: &dyn TweetPoster,
+    knowledge: &KnowledgeBase<E>,
+    interaction_id: Option<i64>,
+    channel_id: &str,
+    in_reply_to: u64,
+    chunks: &[String],
+) -> usize {
+    let mut posted = Vec::new();
+    for chunk in chunks {
+        match poster.reply(chunk, in_reply_to).await {
+            Ok(id) => posted.push(id),
+            Err(err) => {
+                error!(?err, "Failed to send tweet");
+                break;
+            }
+        }
+    }
+
+    if let (Some(interaction_id), false) = (interaction_id, posted.is_empty()) {
+        if let Err(err) = knowledge
+            .record_sent_messages(interaction_id, &Source::Twitter, channel_id, &posted)
+            .await
+        {
+            error!(?err, "Failed to record sent tweets");
+        }
+    }
+    posted.len()
 }
 
 impl From<twitter::Tweet> for Message {
@@ -57,6 +105,7 @@ impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> TwitterClient<M,
             agent,
             attention,
             api,
+            poller: Arc::new(Mutex::new(AdaptivePoller::new(PollerConfig::default()))),
         }
     }
 }
@@ -70,11 +119,23 @@ impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> TwitterClient<M,
             agent,
             attention,
             api,
+            poller: Arc::new(Mutex::new(AdaptivePoller::new(PollerConfig::default()))),
         }
     }
 }
 
-impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static, A: Authorization> TwitterClient<M, E, A> {
+impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static, A: Authorization + Send + Sync> TwitterClient<M, E, A> {
+    /// Sets the bounds of the mention polling interval, see [AdaptivePoller].
+    pub fn with_poller_config(mut self, config: PollerConfig) -> Self {
+        self.poller = Arc::new(Mutex::new(AdaptivePoller::new(config)));
+        self
+    }
+
+    /// Current mention polling interval and remaining rate limit.
+    pub fn poll_stats(&self) -> PollerStats {
+        self.poller.lock().unwrap().stats()
+    }
+
     pub async fn start(&self) -> Result<(), Box<dyn std::error::Error>> {
         info!("Starting Twitter bot");
         self.listen_for_mentions().await
@@ -82,7 +143,11 @@ impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static, A: Authorization
 
     async fn listen_for_mentions(&self) -> Result<(), Box<dyn std::error::Error>> {
         let me = self.api.get_users_me().send().await?;
-        let user_id = me.data.as_ref().unwrap().id;
+        let user_id = me
+            .data
+            .as_ref()
+            .ok_or("Twitter returned no data for the authenticated user")?
+            .id;
 
         // In a real implementation, you would use Twitter's streaming API
         // This is a simplified polling approach
@@ -92,13 +157,33 @@ impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static, A: Authorization
                 .get_user_mentions(user_id)
                 .max_results(5)
                 .send()
-                .await?;
-
-            for tweet in mentions.data.clone().unwrap_or_default() {
-                self.handle_mention(tweet).await?;
-            }
+                .await;
+            let delay = match mentions {
+                Ok(mentions) => {
+                    let tweets = mentions.data.clone().unwrap_or_default();
+                    let delay = self.poller.lock().unwrap().observe(tweets.len(), None);
+                    for tweet in tweets {
+                        self.handle_mention(tweet).await?;
+                    }
+                    delay
+                }
+                // twitter_v2 does not expose response headers, so a rejected
+                // poll is the only rate limit signal. Wait out the window.
+                Err(twitter::Error::Api(err)) if err.status.as_u16() == 429 => {
+                    warn!("Twitter rate limit reached, pausing mention polling");
+                    self.poller.lock().unwrap().observe(
+                        0,
+                        Some(RateLimit {
+                            remaining: 0,
+                            reset_in: RATE_LIMIT_WINDOW,
+                        }),
+                    )
+                }
+                Err(err) => return Err(err.into()),
+            };
 
-            tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
+            debug!(stats = ?self.poll_stats(), "Polled mentions");
+            tokio::time::sleep(delay).await;
         }
     }
 
@@ -137,8 +222,12 @@ impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static, A: Authorization
             message_content: tweet.text.clone(),
             mentioned_names,
             history,
-            channel_type: knowledge_msg.channel_type,
-            source: knowledge_msg.source,
+            recent_replies: Vec::new(),
+            channel_id: knowledge_msg.channel_id.clone(),
+            channel_type: knowledge_msg.channel_type.clone(),
+            source: knowledge_msg.source.clone(),
+            topic_match: self.agent.character.topic_match(&tweet.text),
+            familiarity: None,
         };
 
         debug!(?context, "Attention context");
@@ -154,10 +243,13 @@ impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static, A: Authorization
         let agent = self
             .agent
             .builder()
-            .context(&format!(
-                "Current time: {}",
-                chrono::Local::now().format("%I:%M:%S %p, %Y-%m-%d")
-            ))
+            .context(
+                &self
+                    .agent
+                    .locale()
+                    .preferences()
+                    .current_time(self.agent.clock().now_utc()),
+            )
             .context("Please keep your responses concise and under 280 characters.")
             .build();
 
@@ -165,33 +257,72 @@ impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static, A: Authorization
             Ok(response) => response,
             Err(err) => {
                 error!(?err, "Failed to generate response");
+                let apology = self
+                    .agent
+                    .localized_template(
+                        templates::ERROR_GENERIC,
+                        Some(&knowledge_msg.account_id),
+                        &knowledge_msg.channel_id,
+                        &[],
+                    )
+                    .await;
+                post_replies(
+                    &self.api,
+                    knowledge,
+                    None,
+                    &knowledge_msg.channel_id,
+                    tweet.id.as_u64(),
+                    &[apology],
+                )
+                .await;
                 return Ok(());
             }
         };
 
         debug!(response = %response, "Generated response");
 
+        let draft = self
+            .agent
+            .process_response(
+                ResponseDraft::new(response, Source::Twitter),
+                &MessageContext::from(&knowledge_msg),
+            )
+            .await;
+
         // Split response into tweet-sized chunks if necessary
-        let chunks: Vec<String> = response
+        let chunks: Vec<String> = draft
+            .text
             .chars()
             .collect::<Vec<char>>()
             .chunks(MAX_TWEET_LENGTH)
             .map(|chunk| chunk.iter().collect::<String>())
             .collect();
 
-        // Reply to the original tweet
-        for chunk in chunks {
-            if let Err(err) = self
-                .api
-                .post_tweet()
-                .in_reply_to_tweet_id(tweet.id)
-                .text(chunk)
-                .send()
-                .await
-            {
-                error!(?err, "Failed to send tweet");
+        let interaction_id = match knowledge
+            .create_interaction(
+                knowledge_msg.channel_id.clone(),
+                knowledge_msg.account_id.clone(),
+                vec![knowledge_msg.id.clone()],
+            )
+            .await
+        {
+            Ok(id) => Some(id),
+            Err(err) => {
+                error!(?err, "Failed to record interaction");
+                None
             }
-        }
+        };
+
+        // Reply to the original tweet
+        post_replies(
+            &self.api,
+            knowledge,
+            interaction_id,
+            &knowledge_msg.channel_id,
+            tweet.id.as_u64(),
+            &chunks,
+        )
+        .await;
 
         Ok(())
     }
@@ -239,3 +370,75 @@ impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static, A: Authorization
         Ok(thread)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::{clients::post_tweet::PostTweetError, test_utils};
+
+    /// Replies with ids from 1000, failing once `fail_after` were posted.
+    struct FakePoster {
+        posted: Mutex<Vec<String>>,
+        fail_after: usize,
+    }
+
+    #[async_trait]
+    impl TweetPoster for FakePoster {
+        async fn post(&self, _text: &str) -> Result<String, PostTweetError> {
+            unreachable!("answers are posted as replies")
+        }
+
+        async fn reply(&self, text: &str, _in_reply_to: u64) -> Result<String, PostTweetError> {
+            let mut posted = self.posted.lock().unwrap();
+            if posted.len() == self.fail_after {
+                return Err(PostTweetError::Api("Too Many Requests".to_string()));
+            }
+            posted.push(text.to_string());
+            Ok(format!("{}", 1000 + posted.len()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_post_replies_records_posted_tweets() {
+        let knowledge = test_utils::knowledge_base().await;
+        let interaction = knowledge
+            .create_interaction("1".to_string(), "alice".to_string(), vec![])
+            .await
+            .unwrap();
+        let poster = FakePoster {
+            posted: Mutex::new(Vec::new()),
+            fail_after: 2,
+        };
+        let chunks = ["one", "two", "three"].map(String::from);
+
+        let posted = post_replies(
+            &poster,
+            &knowledge,
+            Some(interaction),
+            "1",
+            1,
+            &chunks,
+        )
+        .await;
+
+        assert_eq!(posted, 2);
+        let sent = knowledge.sent_messages(interaction).await.unwrap();
+        assert_eq!(
+            sent.iter()
+                .map(|sent| sent.platform_message_id.as_str())
+                .collect::<Vec<_>>(),
+            ["1001", "1002"]
+        );
+        assert_eq!(
+            knowledge
+                .interaction_for_platform_message(&Source::Twitter, "1002")
+                .await
+                .unwrap(),
+            Some(interaction)
+        );
+    }
+}
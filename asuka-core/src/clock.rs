@@ -0,0 +1,99 @@
+//! The current time, injectable so that time-dependent behavior can be
+//! tested without sleeping: cooldowns, scheduled jobs, retention cutoffs and
+//! the time written into prompts.
+//!
+//! Components default to [SystemClock]. A clock set on the
+//! [KnowledgeBase](crate::knowledge::KnowledgeBase) or the
+//! [Agent](crate::agent::Agent) is shared by everything built on them, so
+//! tests set a [MockClock] once and move it along:
+//!
+//! ```ignore
+//! let clock = MockClock::new(start);
+//! let agent = Agent::new(character, model, knowledge).with_clock(Arc::new(clock.clone()));
+//! clock.advance(chrono::Duration::minutes(10));
+//! ```
+//!
+//! Waiting is left to tokio's timers, which tests pause and advance with
+//! `tokio::time`. Rows stamped by the database with `CURRENT_TIMESTAMP` keep
+//! the database's time.
+
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+
+pub trait Clock: Send + Sync {
+    fn now_utc(&self) -> DateTime<Utc>;
+
+    fn now_in(&self, tz: &Tz) -> DateTime<Tz> {
+        self.now_utc().with_timezone(tz)
+    }
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_utc(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Always the same time.
+pub struct FixedClock(pub DateTime<Utc>);
+
+impl Clock for FixedClock {
+    fn now_utc(&self) -> DateTime<Utc> {
+        self.0
+    }
+}
+
+/// A time that only changes when set or advanced. Clones share it.
+#[derive(Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<DateTime<Utc>>>,
+}
+
+impl MockClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, by: chrono::Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+impl Clock for MockClock {
+    fn now_utc(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_mock_clock_is_shared() {
+        let start = Utc.with_ymd_and_hms(2024, 11, 5, 23, 30, 0).unwrap();
+        let clock = MockClock::new(start);
+        let shared: Arc<dyn Clock> = Arc::new(clock.clone());
+
+        clock.advance(chrono::Duration::hours(1));
+        assert_eq!(shared.now_utc(), start + chrono::Duration::hours(1));
+        assert_eq!(
+            shared.now_in(&chrono_tz::Europe::Berlin).to_rfc3339(),
+            "2024-11-06T01:30:00+01:00"
+        );
+
+        clock.set(start);
+        assert_eq!(shared.now_utc(), start);
+    }
+}
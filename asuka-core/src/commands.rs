@@ -0,0 +1,855 @@
+use std::collections::HashSet;
+
+use rig::embeddings::EmbeddingModel;
+use tracing::{error, info};
+
+use crate::{
+    confidence::STRICT_CONFIDENCE_SETTING,
+    hooks::DISCLAIMER_SETTING,
+    knowledge::{
+        format_gaps, format_tool_calls, KnowledgeBase, MaintenanceOptions, RETAIN_FOREVER_SETTING,
+    },
+    locale::{self, DateFormat, DATE_FORMAT_SETTING, TIMEZONE_SETTING},
+};
+
+/// Channel setting with `on` or `off`, changed with `/listen`. The Discord
+/// client ignores messages in channels where it is `off`, except `/listen`
+/// itself.
+pub const LISTEN_SETTING: &str = "listen";
+
+const GLOBAL_FLAG: &str = "--global";
+const GUILD_FLAG: &str = "--guild";
+const RESTYLE_FLAG: &str = "--restyle";
+const CONFIRM_FLAG: &str = "--confirm";
+const DEFAULT_GAP_DAYS: i64 = 7;
+const DEFAULT_SUMMARY_HOURS: i64 = 24;
+/// Longest window `/summarize` accepts, one week.
+const MAX_SUMMARY_HOURS: i64 = 24 * 7;
+const GAP_LIMIT: usize = 10;
+
+/// Admin commands sent as plain text. Discord spells them `/pin-context`,
+/// Telegram `/pin_context`; both forms are accepted everywhere.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    PinContext {
+        global: bool,
+        content: String,
+    },
+    UnpinContext {
+        id: i64,
+    },
+    ListPinned,
+    /// Most asked questions the docs could not answer in the last `days`.
+    KnowledgeGaps {
+        days: i64,
+    },
+    /// Sets the disclaimer appended to replies in the channel, or clears it
+    /// when `text` is `None`.
+    SetDisclaimer {
+        text: Option<String>,
+    },
+    /// Declines (`Some(true)`) or hedges (`Some(false)`) low confidence
+    /// replies in the channel, or follows the config when `None`.
+    SetStrictMode {
+        enabled: Option<bool>,
+    },
+    /// Sets the time zone times are written in, by IANA name, in the channel
+    /// or with `guild` in every channel of its guild, or goes back to the
+    /// default when `None`. See [crate::locale].
+    SetTimezone {
+        guild: bool,
+        timezone: Option<String>,
+    },
+    /// Sets how dates are written, like [Command::SetTimezone].
+    SetDateFormat {
+        guild: bool,
+        format: Option<DateFormat>,
+    },
+    /// Starts or stops the bot listening in a Discord channel. Still handled
+    /// in channels where it doesn't listen, so it can be turned back on.
+    Listen {
+        enabled: bool,
+    },
+    /// Exempts the channel's messages from retention, or makes them expire
+    /// again, see [crate::retention].
+    RetainForever {
+        enabled: bool,
+    },
+    /// Tool calls behind a bot reply, given by message link or id, or behind
+    /// the latest reply in the channel that used a tool when `None`.
+    ToolCalls {
+        message_id: Option<String>,
+    },
+    /// Open escalations in the channel, see [crate::escalation].
+    ListEscalations,
+    /// Marks an escalation handled by the author.
+    ResolveEscalation {
+        id: i64,
+    },
+    /// Re-reads the config file. Handled by clients that support it, and
+    /// restricted to the bot owner there.
+    ReloadConfig,
+    /// Summary of the last `hours` in the channel. Open to everyone and
+    /// handled by the clients, as it needs the completion model.
+    Summarize {
+        hours: i64,
+    },
+    /// Answers `question` with a deep research loop, see [crate::research].
+    /// Open to everyone and handled by the clients, as it needs the
+    /// completion model.
+    AskDeep {
+        question: String,
+    },
+    /// Cleans up the vector tables, see [KnowledgeBase::maintenance]. Uses an
+    /// incremental vacuum, as a full one would block the bot while it runs.
+    Maintenance {
+        dry_run: bool,
+        reembed: bool,
+    },
+    /// Posts `text` as the bot in `channel_id`, given by id or Discord channel
+    /// mention, see [crate::say]. Handled by the clients, as it is sent like
+    /// an answer.
+    Say {
+        channel_id: String,
+        text: String,
+        restyle: bool,
+    },
+    /// Erases what is stored about a user, given by id or Discord mention,
+    /// see [KnowledgeBase::erase_user]. Only previews what would be erased
+    /// until repeated with `confirm`. Handled by the clients, and restricted
+    /// to the bot owner there.
+    EraseUser {
+        user_id: String,
+        confirm: bool,
+    },
+    /// Asks for a code to link the author's account with their account on
+    /// another platform, or redeems one when `code` is given, see
+    /// [crate::linking]. Open to everyone and handled by the clients.
+    Link {
+        code: Option<String>,
+    },
+    /// Takes the author's account out of its linked identity.
+    Unlink,
+}
+
+impl Command {
+    /// Returns `None` when the text is not one of our commands, and an error
+    /// with usage help when it is but the arguments are wrong.
+    pub fn parse(text: &str) -> Option<Result<Self, String>> {
+        let text = text.trim();
+        let rest = text.strip_prefix('/')?;
+        let (name, args) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        // Telegram appends the bot name in groups, e.g. `/list_pinned@asuka_bot`.
+        let name = name.split('@').next().unwrap_or_default().replace('_', "-");
+        let args = args.trim();
+
+        let command = match name.as_str() {
+            "pin-context" => {
+                let (global, content) = match args.strip_prefix(GLOBAL_FLAG) {
+                    Some(content) => (true, content.trim()),
+                    None => (false, args),
+                };
+                if content.is_empty() {
+                    return Some(Err(
+                        "Usage: /pin-context [--global] <text to always include>".to_string(),
+                    ));
+                }
+                Command::PinContext {
+                    global,
+                    content: content.to_string(),
+                }
+            }
+            "unpin-context" => match args.parse() {
+                Ok(id) => Command::UnpinContext { id },
+                Err(_) => return Some(Err("Usage: /unpin-context <pin id>".to_string())),
+            },
+            "list-pinned" => Command::ListPinned,
+            "knowledge-gaps" if args.is_empty() => Command::KnowledgeGaps {
+                days: DEFAULT_GAP_DAYS,
+            },
+            "knowledge-gaps" => match args.parse() {
+                Ok(days) if days > 0 => Command::KnowledgeGaps { days },
+                _ => return Some(Err("Usage: /knowledge-gaps [days]".to_string())),
+            },
+            "set-disclaimer" if args.is_empty() => {
+                return Some(Err("Usage: /set-disclaimer <text>".to_string()))
+            }
+            "set-disclaimer" => Command::SetDisclaimer {
+                text: Some(args.to_string()),
+            },
+            "clear-disclaimer" => Command::SetDisclaimer { text: None },
+            "strict-mode" => match args {
+                "on" => Command::SetStrictMode {
+                    enabled: Some(true),
+                },
+                "off" => Command::SetStrictMode {
+                    enabled: Some(false),
+                },
+                "default" => Command::SetStrictMode { enabled: None },
+                _ => return Some(Err("Usage: /strict-mode <on|off|default>".to_string())),
+            },
+            "timezone" => {
+                let (guild, zone) = match args.strip_prefix(GUILD_FLAG) {
+                    Some(zone) => (true, zone.trim()),
+                    None => (false, args),
+                };
+                let timezone = match zone {
+                    "default" => None,
+                    zone => match locale::parse_timezone(zone) {
+                        Some(tz) => Some(tz.name().to_string()),
+                        None => {
+                            return Some(Err(
+                                "Usage: /timezone [--guild] <zone, e.g. Europe/Berlin|default>"
+                                    .to_string(),
+                            ))
+                        }
+                    },
+                };
+                Command::SetTimezone { guild, timezone }
+            }
+            "date-format" => {
+                let (guild, format) = match args.strip_prefix(GUILD_FLAG) {
+                    Some(format) => (true, format.trim()),
+                    None => (false, args),
+                };
+                let format = match format {
+                    "default" => None,
+                    format => match DateFormat::from_str(format) {
+                        Some(format) => Some(format),
+                        None => {
+                            return Some(Err(
+                                "Usage: /date-format [--guild] <iso|us|dmy|default>".to_string()
+                            ))
+                        }
+                    },
+                };
+                Command::SetDateFormat { guild, format }
+            }
+            "listen" => match args {
+                "on" => Command::Listen { enabled: true },
+                "off" => Command::Listen { enabled: false },
+                _ => return Some(Err("Usage: /listen <on|off>".to_string())),
+            },
+            "retain-forever" => match args {
+                "on" => Command::RetainForever { enabled: true },
+                "off" => Command::RetainForever { enabled: false },
+                _ => return Some(Err("Usage: /retain-forever <on|off>".to_string())),
+            },
+            "toolcalls" if args.is_empty() || args == "last" => {
+                Command::ToolCalls { message_id: None }
+            }
+            "toolcalls" => match message_id_from_link(args) {
+                Some(id) => Command::ToolCalls {
+                    message_id: Some(id),
+                },
+                None => return Some(Err("Usage: /toolcalls <message link or last>".to_string())),
+            },
+            "escalations" => Command::ListEscalations,
+            "resolve-escalation" => match args.trim_start_matches('#').parse() {
+                Ok(id) => Command::ResolveEscalation { id },
+                Err(_) => return Some(Err("Usage: /resolve-escalation <id>".to_string())),
+            },
+            "reload-config" => Command::ReloadConfig,
+            "summarize" if args.is_empty() => Command::Summarize {
+                hours: DEFAULT_SUMMARY_HOURS,
+            },
+            "summarize" => match args.parse() {
+                Ok(hours) if (1..=MAX_SUMMARY_HOURS).contains(&hours) => {
+                    Command::Summarize { hours }
+                }
+                _ => {
+                    return Some(Err(format!(
+                        "Usage: /summarize [hours], at most {MAX_SUMMARY_HOURS}"
+                    )))
+                }
+            },
+            "ask-deep" if args.is_empty() => {
+                return Some(Err("Usage: /ask-deep <question>".to_string()))
+            }
+            "ask-deep" => Command::AskDeep {
+                question: args.to_string(),
+            },
+            "maintenance" => {
+                let (mut dry_run, mut reembed) = (false, false);
+                for flag in args.split_whitespace() {
+                    match flag {
+                        "--dry-run" => dry_run = true,
+                        "--reembed" => reembed = true,
+                        _ => {
+                            return Some(Err(
+                                "Usage: /maintenance [--dry-run] [--reembed]".to_string()
+                            ))
+                        }
+                    }
+                }
+                Command::Maintenance { dry_run, reembed }
+            }
+            "say" => {
+                let (restyle, args) = match args.strip_prefix(RESTYLE_FLAG) {
+                    Some(args) => (true, args.trim_start()),
+                    None => (false, args),
+                };
+                let (channel, text) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+                let channel_id = channel.trim_start_matches("<#").trim_end_matches('>');
+                let text = text.trim();
+                if channel_id.is_empty() || text.is_empty() {
+                    return Some(Err("Usage: /say [--restyle] <channel> <text>".to_string()));
+                }
+                Command::Say {
+                    channel_id: channel_id.to_string(),
+                    text: text.to_string(),
+                    restyle,
+                }
+            }
+            "erase-user" => {
+                let (user, confirm) = match args.strip_suffix(CONFIRM_FLAG) {
+                    Some(user) => (user.trim(), true),
+                    None => (args, false),
+                };
+                let user_id = user
+                    .trim_start_matches("<@")
+                    .trim_start_matches('!')
+                    .trim_end_matches('>');
+                if user_id.is_empty() || user_id.contains(char::is_whitespace) {
+                    return Some(Err("Usage: /erase-user <user> [--confirm]".to_string()));
+                }
+                Command::EraseUser {
+                    user_id: user_id.to_string(),
+                    confirm,
+                }
+            }
+            "link" if args.is_empty() => Command::Link { code: None },
+            "link" if !args.contains(char::is_whitespace) => Command::Link {
+                code: Some(args.to_string()),
+            },
+            "link" => return Some(Err("Usage: /link [code]".to_string())),
+            "unlink" => Command::Unlink,
+            _ => return None,
+        };
+
+        Some(Ok(command))
+    }
+
+    pub async fn execute<E: EmbeddingModel>(
+        self,
+        knowledge: &KnowledgeBase<E>,
+        channel_id: &str,
+        author: &str,
+    ) -> String {
+        let result = match self {
+            Command::PinContext { global, content } => {
+                let scope = (!global).then(|| channel_id.to_string());
+                knowledge
+                    .add_pin(scope, content, author.to_string())
+                    .await
+                    .map(|id| {
+                        info!(id, channel_id, global, author, "Pinned context");
+                        format!("Pinned as #{id}.")
+                    })
+            }
+            Command::UnpinContext { id } => {
+                knowledge.remove_pin(id, channel_id).await.map(|removed| {
+                    if removed {
+                        info!(id, channel_id, author, "Unpinned context");
+                        format!("Unpinned #{id}.")
+                    } else {
+                        format!("No pin with id #{id} here.")
+                    }
+                })
+            }
+            Command::ListPinned => knowledge.pinned_context(channel_id).await.map(|pins| {
+                if pins.is_empty() {
+                    return "Nothing is pinned here.".to_string();
+                }
+                pins.iter()
+                    .map(|pin| {
+                        let scope = if pin.channel_id.is_some() {
+                            "channel"
+                        } else {
+                            "global"
+                        };
+                        format!("#{} ({}): {}", pin.id, scope, pin.content)
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }),
+            Command::KnowledgeGaps { days } => {
+                let since = knowledge.clock().now_utc() - chrono::Duration::days(days);
+                knowledge
+                    .top_gaps(since, GAP_LIMIT)
+                    .await
+                    .map(|gaps| format_gaps(&gaps))
+            }
+            Command::SetDisclaimer { text } => knowledge
+                .set_channel_setting(channel_id, DISCLAIMER_SETTING, text.as_deref())
+                .await
+                .map(|()| {
+                    info!(channel_id, author, "Updated disclaimer");
+                    match text {
+                        Some(_) => "Disclaimer set.".to_string(),
+                        None => "Disclaimer cleared.".to_string(),
+                    }
+                }),
+            Command::SetStrictMode { enabled } => {
+                let value = enabled.map(|enabled| if enabled { "on" } else { "off" });
+                knowledge
+                    .set_channel_setting(channel_id, STRICT_CONFIDENCE_SETTING, value)
+                    .await
+                    .map(|()| {
+                        info!(channel_id, author, ?enabled, "Updated strict mode");
+                        match enabled {
+                            Some(true) => "Unsupported answers will be declined here.",
+                            Some(false) => "Unsupported answers will be hedged here.",
+                            None => "Strict mode follows the config here.",
+                        }
+                        .to_string()
+                    })
+            }
+            Command::SetTimezone { guild, timezone } => {
+                let result = set_locale_setting(
+                    knowledge,
+                    channel_id,
+                    guild,
+                    TIMEZONE_SETTING,
+                    timezone.as_deref(),
+                )
+                .await;
+                result.map(|set| {
+                    if set {
+                        info!(channel_id, author, guild, ?timezone, "Updated time zone");
+                    }
+                    match (set, timezone) {
+                        (false, _) => "This channel is not in a guild.".to_string(),
+                        (true, Some(timezone)) => format!("Times are written in {timezone}."),
+                        (true, None) => "Times are written in the default time zone.".to_string(),
+                    }
+                })
+            }
+            Command::SetDateFormat { guild, format } => {
+                let result = set_locale_setting(
+                    knowledge,
+                    channel_id,
+                    guild,
+                    DATE_FORMAT_SETTING,
+                    format.map(|format| format.as_str()),
+                )
+                .await;
+                result.map(|set| {
+                    if set {
+                        info!(channel_id, author, guild, ?format, "Updated date format");
+                    }
+                    match (set, format) {
+                        (false, _) => "This channel is not in a guild.".to_string(),
+                        (true, Some(format)) => {
+                            format!("Dates are written in the {} format.", format.as_str())
+                        }
+                        (true, None) => "Dates are written in the default format.".to_string(),
+                    }
+                })
+            }
+            Command::Listen { enabled } => {
+                let value = if enabled { "on" } else { "off" };
+                knowledge
+                    .set_channel_setting(channel_id, LISTEN_SETTING, Some(value))
+                    .await
+                    .map(|()| {
+                        info!(channel_id, author, enabled, "Updated listening");
+                        if enabled {
+                            "Listening in this channel.".to_string()
+                        } else {
+                            "No longer listening in this channel.".to_string()
+                        }
+                    })
+            }
+            Command::RetainForever { enabled } => {
+                let value = enabled.then_some("on");
+                knowledge
+                    .set_channel_setting(channel_id, RETAIN_FOREVER_SETTING, value)
+                    .await
+                    .map(|()| {
+                        info!(channel_id, author, enabled, "Updated retention");
+                        if enabled {
+                            "Messages in this channel will be kept forever.".to_string()
+                        } else {
+                            "Messages in this channel will expire like elsewhere.".to_string()
+                        }
+                    })
+            }
+            Command::ToolCalls { message_id } => {
+                let interaction = match &message_id {
+                    Some(id) => knowledge.interaction_for_message(id).await,
+                    None => knowledge.last_tool_interaction(channel_id).await,
+                };
+                match interaction {
+                    Ok(Some(id)) => match knowledge.tool_calls(id).await {
+                        Ok(calls) => knowledge
+                            .interaction_confidence(id)
+                            .await
+                            .map(|confidence| {
+                                let calls = format_tool_calls(&calls);
+                                match confidence {
+                                    Some(confidence) => format!(
+                                        "Confidence {:.2} ({})\n{calls}",
+                                        confidence.score,
+                                        confidence.outcome.as_str()
+                                    ),
+                                    None => calls,
+                                }
+                            }),
+                        Err(err) => Err(err),
+                    },
+                    Ok(None) => Ok("No tool calls recorded for that reply.".to_string()),
+                    Err(err) => Err(err),
+                }
+            }
+            Command::ListEscalations => {
+                knowledge
+                    .open_escalations(Some(channel_id))
+                    .await
+                    .map(|escalations| {
+                        if escalations.is_empty() {
+                            return "No open escalations here.".to_string();
+                        }
+                        escalations
+                            .iter()
+                            .map(|escalation| {
+                                format!(
+                                    "#{} <@{}> ({}) {}",
+                                    escalation.id,
+                                    escalation.account_id,
+                                    escalation.reason,
+                                    escalation.created_at.format("%Y-%m-%d %H:%M UTC")
+                                )
+                            })
+                            .collect::<Vec<_>>()
+                            .join("\n")
+                    })
+            }
+            Command::ResolveEscalation { id } => {
+                knowledge
+                    .handle_escalation(id, author)
+                    .await
+                    .map(|handled| {
+                        if handled {
+                            info!(id, author, "Resolved escalation");
+                            format!("Escalation #{id} marked handled.")
+                        } else {
+                            format!("No open escalation #{id}.")
+                        }
+                    })
+            }
+            Command::ReloadConfig => Ok("Config reload is not supported here.".to_string()),
+            Command::Summarize { .. } => Ok("Summaries are not supported here.".to_string()),
+            Command::AskDeep { .. } => Ok("Deep research is not supported here.".to_string()),
+            Command::Say { .. } => Ok("Speaking as the bot is not supported here.".to_string()),
+            Command::EraseUser { .. } => Ok("Erasing users is not supported here.".to_string()),
+            Command::Link { .. } | Command::Unlink => {
+                Ok("Linking accounts is not supported here.".to_string())
+            }
+            Command::Maintenance { dry_run, reembed } => {
+                let options = MaintenanceOptions {
+                    dry_run,
+                    reembed_missing: reembed,
+                    ..Default::default()
+                };
+                knowledge.maintenance(&options).await.map(|report| {
+                    info!(author, dry_run, "Ran store maintenance");
+                    report.to_string()
+                })
+            }
+        };
+
+        result.unwrap_or_else(|err| {
+            error!(?err, "Failed to run command");
+            "Sorry, that command failed.".to_string()
+        })
+    }
+}
+
+/// Sets a [crate::locale] setting of `channel_id`, or with `guild` of its
+/// guild. `false` when the channel is not in a guild.
+async fn set_locale_setting<E: EmbeddingModel>(
+    knowledge: &KnowledgeBase<E>,
+    channel_id: &str,
+    guild: bool,
+    key: &str,
+    value: Option<&str>,
+) -> Result<bool, rig_sqlite::SqliteError> {
+    if !guild {
+        knowledge
+            .set_channel_setting(channel_id, key, value)
+            .await?;
+        return Ok(true);
+    }
+    match knowledge.channel_guild(channel_id).await? {
+        Some(guild_id) => {
+            knowledge.set_guild_setting(&guild_id, key, value).await?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// The message id at the end of a Discord or Telegram message link, or the
+/// argument itself when it is a bare id.
+fn message_id_from_link(link: &str) -> Option<String> {
+    let id = link
+        .split(['?', '#'])
+        .next()?
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()?;
+    (!id.is_empty() && !id.contains(char::is_whitespace)).then(|| id.to_string())
+}
+
+/// Runs `text` as a command if it is one. Returns the reply to send, or `None`
+/// when the message should go through the normal response flow.
+pub async fn handle<E: EmbeddingModel>(
+    knowledge: &KnowledgeBase<E>,
+    admins: &HashSet<String>,
+    channel_id: &str,
+    author: &str,
+    text: &str,
+) -> Option<String> {
+    let command = match Command::parse(text)? {
+        Ok(command) => command,
+        Err(usage) => return Some(usage),
+    };
+
+    if !admins.contains(author) {
+        info!(author, ?command, "Ignoring command from non-admin");
+        return Some("Only admins can use this command.".to_string());
+    }
+
+    Some(command.execute(knowledge, channel_id, author).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(
+            Command::parse("/pin-context testnet is sepolia"),
+            Some(Ok(Command::PinContext {
+                global: false,
+                content: "testnet is sepolia".to_string()
+            }))
+        );
+        assert_eq!(
+            Command::parse("/pin_context@asuka_bot --global goerli is obsolete"),
+            Some(Ok(Command::PinContext {
+                global: true,
+                content: "goerli is obsolete".to_string()
+            }))
+        );
+        assert_eq!(
+            Command::parse("/unpin_context 4"),
+            Some(Ok(Command::UnpinContext { id: 4 }))
+        );
+        assert_eq!(
+            Command::parse("/list-pinned"),
+            Some(Ok(Command::ListPinned))
+        );
+        assert!(matches!(
+            Command::parse("/unpin-context four"),
+            Some(Err(_))
+        ));
+        assert_eq!(
+            Command::parse("/knowledge_gaps"),
+            Some(Ok(Command::KnowledgeGaps { days: 7 }))
+        );
+        assert_eq!(
+            Command::parse("/knowledge-gaps 30"),
+            Some(Ok(Command::KnowledgeGaps { days: 30 }))
+        );
+        assert!(matches!(Command::parse("/knowledge-gaps -1"), Some(Err(_))));
+        assert_eq!(
+            Command::parse("/set-disclaimer Not financial advice."),
+            Some(Ok(Command::SetDisclaimer {
+                text: Some("Not financial advice.".to_string())
+            }))
+        );
+        assert!(matches!(Command::parse("/set_disclaimer"), Some(Err(_))));
+        assert_eq!(
+            Command::parse("/clear_disclaimer"),
+            Some(Ok(Command::SetDisclaimer { text: None }))
+        );
+        assert_eq!(
+            Command::parse("/strict_mode on"),
+            Some(Ok(Command::SetStrictMode {
+                enabled: Some(true)
+            }))
+        );
+        assert_eq!(
+            Command::parse("/strict-mode default"),
+            Some(Ok(Command::SetStrictMode { enabled: None }))
+        );
+        assert!(matches!(Command::parse("/strict-mode"), Some(Err(_))));
+        assert_eq!(
+            Command::parse("/listen off"),
+            Some(Ok(Command::Listen { enabled: false }))
+        );
+        assert!(matches!(Command::parse("/listen"), Some(Err(_))));
+        assert_eq!(
+            Command::parse("/timezone --guild cet"),
+            Some(Ok(Command::SetTimezone {
+                guild: true,
+                timezone: Some("CET".to_string())
+            }))
+        );
+        assert_eq!(
+            Command::parse("/timezone default"),
+            Some(Ok(Command::SetTimezone {
+                guild: false,
+                timezone: None
+            }))
+        );
+        assert!(matches!(Command::parse("/timezone"), Some(Err(_))));
+        assert!(matches!(
+            Command::parse("/timezone Mars/Olympus"),
+            Some(Err(_))
+        ));
+        assert_eq!(
+            Command::parse("/date_format dmy"),
+            Some(Ok(Command::SetDateFormat {
+                guild: false,
+                format: Some(DateFormat::Dmy)
+            }))
+        );
+        assert!(matches!(Command::parse("/date-format long"), Some(Err(_))));
+        assert_eq!(
+            Command::parse("/retain_forever on"),
+            Some(Ok(Command::RetainForever { enabled: true }))
+        );
+        assert!(matches!(
+            Command::parse("/retain-forever yes"),
+            Some(Err(_))
+        ));
+        assert_eq!(
+            Command::parse("/toolcalls"),
+            Some(Ok(Command::ToolCalls { message_id: None }))
+        );
+        assert_eq!(
+            Command::parse("/toolcalls last"),
+            Some(Ok(Command::ToolCalls { message_id: None }))
+        );
+        assert_eq!(
+            Command::parse("/toolcalls https://discord.com/channels/1/2/345"),
+            Some(Ok(Command::ToolCalls {
+                message_id: Some("345".to_string())
+            }))
+        );
+        assert_eq!(
+            Command::parse("/toolcalls https://t.me/c/12/678?thread=1"),
+            Some(Ok(Command::ToolCalls {
+                message_id: Some("678".to_string())
+            }))
+        );
+        assert!(matches!(
+            Command::parse("/toolcalls two words"),
+            Some(Err(_))
+        ));
+        assert_eq!(
+            Command::parse("/escalations"),
+            Some(Ok(Command::ListEscalations))
+        );
+        assert_eq!(
+            Command::parse("/resolve_escalation #12"),
+            Some(Ok(Command::ResolveEscalation { id: 12 }))
+        );
+        assert!(matches!(
+            Command::parse("/resolve-escalation"),
+            Some(Err(_))
+        ));
+        assert_eq!(
+            Command::parse("/reload_config"),
+            Some(Ok(Command::ReloadConfig))
+        );
+        assert_eq!(
+            Command::parse("/summarize"),
+            Some(Ok(Command::Summarize { hours: 24 }))
+        );
+        assert_eq!(
+            Command::parse("/summarize 6"),
+            Some(Ok(Command::Summarize { hours: 6 }))
+        );
+        assert!(matches!(Command::parse("/summarize 0"), Some(Err(_))));
+        assert_eq!(
+            Command::parse("/ask_deep how are paymaster fees settled?"),
+            Some(Ok(Command::AskDeep {
+                question: "how are paymaster fees settled?".to_string()
+            }))
+        );
+        assert!(matches!(Command::parse("/ask-deep"), Some(Err(_))));
+        assert_eq!(
+            Command::parse("/link"),
+            Some(Ok(Command::Link { code: None }))
+        );
+        assert_eq!(
+            Command::parse("/link 9F2C41AB"),
+            Some(Ok(Command::Link {
+                code: Some("9F2C41AB".to_string())
+            }))
+        );
+        assert!(matches!(Command::parse("/link a b"), Some(Err(_))));
+        assert_eq!(Command::parse("/unlink"), Some(Ok(Command::Unlink)));
+        assert_eq!(
+            Command::parse("/maintenance"),
+            Some(Ok(Command::Maintenance {
+                dry_run: false,
+                reembed: false
+            }))
+        );
+        assert_eq!(
+            Command::parse("/maintenance --reembed --dry-run"),
+            Some(Ok(Command::Maintenance {
+                dry_run: true,
+                reembed: true
+            }))
+        );
+        assert!(matches!(
+            Command::parse("/maintenance --full"),
+            Some(Err(_))
+        ));
+        assert_eq!(
+            Command::parse("/say <#42> Mainnet launches friday.\nSee you there!"),
+            Some(Ok(Command::Say {
+                channel_id: "42".to_string(),
+                text: "Mainnet launches friday.\nSee you there!".to_string(),
+                restyle: false
+            }))
+        );
+        assert_eq!(
+            Command::parse("/say --restyle -100123 gm"),
+            Some(Ok(Command::Say {
+                channel_id: "-100123".to_string(),
+                text: "gm".to_string(),
+                restyle: true
+            }))
+        );
+        assert!(matches!(Command::parse("/say 42"), Some(Err(_))));
+        assert_eq!(
+            Command::parse("/erase-user <@!1234>"),
+            Some(Ok(Command::EraseUser {
+                user_id: "1234".to_string(),
+                confirm: false
+            }))
+        );
+        assert_eq!(
+            Command::parse("/erase_user 1234 --confirm"),
+            Some(Ok(Command::EraseUser {
+                user_id: "1234".to_string(),
+                confirm: true
+            }))
+        );
+        assert!(matches!(Command::parse("/erase-user"), Some(Err(_))));
+        assert!(matches!(
+            Command::parse("/erase-user --confirm"),
+            Some(Err(_))
+        ));
+        assert_eq!(Command::parse("/start"), None);
+        assert_eq!(Command::parse("what is the testnet?"), None);
+    }
+}
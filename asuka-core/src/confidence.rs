@@ -0,0 +1,446 @@
+//! Confidence of replies, estimated from how well the docs cover the question
+//! and optionally the model's own rating of its answer.
+//!
+//! Replies scoring below the threshold start with the
+//! [LOW_CONFIDENCE_HEDGE](templates::LOW_CONFIDENCE_HEDGE) template, or are
+//! replaced by [LOW_CONFIDENCE_DECLINE](templates::LOW_CONFIDENCE_DECLINE) in
+//! strict channels. Strict mode defaults to [ConfidenceConfig::strict] and is
+//! overridden per channel by the [STRICT_CONFIDENCE_SETTING] channel setting.
+//!
+//! ```toml
+//! [confidence]
+//! threshold = 0.5
+//! self_assessment = true
+//! suggestion = "Try asking in #support."
+//! ```
+
+use rig::{completion::CompletionModel, embeddings::EmbeddingModel};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tracing::{error, info};
+
+use crate::{
+    agent::Agent,
+    events::Event,
+    knowledge::{KnowledgeBase, RetrievalSupport},
+    logging::AUDIT_TARGET,
+    templates,
+};
+
+/// Channel setting turning strict mode on (`on`) or off (`off`) in a channel.
+pub const STRICT_CONFIDENCE_SETTING: &str = "strict_confidence";
+
+/// Documents retrieved when measuring support, at least
+/// [ConfidenceConfig::min_supporting].
+const SUPPORT_CANDIDATES: usize = 5;
+
+/// `[confidence]` settings of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfidenceConfig {
+    /// Retrieval distance up to which a document supports the question. The
+    /// store ranks by L2 distance, in `0.0..=2.0` for normalized embeddings.
+    pub max_distance: f64,
+    /// Supporting documents needed for full retrieval support.
+    pub min_supporting: usize,
+    /// Score below which replies are hedged or declined.
+    pub threshold: f64,
+    /// Asks the model to rate its answer, at the cost of an extra call.
+    pub self_assessment: bool,
+    /// Share of the model's rating in the score.
+    pub self_assessment_weight: f64,
+    /// Declines instead of hedging in channels without their own setting.
+    pub strict: bool,
+    /// Where else to ask, included when declining.
+    pub suggestion: String,
+}
+
+impl Default for ConfidenceConfig {
+    fn default() -> Self {
+        Self {
+            max_distance: 1.0,
+            min_supporting: 1,
+            threshold: 0.5,
+            self_assessment: false,
+            self_assessment_weight: 0.3,
+            strict: false,
+            suggestion: "The team in the support channel should be able to help.".to_string(),
+        }
+    }
+}
+
+impl ConfidenceConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.threshold) {
+            return Err("confidence.threshold must be between 0 and 1".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.self_assessment_weight) {
+            return Err("confidence.self_assessment_weight must be between 0 and 1".to_string());
+        }
+        if self.max_distance <= 0.0 || self.min_supporting == 0 {
+            return Err(
+                "confidence.max_distance and confidence.min_supporting must be positive"
+                    .to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Retrieval support as a score in `0.0..=1.0`: how close the best
+    /// document is, scaled down while fewer than
+    /// [ConfidenceConfig::min_supporting] documents are within reach.
+    pub fn retrieval_score(&self, support: &RetrievalSupport) -> f64 {
+        let Some(best_distance) = support.best_distance else {
+            return 0.0;
+        };
+        let closeness = (1.0 - best_distance / 2.0).clamp(0.0, 1.0);
+        let coverage = (support.supporting as f64 / self.min_supporting as f64).min(1.0);
+        closeness * coverage
+    }
+
+    /// Combines retrieval support with the model's own rating, if any.
+    pub fn score(&self, support: &RetrievalSupport, self_assessed: Option<f64>) -> f64 {
+        let retrieval = self.retrieval_score(support);
+        match self_assessed {
+            Some(rating) => {
+                let weight = self.self_assessment_weight;
+                (1.0 - weight) * retrieval + weight * rating.clamp(0.0, 1.0)
+            }
+            None => retrieval,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfidenceOutcome {
+    /// Sent as generated.
+    Confident,
+    /// Sent after a hedging sentence.
+    Hedged,
+    /// Not answered, with a suggestion where to ask instead.
+    Declined,
+}
+
+impl ConfidenceOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Confident => "confident",
+            Self::Hedged => "hedged",
+            Self::Declined => "declined",
+        }
+    }
+
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "confident" => Some(Self::Confident),
+            "hedged" => Some(Self::Hedged),
+            "declined" => Some(Self::Declined),
+            _ => None,
+        }
+    }
+}
+
+/// Confidence estimated for one reply, stored with its interaction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Confidence {
+    pub score: f64,
+    pub support: RetrievalSupport,
+    /// The model's own rating, when [ConfidenceConfig::self_assessment] is on.
+    pub self_assessed: Option<f64>,
+    pub outcome: ConfidenceOutcome,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct SelfAssessment {
+    /// From 0, a guess, to 1, fully backed by the documents.
+    confidence: f64,
+}
+
+/// Whether `channel_id` is in strict mode.
+pub async fn is_strict<E: EmbeddingModel>(
+    knowledge: &KnowledgeBase<E>,
+    config: &ConfidenceConfig,
+    channel_id: &str,
+) -> bool {
+    match knowledge
+        .channel_setting(channel_id, STRICT_CONFIDENCE_SETTING)
+        .await
+    {
+        Ok(Some(value)) => value == "on",
+        Ok(None) => config.strict,
+        Err(err) => {
+            error!(?err, "Failed to load strict confidence setting");
+            config.strict
+        }
+    }
+}
+
+impl<M: CompletionModel, E: EmbeddingModel> Agent<M, E> {
+    /// Estimates confidence from retrieval alone, before a reply is generated.
+    /// Returns `None` when confidence estimation is off or retrieval fails.
+    ///
+    /// The outcome is final when it is [ConfidenceOutcome::Declined] even for
+    /// a perfect self-assessment, so clients can skip generating the reply.
+    pub async fn estimate_confidence(
+        &self,
+        question: &str,
+        channel_id: &str,
+    ) -> Option<Confidence> {
+        let config = self.confidence_config()?;
+        let support = match self
+            .knowledge()
+            .retrieval_support(
+                question,
+                SUPPORT_CANDIDATES.max(config.min_supporting),
+                config.max_distance,
+            )
+            .await
+        {
+            Ok(support) => support,
+            Err(err) => {
+                error!(?err, "Failed to measure retrieval support");
+                return None;
+            }
+        };
+
+        let best_case = config.self_assessment.then_some(1.0);
+        let score = config.score(&support, best_case);
+        let outcome = self.outcome(config, score, channel_id).await;
+        Some(Confidence {
+            score: config.score(&support, None),
+            support,
+            self_assessed: None,
+            outcome,
+        })
+    }
+
+    /// Completes the estimate with the model's rating of `response`, if
+    /// enabled, and returns the text to send: the response, hedged, or a
+    /// decline. The estimate is stored with the interaction and logged to
+    /// the audit trail.
+    pub async fn apply_confidence(
+        &self,
+        mut confidence: Confidence,
+        question: &str,
+        response: String,
+        channel_id: &str,
+        interaction_id: Option<i64>,
+    ) -> String {
+        let Some(config) = self.confidence_config() else {
+            return response;
+        };
+
+        if config.self_assessment && confidence.outcome != ConfidenceOutcome::Declined {
+            let input = format!(
+                "Question: {question}\n\nYour answer: {response}\n\nRate how confident you are that the answer is correct and backed by the documents provided."
+            );
+            match self.prompt_structured::<SelfAssessment>(&input, &[]).await {
+                Ok(assessment) => {
+                    confidence.self_assessed = Some(assessment.confidence.clamp(0.0, 1.0));
+                }
+                Err(err) => error!(?err, "Failed to get confidence self-assessment"),
+            }
+            confidence.score = config.score(&confidence.support, confidence.self_assessed);
+            confidence.outcome = self.outcome(config, confidence.score, channel_id).await;
+        }
+
+        info!(
+            target: AUDIT_TARGET,
+            channel_id,
+            interaction_id,
+            score = confidence.score,
+            best_distance = confidence.support.best_distance,
+            supporting = confidence.support.supporting,
+            self_assessed = confidence.self_assessed,
+            outcome = confidence.outcome.as_str(),
+            "Reply confidence"
+        );
+        if let Some(id) = interaction_id {
+            if let Err(err) = self.knowledge().record_confidence(id, &confidence).await {
+                error!(?err, "Failed to record reply confidence");
+            }
+        }
+        if confidence.outcome != ConfidenceOutcome::Confident {
+            self.knowledge()
+                .events()
+                .publish(Event::LowConfidenceAnswer {
+                    channel_id: channel_id.to_string(),
+                    interaction_id,
+                    score: confidence.score,
+                    outcome: confidence.outcome.as_str().to_string(),
+                });
+        }
+
+        match confidence.outcome {
+            ConfidenceOutcome::Confident => response,
+            ConfidenceOutcome::Hedged => format!(
+                "{}\n\n{response}",
+                self.localized_template(templates::LOW_CONFIDENCE_HEDGE, None, channel_id, &[])
+                    .await
+            ),
+            ConfidenceOutcome::Declined => {
+                self.localized_template(
+                    templates::LOW_CONFIDENCE_DECLINE,
+                    None,
+                    channel_id,
+                    &[("suggestion", &config.suggestion)],
+                )
+                .await
+            }
+        }
+    }
+
+    async fn outcome(
+        &self,
+        config: &ConfidenceConfig,
+        score: f64,
+        channel_id: &str,
+    ) -> ConfidenceOutcome {
+        if score >= config.threshold {
+            ConfidenceOutcome::Confident
+        } else if is_strict(self.knowledge(), config, channel_id).await {
+            ConfidenceOutcome::Declined
+        } else {
+            ConfidenceOutcome::Hedged
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        knowledge::Document,
+        test_utils::{self, FakeEmbeddingModel, ScriptedCompletionModel},
+    };
+
+    async fn agent(config: ConfidenceConfig) -> Agent<ScriptedCompletionModel, FakeEmbeddingModel> {
+        let mut knowledge = test_utils::knowledge_base().await;
+        knowledge
+            .add_documents(vec![Document {
+                id: "vrf.md".to_string(),
+                source_id: "github".to_string(),
+                content: "vrf requests are free".to_string(),
+                created_at: chrono::Utc::now(),
+                title: String::new(),
+                section: String::new(),
+                topics: vec![],
+                logical_id: None,
+                cleaned: None,
+                chunk: None,
+            }])
+            .await
+            .unwrap();
+        let character = test_utils::character();
+        Agent::new(character, ScriptedCompletionModel::default(), knowledge).with_confidence(config)
+    }
+
+    /// Estimates and applies confidence for a reply, as the clients do.
+    async fn answer(
+        agent: &Agent<ScriptedCompletionModel, FakeEmbeddingModel>,
+        question: &str,
+        channel_id: &str,
+    ) -> (String, Option<Confidence>) {
+        let interaction_id = agent
+            .knowledge()
+            .create_interaction(channel_id.to_string(), "alice".to_string(), vec![])
+            .await
+            .unwrap();
+        let confidence = agent
+            .estimate_confidence(question, channel_id)
+            .await
+            .unwrap();
+        let text = agent
+            .apply_confidence(
+                confidence,
+                question,
+                "Generated answer.".to_string(),
+                channel_id,
+                Some(interaction_id),
+            )
+            .await;
+        let stored = agent
+            .knowledge()
+            .interaction_confidence(interaction_id)
+            .await
+            .unwrap();
+        (text, stored)
+    }
+
+    #[tokio::test]
+    async fn test_confident_hedged_and_declined() {
+        let agent = agent(ConfidenceConfig {
+            suggestion: "Ask in #support.".to_string(),
+            ..Default::default()
+        })
+        .await;
+
+        let (text, confidence) = answer(&agent, "vrf requests are free", "c1").await;
+        assert_eq!(text, "Generated answer.");
+        let confidence = confidence.unwrap();
+        assert_eq!(confidence.outcome, ConfidenceOutcome::Confident);
+        assert_eq!(confidence.support.supporting, 1);
+        assert!(confidence.score > 0.99);
+
+        let (text, confidence) = answer(&agent, "paymaster sponsorship limits", "c1").await;
+        assert_eq!(
+            text,
+            "I couldn't find this in the docs, but here's my best understanding:\n\nGenerated answer."
+        );
+        let confidence = confidence.unwrap();
+        assert_eq!(confidence.outcome, ConfidenceOutcome::Hedged);
+        assert_eq!(confidence.support.supporting, 0);
+        assert_eq!(confidence.score, 0.0);
+
+        agent
+            .knowledge()
+            .set_channel_setting("c2", STRICT_CONFIDENCE_SETTING, Some("on"))
+            .await
+            .unwrap();
+        let (text, confidence) = answer(&agent, "paymaster sponsorship limits", "c2").await;
+        assert_eq!(
+            text,
+            "I couldn't find this in the docs and would rather not guess. Ask in #support."
+        );
+        assert_eq!(confidence.unwrap().outcome, ConfidenceOutcome::Declined);
+    }
+
+    #[tokio::test]
+    async fn test_channel_overrides_strict_default() {
+        let agent = agent(ConfidenceConfig {
+            strict: true,
+            ..Default::default()
+        })
+        .await;
+        agent
+            .knowledge()
+            .set_channel_setting("lenient", STRICT_CONFIDENCE_SETTING, Some("off"))
+            .await
+            .unwrap();
+
+        let (_, strict) = answer(&agent, "paymaster sponsorship limits", "c1").await;
+        assert_eq!(strict.unwrap().outcome, ConfidenceOutcome::Declined);
+        let (_, lenient) = answer(&agent, "paymaster sponsorship limits", "lenient").await;
+        assert_eq!(lenient.unwrap().outcome, ConfidenceOutcome::Hedged);
+    }
+
+    #[test]
+    fn test_score() {
+        let config = ConfidenceConfig {
+            min_supporting: 2,
+            self_assessment_weight: 0.5,
+            ..Default::default()
+        };
+        let support = |best_distance, supporting| RetrievalSupport {
+            best_distance,
+            supporting,
+        };
+
+        assert_eq!(config.score(&support(None, 0), None), 0.0);
+        assert_eq!(config.score(&support(Some(0.0), 2), None), 1.0);
+        assert_eq!(config.score(&support(Some(0.0), 1), None), 0.5);
+        assert_eq!(config.score(&support(Some(1.0), 3), None), 0.5);
+        assert_eq!(config.score(&support(Some(0.0), 2), Some(0.2)), 0.6);
+        assert_eq!(config.score(&support(Some(1.5), 0), Some(1.0)), 0.5);
+    }
+}
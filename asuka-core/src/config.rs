@@ -0,0 +1,681 @@
+//! Credentials for clients and model providers, merged from a config file,
+//! the environment and command line flags.
+//!
+//! Precedence, highest first: command line flags, environment variables,
+//! the `--config` TOML file. Every credential is optional until
+//! [Credentials::require] checks the ones the enabled components need.
+//! Without a file, everything can come from `ASUKA_*` variables instead, see
+//! [crate::deployment].
+//!
+//! The same file holds settings that can be reloaded while running:
+//!
+//! ```toml
+//! [credentials]
+//! discord_api_token = "..."
+//!
+//! [attention]
+//! cooldown_messages = 5
+//!
+//! [discord]
+//! allowed_channels = ["1234567890"]
+//! auto_answer_forums = ["1234567891"]
+//!
+//! [discord.guilds]
+//! listen_pattern = "^(ask|help)-"
+//! introduction = "Hi! Ask me anything about Cartridge in #ask-shinobi."
+//!
+//! [discord.mention_roles]
+//! "1234567892" = ["1234567894"]
+//!
+//! [tools]
+//! timeout_secs = 10
+//!
+//! [confidence]
+//! threshold = 0.5
+//!
+//! [escalation]
+//! targets = [{ guild_id = "1234567892", role_id = "1234567893" }]
+//!
+//! [memory]
+//! session_idle_minutes = 30
+//!
+//! [history]
+//! gap_minutes = 60
+//!
+//! [digest]
+//! hour = 8
+//! discord_owner = "1234567895"
+//!
+//! [reporting]
+//! discord_channel = "1234567896"
+//!
+//! [retention.channel_types]
+//! direct_message = 30
+//! text = 180
+//!
+//! [rate_limit]
+//! per_hour = 10
+//!
+//! [injection]
+//! detect = "drop"
+//!
+//! [localization]
+//! default_language = "en"
+//! translate = true
+//!
+//! [locale]
+//! timezone = "Europe/Berlin"
+//!
+//! [markdown]
+//! table_width = 40
+//!
+//! [attachments]
+//! min_lines = 40
+//!
+//! [linking]
+//! code_ttl_minutes = 10
+//!
+//! [truncation]
+//! prompt_tokens = 6000
+//!
+//! [capabilities]
+//! max_tokens = 300
+//!
+//! [research]
+//! max_iterations = 3
+//! budget_secs = 120
+//!
+//! [diversity]
+//! max_per_document = 2
+//!
+//! [chunking]
+//! max_chars = 2000
+//!
+//! [embedding_policy]
+//! min_chars = 20
+//!
+//! [presence]
+//! activity = "Listening | !ask me about VRF"
+//!
+//! [outage]
+//! delay_notice_secs = 20
+//!
+//! [batching]
+//! window_ms = 4000
+//!
+//! [query_rewrite]
+//! timeout_ms = 1500
+//!
+//! [startup]
+//! discord_channel = "1234567896"
+//!
+//! [[experiments]]
+//! name = "cheaper-model"
+//! guilds = ["1234567892"]
+//! variants = [{ name = "control", weight = 1 }, { name = "mini", weight = 1, model = "gpt-4o-mini" }]
+//!
+//! [[webhooks]]
+//! url = "https://ops.example.com/hooks/asuka"
+//! secret = "..."
+//! events = ["escalation_created"]
+//!
+//! [[knowledge.repos]]
+//! name = "docs"
+//! url = "https://github.com/cartridge-gg/docs"
+//!
+//! [openai]
+//! base_url = "https://my-org.openai.azure.com"
+//! proxy = "http://proxy.internal:3128"
+//! azure = { api_version = "2024-06-01", deployments = { "gpt-4o" = "chat-prod" } }
+//! unsupported_params = { "o1-mini" = ["temperature", "top_p"] }
+//! ```
+//!
+//! `[discord]` needs the `discord` feature, `[knowledge]` the `git-loader`
+//! feature and `[farcaster]` the `farcaster` feature.
+//!
+//! `[openai]`, `[tools]`, `[confidence]`, `[escalation]`, `[memory]`, `[history]`, `[digest]`, `[reporting]`, `[retention]`, `[rate_limit]`, `[injection]`, `[localization]`, `[locale]`, `[markdown]`, `[attachments]`, `[linking]`, `[truncation]`, `[capabilities]`, `[research]`, `[diversity]`, `[chunking]`, `[embedding_policy]`, `[presence]`, `[outage]`, `[batching]`, `[query_rewrite]`, `[startup]`, `[[experiments]]`, `[[webhooks]]`, `[knowledge]` and `[farcaster]`
+//! are read at startup only.
+
+use std::{fmt, path::Path};
+
+use serde::Deserialize;
+use thiserror::Error;
+
+#[cfg(feature = "discord")]
+use crate::clients::discord::DiscordClientConfig;
+#[cfg(feature = "git-loader")]
+use crate::sources::KnowledgeSourceConfig;
+use crate::{
+    attachments::AttachmentConfig, attention::AttentionConfig, capabilities::CapabilitiesConfig,
+    clients::presence::PresenceConfig, confidence::ConfidenceConfig, digest::DigestConfig,
+    escalation::EscalationConfig, experiments::ExperimentConfig, history::HistoryConfig,
+    injection::InjectionConfig, knowledge::ChunkingConfig, knowledge::DiversityConfig,
+    knowledge::EmbeddingPolicy, language::LocalizationConfig, linking::LinkingConfig,
+    locale::LocaleConfig, markdown::MarkdownConfig, memory::MemoryConfig, outage::OutageConfig,
+    pipeline::BatchConfig, providers::ProviderConfig, rate_limit::RateLimitConfig,
+    reporting::ReportingConfig, research::ResearchConfig, retention::RetentionConfig,
+    rewrite::RewriteConfig, startup::StartupConfig, tools::ToolConfig,
+    truncation::TruncationConfig, webhooks::WebhookConfig,
+};
+
+/// A client or model provider that needs credentials.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Component {
+    Discord,
+    Telegram,
+    Twitter,
+    Farcaster,
+    OpenAi,
+    Xai,
+}
+
+impl Component {
+    pub const ALL: &'static [Component] = &[
+        Component::Discord,
+        Component::Telegram,
+        Component::Twitter,
+        Component::Farcaster,
+        Component::OpenAi,
+        Component::Xai,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Component::Discord => "discord",
+            Component::Telegram => "telegram",
+            Component::Twitter => "twitter",
+            Component::Farcaster => "farcaster",
+            Component::OpenAi => "openai",
+            Component::Xai => "xai",
+        }
+    }
+
+    /// Keys of the credentials this component needs.
+    pub fn credentials(&self) -> &'static [&'static str] {
+        match self {
+            Component::Discord => &["discord_api_token"],
+            Component::Telegram => &["telegram_bot_token"],
+            Component::Twitter => &["twitter_bearer_token"],
+            Component::Farcaster => &["neynar_api_key", "farcaster_signer_uuid"],
+            Component::OpenAi => &["openai_api_key"],
+            Component::Xai => &["xai_api_key"],
+        }
+    }
+}
+
+/// Environment variable of a credential key.
+pub fn env_var(key: &str) -> String {
+    key.to_uppercase()
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed to read config file {path}: {error}")]
+    Read { path: String, error: std::io::Error },
+    #[error("Invalid config file {path}: {error}")]
+    Parse {
+        path: String,
+        error: Box<toml::de::Error>,
+    },
+    #[error("Invalid config: {0}")]
+    Invalid(String),
+    #[error(transparent)]
+    Missing(#[from] MissingCredentials),
+}
+
+/// Contents of a `--config` file.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub credentials: Credentials,
+    pub attention: AttentionConfig,
+    #[cfg(feature = "discord")]
+    pub discord: DiscordClientConfig,
+    pub openai: ProviderConfig,
+    pub tools: ToolConfig,
+    /// Confidence estimation, off without the section.
+    pub confidence: Option<ConfidenceConfig>,
+    /// Handing conversations to the support team, off without the section.
+    pub escalation: Option<EscalationConfig>,
+    /// Session summaries and facts of channels, off without the section.
+    pub memory: Option<MemoryConfig>,
+    /// How channel history is written into prompts.
+    pub history: HistoryConfig,
+    /// Daily activity digest for the operator, off without the section.
+    pub digest: Option<DigestConfig>,
+    /// Error reports to the operator, off without the section.
+    pub reporting: Option<ReportingConfig>,
+    /// Purging of old messages, which are kept forever without the section.
+    pub retention: Option<RetentionConfig>,
+    /// Limit on answers per user, unlimited without the section.
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Prompt injection defenses, off without the section.
+    pub injection: Option<InjectionConfig>,
+    /// Templates in the language of the user, off without the section.
+    pub localization: Option<LocalizationConfig>,
+    /// Time zone and date format of times, see [crate::locale].
+    pub locale: LocaleConfig,
+    /// Markdown of replies per platform, see [crate::markdown].
+    pub markdown: MarkdownConfig,
+    /// Long code blocks sent as files, see [crate::attachments]. Off without
+    /// the section.
+    pub attachments: Option<AttachmentConfig>,
+    /// Account linking across platforms, see [crate::linking]. Off without
+    /// the section.
+    pub linking: Option<LinkingConfig>,
+    /// Retrieved documents cut to fit the prompt, see [crate::truncation].
+    /// Sent whole without the section.
+    pub truncation: Option<TruncationConfig>,
+    /// Summary of what the agent can do for questions about it, see
+    /// [crate::capabilities]. Off without the section.
+    pub capabilities: Option<CapabilitiesConfig>,
+    /// Deep research answers to `/ask-deep`, see [crate::research]. Off
+    /// without the section.
+    pub research: Option<ResearchConfig>,
+    /// Diversity of retrieved documents, plain top-k without the section.
+    pub diversity: Option<DiversityConfig>,
+    /// Splitting large documents before they are embedded, see
+    /// [crate::knowledge::ChunkingConfig]. Whole documents without the section.
+    pub chunking: Option<ChunkingConfig>,
+    /// Which messages are embedded, see [crate::knowledge::EmbeddingPolicy].
+    /// All without the section.
+    pub embedding_policy: Option<EmbeddingPolicy>,
+    /// Discord presence following the bot's status, unset without the
+    /// section.
+    pub presence: Option<PresenceConfig>,
+    /// Notices about slow and deferred answers while the completion provider
+    /// lacks capacity, see [crate::outage]. Off without the section.
+    pub outage: Option<OutageConfig>,
+    /// Answering rapid-fire messages of a user together, see
+    /// [crate::pipeline::Debouncer]. Each message on its own without the
+    /// section.
+    pub batching: Option<BatchConfig>,
+    /// Rewriting messages into search queries, see [crate::rewrite]. Off
+    /// without the section.
+    pub query_rewrite: Option<RewriteConfig>,
+    /// Progress of the initial ingestion, see [crate::startup].
+    pub startup: StartupConfig,
+    /// A/B experiments on replies, see [crate::experiments].
+    pub experiments: Vec<ExperimentConfig>,
+    /// Destinations notified of notable events, see [crate::webhooks].
+    pub webhooks: Vec<WebhookConfig>,
+    /// Repositories ingested into the knowledge base, the `--github-repo`
+    /// flag's without the section.
+    #[cfg(feature = "git-loader")]
+    pub knowledge: Option<KnowledgeSourceConfig>,
+    #[cfg(feature = "farcaster")]
+    pub farcaster: crate::clients::farcaster::FarcasterConfig,
+}
+
+impl ConfigFile {
+    /// Reads and validates the file at `path`.
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let content = std::fs::read_to_string(path).map_err(|error| ConfigError::Read {
+            path: path.display().to_string(),
+            error,
+        })?;
+        let file: Self = toml::from_str(&content).map_err(|error| ConfigError::Parse {
+            path: path.display().to_string(),
+            error: Box::new(error),
+        })?;
+        file.validate()?;
+        Ok(file)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.attention
+            .validate()
+            .and_then(|()| self.openai.validate())
+            .and_then(|()| self.tools.validate())
+            .and_then(|()| self.confidence.as_ref().map_or(Ok(()), |c| c.validate()))
+            .and_then(|()| self.escalation.as_ref().map_or(Ok(()), |e| e.validate()))
+            .and_then(|()| self.memory.as_ref().map_or(Ok(()), |m| m.validate()))
+            .and_then(|()| self.history.validate())
+            .and_then(|()| self.digest.as_ref().map_or(Ok(()), |d| d.validate()))
+            .and_then(|()| self.reporting.as_ref().map_or(Ok(()), |r| r.validate()))
+            .and_then(|()| self.retention.as_ref().map_or(Ok(()), |r| r.validate()))
+            .and_then(|()| self.rate_limit.as_ref().map_or(Ok(()), |r| r.validate()))
+            .and_then(|()| self.injection.as_ref().map_or(Ok(()), |i| i.validate()))
+            .and_then(|()| self.localization.as_ref().map_or(Ok(()), |l| l.validate()))
+            .and_then(|()| self.locale.validate())
+            .and_then(|()| self.markdown.validate())
+            .and_then(|()| self.attachments.as_ref().map_or(Ok(()), |a| a.validate()))
+            .and_then(|()| self.linking.as_ref().map_or(Ok(()), |l| l.validate()))
+            .and_then(|()| self.truncation.as_ref().map_or(Ok(()), |t| t.validate()))
+            .and_then(|()| self.capabilities.as_ref().map_or(Ok(()), |c| c.validate()))
+            .and_then(|()| self.research.as_ref().map_or(Ok(()), |r| r.validate()))
+            .and_then(|()| self.diversity.as_ref().map_or(Ok(()), |d| d.validate()))
+            .and_then(|()| self.chunking.as_ref().map_or(Ok(()), |c| c.validate()))
+            .and_then(|()| {
+                self.embedding_policy
+                    .as_ref()
+                    .map_or(Ok(()), |e| e.validate())
+            })
+            .and_then(|()| self.presence.as_ref().map_or(Ok(()), |p| p.validate()))
+            .and_then(|()| self.outage.as_ref().map_or(Ok(()), |o| o.validate()))
+            .and_then(|()| self.batching.as_ref().map_or(Ok(()), |b| b.validate()))
+            .and_then(|()| self.query_rewrite.as_ref().map_or(Ok(()), |r| r.validate()))
+            .and_then(|()| self.startup.validate())
+            .and_then(|()| crate::experiments::validate(&self.experiments))
+            .and_then(|()| self.webhooks.iter().try_for_each(|w| w.validate()))
+            .map_err(ConfigError::Invalid)?;
+        #[cfg(feature = "discord")]
+        self.discord.validate().map_err(ConfigError::Invalid)?;
+        #[cfg(feature = "git-loader")]
+        if let Some(knowledge) = &self.knowledge {
+            knowledge.validate().map_err(ConfigError::Invalid)?;
+        }
+        #[cfg(feature = "farcaster")]
+        self.farcaster.validate().map_err(ConfigError::Invalid)?;
+        Ok(())
+    }
+}
+
+/// Every credential missing for the enabled components.
+#[derive(Error, Debug, PartialEq)]
+pub struct MissingCredentials {
+    /// Component name and the environment variables it is missing.
+    pub missing: Vec<(&'static str, Vec<String>)>,
+}
+
+impl fmt::Display for MissingCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Missing credentials:")?;
+        for (component, vars) in &self.missing {
+            write!(f, "\n  {component}: {}", vars.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Credentials {
+    pub discord_api_token: Option<String>,
+    pub telegram_bot_token: Option<String>,
+    pub twitter_bearer_token: Option<String>,
+    pub neynar_api_key: Option<String>,
+    /// Neynar managed signer that casts are published with.
+    pub farcaster_signer_uuid: Option<String>,
+    pub openai_api_key: Option<String>,
+    pub xai_api_key: Option<String>,
+}
+
+impl Credentials {
+    /// Merges the config file at `path`, if any, the environment and
+    /// `flags`, in increasing precedence.
+    pub fn load(path: Option<&Path>, flags: Credentials) -> Result<Self, ConfigError> {
+        let file = match path {
+            Some(path) => ConfigFile::from_file(path)?.credentials,
+            None => Self::default(),
+        };
+        Ok(file
+            .merge(Self::from_lookup(|key| std::env::var(key).ok()))
+            .merge(flags))
+    }
+
+    pub(crate) fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Self {
+        let var = |key: &str| lookup(&env_var(key)).filter(|value| !value.is_empty());
+        Self {
+            discord_api_token: var("discord_api_token"),
+            telegram_bot_token: var("telegram_bot_token"),
+            twitter_bearer_token: var("twitter_bearer_token"),
+            neynar_api_key: var("neynar_api_key"),
+            farcaster_signer_uuid: var("farcaster_signer_uuid"),
+            openai_api_key: var("openai_api_key"),
+            xai_api_key: var("xai_api_key"),
+        }
+    }
+
+    /// Fills the values unset in `over` from `self`.
+    pub fn merge(self, over: Credentials) -> Self {
+        Self {
+            discord_api_token: over.discord_api_token.or(self.discord_api_token),
+            telegram_bot_token: over.telegram_bot_token.or(self.telegram_bot_token),
+            twitter_bearer_token: over.twitter_bearer_token.or(self.twitter_bearer_token),
+            neynar_api_key: over.neynar_api_key.or(self.neynar_api_key),
+            farcaster_signer_uuid: over.farcaster_signer_uuid.or(self.farcaster_signer_uuid),
+            openai_api_key: over.openai_api_key.or(self.openai_api_key),
+            xai_api_key: over.xai_api_key.or(self.xai_api_key),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        let value = match key {
+            "discord_api_token" => &self.discord_api_token,
+            "telegram_bot_token" => &self.telegram_bot_token,
+            "twitter_bearer_token" => &self.twitter_bearer_token,
+            "neynar_api_key" => &self.neynar_api_key,
+            "farcaster_signer_uuid" => &self.farcaster_signer_uuid,
+            "openai_api_key" => &self.openai_api_key,
+            "xai_api_key" => &self.xai_api_key,
+            _ => return None,
+        };
+        value.as_deref()
+    }
+
+    /// Checks that every credential of `components` is set, reporting all
+    /// that are missing at once.
+    pub fn require(&self, components: &[Component]) -> Result<(), MissingCredentials> {
+        let missing = components
+            .iter()
+            .filter_map(|component| {
+                let vars = component
+                    .credentials()
+                    .iter()
+                    .filter(|key| self.get(key).is_none())
+                    .map(|key| env_var(key))
+                    .collect::<Vec<_>>();
+                (!vars.is_empty()).then_some((component.name(), vars))
+            })
+            .collect::<Vec<_>>();
+
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(MissingCredentials { missing })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_flags_override_env_override_file() {
+        let file: Credentials = toml::from_str(
+            r#"
+            discord_api_token = "file-discord"
+            openai_api_key = "file-openai"
+            xai_api_key = "file-xai"
+            "#,
+        )
+        .unwrap();
+        let env = HashMap::from([
+            ("OPENAI_API_KEY", "env-openai"),
+            ("XAI_API_KEY", "env-xai"),
+            ("TELEGRAM_BOT_TOKEN", ""),
+        ]);
+        let env = Credentials::from_lookup(|key| env.get(key).map(|v| v.to_string()));
+        let flags = Credentials {
+            xai_api_key: Some("flag-xai".to_string()),
+            ..Default::default()
+        };
+
+        let merged = file.merge(env).merge(flags);
+        assert_eq!(merged.get("discord_api_token"), Some("file-discord"));
+        assert_eq!(merged.get("openai_api_key"), Some("env-openai"));
+        assert_eq!(merged.get("xai_api_key"), Some("flag-xai"));
+        // Empty variables count as unset.
+        assert_eq!(merged.get("telegram_bot_token"), None);
+    }
+
+    #[test]
+    fn test_missing_credentials_are_aggregated() {
+        let credentials = Credentials {
+            openai_api_key: Some("key".to_string()),
+            ..Default::default()
+        };
+
+        assert!(credentials.require(&[Component::OpenAi]).is_ok());
+
+        let err = credentials
+            .require(&[Component::Discord, Component::OpenAi, Component::Telegram])
+            .unwrap_err();
+        assert_eq!(
+            err.missing,
+            [
+                ("discord", vec!["DISCORD_API_TOKEN".to_string()]),
+                ("telegram", vec!["TELEGRAM_BOT_TOKEN".to_string()]),
+            ]
+        );
+        assert_eq!(
+            err.to_string(),
+            "Missing credentials:\n  discord: DISCORD_API_TOKEN\n  telegram: TELEGRAM_BOT_TOKEN"
+        );
+    }
+
+    #[test]
+    fn test_farcaster_needs_key_and_signer() {
+        let credentials = Credentials {
+            neynar_api_key: Some("key".to_string()),
+            ..Default::default()
+        };
+        let err = credentials.require(&[Component::Farcaster]).unwrap_err();
+        assert_eq!(
+            err.missing,
+            [("farcaster", vec!["FARCASTER_SIGNER_UUID".to_string()])]
+        );
+    }
+
+    #[test]
+    fn test_unknown_keys_are_rejected() {
+        assert!(toml::from_str::<Credentials>("discord_token = \"typo\"").is_err());
+        assert!(toml::from_str::<ConfigFile>("[attention]\nbot_names = []").is_err());
+    }
+
+    #[test]
+    fn test_invalid_settings_are_rejected() {
+        let file: ConfigFile = toml::from_str("[attention]\nreply_threshold = 1.5").unwrap();
+        assert!(matches!(file.validate(), Err(ConfigError::Invalid(_))));
+
+        let file: ConfigFile =
+            toml::from_str("[openai.azure]\napi_version = \"2024-06-01\"").unwrap();
+        assert!(matches!(file.validate(), Err(ConfigError::Invalid(_))));
+
+        let file: ConfigFile =
+            toml::from_str("[escalation]\ntargets = [{ guild_id = \"1\" }]").unwrap();
+        assert!(matches!(file.validate(), Err(ConfigError::Invalid(_))));
+
+        let file: ConfigFile = toml::from_str("[memory]\nsimilarity = 0.0").unwrap();
+        assert!(matches!(file.validate(), Err(ConfigError::Invalid(_))));
+
+        let file: ConfigFile = toml::from_str("[history]\nmax_message_chars = 0").unwrap();
+        assert!(matches!(file.validate(), Err(ConfigError::Invalid(_))));
+
+        let file: ConfigFile = toml::from_str("[digest]\ndiscord_owner = \"me\"").unwrap();
+        assert!(matches!(file.validate(), Err(ConfigError::Invalid(_))));
+
+        let file: ConfigFile = toml::from_str("[reporting]\nmax_per_hour = 5").unwrap();
+        assert!(matches!(file.validate(), Err(ConfigError::Invalid(_))));
+
+        let file: ConfigFile = toml::from_str("[retention.sources]\nslack = 30").unwrap();
+        assert!(matches!(file.validate(), Err(ConfigError::Invalid(_))));
+
+        let file: ConfigFile = toml::from_str("[rate_limit]\nper_minute = 0").unwrap();
+        assert!(matches!(file.validate(), Err(ConfigError::Invalid(_))));
+
+        let file: ConfigFile =
+            toml::from_str("[injection]\nmax_preamble_similarity = 2.0").unwrap();
+        assert!(matches!(file.validate(), Err(ConfigError::Invalid(_))));
+
+        let file: ConfigFile =
+            toml::from_str("[localization]\ndefault_language = \"English\"").unwrap();
+        assert!(matches!(file.validate(), Err(ConfigError::Invalid(_))));
+
+        let file: ConfigFile = toml::from_str("[locale]\ntimezone = \"CEST+1\"").unwrap();
+        assert!(matches!(file.validate(), Err(ConfigError::Invalid(_))));
+
+        let file: ConfigFile = toml::from_str("[diversity]\nlambda = 1.5").unwrap();
+        assert!(matches!(file.validate(), Err(ConfigError::Invalid(_))));
+
+        let file: ConfigFile = toml::from_str("[chunking]\nmax_chars = 0").unwrap();
+        assert!(matches!(file.validate(), Err(ConfigError::Invalid(_))));
+
+        let file: ConfigFile =
+            toml::from_str("[embedding_policy]\nskip_patterns = [\"(ok\"]").unwrap();
+        assert!(matches!(file.validate(), Err(ConfigError::Invalid(_))));
+
+        let file: ConfigFile = toml::from_str("[presence]\nactivity = \"\"").unwrap();
+        assert!(matches!(file.validate(), Err(ConfigError::Invalid(_))));
+
+        let file: ConfigFile = toml::from_str("[outage]\nnotice_window_minutes = 0").unwrap();
+        assert!(matches!(file.validate(), Err(ConfigError::Invalid(_))));
+
+        let file: ConfigFile = toml::from_str("[batching]\nwindow_ms = 0").unwrap();
+        assert!(matches!(file.validate(), Err(ConfigError::Invalid(_))));
+
+        let file: ConfigFile = toml::from_str("[query_rewrite]\ntimeout_ms = 0").unwrap();
+        assert!(matches!(file.validate(), Err(ConfigError::Invalid(_))));
+
+        let file: ConfigFile = toml::from_str("[startup]\nprogress_secs = 0").unwrap();
+        assert!(matches!(file.validate(), Err(ConfigError::Invalid(_))));
+
+        let file: ConfigFile = toml::from_str(
+            "[[experiments]]\nname = \"model\"\nguilds = [\"1\"]\nvariants = [{ name = \"a\", weight = 0 }]",
+        )
+        .unwrap();
+        assert!(matches!(file.validate(), Err(ConfigError::Invalid(_))));
+
+        let file: ConfigFile = toml::from_str(
+            "[[webhooks]]\nurl = \"https://ops.example.com\"\nsecret = \"s\"\nevents = [\"escalation\"]",
+        )
+        .unwrap();
+        assert!(matches!(file.validate(), Err(ConfigError::Invalid(_))));
+    }
+
+    #[cfg(feature = "discord")]
+    #[test]
+    fn test_invalid_discord_settings_are_rejected() {
+        let file: ConfigFile =
+            toml::from_str("[discord]\nallowed_channels = [\"general\"]").unwrap();
+        assert!(matches!(file.validate(), Err(ConfigError::Invalid(_))));
+
+        let file: ConfigFile =
+            toml::from_str("[discord]\nauto_answer_forums = [\"support\"]").unwrap();
+        assert!(matches!(file.validate(), Err(ConfigError::Invalid(_))));
+
+        let file: ConfigFile =
+            toml::from_str("[discord.mention_roles]\n\"1\" = [\"moderators\"]").unwrap();
+        assert!(matches!(file.validate(), Err(ConfigError::Invalid(_))));
+
+        let file: ConfigFile = toml::from_str("[discord.guilds]\nlisten_pattern = \"(\"").unwrap();
+        assert!(matches!(file.validate(), Err(ConfigError::Invalid(_))));
+    }
+
+    #[cfg(feature = "git-loader")]
+    #[test]
+    fn test_invalid_knowledge_sources_are_rejected() {
+        let file: ConfigFile =
+            toml::from_str("[[knowledge.repos]]\nname = \"docs\"\nurl = \"docs\"").unwrap();
+        assert!(matches!(file.validate(), Err(ConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_openai_provider_settings() {
+        let file: ConfigFile = toml::from_str(
+            r#"
+            [openai]
+            base_url = "https://my-org.openai.azure.com"
+            headers = { "x-team" = "cartridge" }
+            azure = { api_version = "2024-06-01", deployments = { "gpt-4o" = "chat-prod" } }
+            "#,
+        )
+        .unwrap();
+        assert!(file.validate().is_ok());
+        let azure = file.openai.azure.unwrap();
+        assert_eq!(azure.deployments["gpt-4o"], "chat-prod");
+    }
+}
@@ -0,0 +1,259 @@
+//! Durable per-channel conversation state, so a restart does not lose track
+//! of conversations that were in flight.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rig::embeddings::EmbeddingModel;
+use tracing::{debug, error};
+
+use crate::knowledge::{ConversationState, KnowledgeBase};
+
+/// How long a changed state may stay unwritten, so bursts of changes are
+/// stored once.
+const DEFAULT_WRITE_DELAY: Duration = Duration::from_secs(2);
+
+struct Entry {
+    state: ConversationState,
+    /// Bumped on every change.
+    generation: u64,
+    /// Generation last written to the database.
+    written: u64,
+}
+
+/// Cache of conversation states in front of the `conversation_state` table.
+/// States are loaded on first use after startup and written shortly after
+/// each change.
+#[derive(Clone)]
+pub struct ConversationStore<E: EmbeddingModel + 'static> {
+    knowledge: KnowledgeBase<E>,
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+    write_delay: Duration,
+}
+
+impl<E: EmbeddingModel> ConversationStore<E> {
+    pub fn new(knowledge: KnowledgeBase<E>) -> Self {
+        Self {
+            knowledge,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            write_delay: DEFAULT_WRITE_DELAY,
+        }
+    }
+
+    pub fn with_write_delay(mut self, delay: Duration) -> Self {
+        self.write_delay = delay;
+        self
+    }
+
+    pub async fn get(&self, channel_id: &str) -> ConversationState {
+        self.ensure_loaded(channel_id).await;
+        self.entries.lock().unwrap()[channel_id].state.clone()
+    }
+
+    /// Changes the state of a channel and schedules a write.
+    pub async fn update<F: FnOnce(&mut ConversationState)>(&self, channel_id: &str, change: F) {
+        self.ensure_loaded(channel_id).await;
+        let generation = {
+            let mut entries = self.entries.lock().unwrap();
+            let entry = entries.get_mut(channel_id).unwrap();
+            change(&mut entry.state);
+            entry.generation += 1;
+            entry.generation
+        };
+
+        let store = self.clone();
+        let channel_id = channel_id.to_string();
+        tokio::spawn(async move {
+            tokio::time::sleep(store.write_delay).await;
+            // A later change schedules its own write.
+            let current = store.entries.lock().unwrap()[&channel_id].generation == generation;
+            if current {
+                store.write(&channel_id).await;
+            }
+        });
+    }
+
+    /// Writes every changed state now, e.g. before shutting down.
+    pub async fn flush(&self) {
+        let dirty = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, entry)| entry.written < entry.generation)
+            .map(|(channel_id, _)| channel_id.clone())
+            .collect::<Vec<_>>();
+
+        for channel_id in dirty {
+            self.write(&channel_id).await;
+        }
+    }
+
+    async fn ensure_loaded(&self, channel_id: &str) {
+        if self.entries.lock().unwrap().contains_key(channel_id) {
+            return;
+        }
+
+        let state = match self.knowledge.load_conversation_state(channel_id).await {
+            Ok(state) => {
+                debug!(
+                    channel_id,
+                    found = state.is_some(),
+                    "Loaded conversation state"
+                );
+                state.unwrap_or_default()
+            }
+            Err(err) => {
+                error!(?err, channel_id, "Failed to load conversation state");
+                ConversationState::default()
+            }
+        };
+
+        // A concurrent load may have won; its entry may already hold changes.
+        self.entries
+            .lock()
+            .unwrap()
+            .entry(channel_id.to_string())
+            .or_insert(Entry {
+                state,
+                generation: 0,
+                written: 0,
+            });
+    }
+
+    async fn write(&self, channel_id: &str) {
+        let (state, generation) = {
+            let entries = self.entries.lock().unwrap();
+            let entry = &entries[channel_id];
+            if entry.written >= entry.generation {
+                return;
+            }
+            (entry.state.clone(), entry.generation)
+        };
+
+        match self
+            .knowledge
+            .save_conversation_state(channel_id, &state)
+            .await
+        {
+            Ok(true) => {
+                let mut entries = self.entries.lock().unwrap();
+                let entry = entries.get_mut(channel_id).unwrap();
+                entry.written = entry.written.max(generation);
+            }
+            Ok(false) => debug!(
+                channel_id,
+                "Conversation state ahead of messages, not written"
+            ),
+            Err(err) => error!(?err, channel_id, "Failed to store conversation state"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agent::Agent,
+        attention::AttentionCommand,
+        knowledge::{ChannelType, Message, Source},
+        test_utils::{self, ScriptedCompletionModel},
+    };
+    use rig::completion::Prompt;
+
+    fn message(id: &str) -> Message {
+        Message {
+            id: id.to_string(),
+            source: Source::Discord,
+            source_id: "u1".to_string(),
+            channel_type: ChannelType::Text,
+            channel_id: "c1".to_string(),
+            account_id: "u1".to_string(),
+            role: "user".to_string(),
+            content: format!("message {id}"),
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    const DELAY: Duration = Duration::from_millis(20);
+
+    #[tokio::test]
+    async fn test_state_survives_restart() {
+        let knowledge = test_utils::knowledge_base().await;
+        let store = ConversationStore::new(knowledge.clone()).with_write_delay(DELAY);
+
+        knowledge.create_message(message("m1")).await.unwrap();
+        store
+            .update("c1", |state| {
+                state.receive("m1");
+                state.summary = Some("Asking about katana flags".to_string());
+                state.open_questions.push("Which port?".to_string());
+            })
+            .await;
+        store.update("c1", |state| state.receive("m2")).await;
+
+        // m2 is not stored yet, so writing it would run ahead of the history.
+        tokio::time::sleep(DELAY * 5).await;
+        assert_eq!(knowledge.load_conversation_state("c1").await.unwrap(), None);
+
+        knowledge.create_message(message("m2")).await.unwrap();
+        store
+            .update("c1", |state| {
+                state.settle(&["m1".to_string()], AttentionCommand::Respond)
+            })
+            .await;
+        tokio::time::sleep(DELAY * 5).await;
+
+        // A fresh store stands in for the restarted process.
+        let restarted = ConversationStore::new(knowledge.clone());
+        let state = restarted.get("c1").await;
+        assert_eq!(state.summary.as_deref(), Some("Asking about katana flags"));
+        assert_eq!(state.pending_messages, ["m2"]);
+        assert_eq!(state.last_attention, Some(AttentionCommand::Respond));
+        assert_eq!(state.last_message_id.as_deref(), Some("m2"));
+        assert_eq!(restarted.get("c2").await, ConversationState::default());
+
+        // The restored summary reaches the prompt.
+        let model = ScriptedCompletionModel::new(["Port 5050."]);
+        let character = test_utils::character();
+        let agent =
+            Agent::new(character, model.clone(), knowledge).with_conversation_store(restarted);
+        let reply = agent
+            .channel_builder("c1")
+            .await
+            .build()
+            .prompt("And the port?")
+            .await
+            .unwrap();
+        assert_eq!(reply, "Port 5050.");
+        let documents = &model.requests()[0].documents;
+        assert!(documents
+            .iter()
+            .any(|document| document.contains("Asking about katana flags")));
+    }
+
+    #[tokio::test]
+    async fn test_flush_writes_pending_changes() {
+        let knowledge = test_utils::knowledge_base().await;
+        let store =
+            ConversationStore::new(knowledge.clone()).with_write_delay(Duration::from_secs(60));
+
+        store
+            .update("c1", |state| {
+                state.pending_follow_ups.push("share docs link".into())
+            })
+            .await;
+        assert_eq!(knowledge.load_conversation_state("c1").await.unwrap(), None);
+
+        store.flush().await;
+        let state = knowledge
+            .load_conversation_state("c1")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(state.pending_follow_ups, ["share docs link"]);
+    }
+}
@@ -0,0 +1,105 @@
+//! Corrections of the bot's own answers. When a user replies to one of its
+//! messages pointing out a mistake ("actually that flag was removed in v2"),
+//! the correction is checked against the docs and the original message is
+//! amended rather than left up with the mistake: edited where the platform
+//! allows it, followed up on elsewhere. See
+//! [Agent::correct_answer](crate::agent::Agent::correct_answer).
+
+/// Instruction for generating a correction, followed by the original answer.
+pub const CORRECTION_INSTRUCTION: &str = "The user is replying to an answer you gave earlier, shown below, and says it was wrong. Check their correction against the docs. Reply with only the corrected information, in a sentence or two, without repeating the rest of the answer. If the docs contradict the correction, reply with exactly NO_CORRECTION.";
+
+/// Reply of the model when the original answer stands.
+pub const NO_CORRECTION: &str = "NO_CORRECTION";
+
+/// Phrasings of a correction, as words.
+const CORRECTIONS: &[&[&str]] = &[
+    &["actually"],
+    &["that", "s", "wrong"],
+    &["that", "is", "wrong"],
+    &["you", "re", "wrong"],
+    &["not", "right"],
+    &["not", "correct"],
+    &["incorrect"],
+    &["not", "true"],
+    &["was", "removed"],
+    &["has", "been", "removed"],
+    &["no", "longer"],
+    &["deprecated"],
+    &["outdated"],
+    &["out", "of", "date"],
+    &["doesn", "t", "exist"],
+    &["does", "not", "exist"],
+];
+
+/// Whether `text`, a reply to one of the bot's answers, says the answer was
+/// wrong.
+pub fn is_correction(text: &str) -> bool {
+    let words = text
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(String::from)
+        .collect::<Vec<_>>();
+    CORRECTIONS.iter().any(|phrase| {
+        words
+            .windows(phrase.len())
+            .any(|window| window.iter().zip(phrase.iter()).all(|(a, b)| a == b))
+    })
+}
+
+/// `original` followed by the correction `notice`, at most `max_chars` long.
+/// The notice is what matters, so the original is cut to make room and ends
+/// with `…`.
+pub fn append_correction(original: &str, notice: &str, max_chars: usize) -> String {
+    let original = original.trim_end();
+    let notice = notice.trim();
+    let full = format!("{original}\n\n{notice}");
+    if full.chars().count() <= max_chars {
+        return full;
+    }
+
+    let room = max_chars.saturating_sub(notice.chars().count() + "…\n\n".chars().count());
+    match original.char_indices().nth(room) {
+        Some((end, _)) if room > 0 => {
+            format!("{}…\n\n{notice}", original[..end].trim_end())
+        }
+        _ => notice.chars().take(max_chars).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_corrections() {
+        for text in [
+            "actually that flag was removed in v2",
+            "That's wrong, the fee is 0.02 ETH",
+            "this is outdated, use `renew` instead",
+            "that command doesn't exist anymore",
+        ] {
+            assert!(is_correction(text), "{text}");
+        }
+        for text in ["thanks, that worked!", "how do I renew them?", ""] {
+            assert!(!is_correction(text), "{text}");
+        }
+    }
+
+    #[test]
+    fn test_append_correction() {
+        let notice = "✏️ Correction: the flag was removed in v2.";
+        assert_eq!(
+            append_correction("Use `--fast`.", notice, 2000),
+            format!("Use `--fast`.\n\n{notice}")
+        );
+
+        let original = "Use `--fast`. ".repeat(200);
+        let edited = append_correction(&original, notice, 2000);
+        assert!(edited.chars().count() <= 2000);
+        assert!(edited.starts_with("Use `--fast`."));
+        assert!(edited.ends_with(&format!("…\n\n{notice}")));
+
+        assert_eq!(append_correction(&original, notice, 10), "✏️ Correct");
+    }
+}
@@ -0,0 +1,416 @@
+//! Daily digest of what the agent did, sent to the operator so they don't
+//! have to tail logs: messages handled per source, replies, how many
+//! messages went unanswered, tool calls, errors by category, messages purged
+//! by [retention](crate::retention), messages ignored by the
+//! [rate limit](crate::rate_limit) and the top knowledge gaps. Spend isn't
+//! reported, nothing accounts for model usage yet.
+//!
+//! [DigestJob::spawn] sends the previous day's digest every day at
+//! [DigestConfig::hour], through a [DigestSink] such as a Discord DM to the
+//! owner, a Telegram chat, or the log when neither is configured. The text
+//! comes from the [DIGEST](crate::templates::DIGEST) template.
+//!
+//! ```toml
+//! [digest]
+//! hour = 8
+//! discord_owner = "80351110224678912"
+//! ```
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use rig::{completion::CompletionModel, embeddings::EmbeddingModel};
+use rig_sqlite::SqliteError;
+use serde::Deserialize;
+use thiserror::Error;
+use tracing::{error, info};
+
+use crate::{
+    agent::Agent,
+    knowledge::{format_gaps, Activity},
+    templates,
+};
+
+/// `[digest]` settings of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DigestConfig {
+    /// Hour of the day, in UTC, the previous day's digest is sent at.
+    pub hour: u32,
+    /// Discord user the digest is sent to as a DM.
+    pub discord_owner: Option<String>,
+    /// Telegram chat the digest is sent to, when there's no Discord owner.
+    pub telegram_chat: Option<i64>,
+    /// Knowledge gaps listed at most.
+    pub gaps: usize,
+}
+
+impl Default for DigestConfig {
+    fn default() -> Self {
+        Self {
+            hour: 8,
+            discord_owner: None,
+            telegram_chat: None,
+            gaps: 5,
+        }
+    }
+}
+
+impl DigestConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.hour > 23 {
+            return Err(format!(
+                "digest.hour: {} is not an hour of the day",
+                self.hour
+            ));
+        }
+        if let Some(owner) = &self.discord_owner {
+            if owner.parse::<u64>().is_err() {
+                return Err(format!("digest.discord_owner: {owner:?} is not a user id"));
+            }
+        }
+        Ok(())
+    }
+
+    /// First time the digest is due after `now`.
+    pub fn next_run(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let today = now
+            .date_naive()
+            .and_hms_opt(self.hour, 0, 0)
+            .expect("hour is validated")
+            .and_utc();
+        if today > now {
+            today
+        } else {
+            today + chrono::Duration::days(1)
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum DigestError {
+    #[error("Failed to aggregate activity: {0}")]
+    Database(#[from] SqliteError),
+    #[error("Failed to deliver digest: {0}")]
+    Delivery(String),
+}
+
+/// Where digests are sent.
+#[async_trait]
+pub trait DigestSink: Send + Sync {
+    async fn deliver(&self, digest: &str) -> Result<(), String>;
+}
+
+/// Logs digests, for bots without a configured recipient.
+pub struct LogDigest;
+
+#[async_trait]
+impl DigestSink for LogDigest {
+    async fn deliver(&self, digest: &str) -> Result<(), String> {
+        info!(%digest, "Daily digest");
+        Ok(())
+    }
+}
+
+pub struct DigestJob<M: CompletionModel, E: EmbeddingModel + 'static> {
+    agent: Agent<M, E>,
+    config: DigestConfig,
+    sink: Arc<dyn DigestSink>,
+}
+
+impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> DigestJob<M, E> {
+    pub fn new(agent: Agent<M, E>, config: DigestConfig, sink: Arc<dyn DigestSink>) -> Self {
+        Self {
+            agent,
+            config,
+            sink,
+        }
+    }
+
+    /// The digest of `day`, in UTC, with the date written in the configured
+    /// format. Gaps are those asked since the start of the day.
+    pub async fn report(&self, day: NaiveDate) -> Result<String, SqliteError> {
+        let since = day.and_hms_opt(0, 0, 0).expect("midnight exists").and_utc();
+        let until = since + chrono::Duration::days(1);
+        let knowledge = self.agent.knowledge();
+        let activity = knowledge.activity(since, until).await?;
+        let gaps = knowledge.top_gaps(since, self.config.gaps).await?;
+
+        Ok(self.agent.character.template(
+            templates::DIGEST,
+            &[
+                ("day", &self.agent.locale().preferences().day(day)),
+                ("messages", &messages(&activity)),
+                ("responses", &activity.responses.to_string()),
+                (
+                    "unanswered",
+                    &format!(
+                        "{} ({:.0}%)",
+                        activity.unanswered,
+                        activity.unanswered_rate() * 100.0
+                    ),
+                ),
+                ("tools", &tools(&activity)),
+                ("errors", &counts(activity.errors())),
+                ("escalations", &activity.escalations.to_string()),
+                ("purged", &activity.purged.to_string()),
+                ("rate_limited", &activity.rate_limited.to_string()),
+                ("gaps", &format_gaps(&gaps)),
+            ],
+        ))
+    }
+
+    /// Sends the digest of `day`.
+    pub async fn run(&self, day: NaiveDate) -> Result<(), DigestError> {
+        let report = self.report(day).await?;
+        self.sink
+            .deliver(&report)
+            .await
+            .map_err(DigestError::Delivery)
+    }
+
+    /// Sends the previous day's digest every day at [DigestConfig::hour]
+    /// until the task is aborted.
+    pub fn spawn(self) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                let now = self.agent.clock().now_utc();
+                let next = self.config.next_run(now);
+                tokio::time::sleep((next - now).to_std().unwrap_or_default()).await;
+
+                let day = next.date_naive() - chrono::Duration::days(1);
+                if let Err(err) = self.run(day).await {
+                    error!(?err, %day, "Failed to send digest");
+                }
+            }
+        })
+    }
+}
+
+/// Total received messages, with the count of each source.
+fn messages(activity: &Activity) -> String {
+    match activity.received() {
+        0 => "none".to_string(),
+        received => format!("{received} ({})", counts(activity.messages.iter().cloned())),
+    }
+}
+
+/// Calls of each tool, with how many failed.
+fn tools(activity: &Activity) -> String {
+    if activity.tool_calls.is_empty() {
+        return "none".to_string();
+    }
+    activity
+        .tool_calls
+        .iter()
+        .map(|tool| match tool.failures {
+            0 => format!("{} {}", tool.tool_name, tool.calls),
+            failures => format!("{} {} ({failures} failed)", tool.tool_name, tool.calls),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn counts(items: impl IntoIterator<Item = (String, i64)>) -> String {
+    let counts = items
+        .into_iter()
+        .map(|(name, count)| format!("{name} {count}"))
+        .collect::<Vec<_>>();
+    if counts.is_empty() {
+        "none".to_string()
+    } else {
+        counts.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::{
+        clock::MockClock,
+        confidence::{Confidence, ConfidenceOutcome},
+        knowledge::{ChannelType, GapConfig, Message, RetrievalSupport, Source, ToolCall},
+        test_utils::{self, ScriptedCompletionModel},
+    };
+
+    #[derive(Default)]
+    struct Outbox(Mutex<Vec<String>>);
+
+    #[async_trait]
+    impl DigestSink for Outbox {
+        async fn deliver(&self, digest: &str) -> Result<(), String> {
+            self.0.lock().unwrap().push(digest.to_string());
+            Ok(())
+        }
+    }
+
+    fn message(id: &str, source: Source, role: &str, created_at: DateTime<Utc>) -> Message {
+        Message {
+            id: id.to_string(),
+            source,
+            source_id: "u1".to_string(),
+            channel_type: ChannelType::Text,
+            channel_id: "c1".to_string(),
+            account_id: "u1".to_string(),
+            role: role.to_string(),
+            content: format!("message {id}"),
+            created_at,
+        }
+    }
+
+    fn tool_call(interaction_id: i64, tool_name: &str, output: Result<&str, &str>) -> ToolCall {
+        ToolCall {
+            interaction_id,
+            tool_name: tool_name.to_string(),
+            args: "{}".to_string(),
+            output: output.map(String::from).map_err(String::from),
+            duration_ms: 10,
+            truncated: false,
+        }
+    }
+
+    #[test]
+    fn test_next_run() {
+        let config = DigestConfig::default();
+        let at = |text: &str| text.parse::<DateTime<Utc>>().unwrap();
+
+        assert_eq!(
+            config.next_run(at("2024-11-05T07:59:00Z")),
+            at("2024-11-05T08:00:00Z")
+        );
+        assert_eq!(
+            config.next_run(at("2024-11-05T08:00:00Z")),
+            at("2024-11-06T08:00:00Z")
+        );
+        assert!(DigestConfig {
+            hour: 24,
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_scheduled_digest() {
+        let character = test_utils::character();
+        let clock = MockClock::new("2024-11-05T07:59:00Z".parse().unwrap());
+        let agent = Agent::new(
+            character,
+            ScriptedCompletionModel::default(),
+            test_utils::knowledge_base().await,
+        )
+        .with_clock(Arc::new(clock));
+        let outbox = Arc::new(Outbox::default());
+        let handle = DigestJob::new(agent, DigestConfig::default(), outbox.clone()).spawn();
+
+        while outbox.0.lock().unwrap().is_empty() {
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        }
+        handle.abort();
+
+        let digest = outbox.0.lock().unwrap()[0].clone();
+        assert!(digest.starts_with("Digest for 2024-11-04\n"), "{digest}");
+    }
+
+    #[tokio::test]
+    async fn test_digest_of_a_day() {
+        let character = test_utils::character();
+        let agent = Agent::new(
+            character,
+            ScriptedCompletionModel::default(),
+            test_utils::knowledge_base().await,
+        );
+        let knowledge = agent.knowledge();
+
+        // Rows stamped by SQLite fall on the current day
+        let now = Utc::now();
+        for msg in [
+            message("m1", Source::Discord, "user", now),
+            message("m2", Source::Discord, "user", now),
+            message("m3", Source::Discord, "user", now),
+            message("m4", Source::Telegram, "user", now),
+            message("m1:reply", Source::Discord, "assistant", now),
+            message("m4:reply", Source::Telegram, "assistant", now),
+            message(
+                "old",
+                Source::Discord,
+                "user",
+                now - chrono::Duration::days(2),
+            ),
+        ] {
+            knowledge.create_message(msg).await.unwrap();
+        }
+        let answered = knowledge
+            .create_interaction("c1".to_string(), "u1".to_string(), vec!["m1".to_string()])
+            .await
+            .unwrap();
+        let declined = knowledge
+            .create_interaction("c1".to_string(), "u1".to_string(), vec!["m4".to_string()])
+            .await
+            .unwrap();
+        let confidence = Confidence {
+            score: 0.1,
+            support: RetrievalSupport {
+                best_distance: None,
+                supporting: 0,
+            },
+            self_assessed: None,
+            outcome: ConfidenceOutcome::Declined,
+        };
+        knowledge
+            .record_confidence(declined, &confidence)
+            .await
+            .unwrap();
+        for call in [
+            tool_call(answered, "search_docs", Ok("[]")),
+            tool_call(answered, "search_docs", Ok("[]")),
+            tool_call(answered, "post_tweet", Err("Twitter is down")),
+        ] {
+            knowledge.record_tool_call(call).await.unwrap();
+        }
+        knowledge
+            .create_escalation("c1", "u1", "low_confidence")
+            .await
+            .unwrap();
+        for (question, distance) in [
+            ("How do I rotate session keys?", None),
+            ("How do I rotate session keys?", None),
+            ("Is there a testnet faucet?", Some(1.25)),
+        ] {
+            knowledge
+                .record_gap(question, "c1", distance, &GapConfig::default())
+                .await
+                .unwrap();
+        }
+
+        let outbox = Arc::new(Outbox::default());
+        let job = DigestJob::new(agent, DigestConfig::default(), outbox.clone());
+        let day = now.date_naive();
+        job.run(day).await.unwrap();
+
+        let sent = outbox.0.lock().unwrap().clone();
+        assert_eq!(sent.len(), 1);
+        insta::assert_snapshot!(sent[0].replace(&day.to_string(), "<day>"), @r#"
+        Digest for <day>
+
+        Messages: 4 (discord 3, telegram 1)
+        Responses sent: 2
+        Unanswered: 2 (50%)
+        Tool calls: search_docs 2, post_tweet 1 (1 failed)
+        Errors: declined answers 1, tool post_tweet 1
+        Escalations: 1
+        Purged by retention: 0
+        Ignored while rate limited: 0
+
+        Top knowledge gaps:
+        2× "How do I rotate session keys?" (no match)
+        1× "Is there a testnet faucet?" (best distance 1.25)
+        "#);
+
+        let empty = job.report(day - chrono::Duration::days(1)).await.unwrap();
+        assert!(empty.contains("Messages: none\n"), "{empty}");
+    }
+}
@@ -0,0 +1,559 @@
+//! Handing a conversation to the support team when the bot can't help.
+//!
+//! An escalation is raised when a strict channel declines a question, when a
+//! user asks for a person, or when the model calls the
+//! [Escalate](crate::clients::escalate::Escalate) tool. It mentions the
+//! target's role with a link to the conversation, in the target's channel or
+//! the conversation itself, and is stored until staff mark it handled with
+//! `/resolve-escalation`. A channel escalates at most once per cooldown.
+//!
+//! ```toml
+//! [escalation]
+//! cooldown_secs = 600
+//!
+//! # Everything in a guild pings its support role
+//! [[escalation.targets]]
+//! guild_id = "1102345678901234567"
+//! role_id = "1109876543210987654"
+//!
+//! # One channel posts to a private staff channel instead
+//! [[escalation.targets]]
+//! channel_id = "1104567890123456789"
+//! role_id = "1109876543210987654"
+//! post_channel_id = "1107654321098765432"
+//! ```
+
+use std::sync::{Arc, OnceLock};
+
+use async_trait::async_trait;
+use regex::Regex;
+use rig::{completion::CompletionModel, embeddings::EmbeddingModel};
+use rig_sqlite::SqliteError;
+use serde::Deserialize;
+use thiserror::Error;
+use tracing::info;
+
+use crate::{agent::Agent, events::Event, logging::AUDIT_TARGET, templates};
+
+/// Where escalations from a guild or channel go. Ids are Discord snowflakes.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EscalationTarget {
+    /// Applies to every channel of this guild.
+    pub guild_id: Option<String>,
+    /// Applies to this channel, before any guild target.
+    pub channel_id: Option<String>,
+    /// Role mentioned in the notice.
+    pub role_id: Option<String>,
+    /// Channel the notice is posted in, instead of the conversation.
+    pub post_channel_id: Option<String>,
+}
+
+/// `[escalation]` settings of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EscalationConfig {
+    pub targets: Vec<EscalationTarget>,
+    /// Seconds after an escalation during which the channel doesn't escalate
+    /// again.
+    pub cooldown_secs: i64,
+}
+
+impl Default for EscalationConfig {
+    fn default() -> Self {
+        Self {
+            targets: Vec::new(),
+            cooldown_secs: 600,
+        }
+    }
+}
+
+impl EscalationConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.cooldown_secs < 0 {
+            return Err("escalation.cooldown_secs must not be negative".to_string());
+        }
+        for target in &self.targets {
+            if target.guild_id.is_none() && target.channel_id.is_none() {
+                return Err("escalation.targets need a guild_id or channel_id".to_string());
+            }
+            if target.role_id.is_none() && target.post_channel_id.is_none() {
+                return Err("escalation.targets need a role_id or post_channel_id".to_string());
+            }
+            let ids = [
+                ("guild_id", &target.guild_id),
+                ("channel_id", &target.channel_id),
+                ("role_id", &target.role_id),
+                ("post_channel_id", &target.post_channel_id),
+            ];
+            if let Some((name, id)) = ids.iter().find_map(|(name, id)| {
+                id.as_ref()
+                    .filter(|id| id.parse::<u64>().is_err())
+                    .map(|id| (name, id))
+            }) {
+                return Err(format!("escalation.targets.{name}: {id:?} is not an id"));
+            }
+        }
+        Ok(())
+    }
+
+    /// The target for a channel, preferring one for the channel itself over
+    /// one for its guild.
+    pub fn target_for(
+        &self,
+        guild_id: Option<&str>,
+        channel_id: &str,
+    ) -> Option<&EscalationTarget> {
+        self.targets
+            .iter()
+            .find(|target| target.channel_id.as_deref() == Some(channel_id))
+            .or_else(|| {
+                let guild_id = guild_id?;
+                self.targets.iter().find(|target| {
+                    target.channel_id.is_none() && target.guild_id.as_deref() == Some(guild_id)
+                })
+            })
+    }
+}
+
+/// Why a conversation was escalated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EscalationReason {
+    /// A strict channel declined the question.
+    LowConfidence,
+    /// The user asked for a person.
+    UserRequest,
+    /// The model called the escalate tool, with its reason.
+    ModelRequest(String),
+}
+
+impl std::fmt::Display for EscalationReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::LowConfidence => write!(f, "low_confidence"),
+            Self::UserRequest => write!(f, "user_request"),
+            Self::ModelRequest(reason) => write!(f, "model: {reason}"),
+        }
+    }
+}
+
+/// Whether a message asks to talk to a person rather than the bot.
+pub fn wants_human(text: &str) -> bool {
+    static REQUEST: OnceLock<Regex> = OnceLock::new();
+    REQUEST
+        .get_or_init(|| {
+            Regex::new(
+                r"(?i)\b(talk|speak|chat)\s+(to|with)\s+(a|an|some|the)?\s*(human|person|real person|someone|somebody|staff|mod|moderator|admin|team member|support)\b|\b(real|actual)\s+(human|person)\b|\blive\s+(agent|support)\b",
+            )
+            .unwrap()
+        })
+        .is_match(text)
+}
+
+/// A notice asking the support team to step in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EscalationNotice {
+    pub channel_id: String,
+    pub text: String,
+    /// Role the notice may ping.
+    pub role_id: Option<String>,
+    /// User the notice may ping.
+    pub account_id: String,
+}
+
+/// Posts escalation notices through the client the conversation is on.
+#[async_trait]
+pub trait EscalationPoster: Send + Sync {
+    async fn post(&self, notice: &EscalationNotice) -> Result<(), String>;
+}
+
+/// The conversation being escalated.
+#[derive(Debug, Clone)]
+pub struct EscalationRequest {
+    pub guild_id: Option<String>,
+    pub channel_id: String,
+    pub account_id: String,
+    /// Link to the message that triggered the escalation.
+    pub link: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EscalationOutcome {
+    /// Posted and stored under this id.
+    Escalated(i64),
+    /// The channel escalated within the cooldown, so nothing was posted.
+    RateLimited,
+    /// No target covers the channel.
+    NoTarget,
+}
+
+#[derive(Error, Debug)]
+pub enum EscalationError {
+    #[error(transparent)]
+    Store(#[from] SqliteError),
+    #[error("Failed to post escalation: {0}")]
+    Post(String),
+}
+
+/// Raises escalations for an agent.
+#[derive(Clone)]
+pub struct Escalator<M: CompletionModel, E: EmbeddingModel + 'static> {
+    agent: Agent<M, E>,
+    config: EscalationConfig,
+    poster: Arc<dyn EscalationPoster>,
+}
+
+impl<M: CompletionModel, E: EmbeddingModel + 'static> Escalator<M, E> {
+    pub fn new(
+        agent: Agent<M, E>,
+        config: EscalationConfig,
+        poster: Arc<dyn EscalationPoster>,
+    ) -> Self {
+        Self {
+            agent,
+            config,
+            poster,
+        }
+    }
+
+    /// Whether escalations from the request's channel go anywhere.
+    pub fn covers(&self, request: &EscalationRequest) -> bool {
+        self.config
+            .target_for(request.guild_id.as_deref(), &request.channel_id)
+            .is_some()
+    }
+
+    pub async fn escalate(
+        &self,
+        request: &EscalationRequest,
+        reason: EscalationReason,
+    ) -> Result<EscalationOutcome, EscalationError> {
+        let Some(target) = self
+            .config
+            .target_for(request.guild_id.as_deref(), &request.channel_id)
+        else {
+            return Ok(EscalationOutcome::NoTarget);
+        };
+
+        let knowledge = self.agent.knowledge();
+        let cooldown = chrono::Duration::seconds(self.config.cooldown_secs);
+        if let Some(last) = knowledge.last_escalation_at(&request.channel_id).await? {
+            if self.agent.clock().now_utc() - last < cooldown {
+                info!(
+                    target: AUDIT_TARGET,
+                    channel_id = request.channel_id,
+                    account_id = request.account_id,
+                    %reason,
+                    "Escalation rate limited"
+                );
+                return Ok(EscalationOutcome::RateLimited);
+            }
+        }
+
+        let reason = reason.to_string();
+        let id = knowledge
+            .create_escalation(&request.channel_id, &request.account_id, &reason)
+            .await?;
+        knowledge.events().publish(Event::EscalationCreated {
+            id,
+            channel_id: request.channel_id.clone(),
+            account_id: request.account_id.clone(),
+            reason: reason.clone(),
+        });
+        let mention = target
+            .role_id
+            .as_ref()
+            .map(|role_id| format!("<@&{role_id}>"))
+            .unwrap_or_default();
+        let notice = self.agent.character.template(
+            templates::ESCALATION_NOTICE,
+            &[
+                ("mention", &mention),
+                ("user", &format!("<@{}>", request.account_id)),
+                ("reason", &reason),
+                ("link", &request.link),
+                ("id", &id.to_string()),
+            ],
+        );
+        let notice = EscalationNotice {
+            channel_id: target
+                .post_channel_id
+                .clone()
+                .unwrap_or_else(|| request.channel_id.clone()),
+            text: notice.trim().to_string(),
+            role_id: target.role_id.clone(),
+            account_id: request.account_id.clone(),
+        };
+        self.poster
+            .post(&notice)
+            .await
+            .map_err(EscalationError::Post)?;
+
+        info!(
+            target: AUDIT_TARGET,
+            id,
+            channel_id = request.channel_id,
+            account_id = request.account_id,
+            reason,
+            "Escalated conversation"
+        );
+        Ok(EscalationOutcome::Escalated(id))
+    }
+
+    /// What to tell the user about the outcome, or `None` when nothing was
+    /// escalated and the bot should carry on as usual.
+    pub fn acknowledgement(&self, outcome: EscalationOutcome) -> Option<String> {
+        let name = match outcome {
+            EscalationOutcome::Escalated(_) => templates::ESCALATION_ACK,
+            EscalationOutcome::RateLimited => templates::ESCALATION_PENDING,
+            EscalationOutcome::NoTarget => return None,
+        };
+        Some(self.agent.character.template(name, &[]))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        clock::{Clock, MockClock, SystemClock},
+        test_utils::{self, FakeEmbeddingModel, ScriptedCompletionModel},
+    };
+    use std::sync::Mutex;
+
+    /// Records notices as `(channel_id, text)`.
+    #[derive(Default)]
+    pub(crate) struct FakePoster(pub(crate) Mutex<Vec<(String, String)>>);
+
+    #[async_trait]
+    impl EscalationPoster for FakePoster {
+        async fn post(&self, notice: &EscalationNotice) -> Result<(), String> {
+            self.0
+                .lock()
+                .unwrap()
+                .push((notice.channel_id.clone(), notice.text.clone()));
+            Ok(())
+        }
+    }
+
+    pub(crate) fn config() -> EscalationConfig {
+        EscalationConfig {
+            targets: vec![
+                EscalationTarget {
+                    guild_id: Some("1".to_string()),
+                    role_id: Some("7".to_string()),
+                    ..Default::default()
+                },
+                EscalationTarget {
+                    channel_id: Some("20".to_string()),
+                    role_id: Some("8".to_string()),
+                    post_channel_id: Some("99".to_string()),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn request(guild_id: Option<&str>, channel_id: &str) -> EscalationRequest {
+        EscalationRequest {
+            guild_id: guild_id.map(str::to_string),
+            channel_id: channel_id.to_string(),
+            account_id: "42".to_string(),
+            link: format!("https://discord.com/channels/1/{channel_id}/5"),
+        }
+    }
+
+    pub(crate) async fn setup(
+        config: EscalationConfig,
+    ) -> (
+        Escalator<ScriptedCompletionModel, FakeEmbeddingModel>,
+        Arc<FakePoster>,
+    ) {
+        setup_with_clock(config, Arc::new(SystemClock)).await
+    }
+
+    async fn setup_with_clock(
+        config: EscalationConfig,
+        clock: Arc<dyn Clock>,
+    ) -> (
+        Escalator<ScriptedCompletionModel, FakeEmbeddingModel>,
+        Arc<FakePoster>,
+    ) {
+        let character = test_utils::character();
+        let agent = Agent::new(
+            character,
+            ScriptedCompletionModel::default(),
+            test_utils::knowledge_base().await,
+        )
+        .with_clock(clock);
+        let poster = Arc::new(FakePoster::default());
+        (Escalator::new(agent, config, poster.clone()), poster)
+    }
+
+    #[test]
+    fn test_wants_human() {
+        for text in [
+            "Can I talk to a human please?",
+            "i want to speak with someone from the team",
+            "is there a real person here",
+            "Live agent",
+            "let me chat with a mod",
+        ] {
+            assert!(wants_human(text), "{text}");
+        }
+        for text in [
+            "How do I talk to the paymaster contract?",
+            "Is this a human readable format?",
+            "the person who deployed it left",
+        ] {
+            assert!(!wants_human(text), "{text}");
+        }
+    }
+
+    #[test]
+    fn test_target_for_and_validate() {
+        let config = config();
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config
+                .target_for(Some("1"), "10")
+                .unwrap()
+                .role_id
+                .as_deref(),
+            Some("7")
+        );
+        assert_eq!(
+            config
+                .target_for(Some("1"), "20")
+                .unwrap()
+                .role_id
+                .as_deref(),
+            Some("8")
+        );
+        assert!(config.target_for(Some("2"), "10").is_none());
+        assert!(config.target_for(None, "10").is_none());
+
+        let invalid = EscalationConfig {
+            targets: vec![EscalationTarget {
+                guild_id: Some("1".to_string()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert!(invalid.validate().is_err());
+        let invalid = EscalationConfig {
+            targets: vec![EscalationTarget {
+                channel_id: Some("#support".to_string()),
+                role_id: Some("7".to_string()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert!(invalid.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_low_confidence_and_user_request_escalate() {
+        let (escalator, poster) = setup(config()).await;
+
+        let outcome = escalator
+            .escalate(&request(Some("1"), "10"), EscalationReason::LowConfidence)
+            .await
+            .unwrap();
+        let EscalationOutcome::Escalated(id) = outcome else {
+            panic!("unexpected outcome {outcome:?}");
+        };
+        assert_eq!(
+            escalator.acknowledgement(outcome).unwrap(),
+            "I've asked the team to take a look, someone will follow up here."
+        );
+
+        escalator
+            .escalate(&request(Some("1"), "20"), EscalationReason::UserRequest)
+            .await
+            .unwrap();
+
+        let posts = poster.0.lock().unwrap().clone();
+        assert_eq!(
+            posts,
+            [
+                (
+                    "10".to_string(),
+                    format!("<@&7> <@42> needs help (low_confidence): https://discord.com/channels/1/10/5 (escalation #{id})")
+                ),
+                (
+                    "99".to_string(),
+                    format!("<@&8> <@42> needs help (user_request): https://discord.com/channels/1/20/5 (escalation #{})", id + 1)
+                ),
+            ]
+        );
+
+        let open = escalator
+            .agent
+            .knowledge()
+            .open_escalations(None)
+            .await
+            .unwrap();
+        let reasons: Vec<_> = open.iter().map(|e| e.reason.as_str()).collect();
+        assert_eq!(reasons, ["low_confidence", "user_request"]);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_per_channel() {
+        let clock = MockClock::new(chrono::Utc::now());
+        let (escalator, poster) = setup_with_clock(config(), Arc::new(clock.clone())).await;
+
+        let first = escalator
+            .escalate(&request(Some("1"), "10"), EscalationReason::UserRequest)
+            .await
+            .unwrap();
+        assert!(matches!(first, EscalationOutcome::Escalated(_)));
+        let second = escalator
+            .escalate(&request(Some("1"), "10"), EscalationReason::LowConfidence)
+            .await
+            .unwrap();
+        assert_eq!(second, EscalationOutcome::RateLimited);
+        assert_eq!(
+            escalator.acknowledgement(second).unwrap(),
+            "The team has already been notified and will be with you soon."
+        );
+
+        // Other channels have their own cooldown
+        let other = escalator
+            .escalate(&request(Some("1"), "11"), EscalationReason::UserRequest)
+            .await
+            .unwrap();
+        assert!(matches!(other, EscalationOutcome::Escalated(_)));
+        assert_eq!(poster.0.lock().unwrap().len(), 2);
+
+        // The cooldown ends
+        clock.advance(chrono::Duration::seconds(config().cooldown_secs + 1));
+        let again = escalator
+            .escalate(&request(Some("1"), "10"), EscalationReason::UserRequest)
+            .await
+            .unwrap();
+        assert!(matches!(again, EscalationOutcome::Escalated(_)));
+
+        let uncovered = escalator
+            .escalate(&request(Some("2"), "30"), EscalationReason::UserRequest)
+            .await
+            .unwrap();
+        assert_eq!(uncovered, EscalationOutcome::NoTarget);
+        assert_eq!(escalator.acknowledgement(uncovered), None);
+
+        // Without a cooldown every escalation goes through
+        let (escalator, poster) = setup(EscalationConfig {
+            cooldown_secs: 0,
+            ..config()
+        })
+        .await;
+        for _ in 0..2 {
+            escalator
+                .escalate(&request(Some("1"), "10"), EscalationReason::UserRequest)
+                .await
+                .unwrap();
+        }
+        assert_eq!(poster.0.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,176 @@
+//! Notable things that happen while the bot runs, published for whoever
+//! wants to react to them, such as the [webhooks](crate::webhooks) notifying
+//! an ops dashboard.
+//!
+//! Components publish on the [EventBus] of the
+//! [KnowledgeBase](crate::knowledge::KnowledgeBase), shared like its clock
+//! by everything built on it: escalations, confidence checks and recorded
+//! tools. Publishing never waits, an event nobody subscribed to is dropped,
+//! and a subscriber that falls behind misses events rather than slowing down
+//! the message handling that publishes them.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tracing::debug;
+
+use crate::status::{Condition, StatusBoard};
+
+/// Events kept for a subscriber that has yet to receive them.
+const CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum Event {
+    /// A conversation was handed to the support team, see
+    /// [crate::escalation].
+    EscalationCreated {
+        id: i64,
+        channel_id: String,
+        account_id: String,
+        reason: String,
+    },
+    /// A reply was hedged or declined, see [crate::confidence].
+    LowConfidenceAnswer {
+        channel_id: String,
+        interaction_id: Option<i64>,
+        score: f64,
+        outcome: String,
+    },
+    /// A tool was called while answering, see
+    /// [RecordedTool](crate::clients::recorded_tool::RecordedTool).
+    ToolExecuted {
+        interaction_id: i64,
+        tool_name: String,
+        duration_ms: i64,
+        success: bool,
+    },
+    /// The completion budget is spent until it resets.
+    BudgetExceeded,
+    /// An error the [reporter](crate::reporting) let through, with how often
+    /// it occurred since it was first seen.
+    ErrorBurst {
+        kind: String,
+        message: String,
+        occurrences: u64,
+        first_seen: DateTime<Utc>,
+    },
+}
+
+impl Event {
+    /// Names of the events, as in [Event::name].
+    pub const NAMES: [&'static str; 5] = [
+        "escalation_created",
+        "low_confidence_answer",
+        "tool_executed",
+        "budget_exceeded",
+        "error_burst",
+    ];
+
+    /// The `event` field of the event's JSON, e.g. `escalation_created`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::EscalationCreated { .. } => "escalation_created",
+            Event::LowConfidenceAnswer { .. } => "low_confidence_answer",
+            Event::ToolExecuted { .. } => "tool_executed",
+            Event::BudgetExceeded => "budget_exceeded",
+            Event::ErrorBurst { .. } => "error_burst",
+        }
+    }
+}
+
+/// Where events are published. Clones publish to the same subscribers.
+#[derive(Clone, Debug)]
+pub struct EventBus {
+    events: broadcast::Sender<Event>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self {
+            events: broadcast::channel(CAPACITY).0,
+        }
+    }
+}
+
+impl EventBus {
+    pub fn publish(&self, event: Event) {
+        debug!(event = event.name(), "Publishing event");
+        // Fails only when nobody is subscribed
+        let _ = self.events.send(event);
+    }
+
+    /// Receives every event published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Publishes [Event::BudgetExceeded] whenever [Condition::OverBudget] is
+    /// raised on `status`, until the board is dropped.
+    pub fn watch_status(&self, status: &StatusBoard) -> tokio::task::JoinHandle<()> {
+        let bus = self.clone();
+        let mut updates = status.subscribe();
+        tokio::spawn(async move {
+            let mut over_budget = updates.borrow_and_update().has(Condition::OverBudget);
+            while updates.changed().await.is_ok() {
+                let now = updates.borrow_and_update().has(Condition::OverBudget);
+                if now && !over_budget {
+                    bus.publish(Event::BudgetExceeded);
+                }
+                over_budget = now;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_json() {
+        let event = Event::EscalationCreated {
+            id: 7,
+            channel_id: "c1".to_string(),
+            account_id: "alice".to_string(),
+            reason: "user_request".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({
+                "event": "escalation_created",
+                "data": {
+                    "id": 7,
+                    "channel_id": "c1",
+                    "account_id": "alice",
+                    "reason": "user_request"
+                }
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(Event::BudgetExceeded).unwrap(),
+            serde_json::json!({ "event": "budget_exceeded" })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_budget_exceeded_on_raise() {
+        let bus = EventBus::default();
+        let status = StatusBoard::default();
+        let mut events = bus.subscribe();
+        bus.watch_status(&status);
+        tokio::task::yield_now().await;
+
+        status.set(Condition::Degraded, true);
+        status.set(Condition::OverBudget, true);
+        assert_eq!(events.recv().await.unwrap(), Event::BudgetExceeded);
+
+        // Only raising it again publishes again
+        status.set(Condition::Degraded, false);
+        status.set(Condition::OverBudget, false);
+        tokio::task::yield_now().await;
+        status.set(Condition::OverBudget, true);
+        assert_eq!(events.recv().await.unwrap(), Event::BudgetExceeded);
+        assert!(events.try_recv().is_err());
+    }
+}
@@ -0,0 +1,249 @@
+//! A/B experiments on how replies are generated: named variants of the
+//! preamble, model and temperature, compared by
+//! [KnowledgeBase::experiment_report](crate::knowledge::KnowledgeBase::experiment_report).
+//!
+//! An experiment only runs in the guilds it lists, Telegram chats counting as
+//! guilds. There every user is assigned a variant by hashing the experiment,
+//! channel and user, so they keep getting the same one, in proportion to the
+//! variants' weights. Clients resolve the variant with [Agent::assign] before
+//! assembling the prompt, answer through [Agent::for_variant] and record it
+//! on the interaction. Removing an experiment from the config puts everyone
+//! back on the default character and model from the next message on.
+//!
+//! ```toml
+//! [[experiments]]
+//! name = "cheaper-model"
+//! guilds = ["1234567892"]
+//! variants = [
+//!     { name = "control", weight = 1 },
+//!     { name = "mini", weight = 1, model = "gpt-4o-mini", temperature = 0.3 },
+//! ]
+//! ```
+//!
+//! [Agent::assign]: crate::agent::Agent::assign
+//! [Agent::for_variant]: crate::agent::Agent::for_variant
+
+use serde::Deserialize;
+
+/// One `[[experiments]]` entry of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExperimentConfig {
+    pub name: String,
+    /// Discord guild or Telegram chat ids the experiment runs in.
+    pub guilds: Vec<String>,
+    pub variants: Vec<VariantConfig>,
+}
+
+/// A variant of an experiment. Settings left out are the default's.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VariantConfig {
+    pub name: String,
+    /// Share of the users assigned this variant, relative to the others.
+    pub weight: u32,
+    /// Replaces the character's preamble.
+    #[serde(default)]
+    pub preamble: Option<String>,
+    /// Model requested instead of the default, by name.
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub temperature: Option<f64>,
+}
+
+/// The variant a user gets in a channel.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Assignment {
+    pub experiment: String,
+    pub variant: VariantConfig,
+}
+
+impl ExperimentConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("experiments: name must not be empty".to_string());
+        }
+        let prefix = format!("experiments.{}", self.name);
+        if self.guilds.is_empty() {
+            return Err(format!("{prefix}: guilds must not be empty"));
+        }
+        if self.variants.iter().map(|v| v.weight).sum::<u32>() == 0 {
+            return Err(format!("{prefix}: variants need a weight above 0"));
+        }
+        for (i, variant) in self.variants.iter().enumerate() {
+            if variant.name.trim().is_empty() {
+                return Err(format!("{prefix}: variant names must not be empty"));
+            }
+            if self.variants[..i].iter().any(|v| v.name == variant.name) {
+                return Err(format!(
+                    "{prefix}: variant {:?} is listed twice",
+                    variant.name
+                ));
+            }
+            if let Some(temperature) = variant.temperature {
+                if !(0.0..=2.0).contains(&temperature) {
+                    return Err(format!(
+                        "{prefix}.{}: temperature must be between 0 and 2, got {temperature}",
+                        variant.name
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The variant of `user_id` in `channel_id`, the same on every call.
+    pub fn variant(&self, channel_id: &str, user_id: &str) -> &VariantConfig {
+        let total = self
+            .variants
+            .iter()
+            .map(|v| u64::from(v.weight))
+            .sum::<u64>();
+        let mut bucket = fnv1a(&[&self.name, channel_id, user_id]) % total.max(1);
+        for variant in &self.variants {
+            if bucket < u64::from(variant.weight) {
+                return variant;
+            }
+            bucket -= u64::from(variant.weight);
+        }
+        &self.variants[0]
+    }
+}
+
+/// Checks experiments together: names are unique and a guild runs one
+/// experiment at most, so assignments don't depend on the order of the file.
+pub fn validate(experiments: &[ExperimentConfig]) -> Result<(), String> {
+    for (i, experiment) in experiments.iter().enumerate() {
+        experiment.validate()?;
+        for earlier in &experiments[..i] {
+            if earlier.name == experiment.name {
+                return Err(format!(
+                    "experiments: {:?} is listed twice",
+                    experiment.name
+                ));
+            }
+            if let Some(guild) = experiment
+                .guilds
+                .iter()
+                .find(|g| earlier.guilds.contains(g))
+            {
+                return Err(format!(
+                    "experiments: guild {guild} is in both {:?} and {:?}",
+                    earlier.name, experiment.name
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The variant of `user_id` in `channel_id` of `guild_id`, if an experiment
+/// runs there.
+pub fn assign(
+    experiments: &[ExperimentConfig],
+    guild_id: Option<&str>,
+    channel_id: &str,
+    user_id: &str,
+) -> Option<Assignment> {
+    let guild_id = guild_id?;
+    let experiment = experiments
+        .iter()
+        .find(|experiment| experiment.guilds.iter().any(|g| g == guild_id))?;
+    Some(Assignment {
+        experiment: experiment.name.clone(),
+        variant: experiment.variant(channel_id, user_id).clone(),
+    })
+}
+
+/// FNV-1a over `parts`, separated so `("ab", "c")` and `("a", "bc")` differ.
+/// Unlike [std::hash::DefaultHasher] it is stable across Rust releases, so
+/// users keep their variant after an upgrade.
+fn fnv1a(parts: &[&str]) -> u64 {
+    parts
+        .iter()
+        .flat_map(|part| part.bytes().chain([0xff]))
+        .fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn experiment(weights: &[u32]) -> ExperimentConfig {
+        ExperimentConfig {
+            name: "preamble".to_string(),
+            guilds: vec!["g1".to_string()],
+            variants: weights
+                .iter()
+                .enumerate()
+                .map(|(i, weight)| VariantConfig {
+                    name: format!("v{i}"),
+                    weight: *weight,
+                    preamble: None,
+                    model: None,
+                    temperature: None,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_assignment_is_deterministic_and_weighted() {
+        let experiments = [experiment(&[3, 1])];
+
+        let mut counts = [0; 2];
+        for user in 0..1000 {
+            let user = user.to_string();
+            let assigned = assign(&experiments, Some("g1"), "c1", &user).unwrap();
+            for _ in 0..3 {
+                assert_eq!(
+                    assign(&experiments, Some("g1"), "c1", &user).unwrap(),
+                    assigned
+                );
+            }
+            counts[(assigned.variant.name == "v1") as usize] += 1;
+        }
+        assert!((700..800).contains(&counts[0]), "{counts:?}");
+
+        // A variant without weight gets nobody
+        let experiments = [experiment(&[1, 0])];
+        assert!((0..100).all(|user| {
+            assign(&experiments, Some("g1"), "c1", &user.to_string())
+                .unwrap()
+                .variant
+                .name
+                == "v0"
+        }));
+    }
+
+    #[test]
+    fn test_experiments_only_run_where_configured() {
+        let experiments = [experiment(&[1, 1])];
+        assert!(assign(&experiments, Some("g2"), "c1", "alice").is_none());
+        assert!(assign(&experiments, None, "c1", "alice").is_none());
+        assert!(assign(&[], Some("g1"), "c1", "alice").is_none());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate(&[experiment(&[1, 1])]).is_ok());
+        assert!(validate(&[experiment(&[0, 0])]).is_err());
+        assert!(validate(&[experiment(&[1, 1]), experiment(&[1])]).is_err());
+
+        let mut other = experiment(&[1]);
+        other.name = "model".to_string();
+        assert!(validate(&[experiment(&[1, 1]), other.clone()]).is_err());
+        other.guilds = vec!["g2".to_string()];
+        assert!(validate(&[experiment(&[1, 1]), other]).is_ok());
+
+        let mut duplicate = experiment(&[1, 1]);
+        duplicate.variants[1].name = "v0".to_string();
+        assert!(duplicate.validate().is_err());
+        let mut hot = experiment(&[1]);
+        hot.variants[0].temperature = Some(3.0);
+        assert!(hot.validate().is_err());
+    }
+}
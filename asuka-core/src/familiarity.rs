@@ -0,0 +1,244 @@
+//! Keeps the bot from introducing itself to people who already know it.
+//!
+//! Before a reply, [Agent::familiarity] counts the interactions the bot
+//! answered in the channel and for the user, see
+//! [KnowledgeBase::familiarity](crate::knowledge::KnowledgeBase::familiarity).
+//! The prompt then tells the model what the numbers mean: the
+//! [FAMILIAR_USER](templates::FAMILIAR_USER) template for a user it answered
+//! at least [FamiliarityConfig::familiar_after] times, else
+//! [FAMILIAR_CHANNEL](templates::FAMILIAR_CHANNEL) for a channel it did, and
+//! [NEW_USER](templates::NEW_USER) for someone it never answered. Familiarity
+//! fades after [FamiliarityConfig::forget_after_days] without interactions.
+//!
+//! The thresholds sit next to the templates in the character file, and a
+//! template overridden with an empty text leaves its instruction out:
+//!
+//! ```toml
+//! [familiarity]
+//! familiar_after = 3
+//! forget_after_days = 90
+//!
+//! [templates]
+//! new_user = ""
+//! ```
+//!
+//! The attention stage gets the same numbers, see
+//! [AttentionContext::familiarity](crate::attention::AttentionContext::familiarity).
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use rig::{completion::CompletionModel, embeddings::EmbeddingModel};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::{agent::Agent, knowledge::Familiarity, locale::FormatPreferences, templates};
+
+/// Seconds a looked-up familiarity is reused for the same channel and user.
+const CACHE_SECS: i64 = 60;
+
+/// `[familiarity]` settings of the character file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FamiliarityConfig {
+    /// Interactions from which the bot knows a user or a channel.
+    pub familiar_after: u64,
+    /// Days without an interaction after which the bot is a stranger again.
+    pub forget_after_days: i64,
+}
+
+impl Default for FamiliarityConfig {
+    fn default() -> Self {
+        Self {
+            familiar_after: 3,
+            forget_after_days: 90,
+        }
+    }
+}
+
+impl FamiliarityConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.familiar_after == 0 {
+            return Err("familiar_after must be positive".to_string());
+        }
+        if self.forget_after_days <= 0 {
+            return Err("forget_after_days must be positive".to_string());
+        }
+        Ok(())
+    }
+
+    fn is_familiar(&self, count: u64, last: Option<DateTime<Utc>>, now: DateTime<Utc>) -> bool {
+        count >= self.familiar_after
+            && last.is_some_and(|last| now - last < chrono::Duration::days(self.forget_after_days))
+    }
+}
+
+/// Familiarity looked up recently, by channel and user. Clones share it.
+#[derive(Clone, Default)]
+pub struct FamiliarityCache {
+    entries: Arc<Mutex<HashMap<(String, String), (DateTime<Utc>, Familiarity)>>>,
+}
+
+impl<M: CompletionModel, E: EmbeddingModel> Agent<M, E> {
+    /// How well the bot knows `channel_id` and `account_id`, from the cache
+    /// when it was looked up in the last minute. Look it up before the
+    /// interaction being answered is created, so it isn't counted.
+    pub async fn familiarity(&self, channel_id: &str, account_id: &str) -> Option<Familiarity> {
+        let now = self.clock().now_utc();
+        let key = (channel_id.to_string(), account_id.to_string());
+        {
+            let mut entries = self.familiarity_cache().entries.lock().unwrap();
+            entries.retain(|_, (at, _)| now - *at < chrono::Duration::seconds(CACHE_SECS));
+            if let Some((_, familiarity)) = entries.get(&key) {
+                return Some(familiarity.clone());
+            }
+        }
+
+        match self.knowledge().familiarity(channel_id, account_id).await {
+            Ok(familiarity) => {
+                self.familiarity_cache()
+                    .entries
+                    .lock()
+                    .unwrap()
+                    .insert(key, (now, familiarity.clone()));
+                Some(familiarity)
+            }
+            Err(err) => {
+                error!(?err, "Failed to look up familiarity");
+                None
+            }
+        }
+    }
+
+    /// The instruction telling the model whether to introduce itself, if
+    /// any, see the [module docs](self).
+    pub fn familiarity_instruction(
+        &self,
+        familiarity: &Familiarity,
+        preferences: &FormatPreferences,
+    ) -> Option<String> {
+        let config = &self.character.familiarity;
+        let now = self.clock().now_utc();
+        let instruction =
+            if config.is_familiar(familiarity.user_interactions, familiarity.user_last, now) {
+                self.character.template(
+                    templates::FAMILIAR_USER,
+                    &[
+                        ("count", &familiarity.user_interactions.to_string()),
+                        (
+                            "last",
+                            &familiarity
+                                .user_last
+                                .map(|last| preferences.date(last))
+                                .unwrap_or_default(),
+                        ),
+                    ],
+                )
+            } else if config.is_familiar(
+                familiarity.channel_interactions,
+                familiarity.channel_last,
+                now,
+            ) {
+                self.character.template(
+                    templates::FAMILIAR_CHANNEL,
+                    &[("count", &familiarity.channel_interactions.to_string())],
+                )
+            } else if familiarity.is_first_time() {
+                self.character.template(templates::NEW_USER, &[])
+            } else {
+                String::new()
+            };
+        Some(instruction).filter(|instruction| !instruction.trim().is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        attention::ResponseMode,
+        character::Character,
+        clock::MockClock,
+        test_utils::{self, FakeEmbeddingModel, ScriptedCompletionModel},
+    };
+
+    type TestAgent = Agent<ScriptedCompletionModel, FakeEmbeddingModel>;
+
+    /// The familiarity instruction of a reply to `account_id` in
+    /// `channel_id`.
+    async fn instruction(agent: &TestAgent, channel_id: &str, account_id: &str) -> Option<String> {
+        let familiarity = agent.familiarity(channel_id, account_id).await;
+        agent
+            .clone()
+            .with_familiarity(familiarity)
+            .render_prompt(channel_id, &ResponseMode::BriefAck, "gm")
+            .await
+            .context("familiarity")
+            .map(String::from)
+    }
+
+    #[tokio::test]
+    async fn test_prompt_instruction_follows_familiarity() {
+        let clock = MockClock::new(Utc::now());
+        let knowledge = test_utils::knowledge_base().await;
+        for _ in 0..3 {
+            knowledge
+                .create_interaction("c1".to_string(), "alice".to_string(), Vec::new())
+                .await
+                .unwrap();
+        }
+        let character: Character =
+            toml::from_str("name = \"shinobi\"\npreamble = \"You help with Cartridge.\"").unwrap();
+        let agent = Agent::new(
+            character,
+            ScriptedCompletionModel::new([]),
+            knowledge.clone(),
+        )
+        .with_clock(Arc::new(clock.clone()));
+
+        // Known wherever she asks
+        let familiar = instruction(&agent, "c2", "alice").await.unwrap();
+        assert!(
+            familiar.starts_with("You have spoken with this user 3 times, most recently "),
+            "{familiar}"
+        );
+        assert!(familiar.contains("Do not introduce yourself"));
+        // New, in a channel that knows the bot
+        let channel = instruction(&agent, "c1", "bob").await.unwrap();
+        assert!(channel.starts_with("You have answered 3 times in this channel"));
+        // New everywhere
+        let new = instruction(&agent, "c3", "carol").await.unwrap();
+        assert!(new.starts_with("You have not spoken with this user before."));
+
+        // Looked up again only once the cached counts expire
+        knowledge
+            .create_interaction("c3".to_string(), "carol".to_string(), Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(instruction(&agent, "c3", "carol").await, Some(new));
+        clock.advance(chrono::Duration::seconds(CACHE_SECS + 1));
+        // Neither new nor familiar
+        assert_eq!(instruction(&agent, "c3", "carol").await, None);
+
+        // Forgotten after a long silence
+        clock.advance(chrono::Duration::days(91));
+        assert_eq!(instruction(&agent, "c2", "alice").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_empty_template_leaves_instruction_out() {
+        let character: Character = toml::from_str(
+            "name = \"shinobi\"\npreamble = \"You help with Cartridge.\"\n\n[templates]\nnew_user = \"\"",
+        )
+        .unwrap();
+        let agent = Agent::new(
+            character,
+            ScriptedCompletionModel::new([]),
+            test_utils::knowledge_base().await,
+        );
+        assert_eq!(instruction(&agent, "c1", "carol").await, None);
+    }
+}
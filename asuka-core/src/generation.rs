@@ -0,0 +1,222 @@
+//! Sampling settings of completion requests, set per character in its TOML
+//! and per source on top, e.g. a precise support character next to a more
+//! playful one:
+//!
+//! ```toml
+//! [generation]
+//! temperature = 0.2
+//! max_tokens = 800
+//!
+//! [source_generation.telegram]
+//! temperature = 0.4
+//! ```
+//!
+//! Internal calls override them where they need to, such as the attention
+//! deciding at temperature 0. Settings left out are the provider's defaults.
+//! Temperature and `max_tokens` travel in rig's request; the others as
+//! additional parameters, which providers drop when the model doesn't take
+//! them, see [crate::providers::CompletionModel::with_unsupported_params].
+
+use rig::{agent::AgentBuilder, completion::CompletionModel};
+use serde::{Deserialize, Serialize};
+
+/// Most stop sequences OpenAI accepts in a request.
+pub const MAX_STOP_SEQUENCES: usize = 4;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GenerationParams {
+    /// Between 0 and 2, lower being more deterministic.
+    pub temperature: Option<f64>,
+    /// Longest completion, in tokens.
+    pub max_tokens: Option<u64>,
+    /// Nucleus sampling mass, above 0 and at most 1.
+    pub top_p: Option<f64>,
+    /// Sequences the completion ends before.
+    pub stop: Vec<String>,
+    /// Between -2 and 2, positive values discouraging repetition.
+    pub frequency_penalty: Option<f64>,
+}
+
+impl GenerationParams {
+    /// Settings for calls whose answer should only depend on the input, such
+    /// as classifications.
+    pub fn deterministic() -> Self {
+        Self {
+            temperature: Some(0.0),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_temperature(mut self, temperature: f64) -> Self {
+        self.temperature = Some(temperature);
+        self
+    }
+
+    pub fn with_max_tokens(mut self, max_tokens: u64) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(temperature) = self.temperature {
+            if !(0.0..=2.0).contains(&temperature) {
+                return Err(format!(
+                    "temperature must be between 0 and 2, got {temperature}"
+                ));
+            }
+        }
+        if self.max_tokens == Some(0) {
+            return Err("max_tokens must be positive".to_string());
+        }
+        if let Some(top_p) = self.top_p {
+            if !(top_p > 0.0 && top_p <= 1.0) {
+                return Err(format!("top_p must be above 0 and at most 1, got {top_p}"));
+            }
+        }
+        if self.stop.len() > MAX_STOP_SEQUENCES {
+            return Err(format!(
+                "stop takes at most {MAX_STOP_SEQUENCES} sequences, got {}",
+                self.stop.len()
+            ));
+        }
+        if self.stop.iter().any(|stop| stop.is_empty()) {
+            return Err("stop sequences must not be empty".to_string());
+        }
+        if let Some(penalty) = self.frequency_penalty {
+            if !(-2.0..=2.0).contains(&penalty) {
+                return Err(format!(
+                    "frequency_penalty must be between -2 and 2, got {penalty}"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// These settings with the ones `overrides` sets replacing them.
+    pub fn merge(&self, overrides: &GenerationParams) -> Self {
+        Self {
+            temperature: overrides.temperature.or(self.temperature),
+            max_tokens: overrides.max_tokens.or(self.max_tokens),
+            top_p: overrides.top_p.or(self.top_p),
+            stop: if overrides.stop.is_empty() {
+                self.stop.clone()
+            } else {
+                overrides.stop.clone()
+            },
+            frequency_penalty: overrides.frequency_penalty.or(self.frequency_penalty),
+        }
+    }
+
+    /// The settings rig's request has no field for, by their OpenAI names.
+    pub fn additional_params(&self) -> serde_json::Map<String, serde_json::Value> {
+        let mut params = serde_json::Map::new();
+        if let Some(top_p) = self.top_p {
+            params.insert("top_p".to_string(), top_p.into());
+        }
+        if !self.stop.is_empty() {
+            params.insert("stop".to_string(), self.stop.clone().into());
+        }
+        if let Some(penalty) = self.frequency_penalty {
+            params.insert("frequency_penalty".to_string(), penalty.into());
+        }
+        params
+    }
+
+    /// `builder` sending these settings, along with `extra` additional
+    /// parameters such as a model name.
+    pub fn apply<M: CompletionModel>(
+        &self,
+        builder: AgentBuilder<M>,
+        extra: serde_json::Map<String, serde_json::Value>,
+    ) -> AgentBuilder<M> {
+        let builder = match self.temperature {
+            Some(temperature) => builder.temperature(temperature),
+            None => builder,
+        };
+        let builder = match self.max_tokens {
+            Some(max_tokens) => builder.max_tokens(max_tokens),
+            None => builder,
+        };
+        let mut params = self.additional_params();
+        params.extend(extra);
+        if params.is_empty() {
+            builder
+        } else {
+            builder.additional_params(serde_json::Value::Object(params))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let valid = GenerationParams {
+            temperature: Some(0.2),
+            max_tokens: Some(800),
+            top_p: Some(1.0),
+            stop: vec!["\n\n".to_string()],
+            frequency_penalty: Some(-0.5),
+        };
+        assert_eq!(valid.validate(), Ok(()));
+        assert_eq!(GenerationParams::default().validate(), Ok(()));
+
+        for invalid in [
+            GenerationParams {
+                temperature: Some(2.5),
+                ..Default::default()
+            },
+            GenerationParams {
+                max_tokens: Some(0),
+                ..Default::default()
+            },
+            GenerationParams {
+                top_p: Some(0.0),
+                ..Default::default()
+            },
+            GenerationParams {
+                stop: vec!["a", "b", "c", "d", "e"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect(),
+                ..Default::default()
+            },
+            GenerationParams {
+                stop: vec![String::new()],
+                ..Default::default()
+            },
+            GenerationParams {
+                frequency_penalty: Some(3.0),
+                ..Default::default()
+            },
+        ] {
+            assert!(invalid.validate().is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn test_merge_prefers_overrides() {
+        let character = GenerationParams {
+            temperature: Some(0.9),
+            max_tokens: Some(800),
+            stop: vec!["END".to_string()],
+            ..Default::default()
+        };
+
+        let merged = character.merge(&GenerationParams::deterministic().with_max_tokens(60));
+
+        assert_eq!(
+            merged,
+            GenerationParams {
+                temperature: Some(0.0),
+                max_tokens: Some(60),
+                stop: vec!["END".to_string()],
+                ..Default::default()
+            }
+        );
+        assert_eq!(character.merge(&GenerationParams::default()), character);
+    }
+}
@@ -0,0 +1,337 @@
+//! Channel history as prompt text, in as few tokens as still reads well:
+//! consecutive messages of one author are attributed once, times only show
+//! as "— 2 hours later —" after a quiet stretch, bare emoji and reactions are
+//! left out and long messages are cut short.
+//!
+//! Each source can have its own [HistoryFormat]. By default tweets are not
+//! merged, since each tweet of a thread is a post of its own.
+//!
+//! ```toml
+//! [history]
+//! gap_minutes = 60
+//! max_message_chars = 500
+//!
+//! [history.sources.twitter]
+//! merge_consecutive = false
+//! ```
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::{
+    escape,
+    knowledge::{Message, Source},
+};
+
+/// How reactions are stored in history, see
+/// [ReplyOutcome::history_content](crate::clients::reactions::ReplyOutcome::history_content).
+const REACTION_RECORD: &str = "[reacted with ";
+
+/// Rough token count of `text`, at four characters a token.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// How the history of one source is formatted.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryFormat {
+    /// Attribute consecutive messages of one author once.
+    pub merge_consecutive: bool,
+    /// Minutes between two messages from which the gap is shown, `0` for
+    /// never.
+    pub gap_minutes: u32,
+    /// Leave out messages of only emoji or punctuation, and reactions.
+    pub elide_reactions: bool,
+    /// Characters a message is cut to, ending with `…`.
+    pub max_message_chars: usize,
+}
+
+impl Default for HistoryFormat {
+    fn default() -> Self {
+        Self {
+            merge_consecutive: true,
+            gap_minutes: 60,
+            elide_reactions: true,
+            max_message_chars: 500,
+        }
+    }
+}
+
+/// History text and what it is estimated to cost, see [estimate_tokens].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormattedHistory {
+    pub text: String,
+    /// Tokens of the messages one per line as `author: content`.
+    pub tokens_before: usize,
+    pub tokens_after: usize,
+}
+
+impl FormattedHistory {
+    pub fn tokens_saved(&self) -> usize {
+        self.tokens_before.saturating_sub(self.tokens_after)
+    }
+}
+
+impl HistoryFormat {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_message_chars == 0 {
+            return Err("max_message_chars must be above 0".to_string());
+        }
+        Ok(())
+    }
+
+    /// `messages`, oldest first, as prompt text.
+    pub fn format(&self, messages: &[Message]) -> FormattedHistory {
+        let plain = messages
+            .iter()
+            .map(|message| format!("{}: {}", message.source_id, message.content))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let mut lines = Vec::new();
+        let mut previous: Option<&Message> = None;
+        for message in messages {
+            if self.elide_reactions && is_reaction(&message.content) {
+                continue;
+            }
+            let content = escape::inline(&truncate(message.content.trim(), self.max_message_chars));
+            let gap =
+                previous.and_then(|previous| self.gap(previous.created_at, message.created_at));
+            let same_author =
+                previous.is_some_and(|previous| previous.source_id == message.source_id);
+            match gap {
+                Some(gap) => lines.push(gap),
+                None if self.merge_consecutive && same_author => {
+                    lines.push(format!("  {content}"));
+                    previous = Some(message);
+                    continue;
+                }
+                None => {}
+            }
+            lines.push(format!("{}: {content}", message.source_id));
+            previous = Some(message);
+        }
+
+        let text = lines.join("\n");
+        FormattedHistory {
+            tokens_before: estimate_tokens(&plain),
+            tokens_after: estimate_tokens(&text),
+            text,
+        }
+    }
+
+    /// Marker for the time between two messages, when long enough to show.
+    fn gap(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Option<String> {
+        let minutes = (to - from).num_minutes();
+        if self.gap_minutes == 0 || minutes < i64::from(self.gap_minutes) {
+            return None;
+        }
+        let (count, unit) = match minutes {
+            ..=59 => (minutes, "minute"),
+            60..=2879 => (minutes / 60, "hour"),
+            _ => (minutes / 1440, "day"),
+        };
+        let plural = if count == 1 { "" } else { "s" };
+        Some(format!("— {count} {unit}{plural} later —"))
+    }
+}
+
+/// `[history]` settings of the config file: the [HistoryFormat] of every
+/// source, besides those in `sources`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    pub merge_consecutive: bool,
+    pub gap_minutes: u32,
+    pub elide_reactions: bool,
+    pub max_message_chars: usize,
+    /// Formats by source, such as `twitter`, used instead of the one above.
+    /// Setting this replaces the default format of tweets.
+    pub sources: HashMap<String, HistoryFormat>,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        let format = HistoryFormat::default();
+        Self {
+            merge_consecutive: format.merge_consecutive,
+            gap_minutes: format.gap_minutes,
+            elide_reactions: format.elide_reactions,
+            max_message_chars: format.max_message_chars,
+            sources: HashMap::from([(
+                Source::Twitter.as_str().to_string(),
+                HistoryFormat {
+                    merge_consecutive: false,
+                    ..format
+                },
+            )]),
+        }
+    }
+}
+
+impl HistoryConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        self.format_for(&Source::Discord)
+            .validate()
+            .map_err(|err| format!("history.{err}"))?;
+        for (name, format) in &self.sources {
+            if Source::from_str(name).is_none() {
+                return Err(format!("history.sources: unknown source {name:?}"));
+            }
+            format
+                .validate()
+                .map_err(|err| format!("history.sources.{name}.{err}"))?;
+        }
+        Ok(())
+    }
+
+    /// The format of `source`'s history.
+    pub fn format_for(&self, source: &Source) -> HistoryFormat {
+        self.sources
+            .get(source.as_str())
+            .cloned()
+            .unwrap_or_else(|| HistoryFormat {
+                merge_consecutive: self.merge_consecutive,
+                gap_minutes: self.gap_minutes,
+                elide_reactions: self.elide_reactions,
+                max_message_chars: self.max_message_chars,
+            })
+    }
+}
+
+/// Whether `content` says nothing in words, like "🔥🔥" or a stored
+/// reaction.
+fn is_reaction(content: &str) -> bool {
+    let content = content.trim();
+    (content.starts_with(REACTION_RECORD) && content.ends_with(']'))
+        || !content.chars().any(char::is_alphanumeric)
+}
+
+/// `text` cut to `max_chars` characters, ending with `…` when it was cut.
+pub(crate) fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}…", text[..end].trim_end()),
+        None => text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::knowledge::ChannelType;
+
+    fn at(minutes: i64) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2025-01-15T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+            + chrono::Duration::minutes(minutes)
+    }
+
+    fn message(author: &str, minutes: i64, content: &str) -> Message {
+        Message {
+            id: format!("{author}-{minutes}"),
+            source: Source::Discord,
+            source_id: author.to_string(),
+            channel_type: ChannelType::Text,
+            channel_id: "c1".to_string(),
+            account_id: author.to_string(),
+            role: "user".to_string(),
+            content: content.to_string(),
+            created_at: at(minutes),
+        }
+    }
+
+    fn history() -> Vec<Message> {
+        vec![
+            message("alice", 0, "the indexer is lagging again"),
+            message("alice", 1, "since the last deploy"),
+            message("bob", 2, "🔥🔥"),
+            message("shinobi", 3, "[reacted with 👍]"),
+            message("bob", 3, "same here\nrestarting did not help"),
+            message("alice", 150, "fixed it, it was the RPC"),
+            message("alice", 151, &"x".repeat(30)),
+        ]
+    }
+
+    #[test]
+    fn test_format() {
+        let format = HistoryFormat {
+            max_message_chars: 25,
+            ..Default::default()
+        };
+        assert_eq!(
+            format.format(&history()).text,
+            "alice: the indexer is lagging ag…
+  since the last deploy
+bob: same here
+  restarting did…
+— 2 hours later —
+alice: fixed it, it was the RPC
+  xxxxxxxxxxxxxxxxxxxxxxxxx…"
+        );
+    }
+
+    #[test]
+    fn test_format_without_options() {
+        let format = HistoryFormat {
+            merge_consecutive: false,
+            gap_minutes: 0,
+            elide_reactions: false,
+            max_message_chars: 1000,
+        };
+        let history = history();
+        let formatted = format.format(&history[..4]);
+        assert_eq!(
+            formatted.text,
+            "alice: the indexer is lagging again\nalice: since the last deploy\nbob: 🔥🔥\nshinobi: [reacted with 👍]"
+        );
+        assert_eq!(formatted.tokens_before, formatted.tokens_after);
+        assert_eq!(formatted.tokens_saved(), 0);
+    }
+
+    #[test]
+    fn test_estimated_savings() {
+        let formatted = HistoryFormat::default().format(&history());
+
+        // 207 characters as plain lines, 183 formatted
+        assert_eq!(formatted.tokens_before, 52);
+        assert_eq!(formatted.tokens_after, 46);
+        assert_eq!(formatted.tokens_saved(), 6);
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("abcde"), 2);
+        assert_eq!(estimate_tokens("🔥🔥🔥🔥"), 1);
+    }
+
+    #[test]
+    fn test_gaps() {
+        let format = HistoryFormat::default();
+        assert_eq!(format.gap(at(0), at(59)), None);
+        assert_eq!(format.gap(at(0), at(60)).unwrap(), "— 1 hour later —");
+        assert_eq!(format.gap(at(0), at(3 * 1440)).unwrap(), "— 3 days later —");
+        let format = HistoryFormat {
+            gap_minutes: 15,
+            ..Default::default()
+        };
+        assert_eq!(format.gap(at(0), at(20)).unwrap(), "— 20 minutes later —");
+    }
+
+    #[test]
+    fn test_config() {
+        let config: HistoryConfig = toml::from_str("gap_minutes = 30").unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.format_for(&Source::Discord).gap_minutes, 30);
+        assert!(!config.format_for(&Source::Twitter).merge_consecutive);
+
+        for invalid in [
+            "max_message_chars = 0",
+            "[sources.slack]\ngap_minutes = 5",
+            "[sources.telegram]\nmax_message_chars = 0",
+        ] {
+            let config: HistoryConfig = toml::from_str(invalid).unwrap();
+            assert!(config.validate().is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,479 @@
+//! Post-processing of generated replies before they are chunked and sent,
+//! for output tweaks that should not need a client of their own.
+//!
+//! Hooks run in registration order, each on the output of the previous one.
+//! A hook that panics is skipped and the reply continues with the text it was
+//! given.
+
+use std::{
+    collections::HashMap,
+    panic::AssertUnwindSafe,
+    sync::{Arc, OnceLock},
+};
+
+use async_trait::async_trait;
+use futures::FutureExt;
+use regex::Regex;
+use rig::embeddings::EmbeddingModel;
+use tracing::{debug, error};
+
+#[cfg(feature = "discord")]
+use crate::clients::mentions;
+use crate::{
+    attachments::Attachment,
+    knowledge::{ChannelType, KnowledgeBase, Message, Source},
+    markdown::{MarkdownConfig, MarkdownProfile},
+};
+
+/// Channel setting holding the disclaimer [ChannelDisclaimer] appends.
+pub const DISCLAIMER_SETTING: &str = "disclaimer";
+
+/// [ResponseDraft::metadata] key asking the client to send the text with a
+/// parse mode, e.g. `"HTML"` on Telegram.
+pub const PARSE_MODE: &str = "parse_mode";
+
+/// A reply on its way out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResponseDraft {
+    pub text: String,
+    /// Where the reply is sent.
+    pub source: Source,
+    /// Hints for the client sending the reply, see [PARSE_MODE].
+    pub metadata: HashMap<String, String>,
+    /// Files sent after the text, see [crate::attachments].
+    pub attachments: Vec<Attachment>,
+}
+
+impl ResponseDraft {
+    pub fn new(text: impl Into<String>, source: Source) -> Self {
+        Self {
+            text: text.into(),
+            source,
+            metadata: HashMap::new(),
+            attachments: Vec::new(),
+        }
+    }
+}
+
+/// The message being answered.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageContext {
+    pub source: Source,
+    pub channel_id: String,
+    pub channel_type: ChannelType,
+    pub account_id: String,
+}
+
+impl From<&Message> for MessageContext {
+    fn from(message: &Message) -> Self {
+        Self {
+            source: message.source.clone(),
+            channel_id: message.channel_id.clone(),
+            channel_type: message.channel_type.clone(),
+            account_id: message.account_id.clone(),
+        }
+    }
+}
+
+#[async_trait]
+pub trait ResponseHook: Send + Sync {
+    async fn process(&self, resp: ResponseDraft, ctx: &MessageContext) -> ResponseDraft;
+
+    /// Whether the hook runs for replies sent to `source`.
+    fn applies_to(&self, _source: &Source) -> bool {
+        true
+    }
+
+    /// Name used in logs.
+    fn name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
+}
+
+/// Ordered chain of [ResponseHook]s.
+#[derive(Clone, Default)]
+pub struct ResponseHooks {
+    hooks: Vec<Arc<dyn ResponseHook>>,
+}
+
+impl ResponseHooks {
+    pub fn push(&mut self, hook: Arc<dyn ResponseHook>) {
+        self.hooks.push(hook);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    pub async fn run(&self, mut draft: ResponseDraft, ctx: &MessageContext) -> ResponseDraft {
+        for hook in &self.hooks {
+            if !hook.applies_to(&draft.source) {
+                continue;
+            }
+            match AssertUnwindSafe(hook.process(draft.clone(), ctx))
+                .catch_unwind()
+                .await
+            {
+                Ok(processed) => draft = processed,
+                Err(_) => error!(hook = hook.name(), "Response hook panicked, skipping it"),
+            }
+        }
+        debug!(hooks = self.hooks.len(), "Ran response hooks");
+        draft
+    }
+}
+
+/// Appends the channel's [DISCLAIMER_SETTING], if one is set, to every reply
+/// in it.
+pub struct ChannelDisclaimer<E: EmbeddingModel + 'static> {
+    knowledge: KnowledgeBase<E>,
+}
+
+impl<E: EmbeddingModel + 'static> ChannelDisclaimer<E> {
+    pub fn new(knowledge: KnowledgeBase<E>) -> Self {
+        Self { knowledge }
+    }
+}
+
+#[async_trait]
+impl<E: EmbeddingModel + 'static> ResponseHook for ChannelDisclaimer<E> {
+    async fn process(&self, mut resp: ResponseDraft, ctx: &MessageContext) -> ResponseDraft {
+        match self
+            .knowledge
+            .channel_setting(&ctx.channel_id, DISCLAIMER_SETTING)
+            .await
+        {
+            Ok(Some(disclaimer)) if !resp.text.trim_end().ends_with(disclaimer.trim()) => {
+                resp.text = format!("{}\n\n{}", resp.text.trim_end(), disclaimer.trim());
+            }
+            Ok(_) => {}
+            Err(err) => error!(?err, "Failed to load channel disclaimer"),
+        }
+        resp
+    }
+}
+
+/// Normalizes the markdown of replies to what their platform renders, see
+/// [crate::markdown]. Register it before [TelegramFormat], which expects the
+/// normalized markdown.
+pub struct MarkdownGuardrails {
+    config: MarkdownConfig,
+}
+
+impl MarkdownGuardrails {
+    pub fn new(config: MarkdownConfig) -> Self {
+        Self { config }
+    }
+}
+
+#[async_trait]
+impl ResponseHook for MarkdownGuardrails {
+    async fn process(&self, mut resp: ResponseDraft, _ctx: &MessageContext) -> ResponseDraft {
+        resp.text = self.config.normalize(&resp.text, &resp.source);
+        resp
+    }
+
+    fn applies_to(&self, source: &Source) -> bool {
+        self.config.profile(source) != MarkdownProfile::Off
+    }
+}
+
+/// Rewrites markdown into the HTML subset Telegram renders, since Telegram
+/// shows markdown headings and links as plain text. Headings become bold.
+pub struct TelegramFormat;
+
+impl TelegramFormat {
+    fn escape(text: &str) -> String {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    }
+
+    pub fn convert(text: &str) -> String {
+        static PATTERNS: OnceLock<[Regex; 3]> = OnceLock::new();
+        let [heading, bold, link] = PATTERNS.get_or_init(|| {
+            [
+                r"^#{1,6}\s+(.+)$",
+                r"\*\*(.+?)\*\*",
+                r"\[([^\]]+)\]\(([^)\s]+)\)",
+            ]
+            .map(|pattern| Regex::new(pattern).unwrap())
+        });
+
+        // Text between backticks is only escaped.
+        let convert_line = |line: &str| -> String {
+            if let Some(captures) = heading.captures(line.trim()) {
+                return format!("<b>{}</b>", Self::escape(&captures[1]));
+            }
+            line.split('`')
+                .enumerate()
+                .map(|(i, part)| {
+                    let part = Self::escape(part);
+                    if i % 2 == 1 {
+                        return format!("<code>{part}</code>");
+                    }
+                    let part = bold.replace_all(&part, "<b>$1</b>");
+                    link.replace_all(&part, "<a href=\"$2\">$1</a>")
+                        .into_owned()
+                })
+                .collect()
+        };
+
+        let mut lines = Vec::new();
+        let mut code_block: Option<Vec<String>> = None;
+
+        for line in text.lines() {
+            if line.trim_start().starts_with("```") {
+                match code_block.take() {
+                    Some(code) => lines.push(format!("<pre>{}</pre>", code.join("\n"))),
+                    None => code_block = Some(Vec::new()),
+                }
+                continue;
+            }
+            match &mut code_block {
+                Some(code) => code.push(Self::escape(line)),
+                None => lines.push(convert_line(line)),
+            }
+        }
+        // An unclosed block still reads as code.
+        if let Some(code) = code_block {
+            lines.push(format!("<pre>{}</pre>", code.join("\n")));
+        }
+        lines.join("\n")
+    }
+}
+
+#[async_trait]
+impl ResponseHook for TelegramFormat {
+    async fn process(&self, mut resp: ResponseDraft, _ctx: &MessageContext) -> ResponseDraft {
+        resp.text = Self::convert(&resp.text);
+        resp.metadata
+            .insert(PARSE_MODE.to_string(), "HTML".to_string());
+        resp
+    }
+
+    fn applies_to(&self, source: &Source) -> bool {
+        *source == Source::Telegram
+    }
+}
+
+/// Wraps bare URLs in `<>` so Discord does not unfurl them into embeds.
+/// Links in markdown link syntax are left alone.
+pub struct SuppressEmbeds;
+
+impl SuppressEmbeds {
+    pub fn wrap_urls(text: &str) -> String {
+        static URL: OnceLock<Regex> = OnceLock::new();
+        let url = URL.get_or_init(|| Regex::new(r"https?://[^\s<>]+").unwrap());
+        let mut out = String::with_capacity(text.len());
+        let mut last = 0;
+
+        for found in url.find_iter(text) {
+            let before = &text[..found.start()];
+            if before.ends_with('<') || before.ends_with("](") {
+                continue;
+            }
+            // Punctuation ending a sentence is not part of the URL.
+            let url = found
+                .as_str()
+                .trim_end_matches(|c: char| ".,;:!?'\"".contains(c));
+            let url = match url.strip_suffix(')') {
+                Some(stripped) if !url.contains('(') => stripped,
+                _ => url,
+            };
+            out.push_str(&text[last..found.start()]);
+            out.push('<');
+            out.push_str(url);
+            out.push('>');
+            last = found.start() + url.len();
+        }
+        out.push_str(&text[last..]);
+        out
+    }
+}
+
+#[async_trait]
+impl ResponseHook for SuppressEmbeds {
+    async fn process(&self, mut resp: ResponseDraft, _ctx: &MessageContext) -> ResponseDraft {
+        resp.text = Self::wrap_urls(&resp.text);
+        resp
+    }
+
+    fn applies_to(&self, source: &Source) -> bool {
+        *source == Source::Discord
+    }
+}
+
+/// Breaks `@everyone` and `@here` in Discord replies, see
+/// [mentions::sanitize]. The Discord client registers it on its agent.
+#[cfg(feature = "discord")]
+pub struct SuppressMassMentions;
+
+#[cfg(feature = "discord")]
+#[async_trait]
+impl ResponseHook for SuppressMassMentions {
+    async fn process(&self, mut resp: ResponseDraft, _ctx: &MessageContext) -> ResponseDraft {
+        resp.text = mentions::sanitize(&resp.text);
+        resp
+    }
+
+    fn applies_to(&self, source: &Source) -> bool {
+        *source == Source::Discord
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    fn context(source: Source) -> MessageContext {
+        MessageContext {
+            source,
+            channel_id: "c1".to_string(),
+            channel_type: ChannelType::Text,
+            account_id: "alice".to_string(),
+        }
+    }
+
+    struct Append(&'static str);
+
+    #[async_trait]
+    impl ResponseHook for Append {
+        async fn process(&self, mut resp: ResponseDraft, _ctx: &MessageContext) -> ResponseDraft {
+            resp.text.push_str(self.0);
+            resp
+        }
+    }
+
+    struct Panics;
+
+    #[async_trait]
+    impl ResponseHook for Panics {
+        async fn process(&self, _resp: ResponseDraft, _ctx: &MessageContext) -> ResponseDraft {
+            panic!("hook failed");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hooks_compose_in_order() {
+        let mut hooks = ResponseHooks::default();
+        hooks.push(Arc::new(Append(" one")));
+        hooks.push(Arc::new(Panics));
+        hooks.push(Arc::new(Append(" two")));
+        hooks.push(Arc::new(SuppressEmbeds));
+        hooks.push(Arc::new(Append(" https://cartridge.gg")));
+
+        let draft = hooks
+            .run(
+                ResponseDraft::new("see https://docs.cartridge.gg.", Source::Discord),
+                &context(Source::Discord),
+            )
+            .await;
+        assert_eq!(
+            draft.text,
+            "see <https://docs.cartridge.gg>. one two https://cartridge.gg"
+        );
+
+        // Source filters skip the Discord-only hook.
+        let draft = hooks
+            .run(
+                ResponseDraft::new("see https://docs.cartridge.gg", Source::Telegram),
+                &context(Source::Telegram),
+            )
+            .await;
+        assert_eq!(
+            draft.text,
+            "see https://docs.cartridge.gg one two https://cartridge.gg"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_channel_disclaimer() {
+        let knowledge = test_utils::knowledge_base().await;
+        let hook = ChannelDisclaimer::new(knowledge.clone());
+        let ctx = context(Source::Discord);
+
+        let draft = hook
+            .process(ResponseDraft::new("Buy low.", Source::Discord), &ctx)
+            .await;
+        assert_eq!(draft.text, "Buy low.");
+
+        knowledge
+            .set_channel_setting("c1", DISCLAIMER_SETTING, Some("Not financial advice."))
+            .await
+            .unwrap();
+        let draft = hook
+            .process(ResponseDraft::new("Buy low.\n", Source::Discord), &ctx)
+            .await;
+        assert_eq!(draft.text, "Buy low.\n\nNot financial advice.");
+
+        // A reply that already ends with it is left alone.
+        let draft = hook.process(draft.clone(), &ctx).await;
+        assert_eq!(draft.text, "Buy low.\n\nNot financial advice.");
+    }
+
+    #[test]
+    fn test_telegram_format() {
+        let text = "## Setup\nRun `a<b` then see **[docs](https://docs.cartridge.gg)** & more\n```rust\nlet x = 1 < 2;\n```";
+        assert_eq!(
+            TelegramFormat::convert(text),
+            "<b>Setup</b>\nRun <code>a&lt;b</code> then see <b><a href=\"https://docs.cartridge.gg\">docs</a></b> &amp; more\n<pre>let x = 1 &lt; 2;</pre>"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_markdown_guardrails_before_telegram_format() {
+        let mut hooks = ResponseHooks::default();
+        hooks.push(Arc::new(MarkdownGuardrails::new(MarkdownConfig::default())));
+        hooks.push(Arc::new(TelegramFormat));
+        let text = "### Fees\n\n| Network | Fee |\n|---|---|\n| Sepolia | free |";
+
+        let draft = hooks
+            .run(
+                ResponseDraft::new(text, Source::Telegram),
+                &context(Source::Telegram),
+            )
+            .await;
+        assert_eq!(
+            draft.text,
+            "<b>Fees</b>\n\n<pre>Network  Fee\n-------  ----\nSepolia  free</pre>"
+        );
+    }
+
+    #[test]
+    fn test_wrap_urls() {
+        assert_eq!(
+            SuppressEmbeds::wrap_urls("see https://a.io/x, or (https://b.io/y) and <https://c.io>"),
+            "see <https://a.io/x>, or (<https://b.io/y>) and <https://c.io>"
+        );
+        assert_eq!(
+            SuppressEmbeds::wrap_urls("[docs](https://a.io/wiki_(page))"),
+            "[docs](https://a.io/wiki_(page))"
+        );
+    }
+
+    #[cfg(feature = "discord")]
+    #[tokio::test]
+    async fn test_suppress_mass_mentions() {
+        let mut hooks = ResponseHooks::default();
+        hooks.push(Arc::new(SuppressMassMentions));
+        let text = "It pings everyone: @everyone";
+
+        let draft = hooks
+            .run(
+                ResponseDraft::new(text, Source::Discord),
+                &context(Source::Discord),
+            )
+            .await;
+        assert_eq!(draft.text, "It pings everyone: @\u{200B}everyone");
+
+        let draft = hooks
+            .run(
+                ResponseDraft::new(text, Source::Telegram),
+                &context(Source::Telegram),
+            )
+            .await;
+        assert_eq!(draft.text, text);
+    }
+}
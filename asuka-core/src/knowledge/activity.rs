@@ -0,0 +1,240 @@
+//! Counts of what the agent did over a period, for the operator digest, see
+//! [crate::digest].
+
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+
+use super::store::KnowledgeBase;
+
+/// Indexes the aggregates range over. They lead with `agent_id`, which
+/// [super::namespaces] adds to older databases, so they are created after it.
+const INDEXES: &str = "
+    CREATE INDEX IF NOT EXISTS idx_messages_agent_created ON messages(agent_id, created_at);
+    CREATE INDEX IF NOT EXISTS idx_interactions_agent_created
+        ON interactions(agent_id, created_at);
+    CREATE INDEX IF NOT EXISTS idx_tool_calls_created ON tool_calls(created_at);
+    CREATE INDEX IF NOT EXISTS idx_escalations_created ON escalations(agent_id, created_at);
+";
+
+pub(super) fn migrate(conn: &mut rusqlite::Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(INDEXES)
+}
+
+/// What the agent did between two points in time.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Activity {
+    /// Messages received from users, by source.
+    pub messages: Vec<(String, i64)>,
+    /// Received messages that were not part of any answered interaction,
+    /// i.e. ignored, reacted to, or handled as a command.
+    pub unanswered: i64,
+    /// Replies the agent sent.
+    pub responses: i64,
+    /// Tool calls by tool, most used first.
+    pub tool_calls: Vec<ToolActivity>,
+    /// Answers withheld for low confidence.
+    pub declined: i64,
+    pub escalations: i64,
+    /// Messages purged by retention, see
+    /// [KnowledgeBase::apply_retention].
+    pub purged: i64,
+    /// Messages ignored for being over the [rate limit](crate::rate_limit).
+    pub rate_limited: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ToolActivity {
+    pub tool_name: String,
+    pub calls: i64,
+    pub failures: i64,
+}
+
+impl Activity {
+    pub fn received(&self) -> i64 {
+        self.messages.iter().map(|(_, count)| count).sum()
+    }
+
+    /// Share of received messages that went unanswered, `0.0` when there
+    /// were none.
+    pub fn unanswered_rate(&self) -> f64 {
+        match self.received() {
+            0 => 0.0,
+            received => self.unanswered as f64 / received as f64,
+        }
+    }
+
+    /// Failures by category, largest first: failed calls of each tool and
+    /// declined answers.
+    pub fn errors(&self) -> Vec<(String, i64)> {
+        let mut errors = self
+            .tool_calls
+            .iter()
+            .filter(|tool| tool.failures > 0)
+            .map(|tool| (format!("tool {}", tool.tool_name), tool.failures))
+            .collect::<Vec<_>>();
+        if self.declined > 0 {
+            errors.push(("declined answers".to_string(), self.declined));
+        }
+        errors.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        errors
+    }
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Activity from `since` up to `until`, excluded.
+    pub async fn activity(
+        &self,
+        since: chrono::DateTime<chrono::Utc>,
+        until: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Activity, SqliteError> {
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                // Messages, escalations, purges and rate limit events store
+                // RFC 3339 timestamps, the other tables SQLite's
+                // CURRENT_TIMESTAMP format
+                let (since_rfc, until_rfc) = (since.to_rfc3339(), until.to_rfc3339());
+                let (since_sql, until_sql) = (
+                    since.format("%Y-%m-%d %H:%M:%S").to_string(),
+                    until.format("%Y-%m-%d %H:%M:%S").to_string(),
+                );
+
+                let mut activity = Activity::default();
+                let mut stmt = conn.prepare(
+                    "SELECT m.source, COUNT(*),
+                         SUM(NOT EXISTS (
+                             SELECT 1 FROM interaction_messages im WHERE im.message_id = m.id
+                         ))
+                     FROM messages m
+                     WHERE m.agent_id = ?1 AND m.created_at >= ?2 AND m.created_at < ?3
+                         AND m.role = 'user'
+                     GROUP BY m.source
+                     ORDER BY m.source",
+                )?;
+                let mut rows = stmt.query(rusqlite::params![namespace, since_rfc, until_rfc])?;
+                while let Some(row) = rows.next()? {
+                    activity.messages.push((row.get(0)?, row.get(1)?));
+                    activity.unanswered += row.get::<_, i64>(2)?;
+                }
+
+                activity.responses = conn.query_row(
+                    "SELECT COUNT(*) FROM messages
+                     WHERE agent_id = ?1 AND created_at >= ?2 AND created_at < ?3
+                         AND role = 'assistant'",
+                    rusqlite::params![namespace, since_rfc, until_rfc],
+                    |row| row.get(0),
+                )?;
+
+                let mut stmt = conn.prepare(
+                    "SELECT t.tool_name, COUNT(*), COUNT(t.error)
+                     FROM tool_calls t
+                     JOIN interactions i ON i.id = t.interaction_id
+                     WHERE t.created_at >= ?2 AND t.created_at < ?3 AND i.agent_id = ?1
+                     GROUP BY t.tool_name
+                     ORDER BY COUNT(*) DESC, t.tool_name",
+                )?;
+                activity.tool_calls = stmt
+                    .query_map(rusqlite::params![namespace, since_sql, until_sql], |row| {
+                        Ok(ToolActivity {
+                            tool_name: row.get(0)?,
+                            calls: row.get(1)?,
+                            failures: row.get(2)?,
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                activity.declined = conn.query_row(
+                    "SELECT COUNT(*) FROM interactions i
+                     JOIN interaction_confidence c ON c.interaction_id = i.id
+                     WHERE i.agent_id = ?1 AND i.created_at >= ?2 AND i.created_at < ?3
+                         AND c.outcome = 'declined'",
+                    rusqlite::params![namespace, since_sql, until_sql],
+                    |row| row.get(0),
+                )?;
+
+                activity.escalations = conn.query_row(
+                    "SELECT COUNT(*) FROM escalations
+                     WHERE agent_id = ?1 AND created_at >= ?2 AND created_at < ?3",
+                    rusqlite::params![namespace, since_rfc, until_rfc],
+                    |row| row.get(0),
+                )?;
+
+                activity.purged = conn.query_row(
+                    "SELECT COALESCE(SUM(messages), 0) FROM retention_purges
+                     WHERE agent_id = ?1 AND purged_at >= ?2 AND purged_at < ?3",
+                    rusqlite::params![namespace, since_rfc, until_rfc],
+                    |row| row.get(0),
+                )?;
+
+                activity.rate_limited = conn.query_row(
+                    "SELECT COUNT(*) FROM rate_limit_events
+                     WHERE agent_id = ?1 AND event = 'suppressed'
+                         AND created_at >= ?2 AND created_at < ?3",
+                    rusqlite::params![namespace, since_rfc, until_rfc],
+                    |row| row.get(0),
+                )?;
+
+                Ok(activity)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn test_aggregates_use_indexes() {
+        let knowledge = test_utils::knowledge_base().await;
+
+        let plans = knowledge
+            .conn
+            .call(|conn| {
+                let mut plans = Vec::new();
+                for (index, query) in [
+                    (
+                        "idx_messages_agent_created",
+                        "SELECT COUNT(*) FROM messages
+                         WHERE agent_id = 'a' AND created_at >= 'x' AND created_at < 'y'",
+                    ),
+                    (
+                        "idx_interactions_agent_created",
+                        "SELECT COUNT(*) FROM interactions
+                         WHERE agent_id = 'a' AND created_at >= 'x' AND created_at < 'y'",
+                    ),
+                    (
+                        "idx_tool_calls_created",
+                        "SELECT COUNT(*) FROM tool_calls
+                         WHERE created_at >= 'x' AND created_at < 'y'",
+                    ),
+                    (
+                        "idx_escalations_created",
+                        "SELECT COUNT(*) FROM escalations
+                         WHERE agent_id = 'a' AND created_at >= 'x' AND created_at < 'y'",
+                    ),
+                    (
+                        "idx_rate_limit_events_created",
+                        "SELECT COUNT(*) FROM rate_limit_events
+                         WHERE agent_id = 'a' AND event = 'suppressed'
+                             AND created_at >= 'x' AND created_at < 'y'",
+                    ),
+                ] {
+                    let plan = conn
+                        .prepare(&format!("EXPLAIN QUERY PLAN {query}"))?
+                        .query_map([], |row| row.get::<_, String>(3))?
+                        .collect::<Result<Vec<_>, _>>()?;
+                    plans.push((index, plan.join("\n")));
+                }
+                Ok(plans)
+            })
+            .await
+            .unwrap();
+
+        for (index, plan) in plans {
+            assert!(plan.contains(&format!("INDEX {index} ")), "{plan}");
+        }
+    }
+}
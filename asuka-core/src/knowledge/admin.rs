@@ -0,0 +1,497 @@
+//! Inspecting and deleting documents by hand, e.g. from a command line while
+//! the bots keep running on the same database.
+//!
+//! Deletes remove a document together with its embedding, topics and version
+//! record, at most [DELETE_CHUNK] documents per transaction so writers are
+//! never held up for long.
+
+use rig::{embeddings::EmbeddingModel, vector_store::VectorStoreIndex};
+use rig_sqlite::SqliteError;
+use rusqlite::OptionalExtension;
+
+use super::{cleaning, models::Document, store::KnowledgeBase};
+
+/// Documents deleted per transaction.
+const DELETE_CHUNK: usize = 100;
+
+/// A stored document with what is kept about it outside its row.
+#[derive(Debug, Clone)]
+pub struct DocumentDetails {
+    pub document: Document,
+    pub logical_id: String,
+    pub superseded: bool,
+    pub has_embedding: bool,
+}
+
+/// Which documents [KnowledgeBase::matching_documents] selects. The default
+/// selects every document.
+#[derive(Debug, Clone, Default)]
+pub struct DocumentFilter {
+    pub source_id: Option<String>,
+    /// Only documents created before this time.
+    pub created_before: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    pub async fn document_details(&self, id: &str) -> Result<Option<DocumentDetails>, SqliteError> {
+        let id = id.to_string();
+        let namespace = self.namespace.clone();
+
+        let details = self
+            .conn
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        &format!(
+                            "SELECT {}, COALESCE(v.logical_id, d.id), COALESCE(v.superseded, 0),
+                                 d.rowid IN (SELECT rowid FROM documents_embeddings)
+                             FROM documents d
+                             LEFT JOIN document_versions v ON v.document_id = d.id
+                             WHERE d.agent_id = ?2 AND d.id = ?1",
+                            prefixed_columns()
+                        ),
+                        [&id, &namespace],
+                        |row| {
+                            let width = Document::COLUMNS.split(',').count();
+                            Ok(DocumentDetails {
+                                document: Document::try_from(row)?,
+                                logical_id: row.get(width)?,
+                                superseded: row.get(width + 1)?,
+                                has_embedding: row.get(width + 2)?,
+                            })
+                        },
+                    )
+                    .optional()?)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))?;
+
+        let Some(mut details) = details else {
+            return Ok(None);
+        };
+        details.document.topics = self
+            .document_topics(vec![details.document.id.clone()])
+            .await?
+            .remove(&details.document.id)
+            .map(|topics| {
+                let mut topics = topics.into_iter().collect::<Vec<_>>();
+                topics.sort();
+                topics
+            })
+            .unwrap_or_default();
+        if details.logical_id != details.document.id {
+            details.document.logical_id = Some(details.logical_id.clone());
+        }
+        Ok(Some(details))
+    }
+
+    /// Ids of the documents selected by `filter`, oldest first.
+    pub async fn matching_documents(
+        &self,
+        filter: &DocumentFilter,
+    ) -> Result<Vec<String>, SqliteError> {
+        let source_id = filter.source_id.clone();
+        let created_before = filter.created_before.map(|time| time.to_rfc3339());
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                let ids = conn
+                    .prepare(
+                        "SELECT id FROM documents
+                         WHERE agent_id = ?3
+                             AND (?1 IS NULL OR source_id = ?1)
+                             AND (?2 IS NULL OR created_at < ?2)
+                         ORDER BY created_at, id",
+                    )?
+                    .query_map(
+                        rusqlite::params![source_id, created_before, namespace],
+                        |row| row.get::<_, String>(0),
+                    )?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(ids)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Live documents by source id, counting a document split into parts
+    /// once.
+    pub async fn document_counts(&self) -> Result<Vec<(String, usize)>, SqliteError> {
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                let counts = conn
+                    .prepare(
+                        "SELECT d.source_id, COUNT(DISTINCT COALESCE(c.parent_id, d.id))
+                         FROM documents d
+                         LEFT JOIN document_chunks c ON c.document_id = d.id
+                         LEFT JOIN document_versions v ON v.document_id = d.id
+                         WHERE d.agent_id = ?1 AND COALESCE(v.superseded, 0) = 0
+                         GROUP BY d.source_id
+                         ORDER BY d.source_id",
+                    )?
+                    .query_map([namespace], |row| {
+                        Ok((row.get::<_, String>(0)?, row.get::<_, usize>(1)?))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(counts)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Deletes the documents with the given ids, returning how many existed.
+    /// Deleting the live version of a document leaves its older versions
+    /// superseded.
+    pub async fn delete_documents(&self, ids: &[String]) -> Result<usize, SqliteError> {
+        let mut deleted = 0;
+        for chunk in ids.chunks(DELETE_CHUNK) {
+            let chunk = chunk.to_vec();
+            let namespace = self.namespace.clone();
+
+            deleted += self
+                .conn
+                .call(move |conn| {
+                    let tx = conn.transaction()?;
+                    let mut deleted = 0;
+                    for id in &chunk {
+                        deleted += delete_document(&tx, id, &namespace)?;
+                    }
+                    tx.commit()?;
+                    Ok(deleted)
+                })
+                .await
+                .map_err(|e| SqliteError::DatabaseError(Box::new(e)))?;
+        }
+        Ok(deleted)
+    }
+
+    /// Deletes the documents with the given ids like
+    /// [KnowledgeBase::delete_documents], along with the parts they were
+    /// split into, see [super::chunking], all in one transaction. Returns how
+    /// many of them were stored, whole or in parts.
+    pub async fn remove_documents_by_ids(&self, ids: &[String]) -> Result<usize, SqliteError> {
+        let ids = ids.to_vec();
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let mut removed = 0;
+                {
+                    let mut parts = tx.prepare(
+                        "SELECT c.document_id FROM document_chunks c
+                         JOIN documents d ON d.id = c.document_id
+                         WHERE d.agent_id = ?2 AND c.parent_id = ?1",
+                    )?;
+                    for id in &ids {
+                        let mut deleted = delete_document(&tx, id, &namespace)?;
+                        let part_ids = parts
+                            .query_map([id, &namespace], |row| row.get::<_, String>(0))?
+                            .collect::<Result<Vec<_>, _>>()?;
+                        for part_id in &part_ids {
+                            deleted += delete_document(&tx, part_id, &namespace)?;
+                        }
+                        if deleted > 0 {
+                            removed += 1;
+                        }
+                    }
+                }
+                tx.commit()?;
+                Ok(removed)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Embeds the documents with the given ids again, replacing their
+    /// embeddings, and returns how many were embedded. Unknown ids are
+    /// skipped.
+    pub async fn reembed_documents(&self, ids: &[String]) -> Result<usize, SqliteError> {
+        let ids = ids.to_vec();
+        let namespace = self.namespace.clone();
+
+        let rowids = self
+            .conn
+            .call(move |conn| {
+                let mut stmt =
+                    conn.prepare("SELECT rowid FROM documents WHERE agent_id = ?2 AND id = ?1")?;
+                let mut rowids = Vec::new();
+                for id in &ids {
+                    if let Some(rowid) = stmt
+                        .query_row([id, &namespace], |row| row.get::<_, i64>(0))
+                        .optional()?
+                    {
+                        rowids.push(rowid);
+                    }
+                }
+                Ok(rowids)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))?;
+
+        self.reembed::<Document>(&rowids, E::MAX_DOCUMENTS, cleaning::prepare)
+            .await
+    }
+
+    /// The `n` documents closest to `query` with their distances, searching
+    /// the shared namespaces too.
+    pub async fn search_documents(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<(f64, Document)>, SqliteError> {
+        let ids = self
+            .clone()
+            .document_index()
+            .top_n_ids(query, n)
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))?;
+
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT {} FROM documents WHERE id = ?1",
+                    Document::COLUMNS
+                ))?;
+                let mut results = Vec::new();
+                for (distance, id) in ids {
+                    if let Some(document) = stmt
+                        .query_row([&id], |row| Document::try_from(row))
+                        .optional()?
+                    {
+                        results.push((distance, document));
+                    }
+                }
+                Ok(results)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+}
+
+/// Deletes the document `id` of `namespace` with its embedding, topics,
+/// part and version rows, returning whether it existed.
+pub(super) fn delete_document(
+    tx: &rusqlite::Transaction,
+    id: &str,
+    namespace: &str,
+) -> rusqlite::Result<usize> {
+    tx.execute(
+        "DELETE FROM documents_embeddings
+         WHERE rowid = (SELECT rowid FROM documents WHERE agent_id = ?2 AND id = ?1)",
+        [id, namespace],
+    )?;
+    tx.execute(
+        "DELETE FROM document_topics
+         WHERE document_id = ?1
+             AND EXISTS (SELECT 1 FROM documents WHERE agent_id = ?2 AND id = ?1)",
+        [id, namespace],
+    )?;
+    tx.execute(
+        "DELETE FROM document_chunks
+         WHERE document_id = ?1
+             AND EXISTS (SELECT 1 FROM documents WHERE agent_id = ?2 AND id = ?1)",
+        [id, namespace],
+    )?;
+    tx.execute(
+        "DELETE FROM document_versions WHERE agent_id = ?2 AND document_id = ?1",
+        [id, namespace],
+    )?;
+    tx.execute(
+        "DELETE FROM documents WHERE agent_id = ?2 AND id = ?1",
+        [id, namespace],
+    )
+}
+
+/// [Document::COLUMNS] qualified with the `d` alias.
+fn prefixed_columns() -> String {
+    Document::COLUMNS
+        .split(", ")
+        .map(|column| format!("d.{column}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+    use chrono::{DateTime, Utc};
+
+    fn doc(id: &str, source_id: &str, content: &str, created_at: DateTime<Utc>) -> Document {
+        Document {
+            id: id.to_string(),
+            source_id: source_id.to_string(),
+            content: content.to_string(),
+            created_at,
+            title: String::new(),
+            section: String::new(),
+            topics: vec!["vrf".to_string()],
+            logical_id: None,
+            cleaned: None,
+            chunk: None,
+        }
+    }
+
+    fn days_ago(days: i64) -> DateTime<Utc> {
+        Utc::now() - chrono::Duration::days(days)
+    }
+
+    async fn knowledge() -> KnowledgeBase<test_utils::FakeEmbeddingModel> {
+        let mut knowledge = test_utils::knowledge_base().await;
+        knowledge
+            .add_documents(vec![
+                doc("vrf.md", "github", "vrf requests are free", days_ago(60)),
+                doc("katana.md", "github", "katana devnet flags", days_ago(1)),
+                doc("faq.md", "website", "paymaster sponsors fees", days_ago(90)),
+            ])
+            .await
+            .unwrap();
+        knowledge
+    }
+
+    async fn count(knowledge: &KnowledgeBase<test_utils::FakeEmbeddingModel>, sql: &str) -> usize {
+        let sql = sql.to_string();
+        knowledge
+            .conn
+            .call(move |conn| Ok(conn.query_row(&sql, [], |row| row.get(0))?))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_list_and_show() {
+        let knowledge = knowledge().await;
+
+        let page = knowledge
+            .list_documents_from("github", None, 10)
+            .await
+            .unwrap();
+        let ids: Vec<_> = page.items.iter().map(|d| d.id.as_str()).collect();
+        assert_eq!(ids, ["vrf.md", "katana.md"]);
+
+        let details = knowledge.document_details("vrf.md").await.unwrap().unwrap();
+        assert_eq!(details.document.content, "vrf requests are free");
+        assert_eq!(details.document.topics, ["vrf"]);
+        assert_eq!(details.logical_id, "vrf.md");
+        assert!(!details.superseded);
+        assert!(details.has_embedding);
+        assert!(knowledge
+            .document_details("missing.md")
+            .await
+            .unwrap()
+            .is_none());
+
+        // Other namespaces' documents are not visible.
+        let other = knowledge.clone().with_namespace("other");
+        assert!(other.document_details("vrf.md").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_delete_by_source_and_age() {
+        let knowledge = knowledge().await;
+        let filter = DocumentFilter {
+            source_id: Some("github".to_string()),
+            created_before: Some(days_ago(30)),
+        };
+
+        let ids = knowledge.matching_documents(&filter).await.unwrap();
+        assert_eq!(ids, ["vrf.md"]);
+        assert_eq!(knowledge.delete_documents(&ids).await.unwrap(), 1);
+        assert_eq!(knowledge.delete_documents(&ids).await.unwrap(), 0);
+
+        assert!(knowledge
+            .document_details("vrf.md")
+            .await
+            .unwrap()
+            .is_none());
+        for sql in [
+            "SELECT COUNT(*) FROM documents",
+            "SELECT COUNT(*) FROM documents_embeddings",
+            "SELECT COUNT(*) FROM document_topics",
+            "SELECT COUNT(*) FROM document_versions",
+        ] {
+            assert_eq!(count(&knowledge, sql).await, 2, "{sql}");
+        }
+
+        let all = knowledge
+            .matching_documents(&DocumentFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(all, ["faq.md", "katana.md"]);
+    }
+
+    #[tokio::test]
+    async fn test_remove_documents_with_their_parts() {
+        let mut knowledge = knowledge()
+            .await
+            .with_chunking(crate::knowledge::ChunkingConfig {
+                max_chars: 40,
+                overlap: 0,
+                split_on_headings: true,
+            });
+        knowledge
+            .add_documents(vec![doc(
+                "guide.md",
+                "github",
+                "# Guide\n\nFirst section body.\n\n## More\n\nSecond section body.\n",
+                days_ago(1),
+            )])
+            .await
+            .unwrap();
+        assert!(count(&knowledge, "SELECT COUNT(*) FROM document_chunks").await > 1);
+
+        let ids = ["guide.md", "katana.md", "missing.md"].map(String::from);
+        assert_eq!(knowledge.remove_documents_by_ids(&ids).await.unwrap(), 2);
+        assert_eq!(
+            count(&knowledge, "SELECT COUNT(*) FROM document_chunks").await,
+            0
+        );
+        let all = knowledge
+            .matching_documents(&DocumentFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(all, ["faq.md", "vrf.md"]);
+    }
+
+    #[tokio::test]
+    async fn test_reembed_and_search() {
+        let knowledge = knowledge().await;
+        knowledge
+            .conn
+            .call(|conn| {
+                conn.execute_batch(
+                    "DELETE FROM documents_embeddings
+                     WHERE rowid = (SELECT rowid FROM documents WHERE id = 'katana.md');",
+                )?;
+                Ok(())
+            })
+            .await
+            .unwrap();
+
+        let ids = knowledge
+            .matching_documents(&DocumentFilter::default())
+            .await
+            .unwrap();
+        let reembedded = knowledge
+            .reembed_documents(&[ids, vec!["missing.md".to_string()]].concat())
+            .await
+            .unwrap();
+        assert_eq!(reembedded, 3);
+        assert_eq!(
+            count(&knowledge, "SELECT COUNT(*) FROM documents_embeddings").await,
+            3
+        );
+
+        let results = knowledge
+            .search_documents("katana devnet flags", 2)
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].1.id, "katana.md");
+        assert!(results[0].0 <= results[1].0);
+    }
+}
@@ -0,0 +1,131 @@
+//! Audit trail of `/say`, one row per announcement an admin had the bot post,
+//! see [crate::say].
+
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+
+use super::{store::KnowledgeBase, types::Source};
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS announcements (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        agent_id TEXT NOT NULL,
+        interaction_id INTEGER REFERENCES interactions(id) ON DELETE SET NULL,
+        source TEXT NOT NULL,
+        channel_id TEXT NOT NULL,
+        invoked_by TEXT NOT NULL,
+        requested TEXT NOT NULL,
+        content TEXT NOT NULL,
+        restyled INTEGER NOT NULL,
+        created_at TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS idx_announcements_channel
+        ON announcements(agent_id, channel_id, created_at);
+";
+
+/// An announcement posted with `/say`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announcement {
+    pub id: i64,
+    /// Interaction the sent messages are recorded under.
+    pub interaction_id: Option<i64>,
+    pub source: Source,
+    pub channel_id: String,
+    pub invoked_by: String,
+    /// Text as the admin gave it.
+    pub requested: String,
+    /// Text as posted.
+    pub content: String,
+    /// Whether `content` was rewritten from `requested`.
+    pub restyled: bool,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Records that `invoked_by` had the bot post `content`, from their
+    /// `requested` text, as the answer to `interaction_id`.
+    pub async fn record_announcement(
+        &self,
+        interaction_id: i64,
+        source: &Source,
+        channel_id: &str,
+        invoked_by: &str,
+        requested: &str,
+        content: &str,
+    ) -> Result<i64, SqliteError> {
+        let namespace = self.namespace.clone();
+        let source = source.as_str().to_string();
+        let channel_id = channel_id.to_string();
+        let invoked_by = invoked_by.to_string();
+        let requested = requested.to_string();
+        let content = content.to_string();
+        let restyled = requested != content;
+        let now = self.clock.now_utc().to_rfc3339();
+
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT INTO announcements
+                         (agent_id, interaction_id, source, channel_id, invoked_by, requested,
+                          content, restyled, created_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                    rusqlite::params![
+                        namespace,
+                        interaction_id,
+                        source,
+                        channel_id,
+                        invoked_by,
+                        requested,
+                        content,
+                        restyled,
+                        now
+                    ],
+                )?;
+                Ok(conn.last_insert_rowid())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Announcements posted in `channel_id`, oldest first.
+    pub async fn announcements(&self, channel_id: &str) -> Result<Vec<Announcement>, SqliteError> {
+        let namespace = self.namespace.clone();
+        let channel_id = channel_id.to_string();
+
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT id, interaction_id, source, channel_id, invoked_by, requested,
+                         content, restyled, created_at
+                     FROM announcements
+                     WHERE agent_id = ?1 AND channel_id = ?2
+                     ORDER BY created_at, id",
+                )?;
+                let announcements = stmt
+                    .query_map([&namespace, &channel_id], |row| {
+                        let source: String = row.get(2)?;
+                        Ok(Announcement {
+                            id: row.get(0)?,
+                            interaction_id: row.get(1)?,
+                            source: Source::from_str(&source).ok_or_else(|| {
+                                rusqlite::Error::InvalidColumnType(
+                                    2,
+                                    "source".to_string(),
+                                    rusqlite::types::Type::Text,
+                                )
+                            })?,
+                            channel_id: row.get(3)?,
+                            invoked_by: row.get(4)?,
+                            requested: row.get(5)?,
+                            content: row.get(6)?,
+                            restyled: row.get(7)?,
+                            created_at: row.get(8)?,
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(announcements)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+}
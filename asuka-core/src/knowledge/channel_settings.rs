@@ -0,0 +1,157 @@
+//! Free-form per-channel settings, such as the disclaimer appended to replies
+//! in a channel, set by admins from chat.
+
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+use rusqlite::OptionalExtension;
+
+use super::store::KnowledgeBase;
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS channel_settings (
+        agent_id TEXT NOT NULL,
+        channel_id TEXT NOT NULL,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+        PRIMARY KEY (agent_id, channel_id, key)
+    );
+";
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    pub async fn channel_setting(
+        &self,
+        channel_id: &str,
+        key: &str,
+    ) -> Result<Option<String>, SqliteError> {
+        let channel_id = channel_id.to_string();
+        let key = key.to_string();
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        "SELECT value FROM channel_settings
+                         WHERE agent_id = ?1 AND channel_id = ?2 AND key = ?3",
+                        [&namespace, &channel_id, &key],
+                        |row| row.get(0),
+                    )
+                    .optional()?)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Sets a channel setting, or removes it when `value` is `None`.
+    pub async fn set_channel_setting(
+        &self,
+        channel_id: &str,
+        key: &str,
+        value: Option<&str>,
+    ) -> Result<(), SqliteError> {
+        let channel_id = channel_id.to_string();
+        let key = key.to_string();
+        let value = value.map(str::to_string);
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                match value {
+                    Some(value) => conn.execute(
+                        "INSERT INTO channel_settings (agent_id, channel_id, key, value)
+                         VALUES (?1, ?2, ?3, ?4)
+                         ON CONFLICT (agent_id, channel_id, key) DO UPDATE SET
+                             value = excluded.value,
+                             updated_at = CURRENT_TIMESTAMP",
+                        [&namespace, &channel_id, &key, &value],
+                    )?,
+                    None => conn.execute(
+                        "DELETE FROM channel_settings
+                         WHERE agent_id = ?1 AND channel_id = ?2 AND key = ?3",
+                        [&namespace, &channel_id, &key],
+                    )?,
+                };
+                Ok(())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Sets a channel setting unless it is already set, returning whether it
+    /// was. Used for defaults, which must not override what admins chose.
+    pub async fn init_channel_setting(
+        &self,
+        channel_id: &str,
+        key: &str,
+        value: &str,
+    ) -> Result<bool, SqliteError> {
+        let channel_id = channel_id.to_string();
+        let key = key.to_string();
+        let value = value.to_string();
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                let inserted = conn.execute(
+                    "INSERT INTO channel_settings (agent_id, channel_id, key, value)
+                     VALUES (?1, ?2, ?3, ?4)
+                     ON CONFLICT (agent_id, channel_id, key) DO NOTHING",
+                    [&namespace, &channel_id, &key, &value],
+                )?;
+                Ok(inserted > 0)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn test_channel_settings() {
+        let knowledge = test_utils::knowledge_base().await;
+        assert_eq!(
+            knowledge.channel_setting("c1", "disclaimer").await.unwrap(),
+            None
+        );
+
+        knowledge
+            .set_channel_setting("c1", "disclaimer", Some("Not financial advice."))
+            .await
+            .unwrap();
+        knowledge
+            .set_channel_setting("c1", "disclaimer", Some("Not legal advice."))
+            .await
+            .unwrap();
+        assert_eq!(
+            knowledge
+                .channel_setting("c1", "disclaimer")
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("Not legal advice.")
+        );
+        assert_eq!(
+            knowledge.channel_setting("c2", "disclaimer").await.unwrap(),
+            None
+        );
+
+        let other = knowledge.clone().with_namespace("companion");
+        assert_eq!(
+            other.channel_setting("c1", "disclaimer").await.unwrap(),
+            None
+        );
+
+        knowledge
+            .set_channel_setting("c1", "disclaimer", None)
+            .await
+            .unwrap();
+        assert_eq!(
+            knowledge.channel_setting("c1", "disclaimer").await.unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,387 @@
+//! Splitting large documents into parts before they are embedded, so each
+//! embedding stays within the model's input limit and stands for a passage
+//! rather than a whole page.
+//!
+//! With a [ChunkingConfig] set, [KnowledgeBase::add_documents] and the
+//! batched [KnowledgeBase::add_documents_stream] store a document longer
+//! than [ChunkingConfig::max_chars] as parts under `<id>#chunk-<index>`, each
+//! with its [ChunkPosition] and the parent id in the `document_chunks` table,
+//! see [super::chunks]. Shorter documents are stored whole, as without a
+//! config. Parts left over from an earlier, longer version of a document are
+//! removed.
+//!
+//! Searches through [KnowledgeBase::document_index] then find parts;
+//! [parent_id](super::parent_id) gives the document a hit belongs to, and
+//! [KnowledgeBase::retrieve] fills it in.
+//!
+//! ```toml
+//! [chunking]
+//! max_chars = 2000
+//! overlap = 200
+//! ```
+
+use std::{collections::HashSet, ops::Range};
+
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+use serde::Deserialize;
+
+use super::{
+    chunks::ChunkPosition, diversity::parent_id, models::Document, outline::Outline,
+    store::KnowledgeBase,
+};
+
+/// `[chunking]` settings of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChunkingConfig {
+    /// Characters of a part at most.
+    pub max_chars: usize,
+    /// Characters a part repeats from the end of the one before when a
+    /// passage is cut mid-text, so a sentence across the cut is found whole.
+    pub overlap: usize,
+    /// Cut at markdown headings first, keeping each heading with its body.
+    pub split_on_headings: bool,
+}
+
+impl Default for ChunkingConfig {
+    fn default() -> Self {
+        Self {
+            max_chars: 2000,
+            overlap: 200,
+            split_on_headings: true,
+        }
+    }
+}
+
+impl ChunkingConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_chars == 0 {
+            return Err("chunking.max_chars must be positive".to_string());
+        }
+        if self.overlap * 2 > self.max_chars {
+            return Err("chunking.overlap must be at most half of max_chars".to_string());
+        }
+        Ok(())
+    }
+
+    /// `document` split into parts, or itself alone when it fits in one.
+    pub fn split(&self, document: &Document) -> Vec<Document> {
+        let content = &document.content;
+        if content.chars().count() <= self.max_chars {
+            return vec![document.clone()];
+        }
+
+        let outline = Outline::parse(content);
+        let ranges = self
+            .ranges(content, &outline)
+            .into_iter()
+            .filter(|range| !content[range.clone()].trim().is_empty())
+            .collect::<Vec<_>>();
+        let count = ranges.len();
+        let title = match &outline.title {
+            Some(title) if document.title.is_empty() => title.clone(),
+            _ => document.title.clone(),
+        };
+
+        ranges
+            .into_iter()
+            .enumerate()
+            .map(|(index, range)| {
+                let id = format!("{}#chunk-{index}", document.id);
+                Document {
+                    content: content[range.clone()].to_string(),
+                    title: title.clone(),
+                    section: outline.section_at(range.start).unwrap_or_default(),
+                    logical_id: document
+                        .logical_id
+                        .as_ref()
+                        .map(|logical_id| format!("{logical_id}#chunk-{index}")),
+                    // Cleaned again part by part
+                    cleaned: None,
+                    chunk: Some(ChunkPosition {
+                        index,
+                        count,
+                        start: content[..range.start].chars().count(),
+                        end: content[..range.end].chars().count(),
+                    }),
+                    id,
+                    ..document.clone()
+                }
+            })
+            .collect()
+    }
+
+    /// Byte ranges of the parts of `content`: sections that fit packed
+    /// together, longer ones cut into overlapping windows.
+    fn ranges(&self, content: &str, outline: &Outline) -> Vec<Range<usize>> {
+        let mut cuts = vec![0];
+        if self.split_on_headings {
+            cuts.extend(outline.headings.iter().map(|heading| heading.offset));
+        }
+        cuts.push(content.len());
+        cuts.dedup();
+
+        let mut ranges = Vec::new();
+        let mut current: Option<Range<usize>> = None;
+        for section in cuts.windows(2).map(|cut| cut[0]..cut[1]) {
+            if chars(content, &section) > self.max_chars {
+                ranges.extend(current.take());
+                ranges.extend(self.windows(content, section));
+                continue;
+            }
+            current = match current.take() {
+                Some(open) if chars(content, &(open.start..section.end)) <= self.max_chars => {
+                    Some(open.start..section.end)
+                }
+                open => {
+                    ranges.extend(open);
+                    Some(section)
+                }
+            };
+        }
+        ranges.extend(current);
+        ranges
+    }
+
+    /// `range` of `content` cut into windows of at most `max_chars`, at a
+    /// paragraph, line or word break where there is one. Each window starts
+    /// `overlap` characters before the end of the one before, but always
+    /// after its start, so no window repeats another whole.
+    fn windows(&self, content: &str, range: Range<usize>) -> Vec<Range<usize>> {
+        let mut windows = Vec::new();
+        let mut start = range.start;
+        loop {
+            let limit = advance(content, start, self.max_chars).min(range.end);
+            if limit == range.end {
+                windows.push(start..range.end);
+                return windows;
+            }
+            let end = ["\n\n", "\n", " "]
+                .iter()
+                .find_map(|separator| {
+                    content[start..limit]
+                        .rfind(separator)
+                        .filter(|&at| at > 0)
+                        .map(|at| start + at + separator.len())
+                })
+                .unwrap_or(limit);
+            windows.push(start..end);
+
+            let mut back = retreat(content, end, self.overlap);
+            // Start the overlap at a word
+            if !content[..back].ends_with(char::is_whitespace) {
+                back = content[back..end]
+                    .find(char::is_whitespace)
+                    .map_or(back, |at| back + at + 1);
+            }
+            start = if back > start && back < end {
+                back
+            } else {
+                end
+            };
+        }
+    }
+}
+
+fn chars(content: &str, range: &Range<usize>) -> usize {
+    content[range.clone()].chars().count()
+}
+
+/// Byte offset `count` characters after `from`, or the end of `content`.
+fn advance(content: &str, from: usize, count: usize) -> usize {
+    content[from..]
+        .char_indices()
+        .nth(count)
+        .map_or(content.len(), |(at, _)| from + at)
+}
+
+/// Byte offset `count` characters before `to`, or the start of `content`.
+fn retreat(content: &str, to: usize, count: usize) -> usize {
+    if count == 0 {
+        return to;
+    }
+    content[..to]
+        .char_indices()
+        .rev()
+        .nth(count - 1)
+        .map_or(0, |(at, _)| at)
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Splits documents longer than the config into parts when they are
+    /// added. Without a config documents are stored whole.
+    pub fn with_chunking(mut self, config: ChunkingConfig) -> Self {
+        self.chunking = Some(config);
+        self
+    }
+
+    pub fn chunking(&self) -> Option<&ChunkingConfig> {
+        self.chunking.as_ref()
+    }
+
+    /// Ids of stored documents and parts that `documents` replace but don't
+    /// overwrite: parts of a parent beyond its new count, and a parent stored
+    /// whole that is now split, or the other way around.
+    pub(super) async fn superseded_parts(
+        &self,
+        documents: &[Document],
+    ) -> Result<Vec<String>, SqliteError> {
+        let kept = documents
+            .iter()
+            .map(|document| document.id.clone())
+            .collect::<HashSet<_>>();
+        let parents = documents
+            .iter()
+            .map(|document| parent_id(&document.id).to_string())
+            .collect::<HashSet<_>>();
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT id FROM documents
+                     WHERE agent_id = ?1
+                         AND (id = ?2 OR id IN (
+                             SELECT document_id FROM document_chunks WHERE parent_id = ?2
+                         ))",
+                )?;
+                let mut superseded = Vec::new();
+                for parent in &parents {
+                    for id in stmt.query_map([&namespace, parent], |row| row.get::<_, String>(0))? {
+                        let id = id?;
+                        if !kept.contains(&id) {
+                            superseded.push(id);
+                        }
+                    }
+                }
+                Ok(superseded)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    fn config(max_chars: usize, overlap: usize) -> ChunkingConfig {
+        ChunkingConfig {
+            max_chars,
+            overlap,
+            split_on_headings: true,
+        }
+    }
+
+    #[test]
+    fn test_short_documents_are_kept_whole() {
+        let document = test_utils::document("vrf.md", "# VRF\n\nVerifiable randomness.");
+        let parts = config(100, 10).split(&document);
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].id, document.id);
+        assert_eq!(parts[0].chunk, None);
+    }
+
+    #[test]
+    fn test_headings_stay_with_their_body() {
+        let content = "# VRF\n\nVerifiable randomness for games.\n\n\
+            ## Fees\n\nEach request costs a small fee.\n\n\
+            ## Limits\n\nAt most ten requests per block.\n";
+        let document = test_utils::document("vrf.md", content);
+        let parts = config(60, 10).split(&document);
+
+        let contents = parts
+            .iter()
+            .map(|part| part.content.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            contents,
+            [
+                "# VRF\n\nVerifiable randomness for games.\n\n",
+                "## Fees\n\nEach request costs a small fee.\n\n",
+                "## Limits\n\nAt most ten requests per block.\n",
+            ]
+        );
+        assert_eq!(parts[1].id, "/tmp/docs/vrf.md#chunk-1");
+        assert_eq!(parts[1].title, "VRF");
+        assert_eq!(parts[1].section, "VRF > Fees");
+        assert_eq!(
+            parts[2].chunk,
+            Some(ChunkPosition {
+                index: 2,
+                count: 3,
+                start: 83,
+                end: content.chars().count(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_long_sections_overlap_without_repeating_a_part() {
+        let content = (0..60)
+            .map(|n| format!("word{n:02}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let document = test_utils::document("notes.txt", &content);
+        let parts = config(50, 14).split(&document);
+
+        assert!(parts.len() > 1);
+        for pair in parts.windows(2) {
+            let (before, after) = (&pair[0], &pair[1]);
+            assert!(before.content.chars().count() <= 50);
+            // The next part repeats the end of this one, and goes beyond it
+            let repeated = before.content.split_whitespace().last().unwrap();
+            assert!(after.content.contains(repeated), "{after:?}");
+            assert!(!before.content.contains(after.content.trim()));
+        }
+        assert!(parts.last().unwrap().content.ends_with("word59"));
+    }
+
+    #[test]
+    fn test_invalid_overlap() {
+        assert!(config(100, 60).validate().is_err());
+        assert!(config(0, 0).validate().is_err());
+        assert!(ChunkingConfig::default().validate().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_added_documents_are_chunked() {
+        let mut knowledge = test_utils::knowledge_base()
+            .await
+            .with_chunking(config(60, 10));
+        let content = "# VRF\n\nVerifiable randomness for games.\n\n\
+            ## Fees\n\nEach request costs a small fee.\n\n\
+            ## Limits\n\nAt most ten requests per block.\n";
+        knowledge
+            .add_documents(vec![test_utils::document("vrf.md", content)])
+            .await
+            .unwrap();
+
+        let chunk = knowledge
+            .get_chunk("/tmp/docs/vrf.md#chunk-1")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(chunk.parent_id, "/tmp/docs/vrf.md");
+        assert!(chunk.content.starts_with("## Fees"));
+
+        // A shorter version replaces every part
+        knowledge
+            .add_documents(vec![test_utils::document("vrf.md", "# VRF\n\nShort now.")])
+            .await
+            .unwrap();
+        assert!(knowledge
+            .get_chunk("/tmp/docs/vrf.md#chunk-1")
+            .await
+            .unwrap()
+            .is_none());
+        let whole = knowledge
+            .get_chunk("/tmp/docs/vrf.md")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(whole.content, "# VRF\n\nShort now.");
+    }
+}
@@ -0,0 +1,556 @@
+//! Where a stored part of a document sits in it, so a retrieved passage can
+//! be presented as "from the Fees section, near the end of the page" rather
+//! than as loose text.
+//!
+//! A part is stored as a document of its own under `<parent id>#<part>`, see
+//! [parent_id], with its [ChunkPosition] kept in the `document_chunks` table.
+//! Whole documents have no row there and are their own single part.
+//! [KnowledgeBase::retrieve] returns search results as [RetrievedChunk]s with
+//! the position filled in, which the prompt, citations and quotes all use.
+
+use std::{collections::HashMap, ops::Range};
+
+use rig::{
+    embeddings::EmbeddingModel,
+    vector_store::{VectorStoreError, VectorStoreIndex},
+};
+use rig_sqlite::SqliteError;
+use rusqlite::OptionalExtension;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    diversity::parent_id,
+    gaps::{cosine_similarity, from_blob},
+    models::Document,
+    store::KnowledgeBase,
+};
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS document_chunks (
+        document_id TEXT PRIMARY KEY,
+        parent_id TEXT NOT NULL,
+        chunk_index INTEGER NOT NULL,
+        chunk_count INTEGER NOT NULL,
+        start_offset INTEGER NOT NULL,
+        end_offset INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS idx_document_chunks_parent ON document_chunks(parent_id);
+";
+
+/// A part's place in its parent document. Offsets count characters of the
+/// parent's content and are approximate once the parent is cleaned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkPosition {
+    /// Zero-based.
+    pub index: usize,
+    pub count: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl ChunkPosition {
+    /// The position of a document stored whole.
+    pub fn whole(content: &str) -> Self {
+        Self {
+            index: 0,
+            count: 1,
+            start: 0,
+            end: content.chars().count(),
+        }
+    }
+
+    /// Rough place in the parent, such as `near the end`, from the part's
+    /// index.
+    fn placement(&self) -> &'static str {
+        match (self.index * 3) / self.count.max(1) {
+            0 => "near the start",
+            1 => "in the middle",
+            _ => "near the end",
+        }
+    }
+}
+
+/// A document or part of one found for a query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetrievedChunk {
+    pub id: String,
+    /// The document the part belongs to, `id` itself for whole documents.
+    pub parent_id: String,
+    pub content: String,
+    /// Title of the parent, empty for documents without a title.
+    pub title: String,
+    /// Heading chain of a part of a document, empty for whole documents.
+    pub section: String,
+    pub position: ChunkPosition,
+}
+
+impl RetrievedChunk {
+    /// A document retrieved whole.
+    pub fn whole(
+        id: impl Into<String>,
+        content: impl Into<String>,
+        title: impl Into<String>,
+        section: impl Into<String>,
+    ) -> Self {
+        let (id, content) = (id.into(), content.into());
+        Self {
+            parent_id: parent_id(&id).to_string(),
+            position: ChunkPosition::whole(&content),
+            title: title.into(),
+            section: section.into(),
+            content,
+            id,
+        }
+    }
+
+    /// How the passage is cited: its title and innermost heading, such as
+    /// `VRF Overview — Fees`, or its parent's id when it has no title.
+    pub fn citation(&self) -> String {
+        let heading = self.section.rsplit(" > ").next().unwrap_or_default();
+        match (self.title.as_str(), heading) {
+            ("", _) => self.parent_id.clone(),
+            (title, "") => title.to_string(),
+            (title, heading) => format!("{title} — {heading}"),
+        }
+    }
+
+    /// One line telling the model where the passage comes from, such as
+    /// `Source: VRF Overview, section VRF > Fees, part 6 of 7 near the end
+    /// (characters 4100–4800)`. Whole documents only name the document.
+    pub fn provenance(&self) -> String {
+        let name = match self.title.as_str() {
+            "" => self.parent_id.as_str(),
+            title => title,
+        };
+        let mut line = format!("Source: {name}");
+        if !self.section.is_empty() {
+            line.push_str(&format!(", section {}", self.section));
+        }
+        let position = &self.position;
+        if position.count > 1 {
+            line.push_str(&format!(
+                ", part {} of {} {} (characters {}–{})",
+                position.index + 1,
+                position.count,
+                position.placement(),
+                position.start,
+                position.end
+            ));
+        }
+        line
+    }
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// The `n` passages of `index` closest to `query`, closest first, with
+    /// their place in their documents.
+    pub async fn retrieve<I: VectorStoreIndex>(
+        &self,
+        index: &I,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<RetrievedChunk>, VectorStoreError> {
+        let rows = index.top_n::<serde_json::Value>(query, n).await?;
+        let ids = rows.iter().map(|(_, id, _)| id.clone()).collect::<Vec<_>>();
+        let mut positions = self
+            .chunk_positions(ids)
+            .await
+            .map_err(|e| VectorStoreError::DatastoreError(Box::new(e)))?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(_, id, row)| {
+                let text = |column: &str| {
+                    row.get(column)
+                        .and_then(|value| value.as_str())
+                        .unwrap_or_default()
+                        .to_string()
+                };
+                let content = match row.get("content").and_then(|content| content.as_str()) {
+                    Some(content) => content.to_string(),
+                    None => row.to_string(),
+                };
+                let mut chunk = RetrievedChunk::whole(id, content, text("title"), text("section"));
+                if let Some((parent_id, position)) = positions.remove(&chunk.id) {
+                    chunk.parent_id = parent_id;
+                    chunk.position = position;
+                }
+                chunk
+            })
+            .collect())
+    }
+
+    /// The stored document `id` as a passage, looked up by id rather than
+    /// searched, e.g. to quote it again.
+    pub async fn get_chunk(&self, id: &str) -> Result<Option<RetrievedChunk>, SqliteError> {
+        let Some(document) = self.get_document(id).await? else {
+            return Ok(None);
+        };
+        let mut chunk = RetrievedChunk::whole(
+            document.id,
+            document.content,
+            document.title,
+            document.section,
+        );
+        if let Some((parent_id, position)) = self
+            .chunk_positions(vec![chunk.id.clone()])
+            .await?
+            .remove(&chunk.id)
+        {
+            chunk.parent_id = parent_id;
+            chunk.position = position;
+        }
+        Ok(Some(chunk))
+    }
+
+    /// Parent id and position of the stored parts among `document_ids`.
+    /// Whole documents are left out.
+    pub async fn chunk_positions(
+        &self,
+        document_ids: Vec<String>,
+    ) -> Result<HashMap<String, (String, ChunkPosition)>, SqliteError> {
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT parent_id, chunk_index, chunk_count, start_offset, end_offset
+                     FROM document_chunks WHERE document_id = ?1",
+                )?;
+                let mut positions = HashMap::new();
+                for id in document_ids {
+                    let position = stmt
+                        .query_row([&id], |row| {
+                            Ok((
+                                row.get::<_, String>(0)?,
+                                ChunkPosition {
+                                    index: row.get(1)?,
+                                    count: row.get(2)?,
+                                    start: row.get(3)?,
+                                    end: row.get(4)?,
+                                },
+                            ))
+                        })
+                        .optional()?;
+                    if let Some(position) = position {
+                        positions.insert(id, position);
+                    }
+                }
+                Ok(positions)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Similarity to `query` of each of `segments`, byte ranges of
+    /// `passage`'s content, e.g. to [truncate](crate::truncation) it. A
+    /// segment inside another stored part of the same document is scored
+    /// with that part's embedding, so only the others are embedded.
+    /// `query_vector` is filled with the query's embedding when it is `None`,
+    /// for the next passage of the same query.
+    pub async fn score_segments(
+        &self,
+        passage: &RetrievedChunk,
+        segments: &[Range<usize>],
+        query: &str,
+        query_vector: &mut Option<Vec<f64>>,
+    ) -> anyhow::Result<Vec<f64>> {
+        let parts = self
+            .part_embeddings(passage.parent_id.clone(), passage.id.clone())
+            .await?;
+        let content = &passage.content;
+        let mut vectors = Vec::with_capacity(segments.len());
+        let (mut offset, mut chars) = (0, passage.position.start);
+        for segment in segments {
+            chars += content[offset..segment.start].chars().count();
+            let start = chars;
+            chars += content[segment.clone()].chars().count();
+            offset = segment.end;
+            vectors.push(
+                parts
+                    .iter()
+                    .find(|(part, _)| part.start <= start && chars <= part.end)
+                    .map(|(_, vector)| vector.clone()),
+            );
+        }
+
+        let mut texts = Vec::new();
+        if query_vector.is_none() {
+            texts.push(query.to_string());
+        }
+        texts.extend(
+            segments
+                .iter()
+                .zip(&vectors)
+                .filter(|(_, vector)| vector.is_none())
+                .map(|(segment, _)| content[segment.clone()].to_string()),
+        );
+        let mut embedded = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(E::MAX_DOCUMENTS.max(1)) {
+            let embeddings = self.embedding_model.embed_texts(batch.to_vec()).await?;
+            embedded.extend(embeddings.into_iter().map(|embedding| embedding.vec));
+        }
+        let mut embedded = embedded.into_iter();
+        if query_vector.is_none() {
+            *query_vector = embedded.next();
+        }
+        for vector in vectors.iter_mut().filter(|vector| vector.is_none()) {
+            *vector = embedded.next();
+        }
+
+        let query_vector = query_vector.as_deref().unwrap_or_default();
+        Ok(vectors
+            .iter()
+            .map(|vector| {
+                vector
+                    .as_deref()
+                    .map_or(0.0, |vector| cosine_similarity(query_vector, vector))
+            })
+            .collect())
+    }
+
+    /// Position and embedding of the stored parts of `parent_id` but
+    /// `except`.
+    async fn part_embeddings(
+        &self,
+        parent_id: String,
+        except: String,
+    ) -> Result<Vec<(ChunkPosition, Vec<f64>)>, SqliteError> {
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT c.chunk_index, c.chunk_count, c.start_offset, c.end_offset,
+                            e.embedding
+                     FROM document_chunks c
+                     JOIN documents d ON d.id = c.document_id
+                     JOIN documents_embeddings e ON e.rowid = d.rowid
+                     WHERE c.parent_id = ?1 AND c.document_id != ?2",
+                )?;
+                let parts = stmt
+                    .query_map([&parent_id, &except], |row| {
+                        Ok((
+                            ChunkPosition {
+                                index: row.get(0)?,
+                                count: row.get(1)?,
+                                start: row.get(2)?,
+                                end: row.get(3)?,
+                            },
+                            from_blob(&row.get::<_, Vec<u8>>(4)?),
+                        ))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(parts)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+}
+
+/// Records the position of each stored part. Documents stored whole drop the
+/// position of an earlier part stored under the same id.
+pub(super) fn store_chunks(
+    tx: &rusqlite::Transaction,
+    documents: &[Document],
+) -> rusqlite::Result<()> {
+    for document in documents {
+        match &document.chunk {
+            Some(position) => insert_position(tx, &document.id, position)?,
+            None => {
+                tx.execute(
+                    "DELETE FROM document_chunks WHERE document_id = ?1",
+                    [&document.id],
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Records the position of the part stored under `document_id`.
+pub(super) fn insert_position(
+    conn: &rusqlite::Connection,
+    document_id: &str,
+    position: &ChunkPosition,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO document_chunks
+             (document_id, parent_id, chunk_index, chunk_count, start_offset, end_offset)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        rusqlite::params![
+            document_id,
+            parent_id(document_id),
+            position.index,
+            position.count,
+            position.start,
+            position.end
+        ],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    fn part(id: &str, content: &str, section: &str, position: ChunkPosition) -> Document {
+        Document {
+            id: id.to_string(),
+            source_id: "docs".to_string(),
+            content: content.to_string(),
+            created_at: chrono::Utc::now(),
+            title: "VRF Overview".to_string(),
+            section: section.to_string(),
+            topics: Vec::new(),
+            logical_id: None,
+            cleaned: None,
+            chunk: Some(position),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_positions_survive_round_trip() {
+        let mut knowledge = test_utils::knowledge_base().await;
+        let fees = ChunkPosition {
+            index: 5,
+            count: 7,
+            start: 4100,
+            end: 4800,
+        };
+        knowledge
+            .add_documents(vec![
+                part(
+                    "vrf.md#0",
+                    "VRF provides verifiable randomness.",
+                    "VRF",
+                    ChunkPosition {
+                        index: 0,
+                        count: 7,
+                        start: 0,
+                        end: 640,
+                    },
+                ),
+                part(
+                    "vrf.md#5",
+                    "Requests cost a flat fee.",
+                    "VRF > Configuration > Fees",
+                    fees,
+                ),
+            ])
+            .await
+            .unwrap();
+
+        let index = knowledge.clone().document_index();
+        let chunks = knowledge
+            .retrieve(&index, "what do requests cost?", 2)
+            .await
+            .unwrap();
+        let chunk = chunks.iter().find(|chunk| chunk.id == "vrf.md#5").unwrap();
+        assert_eq!(chunk.parent_id, "vrf.md");
+        assert_eq!(chunk.title, "VRF Overview");
+        assert_eq!(chunk.section, "VRF > Configuration > Fees");
+        assert_eq!(chunk.position, fees);
+        assert_eq!(
+            chunk.provenance(),
+            "Source: VRF Overview, section VRF > Configuration > Fees, part 6 of 7 near the end \
+             (characters 4100–4800)"
+        );
+        assert_eq!(chunk.citation(), "VRF Overview — Fees");
+
+        // Looked up by id, as quotes do
+        let chunk = knowledge.get_chunk("vrf.md#0").await.unwrap().unwrap();
+        assert_eq!(chunk.position.index, 0);
+        assert!(chunk.provenance().contains("part 1 of 7 near the start"));
+    }
+
+    #[tokio::test]
+    async fn test_whole_documents_are_one_part() {
+        let mut knowledge = test_utils::knowledge_base().await;
+        let mut document = part(
+            "vrf.md",
+            "VRF provides verifiable randomness.",
+            "",
+            ChunkPosition {
+                index: 1,
+                count: 2,
+                start: 640,
+                end: 675,
+            },
+        );
+        knowledge
+            .add_documents(vec![document.clone()])
+            .await
+            .unwrap();
+        // Stored whole again, its earlier position is dropped
+        document.chunk = None;
+        knowledge.add_documents(vec![document]).await.unwrap();
+
+        let chunk = knowledge.get_chunk("vrf.md").await.unwrap().unwrap();
+        assert_eq!(chunk.parent_id, "vrf.md");
+        assert_eq!(
+            chunk.position,
+            ChunkPosition::whole("VRF provides verifiable randomness.")
+        );
+        assert_eq!(chunk.provenance(), "Source: VRF Overview");
+        assert!(knowledge
+            .chunk_positions(vec!["vrf.md".to_string()])
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_segments_reuse_part_embeddings() {
+        let mut knowledge = test_utils::knowledge_base().await;
+        let (setup, fees) = (
+            "Install the CLI to get set up.\n\n",
+            "Fees are paid in STRK.",
+        );
+        let mut whole = part(
+            "guide.md",
+            &format!("{setup}{fees}"),
+            "",
+            ChunkPosition::whole(""),
+        );
+        whole.chunk = None;
+        let position = |index, start, end| ChunkPosition {
+            index,
+            count: 2,
+            start,
+            end,
+        };
+        knowledge
+            .add_documents(vec![
+                whole,
+                part("guide.md#0", setup, "Setup", position(0, 0, 32)),
+                part("guide.md#1", fees, "Fees", position(1, 32, 54)),
+            ])
+            .await
+            .unwrap();
+
+        let passage = knowledge.get_chunk("guide.md").await.unwrap().unwrap();
+        let segments = [0..setup.len(), setup.len()..passage.content.len()];
+        let calls = || knowledge.embedding_service().metrics().calls();
+        let before = calls();
+        let mut query_vector = None;
+        let scores = knowledge
+            .score_segments(&passage, &segments, "fees paid in STRK", &mut query_vector)
+            .await
+            .unwrap();
+        assert!(scores[1] > scores[0], "{scores:?}");
+        // Only the query was embedded
+        assert_eq!(calls(), before + 1);
+        knowledge
+            .score_segments(&passage, &segments, "fees paid in STRK", &mut query_vector)
+            .await
+            .unwrap();
+        assert_eq!(calls(), before + 1);
+
+        // Text of a part not stored on its own is embedded
+        let passage = knowledge.get_chunk("guide.md#1").await.unwrap().unwrap();
+        let scores = knowledge
+            .score_segments(&passage, &[0..fees.len()], "", &mut query_vector)
+            .await
+            .unwrap();
+        assert!(scores[0] > 0.5, "{scores:?}");
+        assert_eq!(calls(), before + 2);
+    }
+}
@@ -0,0 +1,402 @@
+//! Preprocessing of document content before it is embedded. Markup such as
+//! front matter, MDX imports and JSX tags is noise to the embedding model, so
+//! a cleaned copy is embedded while the original is stored as is.
+
+use std::path::Path;
+
+use regex::Regex;
+
+use super::{diversity::parent_id, models::Document};
+
+/// A single cleaning step. Passes other than [CleanPass::FrontMatter] and
+/// [CleanPass::Drop] leave fenced code blocks untouched.
+#[derive(Clone, Debug)]
+pub enum CleanPass {
+    /// Removes a leading YAML front matter block, keeping its `title` and
+    /// `description` as prepended text.
+    FrontMatter,
+    /// Removes top-level MDX `import` and `export` statements.
+    ImportExport,
+    /// Removes JSX tags and `{/* */}` comments, keeping the text between tags.
+    JsxTags,
+    /// Collapses runs of spaces within lines and runs of blank lines.
+    CollapseWhitespace,
+    /// Removes every match, e.g. navigation or footer boilerplate.
+    Drop(Regex),
+}
+
+impl CleanPass {
+    fn run(&self, content: &str) -> String {
+        match self {
+            Self::FrontMatter => strip_front_matter(content),
+            Self::ImportExport => map_prose(content, strip_imports),
+            Self::JsxTags => map_prose(content, strip_jsx),
+            Self::CollapseWhitespace => map_prose(content, collapse_whitespace)
+                .trim_start_matches('\n')
+                .to_string(),
+            Self::Drop(pattern) => pattern.replace_all(content, "").into_owned(),
+        }
+    }
+}
+
+/// A chain of [CleanPass]es, run in the order they were added. The default
+/// chain is empty and leaves content unchanged.
+#[derive(Clone, Debug, Default)]
+pub struct ContentCleaner {
+    passes: Vec<CleanPass>,
+}
+
+impl ContentCleaner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_pass(mut self, pass: CleanPass) -> Self {
+        self.passes.push(pass);
+        self
+    }
+
+    /// Adds a [CleanPass::Drop] for each pattern.
+    pub fn with_drop_patterns<I, S>(mut self, patterns: I) -> Result<Self, regex::Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        for pattern in patterns {
+            self.passes
+                .push(CleanPass::Drop(Regex::new(pattern.as_ref())?));
+        }
+        Ok(self)
+    }
+
+    /// Plain markdown: only front matter is removed, so files without it are
+    /// embedded exactly as written.
+    pub fn markdown() -> Self {
+        Self::new().with_pass(CleanPass::FrontMatter)
+    }
+
+    pub fn mdx() -> Self {
+        Self::new()
+            .with_pass(CleanPass::FrontMatter)
+            .with_pass(CleanPass::ImportExport)
+            .with_pass(CleanPass::JsxTags)
+            .with_pass(CleanPass::CollapseWhitespace)
+    }
+
+    /// The default chain for files with `extension`.
+    pub fn for_extension(extension: &str) -> Self {
+        match extension.to_lowercase().as_str() {
+            "md" | "markdown" => Self::markdown(),
+            "mdx" => Self::mdx(),
+            _ => Self::new(),
+        }
+    }
+
+    /// The default chain for the extension of `path`, if any.
+    pub fn for_path(path: &str) -> Self {
+        Path::new(path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(Self::for_extension)
+            .unwrap_or_default()
+    }
+
+    pub fn clean(&self, content: &str) -> String {
+        self.passes
+            .iter()
+            .fold(content.to_string(), |content, pass| pass.run(&content))
+    }
+
+    /// Sets the text `document` is embedded with. Nothing is set when cleaning
+    /// changes nothing or would leave no text.
+    pub fn apply(&self, document: &mut Document) {
+        let cleaned = self.clean(&document.content);
+        document.cleaned =
+            (cleaned != document.content && !cleaned.trim().is_empty()).then_some(cleaned);
+    }
+}
+
+/// Cleans documents that were not cleaned by their loader, picking the chain
+/// from the extension of their id, or of their parent's for parts.
+pub(super) fn prepare(mut documents: Vec<Document>) -> Vec<Document> {
+    for document in documents.iter_mut().filter(|d| d.cleaned.is_none()) {
+        let path = document.logical_id.as_deref().unwrap_or(&document.id);
+        ContentCleaner::for_path(parent_id(path)).apply(document);
+    }
+    documents
+}
+
+/// Applies `f` to the text between fenced code blocks, copying the blocks
+/// verbatim.
+fn map_prose(content: &str, f: impl Fn(&str) -> String) -> String {
+    let mut out = String::with_capacity(content.len());
+    let mut prose = String::new();
+    let mut fence: Option<&str> = None;
+
+    for line in content.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        match fence {
+            Some(marker) => {
+                out.push_str(line);
+                if trimmed.starts_with(marker) {
+                    fence = None;
+                }
+            }
+            None => match ["```", "~~~"].into_iter().find(|m| trimmed.starts_with(m)) {
+                Some(marker) => {
+                    out.push_str(&f(&std::mem::take(&mut prose)));
+                    out.push_str(line);
+                    fence = Some(marker);
+                }
+                None => prose.push_str(line),
+            },
+        }
+    }
+    out.push_str(&f(&prose));
+    out
+}
+
+fn strip_front_matter(content: &str) -> String {
+    let Some(rest) = content
+        .strip_prefix("---\n")
+        .or_else(|| content.strip_prefix("---\r\n"))
+    else {
+        return content.to_string();
+    };
+
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if line.trim_end() == "---" {
+            let yaml = &rest[..offset];
+            let body = rest[offset + line.len()..].trim_start_matches(['\r', '\n']);
+            let mut out = String::new();
+            for key in ["title", "description"] {
+                if let Some(value) = front_matter_value(yaml, key) {
+                    out.push_str(&value);
+                    out.push_str("\n\n");
+                }
+            }
+            out.push_str(body);
+            return out;
+        }
+        offset += line.len();
+    }
+
+    // No closing delimiter, so this is not front matter.
+    content.to_string()
+}
+
+pub(super) fn front_matter_value(yaml: &str, key: &str) -> Option<String> {
+    yaml.lines().find_map(|line| {
+        let value = line
+            .strip_prefix(key)?
+            .trim_start()
+            .strip_prefix(':')?
+            .trim();
+        let value = value.trim_matches(|c| c == '"' || c == '\'');
+        (!value.is_empty()).then(|| value.to_string())
+    })
+}
+
+/// Change in bracket nesting over `line`, used to find where a multi-line
+/// statement ends.
+fn bracket_depth(line: &str) -> i32 {
+    line.chars()
+        .map(|c| match c {
+            '{' | '(' | '[' => 1,
+            '}' | ')' | ']' => -1,
+            _ => 0,
+        })
+        .sum()
+}
+
+fn strip_imports(prose: &str) -> String {
+    let mut out = String::with_capacity(prose.len());
+    let mut depth = 0;
+
+    for line in prose.split_inclusive('\n') {
+        if depth > 0 {
+            depth += bracket_depth(line);
+            continue;
+        }
+        if line.starts_with("import ") || line.starts_with("export ") {
+            depth = bracket_depth(line);
+            continue;
+        }
+        out.push_str(line);
+    }
+    out
+}
+
+/// Length of the JSX tag at the start of `text`, or `None` when the `<` does
+/// not open one, as in `a < b` or an autolink like `<https://cartridge.gg>`.
+fn tag_len(text: &str) -> Option<usize> {
+    let name = text[1..].trim_start_matches('/');
+    let first = name.chars().next()?;
+    if !(first.is_ascii_alphabetic() || first == '>') {
+        return None;
+    }
+    let name_end = name
+        .find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+        .unwrap_or(name.len());
+    if name[..name_end].contains([':', '@']) {
+        return None;
+    }
+
+    // Attribute values may contain `>`, as in `onClick={() => open()}`.
+    let mut depth = 0;
+    let mut quote = None;
+    for (i, c) in text.char_indices().skip(1) {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') if depth == 0 => quote = Some(c),
+            (None, '{') => depth += 1,
+            (None, '}') => depth -= 1,
+            (None, '>') if depth == 0 => return Some(i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn strip_jsx(prose: &str) -> String {
+    let mut out = String::with_capacity(prose.len());
+    let mut rest = prose;
+
+    while let Some(start) = rest.find(['<', '{', '`']) {
+        out.push_str(&rest[..start]);
+        let text = &rest[start..];
+
+        let (len, keep) = if let Some(code) = text.strip_prefix('`') {
+            // Inline code is kept as written.
+            let len = code.find('`').map_or(text.len(), |end| end + 2);
+            (len, true)
+        } else if text.starts_with("{/*") {
+            match text.find("*/}") {
+                Some(end) => (end + 3, false),
+                None => (1, true),
+            }
+        } else if text.starts_with('<') {
+            match tag_len(text) {
+                Some(len) => (len, false),
+                None => (1, true),
+            }
+        } else {
+            (1, true)
+        };
+
+        if keep {
+            out.push_str(&text[..len]);
+        }
+        rest = &text[len..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn collapse_whitespace(prose: &str) -> String {
+    let mut out = String::with_capacity(prose.len());
+    let mut blank = false;
+
+    for line in prose.lines() {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if line.is_empty() {
+            if !blank {
+                out.push('\n');
+                blank = true;
+            }
+            continue;
+        }
+        out.push_str(&line);
+        out.push('\n');
+        blank = false;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURES: &[(&str, &str, &str)] = &[
+        (
+            "getting-started.mdx",
+            include_str!("testdata/cleaning/getting-started.mdx"),
+            include_str!("testdata/cleaning/getting-started.txt"),
+        ),
+        (
+            "session-keys.mdx",
+            include_str!("testdata/cleaning/session-keys.mdx"),
+            include_str!("testdata/cleaning/session-keys.txt"),
+        ),
+        (
+            "with-front-matter.md",
+            include_str!("testdata/cleaning/with-front-matter.md"),
+            include_str!("testdata/cleaning/with-front-matter.txt"),
+        ),
+    ];
+
+    #[test]
+    fn test_golden_files() {
+        for (name, input, expected) in FIXTURES {
+            assert_eq!(
+                ContentCleaner::for_path(name).clean(input),
+                *expected,
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_plain_markdown_is_lossless() {
+        let input = include_str!("testdata/cleaning/plain.md");
+        assert_eq!(ContentCleaner::for_path("plain.md").clean(input), input);
+
+        let mut document = Document {
+            id: "docs/plain.md".to_string(),
+            source_id: "github".to_string(),
+            content: input.to_string(),
+            created_at: chrono::Utc::now(),
+            title: String::new(),
+            section: String::new(),
+            topics: Vec::new(),
+            logical_id: None,
+            cleaned: None,
+            chunk: None,
+        };
+        ContentCleaner::markdown().apply(&mut document);
+        assert!(document.cleaned.is_none());
+    }
+
+    #[test]
+    fn test_drop_patterns() {
+        let cleaner = ContentCleaner::new()
+            .with_drop_patterns([r"(?m)^\[Edit this page\]\(.*\)\n?", r"(?m)^Next: .*$"])
+            .unwrap()
+            .with_pass(CleanPass::CollapseWhitespace);
+
+        let cleaned = cleaner.clean(
+            "# Katana\n\nRun a devnet.\n\n[Edit this page](https://github.com/x)\n\nNext: Torii\n",
+        );
+        assert_eq!(cleaned, "# Katana\n\nRun a devnet.\n\n");
+
+        assert!(ContentCleaner::new().with_drop_patterns(["("]).is_err());
+    }
+
+    #[test]
+    fn test_jsx_edge_cases() {
+        let cleaner = ContentCleaner::new().with_pass(CleanPass::JsxTags);
+        assert_eq!(cleaner.clean("if a < b and b > c"), "if a < b and b > c");
+        assert_eq!(
+            cleaner.clean("See <https://cartridge.gg> or `<Tabs>`."),
+            "See <https://cartridge.gg> or `<Tabs>`."
+        );
+        assert_eq!(
+            cleaner
+                .clean("<Button onClick={() => open()} label=\"a > b\">Open</Button>{/* todo */}"),
+            "Open"
+        );
+        assert_eq!(cleaner.clean("<>fragment</>"), "fragment");
+    }
+}
@@ -0,0 +1,145 @@
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+use rusqlite::OptionalExtension;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::store::KnowledgeBase;
+use crate::attention::AttentionCommand;
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS conversation_state (
+        agent_id TEXT NOT NULL,
+        channel_id TEXT NOT NULL,
+        state TEXT NOT NULL,
+        last_message_id TEXT,
+        updated_at TEXT NOT NULL,
+        PRIMARY KEY (agent_id, channel_id)
+    );
+";
+
+/// What the agent keeps track of in a channel beyond the raw messages.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConversationState {
+    /// Rolling summary of the conversation so far.
+    pub summary: Option<String>,
+    /// Questions asked in the channel that have not been answered yet.
+    pub open_questions: Vec<String>,
+    pub last_attention: Option<AttentionCommand>,
+    /// Things the agent said it would come back to.
+    pub pending_follow_ups: Vec<String>,
+    /// Messages received but not yet answered or dismissed, such as a debounce
+    /// window that was open when the bot stopped.
+    pub pending_messages: Vec<String>,
+    /// Newest message this state reflects. The state is only stored once
+    /// that message is, so it is never newer than the history.
+    pub last_message_id: Option<String>,
+    /// Documents the last answer was given from, for quoting them when asked
+    /// where it came from, see [crate::quotes].
+    pub last_sources: Vec<SourceRef>,
+}
+
+/// The part of a document added to a prompt, as byte offsets into its
+/// content.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceRef {
+    pub document_id: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl ConversationState {
+    /// Notes a stored message that still awaits an answer.
+    pub fn receive(&mut self, message_id: &str) {
+        if !self.pending_messages.iter().any(|id| id == message_id) {
+            self.pending_messages.push(message_id.to_string());
+        }
+        self.last_message_id = Some(message_id.to_string());
+    }
+
+    /// Ends the rolling summary once the session it covers is summarized,
+    /// see [crate::memory].
+    pub fn close_session(&mut self) {
+        self.summary = None;
+    }
+
+    /// Records the attention decision on a batch, which settles its messages.
+    pub fn settle(&mut self, message_ids: &[String], decision: AttentionCommand) {
+        self.pending_messages.retain(|id| !message_ids.contains(id));
+        self.last_attention = Some(decision);
+    }
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// The stored state of a channel. A state whose last message is missing
+    /// from the history, e.g. after restoring an older backup of messages, is
+    /// discarded.
+    pub async fn load_conversation_state(
+        &self,
+        channel_id: &str,
+    ) -> Result<Option<ConversationState>, SqliteError> {
+        let channel_id = channel_id.to_string();
+        let namespace = self.namespace.clone();
+
+        let row = self
+            .conn
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        "SELECT s.state, s.last_message_id IS NULL OR m.id IS NOT NULL
+                         FROM conversation_state s
+                         LEFT JOIN messages m ON m.id = s.last_message_id AND m.agent_id = s.agent_id
+                         WHERE s.agent_id = ?1 AND s.channel_id = ?2",
+                        [&namespace, &channel_id],
+                        |row| Ok((row.get::<_, String>(0)?, row.get::<_, bool>(1)?)),
+                    )
+                    .optional()?)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))?;
+
+        match row {
+            Some((state, true)) => serde_json::from_str(&state)
+                .map(Some)
+                .map_err(|e| SqliteError::SerializationError(Box::new(e))),
+            Some((_, false)) => {
+                warn!("Discarding conversation state ahead of stored messages");
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Stores the state of a channel. Returns `false` without writing when
+    /// its last message is not stored yet.
+    pub async fn save_conversation_state(
+        &self,
+        channel_id: &str,
+        state: &ConversationState,
+    ) -> Result<bool, SqliteError> {
+        let json = serde_json::to_string(state)
+            .map_err(|e| SqliteError::SerializationError(Box::new(e)))?;
+        let channel_id = channel_id.to_string();
+        let last_message_id = state.last_message_id.clone();
+        let namespace = self.namespace.clone();
+        let now = self.clock.now_utc().to_rfc3339();
+
+        self.conn
+            .call(move |conn| {
+                let written = conn.execute(
+                    "INSERT INTO conversation_state (agent_id, channel_id, state, last_message_id, updated_at)
+                     SELECT ?1, ?2, ?3, ?4, ?5
+                     WHERE ?4 IS NULL OR EXISTS (SELECT 1 FROM messages WHERE id = ?4 AND agent_id = ?1)
+                     ON CONFLICT (agent_id, channel_id) DO UPDATE SET
+                         state = excluded.state,
+                         last_message_id = excluded.last_message_id,
+                         updated_at = excluded.updated_at",
+                    rusqlite::params![namespace, channel_id, json, last_message_id, now],
+                )?;
+                Ok(written > 0)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+}
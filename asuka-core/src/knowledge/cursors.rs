@@ -0,0 +1,84 @@
+//! Read positions in polled feeds, such as a client's mention feed, so a
+//! restart resumes where the last poll stopped instead of replaying history.
+
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+use rusqlite::OptionalExtension;
+
+use super::store::KnowledgeBase;
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS feed_cursors (
+        agent_id TEXT NOT NULL,
+        feed TEXT NOT NULL,
+        cursor TEXT NOT NULL,
+        updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+        PRIMARY KEY (agent_id, feed)
+    );
+";
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// The cursor last stored for `feed`, if any.
+    pub async fn feed_cursor(&self, feed: &str) -> Result<Option<String>, SqliteError> {
+        let feed = feed.to_string();
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        "SELECT cursor FROM feed_cursors WHERE agent_id = ?1 AND feed = ?2",
+                        [&namespace, &feed],
+                        |row| row.get(0),
+                    )
+                    .optional()?)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    pub async fn set_feed_cursor(&self, feed: &str, cursor: &str) -> Result<(), SqliteError> {
+        let feed = feed.to_string();
+        let cursor = cursor.to_string();
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT INTO feed_cursors (agent_id, feed, cursor) VALUES (?1, ?2, ?3)
+                     ON CONFLICT (agent_id, feed) DO UPDATE SET
+                         cursor = excluded.cursor,
+                         updated_at = CURRENT_TIMESTAMP",
+                    [&namespace, &feed, &cursor],
+                )?;
+                Ok(())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn test_feed_cursor_round_trip() {
+        let knowledge = test_utils::knowledge_base().await;
+        assert_eq!(knowledge.feed_cursor("farcaster:1").await.unwrap(), None);
+
+        knowledge.set_feed_cursor("farcaster:1", "a").await.unwrap();
+        knowledge.set_feed_cursor("farcaster:1", "b").await.unwrap();
+        assert_eq!(
+            knowledge
+                .feed_cursor("farcaster:1")
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("b")
+        );
+
+        let other = knowledge.clone().with_namespace("companion");
+        assert_eq!(other.feed_cursor("farcaster:1").await.unwrap(), None);
+    }
+}
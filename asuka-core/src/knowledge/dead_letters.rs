@@ -0,0 +1,84 @@
+use chrono::{DateTime, Utc};
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+
+use super::store::KnowledgeBase;
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS webhook_dead_letters (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        url TEXT NOT NULL,
+        event TEXT NOT NULL,
+        payload TEXT NOT NULL,
+        error TEXT NOT NULL,
+        attempts INTEGER NOT NULL,
+        created_at TEXT NOT NULL
+    );
+";
+
+/// A webhook payload that could not be delivered after every retry, see
+/// [crate::webhooks].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadLetter {
+    pub url: String,
+    /// Name of the event, see [Event::name](crate::events::Event::name).
+    pub event: String,
+    /// The JSON body as it was signed.
+    pub payload: String,
+    /// Error of the last attempt.
+    pub error: String,
+    pub attempts: u32,
+    pub created_at: DateTime<Utc>,
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    pub async fn record_dead_letter(&self, letter: DeadLetter) -> Result<(), SqliteError> {
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT INTO webhook_dead_letters
+                         (url, event, payload, error, attempts, created_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    rusqlite::params![
+                        letter.url,
+                        letter.event,
+                        letter.payload,
+                        letter.error,
+                        letter.attempts,
+                        letter.created_at.to_rfc3339()
+                    ],
+                )?;
+                Ok(())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// The `limit` newest undelivered payloads.
+    pub async fn dead_letters(&self, limit: usize) -> Result<Vec<DeadLetter>, SqliteError> {
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT url, event, payload, error, attempts, created_at
+                     FROM webhook_dead_letters
+                     ORDER BY id DESC
+                     LIMIT ?1",
+                )?;
+                let letters = stmt
+                    .query_map([limit], |row| {
+                        Ok(DeadLetter {
+                            url: row.get(0)?,
+                            event: row.get(1)?,
+                            payload: row.get(2)?,
+                            error: row.get(3)?,
+                            attempts: row.get(4)?,
+                            created_at: row.get(5)?,
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(letters)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+}
@@ -0,0 +1,261 @@
+//! Requests the bot could not answer while its completion provider was rate
+//! limiting it or down, answered once it recovers, see [crate::outage].
+//!
+//! Each row keeps the message it answers, so the follow-up is stored and
+//! recorded like any reply, and the notice that told the channel about the
+//! wait, if that message got it.
+
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+
+use super::{models::Message, store::KnowledgeBase, types::Source};
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS deferred_replies (
+        id TEXT NOT NULL,
+        agent_id TEXT NOT NULL,
+        source TEXT NOT NULL,
+        source_id TEXT NOT NULL,
+        channel_type TEXT NOT NULL,
+        channel_id TEXT NOT NULL,
+        account_id TEXT NOT NULL,
+        role TEXT NOT NULL,
+        content TEXT NOT NULL,
+        created_at TEXT NOT NULL,
+        request TEXT NOT NULL,
+        interaction_id INTEGER,
+        notice_id TEXT,
+        deferred_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        PRIMARY KEY (agent_id, channel_id, id)
+    );
+";
+
+/// A request waiting for the provider to recover.
+#[derive(Debug, Clone)]
+pub struct DeferredReply {
+    /// The message answered, the last of its batch.
+    pub message: Message,
+    /// What is answered: the content of the message's batch.
+    pub request: String,
+    pub interaction_id: Option<i64>,
+    /// Platform id of the notice sent about the wait, when this request got
+    /// it rather than an earlier one of its channel.
+    pub notice_id: Option<String>,
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Queues `request`, received as `message`, to be answered later, unless
+    /// its channel already has `max_per_channel` queued. Returns whether it
+    /// was queued.
+    pub async fn defer_reply(
+        &self,
+        message: &Message,
+        request: &str,
+        interaction_id: Option<i64>,
+        max_per_channel: usize,
+    ) -> Result<bool, SqliteError> {
+        let namespace = self.namespace.clone();
+        let msg = message.clone();
+        let request = request.to_string();
+
+        self.conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let queued: usize = tx.query_row(
+                    "SELECT COUNT(*) FROM deferred_replies WHERE agent_id = ?1 AND channel_id = ?2",
+                    [&namespace, &msg.channel_id],
+                    |row| row.get(0),
+                )?;
+                if queued >= max_per_channel {
+                    return Ok(false);
+                }
+                let inserted = tx.execute(
+                    "INSERT OR IGNORE INTO deferred_replies (id, agent_id, source, source_id,
+                         channel_type, channel_id, account_id, role, content, created_at,
+                         request, interaction_id)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                    rusqlite::params![
+                        msg.id,
+                        namespace,
+                        msg.source.as_str(),
+                        msg.source_id,
+                        msg.channel_type.as_str(),
+                        msg.channel_id,
+                        msg.account_id,
+                        msg.role,
+                        msg.content,
+                        msg.created_at.to_rfc3339(),
+                        request,
+                        interaction_id
+                    ],
+                )?;
+                tx.commit()?;
+                Ok(inserted > 0)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Links the deferred reply to `message_id` with the notice telling its
+    /// channel about the wait.
+    pub async fn set_deferred_notice(
+        &self,
+        channel_id: &str,
+        message_id: &str,
+        notice_id: &str,
+    ) -> Result<(), SqliteError> {
+        let namespace = self.namespace.clone();
+        let params = [
+            namespace,
+            channel_id.to_string(),
+            message_id.to_string(),
+            notice_id.to_string(),
+        ];
+
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "UPDATE deferred_replies SET notice_id = ?4
+                     WHERE agent_id = ?1 AND channel_id = ?2 AND id = ?3",
+                    params,
+                )?;
+                Ok(())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// At most `limit` deferred replies to messages from `source`, the
+    /// longest waiting first.
+    pub async fn deferred_replies(
+        &self,
+        source: &Source,
+        limit: usize,
+    ) -> Result<Vec<DeferredReply>, SqliteError> {
+        let namespace = self.namespace.clone();
+        let source = source.as_str().to_string();
+
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT {}, request, interaction_id, notice_id FROM deferred_replies
+                     WHERE agent_id = ?1 AND source = ?2
+                     ORDER BY deferred_at, created_at
+                     LIMIT ?3",
+                    Message::COLUMNS
+                ))?;
+                let deferred = stmt
+                    .query_map(rusqlite::params![namespace, source, limit], |row| {
+                        Ok(DeferredReply {
+                            message: Message::try_from(row)?,
+                            request: row.get(9)?,
+                            interaction_id: row.get(10)?,
+                            notice_id: row.get(11)?,
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(deferred)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Removes the deferred reply to `message_id`, once answered or given up.
+    pub async fn remove_deferred(
+        &self,
+        channel_id: &str,
+        message_id: &str,
+    ) -> Result<(), SqliteError> {
+        let namespace = self.namespace.clone();
+        let params = [namespace, channel_id.to_string(), message_id.to_string()];
+
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "DELETE FROM deferred_replies
+                     WHERE agent_id = ?1 AND channel_id = ?2 AND id = ?3",
+                    params,
+                )?;
+                Ok(())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::knowledge::{ChannelType, Message, Source};
+    use crate::test_utils;
+
+    fn message(id: &str, channel_id: &str) -> Message {
+        Message {
+            id: id.to_string(),
+            source: Source::Telegram,
+            source_id: "alice".to_string(),
+            channel_type: ChannelType::Text,
+            channel_id: channel_id.to_string(),
+            account_id: "alice".to_string(),
+            role: "user".to_string(),
+            content: format!("question {id}"),
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deferred_replies_are_capped_per_channel() {
+        let knowledge = test_utils::knowledge_base().await;
+        assert!(knowledge
+            .defer_reply(&message("m1", "c1"), "question m1", Some(7), 2)
+            .await
+            .unwrap());
+        // Queuing again keeps one row
+        assert!(!knowledge
+            .defer_reply(&message("m1", "c1"), "question m1", Some(7), 2)
+            .await
+            .unwrap());
+        assert!(knowledge
+            .defer_reply(&message("m2", "c1"), "question m2", None, 2)
+            .await
+            .unwrap());
+        assert!(!knowledge
+            .defer_reply(&message("m3", "c1"), "question m3", None, 2)
+            .await
+            .unwrap());
+        assert!(knowledge
+            .defer_reply(&message("m4", "c2"), "question m4", None, 2)
+            .await
+            .unwrap());
+        assert!(knowledge
+            .deferred_replies(&Source::Discord, 10)
+            .await
+            .unwrap()
+            .is_empty());
+        knowledge
+            .set_deferred_notice("c1", "m1", "notice-1")
+            .await
+            .unwrap();
+
+        let deferred = knowledge
+            .deferred_replies(&Source::Telegram, 10)
+            .await
+            .unwrap();
+        assert_eq!(deferred.len(), 3);
+        let first = &deferred[0];
+        assert_eq!(first.message.id, "m1");
+        assert_eq!(first.message.source, Source::Telegram);
+        assert_eq!(first.interaction_id, Some(7));
+        assert_eq!(first.notice_id.as_deref(), Some("notice-1"));
+        assert_eq!(deferred[1].notice_id, None);
+
+        knowledge.remove_deferred("c1", "m1").await.unwrap();
+        let ids = knowledge
+            .deferred_replies(&Source::Telegram, 10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|deferred| deferred.message.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["m2", "m4"]);
+    }
+}
@@ -0,0 +1,349 @@
+//! Diversity of retrieved documents, so one long page split into many parts
+//! does not fill every slot of the context and crowd out the next most
+//! relevant page.
+//!
+//! [DiverseIndex] over-fetches candidates and re-selects them by maximal
+//! marginal relevance (MMR): each pick is the candidate most relevant to the
+//! query, less its similarity to what was already picked. Picks are also
+//! capped per parent document. A part of a document is stored under
+//! `<parent id>#<part>`, and whole documents are their own parent, so parts
+//! are grouped with each other but not with other versions or pages.
+//!
+//! ```toml
+//! [diversity]
+//! lambda = 0.7
+//! max_per_document = 2
+//! overfetch = 3
+//! ```
+
+use std::collections::HashMap;
+
+use rig::{
+    embeddings::EmbeddingModel,
+    vector_store::{VectorStoreError, VectorStoreIndex},
+};
+use serde::Deserialize;
+use tracing::debug;
+
+use super::{
+    gaps::{cosine_similarity, from_blob},
+    store::KnowledgeBase,
+};
+
+/// `[diversity]` settings of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiversityConfig {
+    /// Weight of relevance against novelty, in `0.0..=1.0`. `1.0` keeps the
+    /// plain ranking, lower values favour documents unlike those picked.
+    pub lambda: f64,
+    /// Most parts of one document in the results.
+    pub max_per_document: usize,
+    /// Candidates fetched per requested result to select from.
+    pub overfetch: usize,
+}
+
+impl Default for DiversityConfig {
+    fn default() -> Self {
+        Self {
+            lambda: 0.7,
+            max_per_document: 2,
+            overfetch: 3,
+        }
+    }
+}
+
+impl DiversityConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.lambda) {
+            return Err(format!(
+                "diversity.lambda: {} is not between 0 and 1",
+                self.lambda
+            ));
+        }
+        if self.max_per_document == 0 {
+            return Err("diversity.max_per_document must be at least 1".to_string());
+        }
+        if self.overfetch == 0 {
+            return Err("diversity.overfetch must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// The document a stored part belongs to, the id itself for whole documents.
+pub fn parent_id(id: &str) -> &str {
+    id.split_once('#').map_or(id, |(parent, _)| parent)
+}
+
+/// Relevance of a candidate at L2 `distance` from the query, as the cosine
+/// similarity it corresponds to for normalized embeddings.
+fn relevance(distance: f64) -> f64 {
+    1.0 - distance * distance / 2.0
+}
+
+/// A retrieved candidate to select from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    /// L2 distance from the query.
+    pub distance: f64,
+    /// Document the candidate is a part of, see [parent_id].
+    pub parent: String,
+    /// Empty when not known, which makes the candidate unlike all others.
+    pub embedding: Vec<f64>,
+}
+
+/// Indices of at most `n` of `candidates`, in the order picked by maximal
+/// marginal relevance with at most [DiversityConfig::max_per_document] per
+/// parent. Ties keep the original order.
+pub fn select_diverse(candidates: &[Candidate], n: usize, config: &DiversityConfig) -> Vec<usize> {
+    let mut selected: Vec<usize> = Vec::new();
+    let mut per_parent: HashMap<&str, usize> = HashMap::new();
+
+    while selected.len() < n {
+        let mut best: Option<(usize, f64)> = None;
+        for (i, candidate) in candidates.iter().enumerate() {
+            if selected.contains(&i)
+                || per_parent
+                    .get(candidate.parent.as_str())
+                    .copied()
+                    .unwrap_or(0)
+                    >= config.max_per_document
+            {
+                continue;
+            }
+            let redundancy = selected
+                .iter()
+                .map(|&j| similarity(candidate, &candidates[j]))
+                .fold(0.0, f64::max);
+            let score =
+                config.lambda * relevance(candidate.distance) - (1.0 - config.lambda) * redundancy;
+            if best.map_or(true, |(_, best)| score > best) {
+                best = Some((i, score));
+            }
+        }
+        let Some((i, _)) = best else {
+            break;
+        };
+        *per_parent.entry(candidates[i].parent.as_str()).or_default() += 1;
+        selected.push(i);
+    }
+    selected
+}
+
+fn similarity(a: &Candidate, b: &Candidate) -> f64 {
+    if a.embedding.is_empty() || b.embedding.is_empty() {
+        0.0
+    } else {
+        cosine_similarity(&a.embedding, &b.embedding)
+    }
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Wraps a document index so its results are re-selected for diversity,
+    /// or passed through unchanged without a `config`.
+    pub fn diverse_index<I: VectorStoreIndex>(
+        self,
+        index: I,
+        config: Option<DiversityConfig>,
+    ) -> DiverseIndex<I, E> {
+        DiverseIndex {
+            index,
+            knowledge: self,
+            config,
+        }
+    }
+
+    async fn load_embeddings(
+        &self,
+        document_ids: Vec<String>,
+    ) -> Result<HashMap<String, Vec<f64>>, tokio_rusqlite::Error> {
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT e.embedding FROM documents d
+                     JOIN documents_embeddings e ON e.rowid = d.rowid
+                     WHERE d.id = ?1",
+                )?;
+                let mut embeddings = HashMap::new();
+                for id in document_ids {
+                    match stmt.query_row([&id], |row| row.get::<_, Vec<u8>>(0)) {
+                        Ok(blob) => {
+                            embeddings.insert(id, from_blob(&blob));
+                        }
+                        Err(rusqlite::Error::QueryReturnedNoRows) => {}
+                        Err(err) => return Err(err.into()),
+                    }
+                }
+                Ok(embeddings)
+            })
+            .await
+    }
+}
+
+pub struct DiverseIndex<I: VectorStoreIndex, E: EmbeddingModel + 'static> {
+    index: I,
+    knowledge: KnowledgeBase<E>,
+    config: Option<DiversityConfig>,
+}
+
+impl<I: VectorStoreIndex, E: EmbeddingModel + Sync> DiverseIndex<I, E> {
+    fn fetch_size(&self, n: usize) -> usize {
+        match &self.config {
+            Some(config) => n * config.overfetch.max(1),
+            None => n,
+        }
+    }
+
+    async fn diverse<T: Send>(
+        &self,
+        n: usize,
+        candidates: Vec<(f64, String, T)>,
+    ) -> Result<Vec<(f64, String, T)>, VectorStoreError> {
+        let Some(config) = &self.config else {
+            return Ok(candidates.into_iter().take(n).collect());
+        };
+        let ids = candidates.iter().map(|(_, id, _)| id.clone()).collect();
+        let mut embeddings = self
+            .knowledge
+            .load_embeddings(ids)
+            .await
+            .map_err(|e| VectorStoreError::DatastoreError(Box::new(e)))?;
+
+        let scored = candidates
+            .iter()
+            .map(|(distance, id, _)| Candidate {
+                distance: *distance,
+                parent: parent_id(id).to_string(),
+                embedding: embeddings.remove(id).unwrap_or_default(),
+            })
+            .collect::<Vec<_>>();
+        let order = select_diverse(&scored, n, config);
+        debug!(
+            candidates = candidates.len(),
+            selected = order.len(),
+            "Selected diverse documents"
+        );
+
+        let mut candidates = candidates.into_iter().map(Some).collect::<Vec<_>>();
+        Ok(order
+            .into_iter()
+            .filter_map(|i| candidates[i].take())
+            .collect())
+    }
+}
+
+impl<I: VectorStoreIndex, E: EmbeddingModel + Sync> VectorStoreIndex for DiverseIndex<I, E> {
+    async fn top_n<T: for<'a> Deserialize<'a> + Send>(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<(f64, String, T)>, VectorStoreError> {
+        let candidates = self.index.top_n::<T>(query, self.fetch_size(n)).await?;
+        self.diverse(n, candidates).await
+    }
+
+    async fn top_n_ids(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<(f64, String)>, VectorStoreError> {
+        let candidates = self
+            .index
+            .top_n_ids(query, self.fetch_size(n))
+            .await?
+            .into_iter()
+            .map(|(distance, id)| (distance, id, ()))
+            .collect();
+
+        Ok(self
+            .diverse(n, candidates)
+            .await?
+            .into_iter()
+            .map(|(distance, id, _)| (distance, id))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parts of a long page about VRF, all close to the query and to each
+    /// other, ahead of a page about fees a little further from the query.
+    fn candidates() -> Vec<Candidate> {
+        let part = |distance: f64, parent: &str, embedding: [f64; 2]| Candidate {
+            distance,
+            parent: parent.to_string(),
+            embedding: embedding.to_vec(),
+        };
+        vec![
+            part(0.30, "vrf.md", [1.0, 0.0]),
+            part(0.31, "vrf.md", [0.99, 0.1]),
+            part(0.32, "vrf.md", [0.98, 0.2]),
+            part(0.33, "vrf.md", [0.97, 0.25]),
+            part(0.50, "fees.md", [0.0, 1.0]),
+            part(0.55, "fees.md", [0.1, 0.99]),
+        ]
+    }
+
+    fn parents(candidates: &[Candidate], selected: &[usize]) -> Vec<String> {
+        selected
+            .iter()
+            .map(|&i| candidates[i].parent.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_diverse_selection_covers_more_documents() {
+        let candidates = candidates();
+
+        // Plain top-k, which a lambda of 1 and no cap reproduce, is all VRF
+        let plain = DiversityConfig {
+            lambda: 1.0,
+            max_per_document: usize::MAX,
+            overfetch: 1,
+        };
+        let top_k = select_diverse(&candidates, 3, &plain);
+        assert_eq!(top_k, [0, 1, 2]);
+
+        // MMR picks the fees page second, as it is unlike the first pick
+        let mmr = DiversityConfig {
+            max_per_document: usize::MAX,
+            ..Default::default()
+        };
+        assert_eq!(
+            parents(&candidates, &select_diverse(&candidates, 3, &mmr)),
+            ["vrf.md", "fees.md", "vrf.md"]
+        );
+
+        // The cap alone also makes room for the fees page
+        let capped = DiversityConfig {
+            lambda: 1.0,
+            max_per_document: 2,
+            overfetch: 1,
+        };
+        let selected = select_diverse(&candidates, 4, &capped);
+        assert_eq!(selected, [0, 1, 4, 5]);
+        for parent in ["vrf.md", "fees.md"] {
+            let count = parents(&candidates, &selected)
+                .iter()
+                .filter(|p| *p == parent)
+                .count();
+            assert!(count <= 2, "{parent} has {count} parts");
+        }
+
+        // Running out of candidates under the cap returns fewer results
+        let one_each = DiversityConfig {
+            max_per_document: 1,
+            ..Default::default()
+        };
+        assert_eq!(select_diverse(&candidates, 5, &one_each).len(), 2);
+    }
+
+    #[test]
+    fn test_parent_id() {
+        assert_eq!(parent_id("docs/vrf.md#3"), "docs/vrf.md");
+        assert_eq!(parent_id("docs/vrf.md"), "docs/vrf.md");
+    }
+}
@@ -0,0 +1,488 @@
+//! Which messages are worth an embedding. Many messages are "ok", "lol" or
+//! "ty": each would cost an embedding call and a vector row, and only crowd
+//! semantic search with matches that say nothing.
+//!
+//! With a policy set, see [KnowledgeBase::with_embedding_policy], messages
+//! shorter than [EmbeddingPolicy::min_chars] or matching one of
+//! [EmbeddingPolicy::skip_patterns] are stored without an embedding. The
+//! reason is kept in their `embedding_skipped` column. They still show in
+//! history, which reads by recency, but semantic search can't find them.
+//! [KnowledgeBase::backfill_embeddings] embeds those a changed policy no
+//! longer skips.
+//!
+//! ```toml
+//! [embedding_policy]
+//! min_chars = 20
+//! skip_patterns = ["^(ok|okay|lol|ty|thx|thanks)[.!]*$"]
+//! ```
+
+use std::fmt;
+
+use regex::Regex;
+use rig::{
+    embeddings::{Embedding, EmbeddingModel, EmbeddingsBuilder},
+    OneOrMany,
+};
+use rig_sqlite::{SqliteError, SqliteVectorStore, SqliteVectorStoreTable};
+use serde::Deserialize;
+use tracing::{debug, info};
+
+use super::{models::Message, store::KnowledgeBase};
+
+/// Acknowledgements and greetings skipped by default, matched against the
+/// whole trimmed message, ignoring case.
+const LOW_VALUE_PATTERNS: &[&str] = &[
+    r"^(ok|okay|k|kk|lol|lmao|ty|thx|thanks|thank you|np|yes|yeah|yep|no|nope|nice|cool|gm|gn)[.!?]*$",
+    r"^[+-]1$",
+];
+
+pub(super) fn migrate(conn: &mut rusqlite::Connection) -> rusqlite::Result<()> {
+    let exists = conn
+        .prepare("SELECT 1 FROM pragma_table_info('messages') WHERE name = 'embedding_skipped'")?
+        .exists([])?;
+    if !exists {
+        info!("Adding embedding_skipped column to messages");
+        conn.execute_batch("ALTER TABLE messages ADD COLUMN embedding_skipped TEXT")?;
+    }
+    Ok(())
+}
+
+/// `[embedding_policy]` settings of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmbeddingPolicy {
+    /// Characters below which a trimmed message is not embedded.
+    pub min_chars: usize,
+    /// Regular expressions of messages not embedded, matched against the
+    /// trimmed message, ignoring case.
+    pub skip_patterns: Vec<String>,
+}
+
+impl Default for EmbeddingPolicy {
+    fn default() -> Self {
+        Self {
+            min_chars: 20,
+            skip_patterns: LOW_VALUE_PATTERNS.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+}
+
+/// Why a message was stored without an embedding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SkipReason {
+    TooShort,
+    LowValue,
+}
+
+impl SkipReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::TooShort => "too_short",
+            Self::LowValue => "low_value",
+        }
+    }
+
+    pub fn from_str(reason: &str) -> Option<Self> {
+        match reason {
+            "too_short" => Some(Self::TooShort),
+            "low_value" => Some(Self::LowValue),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl EmbeddingPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        self.compile().map(drop)
+    }
+
+    /// Compiles the skip patterns, once rather than for every message.
+    pub fn compile(&self) -> Result<CompiledEmbeddingPolicy, String> {
+        let skip_patterns = self
+            .skip_patterns
+            .iter()
+            .map(|pattern| {
+                Regex::new(&format!("(?i){pattern}"))
+                    .map_err(|err| format!("embedding_policy.skip_patterns: {pattern:?}: {err}"))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(CompiledEmbeddingPolicy {
+            policy: self.clone(),
+            skip_patterns,
+        })
+    }
+}
+
+/// An [EmbeddingPolicy] with its patterns compiled, see
+/// [EmbeddingPolicy::compile].
+#[derive(Clone, Debug)]
+pub struct CompiledEmbeddingPolicy {
+    policy: EmbeddingPolicy,
+    skip_patterns: Vec<Regex>,
+}
+
+impl CompiledEmbeddingPolicy {
+    pub fn policy(&self) -> &EmbeddingPolicy {
+        &self.policy
+    }
+
+    /// Why a message with `content` is not embedded, `None` when it is.
+    pub fn skip_reason(&self, content: &str) -> Option<SkipReason> {
+        let content = content.trim();
+        if content.chars().count() < self.policy.min_chars {
+            return Some(SkipReason::TooShort);
+        }
+        self.skip_patterns
+            .iter()
+            .any(|pattern| pattern.is_match(content))
+            .then_some(SkipReason::LowValue)
+    }
+}
+
+/// Stored messages by whether they were embedded, see
+/// [KnowledgeBase::embedding_stats].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EmbeddingStats {
+    pub embedded: usize,
+    pub too_short: usize,
+    pub low_value: usize,
+}
+
+impl EmbeddingStats {
+    pub fn skipped(&self) -> usize {
+        self.too_short + self.low_value
+    }
+}
+
+/// A message ready to be written: embedded, or skipped by the policy.
+pub(super) enum PreparedMessage {
+    Embedded(Vec<(Message, OneOrMany<Embedding>)>),
+    Skipped(Message, SkipReason),
+}
+
+impl PreparedMessage {
+    /// Inserts the message, with its embedding unless skipped, and returns
+    /// its rowid.
+    pub(super) fn insert<E: EmbeddingModel>(
+        self,
+        tx: &rusqlite::Transaction,
+        store: &SqliteVectorStore<E, Message>,
+    ) -> Result<i64, tokio_rusqlite::Error> {
+        let (msg, reason) = match self {
+            Self::Embedded(embeddings) => return store.add_rows_with_txn(tx, embeddings),
+            Self::Skipped(msg, reason) => (msg, reason),
+        };
+        // As the vector store inserts rows, less the embedding
+        let values = msg.column_values();
+        let placeholders = (1..=values.len())
+            .map(|i| format!("?{i}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        tx.execute(
+            &format!(
+                "INSERT OR REPLACE INTO messages ({}) VALUES ({placeholders})",
+                Message::COLUMNS
+            ),
+            rusqlite::params_from_iter(values.iter().map(|(_, value)| value.to_sql_string())),
+        )?;
+        let rowid = tx.last_insert_rowid();
+        tx.execute(
+            "UPDATE messages SET embedding_skipped = ?1 WHERE rowid = ?2",
+            rusqlite::params![reason.as_str(), rowid],
+        )?;
+        Ok(rowid)
+    }
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Stores messages the policy skips without an embedding. Without a
+    /// policy every message is embedded.
+    pub fn with_embedding_policy(mut self, policy: CompiledEmbeddingPolicy) -> Self {
+        self.embedding_policy = Some(policy);
+        self
+    }
+
+    pub fn embedding_policy(&self) -> Option<&EmbeddingPolicy> {
+        self.embedding_policy
+            .as_ref()
+            .map(CompiledEmbeddingPolicy::policy)
+    }
+
+    /// Embeds `msg`, unless the policy skips it.
+    pub(super) async fn prepare_message(&self, msg: Message) -> anyhow::Result<PreparedMessage> {
+        let skipped = self
+            .embedding_policy
+            .as_ref()
+            .and_then(|policy| policy.skip_reason(&msg.content));
+        if let Some(reason) = skipped {
+            debug!(id = msg.id, %reason, "Not embedding message");
+            return Ok(PreparedMessage::Skipped(msg, reason));
+        }
+        let embeddings = EmbeddingsBuilder::new(self.embedding_model.clone())
+            .documents(vec![msg])?
+            .build()
+            .await?;
+        Ok(PreparedMessage::Embedded(embeddings))
+    }
+
+    /// Messages of this namespace stored with and without an embedding.
+    pub async fn embedding_stats(&self) -> Result<EmbeddingStats, SqliteError> {
+        let namespace = self.namespace.clone();
+        self.conn
+            .call(move |conn| {
+                let mut stats = EmbeddingStats::default();
+                let mut stmt = conn.prepare(
+                    "SELECT embedding_skipped, COUNT(*) FROM messages
+                     WHERE agent_id = ?1 GROUP BY embedding_skipped",
+                )?;
+                let counts = stmt.query_map([&namespace], |row| {
+                    Ok((row.get::<_, Option<String>>(0)?, row.get::<_, usize>(1)?))
+                })?;
+                for count in counts {
+                    let (reason, count) = count?;
+                    match reason.as_deref().and_then(SkipReason::from_str) {
+                        None => stats.embedded += count,
+                        Some(SkipReason::TooShort) => stats.too_short += count,
+                        Some(SkipReason::LowValue) => stats.low_value += count,
+                    }
+                }
+                Ok(stats)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Embeds the skipped messages of this namespace that the policy, or its
+    /// absence, no longer skips, `chunk_size` per transaction. Returns how
+    /// many were embedded.
+    pub async fn backfill_embeddings(&self, chunk_size: usize) -> Result<usize, SqliteError> {
+        let namespace = self.namespace.clone();
+        let skipped = self
+            .conn
+            .call(move |conn| {
+                let rows = conn
+                    .prepare(
+                        "SELECT rowid, content FROM messages
+                         WHERE agent_id = ?1 AND embedding_skipped IS NOT NULL
+                         ORDER BY rowid",
+                    )?
+                    .query_map([&namespace], |row| {
+                        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(rows)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))?;
+
+        let rowids = skipped
+            .into_iter()
+            .filter(|(_, content)| {
+                self.embedding_policy
+                    .as_ref()
+                    .and_then(|policy| policy.skip_reason(content))
+                    .is_none()
+            })
+            .map(|(rowid, _)| rowid)
+            .collect::<Vec<_>>();
+        if rowids.is_empty() {
+            return Ok(0);
+        }
+
+        let embedded = self
+            .reembed::<Message>(&rowids, chunk_size, |messages| messages)
+            .await?;
+        self.conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                for rowid in &rowids {
+                    tx.execute(
+                        "UPDATE messages SET embedding_skipped = NULL WHERE rowid = ?1",
+                        [rowid],
+                    )?;
+                }
+                tx.commit()?;
+                Ok(())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))?;
+        info!(embedded, "Backfilled message embeddings");
+        Ok(embedded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::knowledge::{ChannelType, Source};
+    use crate::test_utils::{self, FlakyEmbeddingModel};
+    use rig::vector_store::VectorStoreIndex;
+
+    fn message(id: &str, content: &str) -> Message {
+        Message {
+            id: id.to_string(),
+            source: Source::Discord,
+            source_id: "alice".to_string(),
+            channel_type: ChannelType::Text,
+            channel_id: "c1".to_string(),
+            account_id: "alice".to_string(),
+            role: "user".to_string(),
+            content: content.to_string(),
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_skip_decisions() {
+        let policy = EmbeddingPolicy::default().compile().unwrap();
+        for (content, expected) in [
+            ("ok", Some(SkipReason::TooShort)),
+            ("  lol  ", Some(SkipReason::TooShort)),
+            ("🔥", Some(SkipReason::TooShort)),
+            ("how do I run katana?", None),
+            ("Thank you!!!!!!!!!!!!!!!!!", Some(SkipReason::LowValue)),
+            ("thank you, the paymaster works now", None),
+        ] {
+            assert_eq!(policy.skip_reason(content), expected, "{content}");
+        }
+
+        let lenient = EmbeddingPolicy {
+            min_chars: 0,
+            ..Default::default()
+        }
+        .compile()
+        .unwrap();
+        assert_eq!(lenient.skip_reason("TY"), Some(SkipReason::LowValue));
+        assert_eq!(lenient.skip_reason("+1"), Some(SkipReason::LowValue));
+        assert_eq!(lenient.skip_reason("why?"), None);
+        let everything = EmbeddingPolicy {
+            min_chars: 0,
+            skip_patterns: vec![],
+        }
+        .compile()
+        .unwrap();
+        assert_eq!(everything.skip_reason(""), None);
+
+        assert!(policy.policy().validate().is_ok());
+        let invalid = EmbeddingPolicy {
+            skip_patterns: vec!["(ok".to_string()],
+            ..Default::default()
+        };
+        assert!(invalid.validate().is_err());
+        assert!(invalid.compile().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_skipped_messages_stay_in_history() {
+        // Skipped messages never reach the embedding model
+        let knowledge = test_utils::knowledge_base_with(FlakyEmbeddingModel::failing(100))
+            .await
+            .with_embedding_policy(EmbeddingPolicy::default().compile().unwrap());
+        knowledge.create_message(message("m1", "ok")).await.unwrap();
+        knowledge
+            .create_message(message("m2", "lol"))
+            .await
+            .unwrap();
+
+        let history = knowledge.channel_messages("c1", 10).await.unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(
+            knowledge.embedding_stats().await.unwrap(),
+            EmbeddingStats {
+                embedded: 0,
+                too_short: 2,
+                low_value: 0,
+            }
+        );
+        let pending: usize = knowledge
+            .conn
+            .call(|conn| {
+                Ok(
+                    conn.query_row("SELECT COUNT(*) FROM pending_messages", [], |row| {
+                        row.get(0)
+                    })?,
+                )
+            })
+            .await
+            .unwrap();
+        assert_eq!(pending, 0);
+    }
+
+    #[tokio::test]
+    async fn test_search_and_backfill() {
+        let knowledge = test_utils::knowledge_base()
+            .await
+            .with_embedding_policy(EmbeddingPolicy::default().compile().unwrap());
+        knowledge
+            .create_message(message("m1", "katana fails to start on port 5050"))
+            .await
+            .unwrap();
+        knowledge
+            .create_message(message("m2", "katana?"))
+            .await
+            .unwrap();
+        knowledge.create_message(message("m3", "ty")).await.unwrap();
+
+        // Semantic search only finds the embedded message
+        let results = knowledge
+            .clone()
+            .message_index()
+            .top_n_ids("katana", 5)
+            .await
+            .unwrap();
+        assert_eq!(
+            results.into_iter().map(|(_, id)| id).collect::<Vec<_>>(),
+            ["m1"]
+        );
+        let results = knowledge
+            .clone()
+            .message_index()
+            .top_n::<Message>("katana", 5)
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].2.content, "katana fails to start on port 5050");
+        let stats = knowledge.embedding_stats().await.unwrap();
+        assert_eq!((stats.embedded, stats.skipped()), (1, 2));
+
+        // Maintenance doesn't count them as missing an embedding
+        let report = knowledge
+            .maintenance(&crate::knowledge::MaintenanceOptions {
+                dry_run: true,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(report.tables[1].missing_embeddings, 0);
+
+        // A shorter minimum embeds the question, not the thanks
+        let policy = EmbeddingPolicy {
+            min_chars: 5,
+            ..Default::default()
+        };
+        let knowledge = knowledge.with_embedding_policy(policy.compile().unwrap());
+        assert_eq!(knowledge.backfill_embeddings(10).await.unwrap(), 1);
+        assert_eq!(knowledge.backfill_embeddings(10).await.unwrap(), 0);
+        let results = knowledge
+            .clone()
+            .message_index()
+            .top_n_ids("katana?", 1)
+            .await
+            .unwrap();
+        assert_eq!(results[0].1, "m2");
+        assert_eq!(
+            knowledge.embedding_stats().await.unwrap(),
+            EmbeddingStats {
+                embedded: 2,
+                too_short: 1,
+                low_value: 0,
+            }
+        );
+    }
+}
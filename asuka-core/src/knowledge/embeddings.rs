@@ -0,0 +1,512 @@
+//! The embedding provider behind every embedding a
+//! [KnowledgeBase](super::KnowledgeBase) makes:
+//! storing messages and documents, retrieval, gap detection, memory dedup
+//! and re-embedding.
+//!
+//! [EmbeddingService] retries transient failures with exponential backoff
+//! and cuts each attempt off after a timeout. After
+//! [EmbeddingServiceConfig::failure_threshold] failed calls in a row its
+//! circuit opens: calls fail fast with [CircuitOpen] for
+//! [EmbeddingServiceConfig::cooldown], then a single trial call is let
+//! through, closing the circuit when it succeeds and opening it again when
+//! it fails. While it is open:
+//!
+//! - [create_message](super::KnowledgeBase::create_message) queues messages in
+//!   `pending_messages`, and [drain_pending](super::KnowledgeBase::drain_pending)
+//!   leaves them there
+//! - retrieval ranks documents by the words they share with the query
+//! - [add_documents](super::KnowledgeBase::add_documents) fails the batch
+//!   before storing any of it, and ingestion waits for the circuit, see
+//!   [IngestOptions](super::IngestOptions)
+//! - with [EmbeddingService::with_status], [Condition::Degraded] is raised
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use rig::embeddings::{Embedding, EmbeddingError, EmbeddingModel};
+use thiserror::Error;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+use crate::status::{Condition, StatusBoard};
+
+#[derive(Clone, Debug)]
+pub struct EmbeddingServiceConfig {
+    /// Retries of one call before it counts as failed.
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Time an attempt may take before it counts as failed.
+    pub timeout: Duration,
+    /// Failed calls in a row that open the circuit.
+    pub failure_threshold: u32,
+    /// How long the circuit stays open before a trial call.
+    pub cooldown: Duration,
+}
+
+impl Default for EmbeddingServiceConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+            failure_threshold: 5,
+            cooldown: Duration::from_secs(60),
+        }
+    }
+}
+
+impl EmbeddingServiceConfig {
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff)
+    }
+}
+
+/// Returned, wrapped in [EmbeddingError::DocumentError], while the circuit
+/// is open. See [is_circuit_open].
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("Embedding provider unavailable, not retrying for {retry_in:?}")]
+pub struct CircuitOpen {
+    pub retry_in: Duration,
+}
+
+/// Whether `err` is a call rejected by an open circuit rather than a
+/// failure of the provider.
+pub fn is_circuit_open(err: &EmbeddingError) -> bool {
+    circuit_open(err).is_some()
+}
+
+/// The open circuit that rejected `err`, telling when to call again.
+pub fn circuit_open(err: &EmbeddingError) -> Option<&CircuitOpen> {
+    match err {
+        EmbeddingError::DocumentError(err) => err.downcast_ref::<CircuitOpen>(),
+        _ => None,
+    }
+}
+
+/// Whether `err` is the provider rate limiting the bot, an HTTP 429.
+pub fn is_rate_limited(err: &EmbeddingError) -> bool {
+    matches!(
+        err,
+        EmbeddingError::HttpError(err) if err.status() == Some(reqwest::StatusCode::TOO_MANY_REQUESTS)
+    )
+}
+
+/// Failures worth retrying: the provider was unreachable, slow or
+/// answered with an error status.
+fn is_transient(err: &EmbeddingError) -> bool {
+    matches!(
+        err,
+        EmbeddingError::HttpError(_) | EmbeddingError::ProviderError(_)
+    )
+}
+
+/// Counts of embedding calls, shared by clones.
+#[derive(Clone, Debug, Default)]
+pub struct EmbeddingMetrics {
+    calls: Arc<AtomicU64>,
+    retries: Arc<AtomicU64>,
+    rate_limited: Arc<AtomicU64>,
+    timeouts: Arc<AtomicU64>,
+    failures: Arc<AtomicU64>,
+    rejected: Arc<AtomicU64>,
+}
+
+impl EmbeddingMetrics {
+    /// Calls let through to the provider.
+    pub fn calls(&self) -> u64 {
+        self.calls.load(Ordering::Relaxed)
+    }
+
+    /// Attempts retried, for any reason.
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    /// Attempts the provider rate limited, see [is_rate_limited].
+    pub fn rate_limited(&self) -> u64 {
+        self.rate_limited.load(Ordering::Relaxed)
+    }
+
+    /// Attempts cut off by the timeout.
+    pub fn timeouts(&self) -> u64 {
+        self.timeouts.load(Ordering::Relaxed)
+    }
+
+    /// Calls given up on.
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    /// Calls failed fast by the open circuit.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Breaker {
+    consecutive_failures: u32,
+    /// Set while the circuit is open or its trial call is in flight.
+    open_until: Option<Instant>,
+}
+
+/// An [EmbeddingModel] with retries, a timeout and a circuit breaker.
+/// Clones share the circuit and metrics.
+#[derive(Clone)]
+pub struct EmbeddingService<E: EmbeddingModel> {
+    model: E,
+    config: EmbeddingServiceConfig,
+    breaker: Arc<Mutex<Breaker>>,
+    metrics: EmbeddingMetrics,
+    status: Option<StatusBoard>,
+}
+
+impl<E: EmbeddingModel> From<E> for EmbeddingService<E> {
+    fn from(model: E) -> Self {
+        Self::new(model)
+    }
+}
+
+impl<E: EmbeddingModel> EmbeddingService<E> {
+    pub fn new(model: E) -> Self {
+        Self {
+            model,
+            config: EmbeddingServiceConfig::default(),
+            breaker: Arc::default(),
+            metrics: EmbeddingMetrics::default(),
+            status: None,
+        }
+    }
+
+    pub fn with_config(mut self, config: EmbeddingServiceConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Counts into `metrics` instead of counters of its own.
+    pub fn with_metrics(mut self, metrics: EmbeddingMetrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Publishes the circuit being open to `status`.
+    pub fn with_status(mut self, status: StatusBoard) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    pub fn model(&self) -> &E {
+        &self.model
+    }
+
+    pub fn config(&self) -> &EmbeddingServiceConfig {
+        &self.config
+    }
+
+    pub fn metrics(&self) -> &EmbeddingMetrics {
+        &self.metrics
+    }
+
+    /// Whether calls fail fast right now: during the cooldown, and while the
+    /// trial call after it is in flight.
+    pub fn is_open(&self) -> bool {
+        self.breaker
+            .lock()
+            .unwrap()
+            .open_until
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    /// Lets a call through, or fails it fast while the circuit is open.
+    fn admit(&self) -> Result<(), CircuitOpen> {
+        let mut breaker = self.breaker.lock().unwrap();
+        let Some(until) = breaker.open_until else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        if now < until {
+            return Err(CircuitOpen {
+                retry_in: until - now,
+            });
+        }
+        // The trial call. Others keep failing fast until it is done, or for
+        // another cooldown should it never finish.
+        breaker.open_until = Some(now + self.config.cooldown);
+        Ok(())
+    }
+
+    fn record_success(&self) {
+        let mut breaker = self.breaker.lock().unwrap();
+        if breaker.open_until.is_some() {
+            info!("Embedding provider recovered, closing circuit");
+            if let Some(status) = &self.status {
+                status.set(Condition::Degraded, false);
+            }
+        }
+        *breaker = Breaker::default();
+    }
+
+    fn record_failure(&self) {
+        let mut breaker = self.breaker.lock().unwrap();
+        breaker.consecutive_failures += 1;
+        let was_open = breaker.open_until.is_some();
+        if was_open || breaker.consecutive_failures >= self.config.failure_threshold {
+            if !was_open {
+                warn!(
+                    failures = breaker.consecutive_failures,
+                    cooldown = ?self.config.cooldown,
+                    "Embedding provider failing, opening circuit"
+                );
+                if let Some(status) = &self.status {
+                    status.set(Condition::Degraded, true);
+                }
+            }
+            breaker.open_until = Some(Instant::now() + self.config.cooldown);
+        }
+    }
+}
+
+impl<E: EmbeddingModel> EmbeddingModel for EmbeddingService<E> {
+    const MAX_DOCUMENTS: usize = E::MAX_DOCUMENTS;
+
+    fn ndims(&self) -> usize {
+        self.model.ndims()
+    }
+
+    async fn embed_texts(
+        &self,
+        texts: impl IntoIterator<Item = String> + Send,
+    ) -> Result<Vec<Embedding>, EmbeddingError> {
+        if let Err(open) = self.admit() {
+            self.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(EmbeddingError::DocumentError(Box::new(open)));
+        }
+        self.metrics.calls.fetch_add(1, Ordering::Relaxed);
+
+        let texts = texts.into_iter().collect::<Vec<_>>();
+        let mut attempt = 0;
+        loop {
+            let result = match tokio::time::timeout(
+                self.config.timeout,
+                self.model.embed_texts(texts.clone()),
+            )
+            .await
+            {
+                Ok(result) => result,
+                Err(_) => {
+                    self.metrics.timeouts.fetch_add(1, Ordering::Relaxed);
+                    Err(EmbeddingError::ProviderError(format!(
+                        "Embedding request timed out after {:?}",
+                        self.config.timeout
+                    )))
+                }
+            };
+
+            if result.as_ref().is_err_and(is_rate_limited) {
+                self.metrics.rate_limited.fetch_add(1, Ordering::Relaxed);
+            }
+            match result {
+                Ok(embeddings) => {
+                    self.record_success();
+                    return Ok(embeddings);
+                }
+                Err(err) if is_transient(&err) && attempt < self.config.max_retries => {
+                    let backoff = self.config.backoff(attempt);
+                    attempt += 1;
+                    self.metrics.retries.fetch_add(1, Ordering::Relaxed);
+                    debug!(%err, attempt, ?backoff, "Retrying embedding call");
+                    tokio::time::sleep(backoff).await;
+                }
+                // The provider is up but refused these texts
+                Err(err) if !is_transient(&err) => {
+                    self.record_success();
+                    return Err(err);
+                }
+                Err(err) => {
+                    self.metrics.failures.fetch_add(1, Ordering::Relaxed);
+                    self.record_failure();
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        knowledge::{ChannelType, Document, DrainSummary, Message, RetryPolicy, Source},
+        test_utils::{self, FlakyEmbeddingModel},
+    };
+    use rig::vector_store::VectorStoreIndex;
+
+    fn config() -> EmbeddingServiceConfig {
+        EmbeddingServiceConfig {
+            max_retries: 1,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(1),
+            timeout: Duration::from_secs(5),
+            failure_threshold: 2,
+            cooldown: Duration::from_secs(60),
+        }
+    }
+
+    async fn embed(service: &EmbeddingService<FlakyEmbeddingModel>) -> Result<(), EmbeddingError> {
+        service.embed_text("gm").await.map(|_| ())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_transient_failures_are_retried() {
+        let service = EmbeddingService::new(FlakyEmbeddingModel::failing(1)).with_config(config());
+
+        embed(&service).await.unwrap();
+
+        assert_eq!(service.metrics().calls(), 1);
+        assert_eq!(service.metrics().retries(), 1);
+        assert_eq!(service.metrics().failures(), 0);
+        assert!(!service.is_open());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_circuit_opens_and_recovers() {
+        // Two calls of two attempts fail, then the provider is back
+        let status = StatusBoard::default();
+        let service = EmbeddingService::new(FlakyEmbeddingModel::failing(4))
+            .with_config(config())
+            .with_status(status.clone());
+
+        assert!(!is_circuit_open(&embed(&service).await.unwrap_err()));
+        assert!(!service.is_open());
+        assert!(!is_circuit_open(&embed(&service).await.unwrap_err()));
+        assert!(service.is_open());
+        assert!(status.status().has(Condition::Degraded));
+
+        // Open: fails fast without calling the provider
+        let err = embed(&service).await.unwrap_err();
+        assert!(is_circuit_open(&err));
+        assert_eq!(service.metrics().calls(), 2);
+        assert_eq!(service.metrics().rejected(), 1);
+
+        // After the cooldown the trial call goes through and closes it
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert!(!service.is_open());
+        embed(&service).await.unwrap();
+        assert!(!service.is_open());
+        assert!(status.status().is_healthy());
+        assert_eq!(service.metrics().failures(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failed_trial_reopens_circuit() {
+        let service = EmbeddingService::new(FlakyEmbeddingModel::failing(10)).with_config(config());
+        for _ in 0..2 {
+            embed(&service).await.unwrap_err();
+        }
+        assert!(service.is_open());
+
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert!(!is_circuit_open(&embed(&service).await.unwrap_err()));
+
+        // A single failed trial is enough to open it for another cooldown
+        assert!(service.is_open());
+        assert!(is_circuit_open(&embed(&service).await.unwrap_err()));
+        assert_eq!(service.metrics().calls(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_knowledge_base_degrades_while_open() {
+        let model = FlakyEmbeddingModel::default();
+        let mut knowledge = test_utils::knowledge_base_with_service(
+            EmbeddingService::new(model.clone()).with_config(EmbeddingServiceConfig {
+                max_retries: 0,
+                failure_threshold: 1,
+                ..Default::default()
+            }),
+        )
+        .await;
+        let doc = |id: &str, content: &str| Document {
+            id: id.to_string(),
+            source_id: "github".to_string(),
+            content: content.to_string(),
+            created_at: chrono::Utc::now(),
+            title: String::new(),
+            section: String::new(),
+            topics: Vec::new(),
+            logical_id: None,
+            cleaned: None,
+            chunk: None,
+        };
+        knowledge
+            .add_documents(vec![
+                doc("katana.md", "Katana devnet flags"),
+                doc("fees.md", "Fees are paid in STRK"),
+            ])
+            .await
+            .unwrap();
+
+        model.fail_next(1);
+        assert!(knowledge
+            .clone()
+            .document_index()
+            .top_n_ids("katana flags", 5)
+            .await
+            .is_err());
+        assert!(knowledge.embedding_service().is_open());
+
+        // Retrieval matches on keywords
+        let results = knowledge
+            .clone()
+            .document_index()
+            .top_n_ids("katana flags?", 5)
+            .await
+            .unwrap();
+        assert_eq!(results, [(0.0, "katana.md".to_string())]);
+
+        // Messages are queued and stay queued
+        let message = Message {
+            id: "m1".to_string(),
+            source: Source::Discord,
+            source_id: "alice".to_string(),
+            channel_type: ChannelType::Text,
+            channel_id: "c1".to_string(),
+            account_id: "alice".to_string(),
+            role: "user".to_string(),
+            content: "gm".to_string(),
+            created_at: chrono::Utc::now(),
+        };
+        knowledge.create_message(message).await.unwrap();
+        assert!(knowledge.message_exists("m1").await.unwrap());
+        assert_eq!(
+            knowledge
+                .drain_pending(&RetryPolicy::default())
+                .await
+                .unwrap(),
+            DrainSummary::default()
+        );
+
+        // Documents are not stored at all
+        let err = knowledge
+            .add_documents(vec![doc("sessions.md", "Sessions expire")])
+            .await
+            .unwrap_err();
+        assert!(err
+            .downcast_ref::<EmbeddingError>()
+            .is_some_and(is_circuit_open));
+        assert!(knowledge
+            .get_document("sessions.md")
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(knowledge.embedding_service().metrics().calls(), 2);
+    }
+}
@@ -0,0 +1,895 @@
+//! Erasure of everything stored about one user, for requests like "delete
+//! what you know about me".
+//!
+//! Every table holding user data is listed in [USER_DATA], with how the
+//! user's rows are found and, where keeping them is useful, how they are
+//! anonymized. The user's messages, their replies and their direct message
+//! channels are looked up once, so tables can be erased in any order. Rows
+//! go in transactions of [ErasureOptions::chunk_size], like a retention
+//! purge.
+//!
+//! Interactions, tool calls, escalations, announcements and pins are kept
+//! anonymized by default, so usage counts and the work of other users stay
+//! intact; everything else is deleted. A test checks that every table with
+//! a user, message or channel column is listed, so new tables aren't
+//! missed.
+
+use std::{collections::HashMap, fmt};
+
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+use rusqlite::{Statement, ToSql};
+use tracing::info;
+
+use super::{store::KnowledgeBase, types::Source};
+
+/// Written in place of user and channel ids of anonymized rows.
+pub const ERASED_ID: &str = "erased";
+
+/// What happens to a user's rows in a table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErasureMode {
+    Delete,
+    /// Keep the rows with the user's ids and content removed. Tables that
+    /// can't be anonymized are deleted from instead.
+    Anonymize,
+}
+
+impl ErasureMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErasureMode::Delete => "deleted",
+            ErasureMode::Anonymize => "anonymized",
+        }
+    }
+}
+
+/// A table holding user data. Conditions and assignments may use the
+/// `:agent`, `:source`, `:user` and `:erased` parameters and the temporary
+/// tables set up by [SETUP].
+struct UserData {
+    table: &'static str,
+    /// Selects the user's rows.
+    rows: &'static str,
+    /// Removes the user from a row, `None` when the table can only be
+    /// deleted from and empty when its rows hold nothing identifying.
+    anonymize: Option<&'static str>,
+    default: ErasureMode,
+}
+
+/// Collects the ids of the user's messages, pending or stored, with the ids
+/// of the replies to them, the direct message channels they were sent in,
+/// and the interactions answering them.
+const SETUP: &[&str] = &[
+    "DROP TABLE IF EXISTS temp.erased_messages",
+    "DROP TABLE IF EXISTS temp.erased_channels",
+    "DROP TABLE IF EXISTS temp.erased_interactions",
+    "CREATE TEMP TABLE erased_messages (
+         id TEXT PRIMARY KEY,
+         channel_id TEXT NOT NULL,
+         channel_type TEXT NOT NULL
+     )",
+    "INSERT OR IGNORE INTO temp.erased_messages
+         SELECT id, channel_id, channel_type FROM messages
+         WHERE agent_id = :agent AND source = :source
+             AND (source_id = :user OR account_id = :user)",
+    "INSERT OR IGNORE INTO temp.erased_messages
+         SELECT id, channel_id, channel_type FROM pending_messages
+         WHERE agent_id = :agent AND source = :source
+             AND (source_id = :user OR account_id = :user)",
+    "INSERT OR IGNORE INTO temp.erased_messages
+         SELECT id || ':reply', channel_id, channel_type FROM temp.erased_messages",
+    "CREATE TEMP TABLE erased_channels (channel_id TEXT PRIMARY KEY)",
+    "INSERT OR IGNORE INTO temp.erased_channels
+         SELECT channel_id FROM temp.erased_messages WHERE channel_type = 'direct_message'",
+    "CREATE TEMP TABLE erased_interactions (id INTEGER PRIMARY KEY)",
+    "INSERT OR IGNORE INTO temp.erased_interactions
+         SELECT m.interaction_id FROM interaction_messages m
+         JOIN interactions i ON i.id = m.interaction_id
+         WHERE i.agent_id = :agent AND m.message_id IN (SELECT id FROM temp.erased_messages)",
+    "INSERT OR IGNORE INTO temp.erased_interactions
+         SELECT id FROM interactions
+         WHERE agent_id = :agent AND (
+             channel_id IN (SELECT channel_id FROM temp.erased_channels)
+             OR (account_id = :user
+                 AND channel_id IN (SELECT channel_id FROM temp.erased_messages))
+         )",
+];
+
+const TEARDOWN: &str = "
+    DROP TABLE IF EXISTS temp.erased_messages;
+    DROP TABLE IF EXISTS temp.erased_channels;
+    DROP TABLE IF EXISTS temp.erased_interactions;
+";
+
+/// Every table holding user data, in the order they are erased.
+const USER_DATA: &[UserData] = &[
+    UserData {
+        table: "messages_embeddings",
+        rows: "rowid IN (
+                   SELECT rowid FROM messages
+                   WHERE agent_id = :agent AND id IN (SELECT id FROM temp.erased_messages)
+               )",
+        anonymize: None,
+        default: ErasureMode::Delete,
+    },
+    UserData {
+        table: "messages",
+        rows: "agent_id = :agent AND id IN (SELECT id FROM temp.erased_messages)",
+        anonymize: Some(
+            "content = '', source_id = :erased, account_id = :erased,
+             channel_id = CASE
+                 WHEN channel_id IN (SELECT channel_id FROM temp.erased_channels) THEN :erased
+                 ELSE channel_id
+             END",
+        ),
+        default: ErasureMode::Delete,
+    },
+    UserData {
+        table: "pending_messages",
+        rows: "agent_id = :agent AND id IN (SELECT id FROM temp.erased_messages)",
+        anonymize: None,
+        default: ErasureMode::Delete,
+    },
+    UserData {
+        table: "interaction_messages",
+        rows: "interaction_id IN (SELECT id FROM temp.erased_interactions)",
+        anonymize: None,
+        default: ErasureMode::Delete,
+    },
+    UserData {
+        table: "interaction_replies",
+        rows: "interaction_id IN (SELECT id FROM temp.erased_interactions)",
+        anonymize: None,
+        default: ErasureMode::Delete,
+    },
+    UserData {
+        table: "interaction_queries",
+        rows: "interaction_id IN (SELECT id FROM temp.erased_interactions)",
+        anonymize: None,
+        default: ErasureMode::Delete,
+    },
+    UserData {
+        table: "interaction_truncations",
+        rows: "interaction_id IN (SELECT id FROM temp.erased_interactions)",
+        anonymize: None,
+        default: ErasureMode::Delete,
+    },
+    UserData {
+        table: "interaction_research_steps",
+        rows: "interaction_id IN (SELECT id FROM temp.erased_interactions)",
+        anonymize: None,
+        default: ErasureMode::Delete,
+    },
+    UserData {
+        table: "sent_messages",
+        rows: "interaction_id IN (SELECT id FROM temp.erased_interactions)",
+        anonymize: None,
+        default: ErasureMode::Delete,
+    },
+    UserData {
+        table: "interaction_confidence",
+        rows: "interaction_id IN (SELECT id FROM temp.erased_interactions)",
+        anonymize: Some(""),
+        default: ErasureMode::Anonymize,
+    },
+    UserData {
+        table: "tool_calls",
+        rows: "interaction_id IN (SELECT id FROM temp.erased_interactions)",
+        anonymize: Some("args = '{}', output = NULL, error = NULL"),
+        default: ErasureMode::Anonymize,
+    },
+    UserData {
+        table: "interactions",
+        rows: "id IN (SELECT id FROM temp.erased_interactions)",
+        anonymize: Some(
+            "account_id = CASE WHEN account_id = :user THEN :erased ELSE account_id END,
+             channel_id = CASE
+                 WHEN channel_id IN (SELECT channel_id FROM temp.erased_channels) THEN :erased
+                 ELSE channel_id
+             END",
+        ),
+        default: ErasureMode::Anonymize,
+    },
+    UserData {
+        table: "escalations",
+        rows: "agent_id = :agent AND (
+                   account_id = :user OR handled_by = :user
+                   OR channel_id IN (SELECT channel_id FROM temp.erased_channels)
+               )",
+        anonymize: Some(
+            "account_id = CASE WHEN account_id = :user THEN :erased ELSE account_id END,
+             handled_by = CASE WHEN handled_by = :user THEN :erased ELSE handled_by END,
+             channel_id = CASE
+                 WHEN channel_id IN (SELECT channel_id FROM temp.erased_channels) THEN :erased
+                 ELSE channel_id
+             END",
+        ),
+        default: ErasureMode::Anonymize,
+    },
+    UserData {
+        table: "announcements",
+        rows: "agent_id = :agent AND invoked_by = :user",
+        anonymize: Some("invoked_by = :erased, requested = ''"),
+        default: ErasureMode::Anonymize,
+    },
+    UserData {
+        table: "pinned_context",
+        rows: "agent_id = :agent AND added_by = :user",
+        anonymize: Some("added_by = :erased"),
+        default: ErasureMode::Anonymize,
+    },
+    UserData {
+        table: "rate_limit_events",
+        rows: "agent_id = :agent AND source = :source AND account_id = :user",
+        anonymize: Some("account_id = :erased"),
+        default: ErasureMode::Delete,
+    },
+    UserData {
+        table: "user_facts",
+        rows: "agent_id = :agent AND account_id = :user",
+        anonymize: None,
+        default: ErasureMode::Delete,
+    },
+    UserData {
+        table: "onboarding_state",
+        rows: "agent_id = :agent AND account_id = :user",
+        anonymize: None,
+        default: ErasureMode::Delete,
+    },
+    UserData {
+        table: "link_codes",
+        rows: "source = :source AND account_id = :user",
+        anonymize: None,
+        default: ErasureMode::Delete,
+    },
+    // The whole identity, so no other account keeps the user's facts
+    UserData {
+        table: "linked_identities",
+        rows: "(source = :source AND account_id = :user) OR canonical_account_id = :user",
+        anonymize: None,
+        default: ErasureMode::Delete,
+    },
+    UserData {
+        table: "language_scores",
+        rows: "agent_id = :agent AND (
+                   (scope = 'account' AND key = :user)
+                   OR (scope = 'channel'
+                       AND key IN (SELECT channel_id FROM temp.erased_channels))
+               )",
+        anonymize: None,
+        default: ErasureMode::Delete,
+    },
+    UserData {
+        table: "conversation_state",
+        rows: "agent_id = :agent AND (
+                   channel_id IN (SELECT channel_id FROM temp.erased_channels)
+                   OR last_message_id IN (SELECT id FROM temp.erased_messages)
+               )",
+        anonymize: None,
+        default: ErasureMode::Delete,
+    },
+    UserData {
+        table: "exchanges",
+        rows: "agent_id = :agent AND (
+                   interaction_id IN (SELECT id FROM temp.erased_interactions)
+                   OR channel_id IN (SELECT channel_id FROM temp.erased_channels)
+               )",
+        anonymize: None,
+        default: ErasureMode::Delete,
+    },
+    UserData {
+        table: "session_summaries",
+        rows: "agent_id = :agent AND channel_id IN (SELECT channel_id FROM temp.erased_channels)",
+        anonymize: None,
+        default: ErasureMode::Delete,
+    },
+    UserData {
+        table: "consolidated_facts",
+        rows: "agent_id = :agent AND channel_id IN (SELECT channel_id FROM temp.erased_channels)",
+        anonymize: None,
+        default: ErasureMode::Delete,
+    },
+    UserData {
+        table: "knowledge_gaps",
+        rows: "agent_id = :agent AND channel_id IN (SELECT channel_id FROM temp.erased_channels)",
+        anonymize: None,
+        default: ErasureMode::Delete,
+    },
+    UserData {
+        table: "channel_settings",
+        rows: "agent_id = :agent AND channel_id IN (SELECT channel_id FROM temp.erased_channels)",
+        anonymize: None,
+        default: ErasureMode::Delete,
+    },
+    UserData {
+        table: "channels",
+        rows: "channel_id IN (SELECT channel_id FROM temp.erased_channels)",
+        anonymize: None,
+        default: ErasureMode::Delete,
+    },
+    UserData {
+        table: "accounts",
+        rows: "source = :source AND source_id = :user",
+        anonymize: None,
+        default: ErasureMode::Delete,
+    },
+];
+
+#[derive(Clone, Debug)]
+pub struct ErasureOptions {
+    /// Only count what would be erased.
+    pub dry_run: bool,
+    /// Modes replacing the default of a table, by table name.
+    pub modes: HashMap<String, ErasureMode>,
+    /// Rows erased per transaction.
+    pub chunk_size: usize,
+}
+
+impl Default for ErasureOptions {
+    fn default() -> Self {
+        Self {
+            dry_run: false,
+            modes: HashMap::new(),
+            chunk_size: 500,
+        }
+    }
+}
+
+impl ErasureOptions {
+    pub fn with_mode(mut self, table: impl Into<String>, mode: ErasureMode) -> Self {
+        self.modes.insert(table.into(), mode);
+        self
+    }
+
+    fn mode(&self, data: &UserData) -> ErasureMode {
+        match (self.modes.get(data.table), data.anonymize) {
+            (Some(ErasureMode::Anonymize), None) => ErasureMode::Delete,
+            (Some(mode), _) => *mode,
+            (None, _) => data.default,
+        }
+    }
+}
+
+/// Rows erased from one table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ErasedTable {
+    pub table: &'static str,
+    pub mode: ErasureMode,
+    pub rows: usize,
+}
+
+/// What [KnowledgeBase::erase_user] erased, by table, leaving out tables
+/// without rows of the user.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ErasureReport {
+    pub dry_run: bool,
+    pub tables: Vec<ErasedTable>,
+}
+
+impl ErasureReport {
+    pub fn total(&self) -> usize {
+        self.tables.iter().map(|table| table.rows).sum()
+    }
+
+    pub fn rows(&self, table: &str) -> usize {
+        self.tables
+            .iter()
+            .find(|erased| erased.table == table)
+            .map_or(0, |erased| erased.rows)
+    }
+}
+
+impl fmt::Display for ErasureReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.dry_run {
+            writeln!(f, "Dry run, nothing was changed.")?;
+        }
+        if self.tables.is_empty() {
+            return write!(f, "Nothing stored about this user.");
+        }
+        for table in &self.tables {
+            writeln!(f, "{}: {} {}", table.table, table.rows, table.mode.as_str())?;
+        }
+        write!(f, "{} rows in total", self.total())
+    }
+}
+
+/// Binds the named parameters `stmt` uses, leaving out the others.
+fn bind(stmt: &mut Statement<'_>, params: &[(&str, &dyn ToSql)]) -> rusqlite::Result<()> {
+    for (name, value) in params {
+        if let Some(index) = stmt.parameter_index(name)? {
+            stmt.raw_bind_parameter(index, value)?;
+        }
+    }
+    Ok(())
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Deletes or anonymizes every row of this knowledge base's namespace
+    /// referring to the user with platform id `user_id` on `source`: their
+    /// messages and the replies to them, the interactions answering them,
+    /// what was learned about them, and their direct message channels.
+    /// Accounts and channels are shared by all namespaces and erased too.
+    pub async fn erase_user(
+        &self,
+        source: &Source,
+        user_id: &str,
+        options: &ErasureOptions,
+    ) -> Result<ErasureReport, SqliteError> {
+        let namespace = self.namespace.clone();
+        let source = source.as_str().to_string();
+        let user_id = user_id.to_string();
+        let options = options.clone();
+
+        let report = self
+            .conn
+            .call(move |conn| {
+                let params: [(&str, &dyn ToSql); 4] = [
+                    (":agent", &namespace),
+                    (":source", &source),
+                    (":user", &user_id),
+                    (":erased", &ERASED_ID),
+                ];
+                for sql in SETUP {
+                    let mut stmt = conn.prepare(sql)?;
+                    bind(&mut stmt, &params)?;
+                    stmt.raw_execute()?;
+                }
+
+                let chunk_size = options.chunk_size.max(1);
+                let mut report = ErasureReport {
+                    dry_run: options.dry_run,
+                    tables: Vec::new(),
+                };
+                for data in USER_DATA {
+                    let mut stmt = conn.prepare(&format!(
+                        "SELECT rowid FROM {} WHERE {}",
+                        data.table, data.rows
+                    ))?;
+                    bind(&mut stmt, &params)?;
+                    let mut rows = stmt.raw_query();
+                    let mut rowids = Vec::new();
+                    while let Some(row) = rows.next()? {
+                        rowids.push(row.get::<_, i64>(0)?);
+                    }
+                    drop(rows);
+                    drop(stmt);
+                    if rowids.is_empty() {
+                        continue;
+                    }
+
+                    let mode = options.mode(data);
+                    let sql = match (mode, data.anonymize) {
+                        (ErasureMode::Anonymize, Some("")) => None,
+                        (ErasureMode::Anonymize, Some(assignments)) => Some(format!(
+                            "UPDATE {} SET {assignments}
+                             WHERE rowid IN (SELECT value FROM json_each(:rowids))",
+                            data.table
+                        )),
+                        _ => Some(format!(
+                            "DELETE FROM {} WHERE rowid IN (SELECT value FROM json_each(:rowids))",
+                            data.table
+                        )),
+                    };
+                    if let (Some(sql), false) = (sql, options.dry_run) {
+                        for chunk in rowids.chunks(chunk_size) {
+                            let chunk = serde_json::to_string(chunk).expect("integers serialize");
+                            let tx = conn.transaction()?;
+                            let mut stmt = tx.prepare(&sql)?;
+                            bind(&mut stmt, &params)?;
+                            bind(&mut stmt, &[(":rowids", &chunk as &dyn ToSql)])?;
+                            stmt.raw_execute()?;
+                            drop(stmt);
+                            tx.commit()?;
+                        }
+                    }
+                    report.tables.push(ErasedTable {
+                        table: data.table,
+                        mode,
+                        rows: rowids.len(),
+                    });
+                }
+
+                conn.execute_batch(TEARDOWN)?;
+                Ok(report)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))?;
+
+        if !report.dry_run {
+            self.identities.clear();
+        }
+        info!(
+            dry_run = report.dry_run,
+            rows = report.total(),
+            tables = ?report.tables,
+            "Erased user data"
+        );
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        knowledge::{ChannelType, Message},
+        test_utils,
+    };
+
+    /// Tables with a user, message or channel column that hold no user data.
+    const EXEMPT: &[&str] = &[
+        // `source_id` names where a document was loaded from
+        "documents",
+    ];
+
+    const IDENTIFYING_COLUMNS: &str = "'account_id', 'source_id', 'channel_id', 'interaction_id',
+        'message_id', 'last_message_id', 'invoked_by', 'added_by', 'handled_by'";
+
+    fn message(id: &str, channel_type: ChannelType, channel_id: &str, user: &str) -> Message {
+        Message {
+            id: id.to_string(),
+            source: Source::Discord,
+            source_id: user.to_string(),
+            channel_type,
+            channel_id: channel_id.to_string(),
+            account_id: user.to_string(),
+            role: "user".to_string(),
+            content: format!("message {id} from {user}"),
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    /// Stores messages of `alice` and `bob` in a shared channel, and a direct
+    /// message exchange of `alice`, with a row in every user data table.
+    async fn seed(knowledge: &KnowledgeBase<test_utils::FakeEmbeddingModel>) {
+        let alice = message("a1", ChannelType::DirectMessage, "dm-alice", "alice");
+        for msg in [
+            alice.clone(),
+            message("a1:reply", ChannelType::DirectMessage, "dm-alice", "bot"),
+            message("a2", ChannelType::Text, "general", "alice"),
+            message("b1", ChannelType::Text, "general", "bob"),
+        ] {
+            knowledge.create_message(msg).await.unwrap();
+        }
+        knowledge
+            .create_interaction(
+                "dm-alice".to_string(),
+                "alice".to_string(),
+                vec!["a1".to_string()],
+            )
+            .await
+            .unwrap();
+        knowledge
+            .create_interaction(
+                "general".to_string(),
+                "bob".to_string(),
+                vec!["b1".to_string()],
+            )
+            .await
+            .unwrap();
+
+        knowledge
+            .conn
+            .call(|conn| {
+                conn.execute_batch(
+                    "INSERT INTO pending_messages
+                         (id, agent_id, source, source_id, channel_type, channel_id, account_id,
+                          role, content, created_at)
+                     VALUES
+                         ('a3', 'default', 'discord', 'alice', 'text', 'general', 'alice',
+                          'user', 'pending from alice', '2024-01-01T00:00:00Z'),
+                         ('b2', 'default', 'discord', 'bob', 'text', 'general', 'bob',
+                          'user', 'pending from bob', '2024-01-01T00:00:00Z');
+                     INSERT INTO interaction_replies (interaction_id, message_id)
+                         VALUES (1, 'a1:reply');
+                     INSERT INTO interaction_queries (interaction_id, original)
+                         VALUES (1, 'message a1 from alice');
+                     INSERT INTO interaction_truncations
+                         (interaction_id, document_id, length, kept_start, kept_end)
+                         VALUES (1, 'guide.md', 20000, 4000, 9000);
+                     INSERT INTO interaction_research_steps (interaction_id, kind, content, tokens)
+                         VALUES (1, 'plan', 'what does alice need?', 120);
+                     INSERT INTO interaction_confidence
+                         (interaction_id, score, supporting, outcome)
+                         VALUES (1, 0.9, 2, 'answered');
+                     INSERT INTO sent_messages
+                         (interaction_id, chunk_index, source, channel_id, platform_message_id,
+                          sent_at)
+                         VALUES (1, 0, 'discord', 'dm-alice', 'p1', '2024-01-01T00:00:00Z');
+                     INSERT INTO tool_calls (interaction_id, tool_name, args, output, duration_ms)
+                         VALUES (1, 'search', '{\"query\":\"alice\"}', 'found', 5);
+                     INSERT INTO escalations (agent_id, channel_id, account_id, reason, created_at)
+                         VALUES ('default', 'dm-alice', 'alice', 'user_request',
+                                 '2024-01-01T00:00:00Z');
+                     INSERT INTO escalations
+                         (agent_id, channel_id, account_id, reason, status, handled_by,
+                          created_at)
+                         VALUES ('default', 'general', 'bob', 'low_confidence', 'handled',
+                                 'alice', '2024-01-01T00:00:00Z');
+                     INSERT INTO announcements
+                         (agent_id, source, channel_id, invoked_by, requested, content,
+                          restyled, created_at)
+                         VALUES ('default', 'discord', 'news', 'alice', 'say hi', 'hi', 0,
+                                 '2024-01-01T00:00:00Z');
+                     INSERT INTO pinned_context (channel_id, content, added_by, position, agent_id)
+                         VALUES ('general', 'be nice', 'alice', 1, 'default');
+                     INSERT INTO rate_limit_events (agent_id, source, account_id, event, created_at)
+                         VALUES ('default', 'discord', 'alice', 'message', '2024-01-01T00:00:00Z');
+                     INSERT INTO user_facts (agent_id, account_id, key, value, updated_at)
+                         VALUES ('default', 'alice', 'language', 'rust', '2024-01-01T00:00:00Z'),
+                                ('default', 'bob', 'language', 'go', '2024-01-01T00:00:00Z');
+                     INSERT INTO onboarding_state (agent_id, account_id, state, updated_at)
+                         VALUES ('default', 'alice', 'done', '2024-01-01T00:00:00Z');
+                     INSERT INTO link_codes (code, source, account_id, expires_at)
+                         VALUES ('C0DE', 'discord', 'alice', '2024-01-01T00:00:00Z');
+                     INSERT INTO linked_identities
+                         (account_id, source, canonical_account_id, linked_at)
+                         VALUES ('alice', 'discord', 'alice', '2024-01-01T00:00:00Z'),
+                                ('t-alice', 'telegram', 'alice', '2024-01-01T00:00:00Z');
+                     INSERT INTO language_scores (agent_id, scope, key, language, score, updated_at)
+                         VALUES ('default', 'account', 'alice', 'en', 1.0, '2024-01-01T00:00:00Z'),
+                                ('default', 'channel', 'dm-alice', 'en', 1.0,
+                                 '2024-01-01T00:00:00Z'),
+                                ('default', 'channel', 'general', 'en', 1.0,
+                                 '2024-01-01T00:00:00Z');
+                     INSERT INTO conversation_state
+                         (agent_id, channel_id, state, last_message_id, updated_at)
+                         VALUES ('default', 'dm-alice', '{}', 'a1', '2024-01-01T00:00:00Z'),
+                                ('default', 'general', '{}', 'a2', '2024-01-01T00:00:00Z');
+                     INSERT INTO exchanges
+                         (agent_id, interaction_id, channel_id, question, answer, embedding,
+                          created_at)
+                         VALUES ('default', 1, 'dm-alice', 'message a1 from alice', 'hi', x'00',
+                                 '2024-01-01T00:00:00Z'),
+                                ('default', 2, 'general', 'message b1 from bob', 'hi', x'00',
+                                 '2024-01-01T00:00:00Z');
+                     INSERT INTO session_summaries
+                         (agent_id, channel_id, summary, message_count, started_at, ended_at)
+                         VALUES ('default', 'dm-alice', 'alice asked about fees', 2,
+                                 '2024-01-01T00:00:00Z', '2024-01-01T00:00:00Z');
+                     INSERT INTO consolidated_facts
+                         (agent_id, channel_id, content, embedding, sessions, created_at,
+                          updated_at)
+                         VALUES ('default', 'dm-alice', 'alice runs a node', x'00', 1,
+                                 '2024-01-01T00:00:00Z', '2024-01-01T00:00:00Z');
+                     INSERT INTO knowledge_gaps
+                         (question, channel_id, embedding, first_asked_at, last_asked_at,
+                          agent_id)
+                         VALUES ('how do I unstake?', 'dm-alice', x'00', '2024-01-01T00:00:00Z',
+                                 '2024-01-01T00:00:00Z', 'default');
+                     INSERT INTO channel_settings (agent_id, channel_id, key, value)
+                         VALUES ('default', 'dm-alice', 'listen', 'on');
+                     INSERT INTO channels (channel_id, channel_type, source)
+                         VALUES ('dm-alice', 'direct_message', 'discord'),
+                                ('general', 'text', 'discord');
+                     INSERT INTO accounts (name, source_id, source)
+                         VALUES ('Alice', 'alice', 'discord'), ('Bob', 'bob', 'discord');",
+                )?;
+                Ok(())
+            })
+            .await
+            .unwrap();
+    }
+
+    /// Rows of `table` still mentioning alice, her messages or her channel.
+    async fn references(
+        knowledge: &KnowledgeBase<test_utils::FakeEmbeddingModel>,
+        table: &'static str,
+    ) -> i64 {
+        knowledge
+            .conn
+            .call(move |conn| {
+                let columns = conn
+                    .prepare(&format!("SELECT name FROM pragma_table_info('{table}')"))?
+                    .query_map([], |row| row.get::<_, String>(0))?
+                    .collect::<Result<Vec<_>, _>>()?;
+                let condition = columns
+                    .iter()
+                    .map(|column| {
+                        format!(
+                            "CAST({column} AS TEXT) IN ('alice', 'a1', 'a1:reply', 'a2', 'a3', 'dm-alice')
+                             OR CAST({column} AS TEXT) LIKE '%alice%'"
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" OR ");
+                if condition.is_empty() {
+                    return Ok(0);
+                }
+                Ok(conn.query_row(
+                    &format!("SELECT COUNT(*) FROM {table} WHERE {condition}"),
+                    [],
+                    |row| row.get(0),
+                )?)
+            })
+            .await
+            .unwrap()
+    }
+
+    async fn count(
+        knowledge: &KnowledgeBase<test_utils::FakeEmbeddingModel>,
+        sql: &'static str,
+    ) -> i64 {
+        knowledge
+            .conn
+            .call(move |conn| Ok(conn.query_row(sql, [], |row| row.get(0))?))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_every_user_data_table_is_listed() {
+        let knowledge = test_utils::knowledge_base().await;
+
+        let tables = knowledge
+            .conn
+            .call(|conn| {
+                Ok(conn
+                    .prepare(&format!(
+                        "SELECT DISTINCT m.name FROM sqlite_master m
+                         JOIN pragma_table_info(m.name) c
+                         WHERE m.type = 'table' AND c.name IN ({IDENTIFYING_COLUMNS})
+                         ORDER BY m.name"
+                    ))?
+                    .query_map([], |row| row.get::<_, String>(0))?
+                    .collect::<Result<Vec<_>, _>>()?)
+            })
+            .await
+            .unwrap();
+
+        let unlisted: Vec<_> = tables
+            .iter()
+            .filter(|table| {
+                !USER_DATA.iter().any(|data| data.table == table.as_str())
+                    && !EXEMPT.contains(&table.as_str())
+            })
+            .collect();
+        assert!(
+            unlisted.is_empty(),
+            "tables missing from USER_DATA: {unlisted:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_erases_every_reference() {
+        let knowledge = test_utils::knowledge_base().await;
+        seed(&knowledge).await;
+        let options = ErasureOptions {
+            chunk_size: 1,
+            ..Default::default()
+        };
+
+        let preview = knowledge
+            .erase_user(
+                &Source::Discord,
+                "alice",
+                &ErasureOptions {
+                    dry_run: true,
+                    ..options.clone()
+                },
+            )
+            .await
+            .unwrap();
+        assert!(preview.dry_run);
+        assert_eq!(references(&knowledge, "messages").await, 3);
+
+        let report = knowledge
+            .erase_user(&Source::Discord, "alice", &options)
+            .await
+            .unwrap();
+
+        assert_eq!(report.tables, preview.tables);
+        for data in USER_DATA {
+            assert_eq!(
+                references(&knowledge, data.table).await,
+                0,
+                "{} still refers to alice",
+                data.table
+            );
+        }
+        assert_eq!(report.rows("messages"), 3);
+        assert_eq!(report.rows("messages_embeddings"), 3);
+        assert_eq!(report.rows("pending_messages"), 1);
+        assert_eq!(report.rows("escalations"), 2);
+        assert_eq!(report.rows("accounts"), 1);
+        assert_eq!(report.rows("linked_identities"), 2);
+
+        // Bob's data and the kept rows are still there
+        assert_eq!(
+            count(
+                &knowledge,
+                "SELECT COUNT(*) FROM messages WHERE account_id = 'bob'"
+            )
+            .await,
+            1
+        );
+        assert_eq!(
+            count(&knowledge, "SELECT COUNT(*) FROM pending_messages").await,
+            1
+        );
+        assert_eq!(
+            count(
+                &knowledge,
+                "SELECT COUNT(*) FROM interaction_messages WHERE message_id = 'b1'"
+            )
+            .await,
+            1
+        );
+        assert_eq!(
+            count(
+                &knowledge,
+                "SELECT COUNT(*) FROM escalations WHERE account_id = 'bob' AND handled_by = 'erased'"
+            )
+            .await,
+            1
+        );
+        assert_eq!(
+            count(&knowledge, "SELECT COUNT(*) FROM interactions").await,
+            2
+        );
+        assert_eq!(
+            count(&knowledge, "SELECT COUNT(*) FROM user_facts").await,
+            1
+        );
+        assert_eq!(count(&knowledge, "SELECT COUNT(*) FROM exchanges").await, 1);
+        assert_eq!(count(&knowledge, "SELECT COUNT(*) FROM accounts").await, 1);
+        assert_eq!(
+            count(
+                &knowledge,
+                "SELECT COUNT(*) FROM messages_embeddings
+                 WHERE rowid NOT IN (SELECT rowid FROM messages)"
+            )
+            .await,
+            0
+        );
+
+        // Erasing again finds nothing
+        let again = knowledge
+            .erase_user(&Source::Discord, "alice", &options)
+            .await
+            .unwrap();
+        assert_eq!(again.total(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_table_modes() {
+        let knowledge = test_utils::knowledge_base().await;
+        seed(&knowledge).await;
+        let options = ErasureOptions::default()
+            .with_mode("interactions", ErasureMode::Delete)
+            .with_mode("messages", ErasureMode::Anonymize)
+            // Facts can't be anonymized and are deleted anyway
+            .with_mode("user_facts", ErasureMode::Anonymize);
+
+        let report = knowledge
+            .erase_user(&Source::Discord, "alice", &options)
+            .await
+            .unwrap();
+
+        let mode = |table: &str| {
+            report
+                .tables
+                .iter()
+                .find(|erased| erased.table == table)
+                .unwrap()
+                .mode
+        };
+        assert_eq!(mode("interactions"), ErasureMode::Delete);
+        assert_eq!(mode("messages"), ErasureMode::Anonymize);
+        assert_eq!(mode("user_facts"), ErasureMode::Delete);
+        assert_eq!(
+            count(&knowledge, "SELECT COUNT(*) FROM interactions").await,
+            1
+        );
+        assert_eq!(
+            count(
+                &knowledge,
+                "SELECT COUNT(*) FROM messages WHERE account_id = 'erased' AND content = ''"
+            )
+            .await,
+            3
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+use rusqlite::OptionalExtension;
+
+use super::store::KnowledgeBase;
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS escalations (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        agent_id TEXT NOT NULL,
+        channel_id TEXT NOT NULL,
+        account_id TEXT NOT NULL,
+        reason TEXT NOT NULL,
+        status TEXT NOT NULL DEFAULT 'open',
+        handled_by TEXT,
+        created_at TEXT NOT NULL,
+        handled_at TEXT
+    );
+    CREATE INDEX IF NOT EXISTS idx_escalations_channel
+        ON escalations(agent_id, channel_id, created_at);
+";
+
+/// A conversation handed to the support team, see [crate::escalation].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Escalation {
+    pub id: i64,
+    pub channel_id: String,
+    pub account_id: String,
+    pub reason: String,
+    /// `open` until staff mark it `handled`.
+    pub status: String,
+    pub handled_by: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    pub async fn create_escalation(
+        &self,
+        channel_id: &str,
+        account_id: &str,
+        reason: &str,
+    ) -> Result<i64, SqliteError> {
+        let namespace = self.namespace.clone();
+        let channel_id = channel_id.to_string();
+        let account_id = account_id.to_string();
+        let reason = reason.to_string();
+        let now = self.clock.now_utc().to_rfc3339();
+
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT INTO escalations (agent_id, channel_id, account_id, reason, created_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    rusqlite::params![namespace, channel_id, account_id, reason, now],
+                )?;
+                Ok(conn.last_insert_rowid())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// When the channel last escalated, handled or not.
+    pub async fn last_escalation_at(
+        &self,
+        channel_id: &str,
+    ) -> Result<Option<chrono::DateTime<chrono::Utc>>, SqliteError> {
+        let namespace = self.namespace.clone();
+        let channel_id = channel_id.to_string();
+
+        self.conn
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        "SELECT MAX(created_at) FROM escalations
+                         WHERE agent_id = ?1 AND channel_id = ?2",
+                        [&namespace, &channel_id],
+                        |row| row.get(0),
+                    )
+                    .optional()?
+                    .flatten())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Open escalations, oldest first, in one channel or all of them.
+    pub async fn open_escalations(
+        &self,
+        channel_id: Option<&str>,
+    ) -> Result<Vec<Escalation>, SqliteError> {
+        let namespace = self.namespace.clone();
+        let channel_id = channel_id.map(str::to_string);
+
+        self.conn
+            .call(move |conn| {
+                let escalations = conn
+                    .prepare(
+                        "SELECT id, channel_id, account_id, reason, status, handled_by, created_at
+                         FROM escalations
+                         WHERE agent_id = ?1 AND status = 'open'
+                             AND (?2 IS NULL OR channel_id = ?2)
+                         ORDER BY created_at, id",
+                    )?
+                    .query_map(rusqlite::params![namespace, channel_id], |row| {
+                        Ok(Escalation {
+                            id: row.get(0)?,
+                            channel_id: row.get(1)?,
+                            account_id: row.get(2)?,
+                            reason: row.get(3)?,
+                            status: row.get(4)?,
+                            handled_by: row.get(5)?,
+                            created_at: row.get(6)?,
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(escalations)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Marks an open escalation handled, returning whether there was one.
+    pub async fn handle_escalation(&self, id: i64, handled_by: &str) -> Result<bool, SqliteError> {
+        let namespace = self.namespace.clone();
+        let handled_by = handled_by.to_string();
+        let now = self.clock.now_utc().to_rfc3339();
+
+        self.conn
+            .call(move |conn| {
+                let updated = conn.execute(
+                    "UPDATE escalations SET status = 'handled', handled_by = ?3, handled_at = ?4
+                     WHERE id = ?1 AND agent_id = ?2 AND status = 'open'",
+                    rusqlite::params![id, namespace, handled_by, now],
+                )?;
+                Ok(updated > 0)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn test_escalation_lifecycle() {
+        let knowledge = test_utils::knowledge_base().await;
+        assert_eq!(knowledge.last_escalation_at("c1").await.unwrap(), None);
+
+        let first = knowledge
+            .create_escalation("c1", "alice", "user_request")
+            .await
+            .unwrap();
+        knowledge
+            .create_escalation("c2", "bob", "low_confidence")
+            .await
+            .unwrap();
+        assert!(knowledge.last_escalation_at("c1").await.unwrap().is_some());
+
+        let open = knowledge.open_escalations(Some("c1")).await.unwrap();
+        assert_eq!(open.len(), 1);
+        assert_eq!(open[0].account_id, "alice");
+        assert_eq!(open[0].reason, "user_request");
+        assert_eq!(open[0].status, "open");
+
+        assert!(knowledge.handle_escalation(first, "mod").await.unwrap());
+        assert!(!knowledge.handle_escalation(first, "mod").await.unwrap());
+        let open = knowledge.open_escalations(None).await.unwrap();
+        assert_eq!(open.len(), 1);
+        assert_eq!(open[0].channel_id, "c2");
+
+        // Handled escalations still count for rate limiting
+        assert!(knowledge.last_escalation_at("c1").await.unwrap().is_some());
+        let other = knowledge.clone().with_namespace("companion");
+        assert!(other.open_escalations(None).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,371 @@
+//! Question and answer pairs of answered interactions, embedded together so
+//! that a topic discussed before is found again even when it is asked
+//! differently. A question alone says little without the answer it got,
+//! which makes single messages poor matches for that.
+//!
+//! [KnowledgeBase::record_exchange] combines the embeddings the messages
+//! were stored with, so recording an exchange only calls the embedding
+//! provider while some of them are still pending.
+
+use chrono::{DateTime, Utc};
+use rig::embeddings::EmbeddingModel;
+use rusqlite::{OptionalExtension, Row};
+use tracing::debug;
+
+use super::{
+    gaps::{cosine_similarity, from_blob, to_blob},
+    store::KnowledgeBase,
+};
+use crate::history::truncate;
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS exchanges (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        agent_id TEXT NOT NULL,
+        interaction_id INTEGER NOT NULL UNIQUE,
+        channel_id TEXT NOT NULL,
+        question TEXT NOT NULL,
+        answer TEXT NOT NULL,
+        embedding BLOB NOT NULL,
+        created_at TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS idx_exchanges_channel
+        ON exchanges(agent_id, channel_id, created_at);
+";
+
+/// Characters of the question in [Exchange::summary].
+const SUMMARY_QUESTION_CHARS: usize = 80;
+
+/// Characters of the answer in [Exchange::summary].
+const SUMMARY_ANSWER_CHARS: usize = 120;
+
+/// An answered interaction: the messages it answered and the reply.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exchange {
+    pub id: i64,
+    pub interaction_id: i64,
+    pub channel_id: String,
+    /// The answered messages, one per line.
+    pub question: String,
+    pub answer: String,
+    /// When the reply was stored.
+    pub created_at: DateTime<Utc>,
+}
+
+impl Exchange {
+    const COLUMNS: &'static str = "id, interaction_id, channel_id, question, answer, created_at";
+
+    /// The first lines of the question and answer, shortened, for a one-line
+    /// mention in prompts.
+    pub fn summary(&self) -> String {
+        let first_line = |text: &str| text.lines().next().unwrap_or_default().trim().to_string();
+        format!(
+            "{} — {}",
+            truncate(&first_line(&self.question), SUMMARY_QUESTION_CHARS),
+            truncate(&first_line(&self.answer), SUMMARY_ANSWER_CHARS)
+        )
+    }
+}
+
+impl TryFrom<&Row<'_>> for Exchange {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &Row) -> Result<Self, Self::Error> {
+        Ok(Exchange {
+            id: row.get(0)?,
+            interaction_id: row.get(1)?,
+            channel_id: row.get(2)?,
+            question: row.get(3)?,
+            answer: row.get(4)?,
+            created_at: row.get(5)?,
+        })
+    }
+}
+
+/// Stored text and embedding, if any, of one message of an exchange.
+type Part = (String, Option<Vec<f64>>);
+
+/// Mean of the normalized question and answer embeddings, normalized.
+/// `None` when one of them is missing.
+fn combine(question: &[Part], answer: &Part) -> Option<Vec<f64>> {
+    let normalize = |vec: Vec<f64>| {
+        let norm = vec.iter().map(|x| x * x).sum::<f64>().sqrt();
+        if norm == 0.0 {
+            vec
+        } else {
+            vec.into_iter().map(|x| x / norm).collect()
+        }
+    };
+
+    let mut sum = answer.1.clone().map(normalize)?;
+    let question = question
+        .iter()
+        .map(|(_, embedding)| embedding.clone().map(normalize))
+        .collect::<Option<Vec<_>>>()?;
+    for embedding in &question {
+        for (total, x) in sum.iter_mut().zip(embedding) {
+            *total += x / question.len() as f64;
+        }
+    }
+    Some(normalize(sum))
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Stores the messages an interaction answered and its stored reply as
+    /// one exchange, replacing an earlier one, and returns its id. `None`
+    /// when the interaction has no stored reply.
+    pub async fn record_exchange(&self, interaction_id: i64) -> anyhow::Result<Option<i64>> {
+        let namespace = self.namespace.clone();
+
+        let parts = self
+            .conn
+            .call(move |conn| {
+                let Some(channel_id) = conn
+                    .query_row(
+                        "SELECT channel_id FROM interactions WHERE id = ?1 AND agent_id = ?2",
+                        rusqlite::params![interaction_id, namespace],
+                        |row| row.get::<_, String>(0),
+                    )
+                    .optional()?
+                else {
+                    return Ok(None);
+                };
+
+                let mut stored =
+                    conn.prepare("SELECT embedding FROM messages_embeddings WHERE rowid = ?1")?;
+                let mut embedding = |rowid: i64| {
+                    stored
+                        .query_row([rowid], |row| row.get::<_, Vec<u8>>(0))
+                        .optional()
+                        .map(|blob| blob.map(|blob| from_blob(&blob)))
+                };
+
+                let question = conn
+                    .prepare(
+                        "SELECT m.rowid, m.content FROM interaction_messages i
+                         JOIN messages m ON m.id = i.message_id AND m.agent_id = ?2
+                         WHERE i.interaction_id = ?1
+                         ORDER BY i.rowid",
+                    )?
+                    .query_map(rusqlite::params![interaction_id, namespace], |row| {
+                        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?
+                    .into_iter()
+                    .map(|(rowid, content)| Ok((content, embedding(rowid)?)))
+                    .collect::<Result<Vec<Part>, rusqlite::Error>>()?;
+
+                let Some((rowid, content, created_at)) = conn
+                    .query_row(
+                        "SELECT rowid, content, created_at FROM messages
+                         WHERE agent_id = ?2 AND role = 'assistant' AND id IN (
+                             SELECT message_id || ':reply' FROM interaction_messages
+                             WHERE interaction_id = ?1
+                         )
+                         ORDER BY created_at DESC
+                         LIMIT 1",
+                        rusqlite::params![interaction_id, namespace],
+                        |row| {
+                            Ok((
+                                row.get::<_, i64>(0)?,
+                                row.get::<_, String>(1)?,
+                                row.get::<_, DateTime<Utc>>(2)?,
+                            ))
+                        },
+                    )
+                    .optional()?
+                else {
+                    return Ok(None);
+                };
+                let answer = (content, embedding(rowid)?);
+
+                Ok(Some((channel_id, question, answer, created_at)))
+            })
+            .await?;
+
+        let Some((channel_id, question, answer, created_at)) = parts else {
+            return Ok(None);
+        };
+        if question.is_empty() {
+            return Ok(None);
+        }
+        let text = question
+            .iter()
+            .map(|(content, _)| content.as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let embedding = match combine(&question, &answer) {
+            Some(embedding) => embedding,
+            None => {
+                debug!(interaction_id, "Embedding exchange of unembedded messages");
+                self.embedding_model
+                    .embed_text(&format!("{text}\n{}", answer.0))
+                    .await?
+                    .vec
+            }
+        };
+        let namespace = self.namespace.clone();
+
+        let id = self
+            .conn
+            .call(move |conn| {
+                Ok(conn.query_row(
+                    "INSERT OR REPLACE INTO exchanges
+                         (agent_id, interaction_id, channel_id, question, answer, embedding,
+                          created_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                     RETURNING id",
+                    rusqlite::params![
+                        namespace,
+                        interaction_id,
+                        channel_id,
+                        text,
+                        answer.0,
+                        to_blob(&embedding),
+                        created_at.to_rfc3339()
+                    ],
+                    |row| row.get(0),
+                )?)
+            })
+            .await?;
+        Ok(Some(id))
+    }
+
+    /// The `k` exchanges of a channel most similar to `query`, most similar
+    /// first, with their cosine similarity.
+    pub async fn similar_exchanges(
+        &self,
+        channel_id: &str,
+        query: &str,
+        k: usize,
+    ) -> anyhow::Result<Vec<(f64, Exchange)>> {
+        let embedding = self.embedding_model.embed_text(query).await?.vec;
+        let namespace = self.namespace.clone();
+        let channel_id = channel_id.to_string();
+
+        let mut exchanges = self
+            .conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT {}, embedding FROM exchanges
+                     WHERE agent_id = ?1 AND channel_id = ?2",
+                    Exchange::COLUMNS
+                ))?;
+                let exchanges = stmt
+                    .query_map(rusqlite::params![namespace, channel_id], |row| {
+                        let exchange = Exchange::try_from(row)?;
+                        let blob = row.get::<_, Vec<u8>>(6)?;
+                        Ok((cosine_similarity(&embedding, &from_blob(&blob)), exchange))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(exchanges)
+            })
+            .await?;
+
+        exchanges.sort_by(|a, b| b.0.total_cmp(&a.0));
+        exchanges.truncate(k);
+        Ok(exchanges)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        knowledge::{ChannelType, Message, Source},
+        test_utils,
+    };
+
+    fn message(id: &str, role: &str, content: &str) -> Message {
+        Message {
+            id: id.to_string(),
+            source: Source::Discord,
+            source_id: "alice".to_string(),
+            channel_type: ChannelType::Text,
+            channel_id: "c1".to_string(),
+            account_id: "alice".to_string(),
+            role: role.to_string(),
+            content: content.to_string(),
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    async fn answer(
+        knowledge: &KnowledgeBase<test_utils::FakeEmbeddingModel>,
+        id: &str,
+        question: &str,
+        reply: &str,
+    ) -> i64 {
+        knowledge
+            .create_message(message(id, "user", question))
+            .await
+            .unwrap();
+        knowledge
+            .create_message(message(&format!("{id}:reply"), "assistant", reply))
+            .await
+            .unwrap();
+        knowledge
+            .create_interaction("c1".to_string(), "alice".to_string(), vec![id.to_string()])
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_exchanges_are_found_by_topic() {
+        let knowledge = test_utils::knowledge_base().await;
+        let staking = answer(
+            &knowledge,
+            "m1",
+            "how do I stake STRK tokens?",
+            "Stake STRK through the staking dashboard.",
+        )
+        .await;
+        let fees = answer(
+            &knowledge,
+            "m2",
+            "what do paymaster fees cost?",
+            "Paymaster fees are sponsored up to a daily limit.",
+        )
+        .await;
+        let unanswered = knowledge
+            .create_interaction(
+                "c1".to_string(),
+                "alice".to_string(),
+                vec!["m3".to_string()],
+            )
+            .await
+            .unwrap();
+
+        // The messages were embedded when stored
+        let calls = knowledge.embedding_service().metrics().calls();
+        let id = knowledge.record_exchange(staking).await.unwrap().unwrap();
+        assert!(knowledge.record_exchange(fees).await.unwrap().is_some());
+        assert_eq!(knowledge.embedding_service().metrics().calls(), calls);
+        assert_eq!(knowledge.record_exchange(unanswered).await.unwrap(), None);
+
+        let similar = knowledge
+            .similar_exchanges("c1", "staking STRK dashboard", 5)
+            .await
+            .unwrap();
+        assert_eq!(similar.len(), 2);
+        assert_eq!(similar[0].1.id, id);
+        assert!(similar[0].0 > similar[1].0);
+        assert_eq!(
+            similar[0].1.summary(),
+            "how do I stake STRK tokens? — Stake STRK through the staking dashboard."
+        );
+        assert!(knowledge
+            .similar_exchanges("c2", "staking STRK dashboard", 5)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Recording again replaces the exchange
+        knowledge.record_exchange(staking).await.unwrap();
+        let similar = knowledge
+            .similar_exchanges("c1", "staking STRK dashboard", 5)
+            .await
+            .unwrap();
+        assert_eq!(similar.len(), 2);
+    }
+}
@@ -0,0 +1,238 @@
+//! The experiment variant each interaction was answered with, see
+//! [crate::experiments], and how the variants compare.
+
+use chrono::{DateTime, Utc};
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+use tracing::info;
+
+use super::store::KnowledgeBase;
+use crate::experiments::Assignment;
+
+/// Adds the `experiment` and `variant` columns to interactions stored before
+/// experiments.
+pub(super) fn migrate(conn: &mut rusqlite::Connection) -> rusqlite::Result<()> {
+    for column in ["experiment", "variant"] {
+        let exists = conn
+            .prepare(&format!(
+                "SELECT 1 FROM pragma_table_info('interactions') WHERE name = '{column}'"
+            ))?
+            .exists([])?;
+        if !exists {
+            info!(column, "Adding experiment column to interactions");
+            conn.execute_batch(&format!(
+                "ALTER TABLE interactions ADD COLUMN {column} TEXT"
+            ))?;
+        }
+    }
+    conn.execute_batch(
+        "CREATE INDEX IF NOT EXISTS idx_interactions_experiment
+             ON interactions(agent_id, experiment, created_at)",
+    )
+}
+
+/// How one variant of an experiment did. Feedback and spend aren't recorded
+/// yet, so the report can't show a thumbs-up rate or cost.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VariantReport {
+    pub variant: String,
+    /// Interactions assigned the variant.
+    pub interactions: i64,
+    /// Interactions whose answer was sent.
+    pub answered: i64,
+    /// Answers withheld for low confidence.
+    pub declined: i64,
+    /// Mean confidence of the answers that were estimated, see
+    /// [crate::confidence].
+    pub mean_confidence: Option<f64>,
+}
+
+impl VariantReport {
+    /// Share of interactions that were answered, `0.0` without interactions.
+    pub fn answer_rate(&self) -> f64 {
+        match self.interactions {
+            0 => 0.0,
+            interactions => self.answered as f64 / interactions as f64,
+        }
+    }
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Records that `interaction_id` is answered with `assignment`'s variant.
+    pub async fn record_variant(
+        &self,
+        interaction_id: i64,
+        assignment: &Assignment,
+    ) -> Result<(), SqliteError> {
+        let namespace = self.namespace.clone();
+        let experiment = assignment.experiment.clone();
+        let variant = assignment.variant.name.clone();
+
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "UPDATE interactions SET experiment = ?1, variant = ?2
+                     WHERE id = ?3 AND agent_id = ?4",
+                    rusqlite::params![experiment, variant, interaction_id, namespace],
+                )?;
+                Ok(())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Per variant of the experiment `name`, by variant name, the
+    /// interactions since `since`.
+    pub async fn experiment_report(
+        &self,
+        name: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<VariantReport>, SqliteError> {
+        let namespace = self.namespace.clone();
+        let name = name.to_string();
+        // Interactions store SQLite's CURRENT_TIMESTAMP format
+        let since = since.format("%Y-%m-%d %H:%M:%S").to_string();
+
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT i.variant,
+                         COUNT(*),
+                         SUM(EXISTS (
+                             SELECT 1 FROM sent_messages s WHERE s.interaction_id = i.id
+                         )),
+                         COUNT(CASE WHEN c.outcome = 'declined' THEN 1 END),
+                         AVG(c.score)
+                     FROM interactions i
+                     LEFT JOIN interaction_confidence c ON c.interaction_id = i.id
+                     WHERE i.agent_id = ?1 AND i.experiment = ?2 AND i.created_at >= ?3
+                     GROUP BY i.variant
+                     ORDER BY i.variant",
+                )?;
+                let reports = stmt
+                    .query_map(rusqlite::params![namespace, name, since], |row| {
+                        Ok(VariantReport {
+                            variant: row.get(0)?,
+                            interactions: row.get(1)?,
+                            answered: row.get(2)?,
+                            declined: row.get(3)?,
+                            mean_confidence: row.get(4)?,
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(reports)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        confidence::{Confidence, ConfidenceOutcome},
+        experiments::VariantConfig,
+        knowledge::{RetrievalSupport, Source},
+        test_utils,
+    };
+
+    fn assignment(variant: &str) -> Assignment {
+        Assignment {
+            experiment: "model".to_string(),
+            variant: VariantConfig {
+                name: variant.to_string(),
+                weight: 1,
+                preamble: None,
+                model: None,
+                temperature: None,
+            },
+        }
+    }
+
+    fn confidence(score: f64, outcome: ConfidenceOutcome) -> Confidence {
+        Confidence {
+            score,
+            support: RetrievalSupport {
+                best_distance: None,
+                supporting: 1,
+            },
+            self_assessed: None,
+            outcome,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_report() {
+        let knowledge = test_utils::knowledge_base().await;
+        let since = Utc::now() - chrono::Duration::hours(1);
+
+        // control: answered at 0.8 and 0.6, one not answered
+        // mini: declined at 0.2
+        let seeded = [
+            ("control", Some(0.8), true),
+            ("control", Some(0.6), true),
+            ("control", None, false),
+            ("mini", Some(0.2), false),
+        ];
+        for (i, (variant, score, sent)) in seeded.into_iter().enumerate() {
+            let id = knowledge
+                .create_interaction("c1".to_string(), format!("user{i}"), Vec::new())
+                .await
+                .unwrap();
+            knowledge
+                .record_variant(id, &assignment(variant))
+                .await
+                .unwrap();
+            if let Some(score) = score {
+                let outcome = if sent {
+                    ConfidenceOutcome::Confident
+                } else {
+                    ConfidenceOutcome::Declined
+                };
+                knowledge
+                    .record_confidence(id, &confidence(score, outcome))
+                    .await
+                    .unwrap();
+            }
+            if sent {
+                knowledge
+                    .record_sent_messages(id, &Source::Discord, "c1", &[format!("m{i}")])
+                    .await
+                    .unwrap();
+            }
+        }
+        // Not part of the experiment
+        knowledge
+            .create_interaction("c1".to_string(), "bob".to_string(), Vec::new())
+            .await
+            .unwrap();
+
+        let report = knowledge.experiment_report("model", since).await.unwrap();
+
+        assert_eq!(report.len(), 2);
+        assert_eq!(report[0].variant, "control");
+        assert_eq!(report[0].interactions, 3);
+        assert_eq!(report[0].answered, 2);
+        assert_eq!(report[0].declined, 0);
+        assert!((report[0].mean_confidence.unwrap() - 0.7).abs() < 1e-9);
+        assert!((report[0].answer_rate() - 2.0 / 3.0).abs() < 1e-9);
+        assert_eq!(
+            report[1],
+            VariantReport {
+                variant: "mini".to_string(),
+                interactions: 1,
+                answered: 0,
+                declined: 1,
+                mean_confidence: Some(0.2),
+            }
+        );
+
+        let later = Utc::now() + chrono::Duration::hours(1);
+        assert!(knowledge
+            .experiment_report("model", later)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,105 @@
+use chrono::{DateTime, Utc};
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+
+use super::store::KnowledgeBase;
+
+pub(super) const SCHEMA: &str = "
+    CREATE INDEX IF NOT EXISTS idx_interactions_account ON interactions(account_id);
+";
+
+/// How well the bot knows a channel and a user, from the interactions it
+/// answered before, see [crate::familiarity].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Familiarity {
+    /// Interactions answered in the channel.
+    pub channel_interactions: u64,
+    pub channel_last: Option<DateTime<Utc>>,
+    /// Interactions answered for the user, in any channel.
+    pub user_interactions: u64,
+    pub user_last: Option<DateTime<Utc>>,
+}
+
+impl Familiarity {
+    /// Whether the bot never answered the user before.
+    pub fn is_first_time(&self) -> bool {
+        self.user_interactions == 0
+    }
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Counts and latest times of the interactions answered in `channel_id`
+    /// and for `account_id`, or any account linked to it.
+    pub async fn familiarity(
+        &self,
+        channel_id: &str,
+        account_id: &str,
+    ) -> Result<Familiarity, SqliteError> {
+        let namespace = self.namespace.clone();
+        let channel_id = channel_id.to_string();
+        // Answers to any linked account of the user count
+        let accounts = match self.linked_identity(account_id).await? {
+            Some(identity) => identity.accounts.into_iter().map(|(_, id)| id).collect(),
+            None => vec![account_id.to_string()],
+        };
+        let accounts = serde_json::to_string(&accounts)
+            .map_err(|e| SqliteError::SerializationError(Box::new(e)))?;
+
+        self.conn
+            .call(move |conn| {
+                let (channel_interactions, channel_last) = conn.query_row(
+                    "SELECT COUNT(*), MAX(created_at) FROM interactions
+                     WHERE agent_id = ?1 AND channel_id = ?2",
+                    rusqlite::params![namespace, channel_id],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )?;
+                let (user_interactions, user_last) = conn.query_row(
+                    "SELECT COUNT(*), MAX(created_at) FROM interactions
+                     WHERE agent_id = ?1 AND account_id IN (SELECT value FROM json_each(?2))",
+                    rusqlite::params![namespace, accounts],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )?;
+                Ok(Familiarity {
+                    channel_interactions,
+                    channel_last,
+                    user_interactions,
+                    user_last,
+                })
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn test_familiarity_counts() {
+        let knowledge = test_utils::knowledge_base().await;
+        for (channel_id, account_id) in [("c1", "alice"), ("c1", "bob"), ("c2", "alice")] {
+            knowledge
+                .create_interaction(channel_id.to_string(), account_id.to_string(), Vec::new())
+                .await
+                .unwrap();
+        }
+
+        let familiarity = knowledge.familiarity("c1", "alice").await.unwrap();
+        assert_eq!(familiarity.channel_interactions, 2);
+        assert_eq!(familiarity.user_interactions, 2);
+        assert!(familiarity.channel_last.is_some() && familiarity.user_last.is_some());
+        assert!(!familiarity.is_first_time());
+
+        let familiarity = knowledge.familiarity("c3", "carol").await.unwrap();
+        assert_eq!(familiarity, Default::default());
+        assert!(familiarity.is_first_time());
+
+        // Interactions of another character don't count
+        let other = knowledge.with_namespace("other");
+        assert_eq!(
+            other.familiarity("c1", "alice").await.unwrap(),
+            Default::default()
+        );
+    }
+}
@@ -0,0 +1,362 @@
+use rig::{embeddings::EmbeddingModel, vector_store::VectorStoreIndex};
+use rig_sqlite::SqliteError;
+use rusqlite::Row;
+use tracing::{debug, info};
+
+use super::store::KnowledgeBase;
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS knowledge_gaps (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        question TEXT NOT NULL,
+        channel_id TEXT NOT NULL,
+        best_distance REAL,
+        embedding BLOB NOT NULL,
+        occurrences INTEGER NOT NULL DEFAULT 1,
+        first_asked_at TEXT NOT NULL,
+        last_asked_at TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS idx_knowledge_gaps_last_asked ON knowledge_gaps(last_asked_at);
+";
+
+/// When a question counts as unanswered by the docs.
+#[derive(Clone, Debug)]
+pub struct GapConfig {
+    /// Retrieval distance above which the best document is not relevant. The
+    /// store ranks by L2 distance, which lies in `0.0..=2.0` for normalized
+    /// embeddings.
+    pub max_distance: f64,
+    /// Cosine similarity at which a question counts as a repeat of a
+    /// recorded gap rather than a new one.
+    pub merge_similarity: f64,
+}
+
+impl Default for GapConfig {
+    fn default() -> Self {
+        Self {
+            max_distance: 1.0,
+            merge_similarity: 0.9,
+        }
+    }
+}
+
+/// A question retrieval had no good answer for, merged with its repeats.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KnowledgeGap {
+    pub id: i64,
+    /// The first phrasing asked.
+    pub question: String,
+    pub channel_id: String,
+    /// Distance of the closest document when last asked; `None` when nothing
+    /// matched at all.
+    pub best_distance: Option<f64>,
+    pub occurrences: i64,
+    pub first_asked_at: chrono::DateTime<chrono::Utc>,
+    pub last_asked_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl TryFrom<&Row<'_>> for KnowledgeGap {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &Row) -> Result<Self, Self::Error> {
+        Ok(KnowledgeGap {
+            id: row.get(0)?,
+            question: row.get(1)?,
+            channel_id: row.get(2)?,
+            best_distance: row.get(3)?,
+            occurrences: row.get(4)?,
+            first_asked_at: row.get(5)?,
+            last_asked_at: row.get(6)?,
+        })
+    }
+}
+
+/// How well the docs cover a question, see [KnowledgeBase::retrieval_support].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetrievalSupport {
+    /// Distance of the closest document; `None` when nothing matched at all.
+    pub best_distance: Option<f64>,
+    /// Documents within the distance limit.
+    pub supporting: usize,
+}
+
+pub(super) fn cosine_similarity(a: &[f64], b: &[f64]) -> f64 {
+    let dot = a.iter().zip(b).map(|(x, y)| x * y).sum::<f64>();
+    let norm = |v: &[f64]| v.iter().map(|x| x * x).sum::<f64>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
+pub(super) fn to_blob(vec: &[f64]) -> Vec<u8> {
+    vec.iter().flat_map(|x| (*x as f32).to_le_bytes()).collect()
+}
+
+pub(super) fn from_blob(blob: &[u8]) -> Vec<f64> {
+    blob.chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64)
+        .collect()
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Looks up `question` in the docs and records a gap when the closest
+    /// document is further than [GapConfig::max_distance], or there is none.
+    /// Returns the id of the recorded gap.
+    pub async fn detect_gap(
+        &self,
+        question: &str,
+        channel_id: &str,
+        config: &GapConfig,
+    ) -> anyhow::Result<Option<i64>> {
+        let best_distance = self
+            .clone()
+            .fresh_index(self.clone().document_index())
+            .top_n_ids(question, 1)
+            .await?
+            .first()
+            .map(|(distance, _)| *distance);
+
+        if best_distance.is_some_and(|distance| distance <= config.max_distance) {
+            return Ok(None);
+        }
+
+        debug!(
+            question,
+            ?best_distance,
+            "No relevant documents for question"
+        );
+        let id = self
+            .record_gap(question, channel_id, best_distance, config)
+            .await?;
+        Ok(Some(id))
+    }
+
+    /// Looks up the `n` documents closest to `question` and counts those
+    /// within `max_distance`.
+    pub async fn retrieval_support(
+        &self,
+        question: &str,
+        n: usize,
+        max_distance: f64,
+    ) -> anyhow::Result<RetrievalSupport> {
+        let results = self
+            .clone()
+            .fresh_index(self.clone().document_index())
+            .top_n_ids(question, n)
+            .await?;
+
+        Ok(RetrievalSupport {
+            best_distance: results.first().map(|(distance, _)| *distance),
+            supporting: results
+                .iter()
+                .filter(|(distance, _)| *distance <= max_distance)
+                .count(),
+        })
+    }
+
+    /// Stores a gap, or bumps the counter of a similar one already recorded.
+    pub async fn record_gap(
+        &self,
+        question: &str,
+        channel_id: &str,
+        best_distance: Option<f64>,
+        config: &GapConfig,
+    ) -> anyhow::Result<i64> {
+        let embedding = self.embedding_model.embed_text(question).await?.vec;
+        let question = question.to_string();
+        let channel_id = channel_id.to_string();
+        let merge_similarity = config.merge_similarity;
+        let now = self.clock.now_utc().to_rfc3339();
+        let namespace = self.namespace.clone();
+
+        let (id, merged) = self
+            .conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+
+                let similar = {
+                    let mut stmt =
+                        tx.prepare("SELECT id, embedding FROM knowledge_gaps WHERE agent_id = ?1")?;
+                    let gaps = stmt
+                        .query_map([&namespace], |row| {
+                            Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?))
+                        })?
+                        .collect::<Result<Vec<_>, _>>()?;
+
+                    gaps.into_iter()
+                        .map(|(id, blob)| (id, cosine_similarity(&embedding, &from_blob(&blob))))
+                        .filter(|(_, similarity)| *similarity >= merge_similarity)
+                        .max_by(|a, b| a.1.total_cmp(&b.1))
+                };
+
+                let result = match similar {
+                    Some((id, _)) => {
+                        tx.execute(
+                            "UPDATE knowledge_gaps
+                             SET occurrences = occurrences + 1, best_distance = ?2, last_asked_at = ?3
+                             WHERE id = ?1",
+                            rusqlite::params![id, best_distance, now],
+                        )?;
+                        (id, true)
+                    }
+                    None => {
+                        let id = tx.query_row(
+                            "INSERT INTO knowledge_gaps
+                                 (question, channel_id, best_distance, embedding, first_asked_at, last_asked_at, agent_id)
+                             VALUES (?1, ?2, ?3, ?4, ?5, ?5, ?6)
+                             RETURNING id",
+                            rusqlite::params![question, channel_id, best_distance, to_blob(&embedding), now, namespace],
+                            |row| row.get(0),
+                        )?;
+                        (id, false)
+                    }
+                };
+
+                tx.commit()?;
+                Ok(result)
+            })
+            .await?;
+
+        info!(id, merged, "Recorded knowledge gap");
+        Ok(id)
+    }
+
+    /// Gaps asked about since `since`, most frequent first.
+    pub async fn top_gaps(
+        &self,
+        since: chrono::DateTime<chrono::Utc>,
+        limit: usize,
+    ) -> Result<Vec<KnowledgeGap>, SqliteError> {
+        let since = since.to_rfc3339();
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT id, question, channel_id, best_distance, occurrences, first_asked_at, last_asked_at
+                     FROM knowledge_gaps
+                     WHERE agent_id = ?3 AND last_asked_at >= ?1
+                     ORDER BY occurrences DESC, last_asked_at DESC
+                     LIMIT ?2",
+                )?;
+                let gaps = stmt
+                    .query_map(rusqlite::params![since, limit as i64, namespace], |row| {
+                        KnowledgeGap::try_from(row)
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(gaps)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+}
+
+/// One line per gap, for admin replies and summary posts.
+pub fn format_gaps(gaps: &[KnowledgeGap]) -> String {
+    if gaps.is_empty() {
+        return "No knowledge gaps recorded.".to_string();
+    }
+
+    gaps.iter()
+        .map(|gap| {
+            let distance = gap
+                .best_distance
+                .map_or("no match".to_string(), |d| format!("best distance {d:.2}"));
+            format!("{}× \"{}\" ({})", gap.occurrences, gap.question, distance)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{knowledge::Document, test_utils};
+
+    #[tokio::test]
+    async fn test_gaps_are_detected_and_merged() {
+        let mut knowledge = test_utils::knowledge_base().await;
+        knowledge
+            .add_documents(vec![Document {
+                id: "katana.md".to_string(),
+                source_id: "github".to_string(),
+                content: "katana devnet flags".to_string(),
+                created_at: chrono::Utc::now(),
+                title: String::new(),
+                section: String::new(),
+                topics: Vec::new(),
+                logical_id: None,
+                cleaned: None,
+                chunk: None,
+            }])
+            .await
+            .unwrap();
+        let since = chrono::Utc::now() - chrono::Duration::minutes(1);
+        let config = GapConfig {
+            max_distance: 0.5,
+            ..Default::default()
+        };
+
+        let covered = knowledge
+            .detect_gap("katana devnet flags", "c1", &config)
+            .await
+            .unwrap();
+        assert_eq!(covered, None);
+
+        let first = knowledge
+            .detect_gap("paymaster sponsorship limits", "c1", &config)
+            .await
+            .unwrap();
+        let repeat = knowledge
+            .detect_gap("Paymaster SPONSORSHIP limits", "c2", &config)
+            .await
+            .unwrap();
+        let other = knowledge
+            .detect_gap("vrf randomness request", "c1", &config)
+            .await
+            .unwrap();
+        assert!(first.is_some());
+        assert_eq!(repeat, first);
+        assert_ne!(other, first);
+
+        // A looser threshold treats the same question as covered.
+        let loose = GapConfig {
+            max_distance: 2.0,
+            ..Default::default()
+        };
+        let ignored = knowledge
+            .detect_gap("paymaster sponsorship limits", "c1", &loose)
+            .await
+            .unwrap();
+        assert_eq!(ignored, None);
+
+        let gaps = knowledge.top_gaps(since, 10).await.unwrap();
+        assert_eq!(gaps.len(), 2);
+        assert_eq!(gaps[0].question, "paymaster sponsorship limits");
+        assert_eq!(gaps[0].occurrences, 2);
+        assert_eq!(gaps[1].occurrences, 1);
+        assert!(gaps[0].best_distance.unwrap() > 0.5);
+
+        let later = chrono::Utc::now() + chrono::Duration::minutes(1);
+        assert!(knowledge.top_gaps(later, 10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_empty_knowledge_base_is_a_gap() {
+        let knowledge = test_utils::knowledge_base().await;
+
+        let id = knowledge
+            .detect_gap("anything at all", "c1", &GapConfig::default())
+            .await
+            .unwrap();
+        assert!(id.is_some());
+
+        let since = chrono::Utc::now() - chrono::Duration::minutes(1);
+        let gaps = knowledge.top_gaps(since, 10).await.unwrap();
+        assert_eq!(gaps[0].best_distance, None);
+        assert!(format_gaps(&gaps).contains("1× \"anything at all\" (no match)"));
+    }
+}
@@ -0,0 +1,412 @@
+//! Channels discovered when the bot joins a Discord guild, settings that
+//! apply to all of its channels, and cleanup of what is stored about a guild
+//! once the bot leaves it.
+
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+use rusqlite::OptionalExtension;
+use tracing::info;
+
+use super::{models::Channel, store::KnowledgeBase};
+
+/// What happens to a guild's messages when the bot leaves it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MessageRetention {
+    /// Keep them, e.g. for summaries and gap reports.
+    #[default]
+    Keep,
+    Delete,
+}
+
+/// A channel found in a guild.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredChannel {
+    pub channel_id: String,
+    pub channel_type: String,
+    pub name: String,
+}
+
+/// Rows removed by [KnowledgeBase::delete_guild_data].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GuildCleanup {
+    pub channels: usize,
+    pub settings: usize,
+    pub pins: usize,
+    pub messages: usize,
+}
+
+/// Adds the `guild_id` column to channels created before guilds were
+/// tracked, and the table of guild settings.
+pub(super) fn migrate(conn: &mut rusqlite::Connection) -> rusqlite::Result<()> {
+    let has_guild_id = conn
+        .prepare("SELECT 1 FROM pragma_table_info('channels') WHERE name = 'guild_id'")?
+        .exists([])?;
+    if !has_guild_id {
+        info!("Adding guild_id column to channels");
+        conn.execute_batch("ALTER TABLE channels ADD COLUMN guild_id TEXT")?;
+    }
+    conn.execute_batch(
+        "CREATE INDEX IF NOT EXISTS idx_channels_guild ON channels(guild_id);
+         CREATE TABLE IF NOT EXISTS guild_settings (
+             agent_id TEXT NOT NULL,
+             guild_id TEXT NOT NULL,
+             key TEXT NOT NULL,
+             value TEXT NOT NULL,
+             updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+             PRIMARY KEY (agent_id, guild_id, key)
+         );",
+    )
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Records the channels of a guild, updating the names and types of the
+    /// ones already known, e.g. after rejoining.
+    pub async fn upsert_guild_channels(
+        &self,
+        guild_id: &str,
+        channels: Vec<DiscoveredChannel>,
+    ) -> Result<(), SqliteError> {
+        let guild_id = guild_id.to_string();
+
+        self.conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                for channel in &channels {
+                    tx.execute(
+                        "INSERT INTO channels (channel_id, channel_type, source, name, guild_id)
+                         VALUES (?1, ?2, 'discord', ?3, ?4)
+                         ON CONFLICT (channel_id) DO UPDATE SET
+                             channel_type = excluded.channel_type,
+                             name = excluded.name,
+                             guild_id = excluded.guild_id,
+                             updated_at = CURRENT_TIMESTAMP",
+                        [
+                            &channel.channel_id,
+                            &channel.channel_type,
+                            &channel.name,
+                            &guild_id,
+                        ],
+                    )?;
+                }
+                tx.commit()?;
+                Ok(())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    pub async fn guild_channels(&self, guild_id: &str) -> Result<Vec<Channel>, SqliteError> {
+        let guild_id = guild_id.to_string();
+
+        self.conn
+            .call(move |conn| {
+                let channels = conn
+                    .prepare(
+                        "SELECT id, channel_id, channel_type, source, name, guild_id, created_at, updated_at
+                         FROM channels WHERE guild_id = ?1 ORDER BY channel_id",
+                    )?
+                    .query_map([&guild_id], |row| Channel::try_from(row))?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(channels)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// The guild `channel_id` was discovered in, `None` for direct messages
+    /// and channels of other platforms.
+    pub async fn channel_guild(&self, channel_id: &str) -> Result<Option<String>, SqliteError> {
+        let channel_id = channel_id.to_string();
+
+        self.conn
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        "SELECT guild_id FROM channels WHERE channel_id = ?1",
+                        [&channel_id],
+                        |row| row.get::<_, Option<String>>(0),
+                    )
+                    .optional()?
+                    .flatten())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// A setting of every channel in `guild_id` that doesn't set `key`
+    /// itself, see [KnowledgeBase::channel_setting].
+    pub async fn guild_setting(
+        &self,
+        guild_id: &str,
+        key: &str,
+    ) -> Result<Option<String>, SqliteError> {
+        let guild_id = guild_id.to_string();
+        let key = key.to_string();
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        "SELECT value FROM guild_settings
+                         WHERE agent_id = ?1 AND guild_id = ?2 AND key = ?3",
+                        [&namespace, &guild_id, &key],
+                        |row| row.get(0),
+                    )
+                    .optional()?)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Sets a guild setting, or removes it when `value` is `None`.
+    pub async fn set_guild_setting(
+        &self,
+        guild_id: &str,
+        key: &str,
+        value: Option<&str>,
+    ) -> Result<(), SqliteError> {
+        let guild_id = guild_id.to_string();
+        let key = key.to_string();
+        let value = value.map(str::to_string);
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                match value {
+                    Some(value) => conn.execute(
+                        "INSERT INTO guild_settings (agent_id, guild_id, key, value)
+                         VALUES (?1, ?2, ?3, ?4)
+                         ON CONFLICT (agent_id, guild_id, key) DO UPDATE SET
+                             value = excluded.value,
+                             updated_at = CURRENT_TIMESTAMP",
+                        [&namespace, &guild_id, &key, &value],
+                    )?,
+                    None => conn.execute(
+                        "DELETE FROM guild_settings
+                         WHERE agent_id = ?1 AND guild_id = ?2 AND key = ?3",
+                        [&namespace, &guild_id, &key],
+                    )?,
+                };
+                Ok(())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Deletes a guild's channels and this knowledge base's settings of the
+    /// guild and settings and pins of its channels. Messages, including ones waiting to be embedded, are
+    /// only deleted with [MessageRetention::Delete].
+    pub async fn delete_guild_data(
+        &self,
+        guild_id: &str,
+        retention: MessageRetention,
+    ) -> Result<GuildCleanup, SqliteError> {
+        let guild = guild_id.to_string();
+        let namespace = self.namespace.clone();
+
+        let cleanup = self
+            .conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let in_guild =
+                    "channel_id IN (SELECT channel_id FROM channels WHERE guild_id = ?2)";
+
+                let settings = tx.execute(
+                    &format!("DELETE FROM channel_settings WHERE agent_id = ?1 AND {in_guild}"),
+                    [&namespace, &guild],
+                )? + tx.execute(
+                    "DELETE FROM guild_settings WHERE agent_id = ?1 AND guild_id = ?2",
+                    [&namespace, &guild],
+                )?;
+                let pins = tx.execute(
+                    &format!("DELETE FROM pinned_context WHERE agent_id = ?1 AND {in_guild}"),
+                    [&namespace, &guild],
+                )?;
+                let mut messages = 0;
+                if retention == MessageRetention::Delete {
+                    tx.execute(
+                        &format!(
+                            "DELETE FROM messages_embeddings WHERE rowid IN (
+                                 SELECT rowid FROM messages WHERE agent_id = ?1 AND {in_guild}
+                             )"
+                        ),
+                        [&namespace, &guild],
+                    )?;
+                    messages += tx.execute(
+                        &format!("DELETE FROM messages WHERE agent_id = ?1 AND {in_guild}"),
+                        [&namespace, &guild],
+                    )?;
+                    messages += tx.execute(
+                        &format!("DELETE FROM pending_messages WHERE agent_id = ?1 AND {in_guild}"),
+                        [&namespace, &guild],
+                    )?;
+                }
+                let channels = tx.execute("DELETE FROM channels WHERE guild_id = ?1", [&guild])?;
+                tx.commit()?;
+
+                Ok(GuildCleanup {
+                    channels,
+                    settings,
+                    pins,
+                    messages,
+                })
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))?;
+
+        info!(guild_id, ?retention, ?cleanup, "Deleted guild data");
+        Ok(cleanup)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        knowledge::{ChannelType, Message, Source},
+        test_utils,
+    };
+
+    fn channel(id: &str, name: &str) -> DiscoveredChannel {
+        DiscoveredChannel {
+            channel_id: id.to_string(),
+            channel_type: "text".to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    fn message(id: &str, channel_id: &str) -> Message {
+        Message {
+            id: id.to_string(),
+            source: Source::Discord,
+            source_id: "user".to_string(),
+            channel_type: ChannelType::Text,
+            channel_id: channel_id.to_string(),
+            account_id: "user".to_string(),
+            role: "user".to_string(),
+            content: format!("message {id}"),
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upsert_on_rejoin() {
+        let knowledge = test_utils::knowledge_base().await;
+        knowledge
+            .upsert_guild_channels("g1", vec![channel("c1", "general"), channel("c2", "help")])
+            .await
+            .unwrap();
+        knowledge
+            .set_channel_setting("c1", "listen", Some("off"))
+            .await
+            .unwrap();
+        knowledge
+            .delete_guild_data("g1", MessageRetention::Keep)
+            .await
+            .unwrap();
+        assert!(knowledge.guild_channels("g1").await.unwrap().is_empty());
+
+        // Rejoining with a renamed channel, and the guild showing up again
+        // on reconnect.
+        for _ in 0..2 {
+            knowledge
+                .upsert_guild_channels(
+                    "g1",
+                    vec![channel("c1", "general"), channel("c2", "support")],
+                )
+                .await
+                .unwrap();
+        }
+
+        let channels = knowledge.guild_channels("g1").await.unwrap();
+        let names: Vec<_> = channels
+            .iter()
+            .map(|c| (c.channel_id.as_str(), c.name.as_deref().unwrap()))
+            .collect();
+        assert_eq!(names, [("c1", "general"), ("c2", "support")]);
+        assert!(channels
+            .iter()
+            .all(|c| c.source == "discord" && c.guild_id.as_deref() == Some("g1")));
+        assert_eq!(
+            knowledge.channel_setting("c1", "listen").await.unwrap(),
+            None
+        );
+
+        // Defaults are applied once, and don't override what admins set
+        assert!(knowledge
+            .init_channel_setting("c1", "listen", "off")
+            .await
+            .unwrap());
+        knowledge
+            .set_channel_setting("c1", "listen", Some("on"))
+            .await
+            .unwrap();
+        assert!(!knowledge
+            .init_channel_setting("c1", "listen", "off")
+            .await
+            .unwrap());
+        assert_eq!(
+            knowledge
+                .channel_setting("c1", "listen")
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("on")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_respects_retention() {
+        let knowledge = test_utils::knowledge_base().await;
+        for (guild_id, channel_id) in [("g1", "c1"), ("g2", "c2")] {
+            knowledge
+                .upsert_guild_channels(guild_id, vec![channel(channel_id, "general")])
+                .await
+                .unwrap();
+            knowledge
+                .set_channel_setting(channel_id, "listen", Some("off"))
+                .await
+                .unwrap();
+            knowledge
+                .add_pin(
+                    Some(channel_id.to_string()),
+                    "Fees are paid in STRK".to_string(),
+                    "admin".to_string(),
+                )
+                .await
+                .unwrap();
+            knowledge
+                .create_message(message(&format!("{channel_id}-m"), channel_id))
+                .await
+                .unwrap();
+        }
+
+        let kept = knowledge
+            .delete_guild_data("g1", MessageRetention::Keep)
+            .await
+            .unwrap();
+        assert_eq!(
+            kept,
+            GuildCleanup {
+                channels: 1,
+                settings: 1,
+                pins: 1,
+                messages: 0,
+            }
+        );
+        assert!(knowledge.message_exists("c1-m").await.unwrap());
+
+        let deleted = knowledge
+            .delete_guild_data("g2", MessageRetention::Delete)
+            .await
+            .unwrap();
+        assert_eq!(deleted.messages, 1);
+        assert!(!knowledge.message_exists("c2-m").await.unwrap());
+        assert!(knowledge.message_exists("c1-m").await.unwrap());
+        assert_eq!(
+            knowledge.channel_setting("c2", "listen").await.unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,262 @@
+//! Content-addressed identity of documents, so a page keeps its id, its
+//! embedding and the citations pointing at it when its file moves.
+//!
+//! Every version stores a hash of its normalized content next to the path it
+//! was read from. When a sync removes some files and adds others, an added
+//! file hashing like a removed one is a move: the logical document is given
+//! the new path and nothing is embedded again. An added file whose content
+//! also changed a little is matched to a removed one by embedding similarity
+//! instead, and stored as a new version under the new path. Citations keep
+//! the document id and look up the current path with
+//! [KnowledgeBase::resolve_document].
+
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+use rusqlite::OptionalExtension;
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use super::{gaps::from_blob, store::KnowledgeBase};
+
+/// Embedding similarity above which an added document whose content changed
+/// is still taken for a moved one.
+pub const RENAME_SIMILARITY: f64 = 0.9;
+
+/// Adds the `content_hash` column to document versions stored before it
+/// existed, and hashes their content.
+pub(super) fn migrate(conn: &mut rusqlite::Connection) -> rusqlite::Result<()> {
+    let exists = conn
+        .prepare(
+            "SELECT 1 FROM pragma_table_info('document_versions') WHERE name = 'content_hash'",
+        )?
+        .exists([])?;
+    if !exists {
+        info!("Adding content hashes to document versions");
+        let tx = conn.transaction()?;
+        tx.execute_batch("ALTER TABLE document_versions ADD COLUMN content_hash TEXT")?;
+        let contents = tx
+            .prepare(
+                "SELECT v.document_id, d.content
+                 FROM document_versions v
+                 JOIN documents d ON d.id = v.document_id",
+            )?
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (document_id, content) in contents {
+            tx.execute(
+                "UPDATE document_versions SET content_hash = ?1 WHERE document_id = ?2",
+                [content_hash(&content), document_id],
+            )?;
+        }
+        tx.commit()?;
+    }
+    conn.execute_batch(
+        "CREATE INDEX IF NOT EXISTS idx_document_versions_hash
+             ON document_versions(agent_id, content_hash);",
+    )
+}
+
+/// Hash of `content` that ignores what changes when a page is only moved:
+/// the front matter, which docs tooling rewrites with positions and dates,
+/// line endings, trailing whitespace and runs of blank lines.
+pub fn content_hash(content: &str) -> String {
+    Sha256::digest(normalize(content).as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn normalize(content: &str) -> String {
+    let body = content
+        .strip_prefix("---\n")
+        .or_else(|| content.strip_prefix("---\r\n"))
+        .and_then(|rest| {
+            let mut offset = 0;
+            for line in rest.split_inclusive('\n') {
+                offset += line.len();
+                if line.trim_end() == "---" {
+                    return Some(&rest[offset..]);
+                }
+            }
+            // No closing delimiter, so this is not front matter.
+            None
+        })
+        .unwrap_or(content);
+
+    let mut lines = Vec::new();
+    for line in body.lines().map(str::trim_end) {
+        if line.is_empty() && lines.last().map_or(true, |last: &&str| last.is_empty()) {
+            continue;
+        }
+        lines.push(line);
+    }
+    lines.join("\n").trim_end().to_string()
+}
+
+/// Where a stored document is now, see [KnowledgeBase::resolve_document].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedDocument {
+    /// Id the document was stored under, which citations keep.
+    pub id: String,
+    /// Logical id of the document, its current path for documents read from
+    /// a repository.
+    pub path: String,
+    pub content_hash: Option<String>,
+    /// Whether a newer version replaced this one.
+    pub superseded: bool,
+}
+
+/// Live version of a document whose file was removed, which an added file
+/// may be a move of.
+pub(super) struct RemovedDocument {
+    pub document_id: String,
+    pub logical_id: String,
+    pub content_hash: String,
+    pub embedding: Vec<f64>,
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// The document stored under the id `id_or_hash`, or else the newest live
+    /// document whose content hashes to it, with its current path.
+    pub async fn resolve_document(
+        &self,
+        id_or_hash: &str,
+    ) -> Result<Option<ResolvedDocument>, SqliteError> {
+        let key = id_or_hash.to_string();
+        let namespaces = serde_json::to_string(
+            &std::iter::once(&self.namespace)
+                .chain(&self.shared_namespaces)
+                .collect::<Vec<_>>(),
+        )
+        .map_err(|e| SqliteError::SerializationError(Box::new(e)))?;
+
+        self.conn
+            .call(move |conn| {
+                let resolved = |row: &rusqlite::Row| {
+                    Ok(ResolvedDocument {
+                        id: row.get(0)?,
+                        path: row.get(1)?,
+                        content_hash: row.get(2)?,
+                        superseded: row.get(3)?,
+                    })
+                };
+                let by_id = conn
+                    .query_row(
+                        "SELECT d.id, COALESCE(v.logical_id, d.id), v.content_hash,
+                                COALESCE(v.superseded, 0)
+                         FROM documents d
+                         LEFT JOIN document_versions v ON v.document_id = d.id
+                         WHERE d.id = ?1 AND d.agent_id IN (SELECT value FROM json_each(?2))",
+                        [&key, &namespaces],
+                        resolved,
+                    )
+                    .optional()?;
+                if by_id.is_some() {
+                    return Ok(by_id);
+                }
+                Ok(conn
+                    .query_row(
+                        "SELECT d.id, v.logical_id, v.content_hash, v.superseded
+                         FROM document_versions v
+                         JOIN documents d ON d.id = v.document_id
+                         WHERE v.content_hash = ?1
+                             AND v.agent_id IN (SELECT value FROM json_each(?2))
+                         ORDER BY v.superseded, d.created_at DESC
+                         LIMIT 1",
+                        [&key, &namespaces],
+                        resolved,
+                    )
+                    .optional()?)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// The live versions of `logical_ids`, with their embeddings.
+    pub(super) async fn removed_documents(
+        &self,
+        logical_ids: Vec<String>,
+    ) -> Result<Vec<RemovedDocument>, tokio_rusqlite::Error> {
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT v.document_id, v.content_hash, e.embedding
+                     FROM document_versions v
+                     JOIN documents d ON d.id = v.document_id
+                     JOIN documents_embeddings e ON e.rowid = d.rowid
+                     WHERE v.agent_id = ?2 AND v.logical_id = ?1 AND v.superseded = 0",
+                )?;
+                let mut removed = Vec::new();
+                for logical_id in logical_ids {
+                    let row = stmt
+                        .query_row([&logical_id, &namespace], |row| {
+                            Ok((
+                                row.get::<_, String>(0)?,
+                                row.get::<_, Option<String>>(1)?,
+                                row.get::<_, Vec<u8>>(2)?,
+                            ))
+                        })
+                        .optional()?;
+                    if let Some((document_id, Some(content_hash), blob)) = row {
+                        removed.push(RemovedDocument {
+                            document_id,
+                            logical_id,
+                            content_hash,
+                            embedding: from_blob(&blob),
+                        });
+                    }
+                }
+                Ok(removed)
+            })
+            .await
+    }
+
+    /// Gives every version of the logical document `from` the logical id
+    /// `to`, keeping their ids and embeddings.
+    pub(super) async fn move_document(
+        &self,
+        from: &str,
+        to: &str,
+    ) -> Result<(), tokio_rusqlite::Error> {
+        let namespace = self.namespace.clone();
+        let (from, to) = (from.to_string(), to.to_string());
+
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "UPDATE document_versions SET logical_id = ?2
+                     WHERE agent_id = ?3 AND logical_id = ?1",
+                    [&from, &to, &namespace],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_hash_ignores_noise() {
+        let page = "# VRF\n\nRandomness on chain.\n";
+        assert_eq!(
+            content_hash(page),
+            content_hash("---\nsidebar_position: 3\n---\n# VRF  \r\n\n\n\nRandomness on chain.")
+        );
+        assert_ne!(
+            content_hash(page),
+            content_hash("# VRF\n\nRandomness off chain.\n")
+        );
+        // An unclosed block is content, not front matter
+        assert_ne!(
+            content_hash(page),
+            content_hash("---\n# VRF\n\nRandomness on chain.\n")
+        );
+    }
+}
@@ -0,0 +1,420 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures::{Stream, StreamExt};
+use rig::embeddings::{EmbeddingError, EmbeddingModel};
+use tracing::{debug, error, info, warn};
+
+use super::{embeddings::circuit_open, models::Document, store::KnowledgeBase};
+
+pub type IngestHook = Arc<dyn Fn(&IngestEvent) + Send + Sync>;
+
+/// Controls how [KnowledgeBase::add_documents_stream] pulls from its source.
+/// At most `batch_size * concurrency` documents are held in memory at once.
+///
+/// Retries follow the [EmbeddingService](super::EmbeddingService) config:
+/// it backs off on rate limits and other transient failures itself, and a
+/// batch its open circuit turned away is tried again once the circuit lets
+/// calls through, up to
+/// [EmbeddingServiceConfig::max_retries](super::EmbeddingServiceConfig::max_retries)
+/// times.
+#[derive(Clone)]
+pub struct IngestOptions {
+    pub batch_size: usize,
+    pub concurrency: usize,
+    pub on_event: Option<IngestHook>,
+}
+
+impl Default for IngestOptions {
+    fn default() -> Self {
+        Self {
+            batch_size: 32,
+            concurrency: 4,
+            on_event: None,
+        }
+    }
+}
+
+impl IngestOptions {
+    pub fn with_hook(mut self, hook: impl Fn(&IngestEvent) + Send + Sync + 'static) -> Self {
+        self.on_event = Some(Arc::new(hook));
+        self
+    }
+
+    fn emit(&self, event: IngestEvent) {
+        if let Some(hook) = &self.on_event {
+            hook(&event);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IngestEvent {
+    /// A batch was pulled from the source. `buffered` counts every document
+    /// currently held in memory, including batches still being embedded.
+    BatchQueued {
+        size: usize,
+        buffered: usize,
+    },
+    BatchStored {
+        size: usize,
+    },
+    BatchFailed {
+        size: usize,
+        error: String,
+    },
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct IngestSummary {
+    pub ingested: usize,
+    /// Documents already stored with the same content, not embedded again.
+    pub unchanged: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    /// Ids of the documents that failed to embed or store, to add again.
+    /// Documents that could not be read have none.
+    pub failed_ids: Vec<String>,
+}
+
+impl IngestSummary {
+    pub fn total(&self) -> usize {
+        self.ingested + self.unchanged + self.skipped + self.failed
+    }
+}
+
+impl std::ops::AddAssign for IngestSummary {
+    fn add_assign(&mut self, other: Self) {
+        self.ingested += other.ingested;
+        self.unchanged += other.unchanged;
+        self.skipped += other.skipped;
+        self.failed += other.failed;
+        self.failed_ids.extend(other.failed_ids);
+    }
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Adds `documents` in batches as [KnowledgeBase::add_documents_stream]
+    /// does: batches stored before one fails are kept, and the summary lists
+    /// the documents to add again.
+    pub async fn add_documents_in_batches(
+        &self,
+        documents: Vec<Document>,
+        options: IngestOptions,
+    ) -> IngestSummary {
+        self.add_documents_stream(
+            futures::stream::iter(documents.into_iter().map(Ok)),
+            options,
+        )
+        .await
+    }
+
+    /// Ingests documents from a stream with bounded buffering. Each batch is
+    /// embedded, then written in its own transaction, so batches stored
+    /// before a failure or cancellation are kept. Cancelling drops the up to
+    /// `concurrency` batches still being embedded: none of their documents
+    /// are stored, so add them again, e.g. by not marking a repository sync
+    /// done until the stream finishes.
+    pub async fn add_documents_stream<S>(
+        &self,
+        documents: S,
+        options: IngestOptions,
+    ) -> IngestSummary
+    where
+        S: Stream<Item = std::io::Result<Document>> + Send,
+    {
+        info!(
+            batch_size = options.batch_size,
+            concurrency = options.concurrency,
+            "Streaming documents into KnowledgeBase"
+        );
+
+        let buffered = Arc::new(AtomicUsize::new(0));
+        let mut summary = IngestSummary::default();
+
+        let batches = documents
+            .chunks(options.batch_size.max(1))
+            .map(|batch| {
+                let held = buffered.fetch_add(batch.len(), Ordering::SeqCst) + batch.len();
+                options.emit(IngestEvent::BatchQueued {
+                    size: batch.len(),
+                    buffered: held,
+                });
+
+                let buffered = buffered.clone();
+                let options = &options;
+                async move {
+                    let outcome = self.ingest_batch(batch, options).await;
+                    buffered.fetch_sub(outcome.total(), Ordering::SeqCst);
+                    outcome
+                }
+            })
+            .buffer_unordered(options.concurrency.max(1));
+        futures::pin_mut!(batches);
+
+        while let Some(outcome) = batches.next().await {
+            summary += outcome;
+        }
+
+        info!(
+            ingested = summary.ingested,
+            unchanged = summary.unchanged,
+            skipped = summary.skipped,
+            failed = summary.failed,
+            "Finished streaming documents"
+        );
+        self.ingest_complete();
+        summary
+    }
+
+    async fn ingest_batch(
+        &self,
+        batch: Vec<std::io::Result<Document>>,
+        options: &IngestOptions,
+    ) -> IngestSummary {
+        let mut summary = IngestSummary::default();
+
+        let documents = batch
+            .into_iter()
+            .filter_map(|document| match document {
+                Ok(document) if document.content.trim().is_empty() => {
+                    debug!(id = document.id, "Skipping empty document");
+                    summary.skipped += 1;
+                    None
+                }
+                Ok(document) => Some(document),
+                Err(err) => {
+                    warn!(?err, "Failed to read document");
+                    summary.failed += 1;
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+
+        if documents.is_empty() {
+            return summary;
+        }
+
+        let size = documents.len();
+        let max_retries = self.embedding_service().config().max_retries;
+        let mut attempt = 0;
+        let result = loop {
+            match self.embed_and_store(documents.clone()).await {
+                Err(err) if attempt < max_retries => match retry_in(&err) {
+                    Some(wait) => {
+                        warn!(%err, size, attempt, ?wait, "Retrying document batch");
+                        tokio::time::sleep(wait).await;
+                        attempt += 1;
+                    }
+                    None => break Err(err),
+                },
+                result => break result,
+            }
+        };
+
+        match result {
+            Ok(stored) => {
+                summary.ingested += stored.inserted + stored.updated;
+                summary.unchanged += stored.skipped;
+                options.emit(IngestEvent::BatchStored { size });
+            }
+            Err(err) => {
+                error!(?err, size, "Failed to ingest document batch");
+                summary.failed += size;
+                summary
+                    .failed_ids
+                    .extend(documents.into_iter().map(|document| document.id));
+                options.emit(IngestEvent::BatchFailed {
+                    size,
+                    error: err.to_string(),
+                });
+            }
+        }
+
+        summary
+    }
+}
+
+/// How long to wait before trying a failed batch again: until the open
+/// embedding circuit lets calls through. Other failures were already
+/// retried by the service, so `None`.
+fn retry_in(err: &anyhow::Error) -> Option<Duration> {
+    err.downcast_ref::<EmbeddingError>()
+        .and_then(circuit_open)
+        .map(|open| open.retry_in)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        knowledge::{EmbeddingService, EmbeddingServiceConfig},
+        loaders::files::stream_documents,
+        test_utils::{self, FlakyEmbeddingModel},
+    };
+    use std::sync::Mutex;
+
+    fn pages(n: usize) -> Vec<Document> {
+        (0..n)
+            .map(|i| test_utils::document(&format!("page-{i}.md"), &format!("page {i} body")))
+            .collect()
+    }
+
+    fn sequential() -> IngestOptions {
+        IngestOptions {
+            batch_size: 2,
+            concurrency: 1,
+            ..Default::default()
+        }
+    }
+
+    fn service(model: FlakyEmbeddingModel) -> EmbeddingService<FlakyEmbeddingModel> {
+        EmbeddingService::new(model).with_config(EmbeddingServiceConfig {
+            max_retries: 2,
+            initial_backoff: Duration::from_secs(2),
+            failure_threshold: 1,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limited_batches_are_retried() {
+        let knowledge =
+            test_utils::knowledge_base_with_service(service(FlakyEmbeddingModel::rate_limited(2)))
+                .await;
+        let start = tokio::time::Instant::now();
+
+        let summary = knowledge
+            .add_documents_in_batches(pages(5), sequential())
+            .await;
+
+        assert_eq!(summary.ingested, 5);
+        assert!(summary.failed_ids.is_empty());
+        // The service backed off 2s, then 4s, and the batch never failed
+        assert!(start.elapsed() >= Duration::from_secs(6));
+        let metrics = knowledge.embedding_service().metrics();
+        assert_eq!((metrics.rate_limited(), metrics.retries()), (2, 2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_batches_wait_for_the_open_circuit() {
+        // The first batch exhausts the service's retries and opens the circuit
+        let knowledge =
+            test_utils::knowledge_base_with_service(service(FlakyEmbeddingModel::failing(3))).await;
+        let start = tokio::time::Instant::now();
+
+        let summary = knowledge
+            .add_documents_in_batches(pages(5), sequential())
+            .await;
+
+        assert_eq!((summary.ingested, summary.failed), (3, 2));
+        // The next batch waited out the cooldown instead of failing
+        assert!(start.elapsed() >= Duration::from_secs(60));
+        let metrics = knowledge.embedding_service().metrics();
+        assert_eq!((metrics.calls(), metrics.rejected()), (3, 1));
+    }
+
+    #[tokio::test]
+    async fn test_failed_batches_are_listed_for_another_run() {
+        let model = FlakyEmbeddingModel::failing(0);
+        let knowledge = test_utils::knowledge_base_with(model.clone()).await;
+        let documents = pages(5);
+
+        // No retries configured, and the circuit stays closed
+        model.fail_next(1);
+        let summary = knowledge
+            .add_documents_in_batches(documents.clone(), sequential())
+            .await;
+        assert_eq!((summary.ingested, summary.failed), (3, 2));
+        assert_eq!(
+            summary.failed_ids,
+            ["/tmp/docs/page-0.md", "/tmp/docs/page-1.md"]
+        );
+
+        let retry = documents
+            .into_iter()
+            .filter(|document| summary.failed_ids.contains(&document.id))
+            .collect();
+        let summary = knowledge
+            .add_documents_in_batches(retry, sequential())
+            .await;
+        assert_eq!((summary.ingested, summary.failed), (2, 0));
+        let stored = knowledge
+            .document_counts()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(_, count)| count)
+            .sum::<usize>();
+        assert_eq!(stored, 5);
+    }
+
+    #[tokio::test]
+    async fn test_stream_ingestion_is_bounded() {
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..2000 {
+            let sub = dir.path().join(format!("section-{}", i % 10));
+            std::fs::create_dir_all(&sub).unwrap();
+            std::fs::write(sub.join(format!("page-{i}.md")), format!("page {i} body")).unwrap();
+        }
+        std::fs::write(dir.path().join("empty.md"), "   ").unwrap();
+
+        let knowledge = test_utils::knowledge_base().await;
+        let peak = Arc::new(Mutex::new(0));
+        let stored = Arc::new(AtomicUsize::new(0));
+
+        let options = IngestOptions {
+            batch_size: 16,
+            concurrency: 3,
+            ..Default::default()
+        }
+        .with_hook({
+            let peak = peak.clone();
+            let stored = stored.clone();
+            move |event| match event {
+                IngestEvent::BatchQueued { buffered, .. } => {
+                    let mut peak = peak.lock().unwrap();
+                    *peak = (*peak).max(*buffered);
+                }
+                IngestEvent::BatchStored { size } => {
+                    stored.fetch_add(*size, Ordering::SeqCst);
+                }
+                IngestEvent::BatchFailed { .. } => {}
+            }
+        });
+
+        let summary = knowledge
+            .add_documents_stream(
+                stream_documents(dir.path().to_path_buf(), "test".to_string()),
+                options,
+            )
+            .await;
+
+        assert_eq!(
+            summary,
+            IngestSummary {
+                ingested: 2000,
+                unchanged: 0,
+                skipped: 1,
+                failed: 0,
+                failed_ids: Vec::new(),
+            }
+        );
+        assert_eq!(stored.load(Ordering::SeqCst), 2000);
+        assert!(*peak.lock().unwrap() <= 16 * 3);
+
+        let count: i64 = knowledge
+            .conn
+            .call(
+                |conn| Ok(conn.query_row("SELECT COUNT(*) FROM documents", [], |row| row.get(0))?),
+            )
+            .await
+            .unwrap();
+        assert_eq!(count, 2000);
+    }
+}
@@ -0,0 +1,405 @@
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+use rusqlite::OptionalExtension;
+
+use super::{gaps::RetrievalSupport, models::Message, store::KnowledgeBase};
+use crate::{
+    confidence::{Confidence, ConfidenceOutcome},
+    rewrite::RetrievalQuery,
+};
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS interactions (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        channel_id TEXT NOT NULL,
+        account_id TEXT NOT NULL,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE INDEX IF NOT EXISTS idx_interactions_channel ON interactions(channel_id);
+
+    CREATE TABLE IF NOT EXISTS interaction_messages (
+        interaction_id INTEGER NOT NULL REFERENCES interactions(id) ON DELETE CASCADE,
+        message_id TEXT NOT NULL,
+        PRIMARY KEY (interaction_id, message_id)
+    );
+    CREATE INDEX IF NOT EXISTS idx_interaction_messages_message ON interaction_messages(message_id);
+
+    CREATE TABLE IF NOT EXISTS interaction_confidence (
+        interaction_id INTEGER PRIMARY KEY REFERENCES interactions(id) ON DELETE CASCADE,
+        score REAL NOT NULL,
+        best_distance REAL,
+        supporting INTEGER NOT NULL,
+        self_assessed REAL,
+        outcome TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS interaction_queries (
+        interaction_id INTEGER PRIMARY KEY REFERENCES interactions(id) ON DELETE CASCADE,
+        original TEXT NOT NULL,
+        rewritten TEXT
+    );
+
+    CREATE TABLE IF NOT EXISTS interaction_truncations (
+        interaction_id INTEGER NOT NULL REFERENCES interactions(id) ON DELETE CASCADE,
+        document_id TEXT NOT NULL,
+        length INTEGER NOT NULL,
+        kept_start INTEGER NOT NULL,
+        kept_end INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS idx_interaction_truncations_interaction
+        ON interaction_truncations(interaction_id);
+
+    CREATE TABLE IF NOT EXISTS interaction_research_steps (
+        interaction_id INTEGER NOT NULL REFERENCES interactions(id) ON DELETE CASCADE,
+        kind TEXT NOT NULL,
+        content TEXT NOT NULL,
+        tokens INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS idx_interaction_research_steps_interaction
+        ON interaction_research_steps(interaction_id);
+";
+
+/// A retrieved document cut to fit the prompt of an interaction, see
+/// [crate::truncation].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Truncation {
+    pub document_id: String,
+    /// Bytes of the document's content.
+    pub length: usize,
+    /// Byte range of the content sent. Empty when the document was dropped
+    /// for lack of room.
+    pub kept_start: usize,
+    pub kept_end: usize,
+}
+
+/// One step of a deep research answer, see [crate::research].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResearchStep {
+    /// What the step did, one of the [crate::research] step kinds.
+    pub kind: String,
+    /// The step's input or output, e.g. the sub-questions of a plan.
+    pub content: String,
+    /// Estimated tokens the step's model call spent, zero without one.
+    pub tokens: usize,
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Groups already stored messages into one interaction, i.e. one unit the
+    /// agent evaluates and answers, and returns its id.
+    pub async fn create_interaction(
+        &self,
+        channel_id: String,
+        account_id: String,
+        message_ids: Vec<String>,
+    ) -> Result<i64, SqliteError> {
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let id: i64 = tx.query_row(
+                    "INSERT INTO interactions (channel_id, account_id, agent_id) VALUES (?1, ?2, ?3) RETURNING id",
+                    rusqlite::params![channel_id, account_id, namespace],
+                    |row| row.get(0),
+                )?;
+                for message_id in &message_ids {
+                    tx.execute(
+                        "INSERT OR IGNORE INTO interaction_messages (interaction_id, message_id)
+                         VALUES (?1, ?2)",
+                        rusqlite::params![id, message_id],
+                    )?;
+                }
+                tx.commit()?;
+                Ok(id)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    pub async fn interaction_messages(
+        &self,
+        interaction_id: i64,
+    ) -> Result<Vec<String>, SqliteError> {
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT m.message_id FROM interaction_messages m
+                     JOIN interactions i ON i.id = m.interaction_id
+                     WHERE m.interaction_id = ?1 AND i.agent_id = ?2
+                     ORDER BY m.rowid",
+                )?;
+                let ids = stmt
+                    .query_map(rusqlite::params![interaction_id, namespace], |row| {
+                        row.get(0)
+                    })?
+                    .collect::<Result<Vec<String>, _>>()?;
+                Ok(ids)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// The stored reply to an interaction, the latest one if its messages
+    /// were answered more than once.
+    pub async fn interaction_reply(
+        &self,
+        interaction_id: i64,
+    ) -> Result<Option<Message>, SqliteError> {
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        &format!(
+                            "SELECT {} FROM messages
+                             WHERE agent_id = ?2 AND role = 'assistant' AND id IN (
+                                 SELECT message_id || ':reply' FROM interaction_messages
+                                 WHERE interaction_id = ?1
+                             )
+                             ORDER BY created_at DESC
+                             LIMIT 1",
+                            Message::COLUMNS
+                        ),
+                        rusqlite::params![interaction_id, namespace],
+                        |row| Message::try_from(row),
+                    )
+                    .optional()?)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Stores how confident the reply to an interaction was, replacing an
+    /// earlier estimate.
+    pub async fn record_confidence(
+        &self,
+        interaction_id: i64,
+        confidence: &Confidence,
+    ) -> Result<(), SqliteError> {
+        let confidence = confidence.clone();
+
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT OR REPLACE INTO interaction_confidence
+                         (interaction_id, score, best_distance, supporting, self_assessed, outcome)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    rusqlite::params![
+                        interaction_id,
+                        confidence.score,
+                        confidence.support.best_distance,
+                        confidence.support.supporting,
+                        confidence.self_assessed,
+                        confidence.outcome.as_str()
+                    ],
+                )?;
+                Ok(())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    pub async fn interaction_confidence(
+        &self,
+        interaction_id: i64,
+    ) -> Result<Option<Confidence>, SqliteError> {
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        "SELECT c.score, c.best_distance, c.supporting, c.self_assessed, c.outcome
+                         FROM interaction_confidence c
+                         JOIN interactions i ON i.id = c.interaction_id
+                         WHERE c.interaction_id = ?1 AND i.agent_id = ?2",
+                        rusqlite::params![interaction_id, namespace],
+                        |row| {
+                            Ok(Confidence {
+                                score: row.get(0)?,
+                                support: RetrievalSupport {
+                                    best_distance: row.get(1)?,
+                                    supporting: row.get(2)?,
+                                },
+                                self_assessed: row.get(3)?,
+                                outcome: ConfidenceOutcome::parse(&row.get::<_, String>(4)?)
+                                    .ok_or_else(|| {
+                                        rusqlite::Error::InvalidColumnType(
+                                            4,
+                                            "outcome".to_string(),
+                                            rusqlite::types::Type::Text,
+                                        )
+                                    })?,
+                            })
+                        },
+                    )
+                    .optional()?)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Stores the query documents were retrieved with for an interaction,
+    /// replacing an earlier one.
+    pub async fn record_query(
+        &self,
+        interaction_id: i64,
+        query: &RetrievalQuery,
+    ) -> Result<(), SqliteError> {
+        let query = query.clone();
+
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT OR REPLACE INTO interaction_queries (interaction_id, original, rewritten)
+                     VALUES (?1, ?2, ?3)",
+                    rusqlite::params![interaction_id, query.original, query.rewritten],
+                )?;
+                Ok(())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    pub async fn interaction_query(
+        &self,
+        interaction_id: i64,
+    ) -> Result<Option<RetrievalQuery>, SqliteError> {
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        "SELECT q.original, q.rewritten
+                         FROM interaction_queries q
+                         JOIN interactions i ON i.id = q.interaction_id
+                         WHERE q.interaction_id = ?1 AND i.agent_id = ?2",
+                        rusqlite::params![interaction_id, namespace],
+                        |row| {
+                            Ok(RetrievalQuery {
+                                original: row.get(0)?,
+                                rewritten: row.get(1)?,
+                            })
+                        },
+                    )
+                    .optional()?)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    pub async fn record_truncation(
+        &self,
+        interaction_id: i64,
+        truncation: &Truncation,
+    ) -> Result<(), SqliteError> {
+        let truncation = truncation.clone();
+
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT INTO interaction_truncations
+                         (interaction_id, document_id, length, kept_start, kept_end)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    rusqlite::params![
+                        interaction_id,
+                        truncation.document_id,
+                        truncation.length,
+                        truncation.kept_start,
+                        truncation.kept_end
+                    ],
+                )?;
+                Ok(())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Documents truncated in the prompt of an interaction, in the order
+    /// they were recorded.
+    pub async fn interaction_truncations(
+        &self,
+        interaction_id: i64,
+    ) -> Result<Vec<Truncation>, SqliteError> {
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT t.document_id, t.length, t.kept_start, t.kept_end
+                     FROM interaction_truncations t
+                     JOIN interactions i ON i.id = t.interaction_id
+                     WHERE t.interaction_id = ?1 AND i.agent_id = ?2
+                     ORDER BY t.rowid",
+                )?;
+                let truncations = stmt
+                    .query_map(rusqlite::params![interaction_id, namespace], |row| {
+                        Ok(Truncation {
+                            document_id: row.get(0)?,
+                            length: row.get(1)?,
+                            kept_start: row.get(2)?,
+                            kept_end: row.get(3)?,
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(truncations)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    pub async fn record_research_step(
+        &self,
+        interaction_id: i64,
+        step: &ResearchStep,
+    ) -> Result<(), SqliteError> {
+        let step = step.clone();
+
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT INTO interaction_research_steps (interaction_id, kind, content, tokens)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    rusqlite::params![interaction_id, step.kind, step.content, step.tokens],
+                )?;
+                Ok(())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Steps of a deep research answer, in the order they were recorded.
+    pub async fn interaction_research_steps(
+        &self,
+        interaction_id: i64,
+    ) -> Result<Vec<ResearchStep>, SqliteError> {
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT s.kind, s.content, s.tokens
+                     FROM interaction_research_steps s
+                     JOIN interactions i ON i.id = s.interaction_id
+                     WHERE s.interaction_id = ?1 AND i.agent_id = ?2
+                     ORDER BY s.rowid",
+                )?;
+                let steps = stmt
+                    .query_map(rusqlite::params![interaction_id, namespace], |row| {
+                        Ok(ResearchStep {
+                            kind: row.get(0)?,
+                            content: row.get(1)?,
+                            tokens: row.get(2)?,
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(steps)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+}
@@ -0,0 +1,257 @@
+//! The languages users and channels write in, and the model's translations
+//! of templates, see [crate::language].
+
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+use rusqlite::OptionalExtension;
+
+use super::store::KnowledgeBase;
+use crate::language::DECAY;
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS language_scores (
+        agent_id TEXT NOT NULL,
+        scope TEXT NOT NULL,
+        key TEXT NOT NULL,
+        language TEXT NOT NULL,
+        score REAL NOT NULL,
+        updated_at TEXT NOT NULL,
+        PRIMARY KEY (agent_id, scope, key, language)
+    );
+    CREATE TABLE IF NOT EXISTS template_translations (
+        agent_id TEXT NOT NULL,
+        template TEXT NOT NULL,
+        language TEXT NOT NULL,
+        source_hash TEXT NOT NULL,
+        text TEXT NOT NULL,
+        created_at TEXT NOT NULL,
+        PRIMARY KEY (agent_id, template, language)
+    );
+";
+
+const ACCOUNT_SCOPE: &str = "account";
+const CHANNEL_SCOPE: &str = "channel";
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Counts a message of `account_id` in `channel_id` as written in
+    /// `language`, after decaying the scores of earlier messages.
+    pub async fn record_language(
+        &self,
+        account_id: &str,
+        channel_id: &str,
+        language: &str,
+    ) -> Result<(), SqliteError> {
+        let namespace = self.namespace.clone();
+        let account_id = account_id.to_string();
+        let channel_id = channel_id.to_string();
+        let language = language.to_string();
+        let now = self.clock.now_utc().to_rfc3339();
+
+        self.conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                for (scope, key) in [(ACCOUNT_SCOPE, &account_id), (CHANNEL_SCOPE, &channel_id)] {
+                    tx.execute(
+                        "UPDATE language_scores SET score = score * ?1
+                         WHERE agent_id = ?2 AND scope = ?3 AND key = ?4",
+                        rusqlite::params![DECAY, namespace, scope, key],
+                    )?;
+                    tx.execute(
+                        "INSERT INTO language_scores
+                             (agent_id, scope, key, language, score, updated_at)
+                         VALUES (?1, ?2, ?3, ?4, 1.0, ?5)
+                         ON CONFLICT (agent_id, scope, key, language) DO UPDATE SET
+                             score = score + 1.0,
+                             updated_at = excluded.updated_at",
+                        rusqlite::params![namespace, scope, key, language, now],
+                    )?;
+                }
+                tx.commit()?;
+                Ok(())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// The language `account_id` has mostly written in lately.
+    pub async fn user_language(&self, account_id: &str) -> Result<Option<String>, SqliteError> {
+        self.dominant_language(ACCOUNT_SCOPE, account_id).await
+    }
+
+    /// The language mostly written in `channel_id` lately.
+    pub async fn channel_language(&self, channel_id: &str) -> Result<Option<String>, SqliteError> {
+        self.dominant_language(CHANNEL_SCOPE, channel_id).await
+    }
+
+    async fn dominant_language(
+        &self,
+        scope: &'static str,
+        key: &str,
+    ) -> Result<Option<String>, SqliteError> {
+        let namespace = self.namespace.clone();
+        let key = key.to_string();
+
+        self.conn
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        "SELECT language FROM language_scores
+                         WHERE agent_id = ?1 AND scope = ?2 AND key = ?3
+                         ORDER BY score DESC, updated_at DESC
+                         LIMIT 1",
+                        rusqlite::params![namespace, scope, key],
+                        |row| row.get(0),
+                    )
+                    .optional()?)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// The cached translation of `template` into `language`, if it was made
+    /// from the text hashing to `source_hash`.
+    pub async fn template_translation(
+        &self,
+        template: &str,
+        language: &str,
+        source_hash: &str,
+    ) -> Result<Option<String>, SqliteError> {
+        let namespace = self.namespace.clone();
+        let template = template.to_string();
+        let language = language.to_string();
+        let source_hash = source_hash.to_string();
+
+        self.conn
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        "SELECT text FROM template_translations
+                         WHERE agent_id = ?1 AND template = ?2 AND language = ?3
+                             AND source_hash = ?4",
+                        rusqlite::params![namespace, template, language, source_hash],
+                        |row| row.get(0),
+                    )
+                    .optional()?)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Caches `text` as the translation of `template` into `language`,
+    /// replacing one made from an earlier text.
+    pub async fn store_template_translation(
+        &self,
+        template: &str,
+        language: &str,
+        source_hash: &str,
+        text: &str,
+    ) -> Result<(), SqliteError> {
+        let namespace = self.namespace.clone();
+        let template = template.to_string();
+        let language = language.to_string();
+        let source_hash = source_hash.to_string();
+        let text = text.to_string();
+        let now = self.clock.now_utc().to_rfc3339();
+
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT INTO template_translations
+                         (agent_id, template, language, source_hash, text, created_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                     ON CONFLICT (agent_id, template, language) DO UPDATE SET
+                         source_hash = excluded.source_hash,
+                         text = excluded.text,
+                         created_at = excluded.created_at",
+                    rusqlite::params![namespace, template, language, source_hash, text, now],
+                )?;
+                Ok(())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn test_dominant_language_follows_recent_messages() {
+        let knowledge = test_utils::knowledge_base().await;
+        assert_eq!(knowledge.user_language("alice").await.unwrap(), None);
+
+        for _ in 0..5 {
+            knowledge
+                .record_language("alice", "c1", "en")
+                .await
+                .unwrap();
+        }
+        knowledge.record_language("bob", "c1", "es").await.unwrap();
+        assert_eq!(
+            knowledge.user_language("alice").await.unwrap().as_deref(),
+            Some("en")
+        );
+        assert_eq!(
+            knowledge.user_language("bob").await.unwrap().as_deref(),
+            Some("es")
+        );
+        // The channel's latest message doesn't outweigh the ones before
+        assert_eq!(
+            knowledge.channel_language("c1").await.unwrap().as_deref(),
+            Some("en")
+        );
+
+        // Alice switches to Spanish and is answered in it a few messages later
+        knowledge
+            .record_language("alice", "c2", "es")
+            .await
+            .unwrap();
+        assert_eq!(
+            knowledge.user_language("alice").await.unwrap().as_deref(),
+            Some("en")
+        );
+        for _ in 0..3 {
+            knowledge
+                .record_language("alice", "c2", "es")
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            knowledge.user_language("alice").await.unwrap().as_deref(),
+            Some("es")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_translation_is_invalidated_by_the_source() {
+        let knowledge = test_utils::knowledge_base().await;
+        knowledge
+            .store_template_translation("rate_limited", "es", "h1", "Espera.")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            knowledge
+                .template_translation("rate_limited", "es", "h1")
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("Espera.")
+        );
+        assert_eq!(
+            knowledge
+                .template_translation("rate_limited", "es", "h2")
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            knowledge
+                .template_translation("rate_limited", "fr", "h1")
+                .await
+                .unwrap(),
+            None
+        );
+    }
+}
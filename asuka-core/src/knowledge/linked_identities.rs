@@ -0,0 +1,474 @@
+//! Accounts of the same person on different platforms, see
+//! [crate::linking].
+//!
+//! Every account of a linked identity points at its canonical account, the
+//! one that requested the first link code. User facts are stored under the
+//! canonical account, so they are shared by all of its accounts, while
+//! interactions and rate limit events stay with the account they happened
+//! on and are counted together. An account belongs to one identity at most.
+//! Links are kept across namespaces, as they are about people rather than
+//! characters.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Duration, Utc};
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+use rusqlite::OptionalExtension;
+use thiserror::Error;
+
+use super::{store::KnowledgeBase, types::Source};
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS link_codes (
+        code TEXT PRIMARY KEY,
+        source TEXT NOT NULL,
+        account_id TEXT NOT NULL UNIQUE,
+        expires_at TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS linked_identities (
+        account_id TEXT PRIMARY KEY,
+        source TEXT NOT NULL,
+        canonical_account_id TEXT NOT NULL,
+        linked_at TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS idx_linked_identities_canonical
+        ON linked_identities(canonical_account_id);
+";
+
+/// Seconds a looked-up identity is reused, so links made by another process
+/// show up without a restart.
+const CACHE_SECS: i64 = 60;
+
+#[derive(Error, Debug)]
+pub enum LinkError {
+    #[error("That link code is unknown.")]
+    UnknownCode,
+    #[error("That link code expired, request a new one with /link.")]
+    Expired,
+    #[error("Redeem the code from your other account.")]
+    SameAccount,
+    #[error("This account is already linked, /unlink it first.")]
+    AlreadyLinked,
+    #[error("Failed to link the accounts: {0}")]
+    Storage(#[from] SqliteError),
+}
+
+/// The accounts of one person.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkedIdentity {
+    pub canonical_account_id: String,
+    /// Every account of the identity, the canonical one included, oldest
+    /// link first.
+    pub accounts: Vec<(Source, String)>,
+}
+
+/// Identities looked up recently, by account. Clones share it, and links
+/// made through any of them clear it.
+#[derive(Clone, Default)]
+pub(super) struct IdentityCache {
+    entries: Arc<Mutex<HashMap<String, (DateTime<Utc>, Option<LinkedIdentity>)>>>,
+}
+
+impl IdentityCache {
+    fn get(&self, account_id: &str, now: DateTime<Utc>) -> Option<Option<LinkedIdentity>> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (at, _)| now - *at < Duration::seconds(CACHE_SECS));
+        entries
+            .get(account_id)
+            .map(|(_, identity)| identity.clone())
+    }
+
+    fn insert(&self, account_id: &str, now: DateTime<Utc>, identity: Option<LinkedIdentity>) {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(account_id.to_string(), (now, identity));
+    }
+
+    fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+fn load_identity(
+    conn: &rusqlite::Connection,
+    account_id: &str,
+) -> rusqlite::Result<Option<LinkedIdentity>> {
+    let Some(canonical_account_id) = conn
+        .query_row(
+            "SELECT canonical_account_id FROM linked_identities WHERE account_id = ?1",
+            [account_id],
+            |row| row.get::<_, String>(0),
+        )
+        .optional()?
+    else {
+        return Ok(None);
+    };
+    let accounts = conn
+        .prepare(
+            "SELECT source, account_id FROM linked_identities
+             WHERE canonical_account_id = ?1
+             ORDER BY linked_at, rowid",
+        )?
+        .query_map([&canonical_account_id], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .filter_map(|(source, account_id)| Some((Source::from_str(&source)?, account_id)))
+        .collect();
+    Ok(Some(LinkedIdentity {
+        canonical_account_id,
+        accounts,
+    }))
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// A new code linking the account to another one that redeems it within
+    /// `ttl`, replacing a code the account requested before.
+    pub async fn create_link_code(
+        &self,
+        source: &Source,
+        account_id: &str,
+        ttl: Duration,
+    ) -> Result<String, SqliteError> {
+        let source = source.as_str().to_string();
+        let account_id = account_id.to_string();
+        let now = self.clock.now_utc();
+        let expires_at = (now + ttl).to_rfc3339();
+
+        self.conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                tx.execute(
+                    "DELETE FROM link_codes WHERE expires_at <= ?1",
+                    [now.to_rfc3339()],
+                )?;
+                let code = tx.query_row(
+                    "INSERT OR REPLACE INTO link_codes (code, source, account_id, expires_at)
+                     VALUES (upper(hex(randomblob(4))), ?1, ?2, ?3)
+                     RETURNING code",
+                    [&source, &account_id, &expires_at],
+                    |row| row.get(0),
+                )?;
+                tx.commit()?;
+                Ok(code)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Links the account redeeming `code` to the account that requested it,
+    /// and moves the redeeming account's user facts to the identity. Facts
+    /// the identity already has win.
+    pub async fn redeem_link_code(
+        &self,
+        code: &str,
+        source: &Source,
+        account_id: &str,
+    ) -> Result<LinkedIdentity, LinkError> {
+        let code = code.trim().to_uppercase();
+        let source = source.as_str().to_string();
+        let account_id = account_id.to_string();
+        let now = self.clock.now_utc();
+
+        let linked = self
+            .conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let requested = tx
+                    .query_row(
+                        "SELECT source, account_id, expires_at FROM link_codes WHERE code = ?1",
+                        [&code],
+                        |row| {
+                            Ok((
+                                row.get::<_, String>(0)?,
+                                row.get::<_, String>(1)?,
+                                row.get::<_, DateTime<Utc>>(2)?,
+                            ))
+                        },
+                    )
+                    .optional()?;
+                let Some((requester_source, requester, expires_at)) = requested else {
+                    return Ok(Err(LinkError::UnknownCode));
+                };
+                if requester == account_id {
+                    return Ok(Err(LinkError::SameAccount));
+                }
+                if expires_at <= now {
+                    tx.execute("DELETE FROM link_codes WHERE code = ?1", [&code])?;
+                    tx.commit()?;
+                    return Ok(Err(LinkError::Expired));
+                }
+                if load_identity(&tx, &account_id)?.is_some() {
+                    return Ok(Err(LinkError::AlreadyLinked));
+                }
+
+                let canonical = load_identity(&tx, &requester)?
+                    .map_or(requester.clone(), |identity| identity.canonical_account_id);
+                let linked_at = now.to_rfc3339();
+                tx.execute(
+                    "INSERT OR IGNORE INTO linked_identities
+                         (account_id, source, canonical_account_id, linked_at)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    [&requester, &requester_source, &canonical, &linked_at],
+                )?;
+                tx.execute(
+                    "INSERT INTO linked_identities
+                         (account_id, source, canonical_account_id, linked_at)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    [&account_id, &source, &canonical, &linked_at],
+                )?;
+                tx.execute(
+                    "UPDATE OR IGNORE user_facts SET account_id = ?2 WHERE account_id = ?1",
+                    [&account_id, &canonical],
+                )?;
+                tx.execute(
+                    "DELETE FROM user_facts WHERE account_id = ?1",
+                    [&account_id],
+                )?;
+                tx.execute("DELETE FROM link_codes WHERE code = ?1", [&code])?;
+                let identity = load_identity(&tx, &account_id)?;
+                tx.commit()?;
+                Ok(Ok(identity))
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))??;
+
+        self.identities.clear();
+        Ok(linked.expect("the account was just linked"))
+    }
+
+    /// Takes the account out of its identity. The identity keeps the user
+    /// facts; when the canonical account leaves, the oldest remaining account
+    /// becomes canonical and gets a copy of them. An identity left with a
+    /// single account is dissolved. `false` when the account wasn't linked.
+    pub async fn unlink_account(&self, account_id: &str) -> Result<bool, SqliteError> {
+        let account_id = account_id.to_string();
+
+        let unlinked = self
+            .conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let Some(identity) = load_identity(&tx, &account_id)? else {
+                    return Ok(false);
+                };
+                tx.execute(
+                    "DELETE FROM linked_identities WHERE account_id = ?1",
+                    [&account_id],
+                )?;
+                let mut canonical = identity.canonical_account_id;
+                if canonical == account_id {
+                    if let Some((_, successor)) = identity
+                        .accounts
+                        .iter()
+                        .find(|(_, account)| *account != account_id)
+                    {
+                        tx.execute(
+                            "INSERT OR IGNORE INTO user_facts
+                                 (agent_id, account_id, key, value, updated_at)
+                             SELECT agent_id, ?2, key, value, updated_at FROM user_facts
+                             WHERE account_id = ?1",
+                            [&canonical, successor],
+                        )?;
+                        tx.execute(
+                            "UPDATE linked_identities SET canonical_account_id = ?2
+                             WHERE canonical_account_id = ?1",
+                            [&canonical, successor],
+                        )?;
+                        canonical = successor.clone();
+                    }
+                }
+                tx.execute(
+                    "DELETE FROM linked_identities
+                     WHERE canonical_account_id = ?1
+                         AND (SELECT COUNT(*) FROM linked_identities
+                              WHERE canonical_account_id = ?1) = 1",
+                    [&canonical],
+                )?;
+                tx.commit()?;
+                Ok(true)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))?;
+
+        self.identities.clear();
+        Ok(unlinked)
+    }
+
+    /// The identity `account_id` belongs to, if it is linked. Cached for a
+    /// minute.
+    pub async fn linked_identity(
+        &self,
+        account_id: &str,
+    ) -> Result<Option<LinkedIdentity>, SqliteError> {
+        let now = self.clock.now_utc();
+        if let Some(identity) = self.identities.get(account_id, now) {
+            return Ok(identity);
+        }
+
+        let key = account_id.to_string();
+        let identity = self
+            .conn
+            .call(move |conn| Ok(load_identity(conn, &key)?))
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))?;
+        self.identities.insert(account_id, now, identity.clone());
+        Ok(identity)
+    }
+
+    /// The account that facts about `account_id` are stored under: its
+    /// identity's canonical account, or itself when it isn't linked.
+    pub async fn canonical_account_id(&self, account_id: &str) -> Result<String, SqliteError> {
+        Ok(self
+            .linked_identity(account_id)
+            .await?
+            .map_or(account_id.to_string(), |identity| {
+                identity.canonical_account_id
+            }))
+    }
+
+    /// Every account of the person behind `source` and `account_id`, itself
+    /// alone when it isn't linked.
+    pub async fn linked_accounts(
+        &self,
+        source: &Source,
+        account_id: &str,
+    ) -> Result<Vec<(Source, String)>, SqliteError> {
+        Ok(self
+            .linked_identity(account_id)
+            .await?
+            .map_or(vec![(source.clone(), account_id.to_string())], |identity| {
+                identity.accounts
+            }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::MockClock, test_utils};
+
+    #[tokio::test]
+    async fn test_link_redeem_unlink() {
+        let clock = MockClock::new(Utc::now());
+        let knowledge = test_utils::knowledge_base()
+            .await
+            .with_clock(Arc::new(clock.clone()));
+        knowledge
+            .set_user_fact("d-alice", "language", "French")
+            .await
+            .unwrap();
+        knowledge
+            .set_user_fact("t-alice", "language", "German")
+            .await
+            .unwrap();
+        knowledge
+            .set_user_fact("t-alice", "timezone", "CET")
+            .await
+            .unwrap();
+
+        let code = knowledge
+            .create_link_code(&Source::Discord, "d-alice", Duration::minutes(10))
+            .await
+            .unwrap();
+        assert_eq!(code.len(), 8);
+        assert!(matches!(
+            knowledge
+                .redeem_link_code(&code, &Source::Discord, "d-alice")
+                .await,
+            Err(LinkError::SameAccount)
+        ));
+        assert!(matches!(
+            knowledge
+                .redeem_link_code("FFFFFFFF", &Source::Telegram, "t-alice")
+                .await,
+            Err(LinkError::UnknownCode)
+        ));
+
+        // Cached as unlinked until the link clears the cache
+        assert_eq!(
+            knowledge.canonical_account_id("t-alice").await.unwrap(),
+            "t-alice"
+        );
+        let identity = knowledge
+            .redeem_link_code(&code.to_lowercase(), &Source::Telegram, "t-alice")
+            .await
+            .unwrap();
+        assert_eq!(identity.canonical_account_id, "d-alice");
+        assert_eq!(
+            identity.accounts,
+            [
+                (Source::Discord, "d-alice".to_string()),
+                (Source::Telegram, "t-alice".to_string())
+            ]
+        );
+        assert_eq!(
+            knowledge.canonical_account_id("t-alice").await.unwrap(),
+            "d-alice"
+        );
+
+        // Facts are shared, the identity's winning over the redeemer's
+        let facts = [
+            ("language".to_string(), "French".to_string()),
+            ("timezone".to_string(), "CET".to_string()),
+        ];
+        assert_eq!(knowledge.user_facts("t-alice").await.unwrap(), facts);
+        assert_eq!(knowledge.user_facts("d-alice").await.unwrap(), facts);
+        knowledge
+            .set_user_fact("t-alice", "language", "Spanish")
+            .await
+            .unwrap();
+        assert_eq!(
+            knowledge.user_facts("d-alice").await.unwrap()[0].1,
+            "Spanish"
+        );
+
+        // Codes are used once, and an account joins one identity only
+        assert!(matches!(
+            knowledge
+                .redeem_link_code(&code, &Source::Telegram, "t-bob")
+                .await,
+            Err(LinkError::UnknownCode)
+        ));
+        let code = knowledge
+            .create_link_code(&Source::Discord, "d-bob", Duration::minutes(10))
+            .await
+            .unwrap();
+        assert!(matches!(
+            knowledge
+                .redeem_link_code(&code, &Source::Telegram, "t-alice")
+                .await,
+            Err(LinkError::AlreadyLinked)
+        ));
+
+        // Expired codes are refused
+        clock.advance(Duration::minutes(11));
+        assert!(matches!(
+            knowledge
+                .redeem_link_code(&code, &Source::Telegram, "t-bob")
+                .await,
+            Err(LinkError::Expired)
+        ));
+
+        // The canonical account leaves: the other one keeps the facts
+        assert!(knowledge.unlink_account("d-alice").await.unwrap());
+        assert!(!knowledge.unlink_account("d-alice").await.unwrap());
+        assert_eq!(knowledge.linked_identity("t-alice").await.unwrap(), None);
+        assert_eq!(
+            knowledge.user_facts("t-alice").await.unwrap()[0].1,
+            "Spanish"
+        );
+        assert_eq!(
+            knowledge
+                .linked_accounts(&Source::Telegram, "t-alice")
+                .await
+                .unwrap(),
+            [(Source::Telegram, "t-alice".to_string())]
+        );
+    }
+}
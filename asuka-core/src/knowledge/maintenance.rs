@@ -0,0 +1,502 @@
+//! Upkeep of the vector tables. Crashed ingestions and direct deletes leave
+//! embeddings whose document or message is gone, rows without an embedding,
+//! and free pages in the database file.
+//!
+//! [KnowledgeBase::maintenance] fixes these while the bot keeps running:
+//! orphans are deleted in small transactions of
+//! [MaintenanceOptions::chunk_size] rows, so writers are never held up for
+//! long. It covers the whole store, not only this knowledge base's namespace.
+
+use std::{collections::HashMap, fmt};
+
+use rig::embeddings::{Embed, EmbeddingModel, EmbeddingsBuilder};
+use rig_sqlite::{SqliteError, SqliteVectorStoreTable};
+use tracing::info;
+
+use super::{
+    cleaning,
+    models::{Document, Message},
+    store::KnowledgeBase,
+};
+
+/// How free pages are returned to the file system.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VacuumMode {
+    None,
+    /// `PRAGMA incremental_vacuum`, which only frees pages on databases with
+    /// `auto_vacuum = INCREMENTAL`.
+    #[default]
+    Incremental,
+    /// `VACUUM`, which rewrites the whole file and blocks writers meanwhile.
+    Full,
+}
+
+#[derive(Clone, Debug)]
+pub struct MaintenanceOptions {
+    /// Only count what would be fixed.
+    pub dry_run: bool,
+    /// Embed the rows found without an embedding.
+    pub reembed_missing: bool,
+    pub vacuum: VacuumMode,
+    /// Rows deleted or embedded per transaction.
+    pub chunk_size: usize,
+}
+
+impl Default for MaintenanceOptions {
+    fn default() -> Self {
+        Self {
+            dry_run: false,
+            reembed_missing: false,
+            vacuum: VacuumMode::default(),
+            chunk_size: 500,
+        }
+    }
+}
+
+/// Findings for one vector table.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TableReport {
+    pub table: &'static str,
+    /// Embeddings without a parent row, deleted unless dry running.
+    pub orphaned_embeddings: usize,
+    /// Parent rows without an embedding, besides messages the embedding
+    /// policy skipped, see [super::embedding_policy].
+    pub missing_embeddings: usize,
+    pub reembedded: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MaintenanceReport {
+    pub dry_run: bool,
+    pub tables: Vec<TableReport>,
+    /// Full text indexes rebuilt.
+    pub fts_rebuilt: Vec<String>,
+    /// Size of the free pages, which vacuuming can return.
+    pub free_bytes: u64,
+    /// Shrinkage of the database file.
+    pub bytes_reclaimed: u64,
+}
+
+impl fmt::Display for MaintenanceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.dry_run {
+            writeln!(f, "Dry run, nothing was changed.")?;
+        }
+        for table in &self.tables {
+            writeln!(
+                f,
+                "{}: {} orphaned embeddings, {} rows without embedding, {} re-embedded",
+                table.table, table.orphaned_embeddings, table.missing_embeddings, table.reembedded
+            )?;
+        }
+        if !self.fts_rebuilt.is_empty() {
+            writeln!(
+                f,
+                "Rebuilt full text indexes: {}",
+                self.fts_rebuilt.join(", ")
+            )?;
+        }
+        write!(
+            f,
+            "{} bytes free, {} bytes reclaimed",
+            self.free_bytes, self.bytes_reclaimed
+        )
+    }
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Deletes orphaned embeddings, optionally embeds rows missing one,
+    /// rebuilds full text indexes, vacuums and analyzes the database.
+    pub async fn maintenance(
+        &self,
+        options: &MaintenanceOptions,
+    ) -> Result<MaintenanceReport, SqliteError> {
+        let size_before = self.database_size().await?;
+        let mut report = MaintenanceReport {
+            dry_run: options.dry_run,
+            free_bytes: size_before.free,
+            ..Default::default()
+        };
+
+        for table in [Document::name(), Message::name()] {
+            let orphaned_embeddings = if options.dry_run {
+                self.count_orphaned_embeddings(table).await?
+            } else {
+                self.delete_orphaned_embeddings(table, options.chunk_size)
+                    .await?
+            };
+            let missing = self.rows_missing_embeddings(table).await?;
+            let reembedded = if !options.reembed_missing || options.dry_run {
+                0
+            } else if table == Document::name() {
+                self.reembed::<Document>(&missing, options.chunk_size, cleaning::prepare)
+                    .await?
+            } else {
+                self.reembed::<Message>(&missing, options.chunk_size, |messages| messages)
+                    .await?
+            };
+            report.tables.push(TableReport {
+                table,
+                orphaned_embeddings,
+                missing_embeddings: missing.len(),
+                reembedded,
+            });
+        }
+
+        if options.dry_run {
+            return Ok(report);
+        }
+
+        let vacuum = options.vacuum;
+        report.fts_rebuilt = self
+            .conn
+            .call(move |conn| {
+                let fts_tables = conn
+                    .prepare(
+                        "SELECT name FROM sqlite_master
+                         WHERE type = 'table' AND sql LIKE 'CREATE VIRTUAL TABLE%USING fts%'",
+                    )?
+                    .query_map([], |row| row.get::<_, String>(0))?
+                    .collect::<Result<Vec<_>, _>>()?;
+                for table in &fts_tables {
+                    conn.execute(
+                        &format!("INSERT INTO {table}({table}) VALUES ('rebuild')"),
+                        [],
+                    )?;
+                }
+
+                match vacuum {
+                    VacuumMode::None => {}
+                    VacuumMode::Incremental => conn.execute_batch("PRAGMA incremental_vacuum;")?,
+                    VacuumMode::Full => conn.execute_batch("VACUUM;")?,
+                }
+                conn.execute_batch("ANALYZE;")?;
+                Ok(fts_tables)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))?;
+
+        let size_after = self.database_size().await?;
+        report.bytes_reclaimed = size_before.total.saturating_sub(size_after.total);
+
+        info!(?report, "Finished store maintenance");
+        Ok(report)
+    }
+
+    async fn database_size(&self) -> Result<DatabaseSize, SqliteError> {
+        self.conn
+            .call(|conn| {
+                let pragma = |name: &str| {
+                    conn.query_row(&format!("PRAGMA {name}"), [], |row| row.get::<_, u64>(0))
+                };
+                let page_size = pragma("page_size")?;
+                Ok(DatabaseSize {
+                    total: pragma("page_count")? * page_size,
+                    free: pragma("freelist_count")? * page_size,
+                })
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    async fn count_orphaned_embeddings(&self, table: &'static str) -> Result<usize, SqliteError> {
+        self.conn
+            .call(move |conn| {
+                Ok(conn.query_row(
+                    &format!(
+                        "SELECT COUNT(*) FROM {table}_embeddings
+                         WHERE rowid NOT IN (SELECT rowid FROM {table})"
+                    ),
+                    [],
+                    |row| row.get(0),
+                )?)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Deletes at most `chunk_size` orphans per transaction until none are
+    /// left, returning how many were deleted.
+    async fn delete_orphaned_embeddings(
+        &self,
+        table: &'static str,
+        chunk_size: usize,
+    ) -> Result<usize, SqliteError> {
+        let chunk_size = chunk_size.max(1);
+        let mut deleted = 0;
+        loop {
+            let chunk = self
+                .conn
+                .call(move |conn| {
+                    let tx = conn.transaction()?;
+                    let rowids = tx
+                        .prepare(&format!(
+                            "SELECT rowid FROM {table}_embeddings
+                             WHERE rowid NOT IN (SELECT rowid FROM {table})
+                             LIMIT ?1"
+                        ))?
+                        .query_map([chunk_size], |row| row.get::<_, i64>(0))?
+                        .collect::<Result<Vec<_>, _>>()?;
+                    for rowid in &rowids {
+                        tx.execute(
+                            &format!("DELETE FROM {table}_embeddings WHERE rowid = ?1"),
+                            [rowid],
+                        )?;
+                    }
+                    tx.commit()?;
+                    Ok(rowids.len())
+                })
+                .await
+                .map_err(|e| SqliteError::DatabaseError(Box::new(e)))?;
+
+            deleted += chunk;
+            if chunk < chunk_size {
+                return Ok(deleted);
+            }
+        }
+    }
+
+    async fn rows_missing_embeddings(&self, table: &'static str) -> Result<Vec<i64>, SqliteError> {
+        let skipped = match table == Message::name() {
+            true => "AND embedding_skipped IS NULL",
+            false => "",
+        };
+        self.conn
+            .call(move |conn| {
+                let rowids = conn
+                    .prepare(&format!(
+                        "SELECT rowid FROM {table}
+                         WHERE rowid NOT IN (SELECT rowid FROM {table}_embeddings) {skipped}
+                         ORDER BY rowid"
+                    ))?
+                    .query_map([], |row| row.get::<_, i64>(0))?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(rowids)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Embeds the rows at `rowids` the way they were embedded when stored,
+    /// `prepare` standing in for what happens before embedding. Existing
+    /// embeddings are replaced in the same transaction, so a row is never
+    /// left without one.
+    pub(super) async fn reembed<T>(
+        &self,
+        rowids: &[i64],
+        chunk_size: usize,
+        prepare: fn(Vec<T>) -> Vec<T>,
+    ) -> Result<usize, SqliteError>
+    where
+        T: SqliteVectorStoreTable + Embed + Clone + Send + 'static,
+        for<'a, 'b> T: TryFrom<&'a rusqlite::Row<'b>, Error = rusqlite::Error>,
+    {
+        let table = T::name();
+        let mut reembedded = 0;
+        for chunk in rowids.chunks(chunk_size.max(1)) {
+            let chunk = chunk.to_vec();
+            let rows = self
+                .conn
+                .call(move |conn| {
+                    let mut stmt =
+                        conn.prepare(&format!("SELECT rowid, * FROM {table} WHERE rowid = ?1"))?;
+                    let rows = chunk
+                        .iter()
+                        .map(|rowid| {
+                            stmt.query_row([rowid], |row| Ok((T::try_from(row)?, row.get(0)?)))
+                        })
+                        .collect::<Result<Vec<(T, i64)>, _>>()?;
+                    Ok(rows)
+                })
+                .await
+                .map_err(|e| SqliteError::DatabaseError(Box::new(e)))?;
+
+            let rowid_by_id: HashMap<String, i64> =
+                rows.iter().map(|(row, rowid)| (row.id(), *rowid)).collect();
+            let rows = prepare(rows.into_iter().map(|(row, _)| row).collect());
+            let embeddings = EmbeddingsBuilder::new(self.embedding_model.clone())
+                .documents(rows)
+                .map_err(|e| SqliteError::DatabaseError(Box::new(e)))?
+                .build()
+                .await
+                .map_err(|e| SqliteError::DatabaseError(Box::new(e)))?
+                .into_iter()
+                .map(|(row, embeddings)| {
+                    let blob = embeddings
+                        .first()
+                        .vec
+                        .iter()
+                        .flat_map(|x| (*x as f32).to_le_bytes())
+                        .collect::<Vec<u8>>();
+                    (rowid_by_id[&row.id()], blob)
+                })
+                .collect::<Vec<_>>();
+
+            reembedded += self
+                .conn
+                .call(move |conn| {
+                    let tx = conn.transaction()?;
+                    for (rowid, blob) in &embeddings {
+                        tx.execute(
+                            &format!("DELETE FROM {table}_embeddings WHERE rowid = ?1"),
+                            [rowid],
+                        )?;
+                        tx.execute(
+                            &format!(
+                                "INSERT INTO {table}_embeddings (rowid, embedding) VALUES (?1, ?2)"
+                            ),
+                            rusqlite::params![rowid, blob],
+                        )?;
+                    }
+                    tx.commit()?;
+                    Ok(embeddings.len())
+                })
+                .await
+                .map_err(|e| SqliteError::DatabaseError(Box::new(e)))?;
+        }
+        Ok(reembedded)
+    }
+}
+
+struct DatabaseSize {
+    total: u64,
+    free: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+    use rig::vector_store::VectorStoreIndex;
+
+    fn doc(id: &str, content: &str) -> Document {
+        Document {
+            id: id.to_string(),
+            source_id: "github".to_string(),
+            content: content.to_string(),
+            created_at: chrono::Utc::now(),
+            title: String::new(),
+            section: String::new(),
+            topics: vec![],
+            logical_id: None,
+            cleaned: None,
+            chunk: None,
+        }
+    }
+
+    /// Knowledge base with two orphaned document embeddings and one document
+    /// without an embedding.
+    async fn inconsistent() -> KnowledgeBase<test_utils::FakeEmbeddingModel> {
+        let mut knowledge = test_utils::knowledge_base().await;
+        knowledge
+            .add_documents(vec![
+                doc("vrf.md", "vrf requests are free"),
+                doc("katana.md", "katana devnet flags"),
+                doc("paymaster.md", "paymaster sponsors fees"),
+                doc("slot.md", "slot deploys katana"),
+            ])
+            .await
+            .unwrap();
+        knowledge
+            .conn
+            .call(|conn| {
+                conn.execute_batch(
+                    "DELETE FROM documents WHERE id IN ('vrf.md', 'katana.md');
+                     DELETE FROM documents_embeddings
+                     WHERE rowid = (SELECT rowid FROM documents WHERE id = 'slot.md');",
+                )?;
+                Ok(())
+            })
+            .await
+            .unwrap();
+        knowledge
+    }
+
+    async fn count(knowledge: &KnowledgeBase<test_utils::FakeEmbeddingModel>, sql: &str) -> usize {
+        let sql = sql.to_string();
+        knowledge
+            .conn
+            .call(move |conn| Ok(conn.query_row(&sql, [], |row| row.get(0))?))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_only_reports() {
+        let knowledge = inconsistent().await;
+        let options = MaintenanceOptions {
+            dry_run: true,
+            reembed_missing: true,
+            ..Default::default()
+        };
+
+        let report = knowledge.maintenance(&options).await.unwrap();
+        assert!(report.dry_run);
+        assert_eq!(
+            report.tables[0],
+            TableReport {
+                table: "documents",
+                orphaned_embeddings: 2,
+                missing_embeddings: 1,
+                reembedded: 0,
+            }
+        );
+        assert_eq!(report.tables[1].orphaned_embeddings, 0);
+        assert_eq!(report.bytes_reclaimed, 0);
+        assert_eq!(
+            count(&knowledge, "SELECT COUNT(*) FROM documents_embeddings").await,
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_restores_integrity() {
+        let knowledge = inconsistent().await;
+        let options = MaintenanceOptions {
+            reembed_missing: true,
+            vacuum: VacuumMode::Full,
+            chunk_size: 1,
+            ..Default::default()
+        };
+
+        let report = knowledge.maintenance(&options).await.unwrap();
+        assert_eq!(
+            report.tables[0],
+            TableReport {
+                table: "documents",
+                orphaned_embeddings: 2,
+                missing_embeddings: 1,
+                reembedded: 1,
+            }
+        );
+        assert!(report.fts_rebuilt.is_empty());
+
+        for table in ["documents", "messages"] {
+            let orphans = format!(
+                "SELECT COUNT(*) FROM {table}_embeddings
+                 WHERE rowid NOT IN (SELECT rowid FROM {table})"
+            );
+            let missing = format!(
+                "SELECT COUNT(*) FROM {table}
+                 WHERE rowid NOT IN (SELECT rowid FROM {table}_embeddings)"
+            );
+            assert_eq!(count(&knowledge, &orphans).await, 0);
+            assert_eq!(count(&knowledge, &missing).await, 0);
+        }
+
+        // The re-embedded document is found by its content again.
+        let results = knowledge
+            .clone()
+            .document_index()
+            .top_n_ids("slot deploys katana", 1)
+            .await
+            .unwrap();
+        assert_eq!(results[0].1, "slot.md");
+
+        let again = knowledge.maintenance(&options).await.unwrap();
+        assert!(again
+            .tables
+            .iter()
+            .all(|table| table.orphaned_embeddings == 0 && table.missing_embeddings == 0));
+    }
+}
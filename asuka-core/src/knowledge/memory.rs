@@ -0,0 +1,647 @@
+//! Tiered memory of a channel beyond its raw messages: summaries of closed
+//! sessions, and durable facts promoted from sentences that recur across
+//! sessions.
+//!
+//! [KnowledgeBase::consolidate_facts] embeds the sentences of a channel's
+//! recent session summaries and clusters them greedily: a sentence joins the
+//! first cluster whose earliest sentence is at least
+//! [ConsolidationOptions::similarity] alike, or starts its own. Clusters
+//! spanning [ConsolidationOptions::min_sessions] sessions become facts, or
+//! reinforce the fact they match.
+
+use chrono::{DateTime, Utc};
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+use rusqlite::Row;
+use tracing::info;
+
+use super::{
+    gaps::{cosine_similarity, from_blob, to_blob},
+    pending,
+    store::KnowledgeBase,
+};
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS session_summaries (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        agent_id TEXT NOT NULL,
+        channel_id TEXT NOT NULL,
+        summary TEXT NOT NULL,
+        message_count INTEGER NOT NULL,
+        started_at TEXT NOT NULL,
+        ended_at TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS idx_session_summaries_channel
+        ON session_summaries(agent_id, channel_id, ended_at);
+
+    CREATE TABLE IF NOT EXISTS consolidated_facts (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        agent_id TEXT NOT NULL,
+        channel_id TEXT NOT NULL,
+        content TEXT NOT NULL,
+        embedding BLOB NOT NULL,
+        sessions INTEGER NOT NULL,
+        created_at TEXT NOT NULL,
+        updated_at TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS idx_consolidated_facts_channel
+        ON consolidated_facts(agent_id, channel_id);
+";
+
+/// Messages of a channel since its last session summary, whose newest
+/// message is old enough for the session to count as closed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdleSession {
+    pub channel_id: String,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+}
+
+/// Tier 1: what a closed session was about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionSummary {
+    pub id: i64,
+    pub channel_id: String,
+    pub summary: String,
+    pub message_count: usize,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+    /// Some of the summarized messages were purged by retention, see
+    /// [KnowledgeBase::apply_retention].
+    pub stale: bool,
+}
+
+impl TryFrom<&Row<'_>> for SessionSummary {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &Row) -> Result<Self, Self::Error> {
+        Ok(SessionSummary {
+            id: row.get(0)?,
+            channel_id: row.get(1)?,
+            summary: row.get(2)?,
+            message_count: row.get(3)?,
+            started_at: row.get(4)?,
+            ended_at: row.get(5)?,
+            stale: row.get(6)?,
+        })
+    }
+}
+
+/// Tier 2: a durable fact about a channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsolidatedFact {
+    pub id: i64,
+    pub channel_id: String,
+    pub content: String,
+    /// Sessions the fact was seen in when last consolidated.
+    pub sessions: usize,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl TryFrom<&Row<'_>> for ConsolidatedFact {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &Row) -> Result<Self, Self::Error> {
+        Ok(ConsolidatedFact {
+            id: row.get(0)?,
+            channel_id: row.get(1)?,
+            content: row.get(2)?,
+            sessions: row.get(3)?,
+            updated_at: row.get(4)?,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ConsolidationOptions {
+    /// Cosine similarity from which two sentences say the same thing.
+    pub similarity: f64,
+    /// Sessions a sentence must recur in to be promoted.
+    pub min_sessions: usize,
+    /// Newest session summaries of a channel considered.
+    pub window: usize,
+}
+
+impl Default for ConsolidationOptions {
+    fn default() -> Self {
+        Self {
+            similarity: 0.85,
+            min_sessions: 2,
+            window: 50,
+        }
+    }
+}
+
+/// Outcome of consolidating one channel.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConsolidationReport {
+    /// Content of the facts added.
+    pub promoted: Vec<String>,
+    /// Existing facts seen again.
+    pub reinforced: usize,
+}
+
+/// Sentences of a summary, without list markers.
+pub fn summary_sentences(summary: &str) -> Vec<String> {
+    let mut sentences = Vec::new();
+    for line in summary.lines() {
+        let line = line.trim().trim_start_matches(['-', '*', '•']).trim();
+        let mut start = 0;
+        for (i, c) in line.char_indices() {
+            let end = i + c.len_utf8();
+            let boundary = matches!(c, '.' | '!' | '?')
+                && line[end..].chars().next().map_or(true, char::is_whitespace);
+            if boundary {
+                sentences.push(line[start..end].trim().to_string());
+                start = end;
+            }
+        }
+        sentences.push(line[start..].trim().to_string());
+    }
+    sentences.retain(|sentence| sentence.chars().any(char::is_alphanumeric));
+    sentences
+}
+
+/// Sentences that say the same thing, by the sessions they come from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SentenceCluster {
+    /// The earliest sentence, which stands for the cluster.
+    pub content: String,
+    pub embedding: Vec<f64>,
+    pub sessions: Vec<i64>,
+}
+
+/// Clusters `(session id, sentence, embedding)` triples in order, see the
+/// [module docs](self).
+pub fn cluster_sentences(
+    sentences: Vec<(i64, String, Vec<f64>)>,
+    similarity: f64,
+) -> Vec<SentenceCluster> {
+    let mut clusters: Vec<SentenceCluster> = Vec::new();
+    for (session, content, embedding) in sentences {
+        match clusters
+            .iter_mut()
+            .find(|cluster| cosine_similarity(&cluster.embedding, &embedding) >= similarity)
+        {
+            Some(cluster) => {
+                if !cluster.sessions.contains(&session) {
+                    cluster.sessions.push(session);
+                }
+            }
+            None => clusters.push(SentenceCluster {
+                content,
+                embedding,
+                sessions: vec![session],
+            }),
+        }
+    }
+    clusters
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Channels whose messages since their last session summary went quiet
+    /// before `idle_since`.
+    pub async fn idle_sessions(
+        &self,
+        idle_since: DateTime<Utc>,
+    ) -> Result<Vec<IdleSession>, SqliteError> {
+        let namespace = self.namespace.clone();
+        let idle_since = idle_since.to_rfc3339();
+
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT m.channel_id, MIN(m.created_at), MAX(m.created_at)
+                     FROM {} m
+                     WHERE m.agent_id = ?1 AND m.created_at > COALESCE((
+                         SELECT MAX(s.ended_at) FROM session_summaries s
+                         WHERE s.agent_id = m.agent_id AND s.channel_id = m.channel_id
+                     ), '')
+                     GROUP BY m.channel_id
+                     HAVING MAX(m.created_at) < ?2
+                     ORDER BY m.channel_id",
+                    pending::MESSAGES_WITH_PENDING
+                ))?;
+                let sessions = stmt
+                    .query_map([&namespace, &idle_since], |row| {
+                        Ok(IdleSession {
+                            channel_id: row.get(0)?,
+                            started_at: row.get(1)?,
+                            ended_at: row.get(2)?,
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(sessions)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Stores the summary of a closed session. Returns its id.
+    pub async fn add_session_summary(
+        &self,
+        session: &IdleSession,
+        summary: &str,
+        message_count: usize,
+    ) -> Result<i64, SqliteError> {
+        let namespace = self.namespace.clone();
+        let channel_id = session.channel_id.clone();
+        let summary = summary.to_string();
+        let started_at = session.started_at.to_rfc3339();
+        let ended_at = session.ended_at.to_rfc3339();
+
+        self.conn
+            .call(move |conn| {
+                Ok(conn.query_row(
+                    "INSERT INTO session_summaries
+                         (agent_id, channel_id, summary, message_count, started_at, ended_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                     RETURNING id",
+                    rusqlite::params![
+                        namespace,
+                        channel_id,
+                        summary,
+                        message_count,
+                        started_at,
+                        ended_at
+                    ],
+                    |row| row.get(0),
+                )?)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// The newest `limit` session summaries of a channel, newest first.
+    pub async fn session_summaries(
+        &self,
+        channel_id: &str,
+        limit: usize,
+    ) -> Result<Vec<SessionSummary>, SqliteError> {
+        let namespace = self.namespace.clone();
+        let channel_id = channel_id.to_string();
+
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT id, channel_id, summary, message_count, started_at, ended_at, stale
+                     FROM session_summaries
+                     WHERE agent_id = ?1 AND channel_id = ?2
+                     ORDER BY ended_at DESC, id DESC
+                     LIMIT ?3",
+                )?;
+                let summaries = stmt
+                    .query_map(rusqlite::params![namespace, channel_id, limit], |row| {
+                        SessionSummary::try_from(row)
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(summaries)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Facts of a channel, the most often seen first.
+    pub async fn consolidated_facts(
+        &self,
+        channel_id: &str,
+    ) -> Result<Vec<ConsolidatedFact>, SqliteError> {
+        let namespace = self.namespace.clone();
+        let channel_id = channel_id.to_string();
+
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT id, channel_id, content, sessions, updated_at
+                     FROM consolidated_facts
+                     WHERE agent_id = ?1 AND channel_id = ?2
+                     ORDER BY sessions DESC, id",
+                )?;
+                let facts = stmt
+                    .query_map([&namespace, &channel_id], |row| {
+                        ConsolidatedFact::try_from(row)
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(facts)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Channels with at least `min_sessions` session summaries.
+    pub async fn channels_with_sessions(
+        &self,
+        min_sessions: usize,
+    ) -> Result<Vec<String>, SqliteError> {
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT channel_id FROM session_summaries
+                     WHERE agent_id = ?1
+                     GROUP BY channel_id
+                     HAVING COUNT(*) >= ?2
+                     ORDER BY channel_id",
+                )?;
+                let channels = stmt
+                    .query_map(rusqlite::params![namespace, min_sessions], |row| row.get(0))?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(channels)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Promotes the sentences recurring across a channel's recent sessions
+    /// to facts, see the [module docs](self).
+    pub async fn consolidate_facts(
+        &self,
+        channel_id: &str,
+        options: &ConsolidationOptions,
+    ) -> anyhow::Result<ConsolidationReport> {
+        let mut summaries = self.session_summaries(channel_id, options.window).await?;
+        // Oldest first, so a cluster is named after its first phrasing
+        summaries.reverse();
+        let sentences = summaries
+            .iter()
+            .flat_map(|summary| {
+                summary_sentences(&summary.summary)
+                    .into_iter()
+                    .map(|sentence| (summary.id, sentence))
+            })
+            .collect::<Vec<_>>();
+        if sentences.is_empty() {
+            return Ok(ConsolidationReport::default());
+        }
+
+        let mut embeddings = Vec::with_capacity(sentences.len());
+        for batch in sentences.chunks(E::MAX_DOCUMENTS) {
+            embeddings.extend(
+                self.embedding_model
+                    .embed_texts(batch.iter().map(|(_, sentence)| sentence.clone()))
+                    .await?,
+            );
+        }
+        let clusters = cluster_sentences(
+            sentences
+                .into_iter()
+                .zip(embeddings)
+                .map(|((session, sentence), embedding)| (session, sentence, embedding.vec))
+                .collect(),
+            options.similarity,
+        );
+        let recurring = clusters
+            .into_iter()
+            .filter(|cluster| cluster.sessions.len() >= options.min_sessions)
+            .collect::<Vec<_>>();
+        if recurring.is_empty() {
+            return Ok(ConsolidationReport::default());
+        }
+
+        let namespace = self.namespace.clone();
+        let channel = channel_id.to_string();
+        let similarity = options.similarity;
+        let now = self.clock.now_utc().to_rfc3339();
+
+        let report = self
+            .conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let mut facts = {
+                    let mut stmt = tx.prepare(
+                        "SELECT id, embedding FROM consolidated_facts
+                         WHERE agent_id = ?1 AND channel_id = ?2",
+                    )?;
+                    stmt.query_map([&namespace, &channel], |row| {
+                        Ok((row.get::<_, i64>(0)?, from_blob(&row.get::<_, Vec<u8>>(1)?)))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?
+                };
+
+                let mut report = ConsolidationReport::default();
+                for cluster in recurring {
+                    let known = facts
+                        .iter()
+                        .map(|(id, embedding)| {
+                            (*id, cosine_similarity(embedding, &cluster.embedding))
+                        })
+                        .filter(|(_, score)| *score >= similarity)
+                        .max_by(|a, b| a.1.total_cmp(&b.1));
+                    match known {
+                        Some((id, _)) => {
+                            tx.execute(
+                                "UPDATE consolidated_facts
+                                 SET sessions = MAX(sessions, ?2), updated_at = ?3
+                                 WHERE id = ?1",
+                                rusqlite::params![id, cluster.sessions.len(), now],
+                            )?;
+                            report.reinforced += 1;
+                        }
+                        None => {
+                            let id = tx.query_row(
+                                "INSERT INTO consolidated_facts
+                                     (agent_id, channel_id, content, embedding, sessions, created_at, updated_at)
+                                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)
+                                 RETURNING id",
+                                rusqlite::params![
+                                    namespace,
+                                    channel,
+                                    cluster.content,
+                                    to_blob(&cluster.embedding),
+                                    cluster.sessions.len(),
+                                    now
+                                ],
+                                |row| row.get(0),
+                            )?;
+                            facts.push((id, cluster.embedding));
+                            report.promoted.push(cluster.content);
+                        }
+                    }
+                }
+
+                tx.commit()?;
+                Ok(report)
+            })
+            .await?;
+
+        info!(
+            channel_id,
+            promoted = report.promoted.len(),
+            reinforced = report.reinforced,
+            "Consolidated session summaries"
+        );
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        knowledge::{ChannelType, Message, Source},
+        test_utils::{self, ControlledEmbeddingModel},
+    };
+
+    fn at(minute: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_730_800_000 + minute * 60, 0).unwrap()
+    }
+
+    fn session(channel_id: &str, minute: i64) -> IdleSession {
+        IdleSession {
+            channel_id: channel_id.to_string(),
+            started_at: at(minute),
+            ended_at: at(minute + 5),
+        }
+    }
+
+    #[test]
+    fn test_summary_sentences() {
+        assert_eq!(
+            summary_sentences(
+                "- Katana runs on port 5050. Use --dev for a devnet!\n\
+                 * Sessions expire, v1.2 too?\n\n."
+            ),
+            [
+                "Katana runs on port 5050.",
+                "Use --dev for a devnet!",
+                "Sessions expire, v1.2 too?",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cluster_sentences() {
+        let clusters = cluster_sentences(
+            vec![
+                (1, "Port 5050.".to_string(), vec![1.0, 0.0]),
+                (1, "Port again.".to_string(), vec![0.99, 0.1]),
+                (2, "Katana uses 5050.".to_string(), vec![0.95, 0.3]),
+                (2, "Fees in STRK.".to_string(), vec![0.0, 1.0]),
+            ],
+            0.9,
+        );
+        assert_eq!(clusters.len(), 2);
+        assert_eq!(clusters[0].content, "Port 5050.");
+        assert_eq!(clusters[0].sessions, [1, 2]);
+        assert_eq!(clusters[1].content, "Fees in STRK.");
+        assert_eq!(clusters[1].sessions, [2]);
+    }
+
+    #[tokio::test]
+    async fn test_idle_sessions_follow_summaries() {
+        let knowledge = test_utils::knowledge_base().await;
+        for (id, channel_id, minute) in [("m1", "c1", 0), ("m2", "c1", 5), ("m3", "c2", 50)] {
+            knowledge
+                .create_message(Message {
+                    id: id.to_string(),
+                    source: Source::Discord,
+                    source_id: "u1".to_string(),
+                    channel_type: ChannelType::Text,
+                    channel_id: channel_id.to_string(),
+                    account_id: "u1".to_string(),
+                    role: "user".to_string(),
+                    content: format!("message {id}"),
+                    created_at: at(minute),
+                })
+                .await
+                .unwrap();
+        }
+
+        // c2 is still active
+        let idle = knowledge.idle_sessions(at(40)).await.unwrap();
+        assert_eq!(idle, [session("c1", 0)]);
+
+        knowledge
+            .add_session_summary(&idle[0], "Asked about katana.", 2)
+            .await
+            .unwrap();
+        assert!(knowledge.idle_sessions(at(40)).await.unwrap().is_empty());
+        assert_eq!(
+            knowledge.idle_sessions(at(90)).await.unwrap(),
+            [IdleSession {
+                channel_id: "c2".to_string(),
+                started_at: at(50),
+                ended_at: at(50),
+            }]
+        );
+
+        let summaries = knowledge.session_summaries("c1", 10).await.unwrap();
+        assert_eq!(summaries[0].summary, "Asked about katana.");
+        assert_eq!(summaries[0].message_count, 2);
+        assert_eq!(summaries[0].ended_at, at(5));
+    }
+
+    #[tokio::test]
+    async fn test_recurring_sentences_are_promoted() {
+        // Paraphrases of the port sentence are 0.95 alike, the fee sentence
+        // is unrelated to both.
+        let model = ControlledEmbeddingModel::default()
+            .with("Katana listens on port 5050.", [1.0, 0.0, 0.0])
+            .with("Katana uses port 5050.", [0.95, 0.312, 0.0])
+            .with("The devnet runs katana on 5050.", [0.95, 0.0, 0.312])
+            .with("Fees are paid in STRK.", [0.0, 1.0, 0.0])
+            .with("Sessions expire after a week.", [0.0, 0.0, 1.0]);
+        let knowledge = test_utils::knowledge_base_with(model).await;
+        let options = ConsolidationOptions {
+            similarity: 0.9,
+            min_sessions: 2,
+            window: 10,
+        };
+
+        knowledge
+            .add_session_summary(
+                &session("c1", 0),
+                "Katana listens on port 5050. Fees are paid in STRK.",
+                4,
+            )
+            .await
+            .unwrap();
+        knowledge
+            .add_session_summary(&session("c1", 60), "Katana uses port 5050.", 3)
+            .await
+            .unwrap();
+        knowledge
+            .add_session_summary(&session("c2", 60), "Sessions expire after a week.", 3)
+            .await
+            .unwrap();
+
+        // Seen in one session only, the fee sentence stays in tier 1
+        let report = knowledge.consolidate_facts("c1", &options).await.unwrap();
+        assert_eq!(report.promoted, ["Katana listens on port 5050."]);
+        assert_eq!(report.reinforced, 0);
+        assert!(knowledge
+            .consolidate_facts("c2", &options)
+            .await
+            .unwrap()
+            .promoted
+            .is_empty());
+
+        // A third phrasing reinforces the fact instead of adding another
+        knowledge
+            .add_session_summary(&session("c1", 120), "The devnet runs katana on 5050.", 2)
+            .await
+            .unwrap();
+        let report = knowledge.consolidate_facts("c1", &options).await.unwrap();
+        assert!(report.promoted.is_empty());
+        assert_eq!(report.reinforced, 1);
+
+        let facts = knowledge.consolidated_facts("c1").await.unwrap();
+        assert_eq!(facts.len(), 1);
+        assert_eq!(facts[0].content, "Katana listens on port 5050.");
+        assert_eq!(facts[0].sessions, 3);
+        assert!(knowledge.consolidated_facts("c2").await.unwrap().is_empty());
+
+        // A stricter threshold no longer sees the paraphrases as one
+        let strict = ConsolidationOptions {
+            similarity: 0.99,
+            ..options
+        };
+        let report = knowledge.consolidate_facts("c1", &strict).await.unwrap();
+        assert_eq!(report, ConsolidationReport::default());
+        assert_eq!(
+            knowledge.channels_with_sessions(2).await.unwrap(),
+            ["c1".to_string()]
+        );
+    }
+}
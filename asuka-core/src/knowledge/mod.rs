@@ -2,8 +2,103 @@ mod types;
 mod store;
 mod models;
 mod error;
+mod activity;
+mod admin;
+mod announcements;
+mod channel_settings;
+mod chunking;
+mod chunks;
+mod cleaning;
+mod conversation_state;
+mod cursors;
+mod dead_letters;
+mod deferred;
+mod diversity;
+mod embedding_policy;
+mod embeddings;
+mod erasure;
+mod escalations;
+mod exchanges;
+mod experiments;
+mod familiarity;
+mod gaps;
+mod guilds;
+mod identity;
+mod ingest;
+mod interactions;
+mod languages;
+mod linked_identities;
+mod maintenance;
+mod memory;
+mod namespaces;
+mod onboarding;
+mod outline;
+mod pagination;
+mod pending;
+mod pins;
+mod rate_limits;
+mod refresh;
+mod replica;
+mod retention;
+mod sent_messages;
+mod service_lock;
+mod snapshot;
+mod source_state;
+mod tool_calls;
+mod topics;
+mod user_facts;
+mod versions;
 
 pub use types::{Source, ChannelType, MessageMetadata, MessageContent};
-pub use store::KnowledgeBase;
+pub use store::{EmbeddingDimensionError, KnowledgeBase, MessageWindow};
 pub use models::{Document, Message, Account, Channel, Conversation};
-pub use error::ConversionError; 
\ No newline at end of file
+pub use error::ConversionError;
+pub use activity::{Activity, ToolActivity};
+pub use admin::{DocumentDetails, DocumentFilter};
+pub use announcements::Announcement;
+pub use chunking::ChunkingConfig;
+pub use chunks::{ChunkPosition, RetrievedChunk};
+pub use cleaning::{CleanPass, ContentCleaner};
+pub use conversation_state::{ConversationState, SourceRef};
+pub use dead_letters::DeadLetter;
+pub use deferred::DeferredReply;
+pub use diversity::{parent_id, select_diverse, Candidate, DiverseIndex, DiversityConfig};
+pub use embedding_policy::{CompiledEmbeddingPolicy, EmbeddingPolicy, EmbeddingStats, SkipReason};
+pub use embeddings::{
+    circuit_open, is_circuit_open, is_rate_limited, CircuitOpen, EmbeddingMetrics,
+    EmbeddingService, EmbeddingServiceConfig,
+};
+pub use erasure::{ErasedTable, ErasureMode, ErasureOptions, ErasureReport, ERASED_ID};
+pub use escalations::Escalation;
+pub use exchanges::Exchange;
+pub use experiments::VariantReport;
+pub use familiarity::Familiarity;
+pub use gaps::{format_gaps, GapConfig, KnowledgeGap, RetrievalSupport};
+pub use guilds::{DiscoveredChannel, GuildCleanup, MessageRetention};
+pub use identity::{content_hash, ResolvedDocument, RENAME_SIMILARITY};
+pub use ingest::{IngestEvent, IngestHook, IngestOptions, IngestSummary};
+pub use interactions::{ResearchStep, Truncation};
+pub use linked_identities::{LinkError, LinkedIdentity};
+pub use maintenance::{MaintenanceOptions, MaintenanceReport, TableReport, VacuumMode};
+pub use memory::{
+    cluster_sentences, summary_sentences, ConsolidatedFact, ConsolidationOptions,
+    ConsolidationReport, IdleSession, SentenceCluster, SessionSummary,
+};
+pub use namespaces::{NamespaceIndex, DEFAULT_NAMESPACE};
+pub use onboarding::OnboardingState;
+pub use outline::{Heading, Outline};
+pub use pagination::{Cursor, CursorError, Page};
+pub use pending::{DrainSummary, RetryPolicy};
+pub use pins::{fit_pins, PinnedContext};
+pub use rate_limits::RateEvent;
+pub use refresh::{RefreshError, RefreshRegistry, RefreshSource, RefreshSummary};
+pub use replica::{ReadReplica, ReplicaConfig, ReplicaError, ReplicaStats};
+pub use retention::{PurgeReport, RetentionPolicy, RETAIN_FOREVER_SETTING};
+pub use sent_messages::SentMessage;
+pub use service_lock::{ServiceLock, ServiceLockConfig, ServiceLockError};
+pub use snapshot::{PublishedSnapshot, SnapshotError, SnapshotManifest, SNAPSHOT_VERSION};
+pub(crate) use snapshot::is_secure_url;
+pub use source_state::SourceState;
+pub use tool_calls::{format_tool_calls, ToolCall};
+pub use topics::{boost_candidates, match_topics, TopicBoost, TopicIndex};
+pub use versions::{latest_versions, DocumentVersion, FreshIndex, StoreSummary}; 
\ No newline at end of file
@@ -1,15 +1,49 @@
 use super::types::{ChannelType, Source};
+use asuka_derive::SqliteTable;
 use rig_sqlite::{Column, ColumnValue, SqliteVectorStoreTable};
+use rig::embeddings::{EmbedError, TextEmbedder};
 use rig::Embed;
 use rusqlite::Row;
 
-#[derive(Embed, Clone, Debug)]
+#[derive(SqliteTable, Clone, Debug)]
+#[table(name = "documents")]
 pub struct Document {
+    #[column(primary_key)]
     pub id: String,
+    #[column(indexed)]
     pub source_id: String,
-    #[embed]
     pub content: String,
     pub created_at: chrono::DateTime<chrono::Utc>,
+    /// Title from the front matter or the first heading, empty when the
+    /// document has neither. See [Outline](super::Outline).
+    #[column(type = "TEXT NOT NULL DEFAULT ''")]
+    pub title: String,
+    /// Heading chain the content sits under, such as `VRF > Configuration`,
+    /// for a part of a larger document. Empty for whole documents.
+    #[column(type = "TEXT NOT NULL DEFAULT ''")]
+    pub section: String,
+    /// Topic tags, kept in the `document_topics` table rather than on the row.
+    #[column(skip)]
+    pub topics: Vec<String>,
+    /// Identity shared by every version of this document, kept in the
+    /// `document_versions` table. Defaults to `id`.
+    #[column(skip)]
+    pub logical_id: Option<String>,
+    /// Text embedded in place of `content`, with markup removed by a
+    /// [ContentCleaner](super::ContentCleaner). Not stored.
+    #[column(skip)]
+    pub cleaned: Option<String>,
+    /// Where a part of a larger document sits in it, kept in the
+    /// `document_chunks` table. `None` for whole documents.
+    #[column(skip)]
+    pub chunk: Option<super::ChunkPosition>,
+}
+
+impl Embed for Document {
+    fn embed(&self, embedder: &mut TextEmbedder) -> Result<(), EmbedError> {
+        embedder.embed(self.cleaned.clone().unwrap_or_else(|| self.content.clone()));
+        Ok(())
+    }
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -30,13 +64,20 @@ pub struct Conversation {
     pub updated_at: chrono::DateTime<chrono::Utc>,
 }
 
-#[derive(Embed, Clone, Debug, serde::Deserialize)]
+#[derive(Embed, SqliteTable, Clone, Debug, serde::Deserialize)]
+#[table(name = "messages")]
 pub struct Message {
+    #[column(primary_key)]
     pub id: String,
+    #[column(as_str)]
     pub source: Source,
+    #[column(indexed)]
     pub source_id: String,
+    #[column(as_str)]
     pub channel_type: ChannelType,
+    #[column(indexed)]
     pub channel_id: String,
+    #[column(indexed)]
     pub account_id: String,
     pub role: String,
     #[embed]
@@ -44,98 +85,22 @@ pub struct Message {
     pub created_at: chrono::DateTime<chrono::Utc>,
 }
 
+/// Implemented by hand: the id is assigned by SQLite and `name` is nullable,
+/// neither of which the derive supports.
 #[derive(Debug, Clone, serde::Deserialize)]
 pub struct Channel {
-    pub id: String,
-    pub name: String,
+    pub id: i64,
+    pub channel_id: String,
+    pub channel_type: String,
     pub source: String,
+    pub name: Option<String>,
+    /// Discord guild the channel belongs to, set when it is discovered on
+    /// joining the guild.
+    pub guild_id: Option<String>,
     pub created_at: chrono::DateTime<chrono::Utc>,
     pub updated_at: chrono::DateTime<chrono::Utc>,
 }
 
-// Implement the table traits
-impl SqliteVectorStoreTable for Document {
-    fn name() -> &'static str {
-        "documents"
-    }
-
-    fn schema() -> Vec<Column> {
-        vec![
-            Column::new("id", "TEXT PRIMARY KEY"),
-            Column::new("source_id", "TEXT").indexed(),
-            Column::new("content", "TEXT"),
-            Column::new("created_at", "TIMESTAMP DEFAULT CURRENT_TIMESTAMP"),
-        ]
-    }
-
-    fn id(&self) -> String {
-        self.id.clone()
-    }
-
-    fn column_values(&self) -> Vec<(&'static str, Box<dyn ColumnValue>)> {
-        vec![
-            ("id", Box::new(self.id.clone())),
-            ("source_id", Box::new(self.source_id.clone())),
-            ("content", Box::new(self.content.clone())),
-            ("created_at", Box::new(self.created_at.to_rfc3339())),
-        ]
-    }
-}
-
-impl SqliteVectorStoreTable for Message {
-    fn name() -> &'static str {
-        "messages"
-    }
-
-    fn schema() -> Vec<Column> {
-        vec![
-            Column::new("id", "TEXT PRIMARY KEY"),
-            Column::new("source", "TEXT"),
-            Column::new("source_id", "TEXT").indexed(),
-            Column::new("channel_type", "TEXT"),
-            Column::new("channel_id", "TEXT").indexed(),
-            Column::new("account_id", "TEXT").indexed(),
-            Column::new("role", "TEXT"),
-            Column::new("content", "TEXT"),
-            Column::new("created_at", "TIMESTAMP DEFAULT CURRENT_TIMESTAMP"),
-        ]
-    }
-
-    fn id(&self) -> String {
-        self.id.clone()
-    }
-
-    fn column_values(&self) -> Vec<(&'static str, Box<dyn ColumnValue>)> {
-        vec![
-            ("id", Box::new(self.id.clone())),
-            ("source", Box::new(self.source.as_str().to_string())),
-            ("source_id", Box::new(self.source_id.clone())),
-            (
-                "channel_type",
-                Box::new(self.channel_type.as_str().to_string()),
-            ),
-            ("channel_id", Box::new(self.channel_id.clone())),
-            ("account_id", Box::new(self.account_id.clone())),
-            ("role", Box::new(self.role.clone())),
-            ("content", Box::new(self.content.clone())),
-            ("created_at", Box::new(self.created_at.to_rfc3339())),
-        ]
-    }
-}
-
-impl TryFrom<&Row<'_>> for Document {
-    type Error = rusqlite::Error;
-
-    fn try_from(row: &Row) -> Result<Self, Self::Error> {
-        Ok(Document {
-            id: row.get(0)?,
-            source_id: row.get(1)?,
-            content: row.get(2)?,
-            created_at: row.get(3)?,
-        })
-    }
-}
-
 impl TryFrom<&Row<'_>> for Account {
     type Error = rusqlite::Error;
 
@@ -164,48 +129,19 @@ impl TryFrom<&Row<'_>> for Conversation {
     }
 }
 
-impl TryFrom<&Row<'_>> for Message {
-    type Error = rusqlite::Error;
-
-    fn try_from(row: &Row) -> Result<Self, Self::Error> {
-        Ok(Message {
-            id: row.get(0)?,
-            source: Source::from_str(&row.get::<_, String>(1)?).ok_or(
-                rusqlite::Error::FromSqlConversionFailure(
-                    1,
-                    rusqlite::types::Type::Text,
-                    Box::new(super::error::ConversionError("Invalid source".to_string())),
-                ),
-            )?,
-            source_id: row.get(2)?,
-            channel_type: ChannelType::from_str(&row.get::<_, String>(3)?).ok_or(
-                rusqlite::Error::FromSqlConversionFailure(
-                    3,
-                    rusqlite::types::Type::Text,
-                    Box::new(super::error::ConversionError(
-                        "Invalid channel type".to_string(),
-                    )),
-                ),
-            )?,
-            channel_id: row.get(4)?,
-            account_id: row.get(5)?,
-            role: row.get(6)?,
-            content: row.get(7)?,
-            created_at: row.get(8)?,
-        })
-    }
-}
-
 impl TryFrom<&Row<'_>> for Channel {
     type Error = rusqlite::Error;
 
     fn try_from(row: &Row) -> Result<Self, Self::Error> {
         Ok(Channel {
-            id: row.get(0)?,
-            name: row.get(1)?,
-            source: row.get(2)?,
-            created_at: row.get(3)?,
-            updated_at: row.get(4)?,
+            id: row.get("id")?,
+            channel_id: row.get("channel_id")?,
+            channel_type: row.get("channel_type")?,
+            source: row.get("source")?,
+            name: row.get("name")?,
+            guild_id: row.get("guild_id")?,
+            created_at: row.get("created_at")?,
+            updated_at: row.get("updated_at")?,
         })
     }
 }
@@ -217,25 +153,102 @@ impl SqliteVectorStoreTable for Channel {
 
     fn schema() -> Vec<Column> {
         vec![
-            Column::new("id", "TEXT PRIMARY KEY"),
+            Column::new("id", "INTEGER PRIMARY KEY AUTOINCREMENT"),
+            Column::new("channel_id", "TEXT NOT NULL UNIQUE").indexed(),
+            Column::new("channel_type", "TEXT NOT NULL"),
+            Column::new("source", "TEXT NOT NULL"),
             Column::new("name", "TEXT"),
-            Column::new("source", "TEXT"),
+            Column::new("guild_id", "TEXT").indexed(),
             Column::new("created_at", "TIMESTAMP DEFAULT CURRENT_TIMESTAMP"),
             Column::new("updated_at", "TIMESTAMP DEFAULT CURRENT_TIMESTAMP"),
         ]
     }
 
     fn id(&self) -> String {
-        self.id.clone()
+        self.id.to_string()
     }
 
     fn column_values(&self) -> Vec<(&'static str, Box<dyn ColumnValue>)> {
         vec![
-            ("id", Box::new(self.id.clone())),
-            ("name", Box::new(self.name.clone())),
+            ("id", Box::new(self.id.to_string())),
+            ("channel_id", Box::new(self.channel_id.clone())),
+            ("channel_type", Box::new(self.channel_type.clone())),
             ("source", Box::new(self.source.clone())),
+            ("name", Box::new(self.name.clone().unwrap_or_default())),
+            (
+                "guild_id",
+                Box::new(self.guild_id.clone().unwrap_or_default()),
+            ),
             ("created_at", Box::new(self.created_at.to_rfc3339())),
             ("updated_at", Box::new(self.updated_at.to_rfc3339())),
         ]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[test]
+    fn test_derived_columns_follow_field_order() {
+        assert_eq!(
+            Message::COLUMNS,
+            "id, source, source_id, channel_type, channel_id, account_id, role, content, created_at"
+        );
+        assert_eq!(
+            Document::COLUMNS,
+            "id, source_id, content, created_at, title, section"
+        );
+
+        let message = Message {
+            id: "m1".to_string(),
+            source: Source::Telegram,
+            source_id: "u1".to_string(),
+            channel_type: ChannelType::DirectMessage,
+            channel_id: "c1".to_string(),
+            account_id: "a1".to_string(),
+            role: "user".to_string(),
+            content: "gm".to_string(),
+            created_at: chrono::Utc::now(),
+        };
+        let names = message
+            .column_values()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>()
+            .join(", ");
+        assert_eq!(names, Message::COLUMNS);
+        assert_eq!(Message::schema().len(), message.column_values().len());
+    }
+
+    #[tokio::test]
+    async fn test_channel_reads_every_column() {
+        let knowledge = test_utils::knowledge_base().await;
+        knowledge
+            .conn
+            .call(|conn| {
+                conn.execute(
+                    "INSERT INTO channels (channel_id, channel_type, source, name)
+                     VALUES ('c1', 'text', 'discord', NULL)",
+                    [],
+                )?;
+                Ok(())
+            })
+            .await
+            .unwrap();
+
+        let channel = knowledge.get_channel(1).await.unwrap().unwrap();
+        assert_eq!(channel.channel_id, "c1");
+        assert_eq!(channel.channel_type, "text");
+        assert_eq!(channel.source, "discord");
+        assert_eq!(channel.name, None);
+        assert_eq!(channel.guild_id, None);
+
+        let channels = knowledge
+            .get_channels_by_source("discord".to_string())
+            .await
+            .unwrap();
+        assert_eq!(channels.len(), 1);
+    }
+}
@@ -0,0 +1,554 @@
+//! Namespacing of stored data by agent, so several characters can share one
+//! database file.
+//!
+//! Every namespaced table has an `agent_id` column, and a [KnowledgeBase]
+//! only reads and writes rows of its own namespace. Document retrieval can
+//! opt into other namespaces, such as docs ingested once for every agent,
+//! through [KnowledgeBase::with_shared_namespace] or per index through
+//! [NamespaceIndex::with_shared_namespace].
+//!
+//! Accounts and channels describe platform entities and stay shared. Link
+//! tables (`interaction_messages`, `document_topics`) follow their parent
+//! row. Document ids are unique across namespaces, so agents ingesting the
+//! same source should do so once into a shared namespace.
+
+use std::marker::PhantomData;
+
+use rig::{
+    embeddings::EmbeddingModel,
+    vector_store::{VectorStoreError, VectorStoreIndex},
+    OneOrMany,
+};
+use rig_sqlite::{SqliteVectorStore, SqliteVectorStoreTable};
+use rusqlite::types::Value;
+use serde::Deserialize;
+use tokio_rusqlite::Connection;
+use tracing::{debug, info};
+
+use super::{
+    embeddings::{self, EmbeddingService},
+    models::Document,
+    replica::ReadReplica,
+    store::KnowledgeBase,
+};
+
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+/// Tables holding per-agent rows, with the columns namespaced lookups filter
+/// by after `agent_id`.
+const NAMESPACED_TABLES: &[(&str, &str)] = &[
+    ("documents", "source_id"),
+    ("messages", "channel_id, created_at"),
+    ("interactions", "channel_id"),
+    ("pinned_context", "channel_id, position"),
+    ("document_versions", "logical_id"),
+    ("knowledge_gaps", "last_asked_at"),
+];
+
+/// Candidates fetched per requested result before filtering by namespace.
+const OVERFETCH: usize = 4;
+
+/// Columns of the store's own, not of the row type, left out of results.
+const STORE_COLUMNS: &[&str] = &["agent_id", "embedding_skipped"];
+
+/// Largest `k` sqlite-vec accepts in a KNN query.
+const MAX_K: usize = 4096;
+
+/// Shortest word of a query matched on by keyword search.
+const MIN_KEYWORD_LENGTH: usize = 3;
+
+/// Most words of a query matched on by keyword search.
+const MAX_KEYWORDS: usize = 8;
+
+/// Adds the `agent_id` column to every namespaced table, assigning existing
+/// rows to [DEFAULT_NAMESPACE], and indexes it ahead of the lookup columns.
+pub(super) fn migrate(conn: &mut rusqlite::Connection) -> rusqlite::Result<()> {
+    let tx = conn.transaction()?;
+    for (table, columns) in NAMESPACED_TABLES {
+        let has_agent_id = tx
+            .prepare(&format!(
+                "SELECT 1 FROM pragma_table_info('{table}') WHERE name = 'agent_id'"
+            ))?
+            .exists([])?;
+        if !has_agent_id {
+            info!(table, "Adding agent_id column");
+            tx.execute_batch(&format!(
+                "ALTER TABLE {table} ADD COLUMN agent_id TEXT NOT NULL DEFAULT '{DEFAULT_NAMESPACE}'"
+            ))?;
+        }
+        tx.execute_batch(&format!(
+            "CREATE INDEX IF NOT EXISTS idx_{table}_agent ON {table}(agent_id, {columns})"
+        ))?;
+    }
+    tx.commit()
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Scopes this knowledge base to `namespace`. Data written before
+    /// namespaces existed belongs to [DEFAULT_NAMESPACE].
+    pub fn with_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.namespace = namespace.into();
+        self
+    }
+
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    /// Includes documents of `namespace` in every document retrieval made
+    /// through this knowledge base. Writes still go to its own namespace.
+    pub fn with_shared_namespace(mut self, namespace: impl Into<String>) -> Self {
+        let namespace = namespace.into();
+        if !self.shared_namespaces.contains(&namespace) {
+            self.shared_namespaces.push(namespace);
+        }
+        self
+    }
+}
+
+/// Stores embedded documents in `namespace`. A document stored again under
+/// its id replaces the row and its embedding, rather than leaving the old
+/// vector behind.
+pub(super) fn store_documents<E: EmbeddingModel>(
+    tx: &rusqlite::Transaction,
+    store: &SqliteVectorStore<E, Document>,
+    namespace: &str,
+    documents: Vec<(Document, OneOrMany<rig::embeddings::Embedding>)>,
+) -> Result<(), tokio_rusqlite::Error> {
+    let ids = documents
+        .iter()
+        .map(|(document, _)| document.id.clone())
+        .collect::<Vec<_>>();
+
+    for id in &ids {
+        tx.execute(
+            "DELETE FROM documents_embeddings
+             WHERE rowid IN (
+                 SELECT rowid FROM documents WHERE id = ?1 AND agent_id = ?2
+             )",
+            [id, namespace],
+        )?;
+    }
+    store.add_rows_with_txn(tx, documents)?;
+    for id in &ids {
+        tx.execute(
+            "UPDATE documents SET agent_id = ?1 WHERE id = ?2",
+            [namespace, id],
+        )?;
+    }
+    Ok(())
+}
+
+/// Vector index over one table, restricted to the rows of a set of
+/// namespaces. Falls back to keyword matching while the embedding circuit is
+/// open, see [super::embeddings].
+pub struct NamespaceIndex<E: EmbeddingModel + 'static, T: SqliteVectorStoreTable> {
+    conn: Connection,
+    embedding_model: EmbeddingService<E>,
+    namespaces: Vec<String>,
+    /// Searched instead of `conn` when set, see [super::replica].
+    replica: Option<ReadReplica>,
+    _table: PhantomData<T>,
+}
+
+impl<E: EmbeddingModel + 'static, T: SqliteVectorStoreTable> NamespaceIndex<E, T> {
+    pub(super) fn new(
+        conn: Connection,
+        embedding_model: EmbeddingService<E>,
+        namespace: String,
+    ) -> Self {
+        Self {
+            conn,
+            embedding_model,
+            namespaces: vec![namespace],
+            replica: None,
+            _table: PhantomData,
+        }
+    }
+
+    pub(super) fn with_replica(mut self, replica: Option<ReadReplica>) -> Self {
+        self.replica = replica;
+        self
+    }
+
+    /// Also retrieves rows of `namespace`, ranked together with the index's
+    /// own.
+    pub fn with_shared_namespace(mut self, namespace: impl Into<String>) -> Self {
+        let namespace = namespace.into();
+        if !self.namespaces.contains(&namespace) {
+            self.namespaces.push(namespace);
+        }
+        self
+    }
+
+    pub fn namespaces(&self) -> &[String] {
+        &self.namespaces
+    }
+}
+
+impl<E: EmbeddingModel + Sync, T: SqliteVectorStoreTable> NamespaceIndex<E, T> {
+    /// Nearest rows within the namespaces, as `(distance, id, columns)`, with
+    /// every table column read as text like rig-sqlite does, or only the id.
+    /// sqlite-vec applies `k` before the join, so `k` grows until enough rows
+    /// survive the namespace filter or the whole table was searched.
+    async fn search(
+        &self,
+        query: &str,
+        n: usize,
+        all_columns: bool,
+    ) -> Result<Vec<(f64, String, serde_json::Map<String, serde_json::Value>)>, VectorStoreError>
+    {
+        let embedding = match self.embedding_model.embed_text(query).await {
+            Ok(embedding) => embedding,
+            Err(err) if embeddings::is_circuit_open(&err) => {
+                debug!("Embedding circuit open, searching by keyword");
+                return self.keyword_search(query, n, all_columns).await;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let query_vec = embedding
+            .vec
+            .iter()
+            .flat_map(|x| (*x as f32).to_le_bytes())
+            .collect::<Vec<u8>>();
+        let namespaces = self.namespaces.clone();
+        let table = T::name();
+
+        let replica = self.replica.as_ref().map(ReadReplica::read);
+        let rows = replica
+            .as_ref()
+            .map_or(&self.conn, |replica| &replica.conn)
+            .call(move |conn| {
+                let placeholders = (0..namespaces.len())
+                    .map(|i| format!("?{}", i + 3))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let select = if all_columns { "d.*" } else { "d.id" };
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT {select}, e.distance
+                     FROM {table}_embeddings e
+                     JOIN {table} d ON e.rowid = d.rowid
+                     WHERE e.embedding MATCH ?1 AND k = ?2 AND d.agent_id IN ({placeholders})
+                     ORDER BY e.distance"
+                ))?;
+                let columns = stmt
+                    .column_names()
+                    .into_iter()
+                    .map(String::from)
+                    .collect::<Vec<_>>();
+                let distance_index = columns.len() - 1;
+                let id_index = columns.iter().position(|c| c == "id").unwrap_or(0);
+                let total: usize =
+                    conn.query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |row| {
+                        row.get(0)
+                    })?;
+
+                let mut k = (n * OVERFETCH).clamp(1, MAX_K);
+                loop {
+                    let params = [Value::Blob(query_vec.clone()), Value::Integer(k as i64)]
+                        .into_iter()
+                        .chain(namespaces.iter().cloned().map(Value::Text));
+                    let rows = stmt
+                        .query_map(rusqlite::params_from_iter(params), |row| {
+                            let mut map = serde_json::Map::new();
+                            for (i, column) in columns[..distance_index].iter().enumerate() {
+                                if !STORE_COLUMNS.contains(&column.as_str()) {
+                                    let value: String = row.get(i)?;
+                                    map.insert(column.clone(), serde_json::Value::String(value));
+                                }
+                            }
+                            let distance: f64 = row.get(distance_index)?;
+                            let id: String = row.get(id_index)?;
+                            Ok((distance, id, map))
+                        })?
+                        .collect::<Result<Vec<_>, _>>()?;
+
+                    if rows.len() >= n || k >= total || k >= MAX_K {
+                        return Ok(rows);
+                    }
+                    k = (k * OVERFETCH).min(MAX_K);
+                }
+            })
+            .await
+            .map_err(|e| VectorStoreError::DatastoreError(Box::new(e)))?;
+
+        debug!(
+            namespaces = ?self.namespaces,
+            found = rows.len(),
+            "Searched namespaces"
+        );
+        Ok(rows.into_iter().take(n).collect())
+    }
+
+    /// Rows within the namespaces ranked by the share of the query's
+    /// [keywords] their content has, for when the query can't be embedded.
+    /// The distance is the share of keywords missing.
+    async fn keyword_search(
+        &self,
+        query: &str,
+        n: usize,
+        all_columns: bool,
+    ) -> Result<Vec<(f64, String, serde_json::Map<String, serde_json::Value>)>, VectorStoreError>
+    {
+        let words = keywords(query);
+        if words.is_empty() {
+            return Ok(Vec::new());
+        }
+        let patterns = words
+            .iter()
+            .map(|word| format!("%{word}%"))
+            .collect::<Vec<_>>();
+        let namespaces = self.namespaces.clone();
+        let table = T::name();
+
+        let replica = self.replica.as_ref().map(ReadReplica::read);
+        let rows = replica
+            .as_ref()
+            .map_or(&self.conn, |replica| &replica.conn)
+            .call(move |conn| {
+                let placeholders = (0..namespaces.len())
+                    .map(|i| format!("?{}", i + 1))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let matches = (0..patterns.len())
+                    .map(|i| format!("d.content LIKE ?{}", namespaces.len() + i + 1))
+                    .collect::<Vec<_>>()
+                    .join(" OR ");
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT d.* FROM {table} d
+                     WHERE d.agent_id IN ({placeholders}) AND ({matches})"
+                ))?;
+                let columns = stmt
+                    .column_names()
+                    .into_iter()
+                    .map(String::from)
+                    .collect::<Vec<_>>();
+                let id_index = columns.iter().position(|c| c == "id").unwrap_or(0);
+                let content_index = columns.iter().position(|c| c == "content").unwrap_or(0);
+
+                let params = namespaces.into_iter().chain(patterns).map(Value::Text);
+                let rows = stmt
+                    .query_map(rusqlite::params_from_iter(params), |row| {
+                        let mut map = serde_json::Map::new();
+                        if all_columns {
+                            for (i, column) in columns.iter().enumerate() {
+                                if !STORE_COLUMNS.contains(&column.as_str()) {
+                                    let value: String = row.get(i)?;
+                                    map.insert(column.clone(), serde_json::Value::String(value));
+                                }
+                            }
+                        }
+                        let id: String = row.get(id_index)?;
+                        let content: String = row.get(content_index)?;
+                        Ok((id, content.to_lowercase(), map))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(rows)
+            })
+            .await
+            .map_err(|e| VectorStoreError::DatastoreError(Box::new(e)))?;
+
+        let mut ranked = rows
+            .into_iter()
+            .map(|(id, content, map)| {
+                let shared = words.iter().filter(|word| content.contains(*word)).count();
+                (1.0 - shared as f64 / words.len() as f64, id, map)
+            })
+            .collect::<Vec<_>>();
+        ranked.sort_by(|a, b| a.0.total_cmp(&b.0));
+        ranked.truncate(n);
+        debug!(
+            namespaces = ?self.namespaces,
+            found = ranked.len(),
+            "Searched namespaces by keyword"
+        );
+        Ok(ranked)
+    }
+}
+
+/// Distinct lowercase words of `query` worth matching on, at most
+/// [MAX_KEYWORDS].
+fn keywords(query: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    for word in query
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= MIN_KEYWORD_LENGTH)
+    {
+        if !words.iter().any(|w| w == word) {
+            words.push(word.to_string());
+        }
+    }
+    words.truncate(MAX_KEYWORDS);
+    words
+}
+
+impl<E: EmbeddingModel + Sync, T: SqliteVectorStoreTable> VectorStoreIndex
+    for NamespaceIndex<E, T>
+{
+    async fn top_n<D: for<'a> Deserialize<'a> + Send>(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<(f64, String, D)>, VectorStoreError> {
+        let rows = self.search(query, n, true).await?;
+
+        let mut top_n = Vec::new();
+        for (distance, id, map) in rows {
+            match serde_json::from_value::<D>(serde_json::Value::Object(map)) {
+                Ok(doc) => top_n.push((distance, id, doc)),
+                Err(err) => debug!(id, ?err, "Failed to deserialize document"),
+            }
+        }
+        Ok(top_n)
+    }
+
+    async fn top_n_ids(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<(f64, String)>, VectorStoreError> {
+        let rows = self.search(query, n, false).await?;
+        Ok(rows
+            .into_iter()
+            .map(|(distance, id, _)| (distance, id))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    fn doc(id: &str, content: &str) -> Document {
+        Document {
+            id: id.to_string(),
+            source_id: "github".to_string(),
+            content: content.to_string(),
+            created_at: chrono::Utc::now(),
+            title: String::new(),
+            section: String::new(),
+            topics: Vec::new(),
+            logical_id: None,
+            cleaned: None,
+            chunk: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retrieval_is_isolated_by_namespace() {
+        let shared = test_utils::knowledge_base().await;
+        let mut shinobi = shared.clone().with_namespace("shinobi");
+        let mut sensei = shared.clone().with_namespace("sensei");
+        let mut common = shared.clone().with_namespace("common");
+
+        shinobi
+            .add_documents(vec![doc("katana.md", "katana devnet flags")])
+            .await
+            .unwrap();
+        sensei
+            .add_documents(vec![doc("cairo.md", "cairo felt types")])
+            .await
+            .unwrap();
+        common
+            .add_documents(vec![doc("faq.md", "katana sepolia faq")])
+            .await
+            .unwrap();
+
+        let ids =
+            |results: Vec<(f64, String)>| results.into_iter().map(|(_, id)| id).collect::<Vec<_>>();
+
+        let own = shinobi
+            .clone()
+            .document_index()
+            .top_n_ids("cairo felt types", 5)
+            .await
+            .unwrap();
+        assert_eq!(ids(own), ["katana.md"]);
+
+        let with_common = sensei
+            .clone()
+            .document_index()
+            .with_shared_namespace("common")
+            .top_n_ids("katana sepolia faq", 5)
+            .await
+            .unwrap();
+        assert_eq!(ids(with_common), ["faq.md", "cairo.md"]);
+
+        let shared = shinobi
+            .clone()
+            .with_shared_namespace("common")
+            .document_index()
+            .top_n_ids("katana devnet flags", 5)
+            .await
+            .unwrap();
+        assert_eq!(ids(shared), ["katana.md", "faq.md"]);
+
+        let documents = shinobi
+            .clone()
+            .document_index()
+            .top_n::<serde_json::Value>("katana", 5)
+            .await
+            .unwrap();
+        assert_eq!(documents.len(), 1);
+        assert_eq!(documents[0].2["content"], "katana devnet flags");
+    }
+
+    #[tokio::test]
+    async fn test_side_tables_are_isolated() {
+        let shared = test_utils::knowledge_base().await;
+        let shinobi = shared.clone().with_namespace("shinobi");
+
+        shinobi
+            .add_pin(None, "testnet is sepolia".into(), "mod".into())
+            .await
+            .unwrap();
+        let legacy = shared
+            .add_pin(None, "legacy pin".into(), "mod".into())
+            .await
+            .unwrap();
+
+        let pins = shinobi.pinned_context("c1").await.unwrap();
+        assert_eq!(pins.len(), 1);
+        assert_eq!(pins[0].content, "testnet is sepolia");
+        assert_eq!(pins[0].position, 1);
+        assert!(!shinobi.remove_pin(legacy, "c1").await.unwrap());
+        assert_eq!(shared.namespace(), DEFAULT_NAMESPACE);
+        assert_eq!(shared.pinned_context("c1").await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_filter_searches_past_other_namespaces() {
+        let shared = test_utils::knowledge_base().await;
+        let mut busy = shared.clone().with_namespace("busy");
+        let mut quiet = shared.clone().with_namespace("quiet");
+
+        let docs = (0..20)
+            .map(|i| doc(&format!("busy-{i}.md"), &format!("katana flags {i}")))
+            .collect::<Vec<_>>();
+        busy.add_documents(docs).await.unwrap();
+        quiet
+            .add_documents(vec![doc("quiet.md", "unrelated words entirely")])
+            .await
+            .unwrap();
+
+        let results = quiet
+            .clone()
+            .document_index()
+            .top_n_ids("katana flags 1", 1)
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].1, "quiet.md");
+    }
+
+    #[test]
+    fn test_keywords() {
+        assert_eq!(
+            keywords("How do I set the Katana block time? katana"),
+            ["how", "set", "the", "katana", "block", "time"]
+        );
+        assert!(keywords("is it ok").is_empty());
+    }
+}
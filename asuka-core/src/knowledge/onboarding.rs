@@ -0,0 +1,109 @@
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+use rusqlite::OptionalExtension;
+use serde::{Deserialize, Serialize};
+
+use super::{pending::MESSAGES_WITH_PENDING, store::KnowledgeBase};
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS onboarding_state (
+        agent_id TEXT NOT NULL,
+        account_id TEXT NOT NULL,
+        state TEXT NOT NULL,
+        updated_at TEXT NOT NULL,
+        PRIMARY KEY (agent_id, account_id)
+    );
+";
+
+/// Where a user is in the direct message onboarding, see
+/// [crate::onboarding].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum OnboardingState {
+    /// Waiting for the answer to the question at `step`.
+    Asking {
+        step: usize,
+    },
+    Completed,
+    /// The user asked a question of their own instead of answering.
+    Skipped,
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    pub async fn onboarding_state(
+        &self,
+        account_id: &str,
+    ) -> Result<Option<OnboardingState>, SqliteError> {
+        let namespace = self.namespace.clone();
+        let account_id = account_id.to_string();
+
+        let state = self
+            .conn
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        "SELECT state FROM onboarding_state WHERE agent_id = ?1 AND account_id = ?2",
+                        [&namespace, &account_id],
+                        |row| row.get::<_, String>(0),
+                    )
+                    .optional()?)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))?;
+
+        state
+            .map(|state| serde_json::from_str(&state))
+            .transpose()
+            .map_err(|e| SqliteError::SerializationError(Box::new(e)))
+    }
+
+    pub async fn save_onboarding_state(
+        &self,
+        account_id: &str,
+        state: OnboardingState,
+    ) -> Result<(), SqliteError> {
+        let json = serde_json::to_string(&state)
+            .map_err(|e| SqliteError::SerializationError(Box::new(e)))?;
+        let namespace = self.namespace.clone();
+        let account_id = account_id.to_string();
+        let now = self.clock.now_utc().to_rfc3339();
+
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT INTO onboarding_state (agent_id, account_id, state, updated_at)
+                     VALUES (?1, ?2, ?3, ?4)
+                     ON CONFLICT (agent_id, account_id) DO UPDATE SET
+                         state = excluded.state,
+                         updated_at = excluded.updated_at",
+                    rusqlite::params![namespace, account_id, json, now],
+                )?;
+                Ok(())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Whether any message from the account is stored, including ones still
+    /// waiting to be embedded.
+    pub async fn has_messages_from(&self, account_id: &str) -> Result<bool, SqliteError> {
+        let namespace = self.namespace.clone();
+        let account_id = account_id.to_string();
+
+        self.conn
+            .call(move |conn| {
+                Ok(conn.query_row(
+                    &format!(
+                        "SELECT EXISTS (
+                             SELECT 1 FROM {MESSAGES_WITH_PENDING}
+                             WHERE agent_id = ?1 AND account_id = ?2
+                         )"
+                    ),
+                    [&namespace, &account_id],
+                    |row| row.get(0),
+                )?)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+}
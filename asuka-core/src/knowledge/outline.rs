@@ -0,0 +1,305 @@
+//! Titles and heading structure of markdown and MDX documents, so a retrieved
+//! document can be cited by name rather than by path. The title comes from
+//! the front matter or the first top-level heading; headings inside fenced
+//! code blocks are ignored.
+
+use tracing::info;
+
+use super::{cleaning::front_matter_value, models::Document};
+
+/// Adds the `title` and `section` columns to documents stored before they
+/// existed.
+pub(super) fn migrate(conn: &mut rusqlite::Connection) -> rusqlite::Result<()> {
+    for column in ["title", "section"] {
+        let exists = conn
+            .prepare(&format!(
+                "SELECT 1 FROM pragma_table_info('documents') WHERE name = '{column}'"
+            ))?
+            .exists([])?;
+        if !exists {
+            info!(column, "Adding column to documents");
+            conn.execute_batch(&format!(
+                "ALTER TABLE documents ADD COLUMN {column} TEXT NOT NULL DEFAULT ''"
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Heading {
+    /// 1 for `#`, up to 6 for `######`.
+    pub level: usize,
+    /// Text without markup, e.g. `Using consume_random` for
+    /// ``## Using `consume_random` ``.
+    pub text: String,
+    /// Byte offset of the heading line in the content.
+    pub offset: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Outline {
+    pub title: Option<String>,
+    /// ATX headings in document order.
+    pub headings: Vec<Heading>,
+}
+
+impl Outline {
+    /// Reads the outline of `content`. A front matter block without a closing
+    /// delimiter is read as text, as [super::CleanPass::FrontMatter] does.
+    pub fn parse(content: &str) -> Self {
+        let (front_matter, body) = split_front_matter(content);
+        let headings = headings(content, body);
+        let title = front_matter
+            .and_then(|yaml| front_matter_value(yaml, "title"))
+            .map(|title| inline_text(&title))
+            .filter(|title| !title.is_empty())
+            .or_else(|| {
+                headings
+                    .iter()
+                    .find(|heading| heading.level == 1)
+                    .map(|heading| heading.text.clone())
+            });
+
+        Self { title, headings }
+    }
+
+    /// The chain of headings `offset` sits under, outermost first, such as
+    /// `VRF > Configuration > Fees`. `None` before the first heading.
+    pub fn section_at(&self, offset: usize) -> Option<String> {
+        let mut chain: Vec<&Heading> = Vec::new();
+        for heading in self.headings.iter().take_while(|h| h.offset <= offset) {
+            while chain.last().is_some_and(|last| last.level >= heading.level) {
+                chain.pop();
+            }
+            chain.push(heading);
+        }
+        (!chain.is_empty()).then(|| {
+            chain
+                .iter()
+                .map(|heading| heading.text.as_str())
+                .collect::<Vec<_>>()
+                .join(" > ")
+        })
+    }
+
+    /// Sets the title of `document` unless it already has one. Whole
+    /// documents sit under no section, so [Document::section] is left empty.
+    pub fn apply(&self, document: &mut Document) {
+        if document.title.is_empty() {
+            if let Some(title) = &self.title {
+                document.title = title.clone();
+            }
+        }
+    }
+}
+
+/// The YAML of a leading front matter block, if any, and the offset the body
+/// starts at.
+fn split_front_matter(content: &str) -> (Option<&str>, usize) {
+    let Some(rest) = content
+        .strip_prefix("---\n")
+        .or_else(|| content.strip_prefix("---\r\n"))
+    else {
+        return (None, 0);
+    };
+
+    let start = content.len() - rest.len();
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if line.trim_end() == "---" {
+            return (Some(&rest[..offset]), start + offset + line.len());
+        }
+        offset += line.len();
+    }
+    (None, 0)
+}
+
+fn headings(content: &str, from: usize) -> Vec<Heading> {
+    let mut headings = Vec::new();
+    let mut fence: Option<&str> = None;
+    let mut offset = from;
+    for line in content[from..].split_inclusive('\n') {
+        let start = offset;
+        offset += line.len();
+
+        let trimmed = line.trim_start();
+        if let Some(marker) = fence {
+            if trimmed.starts_with(marker) {
+                fence = None;
+            }
+            continue;
+        }
+        if let Some(marker) = ["```", "~~~"]
+            .into_iter()
+            .find(|marker| trimmed.starts_with(*marker))
+        {
+            fence = Some(marker);
+            continue;
+        }
+
+        if let Some((level, text)) = heading(line) {
+            headings.push(Heading {
+                level,
+                text,
+                offset: start,
+            });
+        }
+    }
+    headings
+}
+
+/// Level and text of an ATX heading line.
+fn heading(line: &str) -> Option<(usize, String)> {
+    let unindented = line.trim_start_matches(' ');
+    if line.len() - unindented.len() > 3 {
+        return None;
+    }
+
+    let line = unindented.trim_end();
+    let level = line.chars().take_while(|c| *c == '#').count();
+    let rest = &line[level..];
+    if !(1..=6).contains(&level) || !(rest.is_empty() || rest.starts_with([' ', '\t'])) {
+        return None;
+    }
+
+    // Optional closing sequence, as in `## Flags ##`
+    let rest = rest.trim();
+    let rest = match rest.trim_end_matches('#') {
+        stripped if stripped.is_empty() || stripped.ends_with([' ', '\t']) => stripped,
+        _ => rest,
+    };
+    let text = inline_text(rest);
+    (!text.is_empty()).then_some((level, text))
+}
+
+/// `text` without inline markup: links keep their text, emphasis and code
+/// markers are dropped, and so is a trailing MDX heading id like `{#fees}`.
+fn inline_text(text: &str) -> String {
+    let text = match text.rfind("{#") {
+        Some(start) if text.trim_end().ends_with('}') => &text[..start],
+        _ => text,
+    };
+
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(open) = rest.find('[') {
+        out.push_str(&rest[..open]);
+        let after = &rest[open + 1..];
+        let link = after.find("](").and_then(|close| {
+            let end = after[close + 2..].find(')')?;
+            Some((close, close + 2 + end))
+        });
+        match link {
+            Some((close, end)) => {
+                out.push_str(&after[..close]);
+                rest = &after[end + 1..];
+            }
+            None => {
+                out.push('[');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+
+    out.replace(['*', '`'], "")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURES: &[(&str, &str, &str)] = &[
+        (
+            "vrf-overview.mdx",
+            include_str!("testdata/outline/vrf-overview.mdx"),
+            include_str!("testdata/outline/vrf-overview.txt"),
+        ),
+        (
+            "first-heading.md",
+            include_str!("testdata/outline/first-heading.md"),
+            include_str!("testdata/outline/first-heading.txt"),
+        ),
+        (
+            "malformed-front-matter.md",
+            include_str!("testdata/outline/malformed-front-matter.md"),
+            include_str!("testdata/outline/malformed-front-matter.txt"),
+        ),
+        (
+            "no-headings.md",
+            include_str!("testdata/outline/no-headings.md"),
+            include_str!("testdata/outline/no-headings.txt"),
+        ),
+    ];
+
+    /// The title, then the section of each heading.
+    fn describe(outline: &Outline) -> String {
+        let mut lines = vec![format!(
+            "title: {}",
+            outline.title.as_deref().unwrap_or("-")
+        )];
+        lines.extend(
+            outline
+                .headings
+                .iter()
+                .filter_map(|heading| outline.section_at(heading.offset)),
+        );
+        lines.join("\n") + "\n"
+    }
+
+    #[test]
+    fn test_golden_files() {
+        for (name, input, expected) in FIXTURES {
+            assert_eq!(describe(&Outline::parse(input)), *expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn test_section_at() {
+        let content = include_str!("testdata/outline/vrf-overview.mdx");
+        let outline = Outline::parse(content);
+
+        assert_eq!(outline.section_at(0), None);
+        assert_eq!(
+            outline
+                .section_at(content.find("flat fee").unwrap())
+                .as_deref(),
+            Some("VRF > Configuration > Fees")
+        );
+        // Inside the code block, still under the last heading before it
+        assert_eq!(
+            outline
+                .section_at(content.find("# not a heading").unwrap())
+                .as_deref(),
+            Some("VRF > Configuration > Fees")
+        );
+    }
+
+    #[test]
+    fn test_apply_keeps_existing_title() {
+        let mut document = Document {
+            id: "docs/vrf.mdx".to_string(),
+            source_id: "github".to_string(),
+            content: "# VRF\n".to_string(),
+            created_at: chrono::Utc::now(),
+            title: String::new(),
+            section: String::new(),
+            topics: Vec::new(),
+            logical_id: None,
+            cleaned: None,
+            chunk: None,
+        };
+        let outline = Outline::parse(&document.content);
+
+        outline.apply(&mut document);
+        assert_eq!(document.title, "VRF");
+
+        document.title = "Randomness".to_string();
+        outline.apply(&mut document);
+        assert_eq!(document.title, "Randomness");
+    }
+}
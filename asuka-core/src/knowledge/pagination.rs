@@ -0,0 +1,321 @@
+//! Keyset pagination over messages and documents.
+//!
+//! Pages are ordered by `(created_at, id)` ascending, oldest first, and each
+//! page resumes strictly after the last row of the previous one. Because the
+//! position is a value rather than an offset:
+//!
+//! - rows inserted while paginating never shift or duplicate earlier pages; new
+//!   rows appear on a later page once their `(created_at, id)` sorts after the
+//!   cursor. A row inserted with an older `created_at` than the cursor (e.g. a
+//!   backfilled message) is not revisited, so use [KnowledgeBase::messages_since]
+//!   with an overlap window if that matters.
+//! - rows deleted between pages are simply absent, including the row the cursor
+//!   points at; the cursor keeps working since it never has to be found again.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+use rusqlite::Row;
+
+use super::{
+    models::{Document, Message},
+    store::KnowledgeBase,
+};
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid pagination cursor")]
+pub struct CursorError;
+
+/// Position after a row. Opaque to callers: [Cursor::encode] yields a
+/// URL-safe base64 token and [Cursor::decode] reads it back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cursor {
+    created_at: String,
+    id: String,
+}
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        let tuple = serde_json::json!([self.created_at, self.id]);
+        URL_SAFE_NO_PAD.encode(tuple.to_string())
+    }
+
+    pub fn decode(token: &str) -> Result<Self, CursorError> {
+        let bytes = URL_SAFE_NO_PAD.decode(token).map_err(|_| CursorError)?;
+        let (created_at, id): (String, String) =
+            serde_json::from_slice(&bytes).map_err(|_| CursorError)?;
+        Ok(Self { created_at, id })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Resume point for the next call. Set even on the last page so a caller
+    /// can poll for new rows later; unchanged from the request when empty.
+    pub next_cursor: Option<Cursor>,
+    pub has_more: bool,
+}
+
+const MESSAGE_COLUMNS: &str = Message::COLUMNS;
+const DOCUMENT_COLUMNS: &str = Document::COLUMNS;
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    pub async fn list_messages(
+        &self,
+        after: Option<Cursor>,
+        page_size: usize,
+    ) -> Result<Page<Message>, SqliteError> {
+        self.list_page("messages", MESSAGE_COLUMNS, None, after, page_size, |row| {
+            Message::try_from(row)
+        })
+        .await
+    }
+
+    pub async fn list_documents(
+        &self,
+        after: Option<Cursor>,
+        page_size: usize,
+    ) -> Result<Page<Document>, SqliteError> {
+        self.list_page(
+            "documents",
+            DOCUMENT_COLUMNS,
+            None,
+            after,
+            page_size,
+            |row| Document::try_from(row),
+        )
+        .await
+    }
+
+    /// Like [KnowledgeBase::list_documents], limited to documents from one
+    /// source, e.g. `github`.
+    pub async fn list_documents_from(
+        &self,
+        source_id: &str,
+        after: Option<Cursor>,
+        page_size: usize,
+    ) -> Result<Page<Document>, SqliteError> {
+        let source_id = Some(source_id.to_string());
+        self.list_page(
+            "documents",
+            DOCUMENT_COLUMNS,
+            source_id,
+            after,
+            page_size,
+            |row| Document::try_from(row),
+        )
+        .await
+    }
+
+    /// Every message created after `since`, oldest first.
+    pub async fn messages_since(
+        &self,
+        since: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Vec<Message>, SqliteError> {
+        let since = since.to_rfc3339();
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT {MESSAGE_COLUMNS} FROM messages
+                     WHERE agent_id = ?2 AND created_at > ?1
+                     ORDER BY created_at, id"
+                ))?;
+
+                let messages = stmt
+                    .query_map(rusqlite::params![since, namespace], |row| {
+                        Message::try_from(row)
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(messages)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    async fn list_page<T: Send + 'static>(
+        &self,
+        table: &'static str,
+        columns: &'static str,
+        source_id: Option<String>,
+        after: Option<Cursor>,
+        page_size: usize,
+        map: fn(&Row) -> rusqlite::Result<T>,
+    ) -> Result<Page<T>, SqliteError> {
+        let page_size = page_size.max(1);
+        let (created_at, id) = match &after {
+            Some(cursor) => (Some(cursor.created_at.clone()), Some(cursor.id.clone())),
+            None => (None, None),
+        };
+        let namespace = self.namespace.clone();
+
+        let (rows, has_more) = self
+            .conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT {columns}, created_at, id FROM {table}
+                     WHERE agent_id = ?4 AND (?1 IS NULL OR (created_at, id) > (?1, ?2))
+                         AND (?5 IS NULL OR source_id = ?5)
+                     ORDER BY created_at, id
+                     LIMIT ?3"
+                ))?;
+
+                let width = columns.split(',').count();
+                let mut rows = stmt
+                    .query_map(
+                        rusqlite::params![
+                            created_at,
+                            id,
+                            page_size as i64 + 1,
+                            namespace,
+                            source_id
+                        ],
+                        |row| {
+                            let cursor = Cursor {
+                                created_at: row.get(width)?,
+                                id: row.get(width + 1)?,
+                            };
+                            Ok((map(row)?, cursor))
+                        },
+                    )?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                // The extra row only tells us whether another page exists.
+                let has_more = rows.len() > page_size;
+                rows.truncate(page_size);
+                Ok((rows, has_more))
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))?;
+
+        let next_cursor = rows.last().map(|(_, cursor)| cursor.clone()).or(after);
+        Ok(Page {
+            items: rows.into_iter().map(|(item, _)| item).collect(),
+            next_cursor,
+            has_more,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{knowledge::ChannelType, knowledge::Source, test_utils};
+    use chrono::{DateTime, Utc};
+
+    fn message(id: &str, created_at: DateTime<Utc>) -> Message {
+        Message {
+            id: id.to_string(),
+            source: Source::Discord,
+            source_id: "user".to_string(),
+            channel_type: ChannelType::Text,
+            channel_id: "channel".to_string(),
+            account_id: "user".to_string(),
+            role: "user".to_string(),
+            content: format!("message {id}"),
+            created_at,
+        }
+    }
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000 + seconds, 0).unwrap()
+    }
+
+    #[test]
+    fn test_cursor_round_trip() {
+        let cursor = Cursor {
+            created_at: at(0).to_rfc3339(),
+            id: "a/b?c".to_string(),
+        };
+        let token = cursor.encode();
+        assert!(!token.contains('/') && !token.contains('+'));
+        assert_eq!(Cursor::decode(&token).unwrap(), cursor);
+        assert!(Cursor::decode("not a cursor").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_paginate_while_inserting_and_deleting() {
+        let knowledge = test_utils::knowledge_base().await;
+        // Shared timestamps exercise the id tie-breaker.
+        for (id, seconds) in [("m1", 1), ("m2", 2), ("m3", 2), ("m4", 3), ("m5", 4)] {
+            knowledge
+                .create_message(message(id, at(seconds)))
+                .await
+                .unwrap();
+        }
+
+        let mut seen = Vec::new();
+        let first = knowledge.list_messages(None, 2).await.unwrap();
+        assert!(first.has_more);
+        seen.extend(first.items.iter().map(|m| m.id.clone()));
+        assert_eq!(seen, ["m1", "m2"]);
+
+        // A new row and the deletion of the cursor row between pages.
+        knowledge
+            .create_message(message("m6", at(5)))
+            .await
+            .unwrap();
+        knowledge
+            .conn
+            .call(|conn| Ok(conn.execute("DELETE FROM messages WHERE id = 'm2'", [])?))
+            .await
+            .unwrap();
+
+        let mut cursor = first.next_cursor;
+        loop {
+            let page = knowledge.list_messages(cursor.clone(), 2).await.unwrap();
+            seen.extend(page.items.iter().map(|m| m.id.clone()));
+            cursor = page.next_cursor;
+            if !page.has_more {
+                break;
+            }
+        }
+        assert_eq!(seen, ["m1", "m2", "m3", "m4", "m5", "m6"]);
+
+        // Polling from the final cursor yields nothing until something is added.
+        let empty = knowledge.list_messages(cursor.clone(), 2).await.unwrap();
+        assert!(empty.items.is_empty());
+        assert_eq!(empty.next_cursor, cursor);
+
+        let since = knowledge.messages_since(at(3)).await.unwrap();
+        assert_eq!(
+            since.iter().map(|m| m.id.as_str()).collect::<Vec<_>>(),
+            ["m5", "m6"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_documents() {
+        let mut knowledge = test_utils::knowledge_base().await;
+        let documents = (0..5).map(|i| Document {
+            id: format!("doc-{i}"),
+            source_id: "test".to_string(),
+            content: format!("document {i}"),
+            created_at: at(i),
+            title: String::new(),
+            section: String::new(),
+            topics: Vec::new(),
+            logical_id: None,
+            cleaned: None,
+            chunk: None,
+        });
+        knowledge.add_documents(documents).await.unwrap();
+
+        let first = knowledge.list_documents(None, 3).await.unwrap();
+        let cursor = Cursor::decode(&first.next_cursor.unwrap().encode()).unwrap();
+        let second = knowledge.list_documents(Some(cursor), 3).await.unwrap();
+
+        assert!(first.has_more);
+        assert!(!second.has_more);
+        let ids: Vec<_> = first
+            .items
+            .iter()
+            .chain(&second.items)
+            .map(|d| d.id.as_str())
+            .collect();
+        assert_eq!(ids, ["doc-0", "doc-1", "doc-2", "doc-3", "doc-4"]);
+    }
+}
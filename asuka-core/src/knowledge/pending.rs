@@ -0,0 +1,402 @@
+//! Messages that could not be embedded or stored when they arrived, e.g.
+//! while the embedding API is down.
+//!
+//! They wait in `pending_messages` without an embedding, where history
+//! queries still find them, until [KnowledgeBase::drain_pending] stores them.
+//! Each failed retry backs the row off exponentially; after
+//! [RetryPolicy::poison_after] failures it is left for manual review, see
+//! [KnowledgeBase::poisoned_messages].
+
+use std::time::Duration;
+
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+use tracing::{debug, error, info, warn};
+
+use super::{models::Message, store::KnowledgeBase};
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS pending_messages (
+        id TEXT NOT NULL,
+        agent_id TEXT NOT NULL,
+        source TEXT NOT NULL,
+        source_id TEXT NOT NULL,
+        channel_type TEXT NOT NULL,
+        channel_id TEXT NOT NULL,
+        account_id TEXT NOT NULL,
+        role TEXT NOT NULL,
+        content TEXT NOT NULL,
+        created_at TEXT NOT NULL,
+        attempts INTEGER NOT NULL DEFAULT 0,
+        next_attempt_at INTEGER NOT NULL DEFAULT 0,
+        last_error TEXT,
+        poisoned INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (agent_id, id)
+    );
+    CREATE INDEX IF NOT EXISTS idx_pending_messages_channel ON pending_messages(agent_id, channel_id);
+";
+
+/// Stored and pending messages together, for history queries.
+pub(super) const MESSAGES_WITH_PENDING: &str = "(
+    SELECT id, source, source_id, channel_type, channel_id, account_id, role, content, created_at, agent_id
+    FROM messages
+    UNION ALL
+    SELECT id, source, source_id, channel_type, channel_id, account_id, role, content, created_at, agent_id
+    FROM pending_messages
+)";
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Failed retries after which a message is left for manual review.
+    pub poison_after: u32,
+    /// Time between two drains of the queue.
+    pub poll_interval: Duration,
+    /// Messages retried per drain.
+    pub batch_size: usize,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(30),
+            max_backoff: Duration::from_secs(3600),
+            poison_after: 10,
+            poll_interval: Duration::from_secs(30),
+            batch_size: 32,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff)
+    }
+}
+
+/// Outcome of one [KnowledgeBase::drain_pending].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DrainSummary {
+    pub promoted: usize,
+    pub failed: usize,
+    /// Of the failed, those that reached [RetryPolicy::poison_after].
+    pub poisoned: usize,
+}
+
+/// Whether storing failed because the message is already stored, which no
+/// retry can fix.
+pub(super) fn is_duplicate(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<tokio_rusqlite::Error>(),
+        Some(tokio_rusqlite::Error::Rusqlite(rusqlite::Error::SqliteFailure(failure, _)))
+            if failure.code == rusqlite::ErrorCode::ConstraintViolation
+    )
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Queues a message that failed to store. Returns the rowid of its
+    /// pending row.
+    pub(super) async fn queue_message(
+        &self,
+        msg: Message,
+        error: &str,
+    ) -> Result<i64, SqliteError> {
+        let namespace = self.namespace.clone();
+        let error = error.to_string();
+
+        self.conn
+            .call(move |conn| {
+                Ok(conn.query_row(
+                    "INSERT INTO pending_messages (id, agent_id, source, source_id, channel_type,
+                         channel_id, account_id, role, content, created_at, last_error)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+                     ON CONFLICT (agent_id, id) DO UPDATE SET last_error = excluded.last_error
+                     RETURNING rowid",
+                    rusqlite::params![
+                        msg.id,
+                        namespace,
+                        msg.source.as_str(),
+                        msg.source_id,
+                        msg.channel_type.as_str(),
+                        msg.channel_id,
+                        msg.account_id,
+                        msg.role,
+                        msg.content,
+                        msg.created_at.to_rfc3339(),
+                        error
+                    ],
+                    |row| row.get(0),
+                )?)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Retries the pending messages that are due, storing those that now
+    /// embed and backing off the others. Does nothing while the embedding
+    /// circuit is open, so an outage doesn't use up their attempts.
+    pub async fn drain_pending(&self, policy: &RetryPolicy) -> Result<DrainSummary, SqliteError> {
+        if self.embedding_service().is_open() {
+            debug!("Embedding circuit open, not draining pending messages");
+            return Ok(DrainSummary::default());
+        }
+        let namespace = self.namespace.clone();
+        let batch_size = policy.batch_size;
+        let now = self.clock.now_utc().timestamp_millis();
+
+        let due = self
+            .conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT {}, attempts FROM pending_messages
+                     WHERE agent_id = ?1 AND poisoned = 0 AND next_attempt_at <= ?2
+                     ORDER BY created_at
+                     LIMIT ?3",
+                    Message::COLUMNS
+                ))?;
+                let due = stmt
+                    .query_map(rusqlite::params![namespace, now, batch_size], |row| {
+                        Ok((Message::try_from(row)?, row.get::<_, u32>(9)?))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(due)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))?;
+
+        let mut summary = DrainSummary::default();
+        for (msg, attempts) in due {
+            let id = msg.id.clone();
+            let err = match self.store_message(msg, true).await {
+                Ok(_) => {
+                    summary.promoted += 1;
+                    continue;
+                }
+                // Stored by an earlier drain that failed before removing it.
+                Err(err) if is_duplicate(&err) => {
+                    self.remove_pending(&id).await?;
+                    summary.promoted += 1;
+                    continue;
+                }
+                Err(err) => err,
+            };
+
+            let attempts = attempts + 1;
+            let poisoned = attempts >= policy.poison_after;
+            summary.failed += 1;
+            if poisoned {
+                summary.poisoned += 1;
+                error!(?err, id, attempts, "Giving up on pending message");
+            } else {
+                warn!(?err, id, attempts, "Pending message failed again");
+            }
+            let next_attempt_at = self.clock.now_utc().timestamp_millis()
+                + policy.backoff(attempts).as_millis() as i64;
+            self.record_failure(&id, attempts, next_attempt_at, &err.to_string(), poisoned)
+                .await?;
+        }
+
+        if summary != DrainSummary::default() {
+            info!(?summary, "Drained pending messages");
+        }
+        Ok(summary)
+    }
+
+    /// Pending messages that reached [RetryPolicy::poison_after], with the
+    /// last error of each.
+    pub async fn poisoned_messages(&self) -> Result<Vec<(Message, String)>, SqliteError> {
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT {}, COALESCE(last_error, '') FROM pending_messages
+                     WHERE agent_id = ?1 AND poisoned = 1
+                     ORDER BY created_at",
+                    Message::COLUMNS
+                ))?;
+                let messages = stmt
+                    .query_map([&namespace], |row| {
+                        Ok((Message::try_from(row)?, row.get(9)?))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(messages)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Drains the queue every [RetryPolicy::poll_interval] until the task is
+    /// aborted.
+    pub fn spawn_pending_retries(&self, policy: RetryPolicy) -> tokio::task::JoinHandle<()> {
+        let knowledge = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(policy.poll_interval).await;
+                if let Err(err) = knowledge.drain_pending(&policy).await {
+                    error!(?err, "Failed to drain pending messages");
+                }
+            }
+        })
+    }
+
+    async fn remove_pending(&self, id: &str) -> Result<(), SqliteError> {
+        let id = id.to_string();
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "DELETE FROM pending_messages WHERE agent_id = ?1 AND id = ?2",
+                    [&namespace, &id],
+                )?;
+                Ok(())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    async fn record_failure(
+        &self,
+        id: &str,
+        attempts: u32,
+        next_attempt_at: i64,
+        error: &str,
+        poisoned: bool,
+    ) -> Result<(), SqliteError> {
+        let id = id.to_string();
+        let error = error.to_string();
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "UPDATE pending_messages
+                     SET attempts = ?3, next_attempt_at = ?4, last_error = ?5, poisoned = ?6
+                     WHERE agent_id = ?1 AND id = ?2",
+                    rusqlite::params![namespace, id, attempts, next_attempt_at, error, poisoned],
+                )?;
+                Ok(())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        knowledge::{ChannelType, Source},
+        test_utils::{self, FlakyEmbeddingModel},
+    };
+
+    fn message(id: &str, content: &str) -> Message {
+        Message {
+            id: id.to_string(),
+            source: Source::Discord,
+            source_id: "alice".to_string(),
+            channel_type: ChannelType::Text,
+            channel_id: "c1".to_string(),
+            account_id: "alice".to_string(),
+            role: "user".to_string(),
+            content: content.to_string(),
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    fn policy(poison_after: u32) -> RetryPolicy {
+        RetryPolicy {
+            initial_backoff: Duration::ZERO,
+            poison_after,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_messages_are_promoted_once_embedding_recovers() {
+        let knowledge = test_utils::knowledge_base_with(FlakyEmbeddingModel::failing(2)).await;
+
+        knowledge
+            .create_message(message("m1", "is the paymaster down?"))
+            .await
+            .unwrap();
+        assert!(knowledge.message_exists("m1").await.unwrap());
+        assert_eq!(
+            knowledge.channel_messages("c1", 10).await.unwrap(),
+            [("alice".to_string(), "is the paymaster down?".to_string())]
+        );
+
+        let summary = knowledge.drain_pending(&policy(5)).await.unwrap();
+        assert_eq!(
+            summary,
+            DrainSummary {
+                promoted: 0,
+                failed: 1,
+                poisoned: 0
+            }
+        );
+
+        // The model recovered: later messages store directly and the queued
+        // one is promoted.
+        knowledge
+            .create_message(message("m2", "hello?"))
+            .await
+            .unwrap();
+        let summary = knowledge.drain_pending(&policy(5)).await.unwrap();
+        assert_eq!(summary.promoted, 1);
+        assert_eq!(
+            knowledge.drain_pending(&policy(5)).await.unwrap(),
+            DrainSummary::default()
+        );
+
+        let history = knowledge.channel_messages("c1", 10).await.unwrap();
+        assert_eq!(history.len(), 2);
+        let stored: usize = knowledge
+            .conn
+            .call(|conn| {
+                Ok(conn.query_row(
+                    "SELECT COUNT(*) FROM messages m
+                     JOIN messages_embeddings e ON e.rowid = m.rowid",
+                    [],
+                    |row| row.get(0),
+                )?)
+            })
+            .await
+            .unwrap();
+        assert_eq!(stored, 2);
+    }
+
+    #[tokio::test]
+    async fn test_messages_are_poisoned_after_repeated_failures() {
+        let knowledge = test_utils::knowledge_base_with(FlakyEmbeddingModel::failing(10)).await;
+        knowledge.create_message(message("m1", "gm")).await.unwrap();
+
+        assert_eq!(knowledge.drain_pending(&policy(2)).await.unwrap().failed, 1);
+        assert_eq!(
+            knowledge.drain_pending(&policy(2)).await.unwrap().poisoned,
+            1
+        );
+        assert_eq!(
+            knowledge.drain_pending(&policy(2)).await.unwrap(),
+            DrainSummary::default()
+        );
+
+        let poisoned = knowledge.poisoned_messages().await.unwrap();
+        assert_eq!(poisoned.len(), 1);
+        assert_eq!(poisoned[0].0.id, "m1");
+        assert!(poisoned[0].1.contains("embedding API unavailable"));
+        // Still part of the history.
+        assert_eq!(knowledge.channel_messages("c1", 10).await.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.backoff(1), Duration::from_secs(60));
+        assert_eq!(policy.backoff(20), Duration::from_secs(3600));
+    }
+}
@@ -0,0 +1,234 @@
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+use rusqlite::Row;
+use tracing::{debug, warn};
+
+use super::store::KnowledgeBase;
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS pinned_context (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        channel_id TEXT,
+        content TEXT NOT NULL,
+        added_by TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE INDEX IF NOT EXISTS idx_pinned_context_channel ON pinned_context(channel_id, position);
+";
+
+/// A curated fact that is always included in the prompt. Pins without a
+/// `channel_id` apply to every channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PinnedContext {
+    pub id: i64,
+    pub channel_id: Option<String>,
+    pub content: String,
+    pub added_by: String,
+    pub position: i64,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl TryFrom<&Row<'_>> for PinnedContext {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &Row) -> Result<Self, Self::Error> {
+        Ok(PinnedContext {
+            id: row.get(0)?,
+            channel_id: row.get(1)?,
+            content: row.get(2)?,
+            added_by: row.get(3)?,
+            position: row.get(4)?,
+            created_at: row.get(5)?,
+        })
+    }
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Appends a pin to the end of its scope and returns its id.
+    pub async fn add_pin(
+        &self,
+        channel_id: Option<String>,
+        content: String,
+        added_by: String,
+    ) -> Result<i64, SqliteError> {
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                Ok(conn.query_row(
+                    "INSERT INTO pinned_context (channel_id, content, added_by, position, agent_id)
+                     VALUES (?1, ?2, ?3, (
+                         SELECT COALESCE(MAX(position), 0) + 1 FROM pinned_context
+                         WHERE agent_id = ?4 AND channel_id IS ?1
+                     ), ?4)
+                     RETURNING id",
+                    rusqlite::params![channel_id, content, added_by, namespace],
+                    |row| row.get(0),
+                )?)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Removes a pin that applies to a channel, a global one or the channel's
+    /// own, returning whether it existed. Pins of other channels are left.
+    pub async fn remove_pin(&self, id: i64, channel_id: &str) -> Result<bool, SqliteError> {
+        let channel_id = channel_id.to_string();
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                Ok(conn.execute(
+                    "DELETE FROM pinned_context
+                     WHERE id = ?1 AND agent_id = ?2 AND (channel_id IS NULL OR channel_id = ?3)",
+                    rusqlite::params![id, namespace, channel_id],
+                )? > 0)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Lists the pins that apply to a channel: global pins first, then the
+    /// channel's own, each in position order.
+    pub async fn pinned_context(
+        &self,
+        channel_id: &str,
+    ) -> Result<Vec<PinnedContext>, SqliteError> {
+        let channel_id = channel_id.to_string();
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT id, channel_id, content, added_by, position, created_at
+                     FROM pinned_context
+                     WHERE agent_id = ?2 AND (channel_id IS NULL OR channel_id = ?1)
+                     ORDER BY channel_id IS NOT NULL, position, id",
+                )?;
+
+                let pins = stmt
+                    .query_map(rusqlite::params![channel_id, namespace], |row| {
+                        PinnedContext::try_from(row)
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(pins)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+}
+
+/// Keeps pins within `max_chars` combined, dropping the oldest first so the
+/// most recently curated facts survive. Order of the kept pins is preserved.
+pub fn fit_pins(mut pins: Vec<PinnedContext>, max_chars: usize) -> Vec<PinnedContext> {
+    let mut total: usize = pins.iter().map(|pin| pin.content.len()).sum();
+
+    while total > max_chars {
+        let Some(oldest) = pins
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, pin)| (pin.created_at, pin.id))
+            .map(|(index, _)| index)
+        else {
+            break;
+        };
+
+        let pin = pins.remove(oldest);
+        total -= pin.content.len();
+        warn!(
+            id = pin.id,
+            channel_id = ?pin.channel_id,
+            content = pin.content,
+            "Trimmed pinned context over size cap"
+        );
+    }
+
+    debug!(count = pins.len(), chars = total, "Pinned context fitted");
+    pins
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn test_pins_are_ordered_global_first() {
+        let knowledge = test_utils::knowledge_base().await;
+
+        knowledge
+            .add_pin(Some("c1".into()), "channel one".into(), "mod".into())
+            .await
+            .unwrap();
+        knowledge
+            .add_pin(None, "global one".into(), "mod".into())
+            .await
+            .unwrap();
+        knowledge
+            .add_pin(Some("c1".into()), "channel two".into(), "mod".into())
+            .await
+            .unwrap();
+        knowledge
+            .add_pin(None, "global two".into(), "mod".into())
+            .await
+            .unwrap();
+
+        let pins = knowledge.pinned_context("c1").await.unwrap();
+        let contents: Vec<_> = pins.iter().map(|pin| pin.content.as_str()).collect();
+        assert_eq!(
+            contents,
+            ["global one", "global two", "channel one", "channel two"]
+        );
+        assert_eq!(
+            pins.iter().map(|pin| pin.position).collect::<Vec<_>>(),
+            [1, 2, 1, 2]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_channel_pins_do_not_leak() {
+        let knowledge = test_utils::knowledge_base().await;
+
+        knowledge
+            .add_pin(None, "testnet is sepolia".into(), "mod".into())
+            .await
+            .unwrap();
+        let private = knowledge
+            .add_pin(Some("c1".into()), "c1 only".into(), "mod".into())
+            .await
+            .unwrap();
+
+        let pins = knowledge.pinned_context("c2").await.unwrap();
+        assert_eq!(pins.len(), 1);
+        assert_eq!(pins[0].content, "testnet is sepolia");
+
+        // Another channel can't unpin it
+        assert!(!knowledge.remove_pin(private, "c2").await.unwrap());
+        assert!(knowledge.remove_pin(private, "c1").await.unwrap());
+        assert!(!knowledge.remove_pin(private, "c1").await.unwrap());
+        assert_eq!(knowledge.pinned_context("c1").await.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_size_cap_trims_oldest_first() {
+        let pin = |id: i64, content: &str, position: i64| PinnedContext {
+            id,
+            channel_id: None,
+            content: content.to_string(),
+            added_by: "mod".to_string(),
+            position,
+            created_at: chrono::DateTime::from_timestamp(1_700_000_000 + id, 0).unwrap(),
+        };
+
+        // Display order differs from age: the oldest pin sits in the middle.
+        let pins = vec![pin(2, "bbbb", 1), pin(1, "aaaa", 2), pin(3, "cccc", 3)];
+
+        let fitted = fit_pins(pins.clone(), 8);
+        assert_eq!(fitted.iter().map(|pin| pin.id).collect::<Vec<_>>(), [2, 3]);
+
+        assert_eq!(fit_pins(pins.clone(), 100), pins);
+        assert!(fit_pins(pins, 3).is_empty());
+    }
+}
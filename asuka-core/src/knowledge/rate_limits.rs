@@ -0,0 +1,191 @@
+use chrono::{DateTime, Utc};
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+
+use super::{store::KnowledgeBase, types::Source};
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS rate_limit_events (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        agent_id TEXT NOT NULL,
+        source TEXT NOT NULL,
+        account_id TEXT NOT NULL,
+        event TEXT NOT NULL,
+        created_at TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS idx_rate_limit_events_account
+        ON rate_limit_events(agent_id, source, account_id, created_at);
+    CREATE INDEX IF NOT EXISTS idx_rate_limit_events_created
+        ON rate_limit_events(agent_id, created_at);
+";
+
+/// Days events are kept, long enough for the daily digest to count them.
+const KEPT_DAYS: i64 = 7;
+
+/// What happened to a user's message under the
+/// [rate limit](crate::rate_limit).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateEvent {
+    /// The message was answered.
+    Response,
+    /// The user was told they are over the limit.
+    Notice,
+    /// The message was ignored for being over the limit.
+    Suppressed,
+}
+
+impl RateEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RateEvent::Response => "response",
+            RateEvent::Notice => "notice",
+            RateEvent::Suppressed => "suppressed",
+        }
+    }
+
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "response" => Some(RateEvent::Response),
+            "notice" => Some(RateEvent::Notice),
+            "suppressed" => Some(RateEvent::Suppressed),
+            _ => None,
+        }
+    }
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Records `event` for a user at `at`, and forgets the user's events
+    /// older than a week.
+    pub async fn record_rate_event(
+        &self,
+        source: &Source,
+        account_id: &str,
+        event: RateEvent,
+        at: DateTime<Utc>,
+    ) -> Result<(), SqliteError> {
+        let namespace = self.namespace.clone();
+        let source = source.as_str().to_string();
+        let account_id = account_id.to_string();
+        let expired = (at - chrono::Duration::days(KEPT_DAYS)).to_rfc3339();
+
+        self.conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                tx.execute(
+                    "INSERT INTO rate_limit_events (agent_id, source, account_id, event, created_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    rusqlite::params![namespace, source, account_id, event.as_str(), at.to_rfc3339()],
+                )?;
+                tx.execute(
+                    "DELETE FROM rate_limit_events
+                     WHERE agent_id = ?1 AND source = ?2 AND account_id = ?3 AND created_at < ?4",
+                    rusqlite::params![namespace, source, account_id, expired],
+                )?;
+                tx.commit()?;
+                Ok(())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// A user's events from `since` on, oldest first.
+    pub async fn rate_events(
+        &self,
+        source: &Source,
+        account_id: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<(RateEvent, DateTime<Utc>)>, SqliteError> {
+        let namespace = self.namespace.clone();
+        let source = source.as_str().to_string();
+        let account_id = account_id.to_string();
+
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT event, created_at FROM rate_limit_events
+                     WHERE agent_id = ?1 AND source = ?2 AND account_id = ?3 AND created_at >= ?4
+                     ORDER BY created_at, id",
+                )?;
+                let events = stmt
+                    .query_map(
+                        rusqlite::params![namespace, source, account_id, since.to_rfc3339()],
+                        |row| Ok((row.get::<_, String>(0)?, row.get(1)?)),
+                    )?
+                    .collect::<Result<Vec<(String, DateTime<Utc>)>, _>>()?;
+                Ok(events
+                    .into_iter()
+                    .filter_map(|(event, at)| Some((RateEvent::from_str(&event)?, at)))
+                    .collect())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn test_events_are_scoped_and_expire() {
+        let knowledge = test_utils::knowledge_base().await;
+        let other = knowledge.clone().with_namespace("other");
+        let at = |minutes: i64| {
+            DateTime::from_timestamp(1_730_800_000, 0).unwrap() + chrono::Duration::minutes(minutes)
+        };
+
+        knowledge
+            .record_rate_event(&Source::Discord, "u1", RateEvent::Response, at(0))
+            .await
+            .unwrap();
+        knowledge
+            .record_rate_event(&Source::Discord, "u1", RateEvent::Notice, at(5))
+            .await
+            .unwrap();
+        knowledge
+            .record_rate_event(&Source::Telegram, "u1", RateEvent::Response, at(5))
+            .await
+            .unwrap();
+        other
+            .record_rate_event(&Source::Discord, "u1", RateEvent::Response, at(5))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            knowledge
+                .rate_events(&Source::Discord, "u1", at(0))
+                .await
+                .unwrap(),
+            [(RateEvent::Response, at(0)), (RateEvent::Notice, at(5))]
+        );
+        assert_eq!(
+            knowledge
+                .rate_events(&Source::Discord, "u1", at(1))
+                .await
+                .unwrap(),
+            [(RateEvent::Notice, at(5))]
+        );
+
+        // A week later the old events are gone
+        knowledge
+            .record_rate_event(
+                &Source::Discord,
+                "u1",
+                RateEvent::Suppressed,
+                at(7 * 1440 + 1),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            knowledge
+                .rate_events(&Source::Discord, "u1", at(0))
+                .await
+                .unwrap(),
+            [
+                (RateEvent::Notice, at(5)),
+                (RateEvent::Suppressed, at(7 * 1440 + 1))
+            ]
+        );
+    }
+}
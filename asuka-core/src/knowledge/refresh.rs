@@ -0,0 +1,547 @@
+//! On-demand re-sync of knowledge sources. A [RefreshRegistry] maps source
+//! names to [RefreshSource]s; refreshing one pulls its current documents and
+//! stores only the new and changed ones, each changed document as a new
+//! version of its logical document.
+//!
+//! Refreshes are rate limited per source, and the time of the last refresh is
+//! kept in the `knowledge_refreshes` table so the limit holds across restarts.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use rig::embeddings::{EmbeddingModel, EmbeddingsBuilder};
+use rig_sqlite::SqliteError;
+use rusqlite::OptionalExtension;
+use thiserror::Error;
+use tracing::{error, info};
+
+use super::{
+    cleaning,
+    gaps::cosine_similarity,
+    identity::{content_hash, RENAME_SIMILARITY},
+    models::Document,
+    store::KnowledgeBase,
+};
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS knowledge_refreshes (
+        source TEXT PRIMARY KEY,
+        refreshed_at TIMESTAMP NOT NULL
+    );
+";
+
+/// Minimum time between two refreshes of the same source.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// A source whose documents can be re-read on demand.
+#[async_trait]
+pub trait RefreshSource: Send + Sync {
+    /// Brings the source up to date and returns every document it holds.
+    /// Unchanged documents are skipped when stored.
+    async fn sync_changes(&self) -> anyhow::Result<Vec<Document>>;
+}
+
+#[derive(Error, Debug)]
+pub enum RefreshError {
+    #[error("Unknown source {name:?}, expected one of: {known}")]
+    UnknownSource { name: String, known: String },
+    #[error("{name} was refreshed recently, try again in {minutes} minutes")]
+    RateLimited { name: String, minutes: u64 },
+    #[error("Failed to sync {name}: {error}")]
+    Sync { name: String, error: String },
+    #[error("Failed to store documents: {0}")]
+    Store(String),
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RefreshSummary {
+    pub added: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    /// Documents whose file moved, see
+    /// [KnowledgeBase::refresh_moved_documents].
+    pub renamed: usize,
+}
+
+impl fmt::Display for RefreshSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} added, {} updated", self.added, self.updated)?;
+        if self.unchanged > 0 {
+            write!(f, ", {} unchanged", self.unchanged)?;
+        }
+        if self.renamed > 0 {
+            write!(f, ", {} renamed", self.renamed)?;
+        }
+        Ok(())
+    }
+}
+
+/// Named [RefreshSource]s and the knowledge base they refresh.
+#[derive(Clone)]
+pub struct RefreshRegistry<E: EmbeddingModel + 'static> {
+    knowledge: KnowledgeBase<E>,
+    sources: BTreeMap<String, Arc<dyn RefreshSource>>,
+    interval: Duration,
+}
+
+impl<E: EmbeddingModel> RefreshRegistry<E> {
+    pub fn new(knowledge: KnowledgeBase<E>) -> Self {
+        Self {
+            knowledge,
+            sources: BTreeMap::new(),
+            interval: DEFAULT_INTERVAL,
+        }
+    }
+
+    pub fn with_source(mut self, name: &str, source: impl RefreshSource + 'static) -> Self {
+        self.sources.insert(name.to_string(), Arc::new(source));
+        self
+    }
+
+    /// Minimum time between two refreshes of the same source.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Registered source names, sorted.
+    pub fn names(&self) -> Vec<String> {
+        self.sources.keys().cloned().collect()
+    }
+
+    /// Syncs the source called `name` and stores its new and changed
+    /// documents. A failed refresh does not count against the rate limit.
+    pub async fn refresh(&self, name: &str) -> Result<RefreshSummary, RefreshError> {
+        let source = self
+            .sources
+            .get(name)
+            .ok_or_else(|| RefreshError::UnknownSource {
+                name: name.to_string(),
+                known: self.names().join(", "),
+            })?;
+
+        let store_error = |e: SqliteError| RefreshError::Store(format!("{e:?}"));
+        if let Some(wait) = self
+            .knowledge
+            .claim_refresh(name, self.interval)
+            .await
+            .map_err(store_error)?
+        {
+            return Err(RefreshError::RateLimited {
+                name: name.to_string(),
+                minutes: wait.as_secs().div_ceil(60).max(1),
+            });
+        }
+
+        info!(source = name, "Refreshing knowledge source");
+        let result = async {
+            let documents = source
+                .sync_changes()
+                .await
+                .map_err(|e| RefreshError::Sync {
+                    name: name.to_string(),
+                    error: e.to_string(),
+                })?;
+            // The source returned every document it holds, so those it no
+            // longer does were removed, or moved
+            let removed = self
+                .knowledge
+                .absent_documents(&documents)
+                .await
+                .map_err(|e| RefreshError::Store(format!("{e:?}")))?;
+            self.knowledge
+                .refresh_moved_documents(documents, removed)
+                .await
+                .map_err(|e| RefreshError::Store(e.to_string()))
+        }
+        .await;
+
+        match &result {
+            Ok(summary) => info!(
+                source = name,
+                added = summary.added,
+                updated = summary.updated,
+                unchanged = summary.unchanged,
+                renamed = summary.renamed,
+                "Refreshed knowledge source"
+            ),
+            Err(err) => {
+                error!(?err, source = name, "Failed to refresh knowledge source");
+                if let Err(err) = self.knowledge.release_refresh(name).await {
+                    error!(?err, source = name, "Failed to reset refresh rate limit");
+                }
+            }
+        }
+        result
+    }
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Stores the documents that are new or whose content changed. A changed
+    /// document is stored under a new id as the latest version of its logical
+    /// document, superseding the previous one.
+    pub async fn refresh_documents(
+        &self,
+        documents: Vec<Document>,
+    ) -> anyhow::Result<RefreshSummary> {
+        self.refresh_moved_documents(documents, Vec::new()).await
+    }
+
+    /// Like [KnowledgeBase::refresh_documents], for a sync that also removed
+    /// the logical documents `removed`. A new document that is one of them
+    /// moved, see [identity](super::identity), takes it over: with the same
+    /// content it keeps its id and embedding under the new logical id, with
+    /// changed content it is stored as its next version. Either counts as
+    /// renamed, the latter also as updated.
+    pub async fn refresh_moved_documents(
+        &self,
+        documents: Vec<Document>,
+        removed: Vec<String>,
+    ) -> anyhow::Result<RefreshSummary> {
+        let logical_ids = documents
+            .iter()
+            .map(|document| document.logical_id.clone().unwrap_or(document.id.clone()))
+            .collect::<Vec<_>>();
+        let live = self.live_contents(logical_ids.clone()).await?;
+        let mut removed = self.removed_documents(removed).await?;
+
+        let suffix = chrono::Utc::now().timestamp_millis();
+        let mut summary = RefreshSummary::default();
+        let mut changed = Vec::new();
+        let mut added = HashSet::new();
+        for (mut document, logical_id) in documents.into_iter().zip(logical_ids) {
+            match live.get(&logical_id) {
+                None => {
+                    let hash = content_hash(&document.content);
+                    if let Some(i) = removed.iter().position(|moved| moved.content_hash == hash) {
+                        let moved = removed.swap_remove(i);
+                        self.move_document(&moved.logical_id, &logical_id).await?;
+                        if !document.topics.is_empty() {
+                            self.replace_topics(vec![(moved.document_id, document.topics)])
+                                .await?;
+                        }
+                        summary.renamed += 1;
+                        continue;
+                    }
+                    added.insert(logical_id.clone());
+                }
+                Some(content) if *content == document.content => {
+                    summary.unchanged += 1;
+                    continue;
+                }
+                Some(_) => {
+                    summary.updated += 1;
+                    document.id = format!("{}@{suffix}", document.id);
+                }
+            }
+            document.logical_id = Some(logical_id);
+            changed.push(document);
+        }
+
+        if changed.is_empty() {
+            return Ok(summary);
+        }
+        let embeddings = EmbeddingsBuilder::new(self.embedding_model.clone())
+            .documents(cleaning::prepare(changed))?
+            .build()
+            .await?;
+        for (document, embedding) in &embeddings {
+            let Some(logical_id) = document
+                .logical_id
+                .as_ref()
+                .filter(|id| added.contains(*id))
+            else {
+                continue;
+            };
+            let embedding = embedding.first().vec;
+            let closest = removed
+                .iter()
+                .map(|moved| cosine_similarity(&embedding, &moved.embedding))
+                .enumerate()
+                .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                .filter(|(_, similarity)| *similarity >= RENAME_SIMILARITY);
+            match closest {
+                Some((i, _)) => {
+                    let moved = removed.swap_remove(i);
+                    self.move_document(&moved.logical_id, logical_id).await?;
+                    summary.renamed += 1;
+                    summary.updated += 1;
+                }
+                None => summary.added += 1,
+            }
+        }
+        self.store_embedded(embeddings).await?;
+        Ok(summary)
+    }
+
+    /// Logical ids of the live documents from the sources of `documents`
+    /// that are not among them, when `documents` is everything the sources
+    /// hold.
+    pub async fn absent_documents(
+        &self,
+        documents: &[Document],
+    ) -> Result<Vec<String>, SqliteError> {
+        let sources = documents
+            .iter()
+            .map(|document| document.source_id.clone())
+            .collect::<HashSet<_>>();
+        let present = documents
+            .iter()
+            .map(|document| document.logical_id.clone().unwrap_or(document.id.clone()))
+            .collect::<HashSet<_>>();
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT v.logical_id
+                     FROM document_versions v
+                     JOIN documents d ON d.id = v.document_id
+                     WHERE v.agent_id = ?2 AND d.source_id = ?1 AND v.superseded = 0",
+                )?;
+                let mut absent = Vec::new();
+                for source in &sources {
+                    for logical_id in stmt.query_map([source, &namespace], |row| row.get(0))? {
+                        let logical_id: String = logical_id?;
+                        if !present.contains(&logical_id) {
+                            absent.push(logical_id);
+                        }
+                    }
+                }
+                Ok(absent)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Content of the live version of each logical document that has one.
+    async fn live_contents(
+        &self,
+        logical_ids: Vec<String>,
+    ) -> Result<HashMap<String, String>, tokio_rusqlite::Error> {
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT d.content
+                     FROM document_versions v
+                     JOIN documents d ON d.id = v.document_id
+                     WHERE v.agent_id = ?2 AND v.logical_id = ?1 AND v.superseded = 0",
+                )?;
+                let mut contents = HashMap::new();
+                for logical_id in logical_ids {
+                    let content: Option<String> = stmt
+                        .query_row([&logical_id, &namespace], |row| row.get(0))
+                        .optional()?;
+                    if let Some(content) = content {
+                        contents.insert(logical_id, content);
+                    }
+                }
+                Ok(contents)
+            })
+            .await
+    }
+
+    /// Records a refresh of `source` unless one happened less than `interval`
+    /// ago, in which case the time left is returned and nothing is recorded.
+    pub async fn claim_refresh(
+        &self,
+        source: &str,
+        interval: Duration,
+    ) -> Result<Option<Duration>, SqliteError> {
+        let source = source.to_string();
+        let now = self.clock.now_utc();
+
+        self.conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let last: Option<chrono::DateTime<chrono::Utc>> = tx
+                    .query_row(
+                        "SELECT refreshed_at FROM knowledge_refreshes WHERE source = ?1",
+                        [&source],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                if let Some(last) = last {
+                    // A last refresh in the future counts as just now.
+                    let elapsed = (now - last).to_std().unwrap_or_default();
+                    if elapsed < interval {
+                        return Ok(Some(interval - elapsed));
+                    }
+                }
+
+                tx.execute(
+                    "INSERT INTO knowledge_refreshes (source, refreshed_at) VALUES (?1, ?2)
+                     ON CONFLICT (source) DO UPDATE SET refreshed_at = excluded.refreshed_at",
+                    rusqlite::params![source, now],
+                )?;
+                tx.commit()?;
+                Ok(None)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    async fn release_refresh(&self, source: &str) -> Result<(), SqliteError> {
+        let source = source.to_string();
+
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "DELETE FROM knowledge_refreshes WHERE source = ?1",
+                    [&source],
+                )?;
+                Ok(())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{self, document as page, FakeLoader};
+
+    #[tokio::test]
+    async fn test_refresh_stores_only_changes() {
+        let knowledge = test_utils::knowledge_base().await;
+
+        let summary = knowledge
+            .refresh_documents(vec![
+                page("katana.md", "Katana v1"),
+                page("torii.md", "Torii"),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(
+            summary,
+            RefreshSummary {
+                added: 2,
+                updated: 0,
+                unchanged: 0,
+                renamed: 0
+            }
+        );
+
+        let summary = knowledge
+            .refresh_documents(vec![
+                page("katana.md", "Katana v2"),
+                page("torii.md", "Torii"),
+                page("slot.md", "Slot"),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(summary.to_string(), "1 added, 1 updated, 1 unchanged");
+
+        let versions = knowledge
+            .document_versions("github:katana.md")
+            .await
+            .unwrap();
+        assert_eq!(versions.len(), 2);
+        assert!(versions[0].document_id.starts_with("/tmp/docs/katana.md@"));
+        assert!(!versions[0].superseded);
+        assert!(versions[1].superseded);
+    }
+
+    #[tokio::test]
+    async fn test_moved_document_with_changed_content() {
+        let knowledge = test_utils::knowledge_base().await;
+        let vrf = "Cartridge VRF provides verifiable randomness to onchain games \
+                   with a single call per transaction";
+        knowledge
+            .refresh_documents(vec![
+                page("guides/vrf.md", vrf),
+                page("guides/paymaster.md", "The paymaster sponsors fees"),
+            ])
+            .await
+            .unwrap();
+
+        let summary = knowledge
+            .refresh_moved_documents(
+                vec![
+                    page("tools/vrf.md", &format!("{vrf} today")),
+                    page("tools/sozo.md", "Sozo migrates worlds"),
+                ],
+                vec!["github:guides/vrf.md".to_string()],
+            )
+            .await
+            .unwrap();
+        assert_eq!(summary.to_string(), "1 added, 1 updated, 1 renamed");
+
+        // The old version moved along and was superseded by the new one
+        let versions = knowledge
+            .document_versions("github:tools/vrf.md")
+            .await
+            .unwrap();
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions[0].document_id, "/tmp/docs/tools/vrf.md");
+        assert_eq!(versions[1].document_id, "/tmp/docs/guides/vrf.md");
+        assert!(versions[1].superseded);
+
+        let resolved = knowledge
+            .resolve_document("/tmp/docs/guides/vrf.md")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(resolved.path, "github:tools/vrf.md");
+        assert!(resolved.superseded);
+
+        let by_hash = knowledge
+            .resolve_document(&content_hash("The paymaster sponsors fees"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(by_hash.id, "/tmp/docs/guides/paymaster.md");
+        assert_eq!(knowledge.resolve_document("missing").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_claim_refresh() {
+        let knowledge = test_utils::knowledge_base().await;
+        let hour = Duration::from_secs(3600);
+
+        assert_eq!(knowledge.claim_refresh("github", hour).await.unwrap(), None);
+        let wait = knowledge
+            .claim_refresh("github", hour)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(wait > Duration::from_secs(3500));
+        assert_eq!(knowledge.claim_refresh("docs", hour).await.unwrap(), None);
+
+        knowledge.release_refresh("github").await.unwrap();
+        assert_eq!(knowledge.claim_refresh("github", hour).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_registry_stores_what_the_loader_yields() {
+        let knowledge = test_utils::knowledge_base().await;
+        let loader = FakeLoader::new([
+            vec![page("katana.md", "Katana v1"), page("torii.md", "Torii")],
+            vec![
+                page("katana.md", "Katana v2"),
+                page("torii.md", "Torii"),
+                page("slot.md", "Slot"),
+            ],
+        ]);
+        let registry = RefreshRegistry::new(knowledge.clone())
+            .with_source("docs", loader.clone())
+            .with_interval(Duration::ZERO);
+
+        let summary = registry.refresh("docs").await.unwrap();
+        assert_eq!(summary.to_string(), "2 added, 0 updated");
+        let summary = registry.refresh("docs").await.unwrap();
+        assert_eq!(summary.to_string(), "1 added, 1 updated, 1 unchanged");
+        // Out of script, the last batch again
+        let summary = registry.refresh("docs").await.unwrap();
+        assert_eq!(summary.to_string(), "0 added, 0 updated, 3 unchanged");
+        assert_eq!(loader.syncs(), 3);
+    }
+}
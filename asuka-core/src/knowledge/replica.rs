@@ -0,0 +1,537 @@
+//! Document retrieval from a read-only copy of the database, so that a large
+//! ingestion writing to the primary doesn't slow retrieval down, as it does
+//! when both contend for one file on network storage even with WAL.
+//!
+//! A [ReadReplica] is a local copy taken with SQLite's backup API and opened
+//! read-only. Its refresh loop, see [ReadReplica::spawn_refresh], copies the
+//! primary again every [ReplicaConfig::refresh_interval] and, with
+//! [ReplicaConfig::trigger_on_ingest_complete], once an ingestion finishes.
+//! Searches running during a refresh finish on the copy they started on.
+//!
+//! Retrieval through a replica is eventually consistent: a knowledge base
+//! opts into it with [KnowledgeBase::with_eventually_consistent_retrieval],
+//! after which its vector and keyword searches miss what was written since
+//! the last refresh, see [ReplicaStats::staleness]. Every write, and every
+//! other read, still goes to the primary.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use arc_swap::ArcSwap;
+use chrono::{DateTime, Utc};
+use rig::embeddings::EmbeddingModel;
+use rusqlite::{backup::Backup, OpenFlags};
+use thiserror::Error;
+use tokio::sync::{Mutex, Notify};
+use tokio_rusqlite::Connection;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+use super::store::KnowledgeBase;
+use crate::clock::Clock;
+
+/// Pause between attempts while the primary is locked during a copy.
+const BUSY_PAUSE: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone)]
+pub struct ReplicaConfig {
+    /// How often the replica is copied from the primary, and so how far
+    /// retrieval may lag behind writes.
+    pub refresh_interval: Duration,
+    /// Also copy the primary as soon as an ingestion finishes.
+    pub trigger_on_ingest_complete: bool,
+    /// Where copies are written, the system's temporary directory by
+    /// default. Should be local storage.
+    pub directory: Option<PathBuf>,
+}
+
+impl Default for ReplicaConfig {
+    fn default() -> Self {
+        Self {
+            refresh_interval: Duration::from_secs(300),
+            trigger_on_ingest_complete: true,
+            directory: None,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ReplicaError {
+    #[error("Failed to copy the database: {0}")]
+    Copy(#[from] rusqlite::Error),
+    #[error("Failed to open the replica: {0}")]
+    Open(#[from] tokio_rusqlite::Error),
+    #[error("The copy was interrupted: {0}")]
+    Interrupted(#[from] tokio::task::JoinError),
+}
+
+/// How a [ReadReplica] is doing, e.g. for a status page.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplicaStats {
+    /// When the primary was last copied.
+    pub refreshed_at: DateTime<Utc>,
+    /// How long ago that was: retrieval misses writes made since.
+    pub staleness: Duration,
+    pub refreshes: u64,
+    pub failed_refreshes: u64,
+    /// Searches served by the replica.
+    pub reads: u64,
+}
+
+/// One copy of the primary, removed once nothing reads from it anymore.
+pub(super) struct ReplicaFile {
+    pub(super) conn: Connection,
+    path: PathBuf,
+    taken_at: DateTime<Utc>,
+}
+
+impl Drop for ReplicaFile {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            warn!(?err, path = %self.path.display(), "Failed to remove a replica copy");
+        }
+    }
+}
+
+/// A read-only copy of the database, see the [module docs](self). Clones
+/// share it.
+#[derive(Clone)]
+pub struct ReadReplica {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    primary: Connection,
+    /// The primary's file, copied through a connection of its own. `None`
+    /// for an in-memory database, copied through the primary connection.
+    primary_path: Option<PathBuf>,
+    config: ReplicaConfig,
+    clock: Arc<dyn Clock>,
+    current: ArcSwap<ReplicaFile>,
+    /// Held while copying, so refreshes don't overlap.
+    refreshing: Mutex<()>,
+    ingested: Notify,
+    refreshes: AtomicU64,
+    failed_refreshes: AtomicU64,
+    reads: AtomicU64,
+}
+
+impl ReadReplica {
+    /// Copies `primary` and opens the copy.
+    async fn open(
+        primary: Connection,
+        config: ReplicaConfig,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Self, ReplicaError> {
+        let primary_path = primary
+            .call(|conn| {
+                Ok(conn
+                    .path()
+                    .filter(|path| !path.is_empty())
+                    .map(PathBuf::from))
+            })
+            .await?;
+        let first = copy(&primary, primary_path.as_deref(), &config, clock.as_ref()).await?;
+        info!(path = %first.path.display(), "Opened a read replica");
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                primary,
+                primary_path,
+                config,
+                clock,
+                current: ArcSwap::from_pointee(first),
+                refreshing: Mutex::new(()),
+                ingested: Notify::new(),
+                refreshes: AtomicU64::new(1),
+                failed_refreshes: AtomicU64::new(0),
+                reads: AtomicU64::new(0),
+            }),
+        })
+    }
+
+    /// Copies the primary again and moves new searches onto the copy.
+    pub async fn refresh(&self) -> Result<(), ReplicaError> {
+        let inner = &self.inner;
+        let _refreshing = inner.refreshing.lock().await;
+        match copy(
+            &inner.primary,
+            inner.primary_path.as_deref(),
+            &inner.config,
+            inner.clock.as_ref(),
+        )
+        .await
+        {
+            Ok(file) => {
+                debug!(path = %file.path.display(), "Refreshed the read replica");
+                inner.current.store(Arc::new(file));
+                inner.refreshes.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(err) => {
+                inner.failed_refreshes.fetch_add(1, Ordering::Relaxed);
+                Err(err)
+            }
+        }
+    }
+
+    /// Refreshes the replica every [ReplicaConfig::refresh_interval], and
+    /// after each ingestion if [ReplicaConfig::trigger_on_ingest_complete].
+    /// A failed refresh keeps the previous copy until the next one.
+    ///
+    /// Stops once `shutdown` is cancelled. A copy in progress is finished
+    /// first rather than abandoned with its file, so once the task is done
+    /// no copy is being written.
+    pub fn spawn_refresh(&self, shutdown: CancellationToken) -> tokio::task::JoinHandle<()> {
+        let replica = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    biased;
+                    _ = shutdown.cancelled() => break,
+                    _ = tokio::time::sleep(replica.inner.config.refresh_interval) => {}
+                    _ = replica.inner.ingested.notified() => {
+                        debug!("Ingestion complete, refreshing the read replica");
+                    }
+                }
+                if let Err(err) = replica.refresh().await {
+                    error!(?err, "Failed to refresh the read replica");
+                }
+            }
+            debug!("Stopped refreshing the read replica");
+        })
+    }
+
+    pub fn stats(&self) -> ReplicaStats {
+        let inner = &self.inner;
+        let refreshed_at = inner.current.load().taken_at;
+        ReplicaStats {
+            refreshed_at,
+            staleness: (inner.clock.now_utc() - refreshed_at)
+                .to_std()
+                .unwrap_or_default(),
+            refreshes: inner.refreshes.load(Ordering::Relaxed),
+            failed_refreshes: inner.failed_refreshes.load(Ordering::Relaxed),
+            reads: inner.reads.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The current copy, to search. Kept until the search holding it ends,
+    /// even if a refresh replaces it meanwhile.
+    pub(super) fn read(&self) -> Arc<ReplicaFile> {
+        self.inner.reads.fetch_add(1, Ordering::Relaxed);
+        self.inner.current.load_full()
+    }
+
+    /// Wakes the refresh loop once an ingestion finished, if configured to.
+    pub(super) fn ingest_complete(&self) {
+        if self.inner.config.trigger_on_ingest_complete {
+            self.inner.ingested.notify_one();
+        }
+    }
+}
+
+/// Copies the primary to a new file and opens it read-only. A failed copy
+/// is removed.
+async fn copy(
+    primary: &Connection,
+    primary_path: Option<&Path>,
+    config: &ReplicaConfig,
+    clock: &dyn Clock,
+) -> Result<ReplicaFile, ReplicaError> {
+    let path = replica_path(config);
+    let taken_at = clock.now_utc();
+    match copy_to(primary, primary_path, &path).await {
+        Ok(conn) => Ok(ReplicaFile {
+            conn,
+            path,
+            taken_at,
+        }),
+        Err(err) => {
+            if let Err(err) = std::fs::remove_file(&path) {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    warn!(?err, path = %path.display(), "Failed to remove a failed replica copy");
+                }
+            }
+            Err(err)
+        }
+    }
+}
+
+async fn copy_to(
+    primary: &Connection,
+    primary_path: Option<&Path>,
+    path: &Path,
+) -> Result<Connection, ReplicaError> {
+    match primary_path {
+        // A connection of its own reads the primary without queueing behind
+        // its writes, and in WAL mode without blocking them
+        Some(primary_path) => {
+            let (primary_path, path) = (primary_path.to_path_buf(), path.to_path_buf());
+            tokio::task::spawn_blocking(move || {
+                backup(&rusqlite::Connection::open(primary_path)?, &path)
+            })
+            .await??;
+        }
+        None => {
+            let path = path.to_path_buf();
+            primary.call(move |conn| Ok(backup(conn, &path)?)).await?;
+        }
+    }
+
+    Ok(Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .await?)
+}
+
+/// Copies `source` into a new database at `path`, in one step so that the
+/// copy is of one point in time.
+fn backup(source: &rusqlite::Connection, path: &Path) -> rusqlite::Result<()> {
+    let mut copy = rusqlite::Connection::open(path)?;
+    Backup::new(source, &mut copy)?.run_to_completion(-1, BUSY_PAUSE, None)?;
+    // A read-only WAL database needs its shared memory file, which only the
+    // writer creates
+    copy.pragma_update_and_check(None, "journal_mode", "DELETE", |_| Ok(()))
+}
+
+/// A file name no other copy uses, in [ReplicaConfig::directory].
+fn replica_path(config: &ReplicaConfig) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    config
+        .directory
+        .clone()
+        .unwrap_or_else(std::env::temp_dir)
+        .join(format!(
+            "asuka-replica-{}-{}.db",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ))
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Takes a first copy of the database for a [ReadReplica]. Searches only
+    /// use it once given to [KnowledgeBase::with_eventually_consistent_retrieval],
+    /// and it is only refreshed by [ReadReplica::spawn_refresh] or
+    /// [ReadReplica::refresh].
+    pub async fn open_read_replica(
+        &self,
+        config: ReplicaConfig,
+    ) -> Result<ReadReplica, ReplicaError> {
+        ReadReplica::open(self.conn.clone(), config, self.clock.clone()).await
+    }
+
+    /// Serves document searches from `replica`. They miss documents written
+    /// since its last refresh, see the [module docs](self), while writes and
+    /// every other read still use the primary.
+    pub fn with_eventually_consistent_retrieval(mut self, replica: ReadReplica) -> Self {
+        self.replica = Some(replica);
+        self
+    }
+
+    pub fn read_replica(&self) -> Option<&ReadReplica> {
+        self.replica.as_ref()
+    }
+
+    /// Lets the replica's refresh loop know an ingestion finished.
+    pub(super) fn ingest_complete(&self) {
+        if let Some(replica) = &self.replica {
+            replica.ingest_complete();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rig::vector_store::VectorStoreIndex;
+
+    use super::*;
+    use crate::{
+        knowledge::{Document, IngestOptions},
+        test_utils::{self, FakeEmbeddingModel},
+    };
+
+    fn doc(id: &str, content: &str) -> Document {
+        Document {
+            id: id.to_string(),
+            source_id: "github".to_string(),
+            content: content.to_string(),
+            created_at: chrono::Utc::now(),
+            title: String::new(),
+            section: String::new(),
+            topics: Vec::new(),
+            logical_id: None,
+            cleaned: None,
+            chunk: None,
+        }
+    }
+
+    async fn file_knowledge_base(dir: &Path) -> KnowledgeBase<FakeEmbeddingModel> {
+        test_utils::load_sqlite_vec();
+        let conn = Connection::open(dir.join("primary.db")).await.unwrap();
+        KnowledgeBase::new(conn, FakeEmbeddingModel).await.unwrap()
+    }
+
+    fn config(dir: &Path) -> ReplicaConfig {
+        ReplicaConfig {
+            refresh_interval: Duration::from_secs(3600),
+            trigger_on_ingest_complete: false,
+            directory: Some(dir.to_path_buf()),
+        }
+    }
+
+    fn replica_files(dir: &Path) -> usize {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with("asuka-replica-")
+            })
+            .count()
+    }
+
+    async fn found(knowledge: &KnowledgeBase<FakeEmbeddingModel>, query: &str) -> Vec<String> {
+        knowledge
+            .clone()
+            .document_index()
+            .top_n_ids(query, 5)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(_, id)| id)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_searches_lag_until_refresh() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut knowledge = file_knowledge_base(dir.path()).await;
+        knowledge
+            .add_documents([doc("katana", "katana runs a local devnet")])
+            .await
+            .unwrap();
+
+        let replica = knowledge
+            .open_read_replica(config(dir.path()))
+            .await
+            .unwrap();
+        let first = replica.stats().refreshed_at;
+        let mut knowledge = knowledge.with_eventually_consistent_retrieval(replica.clone());
+        knowledge
+            .add_documents([doc("torii", "torii indexes the devnet world")])
+            .await
+            .unwrap();
+
+        // Searches read the copy, which predates the second document, while
+        // reads by id go to the primary
+        let ids = found(&knowledge, "devnet").await;
+        assert_eq!(ids, ["katana"]);
+        assert!(replica.stats().reads > 0);
+        assert!(knowledge.get_document("torii").await.unwrap().is_some());
+
+        replica.refresh().await.unwrap();
+        let ids = found(&knowledge, "devnet").await;
+        assert_eq!(ids.len(), 2);
+
+        let stats = replica.stats();
+        assert_eq!((stats.refreshes, stats.failed_refreshes), (2, 0));
+        assert!(stats.refreshed_at >= first);
+        // Replaced copies are removed once unused
+        assert_eq!(replica_files(dir.path()), 1);
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_primary_is_copied() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut knowledge = test_utils::knowledge_base().await;
+        knowledge
+            .add_documents([doc("katana", "katana runs a local devnet")])
+            .await
+            .unwrap();
+
+        let replica = knowledge
+            .open_read_replica(config(dir.path()))
+            .await
+            .unwrap();
+        let knowledge = knowledge.with_eventually_consistent_retrieval(replica);
+        assert_eq!(found(&knowledge, "devnet").await, ["katana"]);
+    }
+
+    #[tokio::test]
+    async fn test_ingestion_triggers_refresh() {
+        let dir = tempfile::tempdir().unwrap();
+        let knowledge = file_knowledge_base(dir.path()).await;
+        let config = ReplicaConfig {
+            trigger_on_ingest_complete: true,
+            ..config(dir.path())
+        };
+        let replica = knowledge.open_read_replica(config).await.unwrap();
+        let knowledge = knowledge.with_eventually_consistent_retrieval(replica.clone());
+        let shutdown = CancellationToken::new();
+        let refresh = replica.spawn_refresh(shutdown.clone());
+
+        let documents = futures::stream::iter([Ok(doc("katana", "katana runs a local devnet"))]);
+        let summary = knowledge
+            .add_documents_stream(documents, IngestOptions::default())
+            .await;
+        assert_eq!(summary.ingested, 1);
+
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while replica.stats().refreshes < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(found(&knowledge, "devnet").await, ["katana"]);
+
+        // Stops on shutdown, leaving only the copy in use
+        shutdown.cancel();
+        refresh.await.unwrap();
+        assert_eq!(replica_files(dir.path()), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_refresh_during_searches() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut knowledge = file_knowledge_base(dir.path()).await;
+        knowledge
+            .add_documents((0..20).map(|i| doc(&format!("doc{i}"), &format!("devnet note {i}"))))
+            .await
+            .unwrap();
+        let replica = knowledge
+            .open_read_replica(config(dir.path()))
+            .await
+            .unwrap();
+        let knowledge = knowledge.with_eventually_consistent_retrieval(replica.clone());
+
+        let searches = (0..8)
+            .map(|_| {
+                let knowledge = knowledge.clone();
+                tokio::spawn(async move {
+                    for _ in 0..20 {
+                        assert_eq!(found(&knowledge, "devnet note").await.len(), 5);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for _ in 0..5 {
+            replica.refresh().await.unwrap();
+        }
+        for search in searches {
+            search.await.unwrap();
+        }
+        assert_eq!(replica.stats().failed_refreshes, 0);
+    }
+}
@@ -0,0 +1,452 @@
+//! Purging of messages older than their retention period. Documents are never
+//! purged, only chat messages and what refers to them: their embeddings, the
+//! links from interactions, and conversation state pointing at them. Session
+//! summaries written from purged messages are kept but marked stale.
+//!
+//! Channels with [RETAIN_FOREVER_SETTING] on, and the users listed in
+//! [RetentionPolicy::retain_users], keep their messages.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+use tracing::info;
+
+use super::store::KnowledgeBase;
+
+/// Channel setting that exempts a channel from retention when `on`.
+pub const RETAIN_FOREVER_SETTING: &str = "retain_forever";
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS retention_purges (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        agent_id TEXT NOT NULL,
+        messages INTEGER NOT NULL,
+        embeddings INTEGER NOT NULL,
+        stale_summaries INTEGER NOT NULL,
+        cleared_states INTEGER NOT NULL,
+        purged_at TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS idx_retention_purges_agent
+        ON retention_purges(agent_id, purged_at);
+";
+
+/// Adds the `stale` column to session summaries stored before retention.
+pub(super) fn migrate(conn: &mut rusqlite::Connection) -> rusqlite::Result<()> {
+    let exists = conn
+        .prepare("SELECT 1 FROM pragma_table_info('session_summaries') WHERE name = 'stale'")?
+        .exists([])?;
+    if !exists {
+        info!("Adding stale column to session_summaries");
+        conn.execute_batch(
+            "ALTER TABLE session_summaries ADD COLUMN stale INTEGER NOT NULL DEFAULT 0",
+        )?;
+    }
+    Ok(())
+}
+
+/// How long messages are kept. The age for a message's channel type wins
+/// over the one for its source, which wins over [Self::max_age_days]; messages
+/// none of them applies to are kept.
+#[derive(Debug, Clone)]
+pub struct RetentionPolicy {
+    /// Days messages are kept by default.
+    pub max_age_days: Option<u32>,
+    /// Days kept by channel type, such as `direct_message`.
+    pub channel_types: HashMap<String, u32>,
+    /// Days kept by source, such as `telegram`.
+    pub sources: HashMap<String, u32>,
+    /// Users, by platform id, whose messages are kept.
+    pub retain_users: Vec<String>,
+    /// Messages deleted per transaction.
+    pub chunk_size: usize,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            max_age_days: None,
+            channel_types: HashMap::new(),
+            sources: HashMap::new(),
+            retain_users: Vec::new(),
+            chunk_size: 500,
+        }
+    }
+}
+
+impl RetentionPolicy {
+    /// Days messages of `source` in a channel of `channel_type` are kept.
+    pub fn max_age_days(&self, source: &str, channel_type: &str) -> Option<u32> {
+        self.channel_types
+            .get(channel_type)
+            .or_else(|| self.sources.get(source))
+            .copied()
+            .or(self.max_age_days)
+    }
+}
+
+/// What [KnowledgeBase::apply_retention] removed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PurgeReport {
+    pub messages: usize,
+    pub embeddings: usize,
+    /// Messages that were still waiting to be embedded.
+    pub pending: usize,
+    pub stale_summaries: usize,
+    pub cleared_states: usize,
+    /// Messages purged of each source and channel type, as `source/type`.
+    pub by_kind: Vec<(String, usize)>,
+}
+
+impl std::fmt::Display for PurgeReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} messages, {} embeddings, {} pending, {} summaries marked stale, {} conversation states cleared",
+            self.messages, self.embeddings, self.pending, self.stale_summaries, self.cleared_states
+        )
+    }
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Deletes this knowledge base's messages older than `policy` allows at
+    /// `now`, [RetentionPolicy::chunk_size] at a time so writers aren't
+    /// blocked for long. The purge is recorded for the operator digest.
+    pub async fn apply_retention(
+        &self,
+        policy: &RetentionPolicy,
+        now: DateTime<Utc>,
+    ) -> Result<PurgeReport, SqliteError> {
+        let namespace = self.namespace.clone();
+        let policy = policy.clone();
+
+        let report = self
+            .conn
+            .call(move |conn| {
+                let mut report = PurgeReport::default();
+                let retain_users =
+                    serde_json::to_string(&policy.retain_users).expect("strings serialize");
+                let chunk_size = policy.chunk_size.max(1);
+
+                let kinds = conn
+                    .prepare(
+                        "SELECT source, channel_type FROM messages WHERE agent_id = ?1
+                         UNION
+                         SELECT source, channel_type FROM pending_messages WHERE agent_id = ?1",
+                    )?
+                    .query_map([&namespace], |row| Ok((row.get(0)?, row.get(1)?)))?
+                    .collect::<Result<Vec<(String, String)>, _>>()?;
+
+                for (source, channel_type) in kinds {
+                    let Some(days) = policy.max_age_days(&source, &channel_type) else {
+                        continue;
+                    };
+                    let cutoff = (now - chrono::Duration::days(days.into())).to_rfc3339();
+                    let expired = format!(
+                        "agent_id = ?1 AND source = ?2 AND channel_type = ?3 AND created_at < ?4
+                         AND source_id NOT IN (SELECT value FROM json_each(?5))
+                         AND channel_id NOT IN (
+                             SELECT channel_id FROM channel_settings
+                             WHERE agent_id = ?1 AND key = '{RETAIN_FOREVER_SETTING}'
+                                 AND value = 'on'
+                         )"
+                    );
+                    let params =
+                        rusqlite::params![namespace, source, channel_type, cutoff, retain_users];
+                    let mut purged = 0;
+
+                    loop {
+                        let tx = conn.transaction()?;
+                        let chunk = tx
+                            .prepare(&format!(
+                                "SELECT rowid, id, channel_id, created_at FROM messages
+                                 WHERE {expired} LIMIT {chunk_size}"
+                            ))?
+                            .query_map(params, |row| {
+                                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+                            })?
+                            .collect::<Result<Vec<(i64, String, String, String)>, _>>()?;
+
+                        for (rowid, id, channel_id, created_at) in &chunk {
+                            report.embeddings += tx.execute(
+                                "DELETE FROM messages_embeddings WHERE rowid = ?1",
+                                [rowid],
+                            )?;
+                            // Queries and exchanges quote the messages they were made for
+                            for table in ["interaction_queries", "exchanges"] {
+                                tx.execute(
+                                    &format!(
+                                        "DELETE FROM {table} WHERE interaction_id IN (
+                                             SELECT interaction_id FROM interaction_messages
+                                             WHERE message_id = ?1
+                                         )"
+                                    ),
+                                    [id],
+                                )?;
+                            }
+                            tx.execute(
+                                "DELETE FROM interaction_messages WHERE message_id = ?1",
+                                [id],
+                            )?;
+                            tx.execute(
+                                "DELETE FROM interaction_replies WHERE message_id = ?1",
+                                [id],
+                            )?;
+                            report.cleared_states += tx.execute(
+                                "DELETE FROM conversation_state
+                                 WHERE agent_id = ?1 AND channel_id = ?2 AND last_message_id = ?3",
+                                [&namespace, channel_id, id],
+                            )?;
+                            report.stale_summaries += tx.execute(
+                                "UPDATE session_summaries SET stale = 1
+                                 WHERE agent_id = ?1 AND channel_id = ?2 AND stale = 0
+                                     AND started_at <= ?3 AND ended_at >= ?3",
+                                [&namespace, channel_id, created_at],
+                            )?;
+                            purged +=
+                                tx.execute("DELETE FROM messages WHERE rowid = ?1", [rowid])?;
+                        }
+                        tx.commit()?;
+
+                        if chunk.len() < chunk_size {
+                            break;
+                        }
+                    }
+
+                    // Never embedded, so nothing else refers to them
+                    let pending = conn.execute(
+                        &format!("DELETE FROM pending_messages WHERE {expired}"),
+                        params,
+                    )?;
+
+                    report.messages += purged;
+                    report.pending += pending;
+                    if purged + pending > 0 {
+                        report
+                            .by_kind
+                            .push((format!("{source}/{channel_type}"), purged + pending));
+                    }
+                }
+
+                conn.execute(
+                    "INSERT INTO retention_purges
+                         (agent_id, messages, embeddings, stale_summaries, cleared_states,
+                          purged_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    rusqlite::params![
+                        namespace,
+                        report.messages + report.pending,
+                        report.embeddings,
+                        report.stale_summaries,
+                        report.cleared_states,
+                        now.to_rfc3339()
+                    ],
+                )?;
+                Ok(report)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))?;
+
+        info!(%report, by_kind = ?report.by_kind, "Applied retention");
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        knowledge::{ChannelType, ConversationState, IdleSession, Message, Source},
+        test_utils,
+    };
+
+    fn message(
+        id: &str,
+        channel_type: ChannelType,
+        channel_id: &str,
+        user: &str,
+        created_at: DateTime<Utc>,
+    ) -> Message {
+        Message {
+            id: id.to_string(),
+            source: Source::Discord,
+            source_id: user.to_string(),
+            channel_type,
+            channel_id: channel_id.to_string(),
+            account_id: user.to_string(),
+            role: "user".to_string(),
+            content: format!("message {id}"),
+            created_at,
+        }
+    }
+
+    fn policy() -> RetentionPolicy {
+        RetentionPolicy {
+            channel_types: HashMap::from([
+                ("direct_message".to_string(), 30),
+                ("text".to_string(), 180),
+            ]),
+            retain_users: vec!["keeper".to_string()],
+            chunk_size: 2,
+            ..Default::default()
+        }
+    }
+
+    async fn count(
+        knowledge: &KnowledgeBase<test_utils::FakeEmbeddingModel>,
+        sql: &'static str,
+    ) -> i64 {
+        knowledge
+            .conn
+            .call(move |conn| Ok(conn.query_row(sql, [], |row| row.get(0))?))
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn test_max_age_precedence() {
+        let policy = RetentionPolicy {
+            max_age_days: Some(365),
+            sources: HashMap::from([("telegram".to_string(), 90)]),
+            ..policy()
+        };
+
+        assert_eq!(policy.max_age_days("discord", "direct_message"), Some(30));
+        assert_eq!(policy.max_age_days("telegram", "direct_message"), Some(30));
+        assert_eq!(policy.max_age_days("telegram", "thread"), Some(90));
+        assert_eq!(policy.max_age_days("discord", "thread"), Some(365));
+        assert_eq!(
+            RetentionPolicy::default().max_age_days("discord", "text"),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_purges_expired_messages() {
+        let knowledge = test_utils::knowledge_base().await;
+        let now = Utc::now();
+        let days = |days: i64| now - chrono::Duration::days(days);
+
+        for msg in [
+            message("dm-old", ChannelType::DirectMessage, "dm", "u1", days(31)),
+            message("dm-new", ChannelType::DirectMessage, "dm", "u1", days(29)),
+            message("text-old-1", ChannelType::Text, "general", "u1", days(181)),
+            message("text-old-2", ChannelType::Text, "general", "u2", days(200)),
+            message("text-old-3", ChannelType::Text, "general", "u2", days(300)),
+            message("text-new", ChannelType::Text, "general", "u1", days(179)),
+            message(
+                "kept-user",
+                ChannelType::Text,
+                "general",
+                "keeper",
+                days(400),
+            ),
+            message(
+                "kept-channel",
+                ChannelType::Text,
+                "archive",
+                "u1",
+                days(400),
+            ),
+            message("voice-old", ChannelType::Voice, "voice", "u1", days(1000)),
+        ] {
+            knowledge.create_message(msg).await.unwrap();
+        }
+        knowledge
+            .set_channel_setting("archive", RETAIN_FOREVER_SETTING, Some("on"))
+            .await
+            .unwrap();
+        knowledge
+            .create_interaction(
+                "general".to_string(),
+                "u2".to_string(),
+                vec!["text-old-2".to_string(), "text-new".to_string()],
+            )
+            .await
+            .unwrap();
+        knowledge
+            .save_conversation_state(
+                "dm",
+                &ConversationState {
+                    last_message_id: Some("dm-old".to_string()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        let session = IdleSession {
+            channel_id: "general".to_string(),
+            started_at: days(300),
+            ended_at: days(179),
+        };
+        knowledge
+            .add_session_summary(&session, "Talked about fees.", 4)
+            .await
+            .unwrap();
+        assert_eq!(
+            count(&knowledge, "SELECT COUNT(*) FROM messages_embeddings").await,
+            9
+        );
+
+        let report = knowledge.apply_retention(&policy(), now).await.unwrap();
+
+        assert_eq!(report.messages, 4);
+        assert_eq!(report.embeddings, 4);
+        assert_eq!(report.stale_summaries, 1);
+        assert_eq!(report.cleared_states, 1);
+        let remaining = knowledge
+            .conn
+            .call(|conn| {
+                Ok(conn
+                    .prepare("SELECT id FROM messages ORDER BY id")?
+                    .query_map([], |row| row.get(0))?
+                    .collect::<Result<Vec<String>, _>>()?)
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            remaining,
+            [
+                "dm-new",
+                "kept-channel",
+                "kept-user",
+                "text-new",
+                "voice-old"
+            ]
+        );
+        assert_eq!(
+            count(&knowledge, "SELECT COUNT(*) FROM messages_embeddings").await,
+            5
+        );
+        assert_eq!(
+            count(
+                &knowledge,
+                "SELECT COUNT(*) FROM messages_embeddings
+                 WHERE rowid NOT IN (SELECT rowid FROM messages)"
+            )
+            .await,
+            0
+        );
+        assert_eq!(
+            count(&knowledge, "SELECT COUNT(*) FROM interaction_messages").await,
+            1
+        );
+        assert_eq!(
+            count(&knowledge, "SELECT COUNT(*) FROM conversation_state").await,
+            0
+        );
+        assert_eq!(
+            count(&knowledge, "SELECT stale FROM session_summaries").await,
+            1
+        );
+
+        // Nothing is left to purge
+        let again = knowledge.apply_retention(&policy(), now).await.unwrap();
+        assert_eq!(again, PurgeReport::default());
+        assert_eq!(
+            count(&knowledge, "SELECT COUNT(*) FROM retention_purges").await,
+            2
+        );
+    }
+}
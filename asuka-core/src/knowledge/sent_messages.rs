@@ -0,0 +1,242 @@
+//! The platform messages each answer went out as, one row per chunk, so a
+//! reaction to, an edit of or a `/why` about one of the bot's messages can be
+//! tied back to the interaction it answered.
+
+use chrono::{DateTime, Utc};
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+use rusqlite::OptionalExtension;
+
+use super::{store::KnowledgeBase, types::Source};
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS sent_messages (
+        interaction_id INTEGER NOT NULL REFERENCES interactions(id) ON DELETE CASCADE,
+        chunk_index INTEGER NOT NULL,
+        source TEXT NOT NULL,
+        channel_id TEXT NOT NULL,
+        platform_message_id TEXT NOT NULL,
+        sent_at TEXT NOT NULL,
+        PRIMARY KEY (interaction_id, chunk_index)
+    );
+    CREATE INDEX IF NOT EXISTS idx_sent_messages_platform
+        ON sent_messages(source, platform_message_id);
+";
+
+/// One chunk of an answer, as sent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SentMessage {
+    pub interaction_id: i64,
+    /// Position of the chunk in the answer, from 0.
+    pub chunk_index: usize,
+    pub source: Source,
+    pub channel_id: String,
+    pub platform_message_id: String,
+    pub sent_at: DateTime<Utc>,
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Records the messages, in chunk order, that the answer to
+    /// `interaction_id` went out as. Only chunks actually sent should be
+    /// passed: after a failed chunk, those before it.
+    pub async fn record_sent_messages(
+        &self,
+        interaction_id: i64,
+        source: &Source,
+        channel_id: &str,
+        message_ids: &[String],
+    ) -> Result<(), SqliteError> {
+        let source = source.as_str().to_string();
+        let channel_id = channel_id.to_string();
+        let message_ids = message_ids.to_vec();
+        let sent_at = self.clock.now_utc();
+
+        self.conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                for (chunk_index, message_id) in message_ids.iter().enumerate() {
+                    tx.execute(
+                        "INSERT OR REPLACE INTO sent_messages
+                             (interaction_id, chunk_index, source, channel_id, platform_message_id, sent_at)
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                        rusqlite::params![
+                            interaction_id,
+                            chunk_index,
+                            source,
+                            channel_id,
+                            message_id,
+                            sent_at
+                        ],
+                    )?;
+                }
+                tx.commit()?;
+                Ok(())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// The messages the answer to `interaction_id` went out as, in chunk
+    /// order.
+    pub async fn sent_messages(
+        &self,
+        interaction_id: i64,
+    ) -> Result<Vec<SentMessage>, SqliteError> {
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT s.interaction_id, s.chunk_index, s.source, s.channel_id,
+                         s.platform_message_id, s.sent_at
+                     FROM sent_messages s
+                     JOIN interactions i ON i.id = s.interaction_id
+                     WHERE s.interaction_id = ?1 AND i.agent_id = ?2
+                     ORDER BY s.chunk_index",
+                )?;
+                let messages = stmt
+                    .query_map(rusqlite::params![interaction_id, namespace], |row| {
+                        let source: String = row.get(2)?;
+                        Ok(SentMessage {
+                            interaction_id: row.get(0)?,
+                            chunk_index: row.get(1)?,
+                            source: Source::from_str(&source).ok_or_else(|| {
+                                rusqlite::Error::InvalidColumnType(
+                                    2,
+                                    "source".to_string(),
+                                    rusqlite::types::Type::Text,
+                                )
+                            })?,
+                            channel_id: row.get(3)?,
+                            platform_message_id: row.get(4)?,
+                            sent_at: row.get(5)?,
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(messages)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// The interaction whose answer includes the message `message_id` sent on
+    /// `source`.
+    pub async fn interaction_for_platform_message(
+        &self,
+        source: &Source,
+        message_id: &str,
+    ) -> Result<Option<i64>, SqliteError> {
+        let source = source.as_str().to_string();
+        let message_id = message_id.to_string();
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        "SELECT s.interaction_id FROM sent_messages s
+                         JOIN interactions i ON i.id = s.interaction_id
+                         WHERE s.source = ?1 AND s.platform_message_id = ?2 AND i.agent_id = ?3
+                         ORDER BY s.interaction_id DESC
+                         LIMIT 1",
+                        rusqlite::params![source, message_id, namespace],
+                        |row| row.get(0),
+                    )
+                    .optional()?)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Appends the correction `notice` to the stored reply that went out as
+    /// the message `message_id` on `source`, embedding it again so retrieval
+    /// sees the correction. Returns whether there was such a reply.
+    pub async fn correct_reply(
+        &self,
+        source: &Source,
+        message_id: &str,
+        notice: &str,
+    ) -> anyhow::Result<bool> {
+        let Some(interaction_id) = self
+            .interaction_for_platform_message(source, message_id)
+            .await?
+        else {
+            return Ok(false);
+        };
+        let Some(reply) = self.interaction_reply(interaction_id).await? else {
+            return Ok(false);
+        };
+        let content = format!("{}\n\n{}", reply.content.trim_end(), notice.trim());
+        self.update_message_content(&reply.id, content).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn test_sent_messages_lookup() {
+        let knowledge = test_utils::knowledge_base().await;
+        let interaction = knowledge
+            .create_interaction(
+                "c1".to_string(),
+                "alice".to_string(),
+                vec!["m1".to_string()],
+            )
+            .await
+            .unwrap();
+        knowledge
+            .record_sent_messages(
+                interaction,
+                &Source::Telegram,
+                "c1",
+                &["101".to_string(), "102".to_string()],
+            )
+            .await
+            .unwrap();
+
+        let sent = knowledge.sent_messages(interaction).await.unwrap();
+        assert_eq!(
+            sent.iter()
+                .map(|sent| (sent.chunk_index, sent.platform_message_id.as_str()))
+                .collect::<Vec<_>>(),
+            [(0, "101"), (1, "102")]
+        );
+        assert!(sent
+            .iter()
+            .all(|sent| sent.source == Source::Telegram && sent.channel_id == "c1"));
+
+        assert_eq!(
+            knowledge
+                .interaction_for_platform_message(&Source::Telegram, "102")
+                .await
+                .unwrap(),
+            Some(interaction)
+        );
+        // Ids are only unique per platform
+        assert_eq!(
+            knowledge
+                .interaction_for_platform_message(&Source::Discord, "102")
+                .await
+                .unwrap(),
+            None
+        );
+        // Reached from the message id alone, as `/toolcalls` does
+        assert_eq!(
+            knowledge.interaction_for_message("101").await.unwrap(),
+            Some(interaction)
+        );
+
+        let other = knowledge.clone().with_namespace("companion");
+        assert_eq!(
+            other
+                .interaction_for_platform_message(&Source::Telegram, "101")
+                .await
+                .unwrap(),
+            None
+        );
+        assert!(other.sent_messages(interaction).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,381 @@
+//! One running instance per database. A second bot started on the same file
+//! would connect to the same platforms and answer every message again, so
+//! the instance serving a database holds a lock row, refreshed by a
+//! heartbeat, that other instances refuse to start against.
+//!
+//! A lock whose heartbeat is older than [ServiceLockConfig::stale_after] is
+//! left over from a crashed instance and taken over. A live one is only
+//! taken over when forced, after which its holder finds out at its next
+//! heartbeat and [ServiceLock::lost] fires.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+use chrono::{DateTime, Utc};
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+use rusqlite::{OptionalExtension, TransactionBehavior};
+use thiserror::Error;
+use tokio_rusqlite::Connection;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use super::store::KnowledgeBase;
+use crate::clock::Clock;
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS service_lock (
+        id INTEGER PRIMARY KEY CHECK (id = 1),
+        instance_id TEXT NOT NULL,
+        pid INTEGER NOT NULL,
+        acquired_at TEXT NOT NULL,
+        heartbeat_at TEXT NOT NULL
+    );
+";
+
+#[derive(Debug, Clone)]
+pub struct ServiceLockConfig {
+    /// How often the holder refreshes the lock.
+    pub heartbeat_interval: Duration,
+    /// Age of the last heartbeat after which the holder is presumed dead.
+    pub stale_after: Duration,
+}
+
+impl Default for ServiceLockConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: Duration::from_secs(10),
+            stale_after: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ServiceLockError {
+    #[error(
+        "Another instance ({instance_id}, pid {pid}) is running on this database, last seen \
+         at {heartbeat_at}; stop it, or force a takeover"
+    )]
+    Held {
+        instance_id: String,
+        pid: u32,
+        heartbeat_at: DateTime<Utc>,
+    },
+    #[error("The service lock was taken over by another instance")]
+    Lost,
+    #[error(transparent)]
+    Database(#[from] SqliteError),
+}
+
+/// The holder of a lock row, as stored.
+struct Holder {
+    instance_id: String,
+    pid: u32,
+    heartbeat_at: DateTime<Utc>,
+}
+
+/// Id of a new instance: the process id, when it started and a counter, so
+/// that instances of one process, or processes of different containers
+/// sharing a pid, differ.
+fn new_instance_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let started = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    format!(
+        "{}-{started}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// The lock held by this instance, see the [module docs](self). Clones
+/// share it.
+#[derive(Clone)]
+pub struct ServiceLock {
+    conn: Connection,
+    clock: Arc<dyn Clock>,
+    config: ServiceLockConfig,
+    instance_id: String,
+    lost: CancellationToken,
+    released: CancellationToken,
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Takes the database's service lock for a new instance. Fails with
+    /// [ServiceLockError::Held] while another instance's heartbeat is fresh,
+    /// unless `force` is set.
+    pub async fn acquire_service_lock(
+        &self,
+        config: &ServiceLockConfig,
+        force: bool,
+    ) -> Result<ServiceLock, ServiceLockError> {
+        let instance_id = new_instance_id();
+        let now = self.clock.now_utc();
+        let stale_after = config.stale_after;
+
+        let acquired = self
+            .conn
+            .call({
+                let instance_id = instance_id.clone();
+                move |conn| {
+                    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                    let holder = tx
+                        .query_row(
+                            "SELECT instance_id, pid, heartbeat_at FROM service_lock WHERE id = 1",
+                            [],
+                            |row| {
+                                Ok(Holder {
+                                    instance_id: row.get(0)?,
+                                    pid: row.get(1)?,
+                                    heartbeat_at: row.get(2)?,
+                                })
+                            },
+                        )
+                        .optional()?;
+                    let stale = holder.as_ref().map(|holder| {
+                        (now - holder.heartbeat_at)
+                            .to_std()
+                            .is_ok_and(|age| age >= stale_after)
+                    });
+                    if let (Some(holder), Some(false)) = (&holder, stale) {
+                        if !force {
+                            return Ok(Err(ServiceLockError::Held {
+                                instance_id: holder.instance_id.clone(),
+                                pid: holder.pid,
+                                heartbeat_at: holder.heartbeat_at,
+                            }));
+                        }
+                    }
+
+                    tx.execute(
+                        "INSERT OR REPLACE INTO service_lock
+                             (id, instance_id, pid, acquired_at, heartbeat_at)
+                         VALUES (1, ?1, ?2, ?3, ?3)",
+                        rusqlite::params![instance_id, std::process::id(), now.to_rfc3339()],
+                    )?;
+                    tx.commit()?;
+                    Ok(Ok(holder.zip(stale)))
+                }
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))?;
+
+        match acquired? {
+            Some((holder, true)) => info!(
+                previous = holder.instance_id,
+                last_seen = %holder.heartbeat_at,
+                "Previous instance stopped without releasing the service lock, taking it over"
+            ),
+            Some((holder, false)) => warn!(
+                previous = holder.instance_id,
+                pid = holder.pid,
+                "Forcing a takeover of the service lock from a running instance"
+            ),
+            None => {}
+        }
+        info!(instance_id, "Acquired the service lock");
+
+        Ok(ServiceLock {
+            conn: self.conn.clone(),
+            clock: self.clock.clone(),
+            config: config.clone(),
+            instance_id,
+            lost: CancellationToken::new(),
+            released: CancellationToken::new(),
+        })
+    }
+}
+
+impl ServiceLock {
+    pub fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+
+    /// Cancelled once another instance took the lock over, after which this
+    /// one should stop.
+    pub fn lost(&self) -> CancellationToken {
+        self.lost.clone()
+    }
+
+    /// Refreshes the lock's heartbeat. Fails with [ServiceLockError::Lost],
+    /// and cancels [ServiceLock::lost], when another instance holds it.
+    pub async fn heartbeat(&self) -> Result<(), ServiceLockError> {
+        let instance_id = self.instance_id.clone();
+        let now = self.clock.now_utc();
+
+        let updated = self
+            .conn
+            .call(move |conn| {
+                Ok(conn.execute(
+                    "UPDATE service_lock SET heartbeat_at = ?1 WHERE id = 1 AND instance_id = ?2",
+                    rusqlite::params![now.to_rfc3339(), instance_id],
+                )?)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))?;
+        if updated == 0 {
+            self.lost.cancel();
+            return Err(ServiceLockError::Lost);
+        }
+        Ok(())
+    }
+
+    /// Refreshes the heartbeat every [ServiceLockConfig::heartbeat_interval]
+    /// until the lock is released or lost. Failing writes are retried at the
+    /// next beat, the lock only goes stale if they keep failing.
+    pub fn spawn_heartbeat(&self) -> tokio::task::JoinHandle<()> {
+        let lock = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = lock.released.cancelled() => return,
+                    _ = tokio::time::sleep(lock.config.heartbeat_interval) => {}
+                }
+                match lock.heartbeat().await {
+                    Ok(()) => {}
+                    Err(ServiceLockError::Lost) => {
+                        error!(
+                            instance_id = lock.instance_id,
+                            "Another instance took over the service lock"
+                        );
+                        return;
+                    }
+                    Err(err) => warn!(?err, "Failed to refresh the service lock"),
+                }
+            }
+        })
+    }
+
+    /// Releases the lock, so the next instance starts right away. A lock
+    /// taken over by another instance is left to it.
+    pub async fn release(self) -> Result<(), ServiceLockError> {
+        self.released.cancel();
+        let instance_id = self.instance_id.clone();
+
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "DELETE FROM service_lock WHERE id = 1 AND instance_id = ?1",
+                    [&instance_id],
+                )?;
+                Ok(())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))?;
+        info!(instance_id = self.instance_id, "Released the service lock");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::MockClock,
+        test_utils::{self, FakeEmbeddingModel},
+    };
+
+    /// A knowledge base on `file`, as an instance would open it.
+    async fn open(
+        file: &tempfile::NamedTempFile,
+        clock: &MockClock,
+    ) -> KnowledgeBase<FakeEmbeddingModel> {
+        test_utils::load_sqlite_vec();
+        let conn = Connection::open(file.path()).await.unwrap();
+        KnowledgeBase::new(conn, FakeEmbeddingModel)
+            .await
+            .unwrap()
+            .with_clock(Arc::new(clock.clone()))
+    }
+
+    fn clock() -> MockClock {
+        MockClock::new("2024-11-05T08:00:00Z".parse().unwrap())
+    }
+
+    fn seconds(secs: i64) -> chrono::Duration {
+        chrono::Duration::seconds(secs)
+    }
+
+    #[tokio::test]
+    async fn test_second_instance_is_refused() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let clock = clock();
+        let (first, second) = (open(&file, &clock).await, open(&file, &clock).await);
+        let config = ServiceLockConfig::default();
+
+        let lock = first.acquire_service_lock(&config, false).await.unwrap();
+        let err = second
+            .acquire_service_lock(&config, false)
+            .await
+            .err()
+            .unwrap();
+        assert!(
+            matches!(&err, ServiceLockError::Held { instance_id, .. } if instance_id == lock.instance_id()),
+            "{err}"
+        );
+
+        // Heartbeats keep the lock past the stale threshold
+        for _ in 0..10 {
+            clock.advance(seconds(10));
+            lock.heartbeat().await.unwrap();
+        }
+        assert!(second.acquire_service_lock(&config, false).await.is_err());
+
+        // Releasing it lets the next instance start right away
+        lock.release().await.unwrap();
+        second.acquire_service_lock(&config, false).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stale_lock_expires() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let clock = clock();
+        let (first, second) = (open(&file, &clock).await, open(&file, &clock).await);
+        let config = ServiceLockConfig::default();
+
+        // The first instance crashes without releasing the lock
+        let crashed = first.acquire_service_lock(&config, false).await.unwrap();
+        clock.advance(seconds(59));
+        assert!(second.acquire_service_lock(&config, false).await.is_err());
+
+        clock.advance(seconds(1));
+        let lock = second.acquire_service_lock(&config, false).await.unwrap();
+        lock.heartbeat().await.unwrap();
+
+        // Nor does it get the lock back if it was only stalled
+        assert!(matches!(
+            crashed.heartbeat().await,
+            Err(ServiceLockError::Lost)
+        ));
+        assert!(crashed.lost().is_cancelled());
+        assert!(!lock.lost().is_cancelled());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_force_takeover() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let clock = clock();
+        let (first, second) = (open(&file, &clock).await, open(&file, &clock).await);
+        let config = ServiceLockConfig::default();
+
+        let old = first.acquire_service_lock(&config, false).await.unwrap();
+        let lock = second.acquire_service_lock(&config, true).await.unwrap();
+
+        // The old instance finds out at its next heartbeat and stops beating
+        let heartbeat = old.spawn_heartbeat();
+        heartbeat.await.unwrap();
+        assert!(old.lost().is_cancelled());
+
+        // and releasing its lock leaves the new holder's alone
+        old.release().await.unwrap();
+        lock.heartbeat().await.unwrap();
+        assert!(first.acquire_service_lock(&config, false).await.is_err());
+    }
+}
@@ -0,0 +1,677 @@
+//! Snapshots of a namespace's documents with their embeddings, so a fresh
+//! deployment can start from a published file instead of embedding every
+//! document again.
+//!
+//! A snapshot is a JSON lines file: a [SnapshotManifest] on the first line,
+//! then one line per document. It is identified by the SHA-256 of the file,
+//! which [KnowledgeBase::bootstrap_from_url] verifies before importing and
+//! records as the store's [SNAPSHOT_VERSION] in `store_meta`.
+
+use std::{collections::HashMap, path::Path, time::Duration};
+
+use rig::{
+    embeddings::{Embedding, EmbeddingModel},
+    OneOrMany,
+};
+use rig_sqlite::SqliteError;
+use rusqlite::OptionalExtension;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+
+use super::{
+    chunks::{self, ChunkPosition},
+    identity::content_hash,
+    models::Document,
+    store::KnowledgeBase,
+};
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS store_meta (
+        agent_id TEXT NOT NULL,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+        PRIMARY KEY (agent_id, key)
+    );
+";
+
+/// `store_meta` key holding the checksum of the snapshot a namespace was
+/// bootstrapped from.
+pub const SNAPSHOT_VERSION: &str = "snapshot_version";
+
+/// Version of the snapshot file layout.
+const SNAPSHOT_FORMAT: u32 = 1;
+
+/// Download attempts before giving up, each resuming where the last stopped.
+const MAX_ATTEMPTS: u32 = 5;
+
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    #[error("Snapshot URL must use https: {0}")]
+    InsecureUrl(String),
+    #[error("Invalid snapshot checksum {0:?}, expected a SHA-256 hex digest")]
+    InvalidChecksum(String),
+    #[error("Failed to download snapshot: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("Snapshot server responded with status {0}")]
+    Status(u16),
+    #[error("Snapshot file error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Snapshot checksum mismatch: expected {expected}, got {actual}")]
+    Checksum { expected: String, actual: String },
+    #[error("Invalid snapshot: {0}")]
+    Format(String),
+    #[error("Snapshot embeddings have {snapshot} dimensions, the model has {model}")]
+    Dimensions { snapshot: usize, model: usize },
+    #[error("Knowledge base already holds {0} documents, use force to replace them")]
+    NotEmpty(usize),
+    #[error("Failed to store snapshot: {0}")]
+    Store(#[from] tokio_rusqlite::Error),
+}
+
+impl SnapshotError {
+    /// Whether another download attempt may succeed.
+    fn is_transient(&self) -> bool {
+        match self {
+            SnapshotError::Http(_) => true,
+            SnapshotError::Status(status) => *status >= 500,
+            _ => false,
+        }
+    }
+}
+
+/// First line of a snapshot file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotManifest {
+    pub format: u32,
+    /// Type of the embedding model the vectors were made with.
+    pub embedding_model: String,
+    pub dims: usize,
+    pub document_count: usize,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// A snapshot written by [KnowledgeBase::publish_snapshot].
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublishedSnapshot {
+    pub manifest: SnapshotManifest,
+    /// SHA-256 of the file, in hex, to pass to
+    /// [KnowledgeBase::bootstrap_from_url].
+    pub checksum: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotDocument {
+    id: String,
+    source_id: String,
+    content: String,
+    created_at: chrono::DateTime<chrono::Utc>,
+    /// Missing from snapshots published before documents had titles.
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    section: String,
+    topics: Vec<String>,
+    /// Missing from snapshots published before parts had positions.
+    #[serde(default)]
+    chunk: Option<ChunkPosition>,
+    logical_id: String,
+    superseded: bool,
+    embedding: Vec<f32>,
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Writes every document of this namespace, with its embedding, topics
+    /// and version, to a snapshot file at `path`.
+    pub async fn publish_snapshot(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Result<PublishedSnapshot, SnapshotError> {
+        let namespace = self.namespace.clone();
+        let documents = self
+            .conn
+            .call(move |conn| {
+                let mut topics: HashMap<String, Vec<String>> = HashMap::new();
+                let mut stmt = conn.prepare(
+                    "SELECT t.document_id, t.topic FROM document_topics t
+                     JOIN documents d ON d.id = t.document_id
+                     WHERE d.agent_id = ?1
+                     ORDER BY t.topic",
+                )?;
+                let rows = stmt.query_map([&namespace], |row| Ok((row.get(0)?, row.get(1)?)))?;
+                for row in rows {
+                    let (document_id, topic): (String, String) = row?;
+                    topics.entry(document_id).or_default().push(topic);
+                }
+
+                let mut stmt = conn.prepare(
+                    "SELECT d.id, d.source_id, d.content, d.created_at,
+                            COALESCE(v.logical_id, d.id), COALESCE(v.superseded, 0), e.embedding,
+                            d.title, d.section,
+                            c.chunk_index, c.chunk_count, c.start_offset, c.end_offset
+                     FROM documents d
+                     JOIN documents_embeddings e ON e.rowid = d.rowid
+                     LEFT JOIN document_versions v ON v.document_id = d.id
+                     LEFT JOIN document_chunks c ON c.document_id = d.id
+                     WHERE d.agent_id = ?1
+                     ORDER BY d.id",
+                )?;
+                let documents = stmt
+                    .query_map([&namespace], |row| {
+                        let id: String = row.get(0)?;
+                        let blob: Vec<u8> = row.get(6)?;
+                        let chunk = match row.get::<_, Option<usize>>(9)? {
+                            Some(index) => Some(ChunkPosition {
+                                index,
+                                count: row.get(10)?,
+                                start: row.get(11)?,
+                                end: row.get(12)?,
+                            }),
+                            None => None,
+                        };
+                        Ok(SnapshotDocument {
+                            topics: topics.remove(&id).unwrap_or_default(),
+                            id,
+                            source_id: row.get(1)?,
+                            content: row.get(2)?,
+                            created_at: row.get(3)?,
+                            title: row.get(7)?,
+                            section: row.get(8)?,
+                            chunk,
+                            logical_id: row.get(4)?,
+                            superseded: row.get(5)?,
+                            embedding: blob
+                                .chunks_exact(4)
+                                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                                .collect(),
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(documents)
+            })
+            .await?;
+
+        let manifest = SnapshotManifest {
+            format: SNAPSHOT_FORMAT,
+            embedding_model: std::any::type_name::<E>().to_string(),
+            dims: self.embedding_model.ndims(),
+            document_count: documents.len(),
+            created_at: chrono::Utc::now(),
+        };
+        let mut bytes =
+            serde_json::to_vec(&manifest).map_err(|e| SnapshotError::Format(e.to_string()))?;
+        for document in &documents {
+            bytes.push(b'\n');
+            serde_json::to_writer(&mut bytes, document)
+                .map_err(|e| SnapshotError::Format(e.to_string()))?;
+        }
+        bytes.push(b'\n');
+        tokio::fs::write(path, &bytes).await?;
+
+        info!(
+            documents = manifest.document_count,
+            "Published knowledge snapshot"
+        );
+        Ok(PublishedSnapshot {
+            manifest,
+            checksum: format!("{:x}", Sha256::digest(&bytes)),
+        })
+    }
+
+    /// Downloads the snapshot at `url`, verifies it against `checksum` and
+    /// imports it into this namespace.
+    ///
+    /// Interrupted downloads resume from where they stopped. The namespace
+    /// must hold no documents unless `force` is set, in which case its
+    /// documents are replaced. The import runs in a single transaction, so a
+    /// failed download or import leaves the store as it was.
+    ///
+    /// Only https URLs are accepted, except for loopback hosts.
+    pub async fn bootstrap_from_url(
+        &self,
+        url: &str,
+        checksum: &str,
+        force: bool,
+    ) -> Result<SnapshotManifest, SnapshotError> {
+        let checksum = checksum.trim().to_lowercase();
+        if checksum.len() != 64 || !checksum.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(SnapshotError::InvalidChecksum(checksum));
+        }
+        check_url(url)?;
+        if !force {
+            self.ensure_empty().await?;
+        }
+
+        let path = std::env::temp_dir().join(format!("asuka-snapshot-{checksum}.part"));
+        download(url, &path).await?;
+
+        let bytes = tokio::fs::read(&path).await?;
+        let actual = format!("{:x}", Sha256::digest(&bytes));
+        if actual != checksum {
+            // A corrupt file must not be resumed on the next attempt.
+            tokio::fs::remove_file(&path).await.ok();
+            return Err(SnapshotError::Checksum {
+                expected: checksum,
+                actual,
+            });
+        }
+
+        let (manifest, documents) = parse(&bytes, self.embedding_model.ndims())?;
+        if manifest.embedding_model != std::any::type_name::<E>() {
+            warn!(
+                snapshot = manifest.embedding_model,
+                "Snapshot was embedded with a different model type"
+            );
+        }
+        if !force {
+            self.ensure_empty().await?;
+        }
+        self.import_snapshot(documents, checksum).await?;
+        tokio::fs::remove_file(&path).await.ok();
+
+        info!(
+            documents = manifest.document_count,
+            namespace = self.namespace,
+            "Bootstrapped knowledge base from snapshot"
+        );
+        Ok(manifest)
+    }
+
+    /// A value recorded in `store_meta` for this namespace, such as
+    /// [SNAPSHOT_VERSION].
+    pub async fn store_meta(&self, key: &str) -> Result<Option<String>, SqliteError> {
+        let key = key.to_string();
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        "SELECT value FROM store_meta WHERE agent_id = ?1 AND key = ?2",
+                        [&namespace, &key],
+                        |row| row.get(0),
+                    )
+                    .optional()?)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    async fn ensure_empty(&self) -> Result<(), SnapshotError> {
+        let namespace = self.namespace.clone();
+        let count: usize = self
+            .conn
+            .call(move |conn| {
+                Ok(conn.query_row(
+                    "SELECT COUNT(*) FROM documents WHERE agent_id = ?1",
+                    [&namespace],
+                    |row| row.get(0),
+                )?)
+            })
+            .await?;
+        match count {
+            0 => Ok(()),
+            count => Err(SnapshotError::NotEmpty(count)),
+        }
+    }
+
+    async fn import_snapshot(
+        &self,
+        documents: Vec<SnapshotDocument>,
+        checksum: String,
+    ) -> Result<(), tokio_rusqlite::Error> {
+        let store = self.document_store.clone();
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                tx.execute(
+                    "DELETE FROM documents_embeddings
+                     WHERE rowid IN (SELECT rowid FROM documents WHERE agent_id = ?1)",
+                    [&namespace],
+                )?;
+                tx.execute(
+                    "DELETE FROM document_topics
+                     WHERE document_id IN (SELECT id FROM documents WHERE agent_id = ?1)",
+                    [&namespace],
+                )?;
+                tx.execute(
+                    "DELETE FROM document_chunks
+                     WHERE document_id IN (SELECT id FROM documents WHERE agent_id = ?1)",
+                    [&namespace],
+                )?;
+                tx.execute(
+                    "DELETE FROM document_versions WHERE agent_id = ?1",
+                    [&namespace],
+                )?;
+                tx.execute("DELETE FROM documents WHERE agent_id = ?1", [&namespace])?;
+
+                let rows = documents
+                    .iter()
+                    .map(|document| {
+                        let embedding = Embedding {
+                            document: document.content.clone(),
+                            vec: document.embedding.iter().map(|x| *x as f64).collect(),
+                        };
+                        let row = Document {
+                            id: document.id.clone(),
+                            source_id: document.source_id.clone(),
+                            content: document.content.clone(),
+                            created_at: document.created_at,
+                            title: document.title.clone(),
+                            section: document.section.clone(),
+                            topics: document.topics.clone(),
+                            logical_id: Some(document.logical_id.clone()),
+                            cleaned: None,
+                            chunk: document.chunk,
+                        };
+                        (row, OneOrMany::one(embedding))
+                    })
+                    .collect::<Vec<_>>();
+                store.add_rows_with_txn(&tx, rows)?;
+
+                for document in &documents {
+                    tx.execute(
+                        "UPDATE documents SET agent_id = ?1 WHERE id = ?2",
+                        [&namespace, &document.id],
+                    )?;
+                    for topic in &document.topics {
+                        tx.execute(
+                            "INSERT OR IGNORE INTO document_topics (document_id, topic)
+                             VALUES (?1, ?2)",
+                            [&document.id, topic],
+                        )?;
+                    }
+                    if let Some(position) = &document.chunk {
+                        chunks::insert_position(&tx, &document.id, position)?;
+                    }
+                    tx.execute(
+                        "INSERT OR REPLACE INTO document_versions
+                             (document_id, logical_id, superseded, agent_id, content_hash)
+                         VALUES (?1, ?2, ?3, ?4, ?5)",
+                        rusqlite::params![
+                            document.id,
+                            document.logical_id,
+                            document.superseded,
+                            namespace,
+                            content_hash(&document.content)
+                        ],
+                    )?;
+                }
+
+                tx.execute(
+                    "INSERT INTO store_meta (agent_id, key, value) VALUES (?1, ?2, ?3)
+                     ON CONFLICT (agent_id, key) DO UPDATE SET
+                         value = excluded.value,
+                         updated_at = CURRENT_TIMESTAMP",
+                    [&namespace, SNAPSHOT_VERSION, &checksum],
+                )?;
+                tx.commit()?;
+                Ok(())
+            })
+            .await
+    }
+}
+
+fn check_url(url: &str) -> Result<(), SnapshotError> {
+    if is_secure_url(url) {
+        Ok(())
+    } else {
+        Err(SnapshotError::InsecureUrl(url.to_string()))
+    }
+}
+
+/// Whether `url` is https, or http to a loopback host as in tests.
+pub(crate) fn is_secure_url(url: &str) -> bool {
+    let Ok(parsed) = reqwest::Url::parse(url) else {
+        return false;
+    };
+    let loopback = parsed.host_str().is_some_and(|host| {
+        host == "localhost"
+            || host
+                .trim_matches(['[', ']'])
+                .parse::<std::net::IpAddr>()
+                .is_ok_and(|ip| ip.is_loopback())
+    });
+    match parsed.scheme() {
+        "https" => true,
+        "http" => loopback,
+        _ => false,
+    }
+}
+
+/// Downloads `url` to `path`, resuming from the bytes already in `path`.
+async fn download(url: &str, path: &Path) -> Result<(), SnapshotError> {
+    let client = reqwest::Client::new();
+    let mut attempt = 1;
+    loop {
+        match download_attempt(&client, url, path).await {
+            Ok(()) => return Ok(()),
+            Err(err) if err.is_transient() && attempt < MAX_ATTEMPTS => {
+                warn!(?err, attempt, "Snapshot download interrupted, resuming");
+                tokio::time::sleep(RETRY_DELAY * attempt).await;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+async fn download_attempt(
+    client: &reqwest::Client,
+    url: &str,
+    path: &Path,
+) -> Result<(), SnapshotError> {
+    let offset = match tokio::fs::metadata(path).await {
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0,
+    };
+    let mut request = client.get(url);
+    if offset > 0 {
+        request = request.header(reqwest::header::RANGE, format!("bytes={offset}-"));
+    }
+    let mut response = request.send().await?;
+
+    let append = match response.status() {
+        reqwest::StatusCode::PARTIAL_CONTENT => true,
+        // The previous attempt already got every byte.
+        reqwest::StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => return Ok(()),
+        status if status.is_success() => false,
+        status => return Err(SnapshotError::Status(status.as_u16())),
+    };
+    let mut options = tokio::fs::OpenOptions::new();
+    if append {
+        options.append(true);
+    } else {
+        options.write(true).create(true).truncate(true);
+    }
+    let mut file = options.open(path).await?;
+
+    while let Some(chunk) = response.chunk().await? {
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+fn parse(
+    bytes: &[u8],
+    dims: usize,
+) -> Result<(SnapshotManifest, Vec<SnapshotDocument>), SnapshotError> {
+    let format_error = |e: serde_json::Error| SnapshotError::Format(e.to_string());
+    let mut lines = bytes.split(|b| *b == b'\n').filter(|line| !line.is_empty());
+
+    let manifest: SnapshotManifest = serde_json::from_slice(
+        lines
+            .next()
+            .ok_or_else(|| SnapshotError::Format("missing manifest".to_string()))?,
+    )
+    .map_err(format_error)?;
+    if manifest.format != SNAPSHOT_FORMAT {
+        return Err(SnapshotError::Format(format!(
+            "unsupported format {}",
+            manifest.format
+        )));
+    }
+    if manifest.dims != dims {
+        return Err(SnapshotError::Dimensions {
+            snapshot: manifest.dims,
+            model: dims,
+        });
+    }
+
+    let documents = lines
+        .map(|line| serde_json::from_slice::<SnapshotDocument>(line).map_err(format_error))
+        .collect::<Result<Vec<_>, _>>()?;
+    if documents.len() != manifest.document_count {
+        return Err(SnapshotError::Format(format!(
+            "manifest lists {} documents, found {}",
+            manifest.document_count,
+            documents.len()
+        )));
+    }
+    if let Some(document) = documents.iter().find(|d| d.embedding.len() != dims) {
+        return Err(SnapshotError::Format(format!(
+            "embedding of {} has {} dimensions",
+            document.id,
+            document.embedding.len()
+        )));
+    }
+    Ok((manifest, documents))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{self, StaticFileServer};
+    use rig::vector_store::VectorStoreIndex;
+
+    fn doc(id: &str, content: &str, topics: &[&str]) -> Document {
+        Document {
+            id: id.to_string(),
+            source_id: "github".to_string(),
+            content: content.to_string(),
+            created_at: chrono::Utc::now(),
+            title: String::new(),
+            section: String::new(),
+            topics: topics.iter().map(|t| t.to_string()).collect(),
+            logical_id: None,
+            cleaned: None,
+            chunk: None,
+        }
+    }
+
+    async fn published() -> (Vec<u8>, PublishedSnapshot) {
+        let mut source = test_utils::knowledge_base().await.with_namespace("common");
+        source
+            .add_documents(vec![
+                doc("vrf.md", "vrf requests are free", &["vrf"]),
+                doc("katana.md", "katana devnet flags", &["katana"]),
+                doc("paymaster.md", "paymaster sponsors fees", &[]),
+            ])
+            .await
+            .unwrap();
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let snapshot = source.publish_snapshot(file.path()).await.unwrap();
+        (std::fs::read(file.path()).unwrap(), snapshot)
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_resumes_interrupted_download() {
+        let (bytes, snapshot) = published().await;
+        assert_eq!(snapshot.manifest.document_count, 3);
+        assert_eq!(snapshot.manifest.dims, test_utils::FAKE_DIMS);
+
+        let server = StaticFileServer::start(bytes.clone(), Some(bytes.len() / 2)).await;
+        let knowledge = test_utils::knowledge_base().await.with_namespace("common");
+        let manifest = knowledge
+            .bootstrap_from_url(&server.url(), &snapshot.checksum, false)
+            .await
+            .unwrap();
+        assert_eq!(manifest, snapshot.manifest);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            requests[1].headers.get("range"),
+            Some(&format!("bytes={}-", bytes.len() / 2))
+        );
+
+        let ids = knowledge
+            .clone()
+            .document_index()
+            .top_n_ids("katana devnet flags", 1)
+            .await
+            .unwrap();
+        assert_eq!(ids[0].1, "katana.md");
+        assert_eq!(
+            knowledge
+                .document_topics(vec!["vrf.md".to_string()])
+                .await
+                .unwrap()
+                .get("vrf.md"),
+            Some(&["vrf".to_string()].into())
+        );
+        assert_eq!(
+            knowledge.document_versions("vrf.md").await.unwrap().len(),
+            1
+        );
+        assert_eq!(
+            knowledge.store_meta(SNAPSHOT_VERSION).await.unwrap(),
+            Some(snapshot.checksum.clone())
+        );
+
+        // A second bootstrap needs force.
+        assert!(matches!(
+            knowledge
+                .bootstrap_from_url(&server.url(), &snapshot.checksum, false)
+                .await,
+            Err(SnapshotError::NotEmpty(3))
+        ));
+        knowledge
+            .bootstrap_from_url(&server.url(), &snapshot.checksum, true)
+            .await
+            .unwrap();
+        let ids = knowledge
+            .clone()
+            .document_index()
+            .top_n_ids("vrf", 5)
+            .await
+            .unwrap();
+        assert_eq!(ids.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_snapshot_leaves_store_untouched() {
+        let (mut bytes, snapshot) = published().await;
+        let last = bytes.len() - 2;
+        bytes[last] ^= 1;
+
+        let server = StaticFileServer::start(bytes, None).await;
+        let knowledge = test_utils::knowledge_base().await.with_namespace("common");
+        let err = knowledge
+            .bootstrap_from_url(&server.url(), &snapshot.checksum, false)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, SnapshotError::Checksum { .. }));
+        assert!(knowledge.ensure_empty().await.is_ok());
+        assert_eq!(knowledge.store_meta(SNAPSHOT_VERSION).await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_check_url() {
+        assert!(check_url("https://cdn.cartridge.gg/snapshot.jsonl").is_ok());
+        assert!(check_url("http://127.0.0.1:8080/snapshot.jsonl").is_ok());
+        assert!(matches!(
+            check_url("http://cdn.cartridge.gg/snapshot.jsonl"),
+            Err(SnapshotError::InsecureUrl(_))
+        ));
+    }
+}
@@ -0,0 +1,144 @@
+//! Where each synced knowledge source stands: the last commit stored from a
+//! repository, so the next sync only reads what changed since, and the last
+//! error, so a failing source shows up without digging through logs.
+
+use chrono::{DateTime, Utc};
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+use rusqlite::OptionalExtension;
+
+use super::store::KnowledgeBase;
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS source_state (
+        agent_id TEXT NOT NULL,
+        source TEXT NOT NULL,
+        last_commit TEXT,
+        synced_at TEXT,
+        last_error TEXT,
+        updated_at TEXT NOT NULL,
+        PRIMARY KEY (agent_id, source)
+    );
+";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceState {
+    /// Commit of the last successful sync.
+    pub last_commit: Option<String>,
+    pub synced_at: Option<DateTime<Utc>>,
+    /// Error of the last sync, cleared by a successful one.
+    pub last_error: Option<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    pub async fn source_state(&self, source: &str) -> Result<Option<SourceState>, SqliteError> {
+        let source = source.to_string();
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        "SELECT last_commit, synced_at, last_error, updated_at FROM source_state
+                         WHERE agent_id = ?1 AND source = ?2",
+                        [&namespace, &source],
+                        |row| {
+                            Ok(SourceState {
+                                last_commit: row.get(0)?,
+                                synced_at: row.get(1)?,
+                                last_error: row.get(2)?,
+                                updated_at: row.get(3)?,
+                            })
+                        },
+                    )
+                    .optional()?)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Records that `source` was synced up to `commit`.
+    pub async fn record_source_sync(&self, source: &str, commit: &str) -> Result<(), SqliteError> {
+        let source = source.to_string();
+        let commit = commit.to_string();
+        let namespace = self.namespace.clone();
+        let now = self.clock.now_utc();
+
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT INTO source_state (agent_id, source, last_commit, synced_at, updated_at)
+                     VALUES (?1, ?2, ?3, ?4, ?4)
+                     ON CONFLICT (agent_id, source) DO UPDATE SET
+                         last_commit = excluded.last_commit,
+                         synced_at = excluded.synced_at,
+                         last_error = NULL,
+                         updated_at = excluded.updated_at",
+                    rusqlite::params![namespace, source, commit, now],
+                )?;
+                Ok(())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Records that syncing `source` failed, keeping its last commit.
+    pub async fn record_source_error(&self, source: &str, error: &str) -> Result<(), SqliteError> {
+        let source = source.to_string();
+        let error = error.to_string();
+        let namespace = self.namespace.clone();
+        let now = self.clock.now_utc();
+
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT INTO source_state (agent_id, source, last_error, updated_at)
+                     VALUES (?1, ?2, ?3, ?4)
+                     ON CONFLICT (agent_id, source) DO UPDATE SET
+                         last_error = excluded.last_error,
+                         updated_at = excluded.updated_at",
+                    rusqlite::params![namespace, source, error, now],
+                )?;
+                Ok(())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn test_source_state() {
+        let knowledge = test_utils::knowledge_base().await;
+        let other = knowledge.clone().with_namespace("other");
+        assert_eq!(knowledge.source_state("docs").await.unwrap(), None);
+
+        knowledge
+            .record_source_error("docs", "offline")
+            .await
+            .unwrap();
+        let state = knowledge.source_state("docs").await.unwrap().unwrap();
+        assert_eq!(state.last_commit, None);
+        assert_eq!(state.last_error.as_deref(), Some("offline"));
+
+        knowledge.record_source_sync("docs", "abc").await.unwrap();
+        knowledge
+            .record_source_error("docs", "timeout")
+            .await
+            .unwrap();
+        let state = knowledge.source_state("docs").await.unwrap().unwrap();
+        assert_eq!(state.last_commit.as_deref(), Some("abc"));
+        assert!(state.synced_at.is_some());
+        assert_eq!(state.last_error.as_deref(), Some("timeout"));
+
+        knowledge.record_source_sync("docs", "def").await.unwrap();
+        let state = knowledge.source_state("docs").await.unwrap().unwrap();
+        assert_eq!(state.last_commit.as_deref(), Some("def"));
+        assert_eq!(state.last_error, None);
+        assert_eq!(other.source_state("docs").await.unwrap(), None);
+    }
+}
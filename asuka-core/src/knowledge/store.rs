@@ -1,28 +1,108 @@
 use rig::{
-    embeddings::{EmbeddingModel, EmbeddingsBuilder},
+    embeddings::{Embedding, EmbeddingModel, EmbeddingsBuilder},
     vector_store::VectorStoreError,
+    OneOrMany,
 };
+use rig_sqlite::SqliteVectorStoreTable;
+use std::{collections::HashMap, sync::Arc};
+use thiserror::Error;
 use tokio_rusqlite::Connection;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
+use super::chunking::ChunkingConfig;
+use super::diversity::parent_id;
+use super::embedding_policy::{self, CompiledEmbeddingPolicy};
+use super::embeddings::{self, EmbeddingService};
+use super::linked_identities::IdentityCache;
 use super::models::{Account, Channel, Document, Message};
-use rig_sqlite::{SqliteError, SqliteVectorIndex, SqliteVectorStore};
+use super::namespaces::{self, NamespaceIndex, DEFAULT_NAMESPACE};
+use super::replica::ReadReplica;
+use super::versions::StoreSummary;
+use super::{
+    activity, admin, announcements, channel_settings, chunks, cleaning, conversation_state,
+    cursors, dead_letters, deferred, escalations, exchanges, experiments, familiarity, gaps,
+    guilds, identity, interactions, languages, linked_identities, memory, onboarding, outline,
+    pending, pins, rate_limits, refresh, retention, sent_messages, service_lock, snapshot,
+    source_state, tool_calls, topics, user_facts, versions,
+};
+use crate::{
+    clock::{Clock, SystemClock},
+    events::EventBus,
+};
+use rig_sqlite::{SqliteError, SqliteVectorStore};
 use rusqlite::OptionalExtension;
 
+/// How long a write waits for another connection's transaction to finish.
+const BUSY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// The embedding model doesn't fit the vector tables, which are sized when
+/// the database is first created.
+#[derive(Error, Debug)]
+pub enum EmbeddingDimensionError {
+    #[error("The embedding model doesn't report its dimensions")]
+    Unknown,
+    #[error(
+        "{table} embeddings have {stored} dimensions but the embedding model has {model}, \
+         use the model the database was created with or a new database"
+    )]
+    Mismatch {
+        table: &'static str,
+        stored: usize,
+        model: usize,
+    },
+}
+
+/// Messages of a time window, see [KnowledgeBase::messages_between].
+#[derive(Debug, Clone)]
+pub struct MessageWindow {
+    pub messages: Vec<Message>,
+    /// Older messages in the window that were over the limit.
+    pub truncated: usize,
+}
+
 #[derive(Clone)]
 pub struct KnowledgeBase<E: EmbeddingModel + Clone + 'static> {
-    conn: Connection,
-    document_store: SqliteVectorStore<E, Document>,
-    message_store: SqliteVectorStore<E, Message>,
-    embedding_model: E,
+    pub(super) conn: Connection,
+    pub(super) document_store: SqliteVectorStore<E, Document>,
+    pub(super) message_store: SqliteVectorStore<E, Message>,
+    pub(super) embedding_model: EmbeddingService<E>,
+    pub(super) namespace: String,
+    pub(super) shared_namespaces: Vec<String>,
+    /// Time that rows are stamped and compared with.
+    pub(super) clock: Arc<dyn Clock>,
+    /// Where notable events are published, see [crate::events].
+    pub(super) events: EventBus,
+    pub(super) identities: IdentityCache,
+    /// Where document searches read from instead, see [super::replica].
+    pub(super) replica: Option<ReadReplica>,
+    /// Which messages are embedded, all without a policy.
+    pub(super) embedding_policy: Option<CompiledEmbeddingPolicy>,
+    /// How large documents are split, whole without a config.
+    pub(super) chunking: Option<ChunkingConfig>,
 }
 
 impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Opens the knowledge base, creating its tables with the dimensions of
+    /// `embedding_model` when they don't exist yet. Embeds through an
+    /// [EmbeddingService] with the default config.
     pub async fn new(conn: Connection, embedding_model: E) -> Result<Self, VectorStoreError> {
-        let document_store = SqliteVectorStore::new(conn.clone(), &embedding_model).await?;
-        let message_store = SqliteVectorStore::new(conn.clone(), &embedding_model).await?;
+        Self::with_embedding_service(conn, EmbeddingService::new(embedding_model)).await
+    }
+
+    /// Like [KnowledgeBase::new], embedding through `embedding_model`.
+    pub async fn with_embedding_service(
+        conn: Connection,
+        embedding_model: EmbeddingService<E>,
+    ) -> Result<Self, VectorStoreError> {
+        check_dimensions(&conn, embedding_model.ndims()).await?;
+        let document_store = SqliteVectorStore::new(conn.clone(), embedding_model.model()).await?;
+        let message_store = SqliteVectorStore::new(conn.clone(), embedding_model.model()).await?;
 
         conn.call(|conn| {
+            // Lets maintenance and admin commands use the database while the
+            // bots are running. In-memory databases keep their journal mode.
+            conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+            conn.busy_timeout(BUSY_TIMEOUT)?;
             conn.execute_batch(
                 "BEGIN;
 
@@ -50,8 +130,44 @@ impl<E: EmbeddingModel> KnowledgeBase<E> {
                 CREATE INDEX IF NOT EXISTS idx_channel_id_type ON channels(channel_id, channel_type);
 
                 COMMIT;"
-            )
-            .map_err(tokio_rusqlite::Error::from)
+            )?;
+            conn.execute_batch(interactions::SCHEMA)?;
+            conn.execute_batch(pins::SCHEMA)?;
+            conn.execute_batch(topics::SCHEMA)?;
+            conn.execute_batch(chunks::SCHEMA)?;
+            conn.execute_batch(versions::SCHEMA)?;
+            conn.execute_batch(gaps::SCHEMA)?;
+            conn.execute_batch(conversation_state::SCHEMA)?;
+            conn.execute_batch(channel_settings::SCHEMA)?;
+            conn.execute_batch(cursors::SCHEMA)?;
+            conn.execute_batch(refresh::SCHEMA)?;
+            conn.execute_batch(tool_calls::SCHEMA)?;
+            conn.execute_batch(snapshot::SCHEMA)?;
+            conn.execute_batch(pending::SCHEMA)?;
+            conn.execute_batch(user_facts::SCHEMA)?;
+            conn.execute_batch(onboarding::SCHEMA)?;
+            conn.execute_batch(escalations::SCHEMA)?;
+            conn.execute_batch(dead_letters::SCHEMA)?;
+            conn.execute_batch(memory::SCHEMA)?;
+            conn.execute_batch(retention::SCHEMA)?;
+            conn.execute_batch(rate_limits::SCHEMA)?;
+            conn.execute_batch(source_state::SCHEMA)?;
+            conn.execute_batch(sent_messages::SCHEMA)?;
+            conn.execute_batch(announcements::SCHEMA)?;
+            conn.execute_batch(languages::SCHEMA)?;
+            conn.execute_batch(exchanges::SCHEMA)?;
+            conn.execute_batch(familiarity::SCHEMA)?;
+            conn.execute_batch(linked_identities::SCHEMA)?;
+            conn.execute_batch(service_lock::SCHEMA)?;
+            conn.execute_batch(deferred::SCHEMA)?;
+            guilds::migrate(conn)?;
+            namespaces::migrate(conn)?;
+            identity::migrate(conn)?;
+            outline::migrate(conn)?;
+            retention::migrate(conn)?;
+            experiments::migrate(conn)?;
+            embedding_policy::migrate(conn)?;
+            activity::migrate(conn).map_err(tokio_rusqlite::Error::from)
         })
         .await
         .map_err(|e| VectorStoreError::DatastoreError(Box::new(e)))?;
@@ -61,6 +177,14 @@ impl<E: EmbeddingModel> KnowledgeBase<E> {
             document_store,
             message_store,
             embedding_model,
+            namespace: DEFAULT_NAMESPACE.to_string(),
+            shared_namespaces: Vec::new(),
+            clock: Arc::new(SystemClock),
+            events: EventBus::default(),
+            identities: IdentityCache::default(),
+            replica: None,
+            embedding_policy: None,
+            chunking: None,
         })
     }
 
@@ -82,12 +206,40 @@ impl<E: EmbeddingModel> KnowledgeBase<E> {
             .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
     }
 
-    pub fn document_index(self) -> SqliteVectorIndex<E, Document> {
-        SqliteVectorIndex::new(self.embedding_model, self.document_store)
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = events;
+        self
+    }
+
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    /// The embedding service, e.g. for its metrics.
+    pub fn embedding_service(&self) -> &EmbeddingService<E> {
+        &self.embedding_model
     }
 
-    pub fn message_index(self) -> SqliteVectorIndex<E, Message> {
-        SqliteVectorIndex::new(self.embedding_model, self.message_store)
+    pub fn document_index(self) -> NamespaceIndex<E, Document> {
+        let index = NamespaceIndex::new(self.conn, self.embedding_model, self.namespace);
+        self.shared_namespaces
+            .into_iter()
+            .fold(index.with_replica(self.replica), |index, namespace| {
+                index.with_shared_namespace(namespace)
+            })
+    }
+
+    pub fn message_index(self) -> NamespaceIndex<E, Message> {
+        NamespaceIndex::new(self.conn, self.embedding_model, self.namespace)
     }
 
     pub async fn get_user_by_source(&self, source: String) -> Result<Option<Account>, SqliteError> {
@@ -135,7 +287,7 @@ impl<E: EmbeddingModel> KnowledgeBase<E> {
         self.conn
             .call(move |conn| {
                 let mut stmt = conn.prepare(
-                    "SELECT id, name, source, created_at, updated_at FROM channels WHERE id = ?1",
+                    "SELECT id, channel_id, channel_type, source, name, guild_id, created_at, updated_at FROM channels WHERE id = ?1",
                 )?;
 
                 let channel = stmt
@@ -155,7 +307,7 @@ impl<E: EmbeddingModel> KnowledgeBase<E> {
         self.conn
             .call(move |conn| {
                 let mut stmt = conn.prepare(
-                    "SELECT id, name, source, created_at, updated_at FROM channels WHERE source = ?1"
+                    "SELECT id, channel_id, channel_type, source, name, guild_id, created_at, updated_at FROM channels WHERE source = ?1"
                 )?;
 
                 let channels = stmt.query_map(rusqlite::params![source], |row| {
@@ -170,13 +322,37 @@ impl<E: EmbeddingModel> KnowledgeBase<E> {
             .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
     }
 
+    /// Embeds and stores a message. When embedding or writing it fails, the
+    /// message is queued in `pending_messages` instead, where history queries
+    /// still see it, until [KnowledgeBase::drain_pending] stores it. Returns
+    /// the rowid of the stored or queued row.
     pub async fn create_message(&self, msg: Message) -> anyhow::Result<i64> {
-        let embeddings = EmbeddingsBuilder::new(self.embedding_model.clone())
-            .documents(vec![msg.clone()])?
-            .build()
-            .await?;
+        match self.store_message(msg.clone(), false).await {
+            Ok(id) => Ok(id),
+            Err(err) if pending::is_duplicate(&err) => Err(err),
+            Err(err) if is_circuit_open(&err) => {
+                debug!(id = msg.id, "Embedding circuit open, queueing message");
+                Ok(self.queue_message(msg, &err.to_string()).await?)
+            }
+            Err(err) => {
+                warn!(
+                    ?err,
+                    id = msg.id,
+                    "Failed to store message, queueing it for retry"
+                );
+                Ok(self.queue_message(msg, &err.to_string()).await?)
+            }
+        }
+    }
+
+    /// Embeds and stores a message, removing it from `pending_messages` in
+    /// the same transaction when `promote` is set. Messages the embedding
+    /// policy skips are stored without an embedding.
+    pub(super) async fn store_message(&self, msg: Message, promote: bool) -> anyhow::Result<i64> {
+        let prepared = self.prepare_message(msg.clone()).await?;
 
         let store = self.message_store.clone();
+        let namespace = self.namespace.clone();
 
         self.conn
             .call(move |conn| {
@@ -195,7 +371,17 @@ impl<E: EmbeddingModel> KnowledgeBase<E> {
                     ],
                 )?;
 
-                let id = store.add_rows_with_txn(&tx, embeddings)?;
+                let id = prepared.insert(&tx, &store)?;
+                tx.execute(
+                    "UPDATE messages SET agent_id = ?1 WHERE rowid = ?2",
+                    rusqlite::params![namespace, id],
+                )?;
+                if promote {
+                    tx.execute(
+                        "DELETE FROM pending_messages WHERE agent_id = ?1 AND id = ?2",
+                        [&namespace, &msg.id],
+                    )?;
+                }
 
                 tx.commit()?;
 
@@ -205,11 +391,67 @@ impl<E: EmbeddingModel> KnowledgeBase<E> {
             .map_err(|e| anyhow::anyhow!(e))
     }
 
+    /// Replaces the content of the stored message `id` and embeds it again,
+    /// so retrieval finds what it says now. Returns whether it was stored.
+    pub async fn update_message_content(&self, id: &str, content: String) -> anyhow::Result<bool> {
+        let key = id.to_string();
+        let namespace = self.namespace.clone();
+        let stored = self
+            .conn
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        &format!(
+                            "SELECT {} FROM messages WHERE id = ?1 AND agent_id = ?2",
+                            Message::COLUMNS
+                        ),
+                        [&key, &namespace],
+                        |row| Message::try_from(row),
+                    )
+                    .optional()?)
+            })
+            .await?;
+        let Some(msg) = stored else {
+            return Ok(false);
+        };
+
+        let msg = Message { content, ..msg };
+        let prepared = self.prepare_message(msg.clone()).await?;
+
+        let store = self.message_store.clone();
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let rowid: i64 = tx.query_row(
+                    "SELECT rowid FROM messages WHERE id = ?1 AND agent_id = ?2",
+                    [&msg.id, &namespace],
+                    |row| row.get(0),
+                )?;
+                tx.execute("DELETE FROM messages_embeddings WHERE rowid = ?1", [rowid])?;
+                tx.execute("DELETE FROM messages WHERE rowid = ?1", [rowid])?;
+
+                let id = prepared.insert(&tx, &store)?;
+                tx.execute(
+                    "UPDATE messages SET agent_id = ?1 WHERE rowid = ?2",
+                    rusqlite::params![namespace, id],
+                )?;
+                tx.commit()?;
+                Ok(())
+            })
+            .await
+            .map_err(|e| anyhow::anyhow!(e))?;
+        Ok(true)
+    }
+
     pub async fn get_message(&self, id: i64) -> Result<Option<Message>, SqliteError> {
+        let namespace = self.namespace.clone();
+
         self.conn
             .call(move |conn| {
-                Ok(conn.prepare("SELECT id, source, source_id, channel_type, channel_id, account_id, role, content, created_at FROM messages WHERE id = ?1")?
-                    .query_row(rusqlite::params![id], |row| {
+                Ok(conn.prepare("SELECT id, source, source_id, channel_type, channel_id, account_id, role, content, created_at FROM messages WHERE id = ?1 AND agent_id = ?2")?
+                    .query_row(rusqlite::params![id, namespace], |row| {
                         Message::try_from(row)
                     }).optional()?)
             })
@@ -217,23 +459,81 @@ impl<E: EmbeddingModel> KnowledgeBase<E> {
             .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
     }
 
+    /// The document with `id`, if it is one retrieval through this knowledge
+    /// base can return, i.e. it belongs to its namespace or a shared one.
+    pub async fn get_document(&self, id: &str) -> Result<Option<Document>, SqliteError> {
+        let id = id.to_string();
+        let namespaces = serde_json::to_string(
+            &std::iter::once(&self.namespace)
+                .chain(&self.shared_namespaces)
+                .collect::<Vec<_>>(),
+        )
+        .map_err(|e| SqliteError::SerializationError(Box::new(e)))?;
+
+        self.conn
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        &format!(
+                            "SELECT {} FROM documents
+                             WHERE id = ?1 AND agent_id IN (SELECT value FROM json_each(?2))",
+                            Document::COLUMNS
+                        ),
+                        [&id, &namespaces],
+                        |row| Document::try_from(row),
+                    )
+                    .optional()?)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Whether a message with `id` is stored, e.g. to skip items a polled
+    /// feed returns twice.
+    pub async fn message_exists(&self, id: &str) -> Result<bool, SqliteError> {
+        let id = id.to_string();
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        &format!(
+                            "SELECT 1 FROM {} WHERE id = ?1 AND agent_id = ?2",
+                            pending::MESSAGES_WITH_PENDING
+                        ),
+                        [&id, &namespace],
+                        |_| Ok(()),
+                    )
+                    .optional()?
+                    .is_some())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// The last `limit` messages of a channel, newest first.
     pub async fn get_recent_messages(
         &self,
-        channel_id: i64,
+        channel_id: &str,
         limit: usize,
     ) -> Result<Vec<Message>, SqliteError> {
+        let channel_id = channel_id.to_string();
+        let namespace = self.namespace.clone();
+
         self.conn
             .call(move |conn| {
-                let mut stmt = conn.prepare(
+                let mut stmt = conn.prepare(&format!(
                     "SELECT id, source, source_id, channel_type, channel_id, account_id, role, content, created_at 
-                     FROM messages 
-                     WHERE channel_id = ?1 
+                     FROM {} 
+                     WHERE agent_id = ?3 AND channel_id = ?1 
                      ORDER BY created_at DESC 
                      LIMIT ?2",
-                )?;
+                    pending::MESSAGES_WITH_PENDING
+                ))?;
 
                 let messages = stmt
-                    .query_map(rusqlite::params![channel_id, limit], |row| {
+                    .query_map(rusqlite::params![channel_id, limit, namespace], |row| {
                         Message::try_from(row)
                     })?
                     .collect::<Result<Vec<_>, _>>()?;
@@ -250,18 +550,20 @@ impl<E: EmbeddingModel> KnowledgeBase<E> {
         limit: i64,
     ) -> anyhow::Result<Vec<(String, String)>> {
         let channel_id = channel_id.to_string();
+        let namespace = self.namespace.clone();
 
         self.conn
             .call(move |conn| {
-                let mut stmt = conn.prepare(
+                let mut stmt = conn.prepare(&format!(
                     "SELECT source_id, content 
-                     FROM messages 
-                     WHERE channel_id = ?1
+                     FROM {} 
+                     WHERE agent_id = ?3 AND channel_id = ?1
                      ORDER BY created_at DESC 
                      LIMIT ?2",
-                )?;
+                    pending::MESSAGES_WITH_PENDING
+                ))?;
                 let messages = stmt
-                    .query_map([&channel_id, &limit.to_string()], |row| {
+                    .query_map([&channel_id, &limit.to_string(), &namespace], |row| {
                         Ok((row.get(0)?, row.get(1)?))
                     })?
                     .collect::<Result<Vec<_>, _>>()?;
@@ -271,20 +573,261 @@ impl<E: EmbeddingModel> KnowledgeBase<E> {
             .map_err(|e| anyhow::anyhow!(e))
     }
 
-    pub async fn add_documents<'a, I>(&mut self, documents: I) -> anyhow::Result<()>
+    /// Content of the bot's last `limit` replies in a channel, oldest first.
+    pub async fn recent_replies(
+        &self,
+        channel_id: &str,
+        limit: usize,
+    ) -> Result<Vec<String>, SqliteError> {
+        let channel_id = channel_id.to_string();
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT content
+                     FROM {}
+                     WHERE agent_id = ?3 AND channel_id = ?1 AND role = 'assistant'
+                     ORDER BY created_at DESC
+                     LIMIT ?2",
+                    pending::MESSAGES_WITH_PENDING
+                ))?;
+                let mut replies = stmt
+                    .query_map(rusqlite::params![channel_id, limit, namespace], |row| {
+                        row.get(0)
+                    })?
+                    .collect::<Result<Vec<String>, _>>()?;
+                replies.reverse();
+                Ok(replies)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Messages of a channel created in `[from, to)`, oldest first. At most
+    /// `limit` of the newest are returned; the window notes how many older
+    /// ones were left out.
+    pub async fn messages_between(
+        &self,
+        channel_id: &str,
+        from: chrono::DateTime<chrono::Utc>,
+        to: chrono::DateTime<chrono::Utc>,
+        limit: usize,
+    ) -> Result<MessageWindow, SqliteError> {
+        let channel_id = channel_id.to_string();
+        let namespace = self.namespace.clone();
+        let (from, to) = (from.to_rfc3339(), to.to_rfc3339());
+
+        self.conn
+            .call(move |conn| {
+                let total: usize = conn.query_row(
+                    &format!(
+                        "SELECT COUNT(*) FROM {}
+                         WHERE agent_id = ?1 AND channel_id = ?2 AND created_at >= ?3 AND created_at < ?4",
+                        pending::MESSAGES_WITH_PENDING
+                    ),
+                    rusqlite::params![namespace, channel_id, from, to],
+                    |row| row.get(0),
+                )?;
+
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT {} FROM {}
+                     WHERE agent_id = ?1 AND channel_id = ?2 AND created_at >= ?3 AND created_at < ?4
+                     ORDER BY created_at DESC, id DESC
+                     LIMIT ?5",
+                    Message::COLUMNS,
+                    pending::MESSAGES_WITH_PENDING
+                ))?;
+                let mut messages = stmt
+                    .query_map(
+                        rusqlite::params![namespace, channel_id, from, to, limit],
+                        |row| Message::try_from(row),
+                    )?
+                    .collect::<Result<Vec<_>, _>>()?;
+                messages.reverse();
+
+                Ok(MessageWindow {
+                    truncated: total.saturating_sub(messages.len()),
+                    messages,
+                })
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Embeds and stores `documents`, split into parts with a
+    /// [ChunkingConfig], see [super::chunking]. Documents already stored
+    /// under the same id with the same content are not embedded again, and
+    /// the summary counts them skipped. Nothing is stored when embedding
+    /// any of the others fails, including while the embedding circuit is
+    /// open. Many documents are better added with
+    /// [KnowledgeBase::add_documents_in_batches].
+    pub async fn add_documents<'a, I>(&mut self, documents: I) -> anyhow::Result<StoreSummary>
     where
         I: IntoIterator<Item = Document>,
     {
         info!("Adding documents to KnowledgeBase");
-        let embeddings = EmbeddingsBuilder::new(self.embedding_model.clone())
-            .documents(documents)?
-            .build()
+        let summary = self
+            .embed_and_store(documents.into_iter().collect())
             .await?;
+        self.ingest_complete();
+
+        info!(%summary, "Successfully added documents to KnowledgeBase");
+        Ok(summary)
+    }
 
-        debug!("Adding embeddings to document store");
-        self.document_store.add_rows(embeddings).await?;
+    /// Splits, cleans, embeds and stores the new and changed `documents`
+    /// together, then removes the parts they supersede. A document counts as
+    /// updated when any of its parts changed or was removed.
+    pub(super) async fn embed_and_store(
+        &self,
+        mut documents: Vec<Document>,
+    ) -> anyhow::Result<StoreSummary> {
+        let mut superseded = Vec::new();
+        if let Some(config) = &self.chunking {
+            documents = documents
+                .iter()
+                .flat_map(|document| config.split(document))
+                .collect();
+            superseded = self.superseded_parts(&documents).await?;
+        }
+        let stored = self.stored_documents(&documents).await?;
+        let is_unchanged = |document: &Document| stored.get(&document.id) == Some(&true);
+
+        // Per document, whether it was stored before and whether it changed
+        let mut changes: HashMap<&str, (bool, bool)> = HashMap::new();
+        for document in &documents {
+            let change = changes.entry(parent_id(&document.id)).or_default();
+            change.0 |= stored.contains_key(&document.id);
+            change.1 |= !is_unchanged(document);
+        }
+        for id in &superseded {
+            *changes.entry(parent_id(id)).or_default() = (true, true);
+        }
+        let mut summary = StoreSummary::default();
+        for (was_stored, changed) in changes.into_values() {
+            match (was_stored, changed) {
+                (false, _) => summary.inserted += 1,
+                (true, true) => summary.updated += 1,
+                (true, false) => summary.skipped += 1,
+            }
+        }
+
+        let (unchanged, documents): (Vec<_>, Vec<_>) =
+            documents.into_iter().partition(is_unchanged);
+        let embeddings = match documents.is_empty() {
+            true => Vec::new(),
+            false => {
+                EmbeddingsBuilder::new(self.embedding_model.clone())
+                    .documents(cleaning::prepare(documents))?
+                    .build()
+                    .await?
+            }
+        };
+        if !superseded.is_empty() {
+            debug!(count = superseded.len(), "Removing stale document parts");
+        }
+        // Topics of unchanged documents come from the loader's config rather
+        // than the content, so they are stored again
+        self.write_documents(embeddings, unchanged, superseded)
+            .await?;
+        Ok(summary)
+    }
 
-        info!("Successfully added documents to KnowledgeBase");
+    /// Stores documents embedded by an [EmbeddingsBuilder], with their topics,
+    /// positions and versions.
+    pub(super) async fn store_embedded(
+        &self,
+        embeddings: Vec<(Document, OneOrMany<Embedding>)>,
+    ) -> anyhow::Result<()> {
+        self.write_documents(embeddings, Vec::new(), Vec::new())
+            .await?;
         Ok(())
     }
+
+    /// Stores `embeddings` like [KnowledgeBase::store_embedded], tags
+    /// `retagged` with their topics and deletes the `removed` documents, all
+    /// in one transaction.
+    async fn write_documents(
+        &self,
+        embeddings: Vec<(Document, OneOrMany<Embedding>)>,
+        retagged: Vec<Document>,
+        removed: Vec<String>,
+    ) -> Result<(), tokio_rusqlite::Error> {
+        let store = self.document_store.clone();
+        let namespace = self.namespace.clone();
+        let documents = embeddings
+            .iter()
+            .map(|(document, _)| document.clone())
+            .collect::<Vec<_>>();
+
+        debug!(
+            count = documents.len(),
+            "Adding embeddings to document store"
+        );
+        self.conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                topics::store_topics(&tx, &retagged)?;
+                namespaces::store_documents(&tx, &store, &namespace, embeddings)?;
+                topics::store_topics(&tx, &documents)?;
+                chunks::store_chunks(&tx, &documents)?;
+                versions::store_versions(&tx, &namespace, &documents)?;
+                for id in &removed {
+                    admin::delete_document(&tx, id, &namespace)?;
+                }
+                tx.commit()?;
+                Ok(())
+            })
+            .await
+    }
+}
+
+/// Whether embedding failed because the circuit is open, see
+/// [embeddings::is_circuit_open].
+pub(super) fn is_circuit_open(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<rig::embeddings::EmbeddingError>()
+        .is_some_and(embeddings::is_circuit_open)
+}
+
+/// Fails when the existing vector tables were sized for another model, which
+/// would otherwise only surface when the first embedding is inserted.
+async fn check_dimensions(conn: &Connection, ndims: usize) -> Result<(), VectorStoreError> {
+    if ndims == 0 {
+        return Err(VectorStoreError::DatastoreError(Box::new(
+            EmbeddingDimensionError::Unknown,
+        )));
+    }
+
+    for table in [Document::name(), Message::name()] {
+        let sql = conn
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        "SELECT sql FROM sqlite_master WHERE name = ?1",
+                        [format!("{table}_embeddings")],
+                        |row| row.get::<_, String>(0),
+                    )
+                    .optional()?)
+            })
+            .await
+            .map_err(|e| VectorStoreError::DatastoreError(Box::new(e)))?;
+
+        // e.g. `CREATE VIRTUAL TABLE documents_embeddings USING vec0(embedding float[1536])`
+        let stored = sql.as_deref().and_then(|sql| {
+            let start = sql.find("float[")? + "float[".len();
+            let end = start + sql[start..].find(']')?;
+            sql[start..end].parse::<usize>().ok()
+        });
+        if let Some(stored) = stored.filter(|stored| *stored != ndims) {
+            return Err(VectorStoreError::DatastoreError(Box::new(
+                EmbeddingDimensionError::Mismatch {
+                    table,
+                    stored,
+                    model: ndims,
+                },
+            )));
+        }
+    }
+    Ok(())
 }
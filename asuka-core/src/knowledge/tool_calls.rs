@@ -0,0 +1,308 @@
+//! Tool invocations made while answering an interaction, kept so admins can
+//! see what a tool actually returned behind a reply.
+
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+use rusqlite::{OptionalExtension, Row};
+
+use super::store::KnowledgeBase;
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS tool_calls (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        interaction_id INTEGER NOT NULL REFERENCES interactions(id) ON DELETE CASCADE,
+        tool_name TEXT NOT NULL,
+        args TEXT NOT NULL,
+        output TEXT,
+        error TEXT,
+        duration_ms INTEGER NOT NULL,
+        truncated INTEGER NOT NULL DEFAULT 0,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+    );
+    CREATE INDEX IF NOT EXISTS idx_tool_calls_interaction ON tool_calls(interaction_id);
+
+    CREATE TABLE IF NOT EXISTS interaction_replies (
+        interaction_id INTEGER NOT NULL REFERENCES interactions(id) ON DELETE CASCADE,
+        message_id TEXT NOT NULL,
+        PRIMARY KEY (interaction_id, message_id)
+    );
+    CREATE INDEX IF NOT EXISTS idx_interaction_replies_message ON interaction_replies(message_id);
+";
+
+/// One tool invocation. Arguments and output are stored as JSON text, with
+/// sensitive arguments already redacted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolCall {
+    pub interaction_id: i64,
+    pub tool_name: String,
+    pub args: String,
+    /// The tool's output, or its error message.
+    pub output: Result<String, String>,
+    pub duration_ms: i64,
+    /// Whether the arguments or output were cut short to fit.
+    pub truncated: bool,
+}
+
+impl TryFrom<&Row<'_>> for ToolCall {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &Row) -> Result<Self, Self::Error> {
+        let output: Option<String> = row.get(3)?;
+        let error: Option<String> = row.get(4)?;
+        Ok(ToolCall {
+            interaction_id: row.get(0)?,
+            tool_name: row.get(1)?,
+            args: row.get(2)?,
+            output: match error {
+                Some(error) => Err(error),
+                None => Ok(output.unwrap_or_default()),
+            },
+            duration_ms: row.get(5)?,
+            truncated: row.get(6)?,
+        })
+    }
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    pub async fn record_tool_call(&self, call: ToolCall) -> Result<i64, SqliteError> {
+        self.conn
+            .call(move |conn| {
+                let (output, error) = match call.output {
+                    Ok(output) => (Some(output), None),
+                    Err(error) => (None, Some(error)),
+                };
+                Ok(conn.query_row(
+                    "INSERT INTO tool_calls
+                         (interaction_id, tool_name, args, output, error, duration_ms, truncated)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                     RETURNING id",
+                    rusqlite::params![
+                        call.interaction_id,
+                        call.tool_name,
+                        call.args,
+                        output,
+                        error,
+                        call.duration_ms,
+                        call.truncated
+                    ],
+                    |row| row.get(0),
+                )?)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Tool calls of an interaction, in the order they were made.
+    pub async fn tool_calls(&self, interaction_id: i64) -> Result<Vec<ToolCall>, SqliteError> {
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT t.interaction_id, t.tool_name, t.args, t.output, t.error,
+                            t.duration_ms, t.truncated
+                     FROM tool_calls t
+                     JOIN interactions i ON i.id = t.interaction_id
+                     WHERE t.interaction_id = ?1 AND i.agent_id = ?2
+                     ORDER BY t.id",
+                )?;
+                let calls = stmt
+                    .query_map(rusqlite::params![interaction_id, namespace], |row| {
+                        ToolCall::try_from(row)
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(calls)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Records that the bot message `message_id` was sent in answer to an
+    /// interaction.
+    pub async fn link_reply(
+        &self,
+        interaction_id: i64,
+        message_id: &str,
+    ) -> Result<(), SqliteError> {
+        let message_id = message_id.to_string();
+
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT OR IGNORE INTO interaction_replies (interaction_id, message_id)
+                     VALUES (?1, ?2)",
+                    rusqlite::params![interaction_id, message_id],
+                )?;
+                Ok(())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// The interaction a message belongs to, either as a reply the bot sent
+    /// or as one of the messages it answered. See
+    /// [KnowledgeBase::interaction_for_platform_message] when the platform
+    /// is known.
+    pub async fn interaction_for_message(
+        &self,
+        message_id: &str,
+    ) -> Result<Option<i64>, SqliteError> {
+        let message_id = message_id.to_string();
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        "SELECT i.id FROM interactions i
+                         WHERE i.agent_id = ?2 AND i.id IN (
+                             SELECT interaction_id FROM interaction_replies WHERE message_id = ?1
+                             UNION
+                             SELECT interaction_id FROM sent_messages WHERE platform_message_id = ?1
+                             UNION
+                             SELECT interaction_id FROM interaction_messages WHERE message_id = ?1
+                         )
+                         ORDER BY i.id DESC
+                         LIMIT 1",
+                        rusqlite::params![message_id, namespace],
+                        |row| row.get(0),
+                    )
+                    .optional()?)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// The latest interaction in a channel that used a tool.
+    pub async fn last_tool_interaction(
+        &self,
+        channel_id: &str,
+    ) -> Result<Option<i64>, SqliteError> {
+        let channel_id = channel_id.to_string();
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                Ok(conn
+                    .query_row(
+                        "SELECT t.interaction_id FROM tool_calls t
+                         JOIN interactions i ON i.id = t.interaction_id
+                         WHERE i.channel_id = ?1 AND i.agent_id = ?2
+                         ORDER BY t.id DESC
+                         LIMIT 1",
+                        rusqlite::params![channel_id, namespace],
+                        |row| row.get(0),
+                    )
+                    .optional()?)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+}
+
+/// Renders tool calls as a chat message.
+pub fn format_tool_calls(calls: &[ToolCall]) -> String {
+    if calls.is_empty() {
+        return "No tool calls recorded for that reply.".to_string();
+    }
+
+    calls
+        .iter()
+        .enumerate()
+        .map(|(i, call)| {
+            let result = match &call.output {
+                Ok(output) => format!("output: {output}"),
+                Err(error) => format!("error: {error}"),
+            };
+            format!(
+                "{}. {} ({} ms)\nargs: {}\n{}",
+                i + 1,
+                call.tool_name,
+                call.duration_ms,
+                call.args,
+                result
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn test_tool_calls_by_message() {
+        let knowledge = test_utils::knowledge_base().await;
+        let first = knowledge
+            .create_interaction(
+                "c1".to_string(),
+                "alice".to_string(),
+                vec!["m1".to_string()],
+            )
+            .await
+            .unwrap();
+        let second = knowledge
+            .create_interaction(
+                "c1".to_string(),
+                "alice".to_string(),
+                vec!["m2".to_string()],
+            )
+            .await
+            .unwrap();
+        knowledge.link_reply(first, "r1").await.unwrap();
+
+        let call = ToolCall {
+            interaction_id: first,
+            tool_name: "search_docs".to_string(),
+            args: r#"{"query":"vrf"}"#.to_string(),
+            output: Ok("\"VRF costs nothing\"".to_string()),
+            duration_ms: 12,
+            truncated: false,
+        };
+        knowledge.record_tool_call(call.clone()).await.unwrap();
+        let failed = ToolCall {
+            output: Err("timeout".to_string()),
+            ..call.clone()
+        };
+        knowledge.record_tool_call(failed.clone()).await.unwrap();
+
+        assert_eq!(
+            knowledge.interaction_for_message("r1").await.unwrap(),
+            Some(first)
+        );
+        assert_eq!(
+            knowledge.interaction_for_message("m2").await.unwrap(),
+            Some(second)
+        );
+        assert_eq!(knowledge.interaction_for_message("r9").await.unwrap(), None);
+        assert_eq!(
+            knowledge.last_tool_interaction("c1").await.unwrap(),
+            Some(first)
+        );
+        assert_eq!(knowledge.last_tool_interaction("c2").await.unwrap(), None);
+        assert_eq!(knowledge.tool_calls(first).await.unwrap(), [call, failed]);
+        assert!(knowledge.tool_calls(second).await.unwrap().is_empty());
+
+        let other = knowledge.clone().with_namespace("companion");
+        assert_eq!(other.interaction_for_message("r1").await.unwrap(), None);
+        assert!(other.tool_calls(first).await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_format_tool_calls() {
+        let calls = [ToolCall {
+            interaction_id: 1,
+            tool_name: "search_docs".to_string(),
+            args: r#"{"query":"vrf"}"#.to_string(),
+            output: Err("timeout".to_string()),
+            duration_ms: 30,
+            truncated: false,
+        }];
+        assert_eq!(
+            format_tool_calls(&calls),
+            "1. search_docs (30 ms)\nargs: {\"query\":\"vrf\"}\nerror: timeout"
+        );
+    }
+}
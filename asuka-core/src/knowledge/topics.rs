@@ -0,0 +1,361 @@
+use std::collections::{HashMap, HashSet};
+
+use rig::{
+    embeddings::EmbeddingModel,
+    vector_store::{VectorStoreError, VectorStoreIndex},
+};
+use rig_sqlite::SqliteError;
+use serde::Deserialize;
+use tracing::debug;
+
+use super::{models::Document, namespaces::NamespaceIndex, store::KnowledgeBase};
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS document_topics (
+        document_id TEXT NOT NULL,
+        topic TEXT NOT NULL,
+        PRIMARY KEY (document_id, topic)
+    );
+    CREATE INDEX IF NOT EXISTS idx_document_topics_topic ON document_topics(topic);
+";
+
+/// How strongly retrieval favours documents tagged with the message's topics.
+#[derive(Clone, Debug)]
+pub struct TopicBoost {
+    /// Fraction taken off the distance of a matching document, in `0.0..1.0`.
+    pub weight: f64,
+    /// Candidates fetched per requested result before re-scoring.
+    pub overfetch: usize,
+}
+
+impl Default for TopicBoost {
+    fn default() -> Self {
+        Self {
+            weight: 0.3,
+            overfetch: 4,
+        }
+    }
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Replaces the topic tags of the given documents.
+    pub async fn tag_documents(&self, tags: Vec<(String, Vec<String>)>) -> Result<(), SqliteError> {
+        self.replace_topics(tags)
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    pub async fn document_topics(
+        &self,
+        document_ids: Vec<String>,
+    ) -> Result<HashMap<String, HashSet<String>>, SqliteError> {
+        self.load_topics(document_ids)
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    pub(super) async fn replace_topics(
+        &self,
+        tags: Vec<(String, Vec<String>)>,
+    ) -> Result<(), tokio_rusqlite::Error> {
+        self.conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                write_topics(&tx, &tags)?;
+                tx.commit()?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn load_topics(
+        &self,
+        document_ids: Vec<String>,
+    ) -> Result<HashMap<String, HashSet<String>>, tokio_rusqlite::Error> {
+        self.conn
+            .call(move |conn| {
+                let mut stmt =
+                    conn.prepare("SELECT topic FROM document_topics WHERE document_id = ?1")?;
+
+                let mut topics = HashMap::new();
+                for id in document_ids {
+                    let tags = stmt
+                        .query_map([&id], |row| row.get(0))?
+                        .collect::<Result<HashSet<String>, _>>()?;
+                    if !tags.is_empty() {
+                        topics.insert(id, tags);
+                    }
+                }
+                Ok(topics)
+            })
+            .await
+    }
+
+    /// Document index that boosts documents tagged with the query's topics.
+    pub fn topic_index(self, topics: Vec<String>, boost: TopicBoost) -> TopicIndex<E> {
+        TopicIndex {
+            index: self.clone().document_index(),
+            knowledge: self,
+            topics,
+            boost,
+        }
+    }
+}
+
+/// Tags stored documents with the topics they carry, if any.
+pub(super) fn store_topics(
+    tx: &rusqlite::Transaction,
+    documents: &[Document],
+) -> rusqlite::Result<()> {
+    let tags = documents
+        .iter()
+        .filter(|document| !document.topics.is_empty())
+        .map(|document| (document.id.clone(), document.topics.clone()))
+        .collect::<Vec<_>>();
+    write_topics(tx, &tags)
+}
+
+/// Replaces the topics of each document with the given ones.
+fn write_topics(
+    tx: &rusqlite::Transaction,
+    tags: &[(String, Vec<String>)],
+) -> rusqlite::Result<()> {
+    for (document_id, topics) in tags {
+        tx.execute(
+            "DELETE FROM document_topics WHERE document_id = ?1",
+            [document_id],
+        )?;
+        for topic in topics {
+            tx.execute(
+                "INSERT OR IGNORE INTO document_topics (document_id, topic) VALUES (?1, ?2)",
+                [document_id, &topic.to_lowercase()],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+}
+
+/// `word` without a trailing plural `s`. Words of 3 characters or less and
+/// words ending in `ss`, such as "gas" or "access", are kept whole.
+fn singular(word: &str) -> &str {
+    match word.strip_suffix('s') {
+        Some(stem) if word.chars().count() > 3 && !stem.ends_with('s') => stem,
+        _ => word,
+    }
+}
+
+/// Cheap keyword classifier: a topic matches when every word of it appears in
+/// the message, ignoring case and a trailing plural `s`.
+pub fn match_topics(message: &str, topics: &[String]) -> Vec<String> {
+    let message: HashSet<String> = words(message)
+        .map(|word| singular(&word).to_string())
+        .collect();
+
+    topics
+        .iter()
+        .filter(|topic| {
+            let mut topic_words = words(topic).peekable();
+            topic_words.peek().is_some()
+                && topic_words.all(|word| message.contains(singular(&word)))
+        })
+        .map(|topic| topic.to_lowercase())
+        .collect()
+}
+
+/// Re-scores candidates so those tagged with a matched topic move up. Scores are
+/// distances, so a boost shrinks them; ties keep their original order.
+pub fn boost_candidates<T>(
+    mut candidates: Vec<(f64, String, T)>,
+    tags: &HashMap<String, HashSet<String>>,
+    matched: &[String],
+    weight: f64,
+) -> Vec<(f64, String, T)> {
+    for (distance, id, _) in candidates.iter_mut() {
+        let tagged = tags
+            .get(id.as_str())
+            .is_some_and(|topics| matched.iter().any(|topic| topics.contains(topic)));
+        if tagged {
+            *distance *= 1.0 - weight;
+        }
+    }
+
+    candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+    candidates
+}
+
+pub struct TopicIndex<E: EmbeddingModel + 'static> {
+    knowledge: KnowledgeBase<E>,
+    index: NamespaceIndex<E, Document>,
+    topics: Vec<String>,
+    boost: TopicBoost,
+}
+
+impl<E: EmbeddingModel + Sync> TopicIndex<E> {
+    async fn boosted<T: Send>(
+        &self,
+        query: &str,
+        n: usize,
+        candidates: Vec<(f64, String, T)>,
+    ) -> Result<Vec<(f64, String, T)>, VectorStoreError> {
+        let matched = match_topics(query, &self.topics);
+        let ids = candidates.iter().map(|(_, id, _)| id.clone()).collect();
+        let tags = self
+            .knowledge
+            .load_topics(ids)
+            .await
+            .map_err(|e| VectorStoreError::DatastoreError(Box::new(e)))?;
+
+        debug!(?matched, candidates = candidates.len(), "Boosting by topic");
+        let mut ranked = boost_candidates(candidates, &tags, &matched, self.boost.weight);
+        ranked.truncate(n);
+        Ok(ranked)
+    }
+
+    fn fetch_size(&self, query: &str, n: usize) -> usize {
+        if match_topics(query, &self.topics).is_empty() {
+            n
+        } else {
+            n * self.boost.overfetch.max(1)
+        }
+    }
+}
+
+impl<E: EmbeddingModel + Sync> VectorStoreIndex for TopicIndex<E> {
+    async fn top_n<T: for<'a> Deserialize<'a> + Send>(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<(f64, String, T)>, VectorStoreError> {
+        let candidates = self
+            .index
+            .top_n::<T>(query, self.fetch_size(query, n))
+            .await?;
+        self.boosted(query, n, candidates).await
+    }
+
+    async fn top_n_ids(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<(f64, String)>, VectorStoreError> {
+        let candidates = self
+            .index
+            .top_n_ids(query, self.fetch_size(query, n))
+            .await?
+            .into_iter()
+            .map(|(distance, id)| (distance, id, ()))
+            .collect();
+
+        Ok(self
+            .boosted(query, n, candidates)
+            .await?
+            .into_iter()
+            .map(|(distance, id, _)| (distance, id))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    fn document(id: &str, content: &str, topics: &[&str]) -> Document {
+        Document {
+            id: id.to_string(),
+            source_id: "test".to_string(),
+            content: content.to_string(),
+            created_at: chrono::Utc::now(),
+            title: String::new(),
+            section: String::new(),
+            topics: topics.iter().map(|topic| topic.to_string()).collect(),
+            logical_id: None,
+            cleaned: None,
+            chunk: None,
+        }
+    }
+
+    #[test]
+    fn test_match_topics() {
+        let topics = vec!["VRF".to_string(), "session keys".to_string()];
+        assert_eq!(
+            match_topics("How do I request vrf randomness?", &topics),
+            ["vrf"]
+        );
+        assert_eq!(
+            match_topics("Do session keys expire?", &topics),
+            ["session keys"]
+        );
+        assert!(match_topics("what is a session?", &topics).is_empty());
+
+        // Only a single plural `s` of a longer word is ignored
+        let topics = vec![
+            "access".to_string(),
+            "status".to_string(),
+            "gas".to_string(),
+        ];
+        assert_eq!(
+            match_topics("Who has access to the status page?", &topics),
+            ["access", "status"]
+        );
+        assert!(match_topics("Access is denied", &["acce".to_string()]).is_empty());
+        assert!(match_topics("What does the gasless mode do?", &topics).is_empty());
+        assert_eq!(match_topics("gas fees", &topics), ["gas"]);
+    }
+
+    #[tokio::test]
+    async fn test_topic_boost_changes_ranking() {
+        let mut knowledge = test_utils::knowledge_base().await;
+        knowledge
+            .add_documents(vec![
+                document("plain", "deploy contract guide", &[]),
+                document(
+                    "tagged",
+                    "deploy contract using the local devnet tooling and scripts",
+                    &["katana"],
+                ),
+                document("other", "paymaster fees and gas", &["paymaster"]),
+            ])
+            .await
+            .unwrap();
+
+        let query = "deploy contract on katana";
+        let plain = knowledge
+            .clone()
+            .document_index()
+            .top_n_ids(query, 2)
+            .await
+            .unwrap();
+        assert_eq!(plain[0].1, "plain");
+
+        let topics = vec!["katana".to_string(), "paymaster".to_string()];
+        let boosted = knowledge
+            .clone()
+            .topic_index(
+                topics.clone(),
+                TopicBoost {
+                    weight: 0.5,
+                    overfetch: 2,
+                },
+            )
+            .top_n_ids(query, 2)
+            .await
+            .unwrap();
+        assert_eq!(boosted[0].1, "tagged");
+        assert_eq!(boosted[1].1, "plain");
+
+        // Without a topic match the ranking is left alone.
+        let unmatched = knowledge
+            .topic_index(topics, TopicBoost::default())
+            .top_n_ids("deploy contract", 2)
+            .await
+            .unwrap();
+        assert_eq!(unmatched[0].1, "plain");
+    }
+}
@@ -1,30 +1,49 @@
-#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Deserialize)]
 pub enum Source {
     Discord,
     Telegram,
     Github,
     X,
     Twitter,
+    Farcaster,
+    /// A platform served by a client outside this crate, by its lowercase
+    /// name, see [Source::from_str].
+    #[serde(untagged)]
+    Other(String),
 }
 
 impl Source {
-    pub fn as_str(&self) -> &'static str {
+    pub fn as_str(&self) -> &str {
         match self {
             Source::Discord => "discord",
             Source::Telegram => "telegram",
             Source::Github => "github",
             Source::X => "x",
             Source::Twitter => "twitter",
+            Source::Farcaster => "farcaster",
+            Source::Other(name) => name,
         }
     }
 
+    /// The source named `s`. Names of platforms without a client here are
+    /// [Source::Other] as long as they only hold ASCII letters, digits, `-`
+    /// and `_`, so they can key config tables and round-trip through storage.
     pub fn from_str(s: &str) -> Option<Self> {
-        match s.to_lowercase().as_str() {
+        let name = s.to_lowercase();
+        match name.as_str() {
             "discord" => Some(Source::Discord),
             "telegram" => Some(Source::Telegram),
             "github" => Some(Source::Github),
             "x" => Some(Source::X),
             "twitter" => Some(Source::Twitter),
+            "farcaster" => Some(Source::Farcaster),
+            _ if !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') =>
+            {
+                Some(Source::Other(name))
+            }
             _ => None,
         }
     }
@@ -70,4 +89,4 @@ pub trait MessageMetadata {
 
 pub trait MessageContent {
     fn content(&self) -> &str;
-} 
\ No newline at end of file
+}
@@ -0,0 +1,72 @@
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+
+use super::store::KnowledgeBase;
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS user_facts (
+        agent_id TEXT NOT NULL,
+        account_id TEXT NOT NULL,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        updated_at TEXT NOT NULL,
+        PRIMARY KEY (agent_id, account_id, key)
+    );
+";
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Stores what the agent learned about a user, such as a preference
+    /// given during onboarding, replacing an earlier value of `key`. Facts
+    /// about a linked account are stored for its whole identity.
+    pub async fn set_user_fact(
+        &self,
+        account_id: &str,
+        key: &str,
+        value: &str,
+    ) -> Result<(), SqliteError> {
+        let namespace = self.namespace.clone();
+        let account_id = self.canonical_account_id(account_id).await?;
+        let key = key.to_string();
+        let value = value.to_string();
+        let now = self.clock.now_utc().to_rfc3339();
+
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT INTO user_facts (agent_id, account_id, key, value, updated_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5)
+                     ON CONFLICT (agent_id, account_id, key) DO UPDATE SET
+                         value = excluded.value,
+                         updated_at = excluded.updated_at",
+                    rusqlite::params![namespace, account_id, key, value, now],
+                )?;
+                Ok(())
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Facts about a user, or the identity of a linked account, as
+    /// `(key, value)` pairs, ordered by key.
+    pub async fn user_facts(&self, account_id: &str) -> Result<Vec<(String, String)>, SqliteError> {
+        let namespace = self.namespace.clone();
+        let account_id = self.canonical_account_id(account_id).await?;
+
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT key, value FROM user_facts
+                     WHERE agent_id = ?1 AND account_id = ?2
+                     ORDER BY key",
+                )?;
+                let facts = stmt
+                    .query_map([&namespace, &account_id], |row| {
+                        Ok((row.get(0)?, row.get(1)?))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(facts)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+}
@@ -0,0 +1,458 @@
+//! Version tracking for documents that are re-ingested under a new id, e.g.
+//! after a file moved or a loader that suffixes ids with a commit.
+//!
+//! Every stored document belongs to a logical document, identified by
+//! [Document::logical_id] or by its own id when that is unset. Ingesting a
+//! version marks the other versions of the same logical document superseded,
+//! and [FreshIndex] keeps only the newest live version of each.
+
+use std::{collections::HashMap, fmt};
+
+use rig::{
+    embeddings::EmbeddingModel,
+    vector_store::{VectorStoreError, VectorStoreIndex},
+};
+use rig_sqlite::SqliteError;
+use serde::Deserialize;
+
+use super::{identity::content_hash, models::Document, store::KnowledgeBase};
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS document_versions (
+        document_id TEXT PRIMARY KEY,
+        logical_id TEXT NOT NULL,
+        superseded INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX IF NOT EXISTS idx_document_versions_logical ON document_versions(logical_id);
+";
+
+/// Candidates fetched per requested result, so dropped versions do not leave
+/// the context short.
+const OVERFETCH: usize = 3;
+
+/// What [KnowledgeBase::add_documents] did with each document, counting a
+/// chunked document once.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StoreSummary {
+    /// Documents not stored before.
+    pub inserted: usize,
+    /// Stored documents embedded again, as any of their parts changed or
+    /// was removed.
+    pub updated: usize,
+    /// Stored documents left as they were, their content hash unchanged.
+    pub skipped: usize,
+}
+
+impl fmt::Display for StoreSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} inserted, {} updated, {} skipped",
+            self.inserted, self.updated, self.skipped
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DocumentVersion {
+    pub document_id: String,
+    pub logical_id: String,
+    pub superseded: bool,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl<E: EmbeddingModel> KnowledgeBase<E> {
+    /// Every stored version of a logical document, newest first.
+    pub async fn document_versions(
+        &self,
+        logical_id: &str,
+    ) -> Result<Vec<DocumentVersion>, SqliteError> {
+        let logical_id = logical_id.to_string();
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT v.document_id, v.logical_id, v.superseded, d.created_at
+                     FROM document_versions v
+                     JOIN documents d ON d.id = v.document_id
+                     WHERE v.agent_id = ?2 AND v.logical_id = ?1
+                     ORDER BY d.created_at DESC",
+                )?;
+                let versions = stmt
+                    .query_map([&logical_id, &namespace], |row| {
+                        Ok(DocumentVersion {
+                            document_id: row.get(0)?,
+                            logical_id: row.get(1)?,
+                            superseded: row.get(2)?,
+                            created_at: row.get(3)?,
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(versions)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Which of `documents` are stored under their id, each with whether it
+    /// is unchanged: stored under the same logical id with the same
+    /// [content_hash] and title, embedded and not superseded, so it need not
+    /// be embedded again.
+    pub(super) async fn stored_documents(
+        &self,
+        documents: &[Document],
+    ) -> Result<HashMap<String, bool>, tokio_rusqlite::Error> {
+        let versions = documents
+            .iter()
+            .map(|document| {
+                serde_json::json!({
+                    "id": document.id,
+                    "logical_id": document.logical_id.as_ref().unwrap_or(&document.id),
+                    "content_hash": content_hash(&document.content),
+                    "title": document.title,
+                })
+            })
+            .collect::<Vec<_>>();
+        let versions = serde_json::Value::from(versions).to_string();
+        let namespace = self.namespace.clone();
+
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT d.id, COALESCE(
+                         v.logical_id = json_extract(j.value, '$.logical_id')
+                             AND v.content_hash = json_extract(j.value, '$.content_hash')
+                             AND d.title = json_extract(j.value, '$.title')
+                             AND v.superseded = 0
+                             AND d.rowid IN (SELECT rowid FROM documents_embeddings),
+                         0
+                     )
+                     FROM json_each(?2) j
+                     JOIN documents d
+                         ON d.agent_id = ?1 AND d.id = json_extract(j.value, '$.id')
+                     LEFT JOIN document_versions v ON v.document_id = d.id",
+                )?;
+                let stored = stmt
+                    .query_map([&namespace, &versions], |row| {
+                        Ok((row.get(0)?, row.get(1)?))
+                    })?
+                    .collect::<Result<HashMap<_, _>, _>>()?;
+                Ok(stored)
+            })
+            .await
+    }
+
+    async fn load_versions(
+        &self,
+        document_ids: Vec<String>,
+    ) -> Result<HashMap<String, DocumentVersion>, tokio_rusqlite::Error> {
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT d.id, COALESCE(v.logical_id, d.id), COALESCE(v.superseded, 0), d.created_at
+                     FROM documents d
+                     LEFT JOIN document_versions v ON v.document_id = d.id
+                     WHERE d.id = ?1",
+                )?;
+
+                let mut versions = HashMap::new();
+                for id in document_ids {
+                    let version = stmt.query_row([&id], |row| {
+                        Ok(DocumentVersion {
+                            document_id: row.get(0)?,
+                            logical_id: row.get(1)?,
+                            superseded: row.get(2)?,
+                            created_at: row.get(3)?,
+                        })
+                    });
+                    match version {
+                        Ok(version) => {
+                            versions.insert(id, version);
+                        }
+                        Err(rusqlite::Error::QueryReturnedNoRows) => {}
+                        Err(err) => return Err(err.into()),
+                    }
+                }
+                Ok(versions)
+            })
+            .await
+    }
+
+    /// Wraps a document index so results only include the newest live version
+    /// of each logical document.
+    pub fn fresh_index<I: VectorStoreIndex>(self, index: I) -> FreshIndex<I, E> {
+        FreshIndex {
+            index,
+            knowledge: self,
+        }
+    }
+}
+
+/// Records the logical document of each stored document of `namespace` and
+/// supersedes its other versions.
+pub(super) fn store_versions(
+    tx: &rusqlite::Transaction,
+    namespace: &str,
+    documents: &[Document],
+) -> rusqlite::Result<()> {
+    for document in documents {
+        let logical_id = document.logical_id.as_ref().unwrap_or(&document.id);
+        tx.execute(
+            "UPDATE document_versions SET superseded = 1
+             WHERE agent_id = ?3 AND logical_id = ?1 AND document_id != ?2",
+            [logical_id, &document.id, namespace],
+        )?;
+        tx.execute(
+            "INSERT INTO document_versions
+                 (document_id, logical_id, superseded, agent_id, content_hash)
+             VALUES (?1, ?2, 0, ?3, ?4)
+             ON CONFLICT (document_id) DO UPDATE SET
+                 logical_id = excluded.logical_id,
+                 superseded = 0,
+                 agent_id = excluded.agent_id,
+                 content_hash = excluded.content_hash",
+            [
+                &document.id,
+                logical_id,
+                namespace,
+                &content_hash(&document.content),
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+/// Drops superseded candidates and keeps only the newest version of each
+/// logical document, leaving the ranking of the survivors unchanged.
+/// Candidates without version info are kept as their own logical document.
+pub fn latest_versions<T>(
+    candidates: Vec<(f64, String, T)>,
+    versions: &HashMap<String, DocumentVersion>,
+) -> Vec<(f64, String, T)> {
+    let mut newest: HashMap<&str, &DocumentVersion> = HashMap::new();
+    for version in versions.values().filter(|version| !version.superseded) {
+        let entry = newest.entry(version.logical_id.as_str()).or_insert(version);
+        if version.created_at > entry.created_at {
+            *entry = version;
+        }
+    }
+
+    candidates
+        .into_iter()
+        .filter(|(_, id, _)| match versions.get(id) {
+            Some(version) => newest
+                .get(version.logical_id.as_str())
+                .is_some_and(|newest| newest.document_id == *id),
+            None => true,
+        })
+        .collect()
+}
+
+pub struct FreshIndex<I: VectorStoreIndex, E: EmbeddingModel + 'static> {
+    index: I,
+    knowledge: KnowledgeBase<E>,
+}
+
+impl<I: VectorStoreIndex, E: EmbeddingModel + Sync> FreshIndex<I, E> {
+    async fn latest<T: Send>(
+        &self,
+        n: usize,
+        candidates: Vec<(f64, String, T)>,
+    ) -> Result<Vec<(f64, String, T)>, VectorStoreError> {
+        let ids = candidates.iter().map(|(_, id, _)| id.clone()).collect();
+        let versions = self
+            .knowledge
+            .load_versions(ids)
+            .await
+            .map_err(|e| VectorStoreError::DatastoreError(Box::new(e)))?;
+
+        let mut latest = latest_versions(candidates, &versions);
+        latest.truncate(n);
+        Ok(latest)
+    }
+}
+
+impl<I: VectorStoreIndex, E: EmbeddingModel + Sync> VectorStoreIndex for FreshIndex<I, E> {
+    async fn top_n<T: for<'a> Deserialize<'a> + Send>(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<(f64, String, T)>, VectorStoreError> {
+        let candidates = self.index.top_n::<T>(query, n * OVERFETCH).await?;
+        self.latest(n, candidates).await
+    }
+
+    async fn top_n_ids(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<(f64, String)>, VectorStoreError> {
+        let candidates = self
+            .index
+            .top_n_ids(query, n * OVERFETCH)
+            .await?
+            .into_iter()
+            .map(|(distance, id)| (distance, id, ()))
+            .collect();
+
+        Ok(self
+            .latest(n, candidates)
+            .await?
+            .into_iter()
+            .map(|(distance, id, _)| (distance, id))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+    use chrono::{DateTime, Utc};
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000 + seconds, 0).unwrap()
+    }
+
+    fn page(id: &str, content: &str, created_at: DateTime<Utc>) -> Document {
+        Document {
+            id: id.to_string(),
+            source_id: "github".to_string(),
+            content: content.to_string(),
+            created_at,
+            title: String::new(),
+            section: String::new(),
+            topics: Vec::new(),
+            logical_id: Some("docs/katana.md".to_string()),
+            cleaned: None,
+            chunk: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_only_newest_version_is_retrieved() {
+        let mut knowledge = test_utils::knowledge_base().await;
+        let old = page("katana.md@v1", "katana flags --dev --old", at(0));
+        let new = page("moved/katana.md@v2", "katana flags --dev --new", at(10));
+        let other = Document {
+            logical_id: None,
+            ..page("paymaster.md", "paymaster fees", at(5))
+        };
+        knowledge.add_documents(vec![old, other]).await.unwrap();
+        knowledge.add_documents(vec![new]).await.unwrap();
+
+        // The old version is still the closer match lexically.
+        let query = "katana flags --dev --old";
+        let plain = knowledge
+            .clone()
+            .document_index()
+            .top_n_ids(query, 3)
+            .await
+            .unwrap();
+        assert_eq!(plain[0].1, "katana.md@v1");
+
+        let fresh = knowledge
+            .clone()
+            .fresh_index(knowledge.clone().document_index())
+            .top_n_ids(query, 3)
+            .await
+            .unwrap();
+        let ids = fresh.iter().map(|(_, id)| id.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, ["moved/katana.md@v2", "paymaster.md"]);
+
+        let versions = knowledge.document_versions("docs/katana.md").await.unwrap();
+        let summary = versions
+            .iter()
+            .map(|v| (v.document_id.as_str(), v.superseded))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [("moved/katana.md@v2", false), ("katana.md@v1", true)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_documents_are_not_embedded_again() {
+        let model = test_utils::FlakyEmbeddingModel::failing(0);
+        let mut knowledge = test_utils::knowledge_base_with(model.clone()).await;
+        let vrf = test_utils::document("vrf.md", "# VRF\n\nRandomness on chain.");
+        let katana = test_utils::document("katana.md", "# Katana\n\nA local devnet.");
+
+        let summary = knowledge
+            .add_documents(vec![vrf.clone(), katana.clone()])
+            .await
+            .unwrap();
+        assert_eq!(
+            (summary.inserted, summary.updated, summary.skipped),
+            (2, 0, 0)
+        );
+
+        // Embedding would fail, but nothing needs it
+        model.fail_next(1);
+        let summary = knowledge
+            .add_documents(vec![vrf.clone(), katana.clone()])
+            .await
+            .unwrap();
+        assert_eq!(
+            (summary.inserted, summary.updated, summary.skipped),
+            (0, 0, 2)
+        );
+        model.fail_next(0);
+
+        let changed = Document {
+            content: "# VRF\n\nRandomness on chain, now cheaper.".to_string(),
+            ..vrf
+        };
+        let summary = knowledge
+            .add_documents(vec![changed, katana])
+            .await
+            .unwrap();
+        assert_eq!(
+            (summary.inserted, summary.updated, summary.skipped),
+            (0, 1, 1)
+        );
+
+        // The old vector was replaced rather than left behind
+        let embeddings = knowledge
+            .conn
+            .call(|conn| {
+                Ok(
+                    conn.query_row("SELECT COUNT(*) FROM documents_embeddings", [], |row| {
+                        row.get::<_, usize>(0)
+                    })?,
+                )
+            })
+            .await
+            .unwrap();
+        assert_eq!(embeddings, 2);
+        let stored = knowledge.get_document("/tmp/docs/vrf.md").await.unwrap();
+        assert!(stored.unwrap().content.ends_with("now cheaper."));
+    }
+
+    #[test]
+    fn test_latest_versions_without_superseded_flag() {
+        let version = |id: &str, seconds| DocumentVersion {
+            document_id: id.to_string(),
+            logical_id: "page".to_string(),
+            superseded: false,
+            created_at: at(seconds),
+        };
+        let versions = HashMap::from([
+            ("old".to_string(), version("old", 0)),
+            ("new".to_string(), version("new", 10)),
+        ]);
+        let candidates = vec![
+            (0.1, "old".to_string(), ()),
+            (0.2, "unknown".to_string(), ()),
+            (0.3, "new".to_string(), ()),
+        ];
+
+        let latest = latest_versions(candidates, &versions);
+        let ids = latest
+            .iter()
+            .map(|(_, id, _)| id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["unknown", "new"]);
+    }
+}
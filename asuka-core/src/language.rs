@@ -0,0 +1,326 @@
+//! Localization of the messages the bot sends from
+//! [templates](crate::templates) rather than the model, such as the rate
+//! limit notice, in the language of the user.
+//!
+//! The language of every incoming message is guessed with [detect] and kept
+//! as a rolling score per user and per channel, so a user who switches
+//! languages is answered in the new one after a few messages. A template is
+//! rendered in, first found:
+//!
+//! 1. the language the user gave during onboarding, the `language` fact,
+//! 2. the language the user writes in most,
+//! 3. the language of the channel, for messages not addressed to one user,
+//! 4. `default_language`.
+//!
+//! The text comes from the character's per-language template if it has one.
+//! Otherwise, with `translate`, the default template is translated once by
+//! the model and cached per template and language until its text changes.
+//! Without either, the default template is used.
+//!
+//! ```toml
+//! [localization]
+//! default_language = "en"
+//! translate = true
+//! ```
+//!
+//! Per-language templates go in the character file, next to the defaults:
+//!
+//! ```toml
+//! [templates]
+//! muted_ack = "Understood, I'll stay quiet here."
+//!
+//! [templates.rate_limited]
+//! es = "Ya respondí muchas de tus preguntas, vuelve a intentarlo en {{retry_after}}."
+//! ja = "{{retry_after}}後にもう一度お試しください。"
+//! ```
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+/// User fact, set during onboarding, naming the language to answer in.
+pub const LANGUAGE_FACT: &str = "language";
+
+/// Weight the earlier messages keep each time a user or channel sends
+/// another, so the score follows recent messages.
+pub const DECAY: f64 = 0.8;
+
+/// Instruction for translating a template, followed by the target language
+/// and the template as the prompt.
+pub const TRANSLATE_INSTRUCTION: &str = "Translate the message below into the target language, given as an ISO 639-1 code. Keep every placeholder in double braces, such as {{retry_after}}, exactly as written. Reply with only the translation.";
+
+/// `[localization]` settings of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LocalizationConfig {
+    /// ISO 639-1 code of the language the default templates are in, used
+    /// when nothing is known about a user or channel.
+    pub default_language: String,
+    /// Whether templates without a per-language text are translated by the
+    /// model.
+    pub translate: bool,
+}
+
+impl Default for LocalizationConfig {
+    fn default() -> Self {
+        Self {
+            default_language: "en".to_string(),
+            translate: false,
+        }
+    }
+}
+
+impl LocalizationConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !is_code(&self.default_language) {
+            return Err(format!(
+                "localization.default_language: {:?} is not an ISO 639-1 code",
+                self.default_language
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Languages [detect] tells apart by their most common words, with the
+/// names users give them in.
+const LATIN_LANGUAGES: &[(&str, &[&str], &[&str])] = &[
+    (
+        "en",
+        &[
+            "english", "anglais", "inglés", "ingles", "inglês", "englisch",
+        ],
+        &[
+            "the", "and", "is", "are", "you", "what", "how", "does", "to", "of", "it", "this",
+            "that", "with", "for", "can", "my", "i", "have", "why",
+        ],
+    ),
+    (
+        "es",
+        &[
+            "spanish", "español", "espanol", "espagnol", "espanhol", "spanisch",
+        ],
+        &[
+            "el", "los", "las", "que", "es", "y", "por", "qué", "cómo", "para", "una", "mi", "con",
+            "está", "puedo", "hola", "pero", "del", "muy", "yo",
+        ],
+    ),
+    (
+        "fr",
+        &[
+            "french",
+            "français",
+            "francais",
+            "francés",
+            "francês",
+            "französisch",
+        ],
+        &[
+            "le", "les", "des", "est", "et", "je", "vous", "une", "pour", "avec", "pas", "comment",
+            "quoi", "mon", "dans", "ce", "il", "bonjour", "mais", "du",
+        ],
+    ),
+    (
+        "pt",
+        &[
+            "portuguese",
+            "português",
+            "portugues",
+            "portugais",
+            "portugiesisch",
+        ],
+        &[
+            "o", "os", "é", "não", "um", "uma", "para", "com", "como", "você", "meu", "isso",
+            "está", "olá", "mas", "do", "da", "muito", "eu", "posso",
+        ],
+    ),
+    (
+        "de",
+        &[
+            "german", "deutsch", "allemand", "alemán", "alemao", "alemão",
+        ],
+        &[
+            "der", "die", "das", "und", "ist", "ich", "nicht", "ein", "eine", "wie", "was", "mit",
+            "für", "auf", "du", "sie", "zu", "den", "wir", "hallo",
+        ],
+    ),
+    (
+        "it",
+        &["italian", "italiano", "italien", "italienisch"],
+        &[
+            "il", "lo", "gli", "che", "è", "di", "un", "non", "per", "come", "cosa", "sono", "mi",
+            "questo", "ho", "del", "ciao", "ma", "della", "perché",
+        ],
+    ),
+];
+
+/// Languages [detect] tells apart by their script, with the names users give
+/// them in.
+const SCRIPT_LANGUAGES: &[(&str, &[&str])] = &[
+    ("ja", &["japanese", "日本語", "japonais", "japonés"]),
+    ("ko", &["korean", "한국어", "coréen", "coreano"]),
+    ("zh", &["chinese", "中文", "汉语", "chinois", "chino"]),
+    ("ru", &["russian", "русский", "russe", "ruso"]),
+    ("ar", &["arabic", "العربية", "arabe", "árabe"]),
+];
+
+/// Fewest common words a message needs before its language is guessed, so
+/// short messages like "ok" or a name don't count.
+const MIN_WORD_HITS: usize = 2;
+
+/// The language `text` is most likely written in, as an ISO 639-1 code, or
+/// `None` when it is too short or ambiguous to tell.
+pub fn detect(text: &str) -> Option<&'static str> {
+    if let Some(language) = detect_script(text) {
+        return Some(language);
+    }
+
+    let words = text
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>();
+    let mut scores = LATIN_LANGUAGES
+        .iter()
+        .map(|(code, _, common)| {
+            let hits = words
+                .iter()
+                .filter(|word| common.contains(&word.as_str()))
+                .count();
+            (*code, hits)
+        })
+        .collect::<Vec<_>>();
+    scores.sort_by(|a, b| b.1.cmp(&a.1));
+    match scores.as_slice() {
+        [(code, best), (_, second), ..] if *best >= MIN_WORD_HITS && best > second => Some(code),
+        _ => None,
+    }
+}
+
+/// The language of a non-Latin script making up most of the letters of
+/// `text`. Kana tells Japanese from Chinese, which share the Han script.
+fn detect_script(text: &str) -> Option<&'static str> {
+    let (mut letters, mut kana, mut hangul, mut han, mut cyrillic, mut arabic) = (0, 0, 0, 0, 0, 0);
+    for c in text.chars().filter(|c| c.is_alphabetic()) {
+        letters += 1;
+        match c {
+            '\u{3040}'..='\u{30ff}' => kana += 1,
+            '\u{ac00}'..='\u{d7af}' | '\u{1100}'..='\u{11ff}' => hangul += 1,
+            '\u{4e00}'..='\u{9fff}' | '\u{3400}'..='\u{4dbf}' => han += 1,
+            '\u{0400}'..='\u{04ff}' => cyrillic += 1,
+            '\u{0600}'..='\u{06ff}' => arabic += 1,
+            _ => {}
+        }
+    }
+    let majority = |count: usize| count > 0 && count * 2 >= letters;
+    if kana > 0 && majority(kana + han) {
+        Some("ja")
+    } else if majority(hangul) {
+        Some("ko")
+    } else if majority(han) {
+        Some("zh")
+    } else if majority(cyrillic) {
+        Some("ru")
+    } else if majority(arabic) {
+        Some("ar")
+    } else {
+        None
+    }
+}
+
+/// The ISO 639-1 code of `name`, a language as users give it, like "French",
+/// "français" or "fr".
+pub fn code(name: &str) -> Option<String> {
+    let name = name.trim().to_lowercase();
+    let known = LATIN_LANGUAGES
+        .iter()
+        .map(|(code, names, _)| (*code, *names))
+        .chain(SCRIPT_LANGUAGES.iter().copied())
+        .find(|(code, names)| *code == name || names.contains(&name.as_str()))
+        .map(|(code, _)| code.to_string());
+    known.or_else(|| is_code(&name).then_some(name))
+}
+
+fn is_code(code: &str) -> bool {
+    code.len() == 2 && code.chars().all(|c| c.is_ascii_lowercase())
+}
+
+/// Hash of a template's text, which its cached translations are stored
+/// under so changing the text invalidates them.
+pub fn source_hash(text: &str) -> String {
+    Sha256::digest(text.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Whether `translation` kept every placeholder of `source`. Translations
+/// that dropped or translated one are discarded.
+pub fn keeps_placeholders(source: &str, translation: &str) -> bool {
+    placeholders(source).all(|name| placeholders(translation).any(|other| other == name))
+}
+
+pub(crate) fn placeholders(text: &str) -> impl Iterator<Item = &str> {
+    text.split("{{")
+        .skip(1)
+        .filter_map(|rest| rest.split_once("}}").map(|(name, _)| name.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(
+            detect("How does the paymaster work with my session?"),
+            Some("en")
+        );
+        assert_eq!(
+            detect("¿Cómo puedo usar el paymaster con mi sesión?"),
+            Some("es")
+        );
+        assert_eq!(
+            detect("Bonjour, comment je configure le paymaster ?"),
+            Some("fr")
+        );
+        assert_eq!(detect("Olá, como eu posso usar isso com você?"), Some("pt"));
+        assert_eq!(detect("Wie funktioniert das mit der Session?"), Some("de"));
+        assert_eq!(detect("セッションキーはどうやって使いますか？"), Some("ja"));
+        assert_eq!(detect("会话密钥怎么用？"), Some("zh"));
+        assert_eq!(detect("세션 키는 어떻게 사용하나요?"), Some("ko"));
+        assert_eq!(detect("Как использовать ключ сессии?"), Some("ru"));
+
+        // Too short or ambiguous
+        assert_eq!(detect("ok"), None);
+        assert_eq!(detect("gm"), None);
+        assert_eq!(detect("paymaster?"), None);
+    }
+
+    #[test]
+    fn test_code() {
+        assert_eq!(code("French").as_deref(), Some("fr"));
+        assert_eq!(code(" Español ").as_deref(), Some("es"));
+        assert_eq!(code("日本語").as_deref(), Some("ja"));
+        assert_eq!(code("nl").as_deref(), Some("nl"));
+        assert_eq!(code("Klingon"), None);
+    }
+
+    #[test]
+    fn test_keeps_placeholders() {
+        let source = "Try again in {{retry_after}}.";
+        assert!(keeps_placeholders(
+            source,
+            "Réessaie dans {{ retry_after }}."
+        ));
+        assert!(!keeps_placeholders(source, "Réessaie dans {{délai}}."));
+        assert!(!keeps_placeholders(source, "Réessaie plus tard."));
+        assert!(keeps_placeholders("No placeholders", "Sans variables"));
+    }
+
+    #[test]
+    fn test_source_hash() {
+        assert_eq!(source_hash("a"), source_hash("a"));
+        assert_ne!(source_hash("a"), source_hash("b"));
+        assert_eq!(source_hash("").len(), 64);
+    }
+}
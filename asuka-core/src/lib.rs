@@ -1,22 +1,83 @@
+/// Sets up logging with the default [logging::LoggingConfig]. Failures, such
+/// as logging already being initialized, are reported to stderr.
 pub fn init_logging() {
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::from_default_env()
-                .add_directive(tracing::Level::DEBUG.into())
-                .add_directive("asuka=debug".parse().unwrap())
-                .add_directive("rustls=off".parse().unwrap())
-                .add_directive("hyper=off".parse().unwrap())
-                .add_directive("h2=off".parse().unwrap())
-                .add_directive("serenity=off".parse().unwrap())
-                .add_directive("reqwest=off".parse().unwrap()),
-        )
-        .init();
+    if let Err(err) = logging::init_logging(logging::LoggingConfig::default()) {
+        eprintln!("{err}");
+    }
 }
 
 pub mod agent;
+pub mod attachments;
 pub mod attention;
+pub mod capabilities;
 pub mod character;
 pub mod clients;
+pub mod clock;
+pub mod commands;
+pub mod confidence;
+pub mod config;
+pub mod conversation;
+pub mod corrections;
+pub mod deployment;
+pub mod digest;
+pub mod escalation;
+pub mod escape;
+pub mod events;
+pub mod experiments;
+pub mod familiarity;
+pub mod generation;
+pub mod history;
+pub mod hooks;
+pub mod injection;
 pub mod knowledge;
+pub mod language;
+pub mod linking;
 pub mod loaders;
+pub mod locale;
+pub mod logging;
+pub mod markdown;
+#[cfg(feature = "mcp")]
 pub mod mcp;
+pub mod memory;
+pub mod names;
+pub mod onboarding;
+pub mod outage;
+pub mod permissions;
+pub mod pipeline;
+pub mod prompt;
+pub mod providers;
+pub mod quoted;
+pub mod quotes;
+pub mod rate_limit;
+pub mod reporting;
+pub mod research;
+pub mod retention;
+pub mod rewrite;
+pub mod say;
+pub mod scrub;
+#[cfg(feature = "git-loader")]
+pub mod sources;
+pub mod startup;
+pub mod status;
+pub mod structured;
+pub mod summarize;
+pub mod templates;
+pub mod tools;
+pub mod truncation;
+pub mod webhooks;
+
+#[cfg(test)]
+mod test_utils;
+
+// The surface for clients outside this crate, see [clients::external].
+// These paths stay put across releases even when the modules behind them
+// move; anything reached only through a module path may change.
+pub use agent::Agent;
+pub use attention::{Attention, AttentionConfig};
+pub use character::Character;
+pub use clients::{
+    external::{Client, ClientContext, IncomingMessage, RepliedMessage},
+    streaming::ReplySink,
+};
+pub use knowledge::{ChannelType, KnowledgeBase, Source};
+pub use pipeline::{BatchConfig, Handled, Pipeline, PipelineError};
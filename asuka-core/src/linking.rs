@@ -0,0 +1,230 @@
+//! Account linking, so the bot recognizes a person who talks to it on
+//! several platforms.
+//!
+//! The person sends `/link` to the bot in a direct message on one platform
+//! and gets a code, then sends `/link <code>` from their account on the
+//! other platform before the code expires. From then on both accounts share
+//! their user facts, such as onboarding answers, language and time zone, and
+//! count together for the [rate limit](crate::rate_limit) and
+//! [familiarity](crate::familiarity), see
+//! [KnowledgeBase::canonical_account_id]. `/unlink` takes the account out of
+//! its identity again.
+//!
+//! Both commands are open to everyone, once the section is in the config
+//! file:
+//!
+//! ```toml
+//! [linking]
+//! code_ttl_minutes = 10
+//! ```
+
+use rig::embeddings::EmbeddingModel;
+use serde::Deserialize;
+use tracing::{error, info};
+
+use crate::{
+    commands::Command,
+    knowledge::{ChannelType, KnowledgeBase, LinkError, Message},
+};
+
+/// `[linking]` section of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LinkingConfig {
+    /// Minutes a link code can be redeemed in.
+    pub code_ttl_minutes: i64,
+}
+
+impl Default for LinkingConfig {
+    fn default() -> Self {
+        Self {
+            code_ttl_minutes: 10,
+        }
+    }
+}
+
+impl LinkingConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.code_ttl_minutes <= 0 {
+            return Err("linking.code_ttl_minutes must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Runs `message` if it is `/link` or `/unlink`, and returns the reply to
+/// its author. `None` for any other message, including the commands with
+/// wrong arguments, which [crate::commands::handle] answers with the usage.
+/// Linking is off without a `config`.
+pub async fn handle<E: EmbeddingModel>(
+    knowledge: &KnowledgeBase<E>,
+    config: Option<&LinkingConfig>,
+    message: &Message,
+) -> Option<String> {
+    let command = match Command::parse(&message.content)?.ok()? {
+        command @ (Command::Link { .. } | Command::Unlink) => command,
+        _ => return None,
+    };
+    let Some(config) = config else {
+        return Some("Account linking is not enabled.".to_string());
+    };
+    let account_id = message.account_id.as_str();
+
+    let reply = match command {
+        // A code posted in a channel could be redeemed by anyone reading it
+        Command::Link { code: None } if message.channel_type != ChannelType::DirectMessage => {
+            "Send /link to me in a direct message, so nobody else sees your code.".to_string()
+        }
+        Command::Link { code: None } => {
+            let ttl = chrono::Duration::minutes(config.code_ttl_minutes);
+            match knowledge
+                .create_link_code(&message.source, account_id, ttl)
+                .await
+            {
+                Ok(code) => format!(
+                    "Your link code is {code}. Send `/link {code}` to me from your other account \
+                     within {} minutes.",
+                    config.code_ttl_minutes
+                ),
+                Err(err) => {
+                    error!(?err, "Failed to create link code");
+                    "Sorry, that command failed.".to_string()
+                }
+            }
+        }
+        Command::Link { code: Some(code) } => {
+            match knowledge
+                .redeem_link_code(&code, &message.source, account_id)
+                .await
+            {
+                Ok(identity) => {
+                    info!(
+                        account_id,
+                        accounts = identity.accounts.len(),
+                        "Linked accounts"
+                    );
+                    let platforms = identity
+                        .accounts
+                        .iter()
+                        .map(|(source, _)| source.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    format!("Linked. I now know you on {platforms}.")
+                }
+                Err(LinkError::Storage(err)) => {
+                    error!(?err, "Failed to redeem link code");
+                    "Sorry, that command failed.".to_string()
+                }
+                Err(err) => err.to_string(),
+            }
+        }
+        _ => match knowledge.unlink_account(account_id).await {
+            Ok(true) => {
+                info!(account_id, "Unlinked account");
+                "Unlinked this account.".to_string()
+            }
+            Ok(false) => "This account isn't linked.".to_string(),
+            Err(err) => {
+                error!(?err, "Failed to unlink account");
+                "Sorry, that command failed.".to_string()
+            }
+        },
+    };
+    Some(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{knowledge::Source, test_utils};
+
+    fn message(source: Source, channel_type: ChannelType, account_id: &str, text: &str) -> Message {
+        Message {
+            id: "m1".to_string(),
+            source,
+            source_id: account_id.to_string(),
+            channel_type,
+            channel_id: format!("dm-{account_id}"),
+            account_id: account_id.to_string(),
+            role: "user".to_string(),
+            content: text.to_string(),
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_link_across_platforms() {
+        let knowledge = test_utils::knowledge_base().await;
+        let config = LinkingConfig::default();
+        let run = |message: Message| {
+            let knowledge = knowledge.clone();
+            let config = config.clone();
+            async move { handle(&knowledge, Some(&config), &message).await }
+        };
+
+        let public = message(Source::Discord, ChannelType::Text, "d1", "/link");
+        assert!(run(public).await.unwrap().contains("direct message"));
+        assert_eq!(
+            handle(
+                &knowledge,
+                None,
+                &message(Source::Discord, ChannelType::DirectMessage, "d1", "/link")
+            )
+            .await
+            .unwrap(),
+            "Account linking is not enabled."
+        );
+        assert_eq!(
+            run(message(
+                Source::Discord,
+                ChannelType::Text,
+                "d1",
+                "what is vrf?"
+            ))
+            .await,
+            None
+        );
+
+        knowledge
+            .set_user_fact("d1", "language", "French")
+            .await
+            .unwrap();
+        let reply = run(message(
+            Source::Discord,
+            ChannelType::DirectMessage,
+            "d1",
+            "/link",
+        ))
+        .await
+        .unwrap();
+        let code = reply
+            .strip_prefix("Your link code is ")
+            .and_then(|rest| rest.split('.').next())
+            .unwrap();
+        assert!(reply.ends_with("within 10 minutes."), "{reply}");
+
+        let redeem = format!("/link {code}");
+        assert_eq!(
+            run(message(Source::Telegram, ChannelType::Text, "t1", &redeem))
+                .await
+                .unwrap(),
+            "Linked. I now know you on discord, telegram."
+        );
+        // What the bot learned on Discord is known on Telegram
+        assert_eq!(
+            knowledge.user_facts("t1").await.unwrap(),
+            [("language".to_string(), "French".to_string())]
+        );
+        assert_eq!(
+            run(message(Source::Telegram, ChannelType::Text, "t2", &redeem))
+                .await
+                .unwrap(),
+            LinkError::UnknownCode.to_string()
+        );
+
+        let unlink = message(Source::Telegram, ChannelType::Text, "t1", "/unlink");
+        assert_eq!(run(unlink.clone()).await.unwrap(), "Unlinked this account.");
+        assert_eq!(run(unlink).await.unwrap(), "This account isn't linked.");
+        assert!(knowledge.user_facts("t1").await.unwrap().is_empty());
+    }
+}
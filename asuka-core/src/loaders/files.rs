@@ -0,0 +1,58 @@
+use std::path::PathBuf;
+
+use futures::{stream, Stream, StreamExt};
+use tracing::debug;
+use walkdir::WalkDir;
+
+use crate::knowledge::Document;
+
+/// Lazily walks `root` and yields one [Document] per file. Files are only read
+/// when the consumer polls for the next item, so memory use stays proportional
+/// to what the consumer buffers rather than the size of the tree.
+pub fn stream_documents(
+    root: PathBuf,
+    source_id: String,
+) -> impl Stream<Item = std::io::Result<Document>> + Send + 'static {
+    let entries = WalkDir::new(root)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|entry| match entry {
+            Ok(entry) if entry.file_type().is_file() => Some(Ok(entry.into_path())),
+            Ok(_) => None,
+            Err(err) => Some(Err(std::io::Error::from(err))),
+        });
+
+    read_documents(entries, source_id)
+}
+
+/// Reads each of `paths` as a [Document], lazily like [stream_documents].
+pub fn read_documents<I>(
+    paths: I,
+    source_id: String,
+) -> impl Stream<Item = std::io::Result<Document>> + Send + 'static
+where
+    I: IntoIterator<Item = std::io::Result<PathBuf>>,
+    I::IntoIter: Send + 'static,
+{
+    stream::iter(paths).then(move |entry| {
+        let source_id = source_id.clone();
+        async move {
+            let path = entry?;
+            debug!(path = ?path, "Reading document");
+            let content = tokio::fs::read_to_string(&path).await?;
+
+            Ok(Document {
+                id: path.to_string_lossy().to_string(),
+                source_id,
+                content,
+                created_at: chrono::Utc::now(),
+                title: String::new(),
+                section: String::new(),
+                topics: Vec::new(),
+                logical_id: None,
+                cleaned: None,
+                chunk: None,
+            })
+        }
+    })
+}
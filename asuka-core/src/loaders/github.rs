@@ -1,8 +1,20 @@
+use async_trait::async_trait;
+use futures::{Stream, StreamExt};
 use git2::{FetchOptions, RemoteCallbacks, Repository};
 use rig::loaders::{file::FileLoaderError, FileLoader};
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 use tracing::{debug, info};
+use walkdir::WalkDir;
+
+use rig::embeddings::EmbeddingModel;
+
+use crate::knowledge::{ContentCleaner, Document, KnowledgeBase, Outline, RefreshSource};
+
+/// Source of the documents of a [GitLoader], unless set with
+/// [GitLoader::with_source_id].
+const SOURCE_ID: &str = "github";
 
 #[derive(Error, Debug)]
 pub enum GitLoaderError {
@@ -14,26 +26,79 @@ pub enum GitLoaderError {
 
     #[error("File loader error: {0}")]
     FileLoaderError(#[from] FileLoaderError),
+
+    #[error("Store error: {0:?}")]
+    StoreError(#[from] rig_sqlite::SqliteError),
+
+    #[error("Sync task failed: {0}")]
+    TaskError(#[from] tokio::task::JoinError),
 }
 
+#[derive(Clone)]
 pub struct GitRepo {
-    url: String,
+    pub(crate) url: String,
+    pub(crate) branch: String,
     pub(crate) path: PathBuf,
     pub(crate) base_path: PathBuf,
 }
 
+/// Files that differ between two commits, relative to the repository root.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ChangedFiles {
+    /// Added files. A renamed file is added under its new path and deleted
+    /// under its old one.
+    pub added: Vec<PathBuf>,
+    pub modified: Vec<PathBuf>,
+    pub deleted: Vec<PathBuf>,
+}
+
+impl ChangedFiles {
+    /// Added and modified files, the ones to read again.
+    pub fn changed(&self) -> impl Iterator<Item = &PathBuf> {
+        self.added.iter().chain(&self.modified)
+    }
+}
+
+/// Where a [GitRepo::sync_with_diff] brought the repository.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SyncDiff {
+    /// Commit the repository was synced to.
+    pub commit: String,
+    /// Files changed since the last synced commit, or every file as added
+    /// when `full`.
+    pub files: ChangedFiles,
+    /// Whether every file is listed: on the first sync, when forced, or when
+    /// the last synced commit is gone after a force push. Files deleted since
+    /// are then not known.
+    pub full: bool,
+}
+
 impl GitRepo {
+    /// A checkout of `url` under `base_path`, at `<org>/<repo>`. `url` may
+    /// also be a local path or `file://` URL, such as a test fixture.
     pub fn new(url: String, base_path: PathBuf) -> Self {
-        let parts: Vec<&str> = url.trim_end_matches(".git").split('/').collect();
-        let (org, repo) = (parts[parts.len() - 2], parts[parts.len() - 1]);
+        let mut parts = url
+            .trim_end_matches('/')
+            .trim_end_matches(".git")
+            .rsplit('/')
+            .filter(|part| !part.is_empty());
+        let repo = parts.next().unwrap_or("repo");
+        let org = parts.next().unwrap_or("local");
         let path = base_path.join(org).join(repo);
         Self {
             url,
+            branch: "main".to_string(),
             base_path,
             path,
         }
     }
 
+    /// Tracks `branch` instead of `main`.
+    pub fn with_branch(mut self, branch: &str) -> Self {
+        self.branch = branch.to_string();
+        self
+    }
+
     pub fn sync(&self) -> Result<Repository, GitLoaderError> {
         if self.path.exists() {
             info!(path = ?self.path, "Repository path exists, updating");
@@ -46,8 +111,10 @@ impl GitRepo {
 
     fn clone(&self) -> Result<Repository, GitLoaderError> {
         std::fs::create_dir_all(&self.base_path)?;
-        debug!(url = %self.url, path = ?self.path, "Cloning repository");
-        Ok(Repository::clone(&self.url, &self.path)?)
+        debug!(url = %self.url, branch = %self.branch, path = ?self.path, "Cloning repository");
+        Ok(git2::build::RepoBuilder::new()
+            .branch(&self.branch)
+            .clone(&self.url, &self.path)?)
     }
 
     fn reset(&self) -> Result<Repository, GitLoaderError> {
@@ -58,15 +125,16 @@ impl GitRepo {
             let callbacks = RemoteCallbacks::new();
             let mut fetch_options = FetchOptions::new();
             fetch_options.remote_callbacks(callbacks);
-            remote.fetch(&["main"], Some(&mut fetch_options), None)?;
+            remote.fetch(&[&self.branch], Some(&mut fetch_options), None)?;
 
-            let main_ref = repo.find_reference("refs/remotes/origin/main")?;
-            let main_commit = main_ref.peel_to_commit()?;
+            let branch_ref =
+                repo.find_reference(&format!("refs/remotes/origin/{}", self.branch))?;
+            let branch_commit = branch_ref.peel_to_commit()?;
 
             let mut checkout_builder = git2::build::CheckoutBuilder::new();
 
             repo.reset(
-                main_commit.as_object(),
+                branch_commit.as_object(),
                 git2::ResetType::Hard,
                 Some(&mut checkout_builder),
             )?;
@@ -74,19 +142,138 @@ impl GitRepo {
 
         Ok(repo)
     }
+
+    /// Pulls the repository like [GitRepo::sync] and lists the files
+    /// changed since commit `since`, or every file without one or when
+    /// `since` is gone. Blocks on git.
+    pub fn sync_with_diff(&self, since: Option<&str>) -> Result<SyncDiff, GitLoaderError> {
+        let repo = self.sync()?;
+        let commit = Self::head_commit(&repo)?;
+        let files = match since {
+            Some(since) => Self::changed_files(&repo, since)?,
+            None => None,
+        };
+        Ok(match files {
+            Some(files) => SyncDiff {
+                commit,
+                files,
+                full: false,
+            },
+            None => SyncDiff {
+                commit,
+                files: ChangedFiles {
+                    added: self.all_files()?,
+                    ..Default::default()
+                },
+                full: true,
+            },
+        })
+    }
+
+    /// Every file of the checkout, relative to its root, in order.
+    fn all_files(&self) -> Result<Vec<PathBuf>, GitLoaderError> {
+        let mut files = Vec::new();
+        let entries = WalkDir::new(&self.path)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|entry| entry.file_name() != ".git");
+        for entry in entries {
+            let entry = entry.map_err(std::io::Error::from)?;
+            if entry.file_type().is_file() {
+                let path = entry
+                    .path()
+                    .strip_prefix(&self.path)
+                    .unwrap_or(entry.path());
+                files.push(path.to_path_buf());
+            }
+        }
+        Ok(files)
+    }
+
+    /// Id of the commit checked out in `repo`.
+    pub fn head_commit(repo: &Repository) -> Result<String, GitLoaderError> {
+        Ok(repo.head()?.peel_to_commit()?.id().to_string())
+    }
+
+    /// Files that differ between commit `since` and the checked out commit,
+    /// or `None` when `since` is not in `repo`, such as after a force push
+    /// to a fresh clone.
+    pub fn changed_files(
+        repo: &Repository,
+        since: &str,
+    ) -> Result<Option<ChangedFiles>, GitLoaderError> {
+        let since = match git2::Oid::from_str(since).and_then(|oid| repo.find_commit(oid)) {
+            Ok(commit) => commit.tree()?,
+            Err(_) => return Ok(None),
+        };
+        let head = repo.head()?.peel_to_tree()?;
+        let diff = repo.diff_tree_to_tree(Some(&since), Some(&head), None)?;
+
+        let mut files = ChangedFiles::default();
+        for delta in diff.deltas() {
+            let old = delta.old_file().path().map(Path::to_path_buf);
+            let new = delta.new_file().path().map(Path::to_path_buf);
+            match delta.status() {
+                git2::Delta::Added | git2::Delta::Copied => files.added.extend(new),
+                git2::Delta::Deleted => files.deleted.extend(old),
+                git2::Delta::Renamed => {
+                    files.deleted.extend(old);
+                    files.added.extend(new);
+                }
+                _ => files.modified.extend(new),
+            }
+        }
+        Ok(Some(files))
+    }
 }
 
 pub struct GitLoader<'a> {
     path: &'a str,
     repo: GitRepo,
+    source_id: String,
+    topic_map: HashMap<String, String>,
+    cleaners: HashMap<String, ContentCleaner>,
 }
 
 impl<'a> GitLoader<'a> {
     pub fn new(url: String, path: &'a str) -> Result<Self, GitLoaderError> {
-        debug!(url = %url, path = path, "Creating new GitLoader");
-        let repo = GitRepo::new(url, PathBuf::from(path));
+        Self::from_repo(GitRepo::new(url, PathBuf::from(path)), path)
+    }
+
+    /// A loader of `repo`, e.g. one tracking another branch, cloned or
+    /// updated under `path`.
+    pub fn from_repo(repo: GitRepo, path: &'a str) -> Result<Self, GitLoaderError> {
+        debug!(url = %repo.url, branch = %repo.branch, path, "Creating new GitLoader");
         repo.sync()?;
-        Ok(Self { path, repo })
+        Ok(Self {
+            path,
+            repo,
+            source_id: SOURCE_ID.to_string(),
+            topic_map: HashMap::new(),
+            cleaners: HashMap::new(),
+        })
+    }
+
+    /// Stores documents under `source_id` instead of `github`, such as the
+    /// name of a [RepoConfig](crate::sources::RepoConfig), and records their
+    /// syncs under it.
+    pub fn with_source_id(mut self, source_id: &str) -> Self {
+        self.source_id = source_id.to_string();
+        self
+    }
+
+    /// Maps directory names to topic tags. Documents streamed from a matching
+    /// directory, at any depth, are tagged with its topic.
+    pub fn with_topic_map(mut self, topic_map: HashMap<String, String>) -> Self {
+        self.topic_map = topic_map;
+        self
+    }
+
+    /// Cleans documents with `extension` using `cleaner` before they are
+    /// embedded, instead of [ContentCleaner::for_extension].
+    pub fn with_cleaner(mut self, extension: &str, cleaner: ContentCleaner) -> Self {
+        self.cleaners.insert(extension.to_lowercase(), cleaner);
+        self
     }
 
     pub fn with_root(
@@ -95,6 +282,95 @@ impl<'a> GitLoader<'a> {
         FileLoader::with_dir(self.path)
     }
 
+    /// Streams every file under `directory` (recursively) as a [Document],
+    /// reading file contents lazily as the stream is polled.
+    pub fn document_stream(
+        &self,
+        directory: &str,
+    ) -> impl Stream<Item = std::io::Result<Document>> + Send + 'static {
+        repo_documents(
+            self.repo.path.clone(),
+            directory,
+            &self.source_id,
+            self.topic_map.clone(),
+            self.cleaners.clone(),
+        )
+    }
+
+    /// Pulls the repository and lists the files changed since the commit
+    /// `knowledge` last recorded for the loader's source, see
+    /// [KnowledgeBase::source_state], or every file on the first sync or
+    /// with `force_full`. Read the changed ones with [GitLoader::documents],
+    /// remove the documents of the deleted ones with
+    /// [KnowledgeBase::remove_documents_by_ids] and
+    /// [GitLoader::document_ids], then record the sync with
+    /// [GitLoader::mark_synced], so changes that failed to be ingested are
+    /// listed again.
+    pub async fn sync_with_diff<E: EmbeddingModel>(
+        &self,
+        knowledge: &KnowledgeBase<E>,
+        force_full: bool,
+    ) -> Result<SyncDiff, GitLoaderError> {
+        let since = match force_full {
+            true => None,
+            false => knowledge
+                .source_state(&self.source_id)
+                .await?
+                .and_then(|state| state.last_commit),
+        };
+        let repo = self.repo.clone();
+        tokio::task::spawn_blocking(move || repo.sync_with_diff(since.as_deref())).await?
+    }
+
+    /// Records the commit of `diff` as synced into `knowledge`, once its
+    /// files are ingested.
+    pub async fn mark_synced<E: EmbeddingModel>(
+        &self,
+        knowledge: &KnowledgeBase<E>,
+        diff: &SyncDiff,
+    ) -> Result<(), GitLoaderError> {
+        Ok(knowledge
+            .record_source_sync(&self.source_id, &diff.commit)
+            .await?)
+    }
+
+    /// Streams the files at `paths`, relative to the repository root, as
+    /// documents tagged and cleaned like [GitLoader::document_stream] does.
+    pub fn documents<'p>(
+        &self,
+        paths: impl IntoIterator<Item = &'p PathBuf>,
+    ) -> impl Stream<Item = std::io::Result<Document>> + Send + 'static {
+        repo_files(
+            self.repo.path.clone(),
+            paths.into_iter().cloned().collect(),
+            &self.source_id,
+            self.topic_map.clone(),
+            self.cleaners.clone(),
+        )
+    }
+
+    /// Ids the documents of the files at `paths`, relative to the repository
+    /// root, were stored under.
+    pub fn document_ids<'p>(&self, paths: impl IntoIterator<Item = &'p PathBuf>) -> Vec<String> {
+        paths
+            .into_iter()
+            .map(|path| self.repo.path.join(path).to_string_lossy().to_string())
+            .collect()
+    }
+
+    /// A [RefreshSource] that pulls the repository and re-reads `directory`,
+    /// for the `refresh_knowledge` tool.
+    pub fn refresh_source(&self, directory: &str) -> GitSource {
+        GitSource {
+            url: self.repo.url.clone(),
+            base_path: self.repo.base_path.clone(),
+            directory: directory.to_string(),
+            source_id: self.source_id.clone(),
+            topic_map: self.topic_map.clone(),
+            cleaners: self.cleaners.clone(),
+        }
+    }
+
     /// Creates a new [FileLoader] using a glob pattern to match files.
     ///
     /// # Example
@@ -141,3 +417,311 @@ impl<'a> GitLoader<'a> {
         FileLoader::with_dir(path)
     }
 }
+
+/// A directory of a repository, re-read after pulling the latest commit.
+pub struct GitSource {
+    url: String,
+    base_path: PathBuf,
+    directory: String,
+    source_id: String,
+    topic_map: HashMap<String, String>,
+    cleaners: HashMap<String, ContentCleaner>,
+}
+
+#[async_trait]
+impl RefreshSource for GitSource {
+    async fn sync_changes(&self) -> anyhow::Result<Vec<Document>> {
+        let repo = GitRepo::new(self.url.clone(), self.base_path.clone());
+        let root = repo.path.clone();
+        tokio::task::spawn_blocking(move || repo.sync().map(|_| ())).await??;
+
+        let documents = repo_documents(
+            root,
+            &self.directory,
+            &self.source_id,
+            self.topic_map.clone(),
+            self.cleaners.clone(),
+        )
+        .collect::<Vec<_>>()
+        .await;
+        Ok(documents.into_iter().collect::<Result<_, _>>()?)
+    }
+}
+
+fn repo_documents(
+    root: PathBuf,
+    directory: &str,
+    source_id: &str,
+    topic_map: HashMap<String, String>,
+    cleaners: HashMap<String, ContentCleaner>,
+) -> impl Stream<Item = std::io::Result<Document>> + Send + 'static {
+    let source_id = source_id.to_string();
+    super::files::stream_documents(root.join(directory), source_id.clone()).map(move |document| {
+        document
+            .map(|document| prepare_document(&root, &source_id, &topic_map, &cleaners, document))
+    })
+}
+
+/// Reads the files at `paths`, relative to `root`, as documents of
+/// `source_id`, tagged and cleaned like [GitLoader::document_stream] does.
+pub(crate) fn repo_files(
+    root: PathBuf,
+    paths: Vec<PathBuf>,
+    source_id: &str,
+    topic_map: HashMap<String, String>,
+    cleaners: HashMap<String, ContentCleaner>,
+) -> impl Stream<Item = std::io::Result<Document>> + Send + 'static {
+    let source_id = source_id.to_string();
+    let paths = paths
+        .into_iter()
+        .map(|path| Ok(root.join(path)))
+        .collect::<Vec<_>>();
+    super::files::read_documents(paths, source_id.clone()).map(move |document| {
+        document
+            .map(|document| prepare_document(&root, &source_id, &topic_map, &cleaners, document))
+    })
+}
+
+fn prepare_document(
+    root: &Path,
+    source_id: &str,
+    topic_map: &HashMap<String, String>,
+    cleaners: &HashMap<String, ContentCleaner>,
+    mut document: Document,
+) -> Document {
+    let path = Path::new(&document.id);
+    document.topics = path_topics(root, path, topic_map);
+    document.logical_id = Some(logical_id(root, path, source_id));
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    if matches!(extension.as_str(), "md" | "mdx" | "markdown") {
+        Outline::parse(&document.content).apply(&mut document);
+    }
+    match cleaners.get(&extension) {
+        Some(cleaner) => cleaner.apply(&mut document),
+        None => ContentCleaner::for_extension(&extension).apply(&mut document),
+    }
+    document
+}
+
+/// Repository-relative path prefixed with the source, so versions of a page
+/// share an id regardless of where the repository is checked out, and pages
+/// at the same path of two repositories don't.
+fn logical_id(root: &Path, path: &Path, source_id: &str) -> String {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    format!("{source_id}:{}", relative.to_string_lossy())
+}
+
+fn path_topics(root: &Path, path: &Path, topic_map: &HashMap<String, String>) -> Vec<String> {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    let mut topics = Vec::new();
+
+    if let Some(parent) = relative.parent() {
+        for component in parent.components() {
+            let topic = component
+                .as_os_str()
+                .to_str()
+                .and_then(|name| topic_map.get(name));
+            if let Some(topic) = topic {
+                if !topics.contains(topic) {
+                    topics.push(topic.clone());
+                }
+            }
+        }
+    }
+
+    topics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::FixtureRepo;
+
+    /// Logical ids of the documents under `directory`.
+    async fn logical_ids(loader: &GitLoader<'_>, directory: &str) -> Vec<String> {
+        loader
+            .document_stream(directory)
+            .map(|document| document.unwrap().logical_id.unwrap())
+            .collect()
+            .await
+    }
+
+    fn paths(files: &[&str]) -> Vec<PathBuf> {
+        files.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn test_checkout_path() {
+        let base = PathBuf::from("/data");
+        for (url, path) in [
+            (
+                "https://github.com/cartridge-gg/docs.git",
+                "/data/cartridge-gg/docs",
+            ),
+            (
+                "https://github.com/cartridge-gg/docs/",
+                "/data/cartridge-gg/docs",
+            ),
+            ("/tmp/remotes/docs", "/data/remotes/docs"),
+            ("file:///tmp/remotes/docs.git", "/data/remotes/docs"),
+            ("docs", "/data/local/docs"),
+        ] {
+            assert_eq!(
+                GitRepo::new(url.to_string(), base.clone()).path,
+                PathBuf::from(path),
+                "{url}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_loader_follows_the_branch() {
+        let dir = tempfile::tempdir().unwrap();
+        let fixture = FixtureRepo::init(&dir.path().join("remotes/docs"));
+        fixture.commit(&[("docs/intro.md", "# Intro")], &[]);
+        fixture.branch("next");
+        fixture.commit(&[("docs/preview.md", "# Preview")], &[]);
+        fixture.checkout("main");
+
+        let main = dir.path().join("main");
+        let loader = GitLoader::new(fixture.url(), main.to_str().unwrap()).unwrap();
+        assert_eq!(logical_ids(&loader, "docs").await, ["github:docs/intro.md"]);
+
+        let next = dir.path().join("next");
+        let repo = GitRepo::new(fixture.url(), next.clone()).with_branch("next");
+        let loader = GitLoader::from_repo(repo, next.to_str().unwrap()).unwrap();
+        assert_eq!(
+            logical_ids(&loader, "docs").await,
+            ["github:docs/intro.md", "github:docs/preview.md"]
+        );
+
+        // Documents are stored under the configured source
+        let loader = loader.with_source_id("docs");
+        assert_eq!(
+            logical_ids(&loader, "docs").await,
+            ["docs:docs/intro.md", "docs:docs/preview.md"]
+        );
+    }
+
+    #[test]
+    fn test_glob_selects_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let fixture = FixtureRepo::init(&dir.path().join("remotes/docs"));
+        fixture.commit(
+            &[
+                ("docs/intro.md", "# Intro"),
+                ("docs/vrf/fees.md", "# Fees"),
+                ("docs/vrf/diagram.svg", "<svg/>"),
+                ("README.md", "# Docs site"),
+            ],
+            &[],
+        );
+
+        let base = dir.path().join("clones");
+        let loader = GitLoader::new(fixture.url(), base.to_str().unwrap()).unwrap();
+        let root = loader.repo.path.clone();
+        let mut files = loader
+            .with_glob("docs/**/*.md")
+            .unwrap()
+            .ignore_errors()
+            .into_iter()
+            .map(|path| path.strip_prefix(&root).unwrap().to_path_buf())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, paths(&["docs/intro.md", "docs/vrf/fees.md"]));
+    }
+
+    #[test]
+    fn test_changed_files_between_commits() {
+        let dir = tempfile::tempdir().unwrap();
+        let fixture = FixtureRepo::init(&dir.path().join("remotes/docs"));
+        fixture.commit(
+            &[
+                ("a.md", "# A"),
+                ("c.md", "# C"),
+                ("d.md", "# D"),
+                ("unchanged.md", "# Unchanged"),
+            ],
+            &[],
+        );
+        let git = GitRepo::new(fixture.url(), dir.path().join("clones"));
+        let since = GitRepo::head_commit(&git.sync().unwrap()).unwrap();
+
+        fixture.commit(&[("a.md", "# A, edited"), ("b.md", "# B")], &["c.md"]);
+        // A rename is the old path deleted and the new one added
+        fixture.rename("d.md", "e.md");
+        let repo = git.sync().unwrap();
+        let files = GitRepo::changed_files(&repo, &since).unwrap().unwrap();
+        assert_eq!(
+            files,
+            ChangedFiles {
+                added: paths(&["b.md", "e.md"]),
+                modified: paths(&["a.md"]),
+                deleted: paths(&["c.md", "d.md"]),
+            }
+        );
+
+        // Nothing changed since the checked out commit
+        let head = GitRepo::head_commit(&repo).unwrap();
+        assert_eq!(
+            GitRepo::changed_files(&repo, &head).unwrap(),
+            Some(ChangedFiles::default())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync_with_diff_since_the_last_synced_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let fixture = FixtureRepo::init(&dir.path().join("remotes/docs"));
+        fixture.commit(&[("a.md", "# A"), ("c.md", "# C")], &[]);
+        let knowledge = crate::test_utils::knowledge_base().await;
+        let base = dir.path().join("clones");
+        let loader = GitLoader::new(fixture.url(), base.to_str().unwrap()).unwrap();
+
+        // Everything is added on the first sync
+        let first = loader.sync_with_diff(&knowledge, false).await.unwrap();
+        assert!(first.full);
+        assert_eq!(first.files.added, paths(&["a.md", "c.md"]));
+        loader.mark_synced(&knowledge, &first).await.unwrap();
+        let state = knowledge.source_state("github").await.unwrap().unwrap();
+        assert_eq!(state.last_commit, Some(first.commit));
+
+        fixture.commit(&[("a.md", "# A, edited"), ("b.md", "# B")], &["c.md"]);
+        let diff = loader.sync_with_diff(&knowledge, false).await.unwrap();
+        assert!(!diff.full);
+        assert_eq!(diff.files.changed().count(), 2);
+        assert_eq!(diff.files.deleted, paths(&["c.md"]));
+        // Not marked synced, so listed again
+        assert_eq!(
+            loader.sync_with_diff(&knowledge, false).await.unwrap(),
+            diff
+        );
+
+        loader.mark_synced(&knowledge, &diff).await.unwrap();
+        let synced = loader.sync_with_diff(&knowledge, false).await.unwrap();
+        assert_eq!(synced.files, ChangedFiles::default());
+
+        let forced = loader.sync_with_diff(&knowledge, true).await.unwrap();
+        assert!(forced.full);
+        assert_eq!(forced.files.added, paths(&["a.md", "b.md"]));
+    }
+
+    #[test]
+    fn test_rewritten_history_needs_a_full_sync() {
+        let dir = tempfile::tempdir().unwrap();
+        let fixture = FixtureRepo::init(&dir.path().join("remotes/docs"));
+        let first = fixture.commit(&[("intro.md", "# Intro")], &[]);
+        let amended = fixture.amend(&[("intro.md", "# Introduction")]);
+        assert_ne!(first, amended);
+
+        // A fresh clone never saw the replaced commit
+        let git = GitRepo::new(fixture.url(), dir.path().join("clones"));
+        let repo = git.sync().unwrap();
+        assert_eq!(GitRepo::head_commit(&repo).unwrap(), amended);
+        assert_eq!(GitRepo::changed_files(&repo, &first).unwrap(), None);
+    }
+}
@@ -1 +1,3 @@
+pub mod files;
+#[cfg(feature = "git-loader")]
 pub mod github;
@@ -0,0 +1,303 @@
+//! Time zones and date formats the bot writes times in, such as the current
+//! time in prompts, so a user in Berlin is not told it is 3 AM at noon.
+//!
+//! [FormatPreferences] are taken per setting from, first found:
+//!
+//! 1. the channel's settings, set with `/timezone` and `/date-format`,
+//! 2. the guild's settings, set with the same commands and `--guild`,
+//! 3. in direct messages with `infer_from_facts`, the user's `timezone`
+//!    fact, e.g. from "I'm in CET",
+//! 4. the `[locale]` section, UTC and ISO dates by default.
+//!
+//! Times are converted with chrono-tz, so DST transitions fall where the
+//! zone's rules put them, and carry the zone's abbreviation and name.
+//!
+//! ```toml
+//! [locale]
+//! timezone = "Europe/Berlin"
+//! date_format = "dmy"
+//! infer_from_facts = true
+//! ```
+
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use serde::Deserialize;
+
+/// Channel and guild setting with the IANA name of the time zone.
+pub const TIMEZONE_SETTING: &str = "timezone";
+
+/// Channel and guild setting with a [DateFormat].
+pub const DATE_FORMAT_SETTING: &str = "date_format";
+
+/// User fact naming the user's time zone.
+pub const TIMEZONE_FACT: &str = "timezone";
+
+/// How dates are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DateFormat {
+    /// 2025-03-30, with 24-hour times.
+    #[default]
+    Iso,
+    /// 03/30/2025, with 12-hour times.
+    Us,
+    /// 30/03/2025, with 24-hour times.
+    Dmy,
+}
+
+impl DateFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DateFormat::Iso => "iso",
+            DateFormat::Us => "us",
+            DateFormat::Dmy => "dmy",
+        }
+    }
+
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "iso" => Some(DateFormat::Iso),
+            "us" => Some(DateFormat::Us),
+            "dmy" => Some(DateFormat::Dmy),
+            _ => None,
+        }
+    }
+
+    fn date(&self) -> &'static str {
+        match self {
+            DateFormat::Iso => "%Y-%m-%d",
+            DateFormat::Us => "%m/%d/%Y",
+            DateFormat::Dmy => "%d/%m/%Y",
+        }
+    }
+
+    fn time(&self) -> &'static str {
+        match self {
+            DateFormat::Us => "%I:%M:%S %p",
+            DateFormat::Iso | DateFormat::Dmy => "%H:%M:%S",
+        }
+    }
+}
+
+/// `[locale]` settings of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LocaleConfig {
+    /// Time zone where neither the channel nor its guild sets one.
+    pub timezone: String,
+    pub date_format: DateFormat,
+    /// Whether direct messages use the time zone a user told the bot.
+    pub infer_from_facts: bool,
+}
+
+impl Default for LocaleConfig {
+    fn default() -> Self {
+        Self {
+            timezone: "UTC".to_string(),
+            date_format: DateFormat::Iso,
+            infer_from_facts: true,
+        }
+    }
+}
+
+impl LocaleConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.timezone.parse::<Tz>().is_err() {
+            return Err(format!(
+                "locale.timezone: unknown time zone {:?}",
+                self.timezone
+            ));
+        }
+        Ok(())
+    }
+
+    /// Preferences where nothing more specific is set.
+    pub fn preferences(&self) -> FormatPreferences {
+        FormatPreferences {
+            timezone: self.timezone.parse().unwrap_or(Tz::UTC),
+            date_format: self.date_format,
+        }
+    }
+}
+
+/// Settings of one level of [FormatPreferences::resolve], as stored.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LocaleSettings {
+    pub timezone: Option<String>,
+    pub date_format: Option<String>,
+}
+
+/// How times are written for a channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatPreferences {
+    pub timezone: Tz,
+    pub date_format: DateFormat,
+}
+
+impl Default for FormatPreferences {
+    fn default() -> Self {
+        LocaleConfig::default().preferences()
+    }
+}
+
+impl FormatPreferences {
+    /// `default` with each setting replaced by the first of `levels`,
+    /// most specific first, that sets a valid value for it.
+    pub fn resolve(levels: &[LocaleSettings], default: FormatPreferences) -> Self {
+        Self {
+            timezone: levels
+                .iter()
+                .find_map(|level| level.timezone.as_deref().and_then(parse_timezone))
+                .unwrap_or(default.timezone),
+            date_format: levels
+                .iter()
+                .find_map(|level| level.date_format.as_deref().and_then(DateFormat::from_str))
+                .unwrap_or(default.date_format),
+        }
+    }
+
+    /// `at` as a date in the preferred zone and format.
+    pub fn date(&self, at: DateTime<Utc>) -> String {
+        at.with_timezone(&self.timezone)
+            .format(self.date_format.date())
+            .to_string()
+    }
+
+    /// `day` in the preferred format. Days such as a digest's are UTC days,
+    /// so they are not converted.
+    pub fn day(&self, day: NaiveDate) -> String {
+        day.format(self.date_format.date()).to_string()
+    }
+
+    /// `at` as a time and date in the preferred zone and format, labelled
+    /// with the zone, e.g. `14:30:00, 2025-03-30 CEST (Europe/Berlin)`.
+    pub fn date_time(&self, at: DateTime<Utc>) -> String {
+        let local = at.with_timezone(&self.timezone);
+        let abbreviation = local.format("%Z").to_string();
+        let label = match self.timezone.name() {
+            name if name == abbreviation => abbreviation,
+            name => format!("{abbreviation} ({name})"),
+        };
+        format!(
+            "{}, {} {label}",
+            local.format(self.date_format.time()),
+            local.format(self.date_format.date())
+        )
+    }
+
+    /// The line telling the model what time it is.
+    pub fn current_time(&self, now: DateTime<Utc>) -> String {
+        format!("Current time: {}", self.date_time(now))
+    }
+}
+
+/// The time zone named in `text`: an IANA name such as `Europe/Berlin`, an
+/// abbreviation chrono-tz knows such as `CET`, or an offset such as
+/// `UTC+2`, alone or in a sentence like "I'm in CET".
+pub fn parse_timezone(text: &str) -> Option<Tz> {
+    let text = text.trim();
+    if let Ok(tz) = text.parse() {
+        return Some(tz);
+    }
+    text.split_whitespace()
+        .map(|word| word.trim_matches(|c: char| !c.is_alphanumeric() && !"/_+-".contains(c)))
+        .find_map(|word| {
+            word.parse()
+                .ok()
+                .or_else(|| word.to_uppercase().parse().ok())
+                .or_else(|| offset_zone(word))
+        })
+}
+
+/// `UTC+2` or `GMT-5` as the fixed-offset `Etc/GMT` zone, whose names count
+/// the other way round.
+fn offset_zone(word: &str) -> Option<Tz> {
+    let upper = word.to_uppercase();
+    let offset = upper
+        .strip_prefix("UTC")
+        .or_else(|| upper.strip_prefix("GMT"))?;
+    let (sign, hours) = match offset.split_at_checked(1)? {
+        ("+", hours) => ("-", hours),
+        ("-", hours) => ("+", hours),
+        _ => return None,
+    };
+    let hours: u8 = hours.parse().ok().filter(|hours| *hours <= 14)?;
+    match hours {
+        0 => Some(Tz::UTC),
+        hours => format!("Etc/GMT{sign}{hours}").parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn test_dst_boundary() {
+        let berlin = FormatPreferences {
+            timezone: chrono_tz::Europe::Berlin,
+            date_format: DateFormat::Iso,
+        };
+        // Clocks in Berlin jump from 02:00 CET to 03:00 CEST at 01:00 UTC
+        let before = Utc.with_ymd_and_hms(2025, 3, 30, 0, 59, 0).unwrap();
+        let after = Utc.with_ymd_and_hms(2025, 3, 30, 1, 0, 0).unwrap();
+        assert_eq!(
+            berlin.current_time(before),
+            "Current time: 01:59:00, 2025-03-30 CET (Europe/Berlin)"
+        );
+        assert_eq!(
+            berlin.current_time(after),
+            "Current time: 03:00:00, 2025-03-30 CEST (Europe/Berlin)"
+        );
+
+        let us = FormatPreferences {
+            timezone: chrono_tz::America::New_York,
+            date_format: DateFormat::Us,
+        };
+        assert_eq!(
+            us.date_time(after),
+            "09:00:00 PM, 03/29/2025 EDT (America/New_York)"
+        );
+        assert_eq!(
+            FormatPreferences::default().date_time(after),
+            "01:00:00, 2025-03-30 UTC"
+        );
+    }
+
+    #[test]
+    fn test_resolve_precedence() {
+        let settings = |timezone: Option<&str>, date_format: Option<&str>| LocaleSettings {
+            timezone: timezone.map(str::to_string),
+            date_format: date_format.map(str::to_string),
+        };
+        let channel = settings(Some("Asia/Tokyo"), None);
+        let guild = settings(Some("Europe/Paris"), Some("dmy"));
+        let default = FormatPreferences::default();
+
+        let resolved = FormatPreferences::resolve(&[channel.clone(), guild.clone()], default);
+        assert_eq!(resolved.timezone, chrono_tz::Asia::Tokyo);
+        assert_eq!(resolved.date_format, DateFormat::Dmy);
+
+        let resolved = FormatPreferences::resolve(&[LocaleSettings::default(), guild], default);
+        assert_eq!(resolved.timezone, chrono_tz::Europe::Paris);
+
+        // Invalid values are skipped
+        let resolved = FormatPreferences::resolve(&[settings(Some("Mars/Olympus"), None)], default);
+        assert_eq!(resolved, default);
+    }
+
+    #[test]
+    fn test_parse_timezone() {
+        assert_eq!(
+            parse_timezone("Europe/Berlin"),
+            Some(chrono_tz::Europe::Berlin)
+        );
+        assert_eq!(parse_timezone("I'm in CET."), Some(chrono_tz::CET));
+        assert_eq!(parse_timezone("i live in est"), Some(chrono_tz::EST));
+        assert_eq!(parse_timezone("UTC+2"), Some(chrono_tz::Etc::GMTMinus2));
+        assert_eq!(parse_timezone("GMT-5"), Some(chrono_tz::Etc::GMTPlus5));
+        assert_eq!(parse_timezone("somewhere nice"), None);
+    }
+}
@@ -0,0 +1,307 @@
+use std::path::PathBuf;
+
+use serde::Deserialize;
+use thiserror::Error;
+use tracing::{error, Subscriber};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::{
+    fmt::{
+        writer::{BoxMakeWriter, MakeWriterExt},
+        MakeWriter,
+    },
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+    EnvFilter,
+};
+
+use crate::reporting::{ErrorLayer, ErrorReporter};
+
+/// Target of audit trail events, such as rendered prompts and the decisions
+/// made from them. Enable with `asuka::audit=info`.
+pub const AUDIT_TARGET: &str = "asuka::audit";
+
+const DEFAULT_FILTER: &str =
+    "debug,asuka=debug,rustls=off,hyper=off,h2=off,serenity=off,reqwest=off";
+
+#[derive(Error, Debug)]
+pub enum LoggingError {
+    #[error("Logging is already initialized")]
+    AlreadyInitialized,
+    #[error("Invalid log filter: {0}")]
+    InvalidFilter(String),
+    #[error("Unknown log format: {0}")]
+    UnknownFormat(String),
+    #[error("Failed to open log file: {0}")]
+    File(String),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Full,
+    Pretty,
+    Compact,
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = LoggingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "full" => Ok(Self::Full),
+            "pretty" => Ok(Self::Pretty),
+            "compact" => Ok(Self::Compact),
+            "json" => Ok(Self::Json),
+            _ => Err(LoggingError::UnknownFormat(s.to_string())),
+        }
+    }
+}
+
+/// Daily rotated log files, written alongside stdout.
+#[derive(Clone, Debug, Deserialize)]
+pub struct LogFile {
+    pub directory: PathBuf,
+    #[serde(default = "default_file_prefix")]
+    pub prefix: String,
+}
+
+fn default_file_prefix() -> String {
+    "asuka.log".to_string()
+}
+
+/// Logging setup, usually read with [LoggingConfig::from_env] or from a
+/// `[logging]` table.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    pub format: LogFormat,
+    /// Env-filter directives, used when `RUST_LOG` is not set.
+    pub filter: String,
+    pub file: Option<LogFile>,
+    /// Log panics as errors before the default hook runs.
+    pub capture_panics: bool,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::default(),
+            filter: DEFAULT_FILTER.to_string(),
+            file: None,
+            capture_panics: true,
+        }
+    }
+}
+
+impl LoggingConfig {
+    /// Reads `ASUKA_LOG_FORMAT`, `ASUKA_LOG_FILTER`, `ASUKA_LOG_DIR` and
+    /// `ASUKA_LOG_FILE_PREFIX`, falling back to the defaults.
+    pub fn from_env() -> Result<Self, LoggingError> {
+        Self::from_lookup(|key| std::env::var(key).ok())
+    }
+
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self, LoggingError> {
+        let mut config = Self::default();
+
+        if let Some(format) = lookup("ASUKA_LOG_FORMAT") {
+            config.format = format.parse()?;
+        }
+        if let Some(filter) = lookup("ASUKA_LOG_FILTER") {
+            config.filter = filter;
+        }
+        if let Some(directory) = lookup("ASUKA_LOG_DIR") {
+            config.file = Some(LogFile {
+                directory: directory.into(),
+                prefix: lookup("ASUKA_LOG_FILE_PREFIX").unwrap_or_else(default_file_prefix),
+            });
+        }
+
+        Ok(config)
+    }
+}
+
+/// Installs the global subscriber. Subscribers can only be set once per
+/// process, so later calls fail with [LoggingError::AlreadyInitialized].
+pub fn init_logging(config: LoggingConfig) -> Result<(), LoggingError> {
+    init_logging_with_reporter(config, &ErrorReporter::new())
+}
+
+/// Like [init_logging], also forwarding error events to `reporter` once it
+/// is started.
+pub fn init_logging_with_reporter(
+    config: LoggingConfig,
+    reporter: &ErrorReporter,
+) -> Result<(), LoggingError> {
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => EnvFilter::try_new(&config.filter)
+            .map_err(|e| LoggingError::InvalidFilter(e.to_string()))?,
+    };
+
+    let subscriber = match &config.file {
+        Some(file) => {
+            let appender = RollingFileAppender::builder()
+                .rotation(Rotation::DAILY)
+                .filename_prefix(&file.prefix)
+                .build(&file.directory)
+                .map_err(|e| LoggingError::File(e.to_string()))?;
+            let writer = BoxMakeWriter::new(std::io::stdout.and(appender));
+            build_subscriber(config.format, filter, writer, false, reporter.layer())
+        }
+        None => build_subscriber(
+            config.format,
+            filter,
+            std::io::stdout,
+            true,
+            reporter.layer(),
+        ),
+    };
+
+    subscriber
+        .try_init()
+        .map_err(|_| LoggingError::AlreadyInitialized)?;
+
+    if config.capture_panics {
+        install_panic_hook();
+    }
+
+    Ok(())
+}
+
+fn build_subscriber<W>(
+    format: LogFormat,
+    filter: EnvFilter,
+    writer: W,
+    ansi: bool,
+    errors: ErrorLayer,
+) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(ansi);
+
+    match format {
+        LogFormat::Full => Box::new(builder.finish().with(errors)),
+        LogFormat::Pretty => Box::new(builder.pretty().finish().with(errors)),
+        LogFormat::Compact => Box::new(builder.compact().finish().with(errors)),
+        LogFormat::Json => Box::new(builder.json().finish().with(errors)),
+    }
+}
+
+/// Logs panics through tracing, then defers to the previous hook.
+fn install_panic_hook() {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let payload = info
+            .payload()
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| info.payload().downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("<non-string payload>");
+        let location = info.location().map(|location| location.to_string());
+
+        error!(target: "panic", location, "Panicked: {}", payload);
+        previous(info);
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl Capture {
+        fn output(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl io::Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Capture {
+        type Writer = Self;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    fn capture(format: LogFormat, f: impl FnOnce()) -> String {
+        let writer = Capture::default();
+        let subscriber = build_subscriber(
+            format,
+            EnvFilter::new("debug"),
+            writer.clone(),
+            false,
+            ErrorReporter::new().layer(),
+        );
+        tracing::subscriber::with_default(subscriber, f);
+        writer.output()
+    }
+
+    #[test]
+    fn test_formatter_selection() {
+        let json = capture(LogFormat::Json, || {
+            tracing::info!(channel = "general", "hello")
+        });
+        let line: serde_json::Value = serde_json::from_str(json.trim()).unwrap();
+        assert_eq!(line["fields"]["message"], "hello");
+        assert_eq!(line["fields"]["channel"], "general");
+
+        let compact = capture(LogFormat::Compact, || {
+            tracing::info!(channel = "general", "hello")
+        });
+        assert!(serde_json::from_str::<serde_json::Value>(compact.trim()).is_err());
+        assert!(compact.contains("hello") && compact.contains("channel=\"general\""));
+    }
+
+    #[test]
+    fn test_panics_are_logged() {
+        let output = capture(LogFormat::Compact, || {
+            install_panic_hook();
+            let result = std::panic::catch_unwind(|| panic!("boom"));
+            let _ = std::panic::take_hook();
+            assert!(result.is_err());
+        });
+
+        assert!(output.contains("Panicked: boom"));
+        assert!(output.contains("logging.rs"));
+    }
+
+    #[test]
+    fn test_config_from_env() {
+        let config = LoggingConfig::from_lookup(|key| match key {
+            "ASUKA_LOG_FORMAT" => Some("JSON".to_string()),
+            "ASUKA_LOG_DIR" => Some("/var/log/asuka".to_string()),
+            _ => None,
+        })
+        .unwrap();
+        assert_eq!(config.format, LogFormat::Json);
+        assert_eq!(config.filter, DEFAULT_FILTER);
+        assert_eq!(config.file.unwrap().prefix, "asuka.log");
+
+        let invalid = LoggingConfig::from_lookup(|key| {
+            (key == "ASUKA_LOG_FORMAT").then(|| "yaml".to_string())
+        });
+        assert!(matches!(invalid, Err(LoggingError::UnknownFormat(_))));
+    }
+}
@@ -0,0 +1,725 @@
+//! Normalization of generated markdown to the subset each platform renders.
+//! Models answer with tables, nested lists, headings and LaTeX, which show
+//! up as walls of pipes and stray symbols where they aren't supported.
+//!
+//! Each source gets a [MarkdownProfile]:
+//!
+//! - [Discord](MarkdownProfile::Discord) keeps everything but tables wider
+//!   than [MarkdownConfig::table_width], which become bullet lists.
+//! - [Telegram](MarkdownProfile::Telegram) turns tables into aligned text in
+//!   a code block, or bullets when too wide, headings into bold lines, and
+//!   flattens lists nested deeper than two levels.
+//! - [Plain](MarkdownProfile::Plain), for tweets and casts, turns tables into
+//!   bullets, headings into plain lines and flattens every nested list.
+//!
+//! Telegram and plain profiles also replace images by their alt text, drop
+//! HTML tags and write LaTeX as inline code. Code blocks are never touched,
+//! and normalizing a normalized reply leaves it as is.
+//!
+//! Only the constructs being rewritten are changed, the rest of the reply is
+//! copied as generated. [MarkdownGuardrails](crate::hooks::MarkdownGuardrails)
+//! runs this on replies.
+//!
+//! ```toml
+//! [markdown]
+//! table_width = 40
+//!
+//! [markdown.profiles]
+//! discord = "off"
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Range,
+    sync::OnceLock,
+};
+
+use pulldown_cmark::{Event, Options, Parser, Tag, TagEnd};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::knowledge::Source;
+
+/// Markdown a platform renders, see the [module docs](self).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MarkdownProfile {
+    Discord,
+    Telegram,
+    Plain,
+    /// Replies are sent as generated.
+    Off,
+}
+
+impl MarkdownProfile {
+    /// Profile of replies to `source` unless configured otherwise. Sources
+    /// without a client here are left alone.
+    pub fn default_for(source: &Source) -> Self {
+        match source {
+            Source::Discord => MarkdownProfile::Discord,
+            Source::Telegram => MarkdownProfile::Telegram,
+            Source::Twitter | Source::X | Source::Farcaster => MarkdownProfile::Plain,
+            Source::Github | Source::Other(_) => MarkdownProfile::Off,
+        }
+    }
+
+    fn rules(self, table_width: usize) -> Option<Rules> {
+        let rules = match self {
+            MarkdownProfile::Discord => Rules {
+                tables: Tables::Keep,
+                headings: Headings::Keep,
+                list_depth: None,
+                strip: false,
+                table_width,
+            },
+            MarkdownProfile::Telegram => Rules {
+                tables: Tables::Aligned,
+                headings: Headings::Bold,
+                list_depth: Some(2),
+                strip: true,
+                table_width,
+            },
+            MarkdownProfile::Plain => Rules {
+                tables: Tables::Bullets,
+                headings: Headings::Plain,
+                list_depth: Some(1),
+                strip: true,
+                table_width,
+            },
+            MarkdownProfile::Off => return None,
+        };
+        Some(rules)
+    }
+}
+
+/// `[markdown]` settings of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MarkdownConfig {
+    /// Whether replies are normalized at all.
+    pub enabled: bool,
+    /// Widest table, in characters per line, sent as a table. Wider ones
+    /// become bullet lists.
+    pub table_width: usize,
+    /// Profiles by source name, overriding [MarkdownProfile::default_for].
+    pub profiles: HashMap<String, MarkdownProfile>,
+}
+
+impl Default for MarkdownConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            table_width: 48,
+            profiles: HashMap::new(),
+        }
+    }
+}
+
+impl MarkdownConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.table_width == 0 {
+            return Err("markdown.table_width must be positive".to_string());
+        }
+        for source in self.profiles.keys() {
+            if Source::from_str(source).is_none() {
+                return Err(format!("markdown.profiles: unknown source {source:?}"));
+            }
+        }
+        Ok(())
+    }
+
+    /// The profile of replies to `source`.
+    pub fn profile(&self, source: &Source) -> MarkdownProfile {
+        if !self.enabled {
+            return MarkdownProfile::Off;
+        }
+        self.profiles
+            .get(source.as_str())
+            .copied()
+            .unwrap_or_else(|| MarkdownProfile::default_for(source))
+    }
+
+    /// `text` normalized for `source`.
+    pub fn normalize(&self, text: &str, source: &Source) -> String {
+        normalize(text, self.profile(source), self.table_width)
+    }
+}
+
+/// How tables are rewritten.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Tables {
+    /// Kept when narrow enough, bullets otherwise.
+    Keep,
+    /// Aligned text in a code block when narrow enough, bullets otherwise.
+    Aligned,
+    Bullets,
+}
+
+/// How headings are rewritten.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Headings {
+    Keep,
+    Bold,
+    Plain,
+}
+
+struct Rules {
+    tables: Tables,
+    headings: Headings,
+    /// Deepest list nesting kept, unlimited when `None`.
+    list_depth: Option<usize>,
+    /// Whether images, HTML and LaTeX are rewritten.
+    strip: bool,
+    table_width: usize,
+}
+
+/// A replacement of part of the reply.
+struct Edit {
+    range: Range<usize>,
+    text: String,
+    /// Whether the replaced text is a block, whose lines after the first are
+    /// indented like the line it starts on.
+    block: bool,
+}
+
+impl Edit {
+    fn inline(range: Range<usize>, text: impl Into<String>) -> Self {
+        Self {
+            range,
+            text: text.into(),
+            block: false,
+        }
+    }
+
+    fn block(range: Range<usize>, text: impl Into<String>) -> Self {
+        Self {
+            range,
+            text: text.into(),
+            block: true,
+        }
+    }
+}
+
+fn options() -> Options {
+    Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_MATH
+}
+
+/// `text` rewritten to what `profile` renders, see the [module docs](self).
+pub fn normalize(text: &str, profile: MarkdownProfile, table_width: usize) -> String {
+    let Some(rules) = profile.rules(table_width) else {
+        return text.to_string();
+    };
+    let events: Vec<_> = Parser::new_ext(text, options())
+        .into_offset_iter()
+        .collect();
+
+    let mut edits = Vec::new();
+    // Line starts of list items nested too deep, with the columns the lines
+    // move left by.
+    let mut dedents = BTreeMap::new();
+    // Marker columns of the list items being parsed, outermost first.
+    let mut items: Vec<usize> = Vec::new();
+
+    let mut i = 0;
+    while i < events.len() {
+        let (event, range) = &events[i];
+        match event {
+            Event::Start(Tag::Item) => {
+                let column = marker_column(text, range.start);
+                items.push(column);
+                if let Some(max) = rules.list_depth.filter(|max| items.len() > *max) {
+                    let shift = column.saturating_sub(items[max - 1]);
+                    for line in line_starts(text, range.clone()) {
+                        dedents.insert(line, shift);
+                    }
+                }
+            }
+            Event::End(TagEnd::Item) => {
+                items.pop();
+            }
+            Event::Start(Tag::Heading { .. }) if rules.headings != Headings::Keep => {
+                let marker = if rules.headings == Headings::Bold {
+                    "**"
+                } else {
+                    ""
+                };
+                match inner(&events, i) {
+                    Some(content) if range.start <= content.start && content.end <= range.end => {
+                        let rest = &text[content.end..range.end];
+                        let newlines = &rest[rest.trim_end().len()..];
+                        edits.push(Edit::inline(range.start..content.start, marker));
+                        edits.push(Edit::inline(
+                            content.end..range.end,
+                            format!("{marker}{newlines}"),
+                        ));
+                    }
+                    _ => edits.push(Edit::block(range.clone(), "")),
+                }
+            }
+            Event::Start(Tag::Image { .. }) if rules.strip => {
+                let alt = inner(&events, i).map_or("", |alt| &text[alt]);
+                edits.push(Edit::inline(range.clone(), alt));
+                i = end_of(&events, i);
+            }
+            Event::InlineHtml(_) if rules.strip => edits.push(Edit::inline(range.clone(), "")),
+            Event::Start(Tag::HtmlBlock) if rules.strip => {
+                edits.push(Edit::block(range.clone(), strip_tags(&text[range.clone()])));
+                i = end_of(&events, i);
+            }
+            Event::InlineMath(math) | Event::DisplayMath(math) if rules.strip => {
+                let math = math.split_whitespace().collect::<Vec<_>>().join(" ");
+                edits.push(Edit::inline(range.clone(), code_span(&math)));
+            }
+            Event::Start(Tag::Table(_)) => {
+                let end = end_of(&events, i);
+                let table = Table::collect(text, &events[i..=end]);
+                let fits = |text: &str| width(text) <= rules.table_width;
+                let aligned = (rules.tables == Tables::Aligned)
+                    .then(|| table.aligned())
+                    .filter(|aligned| fits(aligned));
+                let replacement = match rules.tables {
+                    Tables::Keep if fits(&text[range.clone()]) => None,
+                    _ => Some(aligned.unwrap_or_else(|| table.bullets())),
+                };
+                if let Some(replacement) = replacement {
+                    edits.push(Edit::block(range.clone(), replacement));
+                }
+                i = end;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    apply(text, edits, &dedents)
+}
+
+/// Index of the event ending the tag started at `start`.
+fn end_of(events: &[(Event, Range<usize>)], start: usize) -> usize {
+    let mut depth = 0;
+    for (i, (event, _)) in events.iter().enumerate().skip(start) {
+        match event {
+            Event::Start(_) => depth += 1,
+            Event::End(_) => depth -= 1,
+            _ => {}
+        }
+        if depth == 0 {
+            return i;
+        }
+    }
+    events.len() - 1
+}
+
+/// Source range of the content of the tag started at `start`, `None` when
+/// it is empty.
+fn inner(events: &[(Event, Range<usize>)], start: usize) -> Option<Range<usize>> {
+    let end = end_of(events, start);
+    (end > start + 1)
+        .then(|| events[start + 1].1.start..events[end - 1].1.end)
+        .filter(|range| range.start <= range.end)
+}
+
+/// Column of the list marker of the item starting at `offset`.
+fn marker_column(text: &str, offset: usize) -> usize {
+    let line = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let rest = &text[offset..];
+    offset + rest.len() - rest.trim_start_matches([' ', '\t']).len() - line
+}
+
+/// Starts of the lines `range` covers.
+fn line_starts(text: &str, range: Range<usize>) -> Vec<usize> {
+    let first = text[..range.start].rfind('\n').map_or(0, |i| i + 1);
+    let mut lines = vec![first];
+    lines.extend(
+        text[range.clone()]
+            .match_indices('\n')
+            .map(|(i, _)| range.start + i + 1)
+            .filter(|start| *start < range.end),
+    );
+    lines
+}
+
+/// Characters of the longest line.
+fn width(text: &str) -> usize {
+    text.lines()
+        .map(|line| line.chars().count())
+        .max()
+        .unwrap_or(0)
+}
+
+/// Text of an HTML block without its tags, line by line.
+fn strip_tags(html: &str) -> String {
+    static TAG: OnceLock<Regex> = OnceLock::new();
+    let tag = TAG.get_or_init(|| Regex::new(r"<[^>]*>").unwrap());
+    tag.replace_all(html, "")
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn code_span(code: &str) -> String {
+    match code {
+        "" => String::new(),
+        code if code.contains('`') => format!("`` {code} ``"),
+        code => format!("`{code}`"),
+    }
+}
+
+/// `text` with `edits` applied and the lines in `dedents` moved left.
+/// Edits overlapping an earlier one are dropped.
+fn apply(text: &str, mut edits: Vec<Edit>, dedents: &BTreeMap<usize, usize>) -> String {
+    edits.sort_by_key(|edit| edit.range.start);
+
+    let mut out = String::with_capacity(text.len());
+    let mut pos = 0;
+    for edit in edits {
+        if edit.range.start < pos {
+            continue;
+        }
+        copy(&mut out, text, pos..edit.range.start, dedents);
+        if edit.block {
+            let line = &out[out.rfind('\n').map_or(0, |i| i + 1)..];
+            let indent = if line.chars().all(|c| c.is_whitespace() || c == '>') {
+                line.to_string()
+            } else {
+                " ".repeat(line.chars().count())
+            };
+            let replaced = &text[edit.range.clone()];
+            out.push_str(
+                &edit
+                    .text
+                    .lines()
+                    .collect::<Vec<_>>()
+                    .join(&format!("\n{indent}")),
+            );
+            out.push_str(&replaced[replaced.trim_end().len()..]);
+        } else {
+            out.push_str(&edit.text);
+        }
+        pos = edit.range.end;
+    }
+    copy(&mut out, text, pos..text.len(), dedents);
+    out
+}
+
+/// Copies `range` of `text`, removing the leading spaces of lines in
+/// `dedents`.
+fn copy(out: &mut String, text: &str, range: Range<usize>, dedents: &BTreeMap<usize, usize>) {
+    let mut pos = range.start;
+    for (&line, &shift) in dedents.range(range.clone()) {
+        if line < pos {
+            continue;
+        }
+        out.push_str(&text[pos..line]);
+        let spaces = text[line..range.end]
+            .chars()
+            .take_while(|c| *c == ' ' || *c == '\t')
+            .take(shift)
+            .count();
+        pos = line + spaces;
+    }
+    out.push_str(&text[pos..range.end]);
+}
+
+/// A table cell, as plain text and as written.
+struct Cell {
+    text: String,
+    source: String,
+}
+
+/// Rows of a table, the header first.
+struct Table(Vec<Vec<Cell>>);
+
+impl Table {
+    fn collect(text: &str, events: &[(Event, Range<usize>)]) -> Self {
+        let mut rows: Vec<Vec<Cell>> = Vec::new();
+        for (event, range) in events {
+            let cell = |rows: &mut Vec<Vec<Cell>>| rows.last_mut().and_then(|row| row.last_mut());
+            match event {
+                Event::Start(Tag::TableHead | Tag::TableRow) => rows.push(Vec::new()),
+                Event::Start(Tag::TableCell) => {
+                    if let Some(row) = rows.last_mut() {
+                        let source = text[range.clone()].trim().trim_matches('|').trim();
+                        row.push(Cell {
+                            text: String::new(),
+                            source: source.replace("\\|", "|"),
+                        });
+                    }
+                }
+                Event::Text(content)
+                | Event::Code(content)
+                | Event::InlineMath(content)
+                | Event::DisplayMath(content) => {
+                    if let Some(cell) = cell(&mut rows) {
+                        cell.text.push_str(content);
+                    }
+                }
+                Event::SoftBreak | Event::HardBreak => {
+                    if let Some(cell) = cell(&mut rows) {
+                        cell.text.push(' ');
+                    }
+                }
+                _ => {}
+            }
+        }
+        Table(rows)
+    }
+
+    /// Columns padded to the same width in a code block, the header
+    /// underlined.
+    fn aligned(&self) -> String {
+        let columns = self.0.iter().map(Vec::len).max().unwrap_or(0);
+        let widths: Vec<usize> = (0..columns)
+            .map(|column| {
+                self.0
+                    .iter()
+                    .filter_map(|row| row.get(column))
+                    .map(|cell| cell.text.trim().chars().count())
+                    .max()
+                    .unwrap_or(0)
+                    .max(1)
+            })
+            .collect();
+        let line = |cells: Vec<&str>| {
+            cells
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{cell:<width$}"))
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        };
+
+        let mut lines = vec!["```".to_string()];
+        for (i, row) in self.0.iter().enumerate() {
+            lines.push(line(
+                (0..columns)
+                    .map(|column| row.get(column).map_or("", |cell| cell.text.trim()))
+                    .collect(),
+            ));
+            if i == 0 {
+                let rule: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+                lines.push(line(rule.iter().map(String::as_str).collect()));
+            }
+        }
+        lines.push("```".to_string());
+        lines.join("\n")
+    }
+
+    /// One bullet per row: the first cell, then the others after their
+    /// column's header.
+    fn bullets(&self) -> String {
+        let Some((header, rows)) = self.0.split_first() else {
+            return String::new();
+        };
+        if rows.is_empty() {
+            return header
+                .iter()
+                .map(|cell| cell.source.as_str())
+                .filter(|source| !source.is_empty())
+                .collect::<Vec<_>>()
+                .join(", ");
+        }
+
+        rows.iter()
+            .filter_map(|row| {
+                let lead = row.first().map_or("", |cell| cell.source.as_str());
+                let fields = row
+                    .iter()
+                    .enumerate()
+                    .skip(1)
+                    .filter(|(_, cell)| !cell.source.is_empty())
+                    .map(|(column, cell)| {
+                        match header.get(column).filter(|name| !name.source.is_empty()) {
+                            Some(name) => format!("{}: {}", name.source, cell.source),
+                            None => cell.source.clone(),
+                        }
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                match (lead.is_empty(), fields.is_empty()) {
+                    (true, true) => None,
+                    (false, true) => Some(format!("- {lead}")),
+                    (true, false) => Some(format!("- {fields}")),
+                    (false, false) => Some(format!("- {lead} — {fields}")),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURES: &[(&str, &str, [&str; 3])] = &[
+        (
+            "table.md",
+            include_str!("testdata/markdown/table.md"),
+            [
+                include_str!("testdata/markdown/table.discord.md"),
+                include_str!("testdata/markdown/table.telegram.md"),
+                include_str!("testdata/markdown/table.plain.md"),
+            ],
+        ),
+        (
+            "wide-table.md",
+            include_str!("testdata/markdown/wide-table.md"),
+            [
+                include_str!("testdata/markdown/wide-table.discord.md"),
+                include_str!("testdata/markdown/wide-table.telegram.md"),
+                include_str!("testdata/markdown/wide-table.plain.md"),
+            ],
+        ),
+        (
+            "nesting.md",
+            include_str!("testdata/markdown/nesting.md"),
+            [
+                include_str!("testdata/markdown/nesting.discord.md"),
+                include_str!("testdata/markdown/nesting.telegram.md"),
+                include_str!("testdata/markdown/nesting.plain.md"),
+            ],
+        ),
+        (
+            "rich.md",
+            include_str!("testdata/markdown/rich.md"),
+            [
+                include_str!("testdata/markdown/rich.discord.md"),
+                include_str!("testdata/markdown/rich.telegram.md"),
+                include_str!("testdata/markdown/rich.plain.md"),
+            ],
+        ),
+    ];
+
+    const PROFILES: [MarkdownProfile; 3] = [
+        MarkdownProfile::Discord,
+        MarkdownProfile::Telegram,
+        MarkdownProfile::Plain,
+    ];
+
+    /// Contents of the code blocks of `text`.
+    fn code_blocks(text: &str) -> Vec<String> {
+        let mut blocks = Vec::new();
+        let mut current: Option<String> = None;
+        for event in Parser::new_ext(text, options()) {
+            match event {
+                Event::Start(Tag::CodeBlock(_)) => current = Some(String::new()),
+                Event::End(TagEnd::CodeBlock) => blocks.extend(current.take()),
+                Event::Text(text) => {
+                    if let Some(block) = &mut current {
+                        block.push_str(&text);
+                    }
+                }
+                _ => {}
+            }
+        }
+        blocks
+    }
+
+    #[test]
+    fn test_golden_files() {
+        for (name, input, expected) in FIXTURES {
+            for (profile, expected) in PROFILES.iter().zip(expected) {
+                let normalized = normalize(input, *profile, 48);
+                assert_eq!(normalized, *expected, "{name} {profile:?}");
+                assert_eq!(
+                    normalize(&normalized, *profile, 48),
+                    normalized,
+                    "{name} {profile:?} is not idempotent"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_code_blocks_are_preserved() {
+        for (name, input, _) in FIXTURES {
+            for profile in PROFILES {
+                let normalized = code_blocks(&normalize(input, profile, 48));
+                for block in code_blocks(input) {
+                    assert!(normalized.contains(&block), "{name} {profile:?}: {block}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_malformed_markdown_does_not_panic() {
+        const PIECES: &[&str] = &[
+            "|",
+            "| a | b |\n",
+            "|---|---|\n",
+            "\n",
+            "\n\n",
+            "- ",
+            "  ",
+            "    - ",
+            "1. ",
+            "# ",
+            "===\n",
+            "```",
+            "`",
+            "$",
+            "$$",
+            "<div>",
+            "</",
+            ">",
+            "<b>",
+            "![",
+            "](",
+            ")",
+            "*",
+            "**",
+            "\\",
+            "\t",
+            "é",
+            "x y",
+            "> ",
+        ];
+
+        // A fixed linear congruential generator keeps failures reproducible
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = |bound: usize| {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (state >> 33) as usize % bound
+        };
+
+        for _ in 0..500 {
+            let text: String = (0..next(40)).map(|_| PIECES[next(PIECES.len())]).collect();
+            for profile in PROFILES {
+                normalize(&text, profile, 12);
+            }
+        }
+    }
+
+    #[test]
+    fn test_profiles() {
+        let mut config = MarkdownConfig::default();
+        assert_eq!(config.profile(&Source::Twitter), MarkdownProfile::Plain);
+        assert_eq!(
+            config.profile(&Source::Other("slack".to_string())),
+            MarkdownProfile::Off
+        );
+
+        config
+            .profiles
+            .insert("discord".to_string(), MarkdownProfile::Off);
+        assert_eq!(config.normalize("# Fees", &Source::Discord), "# Fees");
+        assert_eq!(config.normalize("# Fees", &Source::Telegram), "**Fees**");
+
+        // The escape hatch turns every profile off
+        config.enabled = false;
+        assert_eq!(config.normalize("# Fees", &Source::Telegram), "# Fees");
+
+        config
+            .profiles
+            .insert("myspace!".to_string(), MarkdownProfile::Plain);
+        assert!(config.validate().is_err());
+    }
+}
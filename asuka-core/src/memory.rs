@@ -0,0 +1,410 @@
+//! Tiered memory of each channel, so replies can draw on more than the last
+//! few messages without one rolling summary being rewritten all the time:
+//!
+//! - tier 0, the last raw messages;
+//! - tier 1, a summary of each session, written once the channel has been
+//!   quiet for [MemoryConfig::session_idle_minutes];
+//! - tier 2, facts promoted from sentences recurring across sessions, see
+//!   [KnowledgeBase::consolidate_facts](crate::knowledge::KnowledgeBase::consolidate_facts).
+//!
+//! Beyond the tiers, a prompt mentions the earlier exchange of the channel
+//! most like the message, when it is older than the raw messages shown, see
+//! [KnowledgeBase::similar_exchanges](crate::knowledge::KnowledgeBase::similar_exchanges).
+//!
+//! [Memory::spawn] closes sessions and consolidates them periodically. Prompts
+//! take a bounded share of each tier, see [Agent::with_memory].
+//!
+//! ```toml
+//! [memory]
+//! session_idle_minutes = 30
+//! recent_messages = 6
+//! summary_chars = 1200
+//! fact_chars = 800
+//! recall_similarity = 0.8
+//! ```
+
+use rig::{
+    completion::{CompletionModel, Prompt},
+    embeddings::EmbeddingModel,
+};
+use rig_sqlite::SqliteError;
+use serde::Deserialize;
+use tracing::{debug, error, info};
+
+use crate::{
+    agent::Agent,
+    knowledge::{ConsolidationOptions, ConsolidationReport, IdleSession},
+    summarize::chunk_transcript,
+};
+
+/// `[memory]` settings of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MemoryConfig {
+    /// Minutes without messages after which a channel's session is closed
+    /// and summarized.
+    pub session_idle_minutes: i64,
+    /// Newest messages of a session summarized at most.
+    pub max_session_messages: usize,
+    /// Raw messages added to prompts.
+    pub recent_messages: usize,
+    /// Characters of session summaries added to prompts.
+    pub summary_chars: usize,
+    /// Characters of facts added to prompts.
+    pub fact_chars: usize,
+    /// Cosine similarity from which summary sentences say the same thing.
+    pub similarity: f64,
+    /// Sessions a sentence must recur in to become a fact.
+    pub min_sessions: usize,
+    /// Newest session summaries of a channel consolidated.
+    pub consolidation_window: usize,
+    /// Seconds between two runs of [Memory::run].
+    pub interval_secs: u64,
+    /// Whether prompts mention an earlier exchange like the message, see
+    /// [Agent::with_memory].
+    pub recall_exchanges: bool,
+    /// Cosine similarity from which an earlier exchange is mentioned.
+    pub recall_similarity: f64,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        let options = ConsolidationOptions::default();
+        Self {
+            session_idle_minutes: 30,
+            max_session_messages: 200,
+            recent_messages: 6,
+            summary_chars: 1200,
+            fact_chars: 800,
+            similarity: options.similarity,
+            min_sessions: options.min_sessions,
+            consolidation_window: options.window,
+            interval_secs: 300,
+            recall_exchanges: true,
+            recall_similarity: 0.8,
+        }
+    }
+}
+
+impl MemoryConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.similarity > 0.0 && self.similarity <= 1.0) {
+            return Err("memory.similarity must be above 0 and at most 1".to_string());
+        }
+        if !(self.recall_similarity > 0.0 && self.recall_similarity <= 1.0) {
+            return Err("memory.recall_similarity must be above 0 and at most 1".to_string());
+        }
+        if self.session_idle_minutes <= 0 || self.interval_secs == 0 {
+            return Err(
+                "memory.session_idle_minutes and memory.interval_secs must be positive".to_string(),
+            );
+        }
+        if self.min_sessions < 2 {
+            return Err("memory.min_sessions must be at least 2".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn consolidation(&self) -> ConsolidationOptions {
+        ConsolidationOptions {
+            similarity: self.similarity,
+            min_sessions: self.min_sessions,
+            window: self.consolidation_window,
+        }
+    }
+}
+
+/// Closes and consolidates the sessions of every channel of an agent.
+#[derive(Clone)]
+pub struct Memory<M: CompletionModel, E: EmbeddingModel + 'static> {
+    agent: Agent<M, E>,
+    config: MemoryConfig,
+}
+
+impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> Memory<M, E> {
+    pub fn new(agent: Agent<M, E>, config: MemoryConfig) -> Self {
+        Self { agent, config }
+    }
+
+    /// Summarizes the sessions that went quiet before `now` minus
+    /// [MemoryConfig::session_idle_minutes], and ends the rolling summary of
+    /// their channels. Returns the number of sessions closed; a session whose
+    /// summary fails is retried on the next run.
+    pub async fn close_idle_sessions(
+        &self,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Result<usize, SqliteError> {
+        let knowledge = self.agent.knowledge();
+        let idle_since = now - chrono::Duration::minutes(self.config.session_idle_minutes);
+        let mut closed = 0;
+
+        for session in knowledge.idle_sessions(idle_since).await? {
+            let window = knowledge
+                .messages_between(
+                    &session.channel_id,
+                    session.started_at,
+                    session.ended_at + chrono::Duration::seconds(1),
+                    self.config.max_session_messages,
+                )
+                .await?;
+            let summary = match self.summarize(&session, &window.messages).await {
+                Ok(summary) => summary,
+                Err(err) => {
+                    error!(%err, channel_id = %session.channel_id, "Failed to summarize session");
+                    continue;
+                }
+            };
+            knowledge
+                .add_session_summary(&session, &summary, window.messages.len())
+                .await?;
+            self.agent
+                .conversations()
+                .update(&session.channel_id, |state| state.close_session())
+                .await;
+            debug!(channel_id = %session.channel_id, "Closed session");
+            closed += 1;
+        }
+        Ok(closed)
+    }
+
+    /// Promotes recurring session content of every channel to facts. A
+    /// channel failing to consolidate is logged and skipped.
+    pub async fn consolidate(&self) -> Result<Vec<(String, ConsolidationReport)>, SqliteError> {
+        let knowledge = self.agent.knowledge();
+        let options = self.config.consolidation();
+        let mut reports = Vec::new();
+
+        for channel_id in knowledge
+            .channels_with_sessions(options.min_sessions)
+            .await?
+        {
+            match knowledge.consolidate_facts(&channel_id, &options).await {
+                Ok(report) => reports.push((channel_id, report)),
+                Err(err) => error!(?err, %channel_id, "Failed to consolidate sessions"),
+            }
+        }
+        Ok(reports)
+    }
+
+    /// Closes idle sessions, then consolidates.
+    pub async fn run(&self, now: chrono::DateTime<chrono::Utc>) -> Result<(), SqliteError> {
+        let closed = self.close_idle_sessions(now).await?;
+        let promoted: usize = self
+            .consolidate()
+            .await?
+            .iter()
+            .map(|(_, report)| report.promoted.len())
+            .sum();
+        info!(closed, promoted, "Updated channel memory");
+        Ok(())
+    }
+
+    /// Runs [Memory::run] every [MemoryConfig::interval_secs] until the task
+    /// is aborted.
+    pub fn spawn(self) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let interval = std::time::Duration::from_secs(self.config.interval_secs);
+            loop {
+                tokio::time::sleep(interval).await;
+                if let Err(err) = self.run(self.agent.clock().now_utc()).await {
+                    error!(?err, "Failed to update channel memory");
+                }
+            }
+        })
+    }
+
+    async fn summarize(
+        &self,
+        session: &IdleSession,
+        messages: &[crate::knowledge::Message],
+    ) -> Result<String, String> {
+        let transcript = chunk_transcript(messages, usize::MAX).concat();
+        let input = format!(
+            "Summarize this chat session in a few short sentences, one topic, decision \
+            or answer each, so each sentence stands on its own.\n\n{transcript}"
+        );
+        let summary = self
+            .agent
+            .builder()
+            .build()
+            .prompt(input.as_str())
+            .await
+            .map_err(|e| e.to_string())?;
+        debug!(
+            channel_id = %session.channel_id,
+            messages = messages.len(),
+            "Summarized session"
+        );
+        Ok(summary.trim().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        attention::ResponseMode,
+        knowledge::{ChannelType, Message, Source},
+        test_utils::{self, ScriptedCompletionModel},
+    };
+
+    fn at(minute: i64) -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::from_timestamp(1_730_800_000 + minute * 60, 0).unwrap()
+    }
+
+    fn message(id: &str, minute: i64, content: &str) -> Message {
+        Message {
+            id: id.to_string(),
+            source: Source::Discord,
+            source_id: "u1".to_string(),
+            channel_type: ChannelType::Text,
+            channel_id: "c1".to_string(),
+            account_id: "u1".to_string(),
+            role: "user".to_string(),
+            content: content.to_string(),
+            created_at: at(minute),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sessions_become_facts_in_prompts() {
+        let model = ScriptedCompletionModel::new([
+            "Katana listens on port 5050. Fees are paid in STRK.",
+            "Katana listens on port 5050.",
+        ]);
+        let character = test_utils::character();
+        let config = MemoryConfig {
+            recent_messages: 1,
+            ..Default::default()
+        };
+        let agent = Agent::new(character, model.clone(), test_utils::knowledge_base().await)
+            .with_memory(config.clone());
+        let knowledge = agent.knowledge();
+        let memory = Memory::new(agent.clone(), config);
+
+        knowledge
+            .create_message(message("m1", 0, "which port does katana use?"))
+            .await
+            .unwrap();
+        agent
+            .conversations()
+            .update("c1", |state| {
+                state.receive("m1");
+                state.summary = Some("Asking about katana".to_string());
+            })
+            .await;
+        // Still within the idle time
+        assert_eq!(memory.close_idle_sessions(at(10)).await.unwrap(), 0);
+        assert_eq!(memory.close_idle_sessions(at(40)).await.unwrap(), 1);
+        assert_eq!(agent.conversations().get("c1").await.summary, None);
+        assert!(memory.consolidate().await.unwrap().is_empty());
+
+        knowledge
+            .create_message(message("m2", 120, "katana port again?"))
+            .await
+            .unwrap();
+        knowledge
+            .create_message(message("m3", 121, "thanks!"))
+            .await
+            .unwrap();
+        assert_eq!(memory.close_idle_sessions(at(200)).await.unwrap(), 1);
+        assert!(model.requests()[1].prompt.contains("katana port again?"));
+        assert!(!model.requests()[1].prompt.contains("which port"));
+
+        let reports = memory.consolidate().await.unwrap();
+        assert_eq!(reports[0].0, "c1");
+        assert_eq!(reports[0].1.promoted, ["Katana listens on port 5050."]);
+
+        let prompt = agent
+            .render_prompt("c1", &ResponseMode::FullAnswer, "and the fees?")
+            .await;
+        assert_eq!(
+            prompt.context("fact 1"),
+            Some("Known from earlier sessions: Katana listens on port 5050.")
+        );
+        let sessions = prompt
+            .contexts
+            .iter()
+            .filter(|(label, _)| label.starts_with("session "))
+            .map(|(_, text)| text.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sessions,
+            [
+                "Earlier session: Katana listens on port 5050. Fees are paid in STRK.",
+                "Earlier session: Katana listens on port 5050.",
+            ]
+        );
+        assert_eq!(
+            prompt.context("recent messages"),
+            Some("Recent messages:\nu1: thanks!")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_prompt_budget_per_tier() {
+        let character = test_utils::character();
+        let config = MemoryConfig {
+            recent_messages: 0,
+            summary_chars: 30,
+            fact_chars: 0,
+            ..Default::default()
+        };
+        let agent = Agent::new(
+            character,
+            ScriptedCompletionModel::default(),
+            test_utils::knowledge_base().await,
+        )
+        .with_memory(config);
+        let knowledge = agent.knowledge();
+        for (minute, summary) in [
+            (0, "Goerli was deprecated."),
+            (60, "Testnet moved to Sepolia."),
+        ] {
+            let session = IdleSession {
+                channel_id: "c1".to_string(),
+                started_at: at(minute),
+                ended_at: at(minute + 5),
+            };
+            knowledge
+                .add_session_summary(&session, summary, 2)
+                .await
+                .unwrap();
+        }
+
+        // Only the newest summary fits
+        let prompt = agent
+            .render_prompt("c1", &ResponseMode::FullAnswer, "which testnet?")
+            .await;
+        let labels = prompt
+            .contexts
+            .iter()
+            .map(|(label, _)| label.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(labels, ["name", "session 2", "time", "length"]);
+        assert_eq!(
+            prompt.context("session 2"),
+            Some("Earlier session: Testnet moved to Sepolia.")
+        );
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(MemoryConfig::default().validate().is_ok());
+        let config = MemoryConfig {
+            similarity: 1.5,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        let config = MemoryConfig {
+            min_sessions: 1,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        let config = MemoryConfig {
+            recall_similarity: 0.0,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+}
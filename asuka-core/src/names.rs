@@ -0,0 +1,255 @@
+//! Recognizing when a message addresses the bot by name. Names are compared
+//! case- and diacritic-insensitively and only as whole words, so "Shinobi-bot"
+//! and "shinobí" address a bot named "shinobi" while "shinobigame" doesn't,
+//! nor does "@Shin".
+
+use std::collections::HashSet;
+
+/// Accented Latin letters and the letter they fold to.
+const FOLDS: &[(&str, char)] = &[
+    ("àáâãäåāăą", 'a'),
+    ("çćĉċč", 'c'),
+    ("ďđ", 'd'),
+    ("èéêëēĕėęě", 'e'),
+    ("ĝğġģ", 'g'),
+    ("ĥħ", 'h'),
+    ("ìíîïĩīĭįı", 'i'),
+    ("ĵ", 'j'),
+    ("ķ", 'k'),
+    ("ĺļľŀł", 'l'),
+    ("ñńņňŉ", 'n'),
+    ("òóôõöøōŏő", 'o'),
+    ("ŕŗř", 'r'),
+    ("śŝşš", 's'),
+    ("ţťŧ", 't'),
+    ("ùúûüũūŭůűų", 'u'),
+    ("ŵ", 'w'),
+    ("ýÿŷ", 'y'),
+    ("źżž", 'z'),
+];
+
+/// How the bot was addressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchSource {
+    /// A platform mention, such as `@shinobi` on Discord.
+    Mention,
+    /// The name written in the message.
+    Content,
+}
+
+impl MatchSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MatchSource::Mention => "mention",
+            MatchSource::Content => "content",
+        }
+    }
+}
+
+/// The name a message addressed the bot by, as configured.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameMatch {
+    pub name: String,
+    pub source: MatchSource,
+}
+
+/// The names the bot answers to: the character's name, its aliases and, on
+/// Discord, its username and nicknames.
+#[derive(Debug, Clone, Default)]
+pub struct NameMatcher {
+    /// Each name as configured, with its folded words.
+    names: Vec<(String, Vec<String>)>,
+}
+
+impl NameMatcher {
+    pub fn new<I, S>(names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        names
+            .into_iter()
+            .fold(Self::default(), |matcher, name| matcher.with_name(name))
+    }
+
+    /// Adds `name`, unless it has no letters or digits or is already known.
+    pub fn with_name(mut self, name: impl AsRef<str>) -> Self {
+        let name = name.as_ref();
+        if !words(name).is_empty() && !self.contains(name) {
+            self.names.push((name.trim().to_string(), words(name)));
+        }
+        self
+    }
+
+    /// Whether `name` is one of the names, ignoring case and diacritics.
+    pub fn contains(&self, name: &str) -> bool {
+        let words = words(name);
+        self.names.iter().any(|(_, known)| *known == words)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// The name `content` addresses the bot by, either among the names
+    /// `mentioned` on the platform or written in the message.
+    pub fn find(&self, content: &str, mentioned: &HashSet<String>) -> Option<NameMatch> {
+        let mentioned = mentioned.iter().map(|name| words(name)).collect::<Vec<_>>();
+        self.names
+            .iter()
+            .find(|(_, words)| mentioned.contains(words))
+            .map(|(name, _)| NameMatch {
+                name: name.clone(),
+                source: MatchSource::Mention,
+            })
+            .or_else(|| {
+                self.find_in_text(content).map(|name| NameMatch {
+                    name: name.to_string(),
+                    source: MatchSource::Content,
+                })
+            })
+    }
+
+    /// The name written in `text` as whole words, if any. Longer names are
+    /// preferred, so "shinobi bot" is reported over "shinobi".
+    pub fn find_in_text(&self, text: &str) -> Option<&str> {
+        let text = words(text);
+        self.names
+            .iter()
+            .filter(|(_, name)| {
+                text.windows(name.len())
+                    .any(|window| window == name.as_slice())
+            })
+            .max_by_key(|(_, name)| name.len())
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Whether every word of `text` belongs to one of the names, e.g. to
+    /// leave "shinobi" out of "thanks shinobi".
+    pub fn is_name(&self, text: &str) -> bool {
+        let words = words(text);
+        !words.is_empty()
+            && words
+                .iter()
+                .all(|word| self.names.iter().any(|(_, name)| name.contains(word)))
+    }
+}
+
+/// `text` lowercased and without diacritics, split into words at anything
+/// that isn't a letter or digit.
+fn words(text: &str) -> Vec<String> {
+    text.chars()
+        .flat_map(char::to_lowercase)
+        .map(fold)
+        .collect::<String>()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn fold(c: char) -> char {
+    if c.is_ascii() {
+        return c;
+    }
+    FOLDS
+        .iter()
+        .find(|(accented, _)| accented.contains(c))
+        .map_or(c, |(_, base)| *base)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher() -> NameMatcher {
+        NameMatcher::new(["Shinobi", "Shinobai", "shinobi bot"])
+    }
+
+    fn mentions(names: &[&str]) -> HashSet<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_matches() {
+        let matcher = matcher();
+        for (content, expected) in [
+            ("hey shinobi, what are the fees?", "Shinobi"),
+            ("SHINOBI help", "Shinobi"),
+            ("Shinobi-bot can you check this", "shinobi bot"),
+            ("thanks shinobi bot!", "shinobi bot"),
+            ("ok Shinobí", "Shinobi"),
+            ("shinobai: how do I deploy?", "Shinobai"),
+            ("(shinobi)", "Shinobi"),
+            ("is shinobi's answer right?", "Shinobi"),
+        ] {
+            assert_eq!(
+                matcher.find(content, &HashSet::new()),
+                Some(NameMatch {
+                    name: expected.to_string(),
+                    source: MatchSource::Content,
+                }),
+                "{content}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_false_positives() {
+        let matcher = matcher();
+        for content in [
+            "shinobigame is down",
+            "ask the unshinobi crew",
+            "@Shin what do you think",
+            "shin obi",
+            "shinob",
+            "the bot is slow",
+            "",
+        ] {
+            assert_eq!(matcher.find(content, &HashSet::new()), None, "{content}");
+        }
+
+        // A user named like a part of an alias is not the bot
+        assert_eq!(matcher.find("hi", &mentions(&["Shin", "bot"])), None);
+    }
+
+    #[test]
+    fn test_mentions() {
+        let matcher = matcher().with_name("Shinobi Nickname");
+
+        assert_eq!(
+            matcher.find("<@1234> hi", &mentions(&["someone", "shinobi nickname"])),
+            Some(NameMatch {
+                name: "Shinobi Nickname".to_string(),
+                source: MatchSource::Mention,
+            })
+        );
+        assert_eq!(
+            matcher
+                .find("<@1234> hi", &mentions(&["Shinobi"]))
+                .unwrap()
+                .name,
+            "Shinobi"
+        );
+    }
+
+    #[test]
+    fn test_is_name() {
+        let matcher = matcher();
+
+        assert!(matcher.is_name("shinobi"));
+        assert!(matcher.is_name("Shinobi-bot"));
+        assert!(matcher.is_name("@shinobai"));
+        assert!(!matcher.is_name("thanks"));
+        assert!(!matcher.is_name("shinobi thanks"));
+        assert!(!matcher.is_name("!!"));
+    }
+
+    #[test]
+    fn test_names_are_deduplicated() {
+        let matcher = NameMatcher::new(["shinobi", "Shinobi", " SHINOBI ", "", "--"]);
+        assert_eq!(matcher.names.len(), 1);
+        assert!(matcher.contains("Shinobí"));
+        assert!(NameMatcher::new(Vec::<String>::new()).is_empty());
+    }
+}
@@ -0,0 +1,392 @@
+//! Introduction and preference questions for users who message the bot
+//! directly for the first time.
+//!
+//! Configured by the `[onboarding]` section of the character TOML. Answers
+//! are stored as user facts and progress is kept in the knowledge base, so
+//! onboarding picks up where it left off after a restart. A user who asks a
+//! question instead of answering gets it answered as usual and is not asked
+//! again.
+//!
+//! ```toml
+//! [onboarding]
+//! introduction = "Hi, I'm {{name}}. I answer questions about Cartridge's docs."
+//! completion = "Thanks! Ask me anything."
+//!
+//! [[onboarding.questions]]
+//! fact = "language"
+//! prompt = "Which language should I answer in?"
+//!
+//! [[onboarding.questions]]
+//! fact = "verbosity"
+//! prompt = "Do you prefer short or detailed answers?"
+//! choices = ["short", "detailed"]
+//! ```
+
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    knowledge::{ChannelType, KnowledgeBase, Message, OnboardingState},
+    templates::interpolate,
+};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OnboardingConfig {
+    /// Sent on first contact, followed by the first question. `{{name}}` is
+    /// the character's name.
+    pub introduction: String,
+    #[serde(default)]
+    pub questions: Vec<OnboardingQuestion>,
+    /// Sent after the last answer.
+    #[serde(default = "default_completion")]
+    pub completion: String,
+    /// Sent when an answer is not one of the choices. `{{choices}}` lists
+    /// them.
+    #[serde(default = "default_invalid_choice")]
+    pub invalid_choice: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OnboardingQuestion {
+    /// User fact the answer is stored under.
+    pub fact: String,
+    pub prompt: String,
+    /// Accepted answers, matched ignoring case. Any answer is accepted when
+    /// empty.
+    #[serde(default)]
+    pub choices: Vec<String>,
+}
+
+fn default_completion() -> String {
+    "Thanks, that's all I needed. What can I help you with?".to_string()
+}
+
+fn default_invalid_choice() -> String {
+    "Please answer with one of: {{choices}}.".to_string()
+}
+
+impl OnboardingQuestion {
+    /// The answer to store, or `None` when it is not one of the choices.
+    fn accept(&self, text: &str) -> Option<String> {
+        let text = text.trim();
+        if self.choices.is_empty() {
+            return (!text.is_empty()).then(|| text.to_string());
+        }
+        self.choices
+            .iter()
+            .find(|choice| choice.eq_ignore_ascii_case(text))
+            .cloned()
+    }
+}
+
+/// What the client does with a direct message after onboarding saw it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OnboardingStep {
+    /// Send this instead of answering the message.
+    Reply(String),
+    /// Handle the message as usual.
+    Continue,
+}
+
+/// A message asking something of the bot rather than answering onboarding.
+fn is_question(text: &str) -> bool {
+    text.trim_end().ends_with('?')
+}
+
+impl OnboardingConfig {
+    /// Advances the onboarding of the message's author. Must run before the
+    /// message is stored, as first contact is recognized by the author having
+    /// no stored messages. Users who wrote before onboarding was configured
+    /// are left alone.
+    pub async fn advance<E: EmbeddingModel>(
+        &self,
+        character_name: &str,
+        knowledge: &KnowledgeBase<E>,
+        message: &Message,
+    ) -> Result<OnboardingStep, SqliteError> {
+        if message.channel_type != ChannelType::DirectMessage {
+            return Ok(OnboardingStep::Continue);
+        }
+        let account_id = &message.account_id;
+
+        let step = match knowledge.onboarding_state(account_id).await? {
+            Some(OnboardingState::Asking { step }) => step,
+            Some(OnboardingState::Completed | OnboardingState::Skipped) => {
+                return Ok(OnboardingStep::Continue)
+            }
+            None if knowledge.has_messages_from(account_id).await? => {
+                return Ok(OnboardingStep::Continue)
+            }
+            None if is_question(&message.content) => {
+                info!(account_id, "Onboarding skipped on first contact");
+                knowledge
+                    .save_onboarding_state(account_id, OnboardingState::Skipped)
+                    .await?;
+                return Ok(OnboardingStep::Continue);
+            }
+            None => {
+                info!(account_id, "Starting onboarding");
+                let introduction = interpolate(&self.introduction, &[("name", character_name)]);
+                return match self.ask(knowledge, account_id, 0).await? {
+                    Some(question) => Ok(OnboardingStep::Reply(format!(
+                        "{introduction}\n\n{question}"
+                    ))),
+                    None => Ok(OnboardingStep::Reply(introduction)),
+                };
+            }
+        };
+
+        let Some(question) = self.questions.get(step) else {
+            // The questions were shortened since this user started.
+            knowledge
+                .save_onboarding_state(account_id, OnboardingState::Completed)
+                .await?;
+            return Ok(OnboardingStep::Continue);
+        };
+        if is_question(&message.content) {
+            info!(account_id, step, "Onboarding skipped for a question");
+            knowledge
+                .save_onboarding_state(account_id, OnboardingState::Skipped)
+                .await?;
+            return Ok(OnboardingStep::Continue);
+        }
+        let Some(answer) = question.accept(&message.content) else {
+            let choices = question.choices.join(", ");
+            return Ok(OnboardingStep::Reply(interpolate(
+                &self.invalid_choice,
+                &[("choices", &choices)],
+            )));
+        };
+
+        knowledge
+            .set_user_fact(account_id, &question.fact, &answer)
+            .await?;
+        match self.ask(knowledge, account_id, step + 1).await? {
+            Some(question) => Ok(OnboardingStep::Reply(question)),
+            None => {
+                info!(account_id, "Onboarding completed");
+                Ok(OnboardingStep::Reply(self.completion.clone()))
+            }
+        }
+    }
+
+    /// Records that the user is at `step` and returns its prompt, or marks
+    /// onboarding completed when there are no questions left.
+    async fn ask<E: EmbeddingModel>(
+        &self,
+        knowledge: &KnowledgeBase<E>,
+        account_id: &str,
+        step: usize,
+    ) -> Result<Option<String>, SqliteError> {
+        let (state, prompt) = match self.questions.get(step) {
+            Some(question) => (
+                OnboardingState::Asking { step },
+                Some(question.prompt.clone()),
+            ),
+            None => (OnboardingState::Completed, None),
+        };
+        knowledge.save_onboarding_state(account_id, state).await?;
+        Ok(prompt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agent::Agent,
+        character::Character,
+        knowledge::Source,
+        test_utils::{self, FakeEmbeddingModel, ScriptedCompletionModel},
+    };
+    use tokio_rusqlite::Connection;
+
+    fn config() -> OnboardingConfig {
+        toml::from_str(
+            r#"
+            introduction = "Hi, I'm {{name}}."
+
+            [[questions]]
+            fact = "language"
+            prompt = "Which language should I answer in?"
+
+            [[questions]]
+            fact = "verbosity"
+            prompt = "Short or detailed answers?"
+            choices = ["short", "detailed"]
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn agent(
+        knowledge: KnowledgeBase<FakeEmbeddingModel>,
+    ) -> Agent<ScriptedCompletionModel, FakeEmbeddingModel> {
+        let character = Character {
+            onboarding: Some(config()),
+            ..test_utils::character()
+        };
+        Agent::new(character, ScriptedCompletionModel::default(), knowledge)
+    }
+
+    fn dm(id: &str, content: &str) -> Message {
+        Message {
+            id: id.to_string(),
+            source: Source::Discord,
+            source_id: "alice".to_string(),
+            channel_type: ChannelType::DirectMessage,
+            channel_id: "dm-alice".to_string(),
+            account_id: "alice".to_string(),
+            role: "user".to_string(),
+            content: content.to_string(),
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    fn dm_from(account_id: &str, id: &str, content: &str) -> Message {
+        Message {
+            source_id: account_id.to_string(),
+            channel_id: format!("dm-{account_id}"),
+            account_id: account_id.to_string(),
+            ..dm(id, content)
+        }
+    }
+
+    /// Runs a message through onboarding and stores it, as the clients do.
+    async fn send(
+        agent: &Agent<ScriptedCompletionModel, FakeEmbeddingModel>,
+        message: Message,
+    ) -> OnboardingStep {
+        let step = agent.onboard(&message).await;
+        agent.knowledge().create_message(message).await.unwrap();
+        step
+    }
+
+    #[tokio::test]
+    async fn test_onboarding_completes() {
+        let agent = agent(test_utils::knowledge_base().await);
+
+        assert_eq!(
+            send(&agent, dm("1", "hello")).await,
+            OnboardingStep::Reply(
+                "Hi, I'm shinobi.\n\nWhich language should I answer in?".to_string()
+            )
+        );
+        assert_eq!(
+            send(&agent, dm("2", " English ")).await,
+            OnboardingStep::Reply("Short or detailed answers?".to_string())
+        );
+        assert_eq!(
+            send(&agent, dm("3", "very long please")).await,
+            OnboardingStep::Reply("Please answer with one of: short, detailed.".to_string())
+        );
+        assert_eq!(
+            send(&agent, dm("4", "SHORT")).await,
+            OnboardingStep::Reply(default_completion())
+        );
+        assert_eq!(
+            send(&agent, dm("5", "how do I set up VRF?")).await,
+            OnboardingStep::Continue
+        );
+
+        let knowledge = agent.knowledge();
+        assert_eq!(
+            knowledge.user_facts("alice").await.unwrap(),
+            [
+                ("language".to_string(), "English".to_string()),
+                ("verbosity".to_string(), "short".to_string())
+            ]
+        );
+        assert_eq!(
+            knowledge.onboarding_state("alice").await.unwrap(),
+            Some(OnboardingState::Completed)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_question_skips_onboarding() {
+        let agent = agent(test_utils::knowledge_base().await);
+        assert_eq!(
+            send(&agent, dm("1", "how do I set up VRF?")).await,
+            OnboardingStep::Continue
+        );
+        assert_eq!(
+            send(&agent, dm("2", "thanks")).await,
+            OnboardingStep::Continue
+        );
+        assert_eq!(
+            agent.knowledge().onboarding_state("alice").await.unwrap(),
+            Some(OnboardingState::Skipped)
+        );
+
+        // Skipping midway keeps the answers given so far.
+        let agent = agent(test_utils::knowledge_base().await);
+        assert!(matches!(
+            send(&agent, dm_from("bob", "1", "hey")).await,
+            OnboardingStep::Reply(_)
+        ));
+        send(&agent, dm_from("bob", "2", "French")).await;
+        assert_eq!(
+            send(&agent, dm_from("bob", "3", "wait, what is a paymaster?")).await,
+            OnboardingStep::Continue
+        );
+        assert_eq!(
+            agent.knowledge().user_facts("bob").await.unwrap(),
+            [("language".to_string(), "French".to_string())]
+        );
+        assert_eq!(
+            agent.knowledge().onboarding_state("bob").await.unwrap(),
+            Some(OnboardingState::Skipped)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_existing_users_and_other_channels_are_left_alone() {
+        let knowledge = test_utils::knowledge_base().await;
+        knowledge.create_message(dm("0", "hi")).await.unwrap();
+        let agent = agent(knowledge);
+        assert_eq!(
+            send(&agent, dm("1", "hello again")).await,
+            OnboardingStep::Continue
+        );
+
+        let mut message = dm_from("bob", "2", "hello");
+        message.channel_type = ChannelType::Text;
+        assert_eq!(send(&agent, message).await, OnboardingStep::Continue);
+        assert_eq!(
+            agent.knowledge().onboarding_state("bob").await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_onboarding_resumes_after_restart() {
+        async fn open(
+            path: &std::path::Path,
+        ) -> Agent<ScriptedCompletionModel, FakeEmbeddingModel> {
+            test_utils::load_sqlite_vec();
+            let conn = Connection::open(path).await.unwrap();
+            agent(KnowledgeBase::new(conn, FakeEmbeddingModel).await.unwrap())
+        }
+        let file = tempfile::NamedTempFile::new().unwrap();
+
+        let before = open(file.path()).await;
+        send(&before, dm("1", "hello")).await;
+        send(&before, dm("2", "English")).await;
+        drop(before);
+
+        let after = open(file.path()).await;
+        assert_eq!(
+            send(&after, dm("3", "detailed")).await,
+            OnboardingStep::Reply(default_completion())
+        );
+        assert_eq!(
+            after.knowledge().user_facts("alice").await.unwrap().len(),
+            2
+        );
+    }
+}
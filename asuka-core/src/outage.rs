@@ -0,0 +1,321 @@
+//! Telling channels why answers are slow or missing while the completion
+//! provider is rate limiting the bot, is down or its budget is spent,
+//! instead of leaving them in silence.
+//!
+//! [OutageNotices] sends a short notice, the
+//! [PROVIDER_SLOW](crate::templates::PROVIDER_SLOW) template, when an answer
+//! takes longer than [OutageConfig::delay_notice_secs], and raises
+//! [Condition::RateLimited] when a generation fails for lack of capacity. A
+//! channel gets at most one notice per [OutageConfig::notice_window_minutes],
+//! however many of its messages wait. Messages the bot decided not to answer
+//! never get one.
+//!
+//! With [OutageConfig::follow_up], the [Pipeline](crate::pipeline::Pipeline)
+//! queues requests it could not answer and answers them once the provider
+//! recovers, see [Pipeline::follow_up_deferred](crate::pipeline::Pipeline::follow_up_deferred).
+//! The Discord and Telegram clients schedule those answers themselves; other
+//! clients run [Pipeline::spawn_follow_ups](crate::pipeline::Pipeline::spawn_follow_ups).
+//!
+//! ```toml
+//! [outage]
+//! delay_notice_secs = 20
+//! notice_window_minutes = 10
+//! ```
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use rig::completion::PromptError;
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::{
+    clock::{Clock, SystemClock},
+    status::{Condition, StatusBoard},
+};
+
+/// Parts of provider errors that mean it lacks capacity rather than that
+/// the request is wrong, matched ignoring case.
+const CAPACITY_MARKERS: &[&str] = &[
+    "http 429",
+    "http 503",
+    "http 529",
+    "rate limit",
+    "rate_limit",
+    "quota",
+    "overloaded",
+];
+
+/// `[outage]` settings of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutageConfig {
+    /// Seconds an answer may take before its channel is told it is slow.
+    pub delay_notice_secs: u64,
+    /// Minutes after a notice during which its channel gets no other.
+    pub notice_window_minutes: u64,
+    /// Answer requests that failed for lack of capacity once the provider
+    /// recovers.
+    pub follow_up: bool,
+    /// Seconds between attempts to answer queued requests while the
+    /// provider is still failing.
+    pub retry_interval_secs: u64,
+    /// Requests queued per channel at most. Later ones are dropped.
+    pub max_deferred_per_channel: usize,
+}
+
+impl Default for OutageConfig {
+    fn default() -> Self {
+        Self {
+            delay_notice_secs: 20,
+            notice_window_minutes: 10,
+            follow_up: true,
+            retry_interval_secs: 60,
+            max_deferred_per_channel: 5,
+        }
+    }
+}
+
+impl OutageConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.delay_notice_secs == 0 {
+            return Err("outage.delay_notice_secs must be positive".to_string());
+        }
+        if self.notice_window_minutes == 0 {
+            return Err("outage.notice_window_minutes must be positive".to_string());
+        }
+        if self.retry_interval_secs == 0 {
+            return Err("outage.retry_interval_secs must be positive".to_string());
+        }
+        if self.follow_up && self.max_deferred_per_channel == 0 {
+            return Err(
+                "outage.max_deferred_per_channel must be positive with follow_up".to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    pub fn delay_notice(&self) -> Duration {
+        Duration::from_secs(self.delay_notice_secs)
+    }
+
+    pub fn retry_interval(&self) -> Duration {
+        Duration::from_secs(self.retry_interval_secs)
+    }
+}
+
+/// Whether `err` means the provider is rate limiting the bot, overloaded or
+/// out of quota, rather than that the request failed.
+pub fn is_capacity_error(err: &PromptError) -> bool {
+    let message = err.to_string().to_lowercase();
+    CAPACITY_MARKERS
+        .iter()
+        .any(|marker| message.contains(marker))
+}
+
+/// Provider health as seen by the response path, and the channels told
+/// about it. Clones share the channels notified.
+#[derive(Clone)]
+pub struct OutageNotices {
+    config: OutageConfig,
+    status: StatusBoard,
+    clock: Arc<dyn Clock>,
+    notified: Arc<Mutex<HashMap<String, DateTime<Utc>>>>,
+}
+
+impl OutageNotices {
+    pub fn new(config: OutageConfig, status: StatusBoard) -> Self {
+        Self {
+            config,
+            status,
+            clock: Arc::new(SystemClock),
+            notified: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn config(&self) -> &OutageConfig {
+        &self.config
+    }
+
+    pub fn status(&self) -> &StatusBoard {
+        &self.status
+    }
+
+    /// Whether `channel_id` may be sent a notice now, in which case it gets
+    /// no other until the window is up.
+    pub fn claim(&self, channel_id: &str) -> bool {
+        let now = self.clock.now_utc();
+        let window = chrono::Duration::minutes(self.config.notice_window_minutes as i64);
+        let mut notified = self.notified.lock().unwrap();
+        notified.retain(|_, at| now - *at < window);
+        if notified.contains_key(channel_id) {
+            return false;
+        }
+        notified.insert(channel_id.to_string(), now);
+        true
+    }
+
+    /// Whether generating is pointless for now, the budget being spent.
+    pub fn is_over_budget(&self) -> bool {
+        self.status.status().has(Condition::OverBudget)
+    }
+
+    /// Runs `generation`, whose answer goes to `channel_id`, and `notice`
+    /// alongside it when it takes longer than
+    /// [OutageConfig::delay_notice_secs] and the channel may be told.
+    pub async fn notify_if_delayed<T>(
+        &self,
+        channel_id: &str,
+        generation: impl Future<Output = T>,
+        notice: impl Future<Output = ()>,
+    ) -> T {
+        let mut generation = std::pin::pin!(generation);
+        tokio::select! {
+            output = &mut generation => return output,
+            _ = tokio::time::sleep(self.config.delay_notice()) => {}
+        }
+        if !self.claim(channel_id) {
+            return generation.await;
+        }
+        let (_, output) = tokio::join!(notice, generation);
+        output
+    }
+
+    /// Runs `generation` unless the budget is spent, and raises or clears
+    /// [Condition::RateLimited] by its outcome. `None` when it was skipped
+    /// or failed for lack of capacity, so the request can wait.
+    pub async fn generate<T>(
+        &self,
+        generation: impl Future<Output = Result<T, PromptError>>,
+    ) -> Result<Option<T>, PromptError> {
+        if self.is_over_budget() {
+            return Ok(None);
+        }
+        match generation.await {
+            Ok(output) => {
+                self.status.set(Condition::RateLimited, false);
+                Ok(Some(output))
+            }
+            Err(err) if is_capacity_error(&err) => {
+                warn!(%err, "Completion provider lacks capacity");
+                self.status.set(Condition::RateLimited, true);
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use rig::completion::CompletionError;
+
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn provider_error(message: &str) -> PromptError {
+        PromptError::CompletionError(CompletionError::ProviderError(message.to_string()))
+    }
+
+    #[test]
+    fn test_capacity_errors() {
+        assert!(is_capacity_error(&provider_error(
+            "HTTP 429 Too Many Requests from https://api.example.com: slow down"
+        )));
+        assert!(is_capacity_error(&provider_error(
+            "{\"type\":\"overloaded_error\"}"
+        )));
+        assert!(!is_capacity_error(&provider_error(
+            "HTTP 400 Bad Request from https://api.example.com: invalid model"
+        )));
+    }
+
+    #[test]
+    fn test_one_notice_per_channel_and_window() {
+        let clock = MockClock::new(Utc::now());
+        let notices = OutageNotices::new(OutageConfig::default(), StatusBoard::default())
+            .with_clock(Arc::new(clock.clone()));
+
+        assert!(notices.claim("c1"));
+        assert!(!notices.claim("c1"));
+        assert!(notices.claim("c2"));
+
+        clock.advance(chrono::Duration::minutes(9));
+        assert!(!notices.claim("c1"));
+        clock.advance(chrono::Duration::minutes(1));
+        assert!(notices.claim("c1"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_notice_only_for_slow_generations() {
+        let notices = OutageNotices::new(OutageConfig::default(), StatusBoard::default());
+        let sent = &AtomicUsize::new(0);
+        let notice = move || async move {
+            sent.fetch_add(1, Ordering::SeqCst);
+        };
+        let answer = |secs| async move {
+            tokio::time::sleep(Duration::from_secs(secs)).await;
+            "answer"
+        };
+
+        let output = notices.notify_if_delayed("c1", answer(5), notice()).await;
+        assert_eq!(output, "answer");
+        assert_eq!(sent.load(Ordering::SeqCst), 0);
+
+        let output = notices.notify_if_delayed("c1", answer(30), notice()).await;
+        assert_eq!(output, "answer");
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+
+        // Told already
+        notices.notify_if_delayed("c1", answer(30), notice()).await;
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_capacity_failures_raise_rate_limited() {
+        let status = StatusBoard::default();
+        let notices = OutageNotices::new(OutageConfig::default(), status.clone());
+
+        let output = notices
+            .generate(async { Err::<(), _>(provider_error("HTTP 429 from x: rate limit")) })
+            .await
+            .unwrap();
+        assert_eq!(output, None);
+        assert!(status.status().has(Condition::RateLimited));
+
+        let err = notices
+            .generate(async { Err::<(), _>(provider_error("HTTP 400 from x: bad request")) })
+            .await;
+        assert!(err.is_err());
+        assert!(status.status().has(Condition::RateLimited));
+
+        let output = notices.generate(async { Ok(1) }).await.unwrap();
+        assert_eq!(output, Some(1));
+        assert!(status.status().is_healthy());
+
+        // Nothing is generated on a spent budget
+        status.set(Condition::OverBudget, true);
+        let generated = AtomicBool::new(false);
+        let output = notices
+            .generate(async {
+                generated.store(true, Ordering::SeqCst);
+                Ok(())
+            })
+            .await
+            .unwrap();
+        assert_eq!(output, None);
+        assert!(!generated.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,30 @@
+use std::collections::HashSet;
+
+/// What a user may ask the agent to do, ordered from least to most trusted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PermissionTier {
+    User,
+    /// May trigger actions with effects outside the chat, such as posting.
+    Trusted,
+    /// May also run admin commands.
+    Admin,
+}
+
+/// User ids per tier. Admins are implicitly trusted.
+#[derive(Clone, Debug, Default)]
+pub struct Permissions {
+    pub admins: HashSet<String>,
+    pub trusted: HashSet<String>,
+}
+
+impl Permissions {
+    pub fn tier(&self, user_id: &str) -> PermissionTier {
+        if self.admins.contains(user_id) {
+            PermissionTier::Admin
+        } else if self.trusted.contains(user_id) {
+            PermissionTier::Trusted
+        } else {
+            PermissionTier::User
+        }
+    }
+}
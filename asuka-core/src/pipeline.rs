@@ -0,0 +1,2205 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use rig::{
+    agent::AgentBuilder,
+    completion::{Completion, CompletionModel, Prompt, PromptError},
+    embeddings::EmbeddingModel,
+    tool::Tool,
+};
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::{sync::Notify, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info};
+
+use crate::{
+    agent::Agent,
+    attachments,
+    attention::{
+        Assessment, Attention, AttentionCommand, AttentionContext, ReplyRoute, ResponseMode,
+        RECENT_REPLIES,
+    },
+    capabilities::ToolCapability,
+    clients::{
+        describe_capabilities::{self, DescribeCapabilities},
+        escalate::{self, Escalate},
+        external::{Client, ClientContext, IncomingMessage, RepliedMessage},
+        post_tweet::{self, PostTweet, TweetPoster},
+        reactions::{self, ReactionConfig, ReactionError, ReactionTarget, ReplyOutcome},
+        recorded_tool::RecordedTool,
+        refresh_knowledge::{self, RefreshKnowledge},
+        streaming::{self, ReplySink, StreamingCompletion, StreamingConfig, StreamingError},
+    },
+    commands::Command,
+    confidence::ConfidenceOutcome,
+    corrections,
+    escalation::{self, EscalationConfig, EscalationReason, EscalationRequest, Escalator},
+    familiarity::Familiarity,
+    history::truncate,
+    hooks::{MessageContext, ResponseDraft},
+    knowledge::{ChannelType, KnowledgeBase, Message, RateEvent, RefreshRegistry},
+    onboarding::OnboardingStep,
+    outage::OutageNotices,
+    permissions::PermissionTier,
+    rate_limit::{self, RateDecision, RateLimitConfig, RateLimiter},
+    research::{self, ResearchConfig, Researcher},
+    rewrite::RetrievalQuery,
+    templates,
+    tools::{self, ToolGuard},
+};
+
+/// Deferred requests answered per [Pipeline::follow_up_deferred].
+const FOLLOW_UP_BATCH: usize = 16;
+
+/// Characters of a deferred request quoted in its follow-up.
+const FOLLOW_UP_QUESTION_CHARS: usize = 80;
+
+/// `[batching]` settings of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatchConfig {
+    /// Milliseconds to wait for follow-up messages; each new message restarts
+    /// the wait.
+    pub window_ms: u64,
+    /// Answer straight away when the bot is mentioned by name.
+    pub flush_on_mention: bool,
+    /// Answer straight away when a message ends with a question mark.
+    pub flush_on_question: bool,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            window_ms: 4000,
+            flush_on_mention: true,
+            flush_on_question: true,
+        }
+    }
+}
+
+impl BatchConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.window_ms == 0 {
+            return Err("batching.window_ms must be positive".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn window(&self) -> Duration {
+        Duration::from_millis(self.window_ms)
+    }
+
+    pub fn should_flush(&self, content: &str, mentioned: bool) -> bool {
+        (self.flush_on_mention && mentioned)
+            || (self.flush_on_question && content.trim_end().ends_with('?'))
+    }
+}
+
+/// Messages from one user in one channel that are answered together.
+#[derive(Debug, Clone)]
+pub struct Batch {
+    pub messages: Vec<Message>,
+    /// Whether any message in the batch mentioned the bot directly.
+    pub mentioned: bool,
+}
+
+impl Batch {
+    pub fn content(&self) -> String {
+        self.messages
+            .iter()
+            .map(|message| message.content.as_str())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    pub fn message_ids(&self) -> Vec<String> {
+        self.messages
+            .iter()
+            .map(|message| message.id.clone())
+            .collect()
+    }
+}
+
+type BatchKey = (String, String);
+
+struct Window {
+    messages: Vec<Message>,
+    generation: u64,
+    mentioned: bool,
+    flush: bool,
+    wake: Arc<Notify>,
+}
+
+/// Closes the window of a [Debouncer::push] that owns it once that push
+/// stops waiting, also when its future is dropped half way. A window that was
+/// taken already, or replaced by a later owner's, is left alone.
+struct WindowGuard<'a> {
+    windows: &'a Mutex<HashMap<BatchKey, Window>>,
+    key: BatchKey,
+    wake: Arc<Notify>,
+}
+
+impl Drop for WindowGuard<'_> {
+    fn drop(&mut self) {
+        let Ok(mut windows) = self.windows.lock() else {
+            return;
+        };
+        let owned = windows
+            .get(&self.key)
+            .is_some_and(|window| Arc::ptr_eq(&window.wake, &self.wake));
+        if owned {
+            windows.remove(&self.key);
+        }
+    }
+}
+
+/// Collects rapid-fire messages per `(channel, user)` so they are evaluated
+/// and answered once.
+///
+/// The first message of a window makes its caller the window's owner: its
+/// [Debouncer::push] waits until the window goes quiet (or is flushed) and
+/// returns the whole batch. Callers pushing later messages into an open window
+/// get `None` back straight away.
+#[derive(Clone)]
+pub struct Debouncer {
+    config: BatchConfig,
+    windows: Arc<Mutex<HashMap<BatchKey, Window>>>,
+    shutdown: Arc<Notify>,
+    closed: Arc<AtomicBool>,
+}
+
+impl Debouncer {
+    pub fn new(config: BatchConfig) -> Self {
+        Self {
+            config,
+            windows: Arc::new(Mutex::new(HashMap::new())),
+            shutdown: Arc::new(Notify::new()),
+            closed: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn config(&self) -> &BatchConfig {
+        &self.config
+    }
+
+    /// Adds a message to its window. `mentioned` marks a direct mention of the
+    /// bot, which may flush the window early depending on the config.
+    pub async fn push(&self, message: Message, mentioned: bool) -> Option<Batch> {
+        if self.closed.load(Ordering::SeqCst) {
+            return None;
+        }
+
+        let flush = self.config.should_flush(&message.content, mentioned);
+
+        let key = (message.channel_id.clone(), message.account_id.clone());
+        let wake = {
+            let mut windows = self.windows.lock().unwrap();
+            if let Some(window) = windows.get_mut(&key) {
+                window.messages.push(message);
+                window.generation += 1;
+                window.mentioned |= mentioned;
+                window.flush |= flush;
+                window.wake.notify_one();
+                return None;
+            }
+
+            let wake = Arc::new(Notify::new());
+            windows.insert(
+                key.clone(),
+                Window {
+                    messages: vec![message],
+                    generation: 0,
+                    mentioned,
+                    flush,
+                    wake: wake.clone(),
+                },
+            );
+            wake
+        };
+        let _guard = WindowGuard {
+            windows: &self.windows,
+            key: key.clone(),
+            wake: wake.clone(),
+        };
+
+        loop {
+            // Register for shutdown before checking the flag so a concurrent
+            // shutdown cannot slip in between.
+            let shutdown = self.shutdown.notified();
+            tokio::pin!(shutdown);
+            shutdown.as_mut().enable();
+            if self.closed.load(Ordering::SeqCst) {
+                return None;
+            }
+
+            let generation = {
+                let windows = self.windows.lock().unwrap();
+                let window = &windows[&key];
+                if window.flush {
+                    drop(windows);
+                    return Some(self.take(&key));
+                }
+                window.generation
+            };
+
+            tokio::select! {
+                _ = tokio::time::sleep(self.config.window()) => {
+                    let quiet = self.windows.lock().unwrap()[&key].generation == generation;
+                    if quiet {
+                        return Some(self.take(&key));
+                    }
+                }
+                // A follow-up restarts the window; a flush is picked up above.
+                _ = wake.notified() => {}
+                _ = &mut shutdown => return None,
+            }
+        }
+    }
+
+    fn take(&self, key: &BatchKey) -> Batch {
+        let window = self.windows.lock().unwrap().remove(key).unwrap();
+        debug!(
+            channel_id = key.0,
+            account_id = key.1,
+            size = window.messages.len(),
+            "Flushing message batch"
+        );
+        Batch {
+            messages: window.messages,
+            mentioned: window.mentioned,
+        }
+    }
+
+    /// Cancels every open window. Their messages stay stored but are not
+    /// answered, and later pushes are ignored.
+    pub fn shutdown(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.shutdown.notify_waiters();
+    }
+}
+
+/// Adds a message to its window with `debouncer`, see [Debouncer::push].
+/// Without one every message is answered on its own.
+pub(crate) async fn batch(
+    debouncer: Option<&Debouncer>,
+    message: &Message,
+    mentioned: bool,
+) -> Option<Batch> {
+    match debouncer {
+        Some(debouncer) => debouncer.push(message.clone(), mentioned).await,
+        None => Some(Batch {
+            messages: vec![message.clone()],
+            mentioned,
+        }),
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum PipelineError {
+    #[error("Failed to store message: {0}")]
+    Store(String),
+    #[error("Failed to generate reply: {0}")]
+    Completion(#[from] PromptError),
+    #[error("Failed to stream reply: {0}")]
+    Streaming(#[from] StreamingError),
+    #[error("Failed to deliver reply: {0}")]
+    Sink(Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// What [Pipeline::handle] did with a message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Handled {
+    /// The message joined a batch that an earlier call answers.
+    Batched,
+    /// The message was stored but not answered.
+    Ignored(Assessment),
+    /// The sender was over the rate limit, so the message was stored but
+    /// not answered, see [crate::rate_limit].
+    Limited,
+    /// The message was answered with a reaction, this emoji.
+    Reacted(String),
+    /// The message was answered, sent as `chunks`. Replies outside an
+    /// answer, such as onboarding, have no interaction.
+    Replied {
+        interaction_id: Option<i64>,
+        chunks: Vec<String>,
+    },
+    /// The provider lacked capacity to answer, see [crate::outage]. `notice`
+    /// is the handle of the notice sent about it, unless the channel was
+    /// told already; `queued` whether the message is answered later.
+    Deferred {
+        notice: Option<String>,
+        queued: bool,
+    },
+}
+
+/// A deferred request answered by [Pipeline::follow_up_deferred].
+#[derive(Debug, Clone, PartialEq)]
+pub struct FollowUp {
+    pub channel_id: String,
+    pub message_id: String,
+    /// Handle of the notice that told the channel about the wait, when the
+    /// request got it.
+    pub notice: Option<String>,
+    pub chunks: Vec<String>,
+}
+
+/// The way from a received message to the bot's reply, taken by the
+/// built-in clients and those outside this crate alike, see
+/// [crate::clients::external].
+#[derive(Clone)]
+pub struct Pipeline<M: CompletionModel, A: CompletionModel, E: EmbeddingModel + 'static> {
+    agent: Agent<M, E>,
+    attention: Attention<A>,
+    debouncer: Option<Debouncer>,
+    outage: Option<OutageNotices>,
+    streaming: Option<(StreamingConfig, Arc<dyn StreamingCompletion>)>,
+    reactions: Option<ReactionConfig>,
+    escalation: Option<EscalationConfig>,
+    rate_limiter: Option<RateLimiter<E>>,
+    research: Option<ResearchConfig>,
+    tweet_poster: Option<Arc<dyn TweetPoster>>,
+    refresh: Option<RefreshRegistry<E>>,
+    shutdown: CancellationToken,
+}
+
+impl<M, A, E> Pipeline<M, A, E>
+where
+    M: CompletionModel + 'static,
+    A: CompletionModel + 'static,
+    E: EmbeddingModel + 'static,
+{
+    /// A pipeline answering each message on its own.
+    pub fn new(agent: Agent<M, E>, attention: Attention<A>) -> Self {
+        let pipeline = Self {
+            agent,
+            attention,
+            debouncer: None,
+            outage: None,
+            streaming: None,
+            reactions: None,
+            escalation: None,
+            rate_limiter: None,
+            research: None,
+            tweet_poster: None,
+            refresh: None,
+            shutdown: CancellationToken::new(),
+        };
+        pipeline.register_tool(
+            DescribeCapabilities::NAME,
+            describe_capabilities::DESCRIPTION,
+            PermissionTier::User,
+        );
+        pipeline
+    }
+
+    /// Answers rapid-fire messages of a user together, see [Debouncer].
+    pub fn with_batching(mut self, config: BatchConfig) -> Self {
+        self.debouncer = Some(Debouncer::new(config));
+        self
+    }
+
+    /// Tells channels when answers are slow or fail for lack of provider
+    /// capacity, and queues those answers, see [crate::outage].
+    pub fn with_outage_notices(mut self, outage: OutageNotices) -> Self {
+        self.outage = Some(outage);
+        self
+    }
+
+    /// Streams answers into a single message that is edited as text arrives,
+    /// using `model` for the completion.
+    pub fn with_streaming(
+        mut self,
+        config: StreamingConfig,
+        model: Arc<dyn StreamingCompletion>,
+    ) -> Self {
+        self.streaming = Some((config, model));
+        self
+    }
+
+    /// Lets the agent answer with a reaction instead of a message, through
+    /// [Client::react]. Streamed answers are always sent as text.
+    pub fn with_reactions(mut self, config: ReactionConfig) -> Self {
+        self.reactions = Some(config);
+        self
+    }
+
+    /// Hands conversations to the support team, for clients with an
+    /// [escalation poster](Client::escalation_poster), see [crate::escalation].
+    pub fn with_escalation(mut self, config: EscalationConfig) -> Self {
+        self.register_tool(
+            Escalate::<M, E>::NAME,
+            escalate::DESCRIPTION,
+            PermissionTier::User,
+        );
+        self.escalation = Some(config);
+        self
+    }
+
+    /// Limits answers per user, see [crate::rate_limit]. Admins are exempt.
+    pub fn with_rate_limit(mut self, config: RateLimitConfig) -> Self {
+        self.agent
+            .update_capabilities(|capabilities| capabilities.with_rate_limit(config.clone()));
+        self.rate_limiter = Some(RateLimiter::new(self.agent.knowledge().clone(), config));
+        self
+    }
+
+    /// Answers `/ask-deep`, and messages to the bot asking it to take the
+    /// time, with deep research, see [Pipeline::research].
+    pub fn with_research(mut self, config: ResearchConfig) -> Self {
+        self.research = Some(config);
+        self
+    }
+
+    /// Lets trusted users have the agent draft and post tweets.
+    pub fn with_tweet_poster(mut self, poster: Arc<dyn TweetPoster>) -> Self {
+        self.register_tool(
+            PostTweet::<M, E>::NAME,
+            post_tweet::DESCRIPTION,
+            PermissionTier::Trusted,
+        );
+        self.tweet_poster = Some(poster);
+        self
+    }
+
+    /// Lets trusted users re-sync the sources of `registry` from chat.
+    pub fn with_refresh_registry(mut self, registry: RefreshRegistry<E>) -> Self {
+        self.register_tool(
+            RefreshKnowledge::<E>::NAME,
+            refresh_knowledge::DESCRIPTION,
+            PermissionTier::Trusted,
+        );
+        self.refresh = Some(registry);
+        self
+    }
+
+    /// Token that stops tools and research once cancelled.
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Lists a tool offered to users of `tier` and up in the agent's
+    /// capabilities, see [crate::capabilities].
+    fn register_tool(&self, name: &str, description: &str, tier: PermissionTier) {
+        self.agent.update_capabilities(|capabilities| {
+            capabilities.with_tool(ToolCapability::new(name, description, tier))
+        });
+    }
+
+    pub fn agent(&self) -> &Agent<M, E> {
+        &self.agent
+    }
+
+    pub fn attention(&self) -> &Attention<A> {
+        &self.attention
+    }
+
+    /// Send-time utilities for messages a client sends on its own.
+    pub fn context(&self) -> ClientContext<'_, M, E> {
+        ClientContext::new(&self.agent)
+    }
+
+    /// Cancels every open batch, see [Debouncer::shutdown].
+    pub fn close_batches(&self) {
+        if let Some(debouncer) = &self.debouncer {
+            debouncer.shutdown();
+        }
+    }
+
+    /// Stores `incoming`, received by `client`, decides whether to answer it
+    /// and sends the answer through the client's sink for its channel. With
+    /// batching, the call that opened a batch answers it once it closes.
+    pub async fn handle<C>(
+        &self,
+        client: &C,
+        incoming: IncomingMessage,
+    ) -> Result<Handled, PipelineError>
+    where
+        C: Client,
+        C::Sink: 'static,
+        <C::Sink as ReplySink>::Handle: ToString,
+    {
+        let knowledge = self.agent.knowledge();
+        let message = incoming.to_message(client.source());
+        let onboarding = self.agent.onboard(&message).await;
+
+        knowledge
+            .create_message(message.clone())
+            .await
+            .map_err(|e| PipelineError::Store(e.to_string()))?;
+        self.agent
+            .conversations()
+            .update(&message.channel_id, |state| state.receive(&message.id))
+            .await;
+        self.agent.observe_language(&message).await;
+
+        if let OnboardingStep::Reply(text) = onboarding {
+            self.settle(&message, &[message.id.clone()], AttentionCommand::Respond)
+                .await;
+            return self.reply(client, &message, None, text).await;
+        }
+
+        // A reply to one of the bot's answers saying it was wrong amends that
+        // answer rather than starting a new one
+        if let Some(replied) = incoming
+            .replied
+            .as_ref()
+            .filter(|_| corrections::is_correction(&message.content))
+        {
+            if let Some(handled) = self.correct(client, &message, replied).await? {
+                return Ok(handled);
+            }
+        }
+
+        let escalator = self.escalator(client);
+        let escalation = EscalationRequest {
+            guild_id: incoming.guild_id.clone(),
+            channel_id: message.channel_id.clone(),
+            account_id: message.account_id.clone(),
+            link: incoming.link.clone().unwrap_or_default(),
+        };
+        if let Some(escalator) = escalator
+            .as_ref()
+            .filter(|_| escalation::wants_human(&message.content))
+        {
+            match escalator
+                .escalate(&escalation, EscalationReason::UserRequest)
+                .await
+            {
+                Ok(outcome) => {
+                    if let Some(text) = escalator.acknowledgement(outcome) {
+                        self.settle(&message, &[message.id.clone()], AttentionCommand::Respond)
+                            .await;
+                        return self.reply(client, &message, None, text).await;
+                    }
+                }
+                Err(err) => error!(?err, "Failed to escalate"),
+            }
+        }
+
+        let mentioned = incoming.mentions_bot
+            || incoming.full_answer
+            || self
+                .attention
+                .config()
+                .names()
+                .find(&message.content, &incoming.mentioned_names)
+                .is_some();
+        let Some(batch) = batch(self.debouncer.as_ref(), &message, mentioned).await else {
+            debug!("Message added to pending batch");
+            return Ok(Handled::Batched);
+        };
+        let content = batch.content();
+        let message_ids = batch.message_ids();
+
+        let rate_decision = self.rate_limit(client, &incoming, &message).await;
+        if let (RateDecision::Suppress, Some(limiter)) = (rate_decision, &self.rate_limiter) {
+            debug!(
+                account_id = message.account_id,
+                "Ignoring message over the rate limit"
+            );
+            limiter
+                .record(
+                    &message.source,
+                    &message.account_id,
+                    RateEvent::Suppressed,
+                    self.agent.clock().now_utc(),
+                )
+                .await;
+            self.settle(&message, &message_ids, AttentionCommand::Ignore)
+                .await;
+            return Ok(Handled::Limited);
+        }
+
+        let context = AttentionContext {
+            message_content: content.clone(),
+            mentioned_names: incoming.mentioned_names.clone(),
+            history: knowledge
+                .channel_messages(
+                    &message.channel_id,
+                    self.attention.config().max_history_messages,
+                )
+                .await
+                .unwrap_or_else(|err| {
+                    error!(?err, "Failed to fetch channel history");
+                    Vec::new()
+                }),
+            recent_replies: knowledge
+                .recent_replies(&message.channel_id, RECENT_REPLIES)
+                .await
+                .unwrap_or_else(|err| {
+                    error!(?err, "Failed to fetch recent replies");
+                    Vec::new()
+                }),
+            channel_id: message.channel_id.clone(),
+            channel_type: message.channel_type.clone(),
+            source: message.source.clone(),
+            topic_match: self.agent.character.topic_match(&content),
+            familiarity: self
+                .agent
+                .familiarity(&message.channel_id, &message.account_id)
+                .await,
+        };
+        debug!(?context, "Attention context");
+
+        // A direct mention anywhere in the batch always gets an answer
+        let mut assessment = if batch.mentioned {
+            self.attention.addressed(&context)
+        } else {
+            self.attention.assess(&context).await
+        };
+        if incoming.full_answer {
+            assessment.mode = ResponseMode::FullAnswer;
+        }
+        self.settle(&message, &message_ids, assessment.decision)
+            .await;
+
+        let reactions = self
+            .reactions
+            .as_ref()
+            .filter(|config| self.streaming.is_none() && config.allows(&message.channel_type));
+        let mode = match assessment.route(reactions, &message.channel_type) {
+            ReplyRoute::Answer(mode) => mode,
+            ReplyRoute::React(emoji) => return self.react(client, &message, &emoji).await,
+            ReplyRoute::Skip => {
+                debug!("Bot decided not to reply to message");
+                return Ok(Handled::Ignored(assessment));
+            }
+        };
+
+        // Don't answer from a knowledge base that is still loading
+        if let Some(notice) = self.agent.warming_notice(&mode, &message).await {
+            return self.reply(client, &message, None, notice).await;
+        }
+
+        if let Some(limiter) = &self.rate_limiter {
+            let now = self.agent.clock().now_utc();
+            if let RateDecision::Notify { retry_after } = rate_decision {
+                let notice = self
+                    .agent
+                    .localized_template(
+                        templates::RATE_LIMITED,
+                        Some(&message.account_id),
+                        &message.channel_id,
+                        &[("retry_after", &rate_limit::retry_after_text(retry_after))],
+                    )
+                    .await;
+                limiter
+                    .record(&message.source, &message.account_id, RateEvent::Notice, now)
+                    .await;
+                return self.reply(client, &message, None, notice).await;
+            }
+            limiter
+                .record(
+                    &message.source,
+                    &message.account_id,
+                    RateEvent::Response,
+                    now,
+                )
+                .await;
+        }
+
+        let assignment = self.agent.assign(
+            incoming.guild_id.as_deref(),
+            &message.channel_id,
+            &message.account_id,
+        );
+        let interaction_id = match knowledge
+            .create_interaction(
+                message.channel_id.clone(),
+                message.account_id.clone(),
+                message_ids,
+            )
+            .await
+        {
+            Ok(id) => Some(id),
+            Err(err) => {
+                error!(?err, "Failed to record interaction");
+                None
+            }
+        };
+        if let (Some(id), Some(assignment)) = (interaction_id, &assignment) {
+            if let Err(err) = knowledge.record_variant(id, assignment).await {
+                error!(?err, "Failed to record experiment variant");
+            }
+        }
+
+        // Quoted from the documents of the last answer, not generated
+        if let Some(quotes) = self
+            .agent
+            .quote_sources(&message.channel_id, &content)
+            .await
+        {
+            let handled = self.reply(client, &message, interaction_id, quotes).await?;
+            self.record_exchange(interaction_id).await;
+            return Ok(handled);
+        }
+
+        // Brief acknowledgements don't draw on the docs
+        let (query, confidence) = if mode == ResponseMode::BriefAck {
+            (RetrievalQuery::raw(&content), None)
+        } else {
+            self.agent
+                .detect_knowledge_gap(&content, &message.channel_id)
+                .await;
+            let query = self
+                .agent
+                .retrieval_query(
+                    &message.channel_id,
+                    &content,
+                    incoming.is_reply,
+                    interaction_id,
+                )
+                .await;
+            let confidence = self
+                .agent
+                .estimate_confidence(query.text(), &message.channel_id)
+                .await;
+            (query, confidence)
+        };
+
+        // Nothing a generated answer could change, so skip generating one
+        if let Some(confidence) = confidence
+            .as_ref()
+            .filter(|confidence| confidence.outcome == ConfidenceOutcome::Declined)
+        {
+            let mut decline = self
+                .agent
+                .apply_confidence(
+                    confidence.clone(),
+                    &content,
+                    String::new(),
+                    &message.channel_id,
+                    interaction_id,
+                )
+                .await;
+            if let Some(escalator) = &escalator {
+                match escalator
+                    .escalate(&escalation, EscalationReason::LowConfidence)
+                    .await
+                {
+                    Ok(outcome) => {
+                        if let Some(ack) = escalator.acknowledgement(outcome) {
+                            decline = format!("{decline}\n\n{ack}");
+                        }
+                    }
+                    Err(err) => error!(?err, "Failed to escalate"),
+                }
+            }
+            return self.reply(client, &message, interaction_id, decline).await;
+        }
+
+        let variant = assignment.map(|assignment| self.agent.for_variant(&assignment));
+        let mut builder = variant
+            .as_ref()
+            .unwrap_or(&self.agent)
+            .clone()
+            .with_source(message.source.clone())
+            .with_familiarity(context.familiarity.clone())
+            .with_interaction(interaction_id)
+            .response_builder_with_query(&message.channel_id, &mode, &content, &query)
+            .await;
+        if let Some(config) = reactions {
+            builder = builder.context(&config.instruction());
+        }
+        for extra in &incoming.context {
+            builder = builder.context(extra);
+        }
+        let tools = AnswerTools {
+            guard: self.agent.tool_guard(self.shutdown.clone()),
+            mode: &mode,
+            knowledge,
+            interaction_id,
+        };
+        let tier = client.tier(&message.account_id);
+        if let Some(poster) = self.tweet_poster.clone() {
+            if tier >= PermissionTier::Trusted {
+                let tool = PostTweet::new(self.agent.clone(), poster, tier);
+                builder = tools.add(builder, tool).await;
+            }
+        }
+        if let Some(registry) = self.refresh.clone() {
+            if tier >= PermissionTier::Trusted {
+                let follow_up = SinkFollowUp {
+                    sink: client.sink(&message.channel_id),
+                };
+                let tool = RefreshKnowledge::new(registry, tier, Arc::new(follow_up));
+                builder = tools.add(builder, tool).await;
+            }
+        }
+        if let Some(escalator) = escalator.filter(|escalator| escalator.covers(&escalation)) {
+            let tool = Escalate::new(escalator, escalation);
+            builder = tools.add(builder, tool).await;
+        }
+        if let Some(tool) = self.agent.describe_capabilities() {
+            builder = tools.add(builder, tool).await;
+        }
+        let responder = builder.build();
+
+        if let Some(streamed) = self.stream(client, &message, &responder, &content).await {
+            let chunks = match streamed {
+                Ok(chunks) => chunks,
+                Err(err) => {
+                    self.apologize(client, &message).await;
+                    return Err(err.into());
+                }
+            };
+            debug!(count = chunks.len(), "Streamed response");
+            self.store_reply(client, &message, chunks.concat()).await;
+            self.record_exchange(interaction_id).await;
+            return Ok(Handled::Replied {
+                interaction_id,
+                chunks,
+            });
+        }
+
+        let generation = responder.prompt(content.as_str());
+        let generated = match &self.outage {
+            Some(outage) => {
+                let generation = outage.notify_if_delayed(
+                    &message.channel_id,
+                    generation,
+                    self.slow_notice(client, &message),
+                );
+                match outage.generate(generation).await {
+                    Ok(Some(response)) => Ok(response),
+                    Ok(None) => {
+                        return self
+                            .defer(client, outage, &message, interaction_id, &content)
+                            .await
+                    }
+                    Err(err) => Err(err),
+                }
+            }
+            None => generation.await,
+        };
+        let response = match generated {
+            Ok(response) => response,
+            Err(err) => {
+                self.apologize(client, &message).await;
+                return Err(err.into());
+            }
+        };
+        let response = self
+            .agent
+            .guard_preamble(&responder, &content, response)
+            .await;
+        debug!(response = %response, "Generated response");
+
+        if let Some(ReplyOutcome::React(emoji)) =
+            reactions.map(|config| config.classify(&response, &message.channel_type))
+        {
+            return self.react(client, &message, &emoji).await;
+        }
+
+        let response = match confidence {
+            Some(confidence) => {
+                self.agent
+                    .apply_confidence(
+                        confidence,
+                        &content,
+                        response,
+                        &message.channel_id,
+                        interaction_id,
+                    )
+                    .await
+            }
+            None => response,
+        };
+        let draft = self
+            .agent
+            .process_response(
+                ResponseDraft::new(response.clone(), message.source.clone()),
+                &MessageContext::from(&message),
+            )
+            .await;
+        let handled = self
+            .deliver(client, &message, interaction_id, response, draft)
+            .await?;
+        self.record_exchange(interaction_id).await;
+        Ok(handled)
+    }
+
+    /// Answers `incoming`, received by `client`, with deep research if it
+    /// runs `/ask-deep` or asks the bot to take its time in a direct message
+    /// or a mention, editing a message with the progress. It counts against
+    /// the rate limit like any answer, but messages over the limit are
+    /// ignored rather than answered with a notice. `None` for any other
+    /// message, or without research configured, see [Pipeline::with_research].
+    ///
+    /// Runs before a client's [commands](crate::commands), which would
+    /// answer `/ask-deep` as unsupported.
+    pub async fn research<C>(
+        &self,
+        client: &C,
+        incoming: &IncomingMessage,
+    ) -> Result<Option<Handled>, PipelineError>
+    where
+        C: Client,
+        <C::Sink as ReplySink>::Handle: ToString,
+    {
+        let (Some(config), Some(question)) = (&self.research, deep_question(incoming)) else {
+            return Ok(None);
+        };
+        let knowledge = self.agent.knowledge();
+        let message = incoming.to_message(client.source());
+        knowledge
+            .create_message(message.clone())
+            .await
+            .map_err(|e| PipelineError::Store(e.to_string()))?;
+
+        if let Some(limiter) = &self.rate_limiter {
+            let event = match self.rate_limit(client, incoming, &message).await {
+                RateDecision::Allow => RateEvent::Response,
+                RateDecision::Notify { .. } | RateDecision::Suppress => RateEvent::Suppressed,
+            };
+            limiter
+                .record(
+                    &message.source,
+                    &message.account_id,
+                    event,
+                    self.agent.clock().now_utc(),
+                )
+                .await;
+            if event == RateEvent::Suppressed {
+                debug!(
+                    account_id = message.account_id,
+                    "Ignoring deep research over the rate limit"
+                );
+                return Ok(Some(Handled::Limited));
+            }
+        }
+
+        let interaction_id = match knowledge
+            .create_interaction(
+                message.channel_id.clone(),
+                message.account_id.clone(),
+                vec![message.id.clone()],
+            )
+            .await
+        {
+            Ok(id) => Some(id),
+            Err(err) => {
+                error!(?err, "Failed to record interaction");
+                None
+            }
+        };
+        let researcher = Researcher::new(self.agent.clone(), config.clone())
+            .with_shutdown(self.shutdown.child_token());
+        let research = researcher
+            .research(&question, interaction_id, &client.sink(&message.channel_id))
+            .await;
+        let reply = researcher.reply(&research);
+        self.reply(client, &message, interaction_id, reply)
+            .await
+            .map(Some)
+    }
+
+    /// Answers deferred requests received by `client` while the provider
+    /// lacked capacity, see [crate::outage], the longest waiting first. Stops
+    /// when it still lacks capacity; requests failing otherwise are dropped.
+    pub async fn follow_up_deferred<C>(&self, client: &C) -> Result<Vec<FollowUp>, PipelineError>
+    where
+        C: Client,
+        <C::Sink as ReplySink>::Handle: ToString,
+    {
+        let Some(outage) = &self.outage else {
+            return Ok(Vec::new());
+        };
+        let knowledge = self.agent.knowledge();
+        let deferred = knowledge
+            .deferred_replies(&client.source(), FOLLOW_UP_BATCH)
+            .await
+            .map_err(|e| PipelineError::Store(e.to_string()))?;
+
+        let mut follow_ups = Vec::new();
+        for deferred in deferred {
+            let message = &deferred.message;
+            let familiarity = self
+                .agent
+                .familiarity(&message.channel_id, &message.account_id)
+                .await;
+            let generation = self.answer(
+                message,
+                &deferred.request,
+                deferred.interaction_id,
+                familiarity,
+            );
+            let response = match outage.generate(generation).await {
+                Ok(Some(response)) => response,
+                Ok(None) => break,
+                Err(err) => {
+                    error!(?err, message_id = %message.id, "Failed to answer deferred request");
+                    if let Err(err) = knowledge
+                        .remove_deferred(&message.channel_id, &message.id)
+                        .await
+                    {
+                        error!(?err, "Failed to remove deferred request");
+                    }
+                    continue;
+                }
+            };
+
+            let question = truncate(&deferred.request, FOLLOW_UP_QUESTION_CHARS);
+            let intro = self
+                .agent
+                .localized_template(
+                    templates::PROVIDER_FOLLOW_UP,
+                    Some(&message.account_id),
+                    &message.channel_id,
+                    &[("question", question.as_str())],
+                )
+                .await;
+            let handled = self
+                .reply(
+                    client,
+                    message,
+                    deferred.interaction_id,
+                    format!("{intro}\n\n{response}"),
+                )
+                .await?;
+            if let Err(err) = knowledge
+                .remove_deferred(&message.channel_id, &message.id)
+                .await
+            {
+                error!(?err, "Failed to remove deferred request");
+            }
+            self.record_exchange(deferred.interaction_id).await;
+            if let Handled::Replied { chunks, .. } = handled {
+                follow_ups.push(FollowUp {
+                    channel_id: message.channel_id.clone(),
+                    message_id: message.id.clone(),
+                    notice: deferred.notice_id.clone(),
+                    chunks,
+                });
+            }
+        }
+        Ok(follow_ups)
+    }
+
+    /// Runs [Pipeline::follow_up_deferred] for `client` on every change of
+    /// status and every [retry interval](crate::outage::OutageConfig::retry_interval_secs)
+    /// until `shutdown` is cancelled. `None` without outage notices.
+    pub fn spawn_follow_ups<C>(
+        &self,
+        client: C,
+        shutdown: CancellationToken,
+    ) -> Option<JoinHandle<()>>
+    where
+        C: Client + 'static,
+        <C::Sink as ReplySink>::Handle: ToString,
+    {
+        let outage = self.outage.clone()?;
+        let pipeline = self.clone();
+        Some(tokio::spawn(async move {
+            let mut status = outage.status().subscribe();
+            loop {
+                tokio::select! {
+                    _ = shutdown.cancelled() => return,
+                    _ = status.changed() => {}
+                    _ = tokio::time::sleep(outage.config().retry_interval()) => {}
+                }
+                match pipeline.follow_up_deferred(&client).await {
+                    Ok(follow_ups) if !follow_ups.is_empty() => {
+                        info!(count = follow_ups.len(), "Answered deferred requests");
+                    }
+                    Ok(_) => {}
+                    Err(err) => error!(?err, "Failed to answer deferred requests"),
+                }
+            }
+        }))
+    }
+
+    /// Generates the full answer to `content`, a deferred request ending
+    /// with `message`.
+    async fn answer(
+        &self,
+        message: &Message,
+        content: &str,
+        interaction_id: Option<i64>,
+        familiarity: Option<Familiarity>,
+    ) -> Result<String, PromptError> {
+        let query = self
+            .agent
+            .retrieval_query(&message.channel_id, content, false, interaction_id)
+            .await;
+        let responder = self
+            .agent
+            .clone()
+            .with_source(message.source.clone())
+            .with_familiarity(familiarity)
+            .with_interaction(interaction_id)
+            .response_builder_with_query(
+                &message.channel_id,
+                &ResponseMode::FullAnswer,
+                content,
+                &query,
+            )
+            .await
+            .build();
+        let response = responder.prompt(content).await?;
+        let response = self
+            .agent
+            .guard_preamble(&responder, content, response)
+            .await;
+        Ok(self
+            .agent
+            .process_response(
+                ResponseDraft::new(response, message.source.clone()),
+                &MessageContext::from(message),
+            )
+            .await
+            .text)
+    }
+
+    /// Amends the bot's answer `replied`, which `message` says was wrong,
+    /// see [crate::corrections]. Where the client can edit the answer, it is
+    /// amended and the user thanked; otherwise the correction is sent on its
+    /// own. `None` when there is nothing to correct.
+    async fn correct<C>(
+        &self,
+        client: &C,
+        message: &Message,
+        replied: &RepliedMessage,
+    ) -> Result<Option<Handled>, PipelineError>
+    where
+        C: Client,
+        <C::Sink as ReplySink>::Handle: ToString,
+    {
+        let Some(notice) = self
+            .agent
+            .correct_answer(&message.channel_id, &replied.content, &message.content)
+            .await
+        else {
+            return Ok(None);
+        };
+        self.settle(message, &[message.id.clone()], AttentionCommand::Respond)
+            .await;
+
+        match self
+            .agent
+            .knowledge()
+            .correct_reply(&message.source, &replied.id, &notice)
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => debug!("Corrected answer isn't stored"),
+            Err(err) => error!(?err, "Failed to store correction"),
+        }
+        let reply = if client
+            .amend(&message.channel_id, &replied.id, &replied.content, &notice)
+            .await
+        {
+            self.agent
+                .localized_template(
+                    templates::CORRECTION_ACK,
+                    Some(&message.account_id),
+                    &message.channel_id,
+                    &[],
+                )
+                .await
+        } else {
+            notice
+        };
+        self.reply(client, message, None, reply).await.map(Some)
+    }
+
+    /// Escalator posting through `client`, when escalation is configured and
+    /// the client can post escalations.
+    fn escalator<C: Client>(&self, client: &C) -> Option<Escalator<M, E>> {
+        let config = self.escalation.clone()?;
+        Some(Escalator::new(
+            self.agent.clone(),
+            config,
+            client.escalation_poster()?,
+        ))
+    }
+
+    /// Rate limit decision on `message`, always [RateDecision::Allow] for
+    /// admins and without a limit configured.
+    async fn rate_limit<C: Client>(
+        &self,
+        client: &C,
+        incoming: &IncomingMessage,
+        message: &Message,
+    ) -> RateDecision {
+        let Some(limiter) = &self.rate_limiter else {
+            return RateDecision::Allow;
+        };
+        if client.tier(&message.account_id) >= PermissionTier::Admin {
+            return RateDecision::Allow;
+        }
+        limiter
+            .check(
+                &message.source,
+                &message.account_id,
+                &incoming.roles,
+                self.agent.clock().now_utc(),
+            )
+            .await
+    }
+
+    /// Records `decision` on the messages `message_ids` in the channel of
+    /// `message`.
+    async fn settle(&self, message: &Message, message_ids: &[String], decision: AttentionCommand) {
+        self.agent
+            .conversations()
+            .update(&message.channel_id, |state| {
+                state.settle(message_ids, decision)
+            })
+            .await;
+    }
+
+    /// Reacts to `message` with `emoji`, or replies with it where the client
+    /// can't react, and stores what was sent.
+    async fn react<C: Client>(
+        &self,
+        client: &C,
+        message: &Message,
+        emoji: &str,
+    ) -> Result<Handled, PipelineError> {
+        let target = ClientTarget { client, message };
+        let outcome = reactions::react_or_reply(&target, emoji)
+            .await
+            .map_err(|err| PipelineError::Sink(Box::new(err)))?;
+        let handled = match &outcome {
+            ReplyOutcome::React(emoji) => Handled::Reacted(emoji.clone()),
+            ReplyOutcome::Reply(text) => Handled::Replied {
+                interaction_id: None,
+                chunks: vec![text.clone()],
+            },
+        };
+        if let Err(err) = self
+            .agent
+            .knowledge()
+            .create_message(outcome.to_message(message, &client.account_id()))
+            .await
+        {
+            error!(?err, "Failed to store reaction");
+        }
+        Ok(handled)
+    }
+
+    /// Streams the answer of `responder` to `prompt` into the channel of
+    /// `message`. `None` when the stream could not be opened, in which case
+    /// nothing was sent yet.
+    async fn stream<C: Client>(
+        &self,
+        client: &C,
+        message: &Message,
+        responder: &rig::agent::Agent<M>,
+        prompt: &str,
+    ) -> Option<Result<Vec<String>, StreamingError>> {
+        let (config, model) = self.streaming.as_ref()?;
+
+        let request = match responder.completion(prompt, vec![]).await {
+            Ok(builder) => builder.build(),
+            Err(err) => return Some(Err(err.into())),
+        };
+        let deltas = match model.stream(request).await {
+            Ok(deltas) => deltas,
+            Err(err) => {
+                debug!(?err, "Streaming unavailable, sending the reply in chunks");
+                return None;
+            }
+        };
+        let sink = client.sink(&message.channel_id);
+        Some(streaming::stream_reply(&sink, deltas, config).await)
+    }
+
+    /// Queues `content`, the batch ending with `message`, to be answered once
+    /// the provider recovers, and tells its channel unless it was told in
+    /// the window.
+    async fn defer<C>(
+        &self,
+        client: &C,
+        outage: &OutageNotices,
+        message: &Message,
+        interaction_id: Option<i64>,
+        content: &str,
+    ) -> Result<Handled, PipelineError>
+    where
+        C: Client,
+        <C::Sink as ReplySink>::Handle: ToString,
+    {
+        let knowledge = self.agent.knowledge();
+        let config = outage.config();
+        let queued = config.follow_up
+            && knowledge
+                .defer_reply(
+                    message,
+                    content,
+                    interaction_id,
+                    config.max_deferred_per_channel,
+                )
+                .await
+                .unwrap_or_else(|err| {
+                    error!(?err, "Failed to queue deferred request");
+                    false
+                });
+        if !outage.claim(&message.channel_id) {
+            return Ok(Handled::Deferred {
+                notice: None,
+                queued,
+            });
+        }
+
+        let template = if outage.is_over_budget() {
+            templates::OVER_BUDGET
+        } else if queued {
+            templates::PROVIDER_DEFERRED
+        } else {
+            templates::PROVIDER_SLOW
+        };
+        let text = self
+            .agent
+            .localized_template(
+                template,
+                Some(&message.account_id),
+                &message.channel_id,
+                &[],
+            )
+            .await;
+        let notice = self.send_notice(client, message, text).await?;
+        if queued {
+            if let Err(err) = knowledge
+                .set_deferred_notice(&message.channel_id, &message.id, &notice)
+                .await
+            {
+                error!(?err, "Failed to link notice to deferred request");
+            }
+        }
+        Ok(Handled::Deferred {
+            notice: Some(notice),
+            queued,
+        })
+    }
+
+    /// Tells the channel of `message` that answers are slow, sent while its
+    /// answer is still being generated.
+    async fn slow_notice<C>(&self, client: &C, message: &Message)
+    where
+        C: Client,
+        <C::Sink as ReplySink>::Handle: ToString,
+    {
+        let text = self
+            .agent
+            .localized_template(
+                templates::PROVIDER_SLOW,
+                Some(&message.account_id),
+                &message.channel_id,
+                &[],
+            )
+            .await;
+        if let Err(err) = self.send_notice(client, message, text).await {
+            error!(?err, "Failed to send outage notice");
+        }
+    }
+
+    /// Sends `text`, a notice about `message`, and stores it. Returns the
+    /// handle it was sent as.
+    async fn send_notice<C>(
+        &self,
+        client: &C,
+        message: &Message,
+        text: String,
+    ) -> Result<String, PipelineError>
+    where
+        C: Client,
+        <C::Sink as ReplySink>::Handle: ToString,
+    {
+        let handle = client
+            .sink(&message.channel_id)
+            .send(&text)
+            .await
+            .map_err(|err| PipelineError::Sink(Box::new(err)))?;
+        // Apart from the reply to the message, which may follow
+        let notice = Message {
+            id: format!("{}:notice", message.id),
+            ..ReplyOutcome::Reply(text).to_message(message, &client.account_id())
+        };
+        if let Err(err) = self.agent.knowledge().create_message(notice).await {
+            error!(?err, "Failed to store notice");
+        }
+        Ok(handle.to_string())
+    }
+
+    /// Marks `interaction_id` as an exchange a later question may lean on,
+    /// see [crate::knowledge::KnowledgeBase::record_exchange].
+    async fn record_exchange(&self, interaction_id: Option<i64>) {
+        let Some(interaction_id) = interaction_id else {
+            return;
+        };
+        if let Err(err) = self.agent.knowledge().record_exchange(interaction_id).await {
+            error!(?err, "Failed to record exchange");
+        }
+    }
+
+    /// Tells the channel of `message` that its answer failed.
+    async fn apologize<C: Client>(&self, client: &C, message: &Message) {
+        let apology = self
+            .agent
+            .localized_template(
+                templates::ERROR_GENERIC,
+                Some(&message.account_id),
+                &message.channel_id,
+                &[],
+            )
+            .await;
+        if let Err(err) = client.sink(&message.channel_id).send(&apology).await {
+            error!(?err, "Failed to send message");
+        }
+    }
+
+    /// Stores a sent reply, so later attention decisions can see it.
+    async fn store_reply<C: Client>(&self, client: &C, message: &Message, text: String) {
+        let record = ReplyOutcome::Reply(text).to_message(message, &client.account_id());
+        if let Err(err) = self.agent.knowledge().create_message(record).await {
+            error!(?err, "Failed to store reply");
+        }
+    }
+
+    /// Sends `text` in reply to `message` in chunks, records the messages it
+    /// was sent as for `interaction_id` and stores the reply.
+    async fn reply<C>(
+        &self,
+        client: &C,
+        message: &Message,
+        interaction_id: Option<i64>,
+        text: String,
+    ) -> Result<Handled, PipelineError>
+    where
+        C: Client,
+        <C::Sink as ReplySink>::Handle: ToString,
+    {
+        let draft = ResponseDraft::new(text.clone(), message.source.clone());
+        self.deliver(client, message, interaction_id, text, draft)
+            .await
+    }
+
+    /// Sends `draft` in reply to `message`, in chunks and then its files,
+    /// records the messages it was sent as for `interaction_id` and stores
+    /// `text`, or what users saw of it when sending stopped halfway.
+    async fn deliver<C>(
+        &self,
+        client: &C,
+        message: &Message,
+        interaction_id: Option<i64>,
+        text: String,
+        draft: ResponseDraft,
+    ) -> Result<Handled, PipelineError>
+    where
+        C: Client,
+        <C::Sink as ReplySink>::Handle: ToString,
+    {
+        let context = self.context();
+        let sink = client.formatted_sink(&message.channel_id, &draft.metadata);
+
+        let chunks = context.chunk(&draft.text, client.max_message_length());
+        let mut sent = Vec::new();
+        let mut failure = None;
+        for chunk in &chunks {
+            match sink.send(chunk).await {
+                Ok(handle) => sent.push(handle.to_string()),
+                Err(err) => {
+                    failure = Some(PipelineError::Sink(Box::new(err)));
+                    break;
+                }
+            }
+        }
+        let delivered = sent.len();
+
+        // Files go out once the whole text did
+        let mut files = None;
+        if failure.is_none() && !draft.attachments.is_empty() {
+            match sink.send_files(&draft.attachments).await {
+                Ok(handles) => {
+                    sent.extend(handles.iter().map(ToString::to_string));
+                    files = attachments::describe(&draft.attachments);
+                }
+                Err(err) => error!(%err, "Failed to send attachments"),
+            }
+        }
+
+        // The reply is out, so failing to store it only loses bookkeeping
+        if let (Some(interaction_id), false) = (interaction_id, sent.is_empty()) {
+            if let Err(err) = context
+                .record_sent(interaction_id, &message.source, &message.channel_id, &sent)
+                .await
+            {
+                error!(?err, "Failed to record sent messages");
+            }
+        }
+        // Only what users saw of an aborted reply is remembered
+        let stored = match (&failure, files) {
+            (None, Some(names)) => Some(format!("{text}\n\n{names}")),
+            (None, None) => Some(text),
+            (Some(_), _) if delivered == 0 => None,
+            (Some(_), _) => Some(chunks[..delivered].join("\n")),
+        };
+        if let Some(stored) = stored {
+            self.store_reply(client, message, stored).await;
+        }
+
+        match failure {
+            Some(err) => Err(err),
+            None => Ok(Handled::Replied {
+                interaction_id,
+                chunks,
+            }),
+        }
+    }
+}
+
+/// The question of `/ask-deep`, or of a direct message or mention asking the
+/// bot to take its time.
+fn deep_question(incoming: &IncomingMessage) -> Option<String> {
+    match Command::parse(&incoming.content) {
+        Some(Ok(Command::AskDeep { question })) => Some(question),
+        None if (incoming.channel_type == ChannelType::DirectMessage || incoming.mentions_bot)
+            && research::asks_for_depth(&incoming.content) =>
+        {
+            Some(incoming.content.clone())
+        }
+        _ => None,
+    }
+}
+
+/// The tools of one answer, guarded against running past shutdown and
+/// recorded with its interaction.
+struct AnswerTools<'a, E: EmbeddingModel + 'static> {
+    guard: ToolGuard,
+    mode: &'a ResponseMode,
+    knowledge: &'a KnowledgeBase<E>,
+    interaction_id: Option<i64>,
+}
+
+impl<E: EmbeddingModel + 'static> AnswerTools<'_, E> {
+    /// Adds `tool` to `builder`, stressed in the preamble when the mode
+    /// expects it, see [tools::emphasis].
+    async fn add<M, T>(&self, mut builder: AgentBuilder<M>, tool: T) -> AgentBuilder<M>
+    where
+        M: CompletionModel,
+        T: Tool + 'static,
+    {
+        let tool = self.guard.wrap(tool);
+        if let ResponseMode::ToolLikely(hint) = self.mode {
+            if let Some(emphasis) = tools::emphasis(&tool, hint).await {
+                builder = builder.context(&emphasis);
+            }
+        }
+        match self.interaction_id {
+            Some(id) => builder.tool(RecordedTool::new(tool, self.knowledge.clone(), id)),
+            None => builder.tool(tool),
+        }
+    }
+}
+
+/// The message being answered, reacted to through its client.
+struct ClientTarget<'a, C> {
+    client: &'a C,
+    message: &'a Message,
+}
+
+#[async_trait]
+impl<C: Client> ReactionTarget for ClientTarget<'_, C> {
+    async fn react(&self, emoji: &str) -> Result<(), ReactionError> {
+        self.client
+            .react(&self.message.channel_id, &self.message.id, emoji)
+            .await
+    }
+
+    async fn reply(&self, text: &str) -> Result<(), ReactionError> {
+        self.client
+            .sink(&self.message.channel_id)
+            .send(text)
+            .await
+            .map(|_| ())
+            .map_err(|e| ReactionError::Failed(Box::new(e)))
+    }
+}
+
+/// Posts the outcome of a slow knowledge refresh in the channel it was
+/// requested from.
+struct SinkFollowUp<S> {
+    sink: S,
+}
+
+#[async_trait]
+impl<S: ReplySink> refresh_knowledge::FollowUp for SinkFollowUp<S> {
+    async fn send(&self, text: String) {
+        if let Err(err) = self.sink.send(&text).await {
+            error!(?err, "Failed to send follow-up");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        attention::AttentionConfig,
+        knowledge::{ChannelType, RetrievedChunk, Source},
+        memory::MemoryConfig,
+        outage::OutageConfig,
+        prompt::Retriever,
+        rewrite::{ModelRewriter, RewriteConfig},
+        status::{Condition, StatusBoard},
+        test_utils::{self, ScriptedCompletionModel},
+    };
+    use async_trait::async_trait;
+    use rig::vector_store::VectorStoreError;
+    use tokio::time::Instant;
+
+    fn message(account_id: &str, content: &str) -> Message {
+        Message {
+            id: format!("{account_id}-{content}"),
+            source: Source::Discord,
+            source_id: account_id.to_string(),
+            channel_type: ChannelType::Text,
+            channel_id: "channel".to_string(),
+            account_id: account_id.to_string(),
+            role: "user".to_string(),
+            content: content.to_string(),
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    fn spawn_owner(
+        debouncer: &Debouncer,
+        message: Message,
+        mentioned: bool,
+    ) -> tokio::task::JoinHandle<(Option<Batch>, Duration)> {
+        let debouncer = debouncer.clone();
+        let start = Instant::now();
+        tokio::spawn(async move {
+            let batch = debouncer.push(message, mentioned).await;
+            (batch, start.elapsed())
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_follow_ups_are_batched() {
+        let debouncer = Debouncer::new(BatchConfig::default());
+        let owner = spawn_owner(&debouncer, message("alice", "hey"), false);
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(debouncer
+            .push(message("alice", "quick question"), false)
+            .await
+            .is_none());
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert!(debouncer
+            .push(message("alice", "how do I set up VRF"), false)
+            .await
+            .is_none());
+
+        let (batch, elapsed) = owner.await.unwrap();
+        let batch = batch.unwrap();
+        assert_eq!(batch.content(), "hey\nquick question\nhow do I set up VRF");
+        // The window restarted with each follow-up.
+        assert_eq!(elapsed, Duration::from_secs(8));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_question_and_mention_flush_immediately() {
+        let debouncer = Debouncer::new(BatchConfig::default());
+        let owner = spawn_owner(&debouncer, message("alice", "hey"), false);
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(debouncer
+            .push(message("alice", "how do I set up VRF?"), false)
+            .await
+            .is_none());
+
+        let (batch, elapsed) = owner.await.unwrap();
+        let batch = batch.unwrap();
+        assert_eq!(batch.messages.len(), 2);
+        assert!(!batch.mentioned);
+        assert_eq!(elapsed, Duration::from_secs(1));
+
+        let (batch, elapsed) = spawn_owner(&debouncer, message("alice", "shinobi"), true)
+            .await
+            .unwrap();
+        assert!(batch.unwrap().mentioned);
+        assert_eq!(elapsed, Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_users_are_batched_independently() {
+        let debouncer = Debouncer::new(BatchConfig::default());
+        let alice = spawn_owner(&debouncer, message("alice", "hey"), false);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let bob = spawn_owner(&debouncer, message("bob", "hello"), false);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(debouncer
+            .push(message("alice", "anyone here"), false)
+            .await
+            .is_none());
+
+        let (alice, alice_elapsed) = alice.await.unwrap();
+        let (bob, bob_elapsed) = bob.await.unwrap();
+        assert_eq!(alice.unwrap().content(), "hey\nanyone here");
+        assert_eq!(bob.unwrap().content(), "hello");
+        assert_eq!(alice_elapsed, Duration::from_secs(6));
+        assert_eq!(bob_elapsed, Duration::from_secs(4));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shutdown_cancels_open_windows() {
+        let debouncer = Debouncer::new(BatchConfig::default());
+        let owner = spawn_owner(&debouncer, message("alice", "hey"), false);
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        debouncer.shutdown();
+
+        let (batch, elapsed) = owner.await.unwrap();
+        assert!(batch.is_none());
+        assert_eq!(elapsed, Duration::from_secs(1));
+        assert!(debouncer.push(message("bob", "hi"), true).await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_dropped_owner_closes_its_window() {
+        let debouncer = Debouncer::new(BatchConfig::default());
+        let owner = spawn_owner(&debouncer, message("alice", "hey"), false);
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        owner.abort();
+        assert!(owner.await.unwrap_err().is_cancelled());
+
+        // The next message opens a window of its own instead of joining one
+        // nobody answers.
+        let (batch, elapsed) = spawn_owner(&debouncer, message("alice", "anyone here"), false)
+            .await
+            .unwrap();
+        assert_eq!(batch.unwrap().content(), "anyone here");
+        assert_eq!(elapsed, Duration::from_secs(4));
+    }
+
+    /// A platform without a client in this crate, numbering what it sends.
+    #[derive(Clone, Default)]
+    struct Chat {
+        sent: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl ReplySink for Chat {
+        type Handle = String;
+        type Error = std::io::Error;
+
+        async fn send(&self, text: &str) -> Result<String, std::io::Error> {
+            let mut sent = self.sent.lock().unwrap();
+            sent.push(text.to_string());
+            Ok(format!("wa-{}", sent.len()))
+        }
+
+        async fn edit(&self, _handle: &String, _text: &str) -> Result<(), std::io::Error> {
+            Ok(())
+        }
+    }
+
+    impl Client for Chat {
+        type Sink = Chat;
+
+        fn source(&self) -> Source {
+            Source::Other("whatsapp".to_string())
+        }
+
+        fn account_id(&self) -> String {
+            "bot".to_string()
+        }
+
+        fn max_message_length(&self) -> usize {
+            4096
+        }
+
+        fn sink(&self, _channel_id: &str) -> Chat {
+            self.clone()
+        }
+    }
+
+    /// A platform the bot lost its connection to.
+    struct Offline;
+
+    #[async_trait]
+    impl ReplySink for Offline {
+        type Handle = String;
+        type Error = std::io::Error;
+
+        async fn send(&self, _text: &str) -> Result<String, std::io::Error> {
+            Err(std::io::Error::other("offline"))
+        }
+
+        async fn edit(&self, _handle: &String, _text: &str) -> Result<(), std::io::Error> {
+            Err(std::io::Error::other("offline"))
+        }
+    }
+
+    impl Client for Offline {
+        type Sink = Offline;
+
+        fn source(&self) -> Source {
+            Source::Other("whatsapp".to_string())
+        }
+
+        fn account_id(&self) -> String {
+            "bot".to_string()
+        }
+
+        fn max_message_length(&self) -> usize {
+            4096
+        }
+
+        fn sink(&self, _channel_id: &str) -> Offline {
+            Offline
+        }
+    }
+
+    #[tokio::test]
+    async fn test_external_source_round_trips() {
+        let character = test_utils::character();
+        let agent = Agent::new(
+            character,
+            ScriptedCompletionModel::new(["VRF is verifiable randomness."]),
+            test_utils::knowledge_base().await,
+        );
+        let pipeline = Pipeline::new(
+            agent,
+            Attention::new(AttentionConfig::default(), ScriptedCompletionModel::new([])),
+        );
+        let client = Chat::default();
+
+        let handled = pipeline
+            .handle(
+                &client,
+                IncomingMessage::new("m1", "chat", "alice", "what is vrf?")
+                    .with_channel_type(ChannelType::DirectMessage),
+            )
+            .await
+            .unwrap();
+        let Handled::Replied {
+            interaction_id: Some(interaction_id),
+            chunks,
+        } = handled
+        else {
+            panic!("expected a reply, got {handled:?}");
+        };
+        assert_eq!(chunks, ["VRF is verifiable randomness."]);
+        assert_eq!(*client.sent.lock().unwrap(), chunks);
+
+        // The unknown source is read back as itself
+        let knowledge = pipeline.agent().knowledge();
+        let stored = knowledge.get_recent_messages("chat", 10).await.unwrap();
+        assert_eq!(stored.len(), 2);
+        assert!(stored
+            .iter()
+            .all(|message| message.source == client.source()));
+        let sent = knowledge.sent_messages(interaction_id).await.unwrap();
+        assert_eq!(sent[0].source, client.source());
+        assert_eq!(
+            knowledge
+                .interaction_for_platform_message(&client.source(), "wa-1")
+                .await
+                .unwrap(),
+            Some(interaction_id)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_outage_notices_and_follow_ups() {
+        let character = test_utils::character();
+        let rate_limited = "HTTP 429 Too Many Requests from https://api.example.com: rate limit";
+        let model = ScriptedCompletionModel::new([])
+            .then_error(rate_limited)
+            .then_error(rate_limited)
+            .then_reply("VRF is verifiable randomness.")
+            .then_reply("Call request_random.");
+        let status = StatusBoard::default();
+        let pipeline = Pipeline::new(
+            Agent::new(character, model, test_utils::knowledge_base().await),
+            Attention::new(AttentionConfig::default(), ScriptedCompletionModel::new([])),
+        )
+        .with_outage_notices(OutageNotices::new(OutageConfig::default(), status.clone()));
+        let client = Chat::default();
+        let dm = |id, content| {
+            IncomingMessage::new(id, "chat", "alice", content)
+                .with_channel_type(ChannelType::DirectMessage)
+        };
+
+        let handled = pipeline
+            .handle(&client, dm("m1", "what is vrf?"))
+            .await
+            .unwrap();
+        assert_eq!(
+            handled,
+            Handled::Deferred {
+                notice: Some("wa-1".to_string()),
+                queued: true
+            }
+        );
+        assert!(status.status().has(Condition::RateLimited));
+
+        // The channel was told in this window
+        let handled = pipeline
+            .handle(&client, dm("m2", "how do I request it?"))
+            .await
+            .unwrap();
+        assert_eq!(
+            handled,
+            Handled::Deferred {
+                notice: None,
+                queued: true
+            }
+        );
+
+        // Messages the bot doesn't answer get no notice
+        let handled = pipeline
+            .handle(
+                &client,
+                IncomingMessage::new("m3", "lobby", "bob", "thanks!")
+                    .with_channel_type(ChannelType::Text),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(handled, Handled::Ignored(_)));
+        assert_eq!(
+            *client.sent.lock().unwrap(),
+            [pipeline
+                .context()
+                .template(templates::PROVIDER_DEFERRED, &[])]
+        );
+
+        let follow_ups = pipeline.follow_up_deferred(&client).await.unwrap();
+        assert_eq!(follow_ups.len(), 2);
+        assert_eq!(follow_ups[0].message_id, "m1");
+        assert_eq!(follow_ups[0].notice.as_deref(), Some("wa-1"));
+        assert!(follow_ups[0].chunks[0].contains("\"what is vrf?\""));
+        assert!(follow_ups[0].chunks[0].ends_with("VRF is verifiable randomness."));
+        assert_eq!(follow_ups[1].message_id, "m2");
+        assert_eq!(follow_ups[1].notice, None);
+        assert_eq!(client.sent.lock().unwrap().len(), 3);
+        assert!(status.status().is_healthy());
+
+        // Answered once
+        assert!(pipeline
+            .follow_up_deferred(&client)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_over_budget_notice() {
+        let character = test_utils::character();
+        let status = StatusBoard::default();
+        status.set(Condition::OverBudget, true);
+        let pipeline = Pipeline::new(
+            Agent::new(
+                character,
+                ScriptedCompletionModel::new([]),
+                test_utils::knowledge_base().await,
+            ),
+            Attention::new(AttentionConfig::default(), ScriptedCompletionModel::new([])),
+        )
+        .with_outage_notices(OutageNotices::new(OutageConfig::default(), status));
+        let client = Chat::default();
+
+        let handled = pipeline
+            .handle(
+                &client,
+                IncomingMessage::new("m1", "chat", "alice", "what is vrf?")
+                    .with_channel_type(ChannelType::DirectMessage),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(handled, Handled::Deferred { .. }));
+        assert_eq!(
+            *client.sent.lock().unwrap(),
+            [pipeline.context().template(templates::OVER_BUDGET, &[])]
+        );
+    }
+
+    /// Records what documents were searched with.
+    #[derive(Default)]
+    struct RecordingRetriever {
+        queries: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl Retriever for RecordingRetriever {
+        async fn retrieve(
+            &self,
+            query: &str,
+            _n: usize,
+        ) -> Result<Vec<RetrievedChunk>, VectorStoreError> {
+            self.queries.lock().unwrap().push(query.to_string());
+            Ok(vec![RetrievedChunk::whole(
+                "vrf.md",
+                "Raise the fee token allowance to fix error 0x41.",
+                "",
+                "",
+            )])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retrieval_uses_rewritten_query() {
+        let character = test_utils::character();
+        let model = ScriptedCompletionModel::new(["Raise the fee token allowance."]);
+        let rewriter = ScriptedCompletionModel::new(["fix error 0x41 when setting VRF fees"]);
+        let retriever = Arc::new(RecordingRetriever::default());
+        let agent = Agent::new(character, model.clone(), test_utils::knowledge_base().await)
+            .with_retriever(retriever.clone())
+            .with_query_rewrite(RewriteConfig::default())
+            .with_query_rewriter(Arc::new(ModelRewriter::new(rewriter.clone())));
+        let pipeline = Pipeline::new(
+            agent,
+            Attention::new(AttentionConfig::default(), ScriptedCompletionModel::new([])),
+        );
+        let client = Chat::default();
+        let knowledge = pipeline.agent().knowledge();
+        knowledge
+            .create_message(
+                IncomingMessage::new(
+                    "m0",
+                    "chat",
+                    "alice",
+                    "I get error 0x41 when setting VRF fees",
+                )
+                .to_message(client.source()),
+            )
+            .await
+            .unwrap();
+
+        let handled = pipeline
+            .handle(
+                &client,
+                IncomingMessage::new("m1", "chat", "alice", "it still doesn't work")
+                    .with_channel_type(ChannelType::DirectMessage)
+                    .with_is_reply(true),
+            )
+            .await
+            .unwrap();
+        let Handled::Replied {
+            interaction_id: Some(interaction_id),
+            ..
+        } = handled
+        else {
+            panic!("expected a reply, got {handled:?}");
+        };
+
+        // The rewriter saw the conversation, retrieval the rewrite and the
+        // reply the message as written
+        assert!(rewriter.requests()[0]
+            .prompt
+            .contains("I get error 0x41 when setting VRF fees"));
+        assert_eq!(
+            *retriever.queries.lock().unwrap(),
+            ["fix error 0x41 when setting VRF fees"]
+        );
+        assert_eq!(model.requests()[0].prompt, "it still doesn't work");
+        assert_eq!(
+            knowledge.interaction_query(interaction_id).await.unwrap(),
+            Some(RetrievalQuery {
+                original: "it still doesn't work".to_string(),
+                rewritten: Some("fix error 0x41 when setting VRF fees".to_string()),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_earlier_exchange_is_recalled() {
+        let character = test_utils::character();
+        let model = ScriptedCompletionModel::new([
+            "Stake STRK through the staking dashboard.",
+            "Use the staking dashboard, as before.",
+        ]);
+        let agent = Agent::new(character, model.clone(), test_utils::knowledge_base().await)
+            .with_memory(MemoryConfig {
+                recent_messages: 1,
+                recall_similarity: 0.5,
+                ..Default::default()
+            });
+        let pipeline = Pipeline::new(
+            agent,
+            Attention::new(AttentionConfig::default(), ScriptedCompletionModel::new([])),
+        );
+        let client = Chat::default();
+
+        for (id, content) in [
+            ("m1", "how do I stake STRK tokens"),
+            ("m2", "how do I stake STRK tokens again"),
+        ] {
+            pipeline
+                .handle(
+                    &client,
+                    IncomingMessage::new(id, "chat", "alice", content)
+                        .with_channel_type(ChannelType::DirectMessage),
+                )
+                .await
+                .unwrap();
+        }
+
+        let recalled = |request: usize| {
+            model.requests()[request]
+                .documents
+                .iter()
+                .find(|text| text.starts_with("You previously discussed this on "))
+                .cloned()
+        };
+        assert_eq!(recalled(0), None);
+        assert!(recalled(1)
+            .unwrap()
+            .ends_with(": how do I stake STRK tokens — Stake STRK through the staking dashboard."));
+
+        // Both answers were recorded
+        let exchanges = pipeline
+            .agent()
+            .knowledge()
+            .similar_exchanges("chat", "staking dashboard", 5)
+            .await
+            .unwrap();
+        assert_eq!(exchanges.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_undelivered_answer_is_no_exchange() {
+        let agent = Agent::new(
+            test_utils::character(),
+            ScriptedCompletionModel::new(["Stake STRK through the staking dashboard."]),
+            test_utils::knowledge_base().await,
+        );
+        let pipeline = Pipeline::new(
+            agent,
+            Attention::new(AttentionConfig::default(), ScriptedCompletionModel::new([])),
+        );
+
+        let handled = pipeline
+            .handle(
+                &Offline,
+                IncomingMessage::new("m1", "chat", "alice", "how do I stake STRK tokens")
+                    .with_channel_type(ChannelType::DirectMessage),
+            )
+            .await;
+        assert!(matches!(handled, Err(PipelineError::Sink(_))));
+
+        // Nobody saw the answer, so later questions can't lean on it
+        let exchanges = pipeline
+            .agent()
+            .knowledge()
+            .similar_exchanges("chat", "staking dashboard", 5)
+            .await
+            .unwrap();
+        assert!(exchanges.is_empty());
+    }
+}
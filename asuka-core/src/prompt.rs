@@ -0,0 +1,580 @@
+//! The prompt of a reply, assembled as plain data before it is handed to rig,
+//! so tests and debugging see exactly what the model gets.
+//!
+//! [Agent::render_prompt](crate::agent::Agent::render_prompt) builds an
+//! [AssembledPrompt] from the character, the channel's conversation state and
+//! pins, retrieved documents and the current time. The [Retriever] and
+//! [Clock](crate::clock::Clock) are injectable, so the same inputs always
+//! give the same prompt.
+//!
+//! The parts that only change with the character and config, such as the
+//! preamble and the standing instructions, are rendered once into
+//! [StaticSegments] and reused from a [SegmentCache] until a config reload
+//! invalidates it, so each message only renders its own parts.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use async_trait::async_trait;
+use rig::{
+    agent::AgentBuilder,
+    completion::CompletionModel,
+    embeddings::EmbeddingModel,
+    vector_store::{VectorStoreError, VectorStoreIndex},
+};
+
+use crate::{
+    history::estimate_tokens,
+    knowledge::{KnowledgeBase, RetrievedChunk},
+};
+
+/// Everything sent for a reply besides tools: the system preamble, labelled
+/// context documents in the order they are sent, and the user's input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembledPrompt {
+    pub system: String,
+    /// `(label, text)` pairs. Labels are for inspection, only the text is sent.
+    pub contexts: Vec<(String, String)>,
+    pub user: String,
+    /// Estimated tokens of the whole prompt, see [estimate_tokens].
+    pub tokens: usize,
+}
+
+impl AssembledPrompt {
+    pub fn new(system: impl Into<String>, user: impl Into<String>) -> Self {
+        let (system, user) = (system.into(), user.into());
+        Self {
+            tokens: estimate_tokens(&system) + estimate_tokens(&user),
+            system,
+            contexts: Vec::new(),
+            user,
+        }
+    }
+
+    /// A prompt with the system preamble of `segments`, counted with its
+    /// cached estimate.
+    pub fn from_segments(segments: &StaticSegments, user: impl Into<String>) -> Self {
+        let user = user.into();
+        Self {
+            system: segments.system.clone(),
+            contexts: Vec::new(),
+            tokens: segments.system_tokens + estimate_tokens(&user),
+            user,
+        }
+    }
+
+    pub fn push(&mut self, label: impl Into<String>, text: impl Into<String>) {
+        let text = text.into();
+        self.tokens += estimate_tokens(&text);
+        self.contexts.push((label.into(), text));
+    }
+
+    /// Adds the segment of `segments` with `label`, if there is one.
+    pub fn push_segment(&mut self, segments: &StaticSegments, label: &str) {
+        if let Some((text, tokens)) = segments.contexts.get(label) {
+            self.tokens += tokens;
+            self.contexts.push((label.to_string(), text.clone()));
+        }
+    }
+
+    /// Text of the first context with `label`.
+    pub fn context(&self, label: &str) -> Option<&str> {
+        self.contexts
+            .iter()
+            .find(|(context, _)| context == label)
+            .map(|(_, text)| text.as_str())
+    }
+
+    /// Readable layout of the whole prompt, one `[label]` section per part.
+    pub fn render(&self) -> String {
+        let mut sections = vec![format!("[system]\n{}", self.system)];
+        sections.extend(
+            self.contexts
+                .iter()
+                .map(|(label, text)| format!("[{label}]\n{text}")),
+        );
+        sections.push(format!("[user]\n{}", self.user));
+        sections.join("\n\n")
+    }
+
+    /// A builder sending the system preamble and contexts. The user input is
+    /// passed when prompting it.
+    pub fn builder<M: CompletionModel>(&self, model: M) -> AgentBuilder<M> {
+        self.contexts.iter().fold(
+            AgentBuilder::new(model).preamble(&self.system),
+            |builder, (_, text)| builder.context(text),
+        )
+    }
+}
+
+/// The parts of a prompt rendered from the character and config alone, with
+/// their estimated tokens, see [estimate_tokens].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StaticSegments {
+    pub system: String,
+    system_tokens: usize,
+    /// Text and tokens of each context, by label.
+    contexts: HashMap<String, (String, usize)>,
+}
+
+impl StaticSegments {
+    pub fn new(system: impl Into<String>) -> Self {
+        let system = system.into();
+        Self {
+            system_tokens: estimate_tokens(&system),
+            system,
+            contexts: HashMap::new(),
+        }
+    }
+
+    pub fn with_context(mut self, label: impl Into<String>, text: impl Into<String>) -> Self {
+        let text = text.into();
+        let tokens = estimate_tokens(&text);
+        self.contexts.insert(label.into(), (text, tokens));
+        self
+    }
+
+    /// Estimated tokens of all segments.
+    pub fn tokens(&self) -> usize {
+        self.system_tokens
+            + self
+                .contexts
+                .values()
+                .map(|(_, tokens)| tokens)
+                .sum::<usize>()
+    }
+}
+
+/// [StaticSegments] rendered for the current config version, by experiment
+/// variant, as variants have their own preamble. Clones share it.
+#[derive(Clone, Default)]
+pub struct SegmentCache {
+    version: Arc<AtomicU64>,
+    entries: Arc<Mutex<HashMap<Option<String>, (u64, Arc<StaticSegments>)>>>,
+    renders: Arc<AtomicUsize>,
+}
+
+impl SegmentCache {
+    /// The segments of `variant`, from `render` when they weren't rendered
+    /// since the last [SegmentCache::invalidate].
+    pub fn get_or_render(
+        &self,
+        variant: Option<String>,
+        render: impl FnOnce() -> StaticSegments,
+    ) -> Arc<StaticSegments> {
+        let version = self.version.load(Ordering::Acquire);
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(&variant) {
+            Some((rendered, segments)) if *rendered == version => segments.clone(),
+            _ => {
+                self.renders.fetch_add(1, Ordering::Relaxed);
+                let segments = Arc::new(render());
+                entries.insert(variant, (version, segments.clone()));
+                segments
+            }
+        }
+    }
+
+    /// Renders the segments again for the next prompt, e.g. once the config
+    /// is reloaded.
+    pub fn invalidate(&self) {
+        self.version.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Times segments were rendered.
+    pub fn renders(&self) -> usize {
+        self.renders.load(Ordering::Relaxed)
+    }
+}
+
+/// Finds the documents added to a prompt.
+#[async_trait]
+pub trait Retriever: Send + Sync {
+    /// The `n` documents or parts closest to `query`, closest first.
+    async fn retrieve(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<RetrievedChunk>, VectorStoreError>;
+}
+
+/// Retrieves from a vector index over the documents table, with the place
+/// of each part in its document, see [KnowledgeBase::retrieve].
+pub struct IndexRetriever<I: VectorStoreIndex, E: EmbeddingModel> {
+    knowledge: KnowledgeBase<E>,
+    index: I,
+}
+
+impl<I: VectorStoreIndex, E: EmbeddingModel> IndexRetriever<I, E> {
+    pub fn new(knowledge: KnowledgeBase<E>, index: I) -> Self {
+        Self { knowledge, index }
+    }
+}
+
+#[async_trait]
+impl<I: VectorStoreIndex, E: EmbeddingModel> Retriever for IndexRetriever<I, E> {
+    async fn retrieve(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<RetrievedChunk>, VectorStoreError> {
+        self.knowledge.retrieve(&self.index, query, n).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::{TimeZone, Utc};
+    use rig::completion::Prompt;
+
+    use super::*;
+    use crate::{
+        agent::Agent,
+        attention::ResponseMode,
+        clock::FixedClock,
+        test_utils::{self, FakeEmbeddingModel, ScriptedCompletionModel},
+    };
+
+    /// Returns its documents in order, whatever the query.
+    struct FakeRetriever(Vec<(&'static str, &'static str)>);
+
+    #[async_trait]
+    impl Retriever for FakeRetriever {
+        async fn retrieve(
+            &self,
+            _query: &str,
+            n: usize,
+        ) -> Result<Vec<RetrievedChunk>, VectorStoreError> {
+            Ok(self
+                .0
+                .iter()
+                .take(n)
+                .map(|(id, content)| RetrievedChunk::whole(*id, *content, "", ""))
+                .collect())
+        }
+    }
+
+    async fn agent(
+        documents: Vec<(&'static str, &'static str)>,
+    ) -> Agent<ScriptedCompletionModel, FakeEmbeddingModel> {
+        let character = test_utils::character();
+        let now = Utc.with_ymd_and_hms(2024, 11, 5, 9, 30, 0).unwrap();
+        Agent::new(
+            character,
+            ScriptedCompletionModel::default(),
+            test_utils::knowledge_base().await,
+        )
+        .with_clock(Arc::new(FixedClock(now)))
+        .with_retriever(Arc::new(FakeRetriever(documents)))
+    }
+
+    #[tokio::test]
+    async fn test_direct_message_with_history() {
+        let agent = agent(vec![("katana.md", "Katana listens on port 5050.")]).await;
+        agent
+            .conversations()
+            .update("dm1", |state| {
+                state.summary = Some("Asking about katana flags".to_string());
+                state.open_questions = vec!["Which port does katana use?".to_string()];
+                state.pending_follow_ups = vec!["the --dev flag".to_string()];
+            })
+            .await;
+
+        let prompt = agent
+            .render_prompt("dm1", &ResponseMode::FullAnswer, "And the port?")
+            .await;
+        insta::assert_snapshot!(prompt.render(), @r"
+        [system]
+        You help with Cartridge.
+
+        [name]
+        Your name: shinobi
+
+        [summary]
+        Conversation so far: Asking about katana flags
+
+        [open question]
+        Still unanswered: Which port does katana use?
+
+        [follow-up]
+        You said you would follow up on: the --dev flag
+
+        [time]
+        Current time: 09:30:00, 2024-11-05 UTC
+
+        [length]
+        Please keep your responses concise and under 2000 characters when possible.
+
+        [document katana.md]
+        Source: katana.md
+        Katana listens on port 5050.
+
+        [user]
+        And the port?
+        ");
+    }
+
+    #[tokio::test]
+    async fn test_guild_message_with_retrieval_hits() {
+        let agent = agent(vec![
+            ("vrf.md", "VRF requests are free on testnet."),
+            ("paymaster.md", "The paymaster sponsors session fees."),
+            ("katana.md", "Katana listens on port 5050."),
+        ])
+        .await;
+
+        let prompt = agent
+            .render_prompt("g1", &ResponseMode::FullAnswer, "are vrf requests free?")
+            .await;
+        insta::assert_snapshot!(prompt.render(), @r"
+        [system]
+        You help with Cartridge.
+
+        [name]
+        Your name: shinobi
+
+        [time]
+        Current time: 09:30:00, 2024-11-05 UTC
+
+        [length]
+        Please keep your responses concise and under 2000 characters when possible.
+
+        [document vrf.md]
+        Source: vrf.md
+        VRF requests are free on testnet.
+
+        [document paymaster.md]
+        Source: paymaster.md
+        The paymaster sponsors session fees.
+
+        [user]
+        are vrf requests free?
+        ");
+    }
+
+    #[tokio::test]
+    async fn test_pinned_context_present() {
+        let agent = agent(vec![]).await;
+        let knowledge = agent.knowledge();
+        knowledge
+            .add_pin(None, "Mainnet is live.".to_string(), "admin".to_string())
+            .await
+            .unwrap();
+        knowledge
+            .add_pin(
+                Some("g1".to_string()),
+                "Testnet is Sepolia.".to_string(),
+                "admin".to_string(),
+            )
+            .await
+            .unwrap();
+
+        let prompt = agent
+            .render_prompt("g1", &ResponseMode::FullAnswer, "which testnet?")
+            .await;
+        insta::assert_snapshot!(prompt.render(), @r"
+        [system]
+        You help with Cartridge.
+
+        [name]
+        Your name: shinobi
+
+        [pin 1]
+        Pinned note: Mainnet is live.
+
+        [pin 2]
+        Pinned note: Testnet is Sepolia.
+
+        [time]
+        Current time: 09:30:00, 2024-11-05 UTC
+
+        [length]
+        Please keep your responses concise and under 2000 characters when possible.
+
+        [user]
+        which testnet?
+        ");
+    }
+
+    #[tokio::test]
+    async fn test_pins_over_budget_are_trimmed() {
+        let agent = agent(vec![]).await.with_pinned_context_limit(45);
+        for note in [
+            "Goerli is deprecated.",
+            "Testnet is Sepolia.",
+            "Fees are paid in STRK.",
+        ] {
+            agent
+                .knowledge()
+                .add_pin(
+                    Some("g1".to_string()),
+                    note.to_string(),
+                    "admin".to_string(),
+                )
+                .await
+                .unwrap();
+        }
+
+        let prompt = agent
+            .render_prompt("g1", &ResponseMode::FullAnswer, "which testnet?")
+            .await;
+        insta::assert_snapshot!(prompt.render(), @r"
+        [system]
+        You help with Cartridge.
+
+        [name]
+        Your name: shinobi
+
+        [pin 2]
+        Pinned note: Testnet is Sepolia.
+
+        [pin 3]
+        Pinned note: Fees are paid in STRK.
+
+        [time]
+        Current time: 09:30:00, 2024-11-05 UTC
+
+        [length]
+        Please keep your responses concise and under 2000 characters when possible.
+
+        [user]
+        which testnet?
+        ");
+    }
+
+    #[tokio::test]
+    async fn test_brief_ack_skips_retrieval() {
+        let agent = agent(vec![("vrf.md", "VRF requests are free on testnet.")]).await;
+
+        let prompt = agent
+            .render_prompt("g1", &ResponseMode::BriefAck, "gm shinobi")
+            .await;
+        insta::assert_snapshot!(prompt.render(), @r"
+        [system]
+        You help with Cartridge.
+
+        [name]
+        Your name: shinobi
+
+        [instruction]
+        Reply in one short sentence, as a quick acknowledgement or greeting.
+
+        [time]
+        Current time: 09:30:00, 2024-11-05 UTC
+
+        [length]
+        Please keep your responses concise and under 2000 characters when possible.
+
+        [user]
+        gm shinobi
+        ");
+    }
+
+    #[tokio::test]
+    async fn test_static_segments_render_once() {
+        let agent = agent(vec![]).await;
+        let cache = agent.prompt_cache().clone();
+
+        let first = agent
+            .render_prompt("g1", &ResponseMode::FullAnswer, "gm")
+            .await;
+        for i in 0..50 {
+            let agent = agent.clone();
+            let mode = if i % 2 == 0 {
+                ResponseMode::BriefAck
+            } else {
+                ResponseMode::FullAnswer
+            };
+            agent
+                .render_prompt(&format!("c{i}"), &mode, "what is vrf?")
+                .await;
+        }
+        assert_eq!(cache.renders(), 1);
+        // Cached counts add up to what the whole prompt is estimated at
+        let tokens = estimate_tokens(&first.system)
+            + estimate_tokens(&first.user)
+            + first
+                .contexts
+                .iter()
+                .map(|(_, text)| estimate_tokens(text))
+                .sum::<usize>();
+        assert_eq!(first.tokens, tokens);
+
+        // A variant has its own preamble
+        let variant = agent.for_variant(&crate::experiments::Assignment {
+            experiment: "tone".to_string(),
+            variant: crate::experiments::VariantConfig {
+                name: "terse".to_string(),
+                weight: 1,
+                preamble: Some("You help with Cartridge, tersely.".to_string()),
+                model: None,
+                temperature: None,
+            },
+        });
+        let prompt = variant
+            .render_prompt("g1", &ResponseMode::FullAnswer, "gm")
+            .await;
+        assert_eq!(prompt.system, "You help with Cartridge, tersely.");
+        assert_eq!(cache.renders(), 2);
+
+        // Rendered again after a reload
+        cache.invalidate();
+        let prompt = agent
+            .render_prompt("g1", &ResponseMode::FullAnswer, "gm")
+            .await;
+        assert_eq!(prompt, first);
+        assert_eq!(cache.renders(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_builder_sends_the_assembled_prompt() {
+        let model = ScriptedCompletionModel::new(["Sepolia."]);
+        let mut prompt = AssembledPrompt::new("You help with Cartridge.", "which testnet?");
+        prompt.push("name", "Your name: shinobi");
+        prompt.push("pin 1", "Pinned note: Testnet is Sepolia.");
+
+        let reply = prompt
+            .builder(model.clone())
+            .build()
+            .prompt(&prompt.user)
+            .await
+            .unwrap();
+        assert_eq!(reply, "Sepolia.");
+
+        let request = &model.requests()[0];
+        assert_eq!(request.prompt, "which testnet?");
+        assert_eq!(
+            request.documents,
+            ["Your name: shinobi", "Pinned note: Testnet is Sepolia."]
+        );
+        assert_eq!(
+            prompt.context("pin 1"),
+            Some("Pinned note: Testnet is Sepolia.")
+        );
+    }
+
+    #[test]
+    fn test_citation() {
+        let document =
+            |title: &str, section: &str| RetrievedChunk::whole("docs/vrf.mdx", "", title, section);
+
+        assert_eq!(document("", "").citation(), "docs/vrf.mdx");
+        assert_eq!(document("VRF Overview", "").citation(), "VRF Overview");
+        assert_eq!(
+            document("VRF Overview", "VRF > Configuration > Fees").citation(),
+            "VRF Overview — Fees"
+        );
+        // A part without a title is cited by its document
+        assert_eq!(
+            RetrievedChunk::whole("docs/vrf.mdx#3", "", "", "").citation(),
+            "docs/vrf.mdx"
+        );
+    }
+}
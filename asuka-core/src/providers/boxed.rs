@@ -0,0 +1,294 @@
+//! Completion and embedding models behind trait objects, so the provider can
+//! be picked at runtime and an application names one type,
+//! `Agent<BoxCompletionModel, BoxEmbeddingModel>`, whichever it picked.
+//!
+//! rig's model traits return `impl Future` and can't be made into objects.
+//! [DynCompletionModel] and [DynEmbeddingModel] are object safe versions,
+//! implemented for every rig model, and the boxes implement the rig traits
+//! by delegating to them. Everything generic over the rig traits takes the
+//! boxes, and code that knows its models keeps using them directly, without
+//! the extra allocation and indirection per call.
+//!
+//! ```ignore
+//! let embedding_model: BoxEmbeddingModel = match ollama {
+//!     Some(config) => OllamaEmbeddingModel::new(&config).await?.into(),
+//!     None => oai.embedding_model(openai::TEXT_EMBEDDING_3_SMALL).into(),
+//! };
+//! ```
+
+use std::{any::Any, fmt, sync::Arc};
+
+use async_trait::async_trait;
+use rig::{
+    completion::{CompletionError, CompletionModel, CompletionRequest, CompletionResponse},
+    embeddings::{Embedding, EmbeddingError, EmbeddingModel},
+    providers::{anthropic, cohere, openai},
+};
+
+use super::ollama_embeddings::OllamaEmbeddingModel;
+
+/// The raw response of a boxed model's provider, downcast to read it, e.g.
+/// to `openai::CompletionResponse`.
+pub type BoxedResponse = Arc<dyn Any + Send + Sync>;
+
+/// Object safe [CompletionModel].
+#[async_trait]
+pub trait DynCompletionModel: Send + Sync {
+    async fn completion(
+        &self,
+        request: CompletionRequest,
+    ) -> Result<CompletionResponse<BoxedResponse>, CompletionError>;
+}
+
+#[async_trait]
+impl<M> DynCompletionModel for M
+where
+    M: CompletionModel + 'static,
+    M::Response: 'static,
+{
+    async fn completion(
+        &self,
+        request: CompletionRequest,
+    ) -> Result<CompletionResponse<BoxedResponse>, CompletionError> {
+        let response = CompletionModel::completion(self, request).await?;
+        Ok(CompletionResponse {
+            choice: response.choice,
+            raw_response: Arc::new(response.raw_response),
+        })
+    }
+}
+
+/// Object safe [EmbeddingModel].
+#[async_trait]
+pub trait DynEmbeddingModel: Send + Sync {
+    fn ndims(&self) -> usize;
+
+    /// The model's [EmbeddingModel::MAX_DOCUMENTS].
+    fn max_documents(&self) -> usize;
+
+    async fn embed_texts(&self, texts: Vec<String>) -> Result<Vec<Embedding>, EmbeddingError>;
+}
+
+#[async_trait]
+impl<E: EmbeddingModel + 'static> DynEmbeddingModel for E {
+    fn ndims(&self) -> usize {
+        EmbeddingModel::ndims(self)
+    }
+
+    fn max_documents(&self) -> usize {
+        E::MAX_DOCUMENTS
+    }
+
+    async fn embed_texts(&self, texts: Vec<String>) -> Result<Vec<Embedding>, EmbeddingError> {
+        EmbeddingModel::embed_texts(self, texts).await
+    }
+}
+
+/// Any completion model, see the [module docs](self).
+#[derive(Clone)]
+pub struct BoxCompletionModel(Arc<dyn DynCompletionModel>);
+
+impl BoxCompletionModel {
+    pub fn new<M>(model: M) -> Self
+    where
+        M: CompletionModel + 'static,
+        M::Response: 'static,
+    {
+        Self(Arc::new(model))
+    }
+}
+
+impl fmt::Debug for BoxCompletionModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("BoxCompletionModel")
+    }
+}
+
+impl CompletionModel for BoxCompletionModel {
+    type Response = BoxedResponse;
+
+    async fn completion(
+        &self,
+        request: CompletionRequest,
+    ) -> Result<CompletionResponse<BoxedResponse>, CompletionError> {
+        self.0.completion(request).await
+    }
+}
+
+/// Any embedding model, see the [module docs](self).
+#[derive(Clone)]
+pub struct BoxEmbeddingModel(Arc<dyn DynEmbeddingModel>);
+
+impl BoxEmbeddingModel {
+    pub fn new<E: EmbeddingModel + 'static>(model: E) -> Self {
+        Self(Arc::new(model))
+    }
+}
+
+impl fmt::Debug for BoxEmbeddingModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("BoxEmbeddingModel")
+    }
+}
+
+impl EmbeddingModel for BoxEmbeddingModel {
+    /// OpenAI's limit. Batches are split again at the boxed model's own.
+    const MAX_DOCUMENTS: usize = 1024;
+
+    fn ndims(&self) -> usize {
+        self.0.ndims()
+    }
+
+    async fn embed_texts(
+        &self,
+        texts: impl IntoIterator<Item = String> + Send,
+    ) -> Result<Vec<Embedding>, EmbeddingError> {
+        let texts = texts.into_iter().collect::<Vec<_>>();
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(self.0.max_documents().max(1)) {
+            embeddings.extend(self.0.embed_texts(batch.to_vec()).await?);
+        }
+        Ok(embeddings)
+    }
+}
+
+macro_rules! box_completion_models {
+    ($($model:ty),* $(,)?) => {$(
+        impl From<$model> for BoxCompletionModel {
+            fn from(model: $model) -> Self {
+                Self::new(model)
+            }
+        }
+    )*};
+}
+
+macro_rules! box_embedding_models {
+    ($($model:ty),* $(,)?) => {$(
+        impl From<$model> for BoxEmbeddingModel {
+            fn from(model: $model) -> Self {
+                Self::new(model)
+            }
+        }
+    )*};
+}
+
+box_completion_models!(
+    super::CompletionModel,
+    openai::CompletionModel,
+    anthropic::completion::CompletionModel,
+    cohere::CompletionModel,
+);
+
+box_embedding_models!(
+    super::EmbeddingModel,
+    OllamaEmbeddingModel,
+    openai::EmbeddingModel,
+    cohere::EmbeddingModel,
+);
+
+#[cfg(test)]
+mod tests {
+    use rig::completion::Prompt;
+
+    use super::*;
+    use crate::{
+        test_utils::{self, FakeEmbeddingModel, ScriptedCompletionModel},
+        Agent, Character,
+    };
+
+    #[tokio::test]
+    async fn test_boxed_models_behave_as_concrete() {
+        let texts = vec!["session keys".to_string(); 100];
+        let concrete = EmbeddingModel::embed_texts(&FakeEmbeddingModel, texts.clone())
+            .await
+            .unwrap();
+        let boxed = BoxEmbeddingModel::new(FakeEmbeddingModel);
+        assert_eq!(EmbeddingModel::ndims(&boxed), test_utils::FAKE_DIMS);
+        // Split at the fake's 64 documents, in order
+        let embeddings = EmbeddingModel::embed_texts(&boxed, texts).await.unwrap();
+        assert_eq!(embeddings.len(), 100);
+        for (boxed, concrete) in embeddings.iter().zip(&concrete) {
+            assert_eq!(
+                (&boxed.document, &boxed.vec),
+                (&concrete.document, &concrete.vec)
+            );
+        }
+
+        let model = ScriptedCompletionModel::new(["gm"]);
+        let response = CompletionModel::completion(
+            &BoxCompletionModel::new(model.clone()),
+            model.completion_request("hi").build(),
+        )
+        .await
+        .unwrap();
+        assert!(response.raw_response.downcast_ref::<()>().is_some());
+        assert_eq!(model.requests()[0].prompt, "hi");
+        // Errors pass through
+        let model = BoxCompletionModel::new(ScriptedCompletionModel::new([]));
+        let request = model.completion_request("hi").build();
+        assert!(matches!(
+            CompletionModel::completion(&model, request).await,
+            Err(CompletionError::ProviderError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_boxed_agent_sends_the_same_requests() {
+        let character: Character =
+            toml::from_str("name = \"shinobi\"\npreamble = \"You help with Cartridge.\"").unwrap();
+        let prompt = "when do session keys expire?";
+        let document = crate::knowledge::Document {
+            id: "keys.md".to_string(),
+            source_id: "github".to_string(),
+            content: "Session keys expire after 7 days.".to_string(),
+            created_at: chrono::Utc::now(),
+            title: String::new(),
+            section: String::new(),
+            topics: vec![],
+            logical_id: None,
+            cleaned: None,
+            chunk: None,
+        };
+
+        let mut concrete = test_utils::knowledge_base().await;
+        concrete
+            .add_documents(vec![document.clone()])
+            .await
+            .unwrap();
+        let concrete_model = ScriptedCompletionModel::new(["After 7 days."]);
+        let mut boxed =
+            test_utils::knowledge_base_with(BoxEmbeddingModel::new(FakeEmbeddingModel)).await;
+        boxed.add_documents(vec![document]).await.unwrap();
+        let boxed_model = ScriptedCompletionModel::new(["After 7 days."]);
+
+        let mode = crate::attention::ResponseMode::FullAnswer;
+        let concrete_reply = Agent::new(character.clone(), concrete_model.clone(), concrete)
+            .response_builder("c1", &mode, prompt)
+            .await
+            .build()
+            .prompt(prompt)
+            .await
+            .unwrap();
+        let agent: Agent<BoxCompletionModel, BoxEmbeddingModel> = Agent::new(
+            character,
+            BoxCompletionModel::new(boxed_model.clone()),
+            boxed,
+        );
+        let boxed_reply = agent
+            .response_builder("c1", &mode, prompt)
+            .await
+            .build()
+            .prompt(prompt)
+            .await
+            .unwrap();
+
+        assert_eq!(boxed_reply, concrete_reply);
+        let (boxed, concrete) = (&boxed_model.requests()[0], &concrete_model.requests()[0]);
+        assert_eq!(boxed.documents, concrete.documents);
+        assert_eq!(boxed.preamble, concrete.preamble);
+        assert!(boxed
+            .documents
+            .iter()
+            .any(|document| document.contains("Session keys expire")));
+    }
+}
@@ -0,0 +1,535 @@
+//! OpenAI compatible completion and embedding models whose endpoint can be
+//! configured, e.g. to go through a proxy or an Azure OpenAI deployment.
+//!
+//! rig's OpenAI client only takes an API key and base URL, so requests are
+//! made here and the responses parsed with rig's OpenAI types. Models of any
+//! provider can be boxed to be picked at runtime, see [boxed].
+
+pub mod boxed;
+pub mod ollama_embeddings;
+
+use std::collections::HashMap;
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use rig::{
+    completion::{self, CompletionError, CompletionRequest},
+    embeddings::{self, EmbeddingError},
+    providers::openai,
+};
+use serde::Deserialize;
+use serde_json::json;
+use thiserror::Error;
+use tracing::{debug, info};
+
+const OPENAI_API_BASE_URL: &str = "https://api.openai.com";
+
+/// Characters of an error response body kept in the error.
+const ERROR_BODY_SNIPPET: usize = 300;
+
+#[derive(Error, Debug)]
+pub enum ProviderError {
+    #[error("Azure OpenAI needs a base_url")]
+    MissingBaseUrl,
+    #[error("Invalid header {0:?}")]
+    InvalidHeader(String),
+    #[error("Invalid proxy {0:?}: {1}")]
+    InvalidProxy(String, reqwest::Error),
+    #[error("Failed to build HTTP client: {0}")]
+    Client(#[from] reqwest::Error),
+}
+
+/// Routes requests to Azure OpenAI deployments instead of model names.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AzureConfig {
+    /// Sent as the `api-version` query parameter.
+    pub api_version: String,
+    /// Deployment serving each model. Models not listed are assumed to be
+    /// deployed under their own name.
+    #[serde(default)]
+    pub deployments: HashMap<String, String>,
+}
+
+/// Where and how OpenAI requests are sent, read from an `[openai]` table.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProviderConfig {
+    /// API root, `https://api.openai.com` unless set. Required for Azure,
+    /// e.g. `https://my-org.openai.azure.com`.
+    pub base_url: Option<String>,
+    /// Headers added to every request.
+    pub headers: HashMap<String, String>,
+    /// Proxy for every request, e.g. `http://proxy.internal:3128`.
+    pub proxy: Option<String>,
+    pub azure: Option<AzureConfig>,
+    /// Request parameters each model rejects, such as `temperature` for
+    /// reasoning models. They are dropped from its requests.
+    pub unsupported_params: HashMap<String, Vec<String>>,
+}
+
+impl ProviderConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.azure.is_some() && self.base_url.is_none() {
+            return Err(ProviderError::MissingBaseUrl.to_string());
+        }
+        if let Some(azure) = &self.azure {
+            if azure.api_version.trim().is_empty() {
+                return Err("azure.api_version must not be empty".to_string());
+            }
+        }
+        self.header_map("").map_err(|err| err.to_string())?;
+        if let Some(proxy) = &self.proxy {
+            reqwest::Proxy::all(proxy)
+                .map_err(|err| ProviderError::InvalidProxy(proxy.clone(), err).to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Authentication followed by the configured headers.
+    fn header_map(&self, api_key: &str) -> Result<HeaderMap, ProviderError> {
+        let (auth_name, auth_value) = match self.azure {
+            Some(_) => ("api-key", api_key.to_string()),
+            None => ("authorization", format!("Bearer {api_key}")),
+        };
+
+        let mut headers = HeaderMap::new();
+        let auth = std::iter::once((auth_name, auth_value.as_str()));
+        let custom = self.headers.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+        for (name, value) in auth.chain(custom) {
+            let invalid = || ProviderError::InvalidHeader(name.to_string());
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid())?,
+                HeaderValue::from_str(value).map_err(|_| invalid())?,
+            );
+        }
+        Ok(headers)
+    }
+}
+
+#[derive(Clone)]
+pub struct OpenAiClient {
+    base_url: String,
+    azure: Option<AzureConfig>,
+    unsupported_params: HashMap<String, Vec<String>>,
+    http: reqwest::Client,
+}
+
+impl OpenAiClient {
+    pub fn new(api_key: &str, config: &ProviderConfig) -> Result<Self, ProviderError> {
+        let base_url = match (&config.base_url, &config.azure) {
+            (Some(url), _) => url.trim_end_matches('/').to_string(),
+            (None, Some(_)) => return Err(ProviderError::MissingBaseUrl),
+            (None, None) => OPENAI_API_BASE_URL.to_string(),
+        };
+
+        let mut builder = reqwest::Client::builder().default_headers(config.header_map(api_key)?);
+        if let Some(proxy) = &config.proxy {
+            builder = builder.proxy(
+                reqwest::Proxy::all(proxy)
+                    .map_err(|err| ProviderError::InvalidProxy(proxy.clone(), err))?,
+            );
+        }
+
+        Ok(Self {
+            base_url,
+            azure: config.azure.clone(),
+            unsupported_params: config.unsupported_params.clone(),
+            http: builder.build()?,
+        })
+    }
+
+    /// URL of `endpoint` for `model`, e.g. `chat/completions`.
+    fn url(&self, model: &str, endpoint: &str) -> String {
+        match &self.azure {
+            Some(azure) => {
+                let deployment = azure
+                    .deployments
+                    .get(model)
+                    .map(String::as_str)
+                    .unwrap_or(model);
+                format!(
+                    "{}/openai/deployments/{deployment}/{endpoint}?api-version={}",
+                    self.base_url, azure.api_version
+                )
+            }
+            None => format!("{}/v1/{endpoint}", self.base_url),
+        }
+    }
+
+    async fn post(&self, url: &str, body: &serde_json::Value) -> Result<String, String> {
+        post(&self.http, url, body).await
+    }
+
+    pub fn completion_model(&self, model: &str) -> CompletionModel {
+        CompletionModel {
+            client: self.clone(),
+            model: model.to_string(),
+            unsupported_params: self
+                .unsupported_params
+                .get(model)
+                .cloned()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Embedding model with the dimensions rig knows for `model`, or 0.
+    pub fn embedding_model(&self, model: &str) -> EmbeddingModel {
+        let ndims = match model {
+            openai::TEXT_EMBEDDING_3_LARGE => 3072,
+            openai::TEXT_EMBEDDING_3_SMALL | openai::TEXT_EMBEDDING_ADA_002 => 1536,
+            _ => 0,
+        };
+        self.embedding_model_with_ndims(model, ndims)
+    }
+
+    pub fn embedding_model_with_ndims(&self, model: &str, ndims: usize) -> EmbeddingModel {
+        EmbeddingModel {
+            client: self.clone(),
+            model: model.to_string(),
+            ndims,
+        }
+    }
+}
+
+/// Posts `body` and returns the response body, or the status and the start
+/// of the body when the request failed, e.g. for an unknown deployment.
+async fn post(
+    http: &reqwest::Client,
+    url: &str,
+    body: &serde_json::Value,
+) -> Result<String, String> {
+    let response = http
+        .post(url)
+        .json(body)
+        .send()
+        .await
+        .map_err(|err| err.to_string())?;
+    let status = response.status();
+    let text = response.text().await.map_err(|err| err.to_string())?;
+
+    if status.is_success() {
+        Ok(text)
+    } else {
+        let snippet = text.chars().take(ERROR_BODY_SNIPPET).collect::<String>();
+        Err(format!("HTTP {status} from {url}: {snippet}"))
+    }
+}
+
+/// The prompt with the context documents attached, as rig's own providers
+/// send it.
+fn prompt_with_context(request: &CompletionRequest) -> String {
+    if request.documents.is_empty() {
+        return request.prompt.clone();
+    }
+    let documents = request
+        .documents
+        .iter()
+        .map(|document| document.to_string())
+        .collect::<String>();
+    format!(
+        "<attachments>\n{documents}</attachments>\n\n{}",
+        request.prompt
+    )
+}
+
+#[derive(Clone)]
+pub struct CompletionModel {
+    client: OpenAiClient,
+    pub model: String,
+    unsupported_params: Vec<String>,
+}
+
+impl CompletionModel {
+    /// Drops `params` from every request, for models that reject them.
+    pub fn with_unsupported_params(
+        mut self,
+        params: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.unsupported_params
+            .extend(params.into_iter().map(Into::into));
+        self
+    }
+}
+
+impl completion::CompletionModel for CompletionModel {
+    type Response = openai::CompletionResponse;
+
+    async fn completion(
+        &self,
+        mut request: CompletionRequest,
+    ) -> Result<completion::CompletionResponse<Self::Response>, CompletionError> {
+        let mut messages = request
+            .preamble
+            .iter()
+            .map(|preamble| completion::Message {
+                role: "system".to_string(),
+                content: preamble.clone(),
+            })
+            .collect::<Vec<_>>();
+        messages.append(&mut request.chat_history);
+        messages.push(completion::Message {
+            role: "user".to_string(),
+            content: prompt_with_context(&request),
+        });
+
+        let mut body = json!({
+            "model": self.model,
+            "messages": messages,
+            "temperature": request.temperature,
+        });
+        if !request.tools.is_empty() {
+            body["tools"] = json!(request
+                .tools
+                .into_iter()
+                .map(openai::ToolDefinition::from)
+                .collect::<Vec<_>>());
+            body["tool_choice"] = json!("auto");
+        }
+        if let Some(max_tokens) = request.max_tokens {
+            body["max_tokens"] = json!(max_tokens);
+        }
+        if let Some(serde_json::Value::Object(params)) = request.additional_params {
+            for (key, value) in params {
+                body[key] = value;
+            }
+        }
+        if let Some(body) = body.as_object_mut() {
+            for param in &self.unsupported_params {
+                if body.remove(param).is_some_and(|value| !value.is_null()) {
+                    debug!(model = self.model, param, "Dropped unsupported parameter");
+                }
+            }
+        }
+
+        let url = self.client.url(&self.model, "chat/completions");
+        let text = self
+            .client
+            .post(&url, &body)
+            .await
+            .map_err(CompletionError::ProviderError)?;
+        let response: openai::CompletionResponse = serde_json::from_str(&text)?;
+        if let Some(usage) = &response.usage {
+            info!(model = self.model, %usage, "Completion token usage");
+        }
+        response.try_into()
+    }
+}
+
+#[derive(Clone)]
+pub struct EmbeddingModel {
+    client: OpenAiClient,
+    pub model: String,
+    ndims: usize,
+}
+
+impl embeddings::EmbeddingModel for EmbeddingModel {
+    const MAX_DOCUMENTS: usize = 1024;
+
+    fn ndims(&self) -> usize {
+        self.ndims
+    }
+
+    async fn embed_texts(
+        &self,
+        documents: impl IntoIterator<Item = String> + Send,
+    ) -> Result<Vec<embeddings::Embedding>, EmbeddingError> {
+        let documents = documents.into_iter().collect::<Vec<_>>();
+        let body = json!({
+            "model": self.model,
+            "input": documents,
+        });
+
+        let url = self.client.url(&self.model, "embeddings");
+        let text = self
+            .client
+            .post(&url, &body)
+            .await
+            .map_err(EmbeddingError::ProviderError)?;
+        let response: openai::EmbeddingResponse = serde_json::from_str(&text)?;
+        if response.data.len() != documents.len() {
+            return Err(EmbeddingError::ResponseError(
+                "Response data length does not match input length".to_string(),
+            ));
+        }
+
+        Ok(response
+            .data
+            .into_iter()
+            .zip(documents)
+            .map(|(embedding, document)| embeddings::Embedding {
+                document,
+                vec: embedding.embedding,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{generation::GenerationParams, test_utils::MockHttpServer};
+    use rig::{completion::Prompt, embeddings::EmbeddingModel as _};
+
+    const COMPLETION: &str = r#"{"id": "c1", "object": "chat.completion", "created": 0, "model": "gpt-4o", "choices": [{"index": 0, "message": {"role": "assistant", "content": "gm"}, "finish_reason": "stop"}]}"#;
+    const EMBEDDING: &str = r#"{"object": "list", "model": "m", "data": [{"object": "embedding", "embedding": [0.5, 0.5], "index": 0}], "usage": {"prompt_tokens": 1, "total_tokens": 1}}"#;
+
+    async fn roundtrip(config: ProviderConfig, server: &MockHttpServer) {
+        let client = OpenAiClient::new("secret", &config).unwrap();
+
+        let agent = rig::agent::AgentBuilder::new(client.completion_model("gpt-4o")).build();
+        assert_eq!(agent.prompt("hi").await.unwrap(), "gm");
+
+        let embeddings = client
+            .embedding_model_with_ndims(openai::TEXT_EMBEDDING_3_SMALL, 2)
+            .embed_texts(["hi".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(embeddings[0].vec, [0.5, 0.5]);
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_openai_urls_and_headers() {
+        let server = MockHttpServer::start([(200, COMPLETION), (200, EMBEDDING)]).await;
+        let config = ProviderConfig {
+            base_url: Some(format!("{}/", server.url())),
+            headers: HashMap::from([("x-team".to_string(), "cartridge".to_string())]),
+            ..Default::default()
+        };
+        roundtrip(config, &server).await;
+
+        let requests = server.requests();
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].path, "/v1/chat/completions");
+        assert_eq!(requests[0].headers["authorization"], "Bearer secret");
+        assert_eq!(requests[0].headers["x-team"], "cartridge");
+        assert!(requests[0].body.contains("\"model\":\"gpt-4o\""));
+        assert_eq!(requests[1].path, "/v1/embeddings");
+        assert_eq!(requests[1].headers["x-team"], "cartridge");
+    }
+
+    #[tokio::test]
+    async fn test_azure_deployment_urls() {
+        let server = MockHttpServer::start([(200, COMPLETION), (200, EMBEDDING)]).await;
+        let config = ProviderConfig {
+            base_url: Some(server.url()),
+            azure: Some(AzureConfig {
+                api_version: "2024-06-01".to_string(),
+                deployments: HashMap::from([("gpt-4o".to_string(), "chat-prod".to_string())]),
+            }),
+            ..Default::default()
+        };
+        roundtrip(config, &server).await;
+
+        let requests = server.requests();
+        assert_eq!(
+            requests[0].path,
+            "/openai/deployments/chat-prod/chat/completions?api-version=2024-06-01"
+        );
+        assert_eq!(requests[0].headers["api-key"], "secret");
+        assert!(!requests[0].headers.contains_key("authorization"));
+        assert_eq!(
+            requests[1].path,
+            "/openai/deployments/text-embedding-3-small/embeddings?api-version=2024-06-01"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wrong_deployment_surfaces_status_and_body() {
+        let body = r#"{"error": {"code": "DeploymentNotFound", "message": "The API deployment for this resource does not exist."}}"#;
+        let server = MockHttpServer::start([(404, body), (404, body)]).await;
+        let config = ProviderConfig {
+            base_url: Some(server.url()),
+            azure: Some(AzureConfig {
+                api_version: "2024-06-01".to_string(),
+                deployments: HashMap::new(),
+            }),
+            ..Default::default()
+        };
+        let client = OpenAiClient::new("secret", &config).unwrap();
+
+        let agent = rig::agent::AgentBuilder::new(client.completion_model("gpt-4o")).build();
+        let err = agent.prompt("hi").await.unwrap_err().to_string();
+        assert!(err.contains("HTTP 404 Not Found"), "{err}");
+        assert!(err.contains("DeploymentNotFound"), "{err}");
+
+        let err = client
+            .embedding_model("missing")
+            .embed_texts(["hi".to_string()])
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("HTTP 404 Not Found"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_generation_params_reach_body() {
+        let server = MockHttpServer::start([(200, COMPLETION)]).await;
+        let config = ProviderConfig {
+            base_url: Some(server.url()),
+            unsupported_params: HashMap::from([(
+                "o1-mini".to_string(),
+                vec!["temperature".to_string(), "top_p".to_string()],
+            )]),
+            ..Default::default()
+        };
+        let client = OpenAiClient::new("secret", &config).unwrap();
+        let params = GenerationParams {
+            temperature: Some(0.2),
+            max_tokens: Some(800),
+            top_p: Some(0.9),
+            stop: vec!["END".to_string()],
+            frequency_penalty: None,
+        };
+
+        let agent = params
+            .apply(
+                rig::agent::AgentBuilder::new(client.completion_model("o1-mini")),
+                serde_json::Map::new(),
+            )
+            .build();
+        agent.prompt("hi").await.unwrap();
+
+        let body: serde_json::Value = serde_json::from_str(&server.requests()[0].body).unwrap();
+        assert_eq!(body["max_tokens"], 800);
+        assert_eq!(body["stop"], json!(["END"]));
+        assert!(body.get("temperature").is_none());
+        assert!(body.get("top_p").is_none());
+        assert!(body.get("frequency_penalty").is_none());
+    }
+
+    #[test]
+    fn test_validate() {
+        let azure = AzureConfig {
+            api_version: "2024-06-01".to_string(),
+            deployments: HashMap::new(),
+        };
+        let config = ProviderConfig {
+            azure: Some(azure.clone()),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        assert!(matches!(
+            OpenAiClient::new("secret", &config),
+            Err(ProviderError::MissingBaseUrl)
+        ));
+
+        let config = ProviderConfig {
+            headers: HashMap::from([("bad header".to_string(), "x".to_string())]),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.validate().unwrap_err(),
+            "Invalid header \"bad header\""
+        );
+
+        let config = ProviderConfig {
+            base_url: Some("https://my-org.openai.azure.com".to_string()),
+            proxy: Some("http://proxy.internal:3128".to_string()),
+            azure: Some(azure),
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+        assert!(OpenAiClient::new("secret", &config).is_ok());
+    }
+}
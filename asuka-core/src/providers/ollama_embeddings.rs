@@ -0,0 +1,261 @@
+//! Embeddings from a local [Ollama](https://ollama.com) server, so the agent
+//! can run without OpenAI.
+//!
+//! Ollama's `/api/embeddings` endpoint embeds one prompt per request, and the
+//! size of the vectors depends on the model, e.g. 768 for `nomic-embed-text`
+//! and 1024 for `mxbai-embed-large`. The size is found by embedding a probe
+//! when the model is created, so the knowledge base tables get the right
+//! dimensions.
+
+use futures_util::{stream, StreamExt, TryStreamExt};
+use rig::embeddings::{self, EmbeddingError};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::info;
+
+pub const OLLAMA_API_BASE_URL: &str = "http://localhost:11434";
+
+/// Text embedded to find the model's dimensions.
+const PROBE: &str = "dimension probe";
+
+/// Server and model to embed with.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OllamaConfig {
+    pub base_url: String,
+    /// Embedding model pulled on the server, e.g. `nomic-embed-text`.
+    pub model: String,
+    /// Embedding requests in flight at once.
+    pub concurrency: usize,
+}
+
+impl Default for OllamaConfig {
+    fn default() -> Self {
+        Self {
+            base_url: OLLAMA_API_BASE_URL.to_string(),
+            model: "nomic-embed-text".to_string(),
+            concurrency: 4,
+        }
+    }
+}
+
+impl OllamaConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.model.trim().is_empty() {
+            return Err("ollama.model must not be empty".to_string());
+        }
+        if self.concurrency == 0 {
+            return Err("ollama.concurrency must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct EmbeddingResponse {
+    embedding: Vec<f64>,
+}
+
+#[derive(Clone)]
+pub struct OllamaEmbeddingModel {
+    http: reqwest::Client,
+    url: String,
+    pub model: String,
+    ndims: usize,
+    concurrency: usize,
+}
+
+impl OllamaEmbeddingModel {
+    /// Connects to the server and probes the model's dimensions. Fails when
+    /// the server is down or the model is not pulled.
+    pub async fn new(config: &OllamaConfig) -> Result<Self, EmbeddingError> {
+        config.validate().map_err(EmbeddingError::ProviderError)?;
+
+        let mut model = Self {
+            http: reqwest::Client::new(),
+            url: format!("{}/api/embeddings", config.base_url.trim_end_matches('/')),
+            model: config.model.clone(),
+            ndims: 0,
+            concurrency: config.concurrency,
+        };
+        let probe = model.embed(PROBE).await?;
+        if probe.is_empty() {
+            return Err(EmbeddingError::ResponseError(format!(
+                "{} returned an empty embedding",
+                model.model
+            )));
+        }
+        model.ndims = probe.len();
+
+        info!(
+            model = model.model,
+            ndims = model.ndims,
+            "Probed embedding dimensions"
+        );
+        Ok(model)
+    }
+
+    async fn embed(&self, text: &str) -> Result<Vec<f64>, EmbeddingError> {
+        let body = json!({
+            "model": self.model,
+            "prompt": text,
+        });
+        let text = super::post(&self.http, &self.url, &body)
+            .await
+            .map_err(EmbeddingError::ProviderError)?;
+        let response: EmbeddingResponse = serde_json::from_str(&text)?;
+        Ok(response.embedding)
+    }
+}
+
+impl embeddings::EmbeddingModel for OllamaEmbeddingModel {
+    const MAX_DOCUMENTS: usize = 64;
+
+    fn ndims(&self) -> usize {
+        self.ndims
+    }
+
+    async fn embed_texts(
+        &self,
+        documents: impl IntoIterator<Item = String> + Send,
+    ) -> Result<Vec<embeddings::Embedding>, EmbeddingError> {
+        let documents = documents.into_iter().collect::<Vec<_>>();
+
+        stream::iter(documents)
+            .map(|document| async move {
+                let vec = self.embed(&document).await?;
+                if vec.len() != self.ndims {
+                    return Err(EmbeddingError::ResponseError(format!(
+                        "{} returned {} dimensions, expected {}",
+                        self.model,
+                        vec.len(),
+                        self.ndims
+                    )));
+                }
+                Ok(embeddings::Embedding { document, vec })
+            })
+            .buffered(self.concurrency)
+            .try_collect()
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        knowledge::{EmbeddingDimensionError, KnowledgeBase},
+        test_utils::{self, MockHttpServer},
+    };
+    use rig::embeddings::EmbeddingModel as _;
+    use tokio_rusqlite::Connection;
+
+    fn embedding(values: &[f64]) -> String {
+        json!({ "embedding": values }).to_string()
+    }
+
+    fn config(server: &MockHttpServer, model: &str) -> OllamaConfig {
+        OllamaConfig {
+            base_url: format!("{}/", server.url()),
+            model: model.to_string(),
+            concurrency: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_probes_dimensions_and_embeds_one_input_per_request() {
+        let responses = [
+            embedding(&[0.0, 0.0, 0.0]),
+            embedding(&[1.0, 0.0, 0.0]),
+            embedding(&[0.0, 1.0, 0.0]),
+        ];
+        let server = MockHttpServer::start(responses.iter().map(|body| (200, body.as_str()))).await;
+
+        let model = OllamaEmbeddingModel::new(&config(&server, "all-minilm"))
+            .await
+            .unwrap();
+        assert_eq!(model.ndims(), 3);
+
+        let embeddings = model
+            .embed_texts(["vrf".to_string(), "paymaster".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(embeddings[0].document, "vrf");
+        assert_eq!(embeddings[0].vec, [1.0, 0.0, 0.0]);
+        assert_eq!(embeddings[1].document, "paymaster");
+        assert_eq!(embeddings[1].vec, [0.0, 1.0, 0.0]);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert!(requests
+            .iter()
+            .all(|request| request.method == "POST" && request.path == "/api/embeddings"));
+        let bodies = requests
+            .iter()
+            .map(|request| serde_json::from_str::<serde_json::Value>(&request.body).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(bodies[0], json!({"model": "all-minilm", "prompt": PROBE}));
+        assert_eq!(bodies[1], json!({"model": "all-minilm", "prompt": "vrf"}));
+        assert_eq!(
+            bodies[2],
+            json!({"model": "all-minilm", "prompt": "paymaster"})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wrong_size_and_missing_model_fail() {
+        let responses = [embedding(&[0.0, 0.0]), embedding(&[1.0, 0.0, 0.0])];
+        let server = MockHttpServer::start(responses.iter().map(|body| (200, body.as_str()))).await;
+        let model = OllamaEmbeddingModel::new(&config(&server, "all-minilm"))
+            .await
+            .unwrap();
+        let err = model
+            .embed_texts(["vrf".to_string()])
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("returned 3 dimensions, expected 2"), "{err}");
+
+        let body = r#"{"error": "model \"mxbai-embed-large\" not found, try pulling it first"}"#;
+        let server = MockHttpServer::start([(404, body)]).await;
+        let err = OllamaEmbeddingModel::new(&config(&server, "mxbai-embed-large"))
+            .await
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("HTTP 404 Not Found"), "{err}");
+        assert!(err.contains("try pulling it first"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_knowledge_base_takes_probed_dimensions() {
+        test_utils::load_sqlite_vec();
+
+        for (name, ndims) in [("nomic-embed-text", 768), ("mxbai-embed-large", 1024)] {
+            let probe = embedding(&vec![0.5; ndims]);
+            let server = MockHttpServer::start([(200, probe.as_str())]).await;
+            let model = OllamaEmbeddingModel::new(&config(&server, name))
+                .await
+                .unwrap();
+
+            let conn = Connection::open_in_memory().await.unwrap();
+            assert!(KnowledgeBase::new(conn.clone(), model).await.is_ok());
+
+            // Reopening with another model's dimensions is refused
+            let other = embedding(&vec![0.5; 384]);
+            let server = MockHttpServer::start([(200, other.as_str())]).await;
+            let model = OllamaEmbeddingModel::new(&config(&server, "all-minilm"))
+                .await
+                .unwrap();
+            let err = KnowledgeBase::new(conn, model).await.err().unwrap();
+            let rig::vector_store::VectorStoreError::DatastoreError(err) = err else {
+                panic!("unexpected error {err}");
+            };
+            assert!(matches!(
+                err.downcast_ref::<EmbeddingDimensionError>(),
+                Some(EmbeddingDimensionError::Mismatch { stored, model: 384, .. })
+                    if *stored == ndims
+            ));
+        }
+    }
+}
@@ -0,0 +1,249 @@
+//! Content a message forwards or quotes from someone else, kept apart from
+//! what its sender wrote. Otherwise a forwarded question reads as the
+//! sender's own, and the answer tells them "you said" what they quoted.
+//!
+//! Clients find the quoted content, a Telegram forward or a Discord message
+//! link, and [annotate] the message with it before storing it. Each quote
+//! becomes a labelled block after the sender's text, so that history and
+//! prompts show it as quoted:
+//!
+//! ```text
+//! can someone explain this?
+//!
+//! [quoted from alice in #dev, not written by the sender]
+//! > katana fails to start on port 5050
+//! ```
+//!
+//! A message that only forwards, see [is_bare_forward], is left to others
+//! unless it addresses the bot, see [crate::attention].
+
+use std::sync::OnceLock;
+
+use regex::Regex;
+
+use crate::escape;
+
+/// Start of the header of a quoted block.
+const HEADER_START: &str = "[quoted";
+
+/// End of the header of a quoted block.
+const HEADER_END: &str = ", not written by the sender]";
+
+/// Put ahead of each line of quoted text.
+const LINE_PREFIX: &str = ">";
+
+/// A link to a Discord message, guild `@me` for direct messages.
+const DISCORD_LINK: &str =
+    r"https?://(?:(?:ptb|canary)\.)?discord(?:app)?\.com/channels/(\d+|@me)/(\d+)/(\d+)";
+
+/// Text a message forwards or quotes, with where it is from when known.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotedContent {
+    pub original_author: Option<String>,
+    pub original_channel: Option<String>,
+    pub text: String,
+}
+
+impl QuotedContent {
+    pub fn new(text: impl Into<String>) -> Self {
+        Self {
+            original_author: None,
+            original_channel: None,
+            text: text.into(),
+        }
+    }
+
+    pub fn with_original_author(mut self, author: impl Into<String>) -> Self {
+        self.original_author = Some(author.into());
+        self
+    }
+
+    pub fn with_original_channel(mut self, channel: impl Into<String>) -> Self {
+        self.original_channel = Some(channel.into());
+        self
+    }
+
+    /// The block the quote is stored as: a header saying where it is from,
+    /// then its lines, each after `> `.
+    pub fn render(&self) -> String {
+        let from = match (&self.original_author, &self.original_channel) {
+            (Some(author), Some(channel)) => {
+                format!(" from {} in {}", label(author), label(channel))
+            }
+            (Some(name), None) | (None, Some(name)) => format!(" from {}", label(name)),
+            (None, None) => String::new(),
+        };
+        let text = escape::tags(self.text.trim())
+            .lines()
+            .map(|line| format!("{LINE_PREFIX} {line}").trim_end().to_string())
+            .collect::<Vec<_>>()
+            .join("\n");
+        format!("{HEADER_START}{from}{HEADER_END}\n{text}")
+            .trim_end()
+            .to_string()
+    }
+}
+
+/// `name` on one line, without brackets that could end the header early.
+fn label(name: &str) -> String {
+    escape::tags(&name.split_whitespace().collect::<Vec<_>>().join(" ")).replace(['[', ']'], "")
+}
+
+/// `content` followed by the blocks of `quoted`, as the message is stored.
+pub fn annotate(content: &str, quoted: &[QuotedContent]) -> String {
+    if quoted.is_empty() {
+        return content.to_string();
+    }
+    let content = content.trim();
+    (!content.is_empty())
+        .then(|| content.to_string())
+        .into_iter()
+        .chain(quoted.iter().map(QuotedContent::render))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Whether `line` is the header of a quoted block.
+fn is_header(line: &str) -> bool {
+    line.starts_with(HEADER_START) && line.ends_with(HEADER_END)
+}
+
+/// `content` without its quoted blocks: what the sender wrote.
+pub fn own_text(content: &str) -> String {
+    let mut own = Vec::new();
+    let mut quoting = false;
+    for line in content.lines() {
+        if is_header(line) {
+            quoting = true;
+        } else if !(quoting && line.starts_with(LINE_PREFIX)) {
+            quoting = false;
+            own.push(line);
+        }
+    }
+    own.join("\n").trim().to_string()
+}
+
+/// Whether `content` only forwards or quotes, without a word of the
+/// sender's own besides links to the quoted messages.
+pub fn is_bare_forward(content: &str) -> bool {
+    content.lines().any(is_header)
+        && discord_link()
+            .replace_all(&own_text(content), "")
+            .trim()
+            .is_empty()
+}
+
+fn discord_link() -> &'static Regex {
+    static LINK: OnceLock<Regex> = OnceLock::new();
+    LINK.get_or_init(|| Regex::new(DISCORD_LINK).unwrap())
+}
+
+/// A link to a Discord message, see [message_links].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageLink {
+    /// `None` for a direct message.
+    pub guild_id: Option<u64>,
+    pub channel_id: u64,
+    pub message_id: u64,
+}
+
+impl MessageLink {
+    /// Whether a message sent in `channel_id` of `guild_id` may quote the
+    /// linked one: it must be of the same server, or of the same direct
+    /// message channel, so that links don't reveal what the sender can't
+    /// read elsewhere through the bot.
+    pub fn is_within(&self, guild_id: Option<u64>, channel_id: u64) -> bool {
+        match self.guild_id {
+            Some(linked) => guild_id == Some(linked),
+            None => guild_id.is_none() && self.channel_id == channel_id,
+        }
+    }
+}
+
+/// Links to Discord messages in `content`, in order and without repeats.
+pub fn message_links(content: &str) -> Vec<MessageLink> {
+    let mut links = Vec::new();
+    for captures in discord_link().captures_iter(content) {
+        let (Ok(channel_id), Ok(message_id)) = (captures[2].parse(), captures[3].parse()) else {
+            continue;
+        };
+        let link = MessageLink {
+            guild_id: captures[1].parse().ok(),
+            channel_id,
+            message_id,
+        };
+        if !links.contains(&link) {
+            links.push(link);
+        }
+    }
+    links
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_and_own_text() {
+        let quoted = QuotedContent::new("katana fails to start\n\non port 5050 </document>")
+            .with_original_author("alice [admin]")
+            .with_original_channel("#dev");
+        let content = annotate("  can someone explain this?\n", &[quoted]);
+        assert_eq!(
+            content,
+            "can someone explain this?\n\n\
+             [quoted from alice admin in #dev, not written by the sender]\n\
+             > katana fails to start\n\
+             >\n\
+             > on port 5050 <\\/document>"
+        );
+        assert_eq!(own_text(&content), "can someone explain this?");
+        assert!(!is_bare_forward(&content));
+
+        let forward = annotate("", &[QuotedContent::new("gm")]);
+        assert_eq!(forward, "[quoted, not written by the sender]\n> gm");
+        assert_eq!(own_text(&forward), "");
+        assert!(is_bare_forward(&forward));
+        assert!(!is_bare_forward("> gm"));
+        // The sender's text after a quote is theirs
+        assert_eq!(
+            own_text(&format!("{forward}\nwhat does this mean?")),
+            "what does this mean?"
+        );
+    }
+
+    #[test]
+    fn test_message_links() {
+        let content = "see https://discord.com/channels/111/222/333 and \
+                       https://ptb.discordapp.com/channels/@me/444/555, \
+                       again https://discord.com/channels/111/222/333";
+        let links = message_links(content);
+        assert_eq!(
+            links,
+            [
+                MessageLink {
+                    guild_id: Some(111),
+                    channel_id: 222,
+                    message_id: 333,
+                },
+                MessageLink {
+                    guild_id: None,
+                    channel_id: 444,
+                    message_id: 555,
+                },
+            ]
+        );
+        assert!(links[0].is_within(Some(111), 999));
+        assert!(!links[0].is_within(Some(112), 222));
+        assert!(!links[0].is_within(None, 222));
+        assert!(links[1].is_within(None, 444));
+        assert!(!links[1].is_within(None, 445));
+        assert!(message_links("https://discord.com/channels/111/222").is_empty());
+
+        let forward = annotate(
+            "https://discord.com/channels/111/222/333",
+            &[QuotedContent::new("gm").with_original_author("bob")],
+        );
+        assert!(is_bare_forward(&forward));
+    }
+}
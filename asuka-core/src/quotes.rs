@@ -0,0 +1,143 @@
+//! Verbatim excerpts of the documents an answer was given from, for
+//! follow-ups like "where does it say that?". A paraphrase is what the user
+//! is doubting, so the excerpts are cut from the stored documents rather
+//! than generated, and come from the same documents as the answer instead of
+//! a fresh search. See [Agent::quote_sources](crate::agent::Agent::quote_sources).
+
+/// Longest excerpt of one document, in characters.
+pub const MAX_EXCERPT_CHARS: usize = 400;
+
+/// Phrasings of a request for the source, as words.
+const QUOTE_REQUESTS: &[&[&str]] = &[
+    &["where", "does", "it", "say"],
+    &["where", "did", "you", "read"],
+    &["where", "did", "you", "get"],
+    &["where", "is", "that", "from"],
+    &["quote"],
+    &["verbatim"],
+    &["word", "for", "word"],
+    &["exact", "line"],
+    &["exact", "wording"],
+    &["exact", "words"],
+    &["your", "source"],
+    &["source", "for", "that"],
+    &["cite"],
+];
+
+/// Whether `text` asks for the source of the previous answer.
+pub fn is_quote_request(text: &str) -> bool {
+    let words = words(text);
+    QUOTE_REQUESTS.iter().any(|phrase| {
+        words
+            .windows(phrase.len())
+            .any(|window| window.iter().zip(phrase.iter()).all(|(a, b)| a == b))
+    })
+}
+
+/// The part of `text` closest to `focus`, such as the answer being asked
+/// about, cut verbatim and at most `max_chars` long. Paragraphs are tried
+/// first, then lines; a part still too long is cut and ends with `…`.
+pub fn excerpt(text: &str, focus: &str, max_chars: usize) -> String {
+    let focus = words(focus);
+    let overlap = |part: &str| {
+        words(part)
+            .iter()
+            .filter(|word| word.len() > 3 && focus.contains(word))
+            .count()
+    };
+    let best = |parts: Vec<&str>| {
+        parts
+            .into_iter()
+            .map(str::trim)
+            .filter(|part| !part.is_empty())
+            .fold(None, |best: Option<(&str, usize)>, part| {
+                let score = overlap(part);
+                match best {
+                    Some((_, best_score)) if best_score >= score => best,
+                    _ => Some((part, score)),
+                }
+            })
+            .map(|(part, _)| part)
+    };
+
+    let mut part = text.trim();
+    if part.chars().count() > max_chars {
+        part = best(part.split("\n\n").collect()).unwrap_or(part);
+    }
+    if part.chars().count() > max_chars {
+        part = best(part.lines().collect()).unwrap_or(part);
+    }
+    match part.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}…", part[..end].trim_end()),
+        None => part.to_string(),
+    }
+}
+
+/// `excerpt` as a block quote, followed by its citation.
+pub fn block_quote(excerpt: &str, citation: &str) -> String {
+    let quoted = excerpt
+        .lines()
+        .map(|line| format!("> {line}").trim_end().to_string())
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!("{quoted}\n— {citation}")
+}
+
+fn words(text: &str) -> Vec<String> {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote_requests() {
+        for text in [
+            "Where does it say that?",
+            "can you quote the exact line",
+            "what's your source?",
+            "source for that pls",
+            "word-for-word please",
+        ] {
+            assert!(is_quote_request(text), "{text}");
+        }
+        for text in [
+            "How do I rotate session keys?",
+            "that's exciting",
+            "where does it run?",
+            "",
+        ] {
+            assert!(!is_quote_request(text), "{text}");
+        }
+    }
+
+    #[test]
+    fn test_excerpt() {
+        let text = "# Fees\n\nRequests cost a flat fee of 0.01 ETH.\n\nFees are refunded when a request fails.";
+
+        assert_eq!(excerpt(text, "anything", 200), text);
+        assert_eq!(
+            excerpt(text, "Failed requests get their fees refunded.", 60),
+            "Fees are refunded when a request fails."
+        );
+        assert_eq!(
+            excerpt(text, "The flat fee is 0.01 ETH per request.", 60),
+            "Requests cost a flat fee of 0.01 ETH."
+        );
+        assert_eq!(excerpt("Fees are refunded", "", 4), "Fees…");
+        assert_eq!(excerpt("Ünïcödé text", "", 5), "Ünïcö…");
+    }
+
+    #[test]
+    fn test_block_quote() {
+        assert_eq!(
+            block_quote("Line one\n\nLine two", "VRF — Fees"),
+            "> Line one\n>\n> Line two\n— VRF — Fees"
+        );
+    }
+}
@@ -0,0 +1,389 @@
+//! Per-user limit on answers, so one enthusiastic user can't spend the
+//! completion budget and drown out everyone else.
+//!
+//! Answers are counted per source and account over a sliding minute and a
+//! sliding hour, in the knowledge base so restarts don't reset them. A user
+//! over either limit is told once, with the
+//! [RATE_LIMITED](crate::templates::RATE_LIMITED) template, when to try
+//! again; their messages are then ignored until the window frees up, and
+//! counted in the operator [digest](crate::digest).
+//!
+//! The limit is checked once a batch is complete, before the attention
+//! decision, so an ignored message neither costs a model call nor counts
+//! towards the channel cooldown. Only answers count: messages the bot lets
+//! pass or reacts to don't. Direct messages and guild channels share one
+//! count, a user can't double the limit by asking in both, and neither do
+//! [linked accounts](crate::linking) on different platforms. Admins and the
+//! bot owner are always exempt.
+//!
+//! ```toml
+//! [rate_limit]
+//! per_hour = 10
+//! per_minute = 3
+//! exempt_users = ["80351110224678912"]
+//! exempt_roles = ["1234567893"]
+//! ```
+
+use chrono::{DateTime, Duration, Utc};
+use rig::embeddings::EmbeddingModel;
+use serde::Deserialize;
+use tracing::error;
+
+use crate::knowledge::{KnowledgeBase, RateEvent, Source};
+
+/// `[rate_limit]` settings of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Answers a user gets in any hour.
+    pub per_hour: usize,
+    /// Answers a user gets in any minute.
+    pub per_minute: usize,
+    /// Users, by platform id, never limited.
+    pub exempt_users: Vec<String>,
+    /// Discord roles, by id, whose members are never limited.
+    pub exempt_roles: Vec<String>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            per_hour: 10,
+            per_minute: 3,
+            exempt_users: Vec::new(),
+            exempt_roles: Vec::new(),
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.per_hour == 0 || self.per_minute == 0 {
+            return Err("rate_limit: per_hour and per_minute must be above 0".to_string());
+        }
+        if self.per_minute > self.per_hour {
+            return Err(format!(
+                "rate_limit.per_minute: {} is more than per_hour",
+                self.per_minute
+            ));
+        }
+        if let Some(id) = self
+            .exempt_roles
+            .iter()
+            .find(|id| id.parse::<u64>().is_err())
+        {
+            return Err(format!("rate_limit.exempt_roles: {id:?} is not a role id"));
+        }
+        Ok(())
+    }
+
+    /// Whether `account_id`, with `roles`, is never limited.
+    pub fn is_exempt(&self, account_id: &str, roles: &[String]) -> bool {
+        self.exempt_users.iter().any(|id| id == account_id)
+            || roles.iter().any(|role| self.exempt_roles.contains(role))
+    }
+
+    /// Limits with the window they apply to.
+    fn windows(&self) -> [(usize, Duration); 2] {
+        [
+            (self.per_minute, Duration::minutes(1)),
+            (self.per_hour, Duration::hours(1)),
+        ]
+    }
+}
+
+/// What to do with a user's message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateDecision {
+    /// Handle it as usual.
+    Allow,
+    /// Tell the user they are over the limit instead of answering.
+    Notify { retry_after: Duration },
+    /// Ignore it, the user was already told.
+    Suppress,
+}
+
+/// The decision for a message at `now`, given the user's events of the last
+/// hour, oldest first.
+pub fn decide(
+    config: &RateLimitConfig,
+    events: &[(RateEvent, DateTime<Utc>)],
+    now: DateTime<Utc>,
+) -> RateDecision {
+    let responses = events
+        .iter()
+        .filter(|(event, _)| *event == RateEvent::Response)
+        .map(|(_, at)| *at)
+        .collect::<Vec<_>>();
+
+    // The earliest time every window is under its limit again
+    let free_at = config
+        .windows()
+        .into_iter()
+        .filter_map(|(limit, window)| {
+            let recent = responses
+                .iter()
+                .filter(|at| **at > now - window)
+                .collect::<Vec<_>>();
+            (recent.len() >= limit).then(|| *recent[recent.len() - limit] + window)
+        })
+        .max();
+    let Some(free_at) = free_at else {
+        return RateDecision::Allow;
+    };
+
+    let last_response = responses.last().copied();
+    let notified = events.iter().any(|(event, at)| {
+        *event == RateEvent::Notice && !last_response.is_some_and(|last| *at < last)
+    });
+    if notified {
+        RateDecision::Suppress
+    } else {
+        RateDecision::Notify {
+            retry_after: free_at - now,
+        }
+    }
+}
+
+/// `retry_after` as the template's `{{retry_after}}`, such as "20 minutes".
+pub fn retry_after_text(retry_after: Duration) -> String {
+    let minutes = (retry_after.num_seconds() + 59).div_euclid(60).max(1);
+    match minutes {
+        1 => "a minute".to_string(),
+        60 => "an hour".to_string(),
+        minutes => format!("{minutes} minutes"),
+    }
+}
+
+/// Applies [RateLimitConfig] with the events stored in the knowledge base.
+/// Failing to read or record them never blocks an answer.
+#[derive(Clone)]
+pub struct RateLimiter<E: EmbeddingModel + 'static> {
+    knowledge: KnowledgeBase<E>,
+    config: RateLimitConfig,
+}
+
+impl<E: EmbeddingModel + 'static> RateLimiter<E> {
+    pub fn new(knowledge: KnowledgeBase<E>, config: RateLimitConfig) -> Self {
+        Self { knowledge, config }
+    }
+
+    pub fn config(&self) -> &RateLimitConfig {
+        &self.config
+    }
+
+    /// The decision for a message of `account_id`, a member of `roles`.
+    pub async fn check(
+        &self,
+        source: &Source,
+        account_id: &str,
+        roles: &[String],
+        now: DateTime<Utc>,
+    ) -> RateDecision {
+        if self.config.is_exempt(account_id, roles) {
+            return RateDecision::Allow;
+        }
+        match self.events(source, account_id, now).await {
+            Ok(events) => decide(&self.config, &events, now),
+            Err(err) => {
+                error!(?err, account_id, "Failed to read rate limit events");
+                RateDecision::Allow
+            }
+        }
+    }
+
+    /// The last hour's events of the user and the accounts linked to it,
+    /// oldest first.
+    async fn events(
+        &self,
+        source: &Source,
+        account_id: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<(RateEvent, DateTime<Utc>)>, rig_sqlite::SqliteError> {
+        let mut events = Vec::new();
+        for (source, account_id) in self.knowledge.linked_accounts(source, account_id).await? {
+            events.extend(
+                self.knowledge
+                    .rate_events(&source, &account_id, now - Duration::hours(1))
+                    .await?,
+            );
+        }
+        events.sort_by_key(|(_, at)| *at);
+        Ok(events)
+    }
+
+    /// Records what happened to a message of `account_id`.
+    pub async fn record(
+        &self,
+        source: &Source,
+        account_id: &str,
+        event: RateEvent,
+        now: DateTime<Utc>,
+    ) {
+        if let Err(err) = self
+            .knowledge
+            .record_rate_event(source, account_id, event, now)
+            .await
+        {
+            error!(
+                ?err,
+                account_id,
+                ?event,
+                "Failed to record rate limit event"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2025-01-15T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+            + Duration::seconds(seconds)
+    }
+
+    #[test]
+    fn test_minute_burst() {
+        let config = RateLimitConfig::default();
+        let events = [0, 10, 20].map(|s| (RateEvent::Response, at(s)));
+
+        assert_eq!(decide(&config, &events[..2], at(30)), RateDecision::Allow);
+        assert_eq!(
+            decide(&config, &events, at(30)),
+            RateDecision::Notify {
+                retry_after: Duration::seconds(30)
+            }
+        );
+        // The first answer leaves the window
+        assert_eq!(decide(&config, &events, at(60)), RateDecision::Allow);
+    }
+
+    #[test]
+    fn test_hourly_limit_with_single_notice() {
+        let config = RateLimitConfig::default();
+        // Ten answers, five minutes apart
+        let mut events = (0..10)
+            .map(|i| (RateEvent::Response, at(i * 300)))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            decide(&config, &events, at(2800)),
+            RateDecision::Notify {
+                retry_after: Duration::seconds(800)
+            }
+        );
+        events.push((RateEvent::Notice, at(2800)));
+        assert_eq!(decide(&config, &events, at(2900)), RateDecision::Suppress);
+        assert_eq!(decide(&config, &events, at(3599)), RateDecision::Suppress);
+
+        // Once the oldest answer is an hour old, one more is allowed
+        assert_eq!(decide(&config, &events, at(3600)), RateDecision::Allow);
+        events.push((RateEvent::Response, at(3600)));
+        // Over again, so the user is told again
+        assert_eq!(
+            decide(&config, &events, at(3700)),
+            RateDecision::Notify {
+                retry_after: Duration::seconds(200)
+            }
+        );
+    }
+
+    #[test]
+    fn test_retry_after_text() {
+        assert_eq!(retry_after_text(Duration::seconds(5)), "a minute");
+        assert_eq!(retry_after_text(Duration::seconds(60)), "a minute");
+        assert_eq!(retry_after_text(Duration::seconds(1141)), "20 minutes");
+        assert_eq!(retry_after_text(Duration::hours(1)), "an hour");
+    }
+
+    #[test]
+    fn test_config() {
+        assert!(RateLimitConfig::default().validate().is_ok());
+        for invalid in [
+            "per_hour = 0",
+            "per_minute = 20",
+            "exempt_roles = [\"mods\"]",
+        ] {
+            let config: RateLimitConfig = toml::from_str(invalid).unwrap();
+            assert!(config.validate().is_err(), "{invalid}");
+        }
+
+        let config: RateLimitConfig =
+            toml::from_str("exempt_users = [\"u1\"]\nexempt_roles = [\"42\"]").unwrap();
+        assert!(config.is_exempt("u1", &[]));
+        assert!(config.is_exempt("u2", &["42".to_string()]));
+        assert!(!config.is_exempt("u2", &["43".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn test_limits_survive_restarts() {
+        let knowledge = test_utils::knowledge_base().await;
+        let config = RateLimitConfig {
+            per_minute: 1,
+            ..Default::default()
+        };
+        let limiter = RateLimiter::new(knowledge.clone(), config.clone());
+        limiter
+            .record(&Source::Discord, "u1", RateEvent::Response, at(0))
+            .await;
+
+        // A new limiter reads the same events
+        let limiter = RateLimiter::new(knowledge, config);
+        assert!(matches!(
+            limiter.check(&Source::Discord, "u1", &[], at(10)).await,
+            RateDecision::Notify { .. }
+        ));
+        assert_eq!(
+            limiter.check(&Source::Telegram, "u1", &[], at(10)).await,
+            RateDecision::Allow
+        );
+        limiter
+            .record(&Source::Discord, "u1", RateEvent::Notice, at(10))
+            .await;
+        assert_eq!(
+            limiter.check(&Source::Discord, "u1", &[], at(20)).await,
+            RateDecision::Suppress
+        );
+        assert_eq!(
+            limiter.check(&Source::Discord, "u1", &[], at(60)).await,
+            RateDecision::Allow
+        );
+    }
+
+    #[tokio::test]
+    async fn test_linked_accounts_share_the_limit() {
+        let knowledge = test_utils::knowledge_base().await;
+        let code = knowledge
+            .create_link_code(&Source::Discord, "u1", Duration::minutes(10))
+            .await
+            .unwrap();
+        knowledge
+            .redeem_link_code(&code, &Source::Telegram, "t1")
+            .await
+            .unwrap();
+        let limiter = RateLimiter::new(
+            knowledge,
+            RateLimitConfig {
+                per_minute: 1,
+                ..Default::default()
+            },
+        );
+
+        limiter
+            .record(&Source::Discord, "u1", RateEvent::Response, at(0))
+            .await;
+        assert!(matches!(
+            limiter.check(&Source::Telegram, "t1", &[], at(10)).await,
+            RateDecision::Notify { .. }
+        ));
+        assert_eq!(
+            limiter.check(&Source::Telegram, "t2", &[], at(10)).await,
+            RateDecision::Allow
+        );
+    }
+}
@@ -0,0 +1,640 @@
+//! Reports errors to the operator as they happen, instead of leaving them in
+//! the log: an expired API key or a locked database otherwise only shows as a
+//! bot that stopped answering.
+//!
+//! [ErrorReporter::layer] picks up error events from tracing, and
+//! [ErrorReporter::report] can be called from catch points. Errors are
+//! deduplicated by fingerprint, the source and the start of the message,
+//! within [ReportingConfig::window_secs], and at most
+//! [ReportingConfig::max_per_hour] reports are sent. A report carries the
+//! correlation id, when the event or one of its spans has a `correlation_id`
+//! field, and how often the error occurred since it was first seen.
+//!
+//! Nothing is reported until [ErrorReporter::start] is called, which the
+//! `[reporting]` section of the config file enables:
+//!
+//! ```toml
+//! [reporting]
+//! discord_channel = "1234567896"
+//! max_per_hour = 10
+//! ```
+//!
+//! Once given a bus with [ErrorReporter::publish_to], the reporter also
+//! publishes each report as an [ErrorBurst](crate::events::Event::ErrorBurst)
+//! event for the [webhooks](crate::webhooks).
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::{Arc, OnceLock},
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::mpsc;
+use tracing::{
+    field::{Field, Visit},
+    span, warn, Event, Level, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+use crate::events::EventBus;
+
+/// Target of the reporter's own events, which are never reported.
+pub const REPORTING_TARGET: &str = "asuka::reporting";
+
+/// Errors waiting for the reporting task. Events beyond it are dropped rather
+/// than slowing down whatever is logging them.
+const QUEUE_SIZE: usize = 256;
+
+/// Characters of a message kept in a report.
+const MAX_MESSAGE_LENGTH: usize = 500;
+
+tokio::task_local! {
+    /// Set while a report is delivered, so errors logged by the sink itself
+    /// aren't reported in turn.
+    static DELIVERING: ();
+}
+
+/// `[reporting]` settings of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReportingConfig {
+    /// Discord channel reports are posted to.
+    pub discord_channel: Option<String>,
+    /// Telegram chat reports are sent to, when there's no Discord channel.
+    pub telegram_chat: Option<i64>,
+    /// URL reports are posted to, when there's neither, as the `content` and
+    /// `text` fields Discord and Slack incoming webhooks read.
+    pub webhook: Option<String>,
+    /// Seconds an error is not reported again after a report.
+    pub window_secs: u64,
+    /// Reports sent at most in any hour.
+    pub max_per_hour: usize,
+    /// Characters of the message that identify an error, so details such as
+    /// ids at the end don't defeat deduplication.
+    pub fingerprint_length: usize,
+}
+
+impl Default for ReportingConfig {
+    fn default() -> Self {
+        Self {
+            discord_channel: None,
+            telegram_chat: None,
+            webhook: None,
+            window_secs: 3600,
+            max_per_hour: 10,
+            fingerprint_length: 120,
+        }
+    }
+}
+
+impl ReportingConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.discord_channel.is_none() && self.telegram_chat.is_none() && self.webhook.is_none()
+        {
+            return Err(
+                "reporting: one of discord_channel, telegram_chat or webhook is required"
+                    .to_string(),
+            );
+        }
+        if let Some(channel) = &self.discord_channel {
+            if channel.parse::<u64>().is_err() {
+                return Err(format!(
+                    "reporting.discord_channel: {channel:?} is not a channel id"
+                ));
+            }
+        }
+        if let Some(webhook) = &self.webhook {
+            if !webhook.starts_with("https://") && !webhook.starts_with("http://") {
+                return Err(format!("reporting.webhook: {webhook:?} is not a URL"));
+            }
+        }
+        if self.max_per_hour == 0 {
+            return Err("reporting.max_per_hour must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Where reports are sent.
+#[async_trait]
+pub trait ReportSink: Send + Sync {
+    async fn deliver(&self, report: &str) -> Result<(), String>;
+}
+
+/// Posts reports to a webhook, such as a Discord or Slack incoming webhook.
+pub struct WebhookReport {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl WebhookReport {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: url.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl ReportSink for WebhookReport {
+    async fn deliver(&self, report: &str) -> Result<(), String> {
+        self.client
+            .post(&self.url)
+            .json(&json!({ "content": report, "text": report }))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// An error, as logged or passed to [ErrorReporter::report].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorEvent {
+    /// Where the error comes from, the target of a tracing event.
+    pub kind: String,
+    pub message: String,
+    pub correlation_id: Option<String>,
+    pub at: DateTime<Utc>,
+}
+
+/// An error to send, with how often it occurred since it was first seen.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorReport {
+    pub kind: String,
+    pub message: String,
+    pub correlation_id: Option<String>,
+    pub first_seen: DateTime<Utc>,
+    pub occurrences: u64,
+}
+
+impl fmt::Display for ErrorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = truncate(&self.message, MAX_MESSAGE_LENGTH);
+        writeln!(f, "Error: {message}")?;
+        writeln!(f, "Source: {}", self.kind)?;
+        if let Some(id) = &self.correlation_id {
+            writeln!(f, "Correlation id: {id}")?;
+        }
+        write!(
+            f,
+            "Occurrences: {} since {}",
+            self.occurrences,
+            self.first_seen.format("%Y-%m-%d %H:%M:%S UTC")
+        )
+    }
+}
+
+struct Seen {
+    first_seen: DateTime<Utc>,
+    last_seen: DateTime<Utc>,
+    reported_at: Option<DateTime<Utc>>,
+    occurrences: u64,
+}
+
+/// Decides which errors are reported: each fingerprint at most once per
+/// window, and at most [ReportingConfig::max_per_hour] reports in any hour.
+/// An error held back by the hourly limit is reported on its next occurrence
+/// once the limit allows.
+pub struct ReportLimiter {
+    window: chrono::Duration,
+    max_per_hour: usize,
+    fingerprint_length: usize,
+    seen: HashMap<String, Seen>,
+    sent: VecDeque<DateTime<Utc>>,
+}
+
+impl ReportLimiter {
+    pub fn new(config: &ReportingConfig) -> Self {
+        Self {
+            window: chrono::Duration::seconds(config.window_secs as i64),
+            max_per_hour: config.max_per_hour,
+            fingerprint_length: config.fingerprint_length,
+            seen: HashMap::new(),
+            sent: VecDeque::new(),
+        }
+    }
+
+    /// The report to send for `event`, if any. Errors quiet for a whole
+    /// window are forgotten, so their count starts over.
+    pub fn observe(&mut self, event: &ErrorEvent) -> Option<ErrorReport> {
+        let now = event.at;
+        let window = self.window;
+        self.seen.retain(|_, seen| now - seen.last_seen < window);
+        while self
+            .sent
+            .front()
+            .is_some_and(|at| now - *at >= chrono::Duration::hours(1))
+        {
+            self.sent.pop_front();
+        }
+
+        let fingerprint = format!(
+            "{}: {}",
+            event.kind,
+            truncate(&event.message, self.fingerprint_length)
+        );
+        let seen = self.seen.entry(fingerprint).or_insert(Seen {
+            first_seen: now,
+            last_seen: now,
+            reported_at: None,
+            occurrences: 0,
+        });
+        seen.occurrences += 1;
+        seen.last_seen = now;
+
+        if seen.reported_at.is_some_and(|at| now - at < window)
+            || self.sent.len() >= self.max_per_hour
+        {
+            return None;
+        }
+        seen.reported_at = Some(now);
+        self.sent.push_back(now);
+
+        Some(ErrorReport {
+            kind: event.kind.clone(),
+            message: event.message.clone(),
+            correlation_id: event.correlation_id.clone(),
+            first_seen: seen.first_seen,
+            occurrences: seen.occurrences,
+        })
+    }
+}
+
+/// Handle to the reporting task, cheap to clone. Until
+/// [ErrorReporter::start] is called, errors are ignored.
+#[derive(Clone, Default)]
+pub struct ErrorReporter {
+    events: Arc<OnceLock<mpsc::Sender<ErrorEvent>>>,
+    bus: Arc<OnceLock<EventBus>>,
+}
+
+impl ErrorReporter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A tracing layer forwarding error events, to install when logging is
+    /// initialized, see [crate::logging::init_logging_with_reporter].
+    pub fn layer(&self) -> ErrorLayer {
+        ErrorLayer {
+            reporter: self.clone(),
+        }
+    }
+
+    /// Also publishes each report on `bus`. Returns `false` when a bus was
+    /// already given.
+    pub fn publish_to(&self, bus: EventBus) -> bool {
+        self.bus.set(bus).is_ok()
+    }
+
+    /// Starts sending reports to `sink`. Returns `None` when the reporter
+    /// was already started.
+    pub fn start(
+        &self,
+        config: ReportingConfig,
+        sink: Arc<dyn ReportSink>,
+    ) -> Option<tokio::task::JoinHandle<()>> {
+        let (tx, mut rx) = mpsc::channel(QUEUE_SIZE);
+        self.events.set(tx).ok()?;
+        let bus = self.bus.clone();
+
+        Some(tokio::spawn(async move {
+            let mut limiter = ReportLimiter::new(&config);
+            while let Some(event) = rx.recv().await {
+                let Some(report) = limiter.observe(&event) else {
+                    continue;
+                };
+                if let Some(bus) = bus.get() {
+                    bus.publish(crate::events::Event::ErrorBurst {
+                        kind: report.kind.clone(),
+                        message: report.message.clone(),
+                        occurrences: report.occurrences,
+                        first_seen: report.first_seen,
+                    });
+                }
+                let report = report.to_string();
+                if let Err(err) = DELIVERING.scope((), sink.deliver(&report)).await {
+                    warn!(target: REPORTING_TARGET, %err, "Failed to deliver error report");
+                }
+            }
+        }))
+    }
+
+    /// Reports an error caught by the caller rather than logged.
+    pub fn report(&self, kind: &str, message: impl fmt::Display, correlation_id: Option<&str>) {
+        self.send(ErrorEvent {
+            kind: kind.to_string(),
+            message: message.to_string(),
+            correlation_id: correlation_id.map(String::from),
+            at: Utc::now(),
+        });
+    }
+
+    fn is_started(&self) -> bool {
+        self.events.get().is_some()
+    }
+
+    fn send(&self, event: ErrorEvent) {
+        if DELIVERING.try_with(|_| ()).is_ok() {
+            return;
+        }
+        if let Some(events) = self.events.get() {
+            // A full queue means a burst the limiter would mostly drop anyway
+            let _ = events.try_send(event);
+        }
+    }
+}
+
+/// Forwards error events to an [ErrorReporter].
+pub struct ErrorLayer {
+    reporter: ErrorReporter,
+}
+
+/// `correlation_id` of a span, kept for the events inside it.
+struct CorrelationId(String);
+
+#[derive(Default)]
+struct EventFields {
+    message: String,
+    error: Option<String>,
+    correlation_id: Option<String>,
+    /// Other fields, as `name=value`.
+    others: Vec<String>,
+}
+
+impl Visit for EventFields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record(field, value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record(field, format!("{value:?}"));
+    }
+}
+
+impl EventFields {
+    fn record(&mut self, field: &Field, value: String) {
+        match field.name() {
+            "message" => self.message = value,
+            "err" | "error" => self.error = Some(value),
+            "correlation_id" => self.correlation_id = Some(value),
+            name => self.others.push(format!("{name}={value}")),
+        }
+    }
+
+    /// The message, followed by the error and the other fields, e.g.
+    /// `Failed to send message (why=Http(..))`.
+    fn text(self) -> String {
+        let mut text = self.message;
+        if let Some(error) = self.error {
+            text = format!("{text}: {error}");
+        }
+        if !self.others.is_empty() {
+            text = format!("{text} ({})", self.others.join(", "));
+        }
+        text
+    }
+}
+
+impl<S> Layer<S> for ErrorLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        if !self.reporter.is_started() {
+            return;
+        }
+        let mut fields = EventFields::default();
+        attrs.record(&mut fields);
+        if let (Some(correlation_id), Some(span)) = (fields.correlation_id, ctx.span(id)) {
+            span.extensions_mut().insert(CorrelationId(correlation_id));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if *metadata.level() != Level::ERROR
+            || metadata.target() == REPORTING_TARGET
+            || !self.reporter.is_started()
+        {
+            return;
+        }
+
+        let mut fields = EventFields::default();
+        event.record(&mut fields);
+        let correlation_id = fields.correlation_id.take().or_else(|| {
+            ctx.event_scope(event)?.find_map(|span| {
+                span.extensions()
+                    .get::<CorrelationId>()
+                    .map(|id| id.0.clone())
+            })
+        });
+
+        self.reporter.send(ErrorEvent {
+            kind: metadata.target().to_string(),
+            message: fields.text(),
+            correlation_id,
+            at: Utc::now(),
+        });
+    }
+}
+
+fn truncate(text: &str, length: usize) -> String {
+    match text.char_indices().nth(length) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+    use super::*;
+
+    struct ChannelSink(mpsc::UnboundedSender<String>);
+
+    #[async_trait]
+    impl ReportSink for ChannelSink {
+        async fn deliver(&self, report: &str) -> Result<(), String> {
+            // Errors logged while delivering must not come back as reports
+            tracing::error!("Sink failed");
+            self.0.send(report.to_string()).map_err(|e| e.to_string())
+        }
+    }
+
+    fn event(message: &str, minutes: i64) -> ErrorEvent {
+        let start = "2024-11-05T09:30:00Z".parse::<DateTime<Utc>>().unwrap();
+        ErrorEvent {
+            kind: "asuka_core::knowledge::store".to_string(),
+            message: message.to_string(),
+            correlation_id: Some("1304567890".to_string()),
+            at: start + chrono::Duration::minutes(minutes),
+        }
+    }
+
+    #[test]
+    fn test_duplicates_are_counted_not_sent() {
+        let mut limiter = ReportLimiter::new(&ReportingConfig::default());
+
+        let first = limiter
+            .observe(&event("Failed to embed: invalid api key", 0))
+            .unwrap();
+        assert_eq!(first.occurrences, 1);
+        for minute in 1..=20 {
+            assert!(limiter
+                .observe(&event("Failed to embed: invalid api key", minute))
+                .is_none());
+        }
+
+        // Reported again once the window has passed, with the running count
+        let repeat = limiter
+            .observe(&event("Failed to embed: invalid api key", 60))
+            .unwrap();
+        assert_eq!(repeat.occurrences, 22);
+        assert_eq!(repeat.first_seen, first.first_seen);
+        insta::assert_snapshot!(repeat.to_string(), @r"
+        Error: Failed to embed: invalid api key
+        Source: asuka_core::knowledge::store
+        Correlation id: 1304567890
+        Occurrences: 22 since 2024-11-05 09:30:00 UTC
+        ");
+    }
+
+    #[test]
+    fn test_fingerprint_ignores_the_end_of_long_messages() {
+        let mut limiter = ReportLimiter::new(&ReportingConfig {
+            fingerprint_length: 20,
+            ..Default::default()
+        });
+
+        assert!(limiter
+            .observe(&event("Database is locked (table messages)", 0))
+            .is_some());
+        assert!(limiter
+            .observe(&event("Database is locked (table pins)", 1))
+            .is_none());
+        assert!(limiter.observe(&event("Channel not found", 2)).is_some());
+    }
+
+    #[test]
+    fn test_burst_is_rate_limited() {
+        let mut limiter = ReportLimiter::new(&ReportingConfig {
+            max_per_hour: 3,
+            ..Default::default()
+        });
+
+        let sent = (0..10)
+            .filter_map(|i| limiter.observe(&event(&format!("Error {i}"), i)))
+            .map(|report| report.message)
+            .collect::<Vec<_>>();
+        assert_eq!(sent, ["Error 0", "Error 1", "Error 2"]);
+
+        // The held back error is reported when the hour is over
+        let late = limiter.observe(&event("Error 9", 60)).unwrap();
+        assert_eq!(late.occurrences, 2);
+    }
+
+    #[tokio::test]
+    async fn test_layer_reports_error_events() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let reporter = ErrorReporter::new();
+        // Also the subscriber of the reporting task, on this single threaded
+        // runtime
+        let _default = tracing::subscriber::set_default(Registry::default().with(reporter.layer()));
+
+        // Not started yet, so nothing is queued
+        tracing::error!("Before start");
+        reporter
+            .start(ReportingConfig::default(), Arc::new(ChannelSink(tx)))
+            .unwrap();
+        assert!(reporter
+            .start(
+                ReportingConfig::default(),
+                Arc::new(WebhookReport::new("http://x"))
+            )
+            .is_none());
+
+        {
+            let span = tracing::info_span!("message", correlation_id = "1304567890");
+            let _guard = span.enter();
+            tracing::info!("Not an error");
+            tracing::error!(target: REPORTING_TARGET, "Own failure");
+            tracing::error!(
+                err = "database is locked",
+                table = "messages",
+                "Failed to store message"
+            );
+        }
+
+        let report = tokio::time::timeout(Duration::from_secs(1), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(report.starts_with(
+            "Error: Failed to store message: database is locked (table=messages)\n\
+             Source: asuka_core::reporting::tests\n\
+             Correlation id: 1304567890\n"
+        ));
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), rx.recv())
+                .await
+                .is_err(),
+            "only one report"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reports_are_published() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let reporter = ErrorReporter::new();
+        let bus = EventBus::default();
+        let mut events = bus.subscribe();
+        assert!(reporter.publish_to(bus.clone()));
+        assert!(!reporter.publish_to(bus));
+        reporter
+            .start(ReportingConfig::default(), Arc::new(ChannelSink(tx)))
+            .unwrap();
+
+        reporter.report("discord", "Failed to send reply: 403", Some("1304567890"));
+        rx.recv().await.unwrap();
+        let crate::events::Event::ErrorBurst {
+            kind,
+            message,
+            occurrences,
+            ..
+        } = events.recv().await.unwrap()
+        else {
+            panic!("expected an error burst");
+        };
+        assert_eq!(kind, "discord");
+        assert_eq!(message, "Failed to send reply: 403");
+        assert_eq!(occurrences, 1);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(ReportingConfig::default().validate().is_err());
+        assert!(ReportingConfig {
+            discord_channel: Some("general".to_string()),
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+        assert!(ReportingConfig {
+            webhook: Some("https://hooks.example.com/T000".to_string()),
+            ..Default::default()
+        }
+        .validate()
+        .is_ok());
+    }
+}
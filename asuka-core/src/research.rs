@@ -0,0 +1,825 @@
+//! Deep research: an answer worked out over several model calls instead of
+//! one, for questions asked with `/ask-deep` or a "take your time".
+//!
+//! Each iteration searches the knowledge base for a few sub-questions, drafts
+//! an answer from every document found so far and has the model critique the
+//! draft once. The first iteration asks the model for the sub-questions, later
+//! ones search what the critique found missing. Research ends when the
+//! critique is satisfied, after [ResearchConfig::max_iterations], when
+//! [ResearchConfig::budget_secs] or [ResearchConfig::max_tokens] run out, or
+//! on shutdown. The last draft is the answer, with its sources and a note
+//! when it was cut short. Progress is shown by editing one message, and every
+//! step is recorded with the interaction, see
+//! [KnowledgeBase::record_research_step](crate::knowledge::KnowledgeBase::record_research_step).
+//!
+//! Nothing accounts for model usage yet, so spend is estimated from the text
+//! sent and received, see [estimate_tokens]. A call is only made when its
+//! prompt leaves room for a completion within
+//! [ResearchConfig::max_tokens], and the completion is capped at that room.
+//!
+//! ```toml
+//! [research]
+//! max_iterations = 3
+//! budget_secs = 120
+//! max_tokens = 30000
+//! ```
+
+use std::time::Duration;
+
+use rig::{
+    completion::{CompletionModel, Prompt},
+    embeddings::EmbeddingModel,
+};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    agent::Agent,
+    capabilities::documents,
+    clients::streaming::ReplySink,
+    escape,
+    history::estimate_tokens,
+    knowledge::{Document, ResearchStep},
+    structured::{parse_json, schema_instruction},
+    templates,
+};
+
+/// Phrases asking for a deep answer without the command.
+const DEEP_PHRASES: &[&str] = &[
+    "take your time",
+    "take the time to",
+    "dig deep",
+    "research this thoroughly",
+];
+
+/// Step kinds of the interaction log.
+pub const STEP_PLAN: &str = "plan";
+pub const STEP_RETRIEVE: &str = "retrieve";
+pub const STEP_DRAFT: &str = "draft";
+pub const STEP_CRITIQUE: &str = "critique";
+pub const STEP_STOP: &str = "stop";
+
+/// Shown until the first documents are found.
+const PROGRESS_START: &str = "🔍 researching…";
+
+/// Fewest tokens left for a completion for a call to be made.
+const MIN_COMPLETION_TOKENS: usize = 100;
+
+/// Characters of each document sent to draft an answer at most.
+const DOCUMENT_CHARS: usize = 2000;
+
+/// `[research]` section of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResearchConfig {
+    /// Rounds of searching, drafting and critique at most.
+    pub max_iterations: usize,
+    /// Wall-clock time research may take, in seconds.
+    pub budget_secs: u64,
+    /// Estimated tokens all model calls of one question may spend.
+    pub max_tokens: usize,
+    /// Sub-questions searched per iteration at most.
+    pub sub_questions: usize,
+    /// Documents retrieved per sub-question.
+    pub documents_per_question: usize,
+}
+
+impl Default for ResearchConfig {
+    fn default() -> Self {
+        Self {
+            max_iterations: 3,
+            budget_secs: 120,
+            max_tokens: 30_000,
+            sub_questions: 3,
+            documents_per_question: 4,
+        }
+    }
+}
+
+impl ResearchConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_iterations == 0 {
+            return Err("research.max_iterations must be positive".to_string());
+        }
+        if self.budget_secs == 0 {
+            return Err("research.budget_secs must be positive".to_string());
+        }
+        if self.max_tokens < MIN_COMPLETION_TOKENS {
+            return Err(format!(
+                "research.max_tokens must be at least {MIN_COMPLETION_TOKENS}"
+            ));
+        }
+        if self.sub_questions == 0 || self.documents_per_question == 0 {
+            return Err(
+                "research.sub_questions and research.documents_per_question must be positive"
+                    .to_string(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Whether `text` asks for a deep answer in words rather than with
+/// `/ask-deep`.
+pub fn asks_for_depth(text: &str) -> bool {
+    let text = text.to_lowercase();
+    DEEP_PHRASES.iter().any(|phrase| text.contains(phrase))
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct Plan {
+    /// Specific questions to search the documentation for, whose answers
+    /// together answer the question.
+    sub_questions: Vec<String>,
+}
+
+/// A drafted answer.
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct ResearchAnswer {
+    /// The answer to the question.
+    pub answer: String,
+    /// Ids of the documents the answer relies on.
+    #[serde(default)]
+    pub sources: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct Critique {
+    /// Whether the draft fully answers the question, backed by the documents.
+    complete: bool,
+    /// Questions to research for what the draft gets wrong or leaves out.
+    #[serde(default)]
+    missing: Vec<String>,
+}
+
+/// Why research ended before the critique was satisfied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// The critique still found gaps after [ResearchConfig::max_iterations].
+    Iterations,
+    /// [ResearchConfig::budget_secs] ran out.
+    Deadline,
+    /// The next call didn't fit [ResearchConfig::max_tokens].
+    Tokens,
+    Shutdown,
+    /// A model call failed.
+    Failed,
+}
+
+impl StopReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StopReason::Iterations => "iterations",
+            StopReason::Deadline => "deadline",
+            StopReason::Tokens => "tokens",
+            StopReason::Shutdown => "shutdown",
+            StopReason::Failed => "failed",
+        }
+    }
+
+    /// The reason as told to users.
+    fn explanation(&self) -> &'static str {
+        match self {
+            StopReason::Iterations => "as questions remained after the last round",
+            StopReason::Deadline => "as the time for it ran out",
+            StopReason::Tokens => "as it reached its spending limit",
+            StopReason::Shutdown => "as I'm shutting down",
+            StopReason::Failed => "as something went wrong",
+        }
+    }
+}
+
+/// The outcome of researching one question.
+#[derive(Debug)]
+pub struct Research {
+    /// The last draft, none when research stopped before the first.
+    pub answer: Option<ResearchAnswer>,
+    /// Documents found, in the order they were first retrieved.
+    pub documents: Vec<Document>,
+    /// Iterations started.
+    pub iterations: usize,
+    /// Estimated tokens spent.
+    pub tokens: usize,
+    pub stopped: Option<StopReason>,
+}
+
+/// Budgets of one question, and where its steps are recorded.
+struct Run {
+    deadline: Instant,
+    tokens: usize,
+    interaction_id: Option<i64>,
+}
+
+/// The progress message, none when it couldn't be sent.
+struct Progress<'a, S: ReplySink> {
+    sink: &'a S,
+    handle: Option<S::Handle>,
+}
+
+impl<S: ReplySink> Progress<'_, S> {
+    async fn show(&self, text: &str) {
+        if let Some(handle) = &self.handle {
+            if let Err(err) = self.sink.edit(handle, text).await {
+                warn!(%err, "Failed to update research progress");
+            }
+        }
+    }
+}
+
+pub struct Researcher<M: CompletionModel, E: EmbeddingModel + 'static> {
+    agent: Agent<M, E>,
+    config: ResearchConfig,
+    shutdown: CancellationToken,
+}
+
+impl<M: CompletionModel, E: EmbeddingModel + 'static> Researcher<M, E> {
+    pub fn new(agent: Agent<M, E>, config: ResearchConfig) -> Self {
+        Self {
+            agent,
+            config,
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    /// Stops research, keeping the last draft, when `shutdown` is cancelled.
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Researches `question`, showing progress through `sink` and recording
+    /// the steps with `interaction_id`, if any.
+    pub async fn research<S: ReplySink>(
+        &self,
+        question: &str,
+        interaction_id: Option<i64>,
+        sink: &S,
+    ) -> Research {
+        let mut run = Run {
+            deadline: Instant::now() + Duration::from_secs(self.config.budget_secs),
+            tokens: 0,
+            interaction_id,
+        };
+        let mut research = Research {
+            answer: None,
+            documents: Vec::new(),
+            iterations: 0,
+            tokens: 0,
+            stopped: None,
+        };
+        let progress = Progress {
+            sink,
+            handle: sink
+                .send(PROGRESS_START)
+                .await
+                .inspect_err(|err| warn!(%err, "Failed to show research progress"))
+                .ok(),
+        };
+
+        research.stopped = self
+            .iterate(question, &mut run, &mut research, &progress)
+            .await
+            .err();
+        research.tokens = run.tokens;
+        if let Some(reason) = research.stopped {
+            self.record(&run, STEP_STOP, reason.as_str(), 0).await;
+        }
+        progress
+            .show(&format!(
+                "🔍 researched: looked at {}.",
+                documents(research.documents.len())
+            ))
+            .await;
+
+        info!(
+            iterations = research.iterations,
+            documents = research.documents.len(),
+            tokens = research.tokens,
+            stopped = research.stopped.map(|reason| reason.as_str()),
+            "Finished research"
+        );
+        research
+    }
+
+    /// The reply for `research`: the answer with its sources, or why there
+    /// is none.
+    pub fn reply(&self, research: &Research) -> String {
+        let character = &self.agent.character;
+        let Some(answer) = &research.answer else {
+            let reason = research.stopped.unwrap_or(StopReason::Failed);
+            return character.template(
+                templates::RESEARCH_STOPPED,
+                &[("reason", reason.explanation())],
+            );
+        };
+
+        let cited = research
+            .documents
+            .iter()
+            .filter(|document| answer.sources.contains(&document.id))
+            .collect::<Vec<_>>();
+        // Everything looked at when the model cited nothing it was given
+        let sources = if cited.is_empty() {
+            research.documents.iter().collect()
+        } else {
+            cited
+        };
+
+        let mut reply = answer.answer.trim().to_string();
+        if !sources.is_empty() {
+            let list = sources
+                .iter()
+                .map(|document| match document.title.as_str() {
+                    "" => format!("- {}", document.id),
+                    title => format!("- {title} ({})", document.id),
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+            reply.push_str("\n\n");
+            reply.push_str(&character.template(templates::RESEARCH_SOURCES, &[("sources", &list)]));
+        }
+        if let Some(reason) = research.stopped {
+            reply.push_str("\n\n");
+            reply.push_str(&character.template(
+                templates::RESEARCH_PARTIAL,
+                &[("reason", reason.explanation())],
+            ));
+        }
+        reply
+    }
+
+    async fn iterate<S: ReplySink>(
+        &self,
+        question: &str,
+        run: &mut Run,
+        research: &mut Research,
+        progress: &Progress<'_, S>,
+    ) -> Result<(), StopReason> {
+        let mut sub_questions = self.plan(question, run).await?;
+        loop {
+            research.iterations += 1;
+            self.retrieve(&sub_questions, run, research).await;
+            progress
+                .show(&format!(
+                    "🔍 researching: looked at {}…",
+                    documents(research.documents.len())
+                ))
+                .await;
+
+            let draft = self.draft(question, research, run).await?;
+            let critique = self.critique(question, &draft, run).await;
+            research.answer = Some(draft);
+            let critique = critique?;
+            if critique.complete || critique.missing.is_empty() {
+                return Ok(());
+            }
+            if research.iterations >= self.config.max_iterations {
+                return Err(StopReason::Iterations);
+            }
+            sub_questions = critique.missing;
+            sub_questions.truncate(self.config.sub_questions);
+        }
+    }
+
+    async fn plan(&self, question: &str, run: &mut Run) -> Result<Vec<String>, StopReason> {
+        let prompt = format!(
+            "Break this question into at most {} specific questions to search the \
+            documentation for, whose answers together answer it.\n\nQuestion: {}\n\n{}",
+            self.config.sub_questions,
+            escape::inline(question),
+            schema_instruction::<Plan>()
+        );
+        let (output, tokens) = self.complete(&prompt, run).await?;
+
+        let mut sub_questions = match parse_json::<Plan>(&output) {
+            Ok(plan) => plan.sub_questions,
+            Err(err) => {
+                warn!(%err, "Research plan did not parse, searching the question");
+                Vec::new()
+            }
+        };
+        sub_questions.retain(|sub_question| !sub_question.trim().is_empty());
+        sub_questions.truncate(self.config.sub_questions);
+        if sub_questions.is_empty() {
+            sub_questions.push(question.to_string());
+        }
+        self.record(run, STEP_PLAN, &sub_questions.join("\n"), tokens)
+            .await;
+        Ok(sub_questions)
+    }
+
+    /// Adds the documents found for `sub_questions` that weren't found yet.
+    async fn retrieve(&self, sub_questions: &[String], run: &Run, research: &mut Research) {
+        for sub_question in sub_questions {
+            if self.shutdown.is_cancelled() || Instant::now() >= run.deadline {
+                return;
+            }
+            let found = match self
+                .agent
+                .knowledge()
+                .search_documents(sub_question, self.config.documents_per_question)
+                .await
+            {
+                Ok(found) => found,
+                Err(err) => {
+                    error!(?err, sub_question, "Failed to search for research");
+                    continue;
+                }
+            };
+
+            let mut ids = Vec::with_capacity(found.len());
+            for (_, document) in found {
+                ids.push(document.id.clone());
+                if !research
+                    .documents
+                    .iter()
+                    .any(|known| known.id == document.id)
+                {
+                    research.documents.push(document);
+                }
+            }
+            debug!(sub_question, ?ids, "Retrieved for research");
+            let content = format!("{sub_question}\n{}", ids.join("\n"));
+            self.record(run, STEP_RETRIEVE, &content, 0).await;
+        }
+    }
+
+    async fn draft(
+        &self,
+        question: &str,
+        research: &Research,
+        run: &mut Run,
+    ) -> Result<ResearchAnswer, StopReason> {
+        let documents = match research.documents.as_slice() {
+            [] => "(none found)".to_string(),
+            documents => documents
+                .iter()
+                .map(|document| {
+                    let content = document.content.chars().take(DOCUMENT_CHARS);
+                    format!(
+                        "[{}] {}\n{}",
+                        document.id,
+                        escape::inline(&document.title),
+                        escape::tags(&content.collect::<String>())
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n\n---\n\n"),
+        };
+        let previous = research
+            .answer
+            .as_ref()
+            .map(|draft| {
+                format!(
+                    "\n\nYour previous draft, improve on it:\n{}",
+                    escape::tags(&draft.answer)
+                )
+            })
+            .unwrap_or_default();
+        let prompt = format!(
+            "Answer the question using only these documents, and list the ids of the ones \
+            you rely on. Say what they don't cover rather than guessing.\n\n\
+            Question: {}\n\nDocuments:\n{documents}{previous}\n\n{}",
+            escape::inline(question),
+            schema_instruction::<ResearchAnswer>()
+        );
+        let (output, tokens) = self.complete(&prompt, run).await?;
+
+        let draft = parse_json(&output).unwrap_or_else(|err| {
+            warn!(%err, "Research draft did not parse, using it as written");
+            ResearchAnswer {
+                answer: output.clone(),
+                sources: Vec::new(),
+            }
+        });
+        self.record(run, STEP_DRAFT, &draft.answer, tokens).await;
+        Ok(draft)
+    }
+
+    async fn critique(
+        &self,
+        question: &str,
+        draft: &ResearchAnswer,
+        run: &mut Run,
+    ) -> Result<Critique, StopReason> {
+        let prompt = format!(
+            "Critique this draft answer. Is it complete and backed by the documentation, \
+            or is something missing or doubtful? List what to research next as \
+            questions.\n\nQuestion: {}\n\nDraft:\n{}\n\n{}",
+            escape::inline(question),
+            escape::tags(&draft.answer),
+            schema_instruction::<Critique>()
+        );
+        let (output, tokens) = self.complete(&prompt, run).await?;
+
+        let critique = parse_json(&output).unwrap_or_else(|err| {
+            warn!(%err, "Research critique did not parse, keeping the draft");
+            Critique {
+                complete: true,
+                missing: Vec::new(),
+            }
+        });
+        let content = match critique.complete {
+            true => "complete".to_string(),
+            false => critique.missing.join("\n"),
+        };
+        self.record(run, STEP_CRITIQUE, &content, tokens).await;
+        Ok(critique)
+    }
+
+    /// Prompts the character without retrieved documents, within what is
+    /// left of the budgets, and returns the completion with the estimated
+    /// tokens of the call.
+    async fn complete(&self, prompt: &str, run: &mut Run) -> Result<(String, usize), StopReason> {
+        if self.shutdown.is_cancelled() {
+            return Err(StopReason::Shutdown);
+        }
+        if Instant::now() >= run.deadline {
+            return Err(StopReason::Deadline);
+        }
+        let sent = estimate_tokens(&self.agent.character.preamble) + estimate_tokens(prompt);
+        let left = self.config.max_tokens.saturating_sub(run.tokens + sent);
+        if left < MIN_COMPLETION_TOKENS {
+            return Err(StopReason::Tokens);
+        }
+        let max_tokens = self
+            .agent
+            .generation_params(None)
+            .max_tokens
+            .map_or(left as u64, |max| max.min(left as u64));
+        let agent = self.agent.base_builder().max_tokens(max_tokens).build();
+
+        run.tokens += sent;
+        let result = tokio::select! {
+            _ = self.shutdown.cancelled() => return Err(StopReason::Shutdown),
+            result = tokio::time::timeout_at(run.deadline, agent.prompt(prompt)) => result,
+        };
+        let output = match result {
+            Ok(Ok(output)) => output,
+            Ok(Err(err)) => {
+                error!(%err, "Research call failed");
+                return Err(StopReason::Failed);
+            }
+            Err(_) => return Err(StopReason::Deadline),
+        };
+        let received = estimate_tokens(&output);
+        run.tokens += received;
+        Ok((output, sent + received))
+    }
+
+    async fn record(&self, run: &Run, kind: &str, content: &str, tokens: usize) {
+        let Some(interaction_id) = run.interaction_id else {
+            return;
+        };
+        let step = ResearchStep {
+            kind: kind.to_string(),
+            content: content.to_string(),
+            tokens,
+        };
+        if let Err(err) = self
+            .agent
+            .knowledge()
+            .record_research_step(interaction_id, &step)
+            .await
+        {
+            error!(?err, kind, "Failed to record research step");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, sync::Mutex};
+
+    use async_trait::async_trait;
+    use rig::completion::{CompletionError, CompletionRequest, CompletionResponse};
+
+    use super::*;
+    use crate::{
+        character::Character,
+        knowledge::KnowledgeBase,
+        test_utils::{self, FakeEmbeddingModel, ScriptedCompletionModel},
+    };
+
+    /// Records what the progress message was set to.
+    #[derive(Default)]
+    struct Shown(Mutex<Vec<String>>);
+
+    #[async_trait]
+    impl ReplySink for Shown {
+        type Handle = ();
+        type Error = Infallible;
+
+        async fn send(&self, text: &str) -> Result<(), Infallible> {
+            self.0.lock().unwrap().push(text.to_string());
+            Ok(())
+        }
+
+        async fn edit(&self, _handle: &(), text: &str) -> Result<(), Infallible> {
+            self.0.lock().unwrap().push(text.to_string());
+            Ok(())
+        }
+    }
+
+    /// Answers after 40 seconds.
+    #[derive(Clone)]
+    struct Slow(ScriptedCompletionModel);
+
+    impl CompletionModel for Slow {
+        type Response = ();
+
+        async fn completion(
+            &self,
+            request: CompletionRequest,
+        ) -> Result<CompletionResponse<()>, CompletionError> {
+            tokio::time::sleep(Duration::from_secs(40)).await;
+            self.0.completion(request).await
+        }
+    }
+
+    fn document(id: &str, title: &str, content: &str) -> Document {
+        Document {
+            id: id.to_string(),
+            source_id: "github".to_string(),
+            content: content.to_string(),
+            created_at: chrono::Utc::now(),
+            title: title.to_string(),
+            section: String::new(),
+            topics: vec![],
+            logical_id: None,
+            cleaned: None,
+            chunk: None,
+        }
+    }
+
+    async fn knowledge(documents: Vec<Document>) -> KnowledgeBase<FakeEmbeddingModel> {
+        let mut knowledge = test_utils::knowledge_base().await;
+        knowledge.add_documents(documents).await.unwrap();
+        knowledge
+    }
+
+    fn researcher<M: CompletionModel>(
+        model: M,
+        knowledge: KnowledgeBase<FakeEmbeddingModel>,
+        config: ResearchConfig,
+    ) -> Researcher<M, FakeEmbeddingModel> {
+        let character: Character =
+            toml::from_str("name = \"shinobi\"\npreamble = \"You help with Cartridge.\"").unwrap();
+        Researcher::new(Agent::new(character, model, knowledge), config)
+    }
+
+    #[tokio::test]
+    async fn test_iterations_are_bounded() {
+        let knowledge = knowledge(vec![
+            document("fees.md", "Fees", "Fees are paid in STRK."),
+            document("paymaster.md", "Paymaster", "The paymaster covers fees."),
+        ])
+        .await;
+        let interaction_id = knowledge
+            .create_interaction("c1".to_string(), "u1".to_string(), vec![])
+            .await
+            .unwrap();
+        let model = ScriptedCompletionModel::new([
+            r#"{"sub_questions": ["how are fees paid?", "who covers fees?"]}"#,
+            r#"{"answer": "Fees are paid in STRK.", "sources": ["fees.md"]}"#,
+            r#"{"complete": false, "missing": ["when does the paymaster pay?"]}"#,
+            r#"{"answer": "Fees are paid in STRK, or by the paymaster.", "sources": ["paymaster.md"]}"#,
+            r#"{"complete": false, "missing": ["is there a cap?"]}"#,
+            "never asked",
+        ]);
+        let config = ResearchConfig {
+            max_iterations: 2,
+            ..Default::default()
+        };
+        let researcher = researcher(model.clone(), knowledge, config);
+        let shown = Shown::default();
+
+        let research = researcher
+            .research("how do fees work?", Some(interaction_id), &shown)
+            .await;
+        assert_eq!(research.iterations, 2);
+        assert_eq!(research.stopped, Some(StopReason::Iterations));
+        // Planned once, then drafted and critiqued per iteration
+        let requests = model.requests();
+        assert_eq!(requests.len(), 5);
+        assert!(requests[3]
+            .prompt
+            .contains("Your previous draft, improve on it:\nFees are paid in STRK."));
+        assert!(requests[3].prompt.contains("[paymaster.md] Paymaster"));
+        assert!(research.tokens > 0);
+
+        let reply = researcher.reply(&research);
+        assert!(reply.starts_with(
+            "Fees are paid in STRK, or by the paymaster.\n\nSources:\n- Paymaster (paymaster.md)\n\n"
+        ));
+        assert!(reply.contains("questions remained after the last round"));
+        assert_eq!(
+            shown.0.lock().unwrap().last().unwrap(),
+            "🔍 researched: looked at 2 documents."
+        );
+
+        let steps = researcher
+            .agent
+            .knowledge()
+            .interaction_research_steps(interaction_id)
+            .await
+            .unwrap();
+        let kinds = steps
+            .iter()
+            .map(|step| step.kind.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                STEP_PLAN,
+                STEP_RETRIEVE,
+                STEP_RETRIEVE,
+                STEP_DRAFT,
+                STEP_CRITIQUE,
+                STEP_RETRIEVE,
+                STEP_DRAFT,
+                STEP_CRITIQUE,
+                STEP_STOP
+            ]
+        );
+        assert_eq!(
+            steps.iter().map(|step| step.tokens).sum::<usize>(),
+            research.tokens
+        );
+    }
+
+    #[tokio::test]
+    async fn test_token_budget_and_shutdown() {
+        let long = "Session keys let games sign transactions. ".repeat(40);
+        let knowledge = knowledge(vec![
+            document("sessions.md", "Sessions", &long),
+            document("keys.md", "Keys", &long),
+        ])
+        .await;
+        let model = ScriptedCompletionModel::new([
+            r#"{"sub_questions": ["what are session keys?"]}"#,
+            r#"{"answer": "never asked", "sources": []}"#,
+        ]);
+        let config = ResearchConfig {
+            max_tokens: 700,
+            ..Default::default()
+        };
+        let researcher = researcher(model.clone(), knowledge.clone(), config);
+
+        // The plan fits, drafting from both documents doesn't
+        let research = researcher
+            .research("what are session keys?", None, &Shown::default())
+            .await;
+        assert_eq!(research.stopped, Some(StopReason::Tokens));
+        assert!(research.answer.is_none());
+        assert!(research.tokens <= 700);
+        let requests = model.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].max_tokens.unwrap() <= 700);
+        assert_eq!(
+            researcher.reply(&research),
+            "I couldn't finish researching this, as it reached its spending limit. \
+             Try asking a narrower question."
+        );
+
+        let model = ScriptedCompletionModel::new([]);
+        let shutdown = CancellationToken::new();
+        shutdown.cancel();
+        let research = researcher(model.clone(), knowledge, ResearchConfig::default())
+            .with_shutdown(shutdown)
+            .research("what are session keys?", None, &Shown::default())
+            .await;
+        assert_eq!(research.stopped, Some(StopReason::Shutdown));
+        assert!(model.requests().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_deadline_keeps_last_draft() {
+        let knowledge = knowledge(vec![document("fees.md", "", "Fees are paid in STRK.")]).await;
+        let model = ScriptedCompletionModel::new([
+            r#"{"sub_questions": ["how are fees paid?"]}"#,
+            r#"{"answer": "In STRK.", "sources": ["fees.md"]}"#,
+            r#"{"complete": true}"#,
+        ]);
+        let config = ResearchConfig {
+            budget_secs: 100,
+            ..Default::default()
+        };
+        let researcher = researcher(Slow(model.clone()), knowledge, config);
+
+        // Planning and drafting take 80 seconds, the critique can't finish
+        let research = researcher
+            .research("how are fees paid?", None, &Shown::default())
+            .await;
+        assert_eq!(research.stopped, Some(StopReason::Deadline));
+        assert_eq!(model.requests().len(), 2);
+        assert_eq!(
+            researcher.reply(&research),
+            "In STRK.\n\nSources:\n- fees.md\n\n(I stopped researching early, as the time for \
+             it ran out, so this may be incomplete.)"
+        );
+    }
+}
@@ -0,0 +1,176 @@
+//! Daily purge of old messages, so the bot doesn't keep every chat message
+//! forever. [RetentionJob::spawn] applies the policy every day at
+//! [RetentionConfig::hour]; what it removed shows up in the operator
+//! [digest](crate::digest).
+//!
+//! Ages are in days. The age of a channel type wins over the age of a source,
+//! which wins over `max_age_days`; messages none of them applies to are kept.
+//! Documents are never purged. Admins exempt a channel with
+//! `/retain-forever on`.
+//!
+//! ```toml
+//! [retention]
+//! hour = 3
+//! retain_users = ["80351110224678912"]
+//!
+//! [retention.channel_types]
+//! direct_message = 30
+//! text = 180
+//! ```
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use rig::embeddings::EmbeddingModel;
+use rig_sqlite::SqliteError;
+use serde::Deserialize;
+use tracing::error;
+
+use crate::knowledge::{ChannelType, KnowledgeBase, PurgeReport, RetentionPolicy, Source};
+
+/// `[retention]` settings of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    /// Hour of the day, in UTC, messages are purged at.
+    pub hour: u32,
+    /// Days messages are kept when neither their channel type nor their
+    /// source has an age.
+    pub max_age_days: Option<u32>,
+    /// Days kept by channel type: `direct_message`, `text`, `voice` or
+    /// `thread`.
+    pub channel_types: HashMap<String, u32>,
+    /// Days kept by source, such as `discord` or `telegram`.
+    pub sources: HashMap<String, u32>,
+    /// Users, by platform id, whose messages are never purged.
+    pub retain_users: Vec<String>,
+    /// Messages deleted per transaction.
+    pub chunk_size: usize,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        let policy = RetentionPolicy::default();
+        Self {
+            hour: 3,
+            max_age_days: policy.max_age_days,
+            channel_types: policy.channel_types,
+            sources: policy.sources,
+            retain_users: policy.retain_users,
+            chunk_size: policy.chunk_size,
+        }
+    }
+}
+
+impl RetentionConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.hour > 23 {
+            return Err(format!(
+                "retention.hour: {} is not an hour of the day",
+                self.hour
+            ));
+        }
+        if self.chunk_size == 0 {
+            return Err("retention.chunk_size must be above 0".to_string());
+        }
+        if let Some(name) = self
+            .channel_types
+            .keys()
+            .find(|name| ChannelType::from_str(name).is_none())
+        {
+            return Err(format!(
+                "retention.channel_types: unknown channel type {name:?}"
+            ));
+        }
+        if let Some(name) = self
+            .sources
+            .keys()
+            .find(|name| Source::from_str(name).is_none())
+        {
+            return Err(format!("retention.sources: unknown source {name:?}"));
+        }
+        Ok(())
+    }
+
+    pub fn policy(&self) -> RetentionPolicy {
+        RetentionPolicy {
+            max_age_days: self.max_age_days,
+            channel_types: self.channel_types.clone(),
+            sources: self.sources.clone(),
+            retain_users: self.retain_users.clone(),
+            chunk_size: self.chunk_size,
+        }
+    }
+
+    /// First time the purge is due after `now`.
+    pub fn next_run(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let today = now
+            .date_naive()
+            .and_hms_opt(self.hour, 0, 0)
+            .expect("hour is validated")
+            .and_utc();
+        if today > now {
+            today
+        } else {
+            today + chrono::Duration::days(1)
+        }
+    }
+}
+
+pub struct RetentionJob<E: EmbeddingModel + 'static> {
+    knowledge: KnowledgeBase<E>,
+    config: RetentionConfig,
+}
+
+impl<E: EmbeddingModel + 'static> RetentionJob<E> {
+    pub fn new(knowledge: KnowledgeBase<E>, config: RetentionConfig) -> Self {
+        Self { knowledge, config }
+    }
+
+    pub async fn run(&self, now: DateTime<Utc>) -> Result<PurgeReport, SqliteError> {
+        self.knowledge
+            .apply_retention(&self.config.policy(), now)
+            .await
+    }
+
+    /// Purges expired messages every day at [RetentionConfig::hour] until the
+    /// task is aborted.
+    pub fn spawn(self) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                let now = self.knowledge.clock().now_utc();
+                let next = self.config.next_run(now);
+                tokio::time::sleep((next - now).to_std().unwrap_or_default()).await;
+
+                if let Err(err) = self.run(next).await {
+                    error!(?err, "Failed to apply retention");
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let config: RetentionConfig = toml::from_str(
+            "max_age_days = 365\n[channel_types]\ndirect_message = 30\n[sources]\ntelegram = 90",
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.policy().max_age_days("telegram", "text"), Some(90));
+
+        for invalid in [
+            "hour = 24",
+            "chunk_size = 0",
+            "[channel_types]\ndm = 30",
+            "[sources]\nslack = 30",
+        ] {
+            let config: RetentionConfig = toml::from_str(invalid).unwrap();
+            assert!(config.validate().is_err(), "{invalid}");
+        }
+    }
+}
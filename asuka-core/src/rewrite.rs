@@ -0,0 +1,413 @@
+//! Rewriting messages into search queries before documents are retrieved.
+//! A message like "it still doesn't work after I did that" means nothing on
+//! its own, so its embedding finds nothing useful; rewritten with the recent
+//! conversation it becomes "How to fix the VRF fee error on Starknet".
+//!
+//! Only messages that [RewriteConfig::need_rewrite] are sent to the [QueryRewriter], and
+//! the rewrite is only waited for up to `timeout_ms`, after which the
+//! message is searched as written. The rewrite drives retrieval, while the
+//! reply is still prompted with the message. Both are stored with the
+//! interaction, see [KnowledgeBase::interaction_query](crate::knowledge::KnowledgeBase::interaction_query).
+//!
+//! ```toml
+//! [query_rewrite]
+//! specific_words = 12
+//! history_messages = 6
+//! timeout_ms = 1500
+//! ```
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rig::{
+    agent::AgentBuilder,
+    completion::{CompletionModel, Prompt},
+    embeddings::EmbeddingModel,
+};
+use serde::Deserialize;
+use tracing::{debug, error, warn};
+
+use crate::{agent::Agent, escape, knowledge::Message};
+
+/// Preamble of the rewriting call.
+pub const REWRITE_INSTRUCTION: &str = "You turn the latest chat message into a standalone search query for the documentation. Resolve pronouns and references using the conversation, keep product names, errors and versions, and leave out greetings. Reply with the query only, on one line.";
+
+/// Pronouns referring back to the conversation rather than the docs.
+const REFERRING_WORDS: &[&str] = &[
+    "it", "its", "it's", "that", "this", "these", "those", "they", "them", "their", "there", "he",
+    "she", "him", "her", "one", "same", "above", "again",
+];
+
+/// Share of referring words from which a message counts as pronoun-heavy.
+const PRONOUN_SHARE: f64 = 0.2;
+
+/// Longest rewrite used, in characters. Longer ones are the model answering
+/// rather than rewriting.
+const MAX_REWRITE_CHARS: usize = 300;
+
+/// `[query_rewrite]` settings of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RewriteConfig {
+    /// Words from which a message is specific enough to search as written,
+    /// unless it is pronoun-heavy.
+    pub specific_words: usize,
+    /// Recent messages of the channel given to the rewriter.
+    pub history_messages: usize,
+    /// How long to wait for the rewrite before searching the message as
+    /// written.
+    pub timeout_ms: u64,
+}
+
+impl Default for RewriteConfig {
+    fn default() -> Self {
+        Self {
+            specific_words: 12,
+            history_messages: 6,
+            timeout_ms: 1500,
+        }
+    }
+}
+
+impl RewriteConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.specific_words == 0 {
+            return Err("query_rewrite.specific_words must be at least 1".to_string());
+        }
+        if self.timeout_ms == 0 {
+            return Err("query_rewrite.timeout_ms must be positive".to_string());
+        }
+        Ok(())
+    }
+
+    /// Whether `message` lacks standalone meaning: it is short, mostly
+    /// refers back to the conversation, or is a reply to another message.
+    /// Specific messages are searched as written even as replies, to save
+    /// the call.
+    pub fn need_rewrite(&self, message: &str, is_reply: bool) -> bool {
+        let words = message
+            .split_whitespace()
+            .map(|word| {
+                word.trim_matches(|c: char| !c.is_alphanumeric() && c != '\'')
+                    .to_lowercase()
+            })
+            .filter(|word| !word.is_empty())
+            .collect::<Vec<_>>();
+        if words.is_empty() {
+            return false;
+        }
+        let referring = words
+            .iter()
+            .filter(|word| REFERRING_WORDS.contains(&word.as_str()))
+            .count();
+        let pronoun_heavy = referring as f64 / words.len() as f64 >= PRONOUN_SHARE;
+
+        pronoun_heavy || words.len() < self.specific_words || (is_reply && !self.is_long(&words))
+    }
+
+    fn is_long(&self, words: &[String]) -> bool {
+        words.len() >= self.specific_words * 2
+    }
+}
+
+/// The text documents are searched with for a message, and the message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetrievalQuery {
+    pub original: String,
+    /// `None` when the message was searched as written.
+    pub rewritten: Option<String>,
+}
+
+impl RetrievalQuery {
+    /// `message` searched as written.
+    pub fn raw(message: &str) -> Self {
+        Self {
+            original: message.to_string(),
+            rewritten: None,
+        }
+    }
+
+    /// What documents are searched with.
+    pub fn text(&self) -> &str {
+        self.rewritten.as_deref().unwrap_or(&self.original)
+    }
+}
+
+/// The input of the rewriting call: the recent conversation, oldest first,
+/// then the message to rewrite.
+pub fn rewrite_prompt(message: &str, history: &[Message]) -> String {
+    let conversation = history
+        .iter()
+        .map(|message| format!("{}: {}", message.role, escape::inline(&message.content)))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let message = escape::inline(message);
+    if conversation.is_empty() {
+        format!("Latest message: {message}")
+    } else {
+        format!("Conversation:\n{conversation}\n\nLatest message: {message}")
+    }
+}
+
+/// The query in the rewriter's `output`, without quotes or a `Query:`
+/// label. `None` when nothing usable is left.
+pub fn parse_rewrite(output: &str) -> Option<String> {
+    let line = output
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())?;
+    let line = ["query:", "search query:"]
+        .iter()
+        .find_map(|label| {
+            line.get(..label.len())
+                .filter(|prefix| prefix.eq_ignore_ascii_case(label))
+                .map(|_| line[label.len()..].trim())
+        })
+        .unwrap_or(line);
+    let query = line
+        .trim_matches(|c| c == '"' || c == '\'' || c == '`')
+        .trim();
+    (!query.is_empty() && query.chars().count() <= MAX_REWRITE_CHARS).then(|| query.to_string())
+}
+
+/// Rewrites a message into a search query.
+#[async_trait]
+pub trait QueryRewriter: Send + Sync {
+    /// The query for `message` given `history`, oldest first. `None` when it
+    /// can't be rewritten, so the message is searched as written.
+    async fn rewrite(&self, message: &str, history: &[Message]) -> Option<String>;
+}
+
+/// Asks a completion model, ideally a cheap one such as the attention's.
+pub struct ModelRewriter<M: CompletionModel> {
+    model: M,
+}
+
+impl<M: CompletionModel> ModelRewriter<M> {
+    pub fn new(model: M) -> Self {
+        Self { model }
+    }
+}
+
+#[async_trait]
+impl<M: CompletionModel> QueryRewriter for ModelRewriter<M> {
+    async fn rewrite(&self, message: &str, history: &[Message]) -> Option<String> {
+        let agent = AgentBuilder::new(self.model.clone())
+            .preamble(REWRITE_INSTRUCTION)
+            .build();
+        match agent
+            .prompt(rewrite_prompt(message, history).as_str())
+            .await
+        {
+            Ok(output) => parse_rewrite(&output),
+            Err(err) => {
+                error!(?err, "Failed to rewrite query");
+                None
+            }
+        }
+    }
+}
+
+impl<M: CompletionModel, E: EmbeddingModel> Agent<M, E> {
+    /// The query documents are searched with for `input` in `channel_id`,
+    /// rewritten when configured and `input` needs it, and stored with
+    /// `interaction_id`. `is_reply` marks a reply to another message.
+    pub async fn retrieval_query(
+        &self,
+        channel_id: &str,
+        input: &str,
+        is_reply: bool,
+        interaction_id: Option<i64>,
+    ) -> RetrievalQuery {
+        let query = self.rewrite_query(channel_id, input, is_reply).await;
+        if let Some(id) = interaction_id {
+            if let Err(err) = self.knowledge().record_query(id, &query).await {
+                error!(?err, "Failed to record retrieval query");
+            }
+        }
+        query
+    }
+
+    async fn rewrite_query(&self, channel_id: &str, input: &str, is_reply: bool) -> RetrievalQuery {
+        let (Some(config), Some(rewriter)) = (self.rewrite_config(), self.query_rewriter()) else {
+            return RetrievalQuery::raw(input);
+        };
+        if !config.need_rewrite(input, is_reply) {
+            return RetrievalQuery::raw(input);
+        }
+
+        let history = match self
+            .knowledge()
+            .get_recent_messages(channel_id, config.history_messages)
+            .await
+        {
+            Ok(mut messages) => {
+                messages.reverse();
+                messages
+            }
+            Err(err) => {
+                error!(?err, "Failed to load history for query rewrite");
+                Vec::new()
+            }
+        };
+        let timeout = Duration::from_millis(config.timeout_ms);
+        let rewritten = match tokio::time::timeout(timeout, rewriter.rewrite(input, &history)).await
+        {
+            Ok(rewritten) => rewritten,
+            Err(_) => {
+                warn!(
+                    timeout_ms = config.timeout_ms,
+                    "Query rewrite timed out, searching the message as written"
+                );
+                None
+            }
+        };
+        debug!(original = input, rewritten, "Rewrote retrieval query");
+        RetrievalQuery {
+            original: input.to_string(),
+            rewritten,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        knowledge::{ChannelType, Source},
+        test_utils::{self, ScriptedCompletionModel},
+    };
+
+    fn message(role: &str, content: &str) -> Message {
+        Message {
+            id: format!("{role}-{content}"),
+            source: Source::Discord,
+            source_id: role.to_string(),
+            channel_type: ChannelType::Text,
+            channel_id: "c1".to_string(),
+            account_id: role.to_string(),
+            role: role.to_string(),
+            content: content.to_string(),
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    /// Takes longer than any timeout in these tests.
+    struct SlowRewriter;
+
+    #[async_trait]
+    impl QueryRewriter for SlowRewriter {
+        async fn rewrite(&self, _message: &str, _history: &[Message]) -> Option<String> {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Some("too late".to_string())
+        }
+    }
+
+    async fn agent(
+        rewriter: Arc<dyn QueryRewriter>,
+    ) -> Agent<ScriptedCompletionModel, test_utils::FakeEmbeddingModel> {
+        let character = test_utils::character();
+        Agent::new(
+            character,
+            ScriptedCompletionModel::default(),
+            test_utils::knowledge_base().await,
+        )
+        .with_query_rewrite(RewriteConfig::default())
+        .with_query_rewriter(rewriter)
+    }
+
+    #[test]
+    fn test_need_rewrite() {
+        let config = RewriteConfig::default();
+        assert!(config.need_rewrite("it still doesn't work after I did that", false));
+        assert!(config.need_rewrite("and the fees?", false));
+        assert!(!config.need_rewrite(
+            "How do I configure the VRF provider fees for a Starknet mainnet deployment with Katana?",
+            false
+        ));
+        // Replies are rewritten unless long enough to stand on their own
+        assert!(config.need_rewrite(
+            "which version of the controller package should I install for the new session keys",
+            true
+        ));
+        assert!(!config.need_rewrite(
+            "which version of the controller package should I install for the new session keys flow, given that I am on Starknet mainnet and use Katana locally for testing?",
+            true
+        ));
+        assert!(!config.need_rewrite("  ", true));
+    }
+
+    #[test]
+    fn test_rewrite_prompt_and_parse() {
+        let history = [
+            message("user", "I get error 0x41 when setting VRF fees"),
+            message("assistant", "Try bumping the fee token allowance."),
+        ];
+        assert_eq!(
+            rewrite_prompt("it still doesn't work", &history),
+            "Conversation:\nuser: I get error 0x41 when setting VRF fees\nassistant: Try bumping the fee token allowance.\n\nLatest message: it still doesn't work"
+        );
+        assert_eq!(rewrite_prompt("gm", &[]), "Latest message: gm");
+
+        assert_eq!(
+            parse_rewrite("Query: \"fix error 0x41 setting VRF fees\"\n").as_deref(),
+            Some("fix error 0x41 setting VRF fees")
+        );
+        assert_eq!(
+            parse_rewrite("\n  VRF fee allowance  ").as_deref(),
+            Some("VRF fee allowance")
+        );
+        assert_eq!(parse_rewrite("\"\""), None);
+        assert_eq!(parse_rewrite(&"a".repeat(MAX_REWRITE_CHARS + 1)), None);
+    }
+
+    #[tokio::test]
+    async fn test_model_rewriter() {
+        let model = ScriptedCompletionModel::new(["Query: fix error 0x41 setting VRF fees"]);
+        let rewriter = ModelRewriter::new(model.clone());
+        let history = [message("user", "I get error 0x41 when setting VRF fees")];
+
+        assert_eq!(
+            rewriter
+                .rewrite("it still doesn't work", &history)
+                .await
+                .as_deref(),
+            Some("fix error 0x41 setting VRF fees")
+        );
+        let request = &model.requests()[0];
+        assert_eq!(request.preamble.as_deref(), Some(REWRITE_INSTRUCTION));
+        assert!(request.prompt.contains("user: I get error 0x41"));
+        // A failed call searches the message as written
+        assert_eq!(
+            rewriter.rewrite("it still doesn't work", &history).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_is_skipped_or_falls_back() {
+        let model = ScriptedCompletionModel::new(["fix error 0x41 setting VRF fees"]);
+        let agent = agent(Arc::new(ModelRewriter::new(model.clone()))).await;
+
+        let specific =
+            "How do I configure the VRF provider fees for a Starknet mainnet deployment?";
+        let query = agent.retrieval_query("c1", specific, false, None).await;
+        assert_eq!(query, RetrievalQuery::raw(specific));
+        assert!(model.requests().is_empty());
+
+        let query = agent
+            .retrieval_query("c1", "it still doesn't work", false, None)
+            .await;
+        assert_eq!(query.text(), "fix error 0x41 setting VRF fees");
+        assert_eq!(query.original, "it still doesn't work");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rewrite_times_out() {
+        let agent = agent(Arc::new(SlowRewriter)).await;
+        let query = agent.retrieval_query("c1", "and that?", false, None).await;
+        assert_eq!(query, RetrievalQuery::raw("and that?"));
+    }
+}
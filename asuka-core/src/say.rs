@@ -0,0 +1,256 @@
+//! `/say`, for admins posting an announcement as the bot. The text is posted
+//! as given, or with `--restyle` rewritten in character by
+//! [Agent::restyle], and otherwise skips the completion model. It still goes
+//! out like an answer: each client sanitizes, chunks and sends it with its
+//! usual mention policy, then [record_delivery] records the sent messages and
+//! stores it as an assistant turn. Every announcement is audited, see
+//! [KnowledgeBase::announcements].
+
+use rig::{completion::CompletionModel, embeddings::EmbeddingModel};
+use rig_sqlite::SqliteError;
+use thiserror::Error;
+use tracing::{error, info};
+
+use crate::{
+    agent::Agent,
+    knowledge::{ChannelType, KnowledgeBase, Message, Source},
+    logging::AUDIT_TARGET,
+    permissions::PermissionTier,
+};
+
+/// Instruction for restyling, followed by the announcement as the prompt.
+pub const RESTYLE_INSTRUCTION: &str = "An admin asked you to post the announcement below. Rewrite it in your own voice, keeping every fact, name, link, date and number exactly as given and adding nothing. Reply with only the rewritten announcement.";
+
+#[derive(Error, Debug)]
+pub enum SayError {
+    #[error("Only admins can speak as the bot.")]
+    PermissionDenied,
+    #[error("You can't post in that channel.")]
+    NoAccess,
+    #[error("Failed to record the announcement: {0}")]
+    Storage(#[from] SqliteError),
+}
+
+/// A `/say` as a client received it.
+#[derive(Debug, Clone)]
+pub struct SayRequest {
+    pub source: Source,
+    pub invoked_by: String,
+    pub channel_id: String,
+    pub text: String,
+    pub restyle: bool,
+}
+
+/// An announcement ready to be sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Prepared {
+    /// Interaction the sent messages are recorded under.
+    pub interaction_id: i64,
+    pub channel_id: String,
+    pub text: String,
+}
+
+/// Whether a user of `tier` may use `/say`. Checked before anything else, so
+/// other users learn nothing about the channel they named.
+pub fn authorize(tier: PermissionTier) -> Result<(), SayError> {
+    if tier < PermissionTier::Admin {
+        return Err(SayError::PermissionDenied);
+    }
+    Ok(())
+}
+
+/// Restyles the text if asked and records the announcement. Callers check
+/// [authorize] and that the invoker can access the channel first.
+pub async fn prepare<M: CompletionModel, E: EmbeddingModel>(
+    agent: &Agent<M, E>,
+    request: &SayRequest,
+) -> Result<Prepared, SayError> {
+    let text = if request.restyle {
+        agent
+            .restyle(&request.text)
+            .await
+            .unwrap_or_else(|| request.text.clone())
+    } else {
+        request.text.clone()
+    };
+
+    let knowledge = agent.knowledge();
+    let interaction_id = knowledge
+        .create_interaction(
+            request.channel_id.clone(),
+            request.invoked_by.clone(),
+            Vec::new(),
+        )
+        .await?;
+    let id = knowledge
+        .record_announcement(
+            interaction_id,
+            &request.source,
+            &request.channel_id,
+            &request.invoked_by,
+            &request.text,
+            &text,
+        )
+        .await?;
+    info!(
+        target: AUDIT_TARGET,
+        id,
+        channel_id = request.channel_id,
+        invoked_by = request.invoked_by,
+        restyle = request.restyle,
+        "Speaking as the bot"
+    );
+
+    Ok(Prepared {
+        interaction_id,
+        channel_id: request.channel_id.clone(),
+        text,
+    })
+}
+
+/// Records the messages `announcement` went out as, `sent` being the ids of
+/// the first chunks that were sent, and stores what was sent as an assistant
+/// turn of the channel so later answers see it.
+pub async fn record_delivery<E: EmbeddingModel>(
+    knowledge: &KnowledgeBase<E>,
+    announcement: &Prepared,
+    source: Source,
+    channel_type: ChannelType,
+    bot_id: &str,
+    chunks: &[String],
+    sent: &[String],
+) {
+    let Some(first) = sent.first() else {
+        return;
+    };
+    if let Err(err) = knowledge
+        .record_sent_messages(
+            announcement.interaction_id,
+            &source,
+            &announcement.channel_id,
+            sent,
+        )
+        .await
+    {
+        error!(?err, "Failed to record sent messages");
+    }
+
+    let record = Message {
+        id: first.clone(),
+        source,
+        source_id: bot_id.to_string(),
+        channel_type,
+        channel_id: announcement.channel_id.clone(),
+        account_id: bot_id.to_string(),
+        role: "assistant".to_string(),
+        content: chunks[..sent.len().min(chunks.len())].join("\n"),
+        created_at: chrono::Utc::now(),
+    };
+    if let Err(err) = knowledge.create_message(record).await {
+        error!(?err, "Failed to store announcement");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{self, FakeEmbeddingModel, ScriptedCompletionModel};
+
+    async fn agent(replies: &[&str]) -> Agent<ScriptedCompletionModel, FakeEmbeddingModel> {
+        let character = test_utils::character();
+        let model = ScriptedCompletionModel::new(replies.iter().copied());
+        Agent::new(character, model, test_utils::knowledge_base().await)
+    }
+
+    fn request(restyle: bool) -> SayRequest {
+        SayRequest {
+            source: Source::Discord,
+            invoked_by: "alice".to_string(),
+            channel_id: "announcements".to_string(),
+            text: "Mainnet launches friday.".to_string(),
+            restyle,
+        }
+    }
+
+    #[test]
+    fn test_only_admins_can_say() {
+        assert!(authorize(PermissionTier::Admin).is_ok());
+        for tier in [PermissionTier::User, PermissionTier::Trusted] {
+            assert!(matches!(authorize(tier), Err(SayError::PermissionDenied)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_text_is_posted_verbatim_without_restyle() {
+        // No scripted replies, so any completion would fail
+        let agent = agent(&[]).await;
+
+        let announcement = prepare(&agent, &request(false)).await.unwrap();
+
+        assert_eq!(announcement.text, "Mainnet launches friday.");
+        let audit = agent
+            .knowledge()
+            .announcements("announcements")
+            .await
+            .unwrap();
+        assert_eq!(audit.len(), 1);
+        assert_eq!(audit[0].invoked_by, "alice");
+        assert_eq!(audit[0].interaction_id, Some(announcement.interaction_id));
+        assert!(!audit[0].restyled);
+    }
+
+    #[tokio::test]
+    async fn test_restyle() {
+        let agent = agent(&["Big news, friends: mainnet launches friday!"]).await;
+
+        let announcement = prepare(&agent, &request(true)).await.unwrap();
+
+        assert_eq!(
+            announcement.text,
+            "Big news, friends: mainnet launches friday!"
+        );
+        let audit = agent
+            .knowledge()
+            .announcements("announcements")
+            .await
+            .unwrap();
+        assert_eq!(audit[0].requested, "Mainnet launches friday.");
+        assert_eq!(audit[0].content, announcement.text);
+        assert!(audit[0].restyled);
+
+        // A failed restyle posts the text as given
+        let announcement = prepare(&agent, &request(true)).await.unwrap();
+        assert_eq!(announcement.text, "Mainnet launches friday.");
+    }
+
+    #[tokio::test]
+    async fn test_delivered_announcement_is_an_assistant_turn() {
+        let agent = agent(&[]).await;
+        let knowledge = agent.knowledge();
+        let announcement = prepare(&agent, &request(false)).await.unwrap();
+        let chunks = vec![announcement.text.clone()];
+
+        record_delivery(
+            knowledge,
+            &announcement,
+            Source::Discord,
+            ChannelType::Text,
+            "bot",
+            &chunks,
+            &["1001".to_string()],
+        )
+        .await;
+
+        assert_eq!(
+            knowledge.recent_replies("announcements", 5).await.unwrap(),
+            vec!["Mainnet launches friday.".to_string()]
+        );
+        assert_eq!(
+            knowledge
+                .interaction_for_platform_message(&Source::Discord, "1001")
+                .await
+                .unwrap(),
+            Some(announcement.interaction_id)
+        );
+    }
+}
@@ -0,0 +1,437 @@
+//! Scrubbing of records before they are exported to be shared, such as
+//! interaction logs for a research partner.
+//!
+//! [scrub] pseudonymizes account ids and display names with an HMAC keyed
+//! by [ScrubPolicy::key], so a user keeps one pseudonym across an export and
+//! across exports with the same key, redacts emails, phone numbers, street
+//! addresses and wallet addresses in the content, and drops metadata not in
+//! [ScrubPolicy::fields]. Content is matched after folding fullwidth and
+//! other lookalike characters to ASCII, so `ａｌｉｃｅ＠ｅｘａｍｐｌｅ．ｃｏｍ`
+//! is an email too. An export scrubs each record through a [ScrubReport],
+//! which counts what was redacted.
+
+use std::{collections::BTreeMap, fmt, ops::Range, sync::OnceLock};
+
+use hmac::{Hmac, Mac};
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sha2::Sha256;
+
+/// What a match in the content was redacted as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Category {
+    Email,
+    Phone,
+    Address,
+    Wallet,
+}
+
+impl Category {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Category::Email => "email",
+            Category::Phone => "phone",
+            Category::Address => "address",
+            Category::Wallet => "wallet",
+        }
+    }
+
+    /// Placeholder the match is replaced with.
+    fn placeholder(&self) -> String {
+        format!("[{}]", self.as_str())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScrubPolicy {
+    /// Secret pseudonyms are derived with. Keep it to map users the same way
+    /// in later exports; change it so they cannot be linked.
+    pub key: String,
+    /// Metadata fields kept in exported records. Others are dropped.
+    #[serde(default = "default_fields")]
+    pub fields: Vec<String>,
+}
+
+fn default_fields() -> Vec<String> {
+    ["created_at", "role", "source", "channel_type"]
+        .iter()
+        .map(|field| field.to_string())
+        .collect()
+}
+
+impl ScrubPolicy {
+    pub fn new(key: impl Into<String>) -> Self {
+        Self {
+            key: key.into(),
+            fields: default_fields(),
+        }
+    }
+
+    pub fn with_fields(mut self, fields: &[&str]) -> Self {
+        self.fields = fields.iter().map(|field| field.to_string()).collect();
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.key.len() < 16 {
+            return Err("scrub.key must be at least 16 characters".to_string());
+        }
+        Ok(())
+    }
+
+    /// Stable pseudonym of the account `account_id`.
+    pub fn pseudonym(&self, account_id: &str) -> String {
+        format!("user-{}", self.digest("account", account_id))
+    }
+
+    /// Stable pseudonym of the display name `name`, ignoring case.
+    pub fn display_pseudonym(&self, name: &str) -> String {
+        format!("User {}", self.digest("name", &name.trim().to_lowercase()))
+    }
+
+    /// First hex digits of the HMAC of `value`, with `kind` keeping account
+    /// ids and names that happen to be equal apart.
+    fn digest(&self, kind: &str, value: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.key.as_bytes())
+            .expect("HMAC takes keys of any length");
+        mac.update(kind.as_bytes());
+        mac.update(b":");
+        mac.update(value.as_bytes());
+        mac.finalize().into_bytes()[..6]
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+/// One exported message or interaction, as [scrub] sees it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportRecord {
+    pub account_id: String,
+    pub display_name: Option<String>,
+    pub content: String,
+    pub metadata: Map<String, Value>,
+}
+
+/// Redactions by category, over one record or a whole export.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScrubReport {
+    pub records: usize,
+    pub redactions: BTreeMap<Category, usize>,
+}
+
+impl ScrubReport {
+    /// Scrubs `record` like [scrub], adding its redactions to the report.
+    pub fn scrub(&mut self, record: ExportRecord, policy: &ScrubPolicy) -> ExportRecord {
+        let (record, redactions) = scrub_counted(record, policy);
+        self.records += 1;
+        for (category, count) in redactions {
+            *self.redactions.entry(category).or_default() += count;
+        }
+        record
+    }
+
+    pub fn total(&self) -> usize {
+        self.redactions.values().sum()
+    }
+}
+
+impl fmt::Display for ScrubReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} records, {} redactions", self.records, self.total())?;
+        for (category, count) in &self.redactions {
+            write!(f, ", {count} {}", category.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+/// `record` with its account and display name pseudonymized, personal data
+/// in its content redacted and its metadata cut to the policy's fields.
+pub fn scrub(record: ExportRecord, policy: &ScrubPolicy) -> ExportRecord {
+    scrub_counted(record, policy).0
+}
+
+fn scrub_counted(
+    record: ExportRecord,
+    policy: &ScrubPolicy,
+) -> (ExportRecord, BTreeMap<Category, usize>) {
+    let (mut content, redactions) = redact(&record.content);
+    if let Some(name) = record.display_name.as_deref().map(str::trim) {
+        if !name.is_empty() {
+            content = replace_name(&content, name, &policy.display_pseudonym(name));
+        }
+    }
+
+    let record = ExportRecord {
+        account_id: policy.pseudonym(&record.account_id),
+        display_name: record
+            .display_name
+            .map(|name| policy.display_pseudonym(&name)),
+        content,
+        metadata: record
+            .metadata
+            .into_iter()
+            .filter(|(field, _)| policy.fields.contains(field))
+            .collect(),
+    };
+    (record, redactions)
+}
+
+/// Patterns in the order they are applied, so a wallet address is not taken
+/// for a phone number.
+fn patterns() -> &'static [(Category, Regex)] {
+    static PATTERNS: OnceLock<Vec<(Category, Regex)>> = OnceLock::new();
+    PATTERNS.get_or_init(|| {
+        [
+            (
+                Category::Wallet,
+                // Hex addresses of EVM chains and Starknet, abbreviated ones
+                // such as 0x049d…4dc7, and bech32 Bitcoin addresses
+                r"(?i)\b0x[0-9a-f]{4,}(?:…|\.{2,3})[0-9a-f]{4,}\b|\b0x[0-9a-f]{40,64}\b|\bbc1[02-9ac-hj-np-z]{25,59}\b",
+            ),
+            (
+                Category::Email,
+                r"(?i)[a-z0-9._%+-]+\s*(?:@|\[at\]|\(at\))\s*[a-z0-9-]+(?:\s*(?:\.|\[dot\]|\(dot\))\s*[a-z0-9-]+)*\s*(?:\.|\[dot\]|\(dot\))\s*[a-z]{2,}\b",
+            ),
+            (
+                Category::Address,
+                r"(?i)\b\d{1,5}\s+(?:[a-z]+\s+){1,3}(?:street|st|avenue|ave|road|rd|boulevard|blvd|lane|ln|drive|dr|court|ct|place|pl|way|strasse|straße)\b\.?(?:,?\s*(?:apt|apartment|suite|unit)\.?\s*#?\d+[a-z]?)?",
+            ),
+            (Category::Phone, r"\+?\(?\d[\d\s().-]{6,}\d"),
+        ]
+        .into_iter()
+        .map(|(category, pattern)| (category, Regex::new(pattern).unwrap()))
+        .collect()
+    })
+}
+
+/// Whether a phone pattern match has as many digits as a phone number and
+/// does not start with a date, so dates, times, versions and amounts are
+/// left alone.
+fn is_phone(text: &str) -> bool {
+    let digits = text.chars().filter(char::is_ascii_digit).count();
+    let date = text.len() >= 10
+        && text.as_bytes()[4] == b'-'
+        && text.as_bytes()[7] == b'-'
+        && text[..4].bytes().all(|b| b.is_ascii_digit());
+    (9..=15).contains(&digits) && !date
+}
+
+/// `text` with personal data replaced by placeholders, and how many of each
+/// category were replaced.
+pub fn redact(text: &str) -> (String, BTreeMap<Category, usize>) {
+    let mut redactions = BTreeMap::new();
+    let mut text = text.to_string();
+    for (category, pattern) in patterns() {
+        let folded = Folded::new(&text);
+        let ranges = pattern
+            .find_iter(&folded.text)
+            .filter(|found| *category != Category::Phone || is_phone(found.as_str()))
+            .map(|found| folded.original(found.range()))
+            .collect::<Vec<_>>();
+        if ranges.is_empty() {
+            continue;
+        }
+
+        *redactions.entry(*category).or_default() += ranges.len();
+        let placeholder = category.placeholder();
+        for range in ranges.into_iter().rev() {
+            text.replace_range(range, &placeholder);
+        }
+    }
+    (text, redactions)
+}
+
+/// `text` with each lookalike character folded to its ASCII counterpart,
+/// and where each folded character came from.
+struct Folded {
+    text: String,
+    /// Byte offset in the original text of each folded byte, plus its end.
+    offsets: Vec<usize>,
+}
+
+impl Folded {
+    fn new(original: &str) -> Self {
+        let mut text = String::with_capacity(original.len());
+        let mut offsets = Vec::with_capacity(original.len() + 1);
+        for (offset, c) in original.char_indices() {
+            let folded = fold(c);
+            text.push(folded);
+            offsets.extend(std::iter::repeat(offset).take(folded.len_utf8()));
+        }
+        offsets.push(original.len());
+        Self { text, offsets }
+    }
+
+    /// The range of the original text `range` of the folded one came from.
+    fn original(&self, range: Range<usize>) -> Range<usize> {
+        let end = self.offsets[range.end];
+        self.offsets[range.start]..end
+    }
+}
+
+fn fold(c: char) -> char {
+    match c {
+        // Fullwidth forms of ASCII, e.g. ＠ and ０
+        '\u{FF01}'..='\u{FF5E}' => char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+        '\u{FE6B}' => '@',
+        '\u{2024}' | '\u{FE52}' | '\u{3002}' => '.',
+        '\u{2010}'..='\u{2015}' | '\u{2212}' | '\u{FE63}' => '-',
+        '\u{00A0}' | '\u{2000}'..='\u{200A}' | '\u{202F}' | '\u{3000}' => ' ',
+        // Mathematical and circled digits
+        '\u{1D7CE}'..='\u{1D7FF}' => char::from_digit((c as u32 - 0x1D7CE) % 10, 10).unwrap_or(c),
+        _ => c,
+    }
+}
+
+/// `content` with whole-word mentions of `name` replaced by `pseudonym`,
+/// ignoring case.
+fn replace_name(content: &str, name: &str, pseudonym: &str) -> String {
+    let pattern = format!(r"(?i)(^|\W)@?{}(\W|$)", regex::escape(name));
+    match Regex::new(&pattern) {
+        Ok(pattern) => pattern
+            .replace_all(content, |captures: &regex::Captures| {
+                format!("{}{pseudonym}{}", &captures[1], &captures[2])
+            })
+            .into_owned(),
+        Err(_) => content.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> ScrubPolicy {
+        ScrubPolicy::new("research-export-2025")
+    }
+
+    fn record(account_id: &str, name: Option<&str>, content: &str) -> ExportRecord {
+        ExportRecord {
+            account_id: account_id.to_string(),
+            display_name: name.map(str::to_string),
+            content: content.to_string(),
+            metadata: Map::from_iter([
+                (
+                    "created_at".to_string(),
+                    Value::from("2025-01-15T12:00:00Z"),
+                ),
+                ("channel_id".to_string(), Value::from("1234567890")),
+                ("role".to_string(), Value::from("user")),
+            ]),
+        }
+    }
+
+    #[test]
+    fn test_redact_corpus() {
+        let cases = [
+            ("mail me at alice@example.com", "mail me at [email]"),
+            ("ａｌｉｃｅ＠ｅｘａｍｐｌｅ．ｃｏｍ pls", "[email] pls"),
+            ("alice [at] example [dot] com", "[email]"),
+            ("Alice.B+bots@Sub.Example.co.uk.", "[email]."),
+            ("call +1 (555) 123-4567 today", "call [phone] today"),
+            ("call ＋４４ ２０ ７９４６ ０９５８", "call [phone]"),
+            (
+                "released 2025-01-15 as v1.2.3",
+                "released 2025-01-15 as v1.2.3",
+            ),
+            ("it costs 1,000,000 wei", "it costs 1,000,000 wei"),
+            (
+                "send to 0x52908400098527886E0F7030069857D2E4169EE7",
+                "send to [wallet]",
+            ),
+            (
+                "account 0x049d36570d4e46f48e99674bd3fcc84644ddd6b96f7c741b1562b82f9e004dc7 again",
+                "account [wallet] again",
+            ),
+            (
+                "my wallet 0x049d…4dc7 is empty",
+                "my wallet [wallet] is empty",
+            ),
+            (
+                "my wallet 0x049d...4dc7 is empty",
+                "my wallet [wallet] is empty",
+            ),
+            ("bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq", "[wallet]"),
+            ("tx 0xabc failed", "tx 0xabc failed"),
+            ("I live at 42 Main St, Apt 4B", "I live at [address]"),
+            (
+                "ship it to 1600 Pennsylvania Avenue",
+                "ship it to [address]",
+            ),
+            ("I have 3 main points", "I have 3 main points"),
+            ("no personal data here", "no personal data here"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(redact(text).0, expected, "redacting {text:?}");
+        }
+    }
+
+    #[test]
+    fn test_redactions_are_counted() {
+        let mut report = ScrubReport::default();
+        for content in [
+            "reach me at bob@example.com or +1 555 123 4567",
+            "or at carol@example.com",
+            "nothing to see",
+        ] {
+            report.scrub(record("42", None, content), &policy());
+        }
+
+        assert_eq!(report.records, 3);
+        assert_eq!(report.redactions[&Category::Email], 2);
+        assert_eq!(report.redactions[&Category::Phone], 1);
+        assert_eq!(
+            report.to_string(),
+            "3 records, 3 redactions, 2 email, 1 phone"
+        );
+    }
+
+    #[test]
+    fn test_pseudonyms_are_consistent() {
+        let policy = policy();
+        let first = scrub(record("42", Some("Alice"), "I'm alice, hi"), &policy);
+        let second = scrub(record("42", Some("alice"), "@Alice here"), &policy);
+
+        assert_eq!(first.account_id, second.account_id);
+        assert_eq!(first.display_name, second.display_name);
+        assert_ne!(first.account_id, "42");
+        let name = first.display_name.clone().unwrap();
+        assert_eq!(first.content, format!("I'm {name}, hi"));
+        assert_eq!(second.content, format!("{name} here"));
+        // Names inside other words stay
+        assert_eq!(
+            scrub(record("42", Some("al"), "also"), &policy).content,
+            "also"
+        );
+
+        assert_ne!(
+            scrub(record("43", None, ""), &policy).account_id,
+            first.account_id
+        );
+        let rekeyed = ScrubPolicy::new("another-export-key");
+        assert_ne!(
+            scrub(record("42", None, ""), &rekeyed).account_id,
+            first.account_id
+        );
+    }
+
+    #[test]
+    fn test_metadata_allowlist() {
+        let scrubbed = scrub(
+            record("42", None, "hi"),
+            &policy().with_fields(&["created_at"]),
+        );
+        assert_eq!(scrubbed.metadata.keys().collect::<Vec<_>>(), ["created_at"]);
+        assert_eq!(
+            scrub(record("42", None, "hi"), &policy())
+                .metadata
+                .keys()
+                .collect::<Vec<_>>(),
+            ["created_at", "role"]
+        );
+    }
+}
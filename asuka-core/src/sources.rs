@@ -0,0 +1,882 @@
+//! Git repositories the knowledge base is built from, such as the docs, the
+//! examples and an SDK reference, each synced on its own.
+//!
+//! A [SourceManager] clones every repository of the `[knowledge]` section,
+//! ingests it, then keeps it up to date every `sync_minutes`. The commit of
+//! the last sync is kept in the knowledge base per repository, so a sync only
+//! reads the files changed since, and a restart doesn't read everything
+//! again. One repository failing to sync, say it is unreachable, doesn't hold
+//! up the others; its error is recorded and it is tried again next time.
+//!
+//! A repository's documents have its name as their `source_id`, so listing,
+//! deleting and citing documents, and the `refresh_knowledge` tool, can target
+//! it by name.
+//!
+//! ```toml
+//! [[knowledge.repos]]
+//! name = "docs"
+//! url = "https://github.com/cartridge-gg/docs"
+//! directories = ["src/pages"]
+//! globs = ["**/*.md", "**/*.mdx"]
+//! topics = { vrf = "vrf", controller = "controller" }
+//!
+//! [[knowledge.repos]]
+//! name = "sdk"
+//! url = "https://github.com/cartridge-gg/controller"
+//! branch = "next"
+//! directories = ["packages/sdk/src"]
+//! sync_minutes = 360
+//! ```
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use glob::{MatchOptions, Pattern};
+use rig::embeddings::EmbeddingModel;
+use serde::Deserialize;
+use thiserror::Error;
+use tracing::{error, info};
+use walkdir::WalkDir;
+
+use crate::{
+    knowledge::{
+        Document, IngestEvent, IngestHook, KnowledgeBase, RefreshRegistry, RefreshSource,
+        RefreshSummary,
+    },
+    loaders::github::{repo_files, GitLoaderError, GitRepo},
+};
+
+/// Globs match like in `.gitignore`: `*` stays within a directory, `**`
+/// spans any number of them.
+const GLOB_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// `[knowledge]` settings of the config file.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KnowledgeSourceConfig {
+    pub repos: Vec<RepoConfig>,
+}
+
+/// A repository of `[[knowledge.repos]]`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RepoConfig {
+    /// The `source_id` of the repository's documents, and its name for the
+    /// `refresh_knowledge` tool. Changing it ingests the repository anew.
+    pub name: String,
+    pub url: String,
+    #[serde(default = "default_branch")]
+    pub branch: String,
+    /// Directories read, relative to the root, or the whole repository when
+    /// empty.
+    #[serde(default)]
+    pub directories: Vec<String>,
+    /// Only files matching one of these, relative to the root, such as
+    /// `**/*.md`. Every file when empty.
+    #[serde(default)]
+    pub globs: Vec<String>,
+    /// Directory names to topic tags, see
+    /// [GitLoader::with_topic_map](crate::loaders::github::GitLoader::with_topic_map).
+    #[serde(default)]
+    pub topics: HashMap<String, String>,
+    /// Minutes between syncs, `0` to only sync at startup and on request.
+    #[serde(default = "default_sync_minutes")]
+    pub sync_minutes: u64,
+}
+
+fn default_branch() -> String {
+    "main".to_string()
+}
+
+fn default_sync_minutes() -> u64 {
+    60
+}
+
+impl KnowledgeSourceConfig {
+    /// A single repository, for when the config file lists none.
+    pub fn single(name: &str, url: &str, directory: &str) -> Self {
+        Self {
+            repos: vec![RepoConfig {
+                name: name.to_string(),
+                url: url.to_string(),
+                branch: default_branch(),
+                directories: vec![directory.to_string()],
+                globs: Vec::new(),
+                topics: HashMap::new(),
+                sync_minutes: default_sync_minutes(),
+            }],
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let mut names = HashSet::new();
+        for repo in &self.repos {
+            repo.validate()
+                .map_err(|err| format!("knowledge.repos.{err}"))?;
+            if !names.insert(&repo.name) {
+                return Err(format!("knowledge.repos: {:?} is listed twice", repo.name));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RepoConfig {
+    fn validate(&self) -> Result<(), String> {
+        let valid_name = !self.name.is_empty()
+            && self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+        if !valid_name {
+            return Err(format!(
+                "name: {:?} must be lowercase letters, digits, - and _",
+                self.name
+            ));
+        }
+        let parts = self.url.trim_end_matches(".git").split('/');
+        if parts.filter(|part| !part.is_empty()).count() < 2 {
+            return Err(format!(
+                "{}.url: {:?} is not a repository",
+                self.name, self.url
+            ));
+        }
+        if self.branch.is_empty() {
+            return Err(format!("{}.branch: must not be empty", self.name));
+        }
+        if let Some(directory) = self.directories.iter().find(|directory| {
+            Path::new(directory).is_absolute() || directory.split('/').any(|part| part == "..")
+        }) {
+            return Err(format!(
+                "{}.directories: {directory:?} is not inside the repository",
+                self.name
+            ));
+        }
+        for glob in &self.globs {
+            Pattern::new(glob).map_err(|err| format!("{}.globs: {glob:?}: {err}", self.name))?;
+        }
+        Ok(())
+    }
+
+    /// Web address of the file at `path`, relative to the root, for GitHub
+    /// and other hosts with the same layout. `None` for local repositories.
+    pub fn file_url(&self, path: &str) -> Option<String> {
+        if !self.url.starts_with("https://") {
+            return None;
+        }
+        Some(format!(
+            "{}/blob/{}/{}",
+            self.url.trim_end_matches(".git").trim_end_matches('/'),
+            self.branch,
+            path.trim_start_matches('/')
+        ))
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum SourceError {
+    #[error("Unknown source {0:?}")]
+    UnknownSource(String),
+    #[error(transparent)]
+    Git(#[from] GitLoaderError),
+    #[error("Failed to read documents: {0}")]
+    Read(#[from] std::io::Error),
+    #[error("Failed to store documents: {0}")]
+    Store(String),
+    #[error("Sync task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+/// Outcome of syncing one repository.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncReport {
+    /// Commit the repository was synced to.
+    pub commit: String,
+    /// Whether every file was read, rather than those changed since the last
+    /// sync: on the first sync, or when its commit is gone after a force push.
+    pub full: bool,
+    pub summary: RefreshSummary,
+    /// Files deleted since the last sync. Their documents are kept, see
+    /// [KnowledgeBase::delete_documents] to remove them, or taken over by the
+    /// files they moved to, counted as renamed.
+    pub removed: usize,
+}
+
+/// The commit a repository is at, and which of its files to read.
+struct Checkout {
+    root: PathBuf,
+    commit: String,
+    /// Relative to `root`.
+    files: Vec<PathBuf>,
+    /// Deleted since the last sync, relative to `root`.
+    removed: Vec<PathBuf>,
+    full: bool,
+}
+
+/// One repository of a [SourceManager]. Registered with a
+/// [RefreshRegistry], a refresh pulls it and reads every file again.
+#[derive(Clone)]
+pub struct RepoSource {
+    config: RepoConfig,
+    patterns: Vec<Pattern>,
+    base_path: PathBuf,
+    /// Held while the clone is updated, so a scheduled sync and a refresh
+    /// don't update it at once.
+    checkout_lock: Arc<Mutex<()>>,
+}
+
+impl RepoSource {
+    fn new(config: RepoConfig, base_path: &Path) -> Self {
+        // Invalid globs were rejected when the config was validated
+        let patterns = config
+            .globs
+            .iter()
+            .filter_map(|glob| Pattern::new(glob).ok())
+            .collect();
+        Self {
+            config,
+            patterns,
+            base_path: base_path.to_path_buf(),
+            checkout_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    pub fn config(&self) -> &RepoConfig {
+        &self.config
+    }
+
+    /// Whether the file at `path`, relative to the root, is read.
+    fn includes(&self, path: &Path) -> bool {
+        let in_directory = self.config.directories.is_empty()
+            || self
+                .config
+                .directories
+                .iter()
+                .any(|directory| path.starts_with(directory));
+        in_directory
+            && (self.patterns.is_empty()
+                || self
+                    .patterns
+                    .iter()
+                    .any(|pattern| pattern.matches_path_with(path, GLOB_OPTIONS)))
+    }
+
+    /// Pulls the repository and lists the files changed since commit
+    /// `since`, or every file without one. Blocks on git.
+    fn checkout(&self, since: Option<&str>) -> Result<Checkout, GitLoaderError> {
+        let _lock = self
+            .checkout_lock
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        // Cloned under the repository's name, so two branches of one
+        // repository don't share a checkout
+        let git = GitRepo::new(self.config.url.clone(), self.base_path.join(self.name()))
+            .with_branch(&self.config.branch);
+        let repo = git.sync()?;
+        let commit = GitRepo::head_commit(&repo)?;
+
+        let changes = match since {
+            Some(since) if since == commit => Some(Default::default()),
+            Some(since) => GitRepo::changed_files(&repo, since)?,
+            None => None,
+        };
+        let (files, removed, full) = match changes {
+            Some(changes) => (
+                changes
+                    .changed()
+                    .filter(|path| self.includes(path))
+                    .cloned()
+                    .collect(),
+                changes
+                    .deleted
+                    .into_iter()
+                    .filter(|path| self.includes(path))
+                    .collect(),
+                false,
+            ),
+            None => (self.all_files(&git.path)?, Vec::new(), true),
+        };
+
+        Ok(Checkout {
+            root: git.path,
+            commit,
+            files,
+            removed,
+            full,
+        })
+    }
+
+    fn all_files(&self, root: &Path) -> std::io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        let entries = WalkDir::new(root)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|entry| entry.file_name() != ".git");
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let path = entry.path().strip_prefix(root).unwrap_or(entry.path());
+            if self.includes(path) {
+                files.push(path.to_path_buf());
+            }
+        }
+        Ok(files)
+    }
+
+    async fn read(&self, checkout: Checkout) -> std::io::Result<Vec<Document>> {
+        repo_files(
+            checkout.root,
+            checkout.files,
+            self.name(),
+            self.config.topics.clone(),
+            HashMap::new(),
+        )
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect()
+    }
+}
+
+#[async_trait]
+impl RefreshSource for RepoSource {
+    async fn sync_changes(&self) -> anyhow::Result<Vec<Document>> {
+        let source = self.clone();
+        let checkout = tokio::task::spawn_blocking(move || source.checkout(None)).await??;
+        Ok(self.read(checkout).await?)
+    }
+}
+
+/// Syncs the repositories of a [KnowledgeSourceConfig] into a knowledge
+/// base, independently of each other.
+#[derive(Clone)]
+pub struct SourceManager<E: EmbeddingModel + 'static> {
+    knowledge: KnowledgeBase<E>,
+    sources: Vec<RepoSource>,
+}
+
+impl<E: EmbeddingModel + 'static> SourceManager<E> {
+    /// Clones repositories under `base_path`, one directory per repository
+    /// name. Nothing is cloned until the first sync.
+    pub fn new(
+        knowledge: KnowledgeBase<E>,
+        config: &KnowledgeSourceConfig,
+        base_path: impl AsRef<Path>,
+    ) -> Self {
+        let sources = config
+            .repos
+            .iter()
+            .map(|repo| RepoSource::new(repo.clone(), base_path.as_ref()))
+            .collect();
+        Self { knowledge, sources }
+    }
+
+    /// Repository names, in config order.
+    pub fn names(&self) -> Vec<String> {
+        self.sources
+            .iter()
+            .map(|source| source.name().to_string())
+            .collect()
+    }
+
+    /// Adds every repository to `registry`, under its name.
+    pub fn register(&self, registry: RefreshRegistry<E>) -> RefreshRegistry<E> {
+        self.sources.iter().fold(registry, |registry, source| {
+            registry.with_source(source.name(), source.clone())
+        })
+    }
+
+    /// Web address of `document`, when it comes from one of the
+    /// repositories, see [RepoConfig::file_url].
+    pub fn document_url(&self, document: &Document) -> Option<String> {
+        let source = self
+            .sources
+            .iter()
+            .find(|source| source.name() == document.source_id)?;
+        let path = document
+            .logical_id
+            .as_deref()?
+            .strip_prefix(source.name())?
+            .strip_prefix(':')?;
+        source.config.file_url(path)
+    }
+
+    /// Syncs the repository called `name`: the first time every file is
+    /// read, afterwards only those changed since the last sync. Failures are
+    /// recorded as the repository's last error.
+    ///
+    /// A sync without new commits reads nothing, even if the repository's
+    /// directories or globs changed; the `refresh_knowledge` tool reads every
+    /// file.
+    pub async fn sync(&self, name: &str) -> Result<SyncReport, SourceError> {
+        self.sync_reporting(name, None).await
+    }
+
+    async fn sync_reporting(
+        &self,
+        name: &str,
+        on_event: Option<&IngestHook>,
+    ) -> Result<SyncReport, SourceError> {
+        let source = self
+            .sources
+            .iter()
+            .find(|source| source.name() == name)
+            .ok_or_else(|| SourceError::UnknownSource(name.to_string()))?;
+
+        let result = self.sync_source(source, on_event).await;
+        match &result {
+            Ok(report) => info!(
+                source = name,
+                commit = %report.commit,
+                full = report.full,
+                added = report.summary.added,
+                updated = report.summary.updated,
+                renamed = report.summary.renamed,
+                removed = report.removed,
+                "Synced knowledge source"
+            ),
+            Err(err) => {
+                error!(?err, source = name, "Failed to sync knowledge source");
+                if let Err(err) = self
+                    .knowledge
+                    .record_source_error(name, &err.to_string())
+                    .await
+                {
+                    error!(?err, source = name, "Failed to record sync error");
+                }
+            }
+        }
+        result
+    }
+
+    /// Syncs every repository at once, see [SourceManager::sync].
+    pub async fn sync_all(&self) -> Vec<(String, Result<SyncReport, SourceError>)> {
+        self.sync_all_reporting(None).await
+    }
+
+    /// [SourceManager::sync_all], reporting each repository's documents to
+    /// `on_event` as a batch, such as for [crate::startup::warm_up].
+    pub async fn sync_all_with_progress(
+        &self,
+        on_event: IngestHook,
+    ) -> Vec<(String, Result<SyncReport, SourceError>)> {
+        self.sync_all_reporting(Some(&on_event)).await
+    }
+
+    async fn sync_all_reporting(
+        &self,
+        on_event: Option<&IngestHook>,
+    ) -> Vec<(String, Result<SyncReport, SourceError>)> {
+        futures::future::join_all(self.sources.iter().map(|source| async move {
+            (
+                source.name().to_string(),
+                self.sync_reporting(source.name(), on_event).await,
+            )
+        }))
+        .await
+    }
+
+    async fn sync_source(
+        &self,
+        source: &RepoSource,
+        on_event: Option<&IngestHook>,
+    ) -> Result<SyncReport, SourceError> {
+        let emit = |event: IngestEvent| {
+            if let Some(hook) = on_event {
+                hook(&event);
+            }
+        };
+        let store_error = |e: rig_sqlite::SqliteError| SourceError::Store(format!("{e:?}"));
+        let since = self
+            .knowledge
+            .source_state(source.name())
+            .await
+            .map_err(store_error)?
+            .and_then(|state| state.last_commit);
+
+        let checkout = {
+            let source = source.clone();
+            tokio::task::spawn_blocking(move || source.checkout(since.as_deref())).await??
+        };
+        let (commit, full) = (checkout.commit.clone(), checkout.full);
+        let removed_files = checkout.removed.len();
+        // Logical ids of the removed files, as the loader gives them, so
+        // files that only moved keep their documents
+        let removed = checkout
+            .removed
+            .iter()
+            .map(|path| format!("{}:{}", source.name(), path.to_string_lossy()))
+            .collect::<Vec<_>>();
+        let documents = source.read(checkout).await?;
+        // After reading every file, those missing were removed
+        let removed = match full {
+            true => self
+                .knowledge
+                .absent_documents(&documents)
+                .await
+                .map_err(store_error)?,
+            false => removed,
+        };
+        let size = documents.len();
+        emit(IngestEvent::BatchQueued {
+            size,
+            buffered: size,
+        });
+        let summary = match self
+            .knowledge
+            .refresh_moved_documents(documents, removed)
+            .await
+        {
+            Ok(summary) => {
+                emit(IngestEvent::BatchStored { size });
+                summary
+            }
+            Err(e) => {
+                emit(IngestEvent::BatchFailed {
+                    size,
+                    error: e.to_string(),
+                });
+                return Err(SourceError::Store(e.to_string()));
+            }
+        };
+
+        self.knowledge
+            .record_source_sync(source.name(), &commit)
+            .await
+            .map_err(store_error)?;
+        Ok(SyncReport {
+            commit,
+            full,
+            summary,
+            removed: removed_files,
+        })
+    }
+
+    /// Syncs each repository every [RepoConfig::sync_minutes], on its own
+    /// schedule, until the task is aborted. The first sync is left to the
+    /// caller, see [SourceManager::sync_all].
+    pub fn spawn(self) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut syncs = tokio::task::JoinSet::new();
+            for source in &self.sources {
+                if source.config.sync_minutes == 0 {
+                    continue;
+                }
+                let manager = self.clone();
+                let name = source.name().to_string();
+                let interval = Duration::from_secs(source.config.sync_minutes * 60);
+                syncs.spawn(async move {
+                    loop {
+                        tokio::time::sleep(interval).await;
+                        // Failures are logged and recorded by sync
+                        let _ = manager.sync(&name).await;
+                    }
+                });
+            }
+            while syncs.join_next().await.is_some() {}
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        knowledge::DocumentFilter,
+        test_utils::{self, FixtureRepo},
+    };
+
+    fn repo(name: &str, url: &Path, directories: &[&str], globs: &[&str]) -> RepoConfig {
+        RepoConfig {
+            name: name.to_string(),
+            url: url.to_string_lossy().to_string(),
+            branch: default_branch(),
+            directories: directories.iter().map(|d| d.to_string()).collect(),
+            globs: globs.iter().map(|g| g.to_string()).collect(),
+            topics: HashMap::from([("vrf".to_string(), "randomness".to_string())]),
+            sync_minutes: 0,
+        }
+    }
+
+    async fn document_count(
+        knowledge: &KnowledgeBase<test_utils::FakeEmbeddingModel>,
+        source: &str,
+    ) -> usize {
+        knowledge
+            .matching_documents(&DocumentFilter {
+                source_id: Some(source.to_string()),
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .len()
+    }
+
+    #[tokio::test]
+    async fn test_repos_sync_independently() {
+        let dir = tempfile::tempdir().unwrap();
+        let (docs, sdk) = (
+            FixtureRepo::init(&dir.path().join("remotes/docs")),
+            FixtureRepo::init(&dir.path().join("remotes/sdk")),
+        );
+        let docs_head = docs.commit(
+            &[
+                ("src/pages/vrf/fees.md", "# Fees\n\nRequests cost 0.01 ETH."),
+                ("src/pages/intro.mdx", "# Intro\n\nWelcome."),
+                ("src/pages/logo.svg", "<svg/>"),
+                ("README.md", "# Docs site"),
+            ],
+            &[],
+        );
+        let sdk_head = sdk.commit(
+            &[
+                ("reference/session.md", "# Session\n\nKeys last 7 days."),
+                ("reference/account.md", "# Account"),
+            ],
+            &[],
+        );
+
+        let knowledge = test_utils::knowledge_base().await;
+        let config = KnowledgeSourceConfig {
+            repos: vec![
+                repo(
+                    "docs",
+                    docs.path(),
+                    &["src/pages"],
+                    &["**/*.md", "**/*.mdx"],
+                ),
+                repo("sdk", sdk.path(), &[], &[]),
+            ],
+        };
+        let manager = SourceManager::new(knowledge.clone(), &config, dir.path().join("clones"));
+
+        let results = manager.sync_all().await;
+        let reports = results
+            .into_iter()
+            .map(|(name, result)| (name, result.unwrap()))
+            .collect::<HashMap<_, _>>();
+        assert!(reports["docs"].full);
+        assert_eq!(reports["docs"].summary.added, 2);
+        assert_eq!(reports["sdk"].summary.added, 2);
+        assert_eq!(document_count(&knowledge, "docs").await, 2);
+        assert_eq!(document_count(&knowledge, "sdk").await, 2);
+
+        // Tagged with the repository, and topics from its directories
+        let fees = knowledge
+            .document_versions("docs:src/pages/vrf/fees.md")
+            .await
+            .unwrap();
+        assert_eq!(fees.len(), 1);
+        let details = knowledge
+            .document_details(&fees[0].document_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(details.document.source_id, "docs");
+        assert_eq!(details.document.topics, ["randomness"]);
+
+        let cursor = |name: &'static str| {
+            let knowledge = knowledge.clone();
+            async move {
+                knowledge
+                    .source_state(name)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .last_commit
+                    .unwrap()
+            }
+        };
+        assert_eq!(cursor("docs").await, docs_head);
+        assert_eq!(cursor("sdk").await, sdk_head);
+
+        // Only the SDK changed, and only its changed files are read
+        let sdk_next = sdk.commit(
+            &[
+                ("reference/session.md", "# Session\n\nKeys last 30 days."),
+                ("reference/paymaster.md", "# Paymaster"),
+            ],
+            &["reference/account.md"],
+        );
+        let reports = manager
+            .sync_all()
+            .await
+            .into_iter()
+            .map(|(name, result)| (name, result.unwrap()))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(reports["docs"].summary, RefreshSummary::default());
+        assert!(!reports["docs"].full);
+        assert_eq!(
+            reports["sdk"].summary,
+            RefreshSummary {
+                added: 1,
+                updated: 1,
+                unchanged: 0,
+                renamed: 0
+            }
+        );
+        assert_eq!(reports["sdk"].removed, 1);
+        assert_eq!(cursor("docs").await, docs_head);
+        assert_eq!(cursor("sdk").await, sdk_next);
+    }
+
+    #[tokio::test]
+    async fn test_moved_files_keep_their_documents() {
+        let dir = tempfile::tempdir().unwrap();
+        let docs = FixtureRepo::init(&dir.path().join("remotes/docs"));
+        let (vrf, session, paymaster) = (
+            "# VRF\n\nRandomness for onchain games.",
+            "# Session\n\nKeys last 7 days.",
+            "# Paymaster\n\nSponsors transaction fees.",
+        );
+        docs.commit(
+            &[
+                ("guides/vrf.md", vrf),
+                ("guides/session.md", session),
+                ("guides/paymaster.md", paymaster),
+            ],
+            &[],
+        );
+
+        let knowledge = test_utils::knowledge_base().await;
+        let config = KnowledgeSourceConfig {
+            repos: vec![repo("docs", docs.path(), &[], &[])],
+        };
+        let manager = SourceManager::new(knowledge.clone(), &config, dir.path().join("clones"));
+        assert_eq!(manager.sync("docs").await.unwrap().summary.added, 3);
+        let vrf_id = knowledge
+            .document_versions("docs:guides/vrf.md")
+            .await
+            .unwrap()
+            .remove(0)
+            .document_id;
+        let calls = knowledge.embedding_service().metrics().calls();
+
+        // The guides move to a new directory, one of them with new front matter
+        docs.commit(
+            &[
+                ("reference/vrf.md", vrf),
+                (
+                    "reference/session.md",
+                    &format!("---\nsidebar_position: 2\n---\n{session}"),
+                ),
+                ("reference/paymaster.md", paymaster),
+            ],
+            &["guides/vrf.md", "guides/session.md", "guides/paymaster.md"],
+        );
+        let report = manager.sync("docs").await.unwrap();
+        assert_eq!(report.summary.to_string(), "0 added, 0 updated, 3 renamed");
+        assert_eq!(report.removed, 3);
+        assert_eq!(knowledge.embedding_service().metrics().calls(), calls);
+        assert_eq!(document_count(&knowledge, "docs").await, 3);
+
+        // Citations of the old id find the page where it is now
+        let versions = knowledge
+            .document_versions("docs:reference/vrf.md")
+            .await
+            .unwrap();
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].document_id, vrf_id);
+        let resolved = knowledge.resolve_document(&vrf_id).await.unwrap().unwrap();
+        assert_eq!(resolved.path, "docs:reference/vrf.md");
+        assert!(knowledge
+            .document_versions("docs:guides/vrf.md")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failing_repo_does_not_block_others() {
+        let dir = tempfile::tempdir().unwrap();
+        let docs = FixtureRepo::init(&dir.path().join("remotes/docs"));
+        let docs_head = docs.commit(&[("guide.md", "# Guide")], &[]);
+
+        let knowledge = test_utils::knowledge_base().await;
+        let config = KnowledgeSourceConfig {
+            repos: vec![
+                repo("broken", &dir.path().join("remotes/missing"), &[], &[]),
+                repo("docs", docs.path(), &[], &[]),
+            ],
+        };
+        let manager = SourceManager::new(knowledge.clone(), &config, dir.path().join("clones"));
+
+        let results = manager.sync_all().await;
+        assert!(results[0].1.is_err());
+        assert_eq!(results[1].1.as_ref().unwrap().summary.added, 1);
+
+        let broken = knowledge.source_state("broken").await.unwrap().unwrap();
+        assert_eq!(broken.last_commit, None);
+        assert!(broken.last_error.is_some());
+        let docs = knowledge.source_state("docs").await.unwrap().unwrap();
+        assert_eq!(docs.last_commit.unwrap(), docs_head);
+        assert_eq!(docs.last_error, None);
+
+        // Each repository can be refreshed by name
+        let registry = manager.register(RefreshRegistry::new(knowledge.clone()));
+        assert_eq!(registry.names(), ["broken", "docs"]);
+        let summary = registry.refresh("docs").await.unwrap();
+        assert_eq!(summary.unchanged, 1);
+        assert!(matches!(
+            manager.sync("examples").await,
+            Err(SourceError::UnknownSource(_))
+        ));
+    }
+
+    #[test]
+    fn test_config() {
+        let config: KnowledgeSourceConfig = toml::from_str(
+            r#"
+            [[repos]]
+            name = "docs"
+            url = "https://github.com/cartridge-gg/docs.git"
+            globs = ["**/*.md"]
+
+            [[repos]]
+            name = "sdk"
+            url = "https://github.com/cartridge-gg/controller"
+            branch = "next"
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.repos[0].branch, "main");
+        assert_eq!(config.repos[0].sync_minutes, 60);
+        assert_eq!(
+            config.repos[0].file_url("src/pages/vrf.md").unwrap(),
+            "https://github.com/cartridge-gg/docs/blob/main/src/pages/vrf.md"
+        );
+
+        let manager_source = RepoSource::new(config.repos[0].clone(), Path::new("/tmp"));
+        assert!(manager_source.includes(Path::new("README.md")));
+        assert!(manager_source.includes(Path::new("src/pages/vrf.md")));
+        assert!(!manager_source.includes(Path::new("src/pages/logo.svg")));
+
+        for invalid in [
+            "[[repos]]\nname = \"Docs\"\nurl = \"https://github.com/a/b\"",
+            "[[repos]]\nname = \"docs\"\nurl = \"docs\"",
+            "[[repos]]\nname = \"docs\"\nurl = \"https://github.com/a/b\"\nglobs = [\"[\"]",
+            "[[repos]]\nname = \"docs\"\nurl = \"https://github.com/a/b\"\ndirectories = [\"../etc\"]",
+            "[[repos]]\nname = \"docs\"\nurl = \"https://github.com/a/b\"\n[[repos]]\nname = \"docs\"\nurl = \"https://github.com/a/c\"",
+        ] {
+            let config: KnowledgeSourceConfig = toml::from_str(invalid).unwrap();
+            assert!(config.validate().is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,431 @@
+//! Startup sequencing, so the bot doesn't answer from an empty knowledge
+//! base while the repositories are first ingested.
+//!
+//! Clients connect straight away, but until the initial ingestion completes
+//! the agent's [Readiness] is warming: attention runs as usual, and replies
+//! that would draw on the docs are the [WARMING](crate::templates::WARMING)
+//! template instead, see [Agent::warming_notice]. Brief acknowledgements are
+//! still generated. [warm_up] runs the ingestion, logs its progress and
+//! posts it to the operator, editing one message in place, then marks the
+//! agent ready. Health checks and tests can await the transition with
+//! [Readiness::wait_ready]. With [Readiness::with_status], warming is also
+//! published as [Condition::Warming].
+//!
+//! ```toml
+//! [startup]
+//! discord_channel = "1234567896"
+//! progress_secs = 15
+//! ```
+//!
+//! [Agent::warming_notice]: crate::agent::Agent::warming_notice
+
+use std::{
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use tokio::{
+    sync::watch,
+    time::{Instant, MissedTickBehavior},
+};
+use tracing::{info, warn};
+
+use crate::{
+    knowledge::{IngestEvent, IngestHook},
+    status::{Condition, StatusBoard},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StartupState {
+    /// The initial ingestion is still running.
+    Warming,
+    Ready,
+}
+
+/// Whether the knowledge base is loaded, shared by everything that answers
+/// from it. Clones observe the same state.
+#[derive(Clone, Debug)]
+pub struct Readiness {
+    state: Arc<watch::Sender<StartupState>>,
+    status: Option<StatusBoard>,
+}
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Self::ready()
+    }
+}
+
+impl Readiness {
+    /// Waiting for the initial ingestion, until [Readiness::mark_ready].
+    pub fn warming() -> Self {
+        Self {
+            state: Arc::new(watch::channel(StartupState::Warming).0),
+            status: None,
+        }
+    }
+
+    /// Ready from the start, for agents whose knowledge is loaded up front.
+    pub fn ready() -> Self {
+        Self {
+            state: Arc::new(watch::channel(StartupState::Ready).0),
+            status: None,
+        }
+    }
+
+    /// Publishes warming to `status` until ready.
+    pub fn with_status(mut self, status: StatusBoard) -> Self {
+        status.set(Condition::Warming, !self.is_ready());
+        self.status = Some(status);
+        self
+    }
+
+    pub fn state(&self) -> StartupState {
+        *self.state.borrow()
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.state() == StartupState::Ready
+    }
+
+    pub fn mark_ready(&self) {
+        self.state.send_replace(StartupState::Ready);
+        if let Some(status) = &self.status {
+            status.set(Condition::Warming, false);
+        }
+    }
+
+    /// Receives every change of state.
+    pub fn subscribe(&self) -> watch::Receiver<StartupState> {
+        self.state.subscribe()
+    }
+
+    /// Returns once ready, straight away if already.
+    pub async fn wait_ready(&self) {
+        let mut state = self.subscribe();
+        // The sender lives as long as self, so this can't fail
+        let _ = state.wait_for(|state| *state == StartupState::Ready).await;
+    }
+}
+
+/// `[startup]` settings of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StartupConfig {
+    /// Discord channel ingestion progress is posted to. Progress is only
+    /// logged without it.
+    pub discord_channel: Option<String>,
+    /// Seconds between progress updates.
+    pub progress_secs: u64,
+}
+
+impl Default for StartupConfig {
+    fn default() -> Self {
+        Self {
+            discord_channel: None,
+            progress_secs: 15,
+        }
+    }
+}
+
+impl StartupConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(channel) = &self.discord_channel {
+            if channel.parse::<u64>().is_err() {
+                return Err(format!(
+                    "startup.discord_channel: {channel:?} is not a channel id"
+                ));
+            }
+        }
+        if self.progress_secs == 0 {
+            return Err("startup.progress_secs must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Documents of the initial ingestion so far, counted from [IngestEvent]s.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IngestProgress {
+    pub queued: usize,
+    pub stored: usize,
+    pub failed: usize,
+}
+
+impl IngestProgress {
+    pub fn observe(&mut self, event: &IngestEvent) {
+        match event {
+            IngestEvent::BatchQueued { size, .. } => self.queued += size,
+            IngestEvent::BatchStored { size } => self.stored += size,
+            IngestEvent::BatchFailed { size, .. } => self.failed += size,
+        }
+    }
+
+    /// Documents queued but neither stored nor failed yet.
+    pub fn in_progress(&self) -> usize {
+        self.queued.saturating_sub(self.stored + self.failed)
+    }
+}
+
+impl fmt::Display for IngestProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} documents stored, {} failed, {} in progress",
+            self.stored,
+            self.failed,
+            self.in_progress()
+        )
+    }
+}
+
+/// Where ingestion progress is posted.
+#[async_trait]
+pub trait ProgressSink: Send + Sync {
+    /// Posts the first update, returning the id later updates edit.
+    async fn post(&self, text: &str) -> Result<String, String>;
+
+    /// Replaces the update `id` with `text`.
+    async fn edit(&self, id: &str, text: &str) -> Result<(), String>;
+}
+
+/// The one message progress is edited into. An update that can't be edited,
+/// say the message was deleted, is posted anew.
+struct ProgressMessage {
+    sink: Option<Arc<dyn ProgressSink>>,
+    id: Option<String>,
+}
+
+impl ProgressMessage {
+    async fn update(&mut self, text: &str) {
+        let Some(sink) = &self.sink else {
+            return;
+        };
+        if let Some(id) = &self.id {
+            match sink.edit(id, text).await {
+                Ok(()) => return,
+                Err(err) => warn!(%err, "Failed to edit ingestion progress, posting it again"),
+            }
+        }
+        match sink.post(text).await {
+            Ok(id) => self.id = Some(id),
+            Err(err) => warn!(%err, "Failed to post ingestion progress"),
+        }
+    }
+}
+
+/// Runs `ingestion`, the initial load of the knowledge base, then marks
+/// `readiness` ready. `ingestion` is given the hook to report its
+/// [IngestEvent]s to; progress is logged and posted to `sink` every
+/// [StartupConfig::progress_secs] and once more when done.
+pub async fn warm_up<F, Fut, T>(
+    readiness: &Readiness,
+    config: &StartupConfig,
+    sink: Option<Arc<dyn ProgressSink>>,
+    ingestion: F,
+) -> T
+where
+    F: FnOnce(IngestHook) -> Fut,
+    Fut: Future<Output = T>,
+{
+    let progress = Arc::new(Mutex::new(IngestProgress::default()));
+    let hook: IngestHook = {
+        let progress = progress.clone();
+        Arc::new(move |event: &IngestEvent| progress.lock().unwrap().observe(event))
+    };
+    let mut message = ProgressMessage { sink, id: None };
+    let started = Instant::now();
+
+    let ingestion = ingestion(hook);
+    tokio::pin!(ingestion);
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.progress_secs));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let output = loop {
+        tokio::select! {
+            output = &mut ingestion => break output,
+            _ = ticker.tick() => {
+                let progress = *progress.lock().unwrap();
+                info!(
+                    stored = progress.stored,
+                    failed = progress.failed,
+                    in_progress = progress.in_progress(),
+                    "Loading knowledge"
+                );
+                message.update(&format!("Loading knowledge: {progress}")).await;
+            }
+        }
+    };
+
+    let progress = *progress.lock().unwrap();
+    let elapsed = started.elapsed().as_secs();
+    info!(
+        stored = progress.stored,
+        failed = progress.failed,
+        elapsed,
+        "Knowledge loaded, ready to answer"
+    );
+    message
+        .update(&format!(
+            "Knowledge loaded in {elapsed}s: {} documents stored, {} failed. Ready to answer.",
+            progress.stored, progress.failed
+        ))
+        .await;
+    readiness.mark_ready();
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agent::Agent,
+        attention::ResponseMode,
+        knowledge::{ChannelType, Message, Source},
+        templates::{self, Templates},
+        test_utils::{self, ScriptedCompletionModel},
+    };
+    use tokio::sync::oneshot;
+
+    #[derive(Default)]
+    struct RecordingSink {
+        /// `(id edited, text)`, `None` for posts.
+        updates: Mutex<Vec<(Option<String>, String)>>,
+    }
+
+    #[async_trait]
+    impl ProgressSink for RecordingSink {
+        async fn post(&self, text: &str) -> Result<String, String> {
+            let mut updates = self.updates.lock().unwrap();
+            updates.push((None, text.to_string()));
+            Ok(format!("m{}", updates.len()))
+        }
+
+        async fn edit(&self, id: &str, text: &str) -> Result<(), String> {
+            self.updates
+                .lock()
+                .unwrap()
+                .push((Some(id.to_string()), text.to_string()));
+            Ok(())
+        }
+    }
+
+    fn question() -> Message {
+        Message {
+            id: "1".to_string(),
+            source: Source::Discord,
+            source_id: "alice".to_string(),
+            channel_type: ChannelType::Text,
+            channel_id: "c1".to_string(),
+            account_id: "alice".to_string(),
+            role: "user".to_string(),
+            content: "How do sessions work?".to_string(),
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_warming_until_ingestion_completes() {
+        let character = test_utils::character();
+        let readiness = Readiness::warming();
+        let agent = Agent::new(
+            character,
+            ScriptedCompletionModel::new([]),
+            test_utils::knowledge_base().await,
+        )
+        .with_readiness(readiness.clone());
+        let sink = Arc::new(RecordingSink::default());
+        let config = StartupConfig::default();
+        let (finish, finished) = oneshot::channel();
+
+        let mut state = readiness.subscribe();
+        let task = tokio::spawn({
+            let readiness = readiness.clone();
+            let sink = sink.clone();
+            async move {
+                warm_up(&readiness, &config, Some(sink), |hook| async move {
+                    hook(&IngestEvent::BatchQueued {
+                        size: 32,
+                        buffered: 32,
+                    });
+                    hook(&IngestEvent::BatchStored { size: 32 });
+                    hook(&IngestEvent::BatchQueued {
+                        size: 10,
+                        buffered: 10,
+                    });
+                    finished.await.unwrap();
+                    hook(&IngestEvent::BatchStored { size: 10 });
+                    "synced"
+                })
+                .await
+            }
+        });
+        tokio::time::sleep(Duration::from_secs(20)).await;
+
+        // Knowledge questions get the warming notice, greetings are answered
+        assert_eq!(readiness.state(), StartupState::Warming);
+        let warming = Templates::default().render(templates::WARMING, &[]);
+        assert_eq!(
+            agent
+                .warming_notice(&ResponseMode::FullAnswer, &question())
+                .await,
+            Some(warming)
+        );
+        assert_eq!(
+            agent
+                .warming_notice(&ResponseMode::BriefAck, &question())
+                .await,
+            None
+        );
+
+        // Progress is posted once, then edited in place
+        {
+            let updates = sink.updates.lock().unwrap();
+            assert_eq!(updates.len(), 2);
+            assert_eq!(updates[0].0, None);
+            assert_eq!(updates[1].0.as_deref(), Some("m1"));
+            assert_eq!(
+                updates[1].1,
+                "Loading knowledge: 32 documents stored, 0 failed, 10 in progress"
+            );
+        }
+
+        finish.send(()).unwrap();
+        assert_eq!(task.await.unwrap(), "synced");
+        state.changed().await.unwrap();
+        assert_eq!(*state.borrow(), StartupState::Ready);
+        readiness.wait_ready().await;
+        assert_eq!(
+            agent
+                .warming_notice(&ResponseMode::FullAnswer, &question())
+                .await,
+            None
+        );
+        let updates = sink.updates.lock().unwrap();
+        assert_eq!(updates.last().unwrap().0.as_deref(), Some("m1"));
+        let done = &updates.last().unwrap().1;
+        assert!(done.starts_with("Knowledge loaded in "), "{done}");
+        assert!(done.contains("42 documents stored, 0 failed"), "{done}");
+    }
+
+    #[test]
+    fn test_progress() {
+        let mut progress = IngestProgress::default();
+        progress.observe(&IngestEvent::BatchQueued {
+            size: 5,
+            buffered: 5,
+        });
+        progress.observe(&IngestEvent::BatchFailed {
+            size: 2,
+            error: "rate limited".to_string(),
+        });
+        assert_eq!(progress.in_progress(), 3);
+        assert_eq!(
+            progress.to_string(),
+            "0 documents stored, 2 failed, 3 in progress"
+        );
+    }
+}
@@ -0,0 +1,135 @@
+//! What the bot is going through right now, published by the components that
+//! notice it and shown to users, e.g. as the Discord
+//! [presence](crate::clients::presence).
+//!
+//! Components hold a clone of one [StatusBoard] and raise or clear their
+//! [Condition] on it: the [startup](crate::startup) sequence while the
+//! knowledge base is warming, the embedding circuit breaker while it is
+//! open, [outage](crate::outage) notices while the completion provider is
+//! rate limiting the bot. Subscribers get the whole [Status] on every
+//! change.
+
+use std::{collections::BTreeSet, fmt, sync::Arc};
+
+use tokio::sync::watch;
+use tracing::info;
+
+/// A notable state, most pressing last.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Condition {
+    /// The initial ingestion is running, see [crate::startup].
+    Warming,
+    /// The embedding provider is failing, so answers may lack the docs.
+    Degraded,
+    /// The completion provider is rate limiting the bot or down, so answers
+    /// are slow or deferred.
+    RateLimited,
+    /// The completion budget is spent until it resets.
+    OverBudget,
+}
+
+impl Condition {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Condition::Warming => "warming",
+            Condition::Degraded => "degraded",
+            Condition::RateLimited => "rate_limited",
+            Condition::OverBudget => "over_budget",
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The conditions currently raised, none when healthy.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Status {
+    conditions: BTreeSet<Condition>,
+}
+
+impl Status {
+    pub fn is_healthy(&self) -> bool {
+        self.conditions.is_empty()
+    }
+
+    pub fn has(&self, condition: Condition) -> bool {
+        self.conditions.contains(&condition)
+    }
+
+    /// The most pressing condition, the one to show when only one fits.
+    pub fn headline(&self) -> Option<Condition> {
+        self.conditions.last().copied()
+    }
+}
+
+/// Where conditions are published. Clones publish to the same subscribers.
+#[derive(Clone, Debug)]
+pub struct StatusBoard {
+    status: Arc<watch::Sender<Status>>,
+}
+
+impl Default for StatusBoard {
+    fn default() -> Self {
+        Self {
+            status: Arc::new(watch::channel(Status::default()).0),
+        }
+    }
+}
+
+impl StatusBoard {
+    /// Raises `condition`, or clears it when not `active`. Subscribers are
+    /// only woken when that changes the status.
+    pub fn set(&self, condition: Condition, active: bool) {
+        self.status.send_if_modified(|status| {
+            let changed = if active {
+                status.conditions.insert(condition)
+            } else {
+                status.conditions.remove(&condition)
+            };
+            if changed {
+                info!(%condition, active, "Status changed");
+            }
+            changed
+        });
+    }
+
+    pub fn status(&self) -> Status {
+        self.status.borrow().clone()
+    }
+
+    /// Receives every change of status.
+    pub fn subscribe(&self) -> watch::Receiver<Status> {
+        self.status.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_headline_is_most_pressing() {
+        let board = StatusBoard::default();
+        let mut updates = board.subscribe();
+        assert_eq!(board.status().headline(), None);
+
+        board.set(Condition::Warming, true);
+        board.set(Condition::Degraded, true);
+        assert_eq!(board.status().headline(), Some(Condition::Degraded));
+        assert!(updates.has_changed().unwrap());
+        updates.mark_unchanged();
+
+        // Raising a raised condition doesn't wake subscribers
+        board.set(Condition::Warming, true);
+        assert!(!updates.has_changed().unwrap());
+
+        board.set(Condition::Degraded, false);
+        assert_eq!(board.status().headline(), Some(Condition::Warming));
+        board.set(Condition::Warming, false);
+        assert!(board.status().is_healthy());
+    }
+}
@@ -0,0 +1,140 @@
+use rig::completion::{Chat, Message, PromptError};
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+use tracing::{debug, warn};
+
+#[derive(Error, Debug)]
+pub enum StructuredError {
+    #[error("Prompt error: {0}")]
+    PromptError(#[from] PromptError),
+    #[error("Model output did not match the schema: {error}")]
+    InvalidOutput { error: String, raw: String },
+}
+
+/// Instruction appended to the prompt describing the expected JSON.
+pub fn schema_instruction<T: JsonSchema>() -> String {
+    let schema = schemars::schema_for!(T);
+    format!(
+        "Respond with only a JSON value, without any other text, that matches this JSON schema:\n{}",
+        serde_json::to_string_pretty(&schema).unwrap_or_default()
+    )
+}
+
+/// Parses model output as JSON, tolerating markdown code fences and prose
+/// around the value.
+pub fn parse_json<T: DeserializeOwned>(raw: &str) -> Result<T, serde_json::Error> {
+    let text = raw.trim();
+    let text = match text.strip_prefix("```") {
+        Some(fenced) => {
+            // Drop the language tag line, then the closing fence.
+            let body = fenced.split_once('\n').map_or("", |(_, body)| body);
+            body.trim_end().trim_end_matches("```")
+        }
+        None => text,
+    };
+
+    serde_json::from_str(text).or_else(|err| {
+        let start = text.find(['{', '[']);
+        let end = text.rfind(['}', ']']);
+        match (start, end) {
+            (Some(start), Some(end)) if start < end => serde_json::from_str(&text[start..=end]),
+            _ => Err(err),
+        }
+    })
+}
+
+/// Prompts for a value of type `T`. When the first answer does not parse, the
+/// model is asked once more with the validation error before giving up.
+pub async fn prompt_structured<T, C>(chat: &C, input: &str) -> Result<T, StructuredError>
+where
+    T: DeserializeOwned + JsonSchema,
+    C: Chat,
+{
+    let prompt = format!("{}\n\n{}", input, schema_instruction::<T>());
+    let raw = chat.chat(&prompt, vec![]).await?;
+
+    let error = match parse_json(&raw) {
+        Ok(value) => return Ok(value),
+        Err(err) => err.to_string(),
+    };
+    warn!(error, "Structured output did not parse, retrying");
+    debug!(raw, "Invalid structured output");
+
+    let history = vec![
+        Message {
+            role: "user".to_string(),
+            content: prompt,
+        },
+        Message {
+            role: "assistant".to_string(),
+            content: raw,
+        },
+    ];
+    let retry = format!(
+        "Your previous reply was not valid: {}. Reply again with only the corrected JSON.",
+        error
+    );
+    let raw = chat.chat(&retry, history).await?;
+
+    parse_json(&raw).map_err(|err| StructuredError::InvalidOutput {
+        error: err.to_string(),
+        raw,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::ScriptedCompletionModel;
+    use rig::agent::AgentBuilder;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, JsonSchema, PartialEq)]
+    struct Answer {
+        answer: String,
+        confidence: f64,
+    }
+
+    #[test]
+    fn test_parse_json_repairs_wrapping() {
+        let expected = Answer {
+            answer: "sepolia".to_string(),
+            confidence: 0.9,
+        };
+        let fenced = "```json\n{\"answer\": \"sepolia\", \"confidence\": 0.9}\n```";
+        assert_eq!(parse_json::<Answer>(fenced).unwrap(), expected);
+
+        let prose = "Sure! {\"answer\": \"sepolia\", \"confidence\": 0.9} Hope that helps.";
+        assert_eq!(parse_json::<Answer>(prose).unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_retries_with_validation_error() {
+        let model = ScriptedCompletionModel::new([
+            "{\"answer\": \"sepolia\"}",
+            "{\"answer\": \"sepolia\", \"confidence\": 0.8}",
+        ]);
+        let agent = AgentBuilder::new(model.clone()).build();
+
+        let answer: Answer = prompt_structured(&agent, "Which testnet?").await.unwrap();
+        assert_eq!(answer.confidence, 0.8);
+
+        let requests = model.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].prompt.contains("\"confidence\""));
+        assert!(requests[1].prompt.contains("missing field `confidence`"));
+        assert_eq!(requests[1].chat_history.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_one_retry() {
+        let model = ScriptedCompletionModel::new(["not json", "still not json"]);
+        let agent = AgentBuilder::new(model).build();
+
+        match prompt_structured::<Answer, _>(&agent, "Which testnet?").await {
+            Err(StructuredError::InvalidOutput { raw, .. }) => assert_eq!(raw, "still not json"),
+            other => panic!("unexpected result: {other:?}"),
+        }
+    }
+}
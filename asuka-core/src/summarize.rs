@@ -0,0 +1,369 @@
+//! On-demand summaries of what happened in a channel, for people catching up.
+
+use rig::{
+    completion::{CompletionModel, Prompt},
+    embeddings::EmbeddingModel,
+};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tracing::{debug, error, info};
+
+use crate::{
+    agent::Agent,
+    escape,
+    knowledge::{Document, Message},
+    templates,
+};
+
+#[derive(Clone, Debug)]
+pub struct SummarizeConfig {
+    /// Newest messages summarized at most; older ones in the window are
+    /// left out with a notice.
+    pub max_messages: usize,
+    /// Transcript characters per model call. Longer windows are summarized
+    /// in chunks whose summaries are then merged.
+    pub chunk_chars: usize,
+    /// Stores each summary as a document of the channel, so later questions
+    /// can retrieve it.
+    pub store: bool,
+}
+
+impl Default for SummarizeConfig {
+    fn default() -> Self {
+        Self {
+            max_messages: 500,
+            chunk_chars: 12_000,
+            store: false,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+pub struct ChannelSummary {
+    pub key_topics: Vec<String>,
+    pub decisions: Vec<String>,
+    pub unanswered_questions: Vec<String>,
+}
+
+impl ChannelSummary {
+    fn render(&self, character: &crate::character::Character, hours: i64, notice: &str) -> String {
+        fn list(items: &[String]) -> String {
+            if items.is_empty() {
+                return "- None".to_string();
+            }
+            items
+                .iter()
+                .map(|item| format!("- {item}"))
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+
+        character.template(
+            templates::SUMMARY,
+            &[
+                ("hours", &hours.to_string()),
+                ("topics", &list(&self.key_topics)),
+                ("decisions", &list(&self.decisions)),
+                ("questions", &list(&self.unanswered_questions)),
+                ("notice", notice),
+            ],
+        )
+    }
+}
+
+/// Splits messages into transcript chunks of about `budget` characters. A
+/// message is never split, so an oversized one gets a chunk of its own.
+pub fn chunk_transcript(messages: &[Message], budget: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut chunk = String::new();
+
+    for message in messages {
+        let line = format!(
+            "[{}] {}: {}\n",
+            message.created_at.format("%Y-%m-%d %H:%M"),
+            escape::inline(&message.account_id),
+            escape::inline(&message.content)
+        );
+        if !chunk.is_empty() && chunk.len() + line.len() > budget {
+            chunks.push(std::mem::take(&mut chunk));
+        }
+        chunk.push_str(&line);
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+
+    chunks
+}
+
+pub struct Summarizer<M: CompletionModel, E: EmbeddingModel + 'static> {
+    agent: Agent<M, E>,
+    config: SummarizeConfig,
+}
+
+impl<M: CompletionModel, E: EmbeddingModel + 'static> Summarizer<M, E> {
+    pub fn new(agent: Agent<M, E>) -> Self {
+        Self {
+            agent,
+            config: SummarizeConfig::default(),
+        }
+    }
+
+    pub fn with_config(mut self, config: SummarizeConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Summarizes the last `hours` of a channel into a reply. Failures are
+    /// logged and answered with the generic error template.
+    pub async fn summarize(&self, channel_id: &str, hours: i64) -> String {
+        let character = &self.agent.character;
+        let to = self.agent.clock().now_utc();
+        let from = to - chrono::Duration::hours(hours);
+
+        let window = match self
+            .agent
+            .knowledge()
+            .messages_between(channel_id, from, to, self.config.max_messages)
+            .await
+        {
+            Ok(window) => window,
+            Err(err) => {
+                error!(?err, channel_id, "Failed to load messages to summarize");
+                return character.template(templates::ERROR_GENERIC, &[]);
+            }
+        };
+
+        if window.messages.len() < 2 {
+            return character.template(
+                templates::SUMMARY_QUIET,
+                &[
+                    ("hours", &hours.to_string()),
+                    ("count", &window.messages.len().to_string()),
+                ],
+            );
+        }
+
+        let summary = match self.map_reduce(&window.messages).await {
+            Ok(summary) => summary,
+            Err(err) => {
+                error!(%err, channel_id, "Failed to summarize channel");
+                return character.template(templates::ERROR_GENERIC, &[]);
+            }
+        };
+
+        let notice = if window.truncated > 0 {
+            format!(
+                "\n\nOnly the latest {} messages were summarized, {} older ones were left out.",
+                window.messages.len(),
+                window.truncated
+            )
+        } else {
+            String::new()
+        };
+        let reply = summary.render(character, hours, &notice);
+
+        if self.config.store {
+            self.store(channel_id, &reply, to).await;
+        }
+        reply
+    }
+
+    /// Summarizes each chunk on its own, then merges the chunk summaries into
+    /// the final structure. A single chunk goes straight to the merge.
+    async fn map_reduce(&self, messages: &[Message]) -> Result<ChannelSummary, String> {
+        let chunks = chunk_transcript(messages, self.config.chunk_chars);
+        debug!(
+            messages = messages.len(),
+            chunks = chunks.len(),
+            "Summarizing"
+        );
+
+        let parts = if chunks.len() == 1 {
+            chunks
+        } else {
+            let agent = self.agent.builder().build();
+            let mut parts = Vec::with_capacity(chunks.len());
+            for (i, chunk) in chunks.iter().enumerate() {
+                let prompt = format!(
+                    "Summarize part {} of {} of a chat conversation. Keep topics, decisions \
+                    and questions that were left unanswered.\n\n{chunk}",
+                    i + 1,
+                    chunks.len()
+                );
+                parts.push(
+                    agent
+                        .prompt(prompt.as_str())
+                        .await
+                        .map_err(|e| e.to_string())?,
+                );
+            }
+            parts
+        };
+
+        let input = format!(
+            "Summarize this chat conversation for someone who missed it: its key topics, \
+            decisions made and questions still unanswered.\n\n{}",
+            parts.join("\n\n---\n\n")
+        );
+        self.agent
+            .prompt_structured(&input, &[])
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn store(&self, channel_id: &str, summary: &str, at: chrono::DateTime<chrono::Utc>) {
+        let document = Document {
+            id: format!("summary:{channel_id}:{}", at.timestamp()),
+            source_id: channel_id.to_string(),
+            content: summary.to_string(),
+            created_at: at,
+            title: String::new(),
+            section: String::new(),
+            topics: vec!["summary".to_string()],
+            logical_id: None,
+            cleaned: None,
+            chunk: None,
+        };
+        match self
+            .agent
+            .knowledge()
+            .clone()
+            .add_documents([document])
+            .await
+        {
+            Ok(_) => info!(channel_id, "Stored channel summary"),
+            Err(err) => error!(?err, channel_id, "Failed to store channel summary"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        knowledge::{ChannelType, Source},
+        test_utils::{self, FakeEmbeddingModel, ScriptedCompletionModel},
+    };
+
+    const SUMMARY_JSON: &str = r#"{"key_topics": ["vrf fees"], "decisions": ["ship v2 friday"], "unanswered_questions": ["testnet date?"]}"#;
+
+    async fn summarizer(
+        replies: &[&str],
+        config: SummarizeConfig,
+        messages: usize,
+    ) -> (
+        Summarizer<ScriptedCompletionModel, FakeEmbeddingModel>,
+        ScriptedCompletionModel,
+    ) {
+        let knowledge = test_utils::knowledge_base().await;
+        let now = chrono::Utc::now();
+        for i in 0..messages {
+            knowledge
+                .create_message(Message {
+                    id: format!("m{i}"),
+                    source: Source::Discord,
+                    source_id: "u1".to_string(),
+                    channel_type: ChannelType::Text,
+                    channel_id: "c1".to_string(),
+                    account_id: "u1".to_string(),
+                    role: "user".to_string(),
+                    content: format!("message number {i} about vrf"),
+                    created_at: now - chrono::Duration::minutes((messages - i) as i64),
+                })
+                .await
+                .unwrap();
+        }
+
+        let model = ScriptedCompletionModel::new(replies.iter().copied());
+        let character = test_utils::character();
+        let agent = Agent::new(character, model.clone(), knowledge);
+        (Summarizer::new(agent).with_config(config), model)
+    }
+
+    #[test]
+    fn test_chunk_transcript() {
+        let message = |content: &str| Message {
+            id: "m".to_string(),
+            source: Source::Discord,
+            source_id: "u1".to_string(),
+            channel_type: ChannelType::Text,
+            channel_id: "c1".to_string(),
+            account_id: "u1".to_string(),
+            role: "user".to_string(),
+            content: content.to_string(),
+            created_at: chrono::Utc::now(),
+        };
+        let messages = [message("a"), message("b"), message(&"c".repeat(100))];
+
+        let chunks = chunk_transcript(&messages, 60);
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[0].contains("u1: a\n") && chunks[0].contains("u1: b\n"));
+        assert!(chunks[1].len() > 100);
+    }
+
+    #[tokio::test]
+    async fn test_map_reduce_over_chunks() {
+        let config = SummarizeConfig {
+            chunk_chars: 120,
+            ..Default::default()
+        };
+        let (summarizer, model) =
+            summarizer(&["part one", "part two", SUMMARY_JSON], config, 4).await;
+
+        let reply = summarizer.summarize("c1", 24).await;
+        assert!(reply.contains("- vrf fees"));
+        assert!(reply.contains("- ship v2 friday"));
+        assert!(reply.contains("- testnet date?"));
+
+        let requests = model.requests();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[0].prompt.contains("part 1 of 2"));
+        assert!(requests[0].prompt.contains("message number 0"));
+        assert!(requests[1].prompt.contains("message number 3"));
+        let merge = &requests[2].prompt;
+        assert!(merge.contains("part one\n\n---\n\npart two"));
+        assert!(!merge.contains("message number"));
+    }
+
+    #[tokio::test]
+    async fn test_cap_keeps_newest_messages() {
+        let config = SummarizeConfig {
+            max_messages: 2,
+            store: true,
+            ..Default::default()
+        };
+        let (summarizer, model) = summarizer(&[SUMMARY_JSON], config, 5).await;
+
+        let reply = summarizer.summarize("c1", 24).await;
+        assert!(reply
+            .contains("Only the latest 2 messages were summarized, 3 older ones were left out."));
+
+        // A single chunk skips the map step.
+        let requests = model.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].prompt.contains("message number 3"));
+        assert!(requests[0].prompt.contains("message number 4"));
+        assert!(!requests[0].prompt.contains("message number 2"));
+
+        let documents = summarizer
+            .agent
+            .knowledge()
+            .list_documents(None, 10)
+            .await
+            .unwrap();
+        assert_eq!(documents.items.len(), 1);
+        assert_eq!(documents.items[0].source_id, "c1");
+    }
+
+    #[tokio::test]
+    async fn test_quiet_window_short_circuits() {
+        let (summarizer, model) = summarizer(&[], SummarizeConfig::default(), 1).await;
+
+        let reply = summarizer.summarize("c1", 24).await;
+        assert!(reply.contains("1 message"));
+        let reply = summarizer.summarize("c2", 24).await;
+        assert!(reply.contains("0 message"));
+        assert!(model.requests().is_empty());
+    }
+}
@@ -0,0 +1,379 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{debug, warn};
+
+pub const ERROR_GENERIC: &str = "error_generic";
+pub const RATE_LIMITED: &str = "rate_limited";
+pub const CONFIRM_ACTION: &str = "confirm_action";
+pub const MUTED_ACK: &str = "muted_ack";
+pub const OVER_BUDGET: &str = "over_budget";
+pub const SUMMARY: &str = "summary";
+pub const SUMMARY_QUIET: &str = "summary_quiet";
+pub const LOW_CONFIDENCE_HEDGE: &str = "low_confidence_hedge";
+pub const LOW_CONFIDENCE_DECLINE: &str = "low_confidence_decline";
+pub const ESCALATION_NOTICE: &str = "escalation_notice";
+pub const ESCALATION_ACK: &str = "escalation_ack";
+pub const ESCALATION_PENDING: &str = "escalation_pending";
+pub const DIGEST: &str = "digest";
+pub const PREAMBLE_REFUSAL: &str = "preamble_refusal";
+pub const CORRECTION: &str = "correction";
+pub const CORRECTION_ACK: &str = "correction_ack";
+pub const WARMING: &str = "warming";
+pub const FAMILIAR_USER: &str = "familiar_user";
+pub const FAMILIAR_CHANNEL: &str = "familiar_channel";
+pub const NEW_USER: &str = "new_user";
+pub const RESEARCH_STOPPED: &str = "research_stopped";
+pub const RESEARCH_PARTIAL: &str = "research_partial";
+pub const RESEARCH_SOURCES: &str = "research_sources";
+pub const PROVIDER_SLOW: &str = "provider_slow";
+pub const PROVIDER_DEFERRED: &str = "provider_deferred";
+pub const PROVIDER_FOLLOW_UP: &str = "provider_follow_up";
+
+/// Built-in templates used when a character does not override them.
+const DEFAULTS: &[(&str, &str)] = &[
+    (
+        ERROR_GENERIC,
+        "Sorry, something went wrong while answering that. Please try again in a moment.",
+    ),
+    (
+        RATE_LIMITED,
+        "I've answered a lot of your questions recently, give others a turn and try again in {{retry_after}}.",
+    ),
+    (
+        CONFIRM_ACTION,
+        "Please confirm: {{action}}. Run `{{command}}` to proceed.",
+    ),
+    (MUTED_ACK, "Understood, I'll stay quiet here."),
+    (
+        OVER_BUDGET,
+        "I've used up my budget for now. Please try again later.",
+    ),
+    (
+        SUMMARY,
+        "Here's what happened in the last {{hours}} hours.\n\nKey topics:\n{{topics}}\n\nDecisions:\n{{decisions}}\n\nUnanswered questions:\n{{questions}}{{notice}}",
+    ),
+    (
+        SUMMARY_QUIET,
+        "Not much to catch up on, there was {{count}} message(s) here in the last {{hours}} hours.",
+    ),
+    (
+        LOW_CONFIDENCE_HEDGE,
+        "I couldn't find this in the docs, but here's my best understanding:",
+    ),
+    (
+        LOW_CONFIDENCE_DECLINE,
+        "I couldn't find this in the docs and would rather not guess. {{suggestion}}",
+    ),
+    (
+        ESCALATION_NOTICE,
+        "{{mention}} {{user}} needs help ({{reason}}): {{link}} (escalation #{{id}})",
+    ),
+    (
+        ESCALATION_ACK,
+        "I've asked the team to take a look, someone will follow up here.",
+    ),
+    (
+        ESCALATION_PENDING,
+        "The team has already been notified and will be with you soon.",
+    ),
+    (
+        DIGEST,
+        "Digest for {{day}}\n\nMessages: {{messages}}\nResponses sent: {{responses}}\nUnanswered: {{unanswered}}\nTool calls: {{tools}}\nErrors: {{errors}}\nEscalations: {{escalations}}\nPurged by retention: {{purged}}\nIgnored while rate limited: {{rate_limited}}\n\nTop knowledge gaps:\n{{gaps}}",
+    ),
+    (
+        PREAMBLE_REFUSAL,
+        "I can't share how I'm set up, but I'm happy to help with your question.",
+    ),
+    (CORRECTION, "✏️ Correction: {{correction}}"),
+    (
+        CORRECTION_ACK,
+        "Thanks for catching that, I've corrected my answer above.",
+    ),
+    (
+        WARMING,
+        "I'm still loading my knowledge, ask me again in a few minutes.",
+    ),
+    (
+        FAMILIAR_USER,
+        "You have spoken with this user {{count}} times, most recently {{last}}. Do not introduce yourself or explain what you are, answer directly.",
+    ),
+    (
+        FAMILIAR_CHANNEL,
+        "You have answered {{count}} times in this channel, so people here already know you. Do not introduce yourself or explain what you are, answer directly.",
+    ),
+    (
+        NEW_USER,
+        "You have not spoken with this user before. A one-sentence introduction of who you are is welcome before your answer.",
+    ),
+    (
+        RESEARCH_STOPPED,
+        "I couldn't finish researching this, {{reason}}. Try asking a narrower question.",
+    ),
+    (
+        RESEARCH_PARTIAL,
+        "(I stopped researching early, {{reason}}, so this may be incomplete.)",
+    ),
+    (RESEARCH_SOURCES, "Sources:\n{{sources}}"),
+    (
+        PROVIDER_SLOW,
+        "I'm being rate-limited right now, answers may be slow for a few minutes.",
+    ),
+    (
+        PROVIDER_DEFERRED,
+        "I'm being rate-limited right now and can't answer yet. I'll answer here as soon as I can.",
+    ),
+    (
+        PROVIDER_FOLLOW_UP,
+        "Sorry for the wait, here's my answer to \"{{question}}\":",
+    ),
+];
+
+#[derive(Error, Debug)]
+pub enum TemplateError {
+    #[error("Unknown template `{name}`, valid templates are: {valid}")]
+    UnknownTemplate { name: String, valid: String },
+}
+
+/// Per-character template overrides, loaded from the `[templates]` table of the
+/// character TOML. Lookups fall back to the built-in defaults.
+///
+/// A template given as a table holds per-language texts by ISO 639-1 code,
+/// see [crate::language], and keeps the built-in default otherwise.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(
+    try_from = "HashMap<String, TemplateText>",
+    into = "HashMap<String, TemplateText>"
+)]
+pub struct Templates {
+    overrides: HashMap<String, String>,
+    localized: HashMap<String, HashMap<String, String>>,
+}
+
+/// A `[templates]` entry: the text, or the text per language.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum TemplateText {
+    Text(String),
+    Localized(HashMap<String, String>),
+}
+
+impl Templates {
+    pub fn names() -> impl Iterator<Item = &'static str> {
+        DEFAULTS.iter().map(|(name, _)| *name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.overrides.get(name).map(String::as_str).or_else(|| {
+            DEFAULTS
+                .iter()
+                .find(|(default, _)| *default == name)
+                .map(|(_, text)| *text)
+        })
+    }
+
+    /// The character's text of `name` in `language`, without falling back.
+    pub fn localized(&self, name: &str, language: &str) -> Option<&str> {
+        self.localized
+            .get(name)
+            .and_then(|texts| texts.get(language))
+            .map(String::as_str)
+    }
+
+    /// Template `name` with `vars` in. Values, often user content, are put
+    /// in as written and never read as placeholders, see [interpolate].
+    pub fn render(&self, name: &str, vars: &[(&str, &str)]) -> String {
+        match self.get(name) {
+            Some(template) => interpolate(template, vars),
+            None => {
+                warn!(name = name, "Requested unknown template");
+                String::new()
+            }
+        }
+    }
+}
+
+impl TryFrom<HashMap<String, TemplateText>> for Templates {
+    type Error = TemplateError;
+
+    fn try_from(entries: HashMap<String, TemplateText>) -> Result<Self, Self::Error> {
+        if let Some(name) = entries
+            .keys()
+            .find(|name| !Self::names().any(|valid| valid == name.as_str()))
+        {
+            return Err(TemplateError::UnknownTemplate {
+                name: name.clone(),
+                valid: Self::names().collect::<Vec<_>>().join(", "),
+            });
+        }
+
+        let mut templates = Self::default();
+        for (name, text) in entries {
+            match text {
+                TemplateText::Text(text) => {
+                    templates.overrides.insert(name, text);
+                }
+                TemplateText::Localized(texts) => {
+                    templates.localized.insert(name, texts);
+                }
+            }
+        }
+        Ok(templates)
+    }
+}
+
+impl From<Templates> for HashMap<String, TemplateText> {
+    fn from(templates: Templates) -> Self {
+        templates
+            .overrides
+            .into_iter()
+            .map(|(name, text)| (name, TemplateText::Text(text)))
+            .chain(
+                templates
+                    .localized
+                    .into_iter()
+                    .map(|(name, texts)| (name, TemplateText::Localized(texts))),
+            )
+            .collect()
+    }
+}
+
+/// Replaces `{{var}}` placeholders in a single pass. Substituted values are never
+/// re-scanned, and placeholders without a matching variable render as empty.
+pub fn interpolate(template: &str, vars: &[(&str, &str)]) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+
+        match after.find("}}") {
+            Some(end) => {
+                let name = after[..end].trim();
+                match vars.iter().find(|(var, _)| *var == name) {
+                    Some((_, value)) => output.push_str(value),
+                    None => debug!(name = name, "Missing template variable"),
+                }
+                rest = &after[end + 2..];
+            }
+            None => {
+                output.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+
+    output.push_str(rest);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{language::placeholders, test_utils};
+
+    #[test]
+    fn test_override_takes_precedence() {
+        let templates: Templates = toml::from_str(
+            r#"
+            muted_ack = "Fine, going dark."
+            confirm_action = "Sure you want to {{action}}? Then `{{command}}`."
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(templates.render(MUTED_ACK, &[]), "Fine, going dark.");
+        assert_eq!(
+            templates.render(
+                CONFIRM_ACTION,
+                &[
+                    ("action", "erase user 42"),
+                    ("command", "/erase-user 42 --confirm")
+                ]
+            ),
+            "Sure you want to erase user 42? Then `/erase-user 42 --confirm`."
+        );
+        assert_eq!(
+            templates.render(ERROR_GENERIC, &[]),
+            Templates::default().render(ERROR_GENERIC, &[])
+        );
+    }
+
+    #[test]
+    fn test_unknown_template_is_rejected() {
+        let err = toml::from_str::<Templates>(r#"error_genric = "oops""#).unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("error_genric"));
+        assert!(message.contains(ERROR_GENERIC));
+        assert!(message.contains(OVER_BUDGET));
+    }
+
+    #[test]
+    fn test_localized_templates() {
+        let templates: Templates = toml::from_str(
+            r#"
+            muted_ack = "Fine, going dark."
+
+            [rate_limited]
+            es = "Vuelve a intentarlo en {{retry_after}}."
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            templates.localized(RATE_LIMITED, "es"),
+            Some("Vuelve a intentarlo en {{retry_after}}.")
+        );
+        assert_eq!(templates.localized(RATE_LIMITED, "fr"), None);
+        assert_eq!(templates.localized(MUTED_ACK, "es"), None);
+        // The default text stays the built-in one
+        assert_eq!(
+            templates.get(RATE_LIMITED),
+            Templates::default().get(RATE_LIMITED)
+        );
+    }
+
+    #[test]
+    fn test_interpolate_variables() {
+        let text = interpolate("Try again in {{ retry_after }}.", &[("retry_after", "5m")]);
+        assert_eq!(text, "Try again in 5m.");
+    }
+
+    #[test]
+    fn test_interpolate_missing_variable() {
+        assert_eq!(interpolate("Confirm: {{action}}!", &[]), "Confirm: !");
+        assert_eq!(interpolate("Unclosed {{action", &[]), "Unclosed {{action");
+    }
+
+    #[test]
+    fn test_interpolate_does_not_expand_values() {
+        let text = interpolate("{{a}}", &[("a", "{{b}}"), ("b", "nope")]);
+        assert_eq!(text, "{{b}}");
+    }
+
+    #[test]
+    fn test_user_content_is_never_expanded() {
+        const SECRET: &str = "not for users";
+        let templates = Templates::default();
+        for text in test_utils::adversarial_strings() {
+            for name in Templates::names() {
+                let template = templates.get(name).unwrap();
+                let used = placeholders(template).count();
+                // Every variable of the template is user content, and the
+                // variables it doesn't use must not leak through it
+                let mut vars = placeholders(template)
+                    .map(|var| (var, text.as_str()))
+                    .collect::<Vec<_>>();
+                vars.extend(["retry_after", "name", "x"].map(|var| (var, SECRET)));
+
+                let rendered = templates.render(name, &vars);
+                assert!(!rendered.contains(SECRET), "{name} expanded {text:?}");
+                assert!(rendered.len() <= template.len() + used * text.len());
+            }
+            // Malformed templates render without their placeholders
+            assert!(interpolate(&text, &[]).len() <= text.len());
+        }
+    }
+}
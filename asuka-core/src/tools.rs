@@ -0,0 +1,333 @@
+//! Execution limits for the tools an agent calls while answering, so a tool
+//! waiting on a slow API cannot hold up the reply.
+//!
+//! A [ToolGuard] is created per interaction and wraps each tool registered on
+//! the completion agent. Wrapped tools give up after their timeout, stop when
+//! the client shuts down, and share a bounded number of concurrent runs. A
+//! timed out tool answers with a message the model can act on rather than
+//! failing the whole completion.
+//!
+//! ```toml
+//! [tools]
+//! timeout_secs = 10
+//! max_concurrent = 4
+//! timeouts = { ekubo_quote = 20 }
+//! ```
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use rig::{completion::ToolDefinition, tool::Tool};
+use serde::Deserialize;
+use serde_json::Value;
+use thiserror::Error;
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+/// `[tools]` settings of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ToolConfig {
+    /// Timeout of tools without an entry in `timeouts`.
+    pub timeout_secs: u64,
+    /// Timeouts by tool name.
+    pub timeouts: HashMap<String, u64>,
+    /// Tools running at once within one interaction.
+    pub max_concurrent: usize,
+}
+
+impl Default for ToolConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 10,
+            timeouts: HashMap::new(),
+            max_concurrent: 4,
+        }
+    }
+}
+
+impl ToolConfig {
+    pub fn timeout(&self, tool_name: &str) -> Duration {
+        Duration::from_secs(
+            self.timeouts
+                .get(tool_name)
+                .copied()
+                .unwrap_or(self.timeout_secs),
+        )
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.timeout_secs == 0 || self.timeouts.values().any(|secs| *secs == 0) {
+            return Err("tools timeouts must be at least 1 second".to_string());
+        }
+        if self.max_concurrent == 0 {
+            return Err("tools.max_concurrent must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum GuardedToolError<E: std::error::Error> {
+    #[error("Tool {0} was cancelled")]
+    Cancelled(String),
+    #[error("Tool {0} panicked")]
+    Panicked(String),
+    #[error("Invalid tool output: {0}")]
+    Output(serde_json::Error),
+    #[error(transparent)]
+    Tool(E),
+}
+
+/// Limits shared by the tools of one interaction.
+#[derive(Clone)]
+pub struct ToolGuard {
+    config: ToolConfig,
+    permits: Arc<Semaphore>,
+    shutdown: CancellationToken,
+}
+
+impl ToolGuard {
+    /// Tools wrapped by the guard stop when `shutdown` is cancelled.
+    pub fn new(config: ToolConfig, shutdown: CancellationToken) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(config.max_concurrent.max(1))),
+            config,
+            shutdown,
+        }
+    }
+
+    pub fn wrap<T: Tool>(&self, tool: T) -> GuardedTool<T> {
+        GuardedTool {
+            timeout: self.config.timeout(&tool.name()),
+            tool: Arc::new(tool),
+            permits: self.permits.clone(),
+            shutdown: self.shutdown.clone(),
+        }
+    }
+}
+
+/// Prompt context pointing the model at `tool` when it is the one the
+/// attention hinted at with [ResponseMode::ToolLikely](crate::attention::ResponseMode).
+/// Names match ignoring case, spaces, dashes and underscores.
+pub async fn emphasis<T: Tool>(tool: &T, hint: &str) -> Option<String> {
+    let normalize = |name: &str| {
+        name.chars()
+            .filter(|c| c.is_alphanumeric())
+            .flat_map(char::to_lowercase)
+            .collect::<String>()
+    };
+    if normalize(&tool.name()) != normalize(hint) {
+        return None;
+    }
+
+    let definition = tool.definition(String::new()).await;
+    Some(format!(
+        "This request likely needs the {} tool: {}",
+        definition.name, definition.description
+    ))
+}
+
+/// Tool run under the limits of a [ToolGuard]. Time spent waiting for a
+/// free slot counts toward the timeout.
+pub struct GuardedTool<T: Tool> {
+    tool: Arc<T>,
+    timeout: Duration,
+    permits: Arc<Semaphore>,
+    shutdown: CancellationToken,
+}
+
+impl<T: Tool + 'static> Tool for GuardedTool<T>
+where
+    T::Args: 'static,
+    T::Output: Send,
+{
+    const NAME: &'static str = T::NAME;
+
+    type Error = GuardedToolError<T::Error>;
+    type Args = T::Args;
+    type Output = Value;
+
+    fn name(&self) -> String {
+        self.tool.name()
+    }
+
+    async fn definition(&self, prompt: String) -> ToolDefinition {
+        self.tool.definition(prompt).await
+    }
+
+    async fn call(&self, args: Self::Args) -> Result<Value, Self::Error> {
+        let tool = self.tool.clone();
+        let permits = self.permits.clone();
+        let shutdown = self.shutdown.clone();
+        let limit = self.timeout;
+        let name = tool.name();
+
+        // The limits run on a task of their own, so the future returned here
+        // only holds the handle and stays Sync whatever the tool's captures.
+        let task = tokio::spawn(async move {
+            let run = async {
+                let _permit = permits.acquire_owned().await;
+                tool.call(args).await
+            };
+            tokio::select! {
+                _ = shutdown.cancelled() => Err(GuardedToolError::Cancelled(tool.name())),
+                result = tokio::time::timeout(limit, run) => match result {
+                    Ok(Ok(output)) => serde_json::to_value(output).map_err(GuardedToolError::Output),
+                    Ok(Err(err)) => Err(GuardedToolError::Tool(err)),
+                    Err(_) => {
+                        warn!(tool = tool.name(), ?limit, "Tool timed out");
+                        Ok(Value::String(format!(
+                            "tool {} timed out after {limit:?}",
+                            tool.name()
+                        )))
+                    }
+                },
+            }
+        });
+        task.await
+            .unwrap_or_else(|_| Err(GuardedToolError::Panicked(name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{
+        clients::recorded_tool::RecordedTool,
+        test_utils::{self, ScriptedCompletionModel},
+    };
+    use rig::{agent::AgentBuilder, completion::Prompt};
+    use serde_json::json;
+
+    #[derive(Error, Debug)]
+    #[error("never")]
+    struct Never;
+
+    /// Quote tool whose API never answers.
+    struct Hang;
+
+    impl Tool for Hang {
+        const NAME: &'static str = "ekubo_quote";
+
+        type Error = Never;
+        type Args = Value;
+        type Output = String;
+
+        async fn definition(&self, _prompt: String) -> ToolDefinition {
+            ToolDefinition {
+                name: Self::NAME.to_string(),
+                description: "Quotes a swap".to_string(),
+                parameters: json!({"type": "object", "properties": {}}),
+            }
+        }
+
+        async fn call(&self, _args: Value) -> Result<String, Never> {
+            std::future::pending().await
+        }
+    }
+
+    /// Sleeps a second, tracking how many calls run at once.
+    #[derive(Default)]
+    struct Slow {
+        active: AtomicUsize,
+        peak: AtomicUsize,
+    }
+
+    impl Tool for Slow {
+        const NAME: &'static str = "slow";
+
+        type Error = Never;
+        type Args = Value;
+        type Output = ();
+
+        async fn definition(&self, _prompt: String) -> ToolDefinition {
+            Hang.definition(String::new()).await
+        }
+
+        async fn call(&self, _args: Value) -> Result<(), Never> {
+            let active = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(active, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            self.active.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timeout_reaches_the_model() {
+        let knowledge = test_utils::knowledge_base().await;
+        let interaction_id = knowledge
+            .create_interaction(
+                "c1".to_string(),
+                "alice".to_string(),
+                vec!["m1".to_string()],
+            )
+            .await
+            .unwrap();
+        let guard = ToolGuard::new(ToolConfig::default(), CancellationToken::new());
+        let model = ScriptedCompletionModel::default().then_tool_call("ekubo_quote", json!({}));
+        let agent = AgentBuilder::new(model)
+            .tool(RecordedTool::new(
+                guard.wrap(Hang),
+                knowledge.clone(),
+                interaction_id,
+            ))
+            .build();
+
+        let response = agent.prompt("quote 1 ETH to USDC").await.unwrap();
+        assert_eq!(response, "\"tool ekubo_quote timed out after 10s\"");
+
+        let calls = knowledge.tool_calls(interaction_id).await.unwrap();
+        assert_eq!(
+            calls[0].output,
+            Ok("\"tool ekubo_quote timed out after 10s\"".to_string())
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_per_tool_timeout_and_shutdown() {
+        let shutdown = CancellationToken::new();
+        let config = ToolConfig {
+            timeouts: HashMap::from([("ekubo_quote".to_string(), 3)]),
+            ..Default::default()
+        };
+        let guard = ToolGuard::new(config, shutdown.clone());
+        let tool = guard.wrap(Hang);
+
+        let output = tool.call(json!({})).await.unwrap();
+        assert_eq!(output, json!("tool ekubo_quote timed out after 3s"));
+
+        shutdown.cancel();
+        assert!(matches!(
+            tool.call(json!({})).await,
+            Err(GuardedToolError::Cancelled(name)) if name == "ekubo_quote"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_emphasis_matches_hinted_tool() {
+        let tool = ToolGuard::new(ToolConfig::default(), CancellationToken::new()).wrap(Hang);
+
+        assert_eq!(
+            emphasis(&tool, "Ekubo Quote").await.as_deref(),
+            Some("This request likely needs the ekubo_quote tool: Quotes a swap")
+        );
+        assert_eq!(emphasis(&tool, "post_tweet").await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_concurrent_runs_are_bounded() {
+        let config = ToolConfig {
+            max_concurrent: 2,
+            ..Default::default()
+        };
+        let tool = ToolGuard::new(config, CancellationToken::new()).wrap(Slow::default());
+
+        let results = futures::future::join_all((0..5).map(|_| tool.call(json!({})))).await;
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(tool.tool.peak.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,265 @@
+//! Cutting a retrieved document that doesn't fit the prompt down to its part
+//! most relevant to the question, instead of sending all of it or none.
+//!
+//! The prompt is budgeted at [TruncationConfig::prompt_tokens]. A document
+//! that doesn't fit what is left is split at paragraphs, and sentences of
+//! paragraphs too long on their own, see [segments]. Each piece is scored
+//! against the query, see
+//! [KnowledgeBase::score_segments](crate::knowledge::KnowledgeBase::score_segments),
+//! and the best one is kept along with its neighbours while they fit, see
+//! [focus]. Markers show where text was left out, and a note tells the model
+//! the document was truncated. With less than
+//! [TruncationConfig::min_document_tokens] left, the document is dropped.
+//! Each truncation is recorded with the interaction, see
+//! [KnowledgeBase::record_truncation](crate::knowledge::KnowledgeBase::record_truncation).
+//!
+//! ```toml
+//! [truncation]
+//! prompt_tokens = 6000
+//! min_document_tokens = 150
+//! ```
+
+use std::ops::Range;
+
+use serde::Deserialize;
+
+/// Marks text left out of a truncated document.
+pub const ELLIPSIS: &str = "[…]";
+
+/// Ends a truncated document.
+pub const TRUNCATION_NOTE: &str =
+    "(This document was truncated to its part most relevant to the question.)";
+
+/// Splits paragraphs.
+const PARAGRAPH_BREAKS: &[&str] = &["\n\n"];
+
+/// Ends sentences, and lines of paragraphs without punctuation such as lists.
+const SENTENCE_ENDS: &[&str] = &[". ", "! ", "? ", ".\n", "!\n", "?\n", "\n"];
+
+/// `[truncation]` section of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TruncationConfig {
+    /// Estimated tokens the prompt may reach with its documents.
+    pub prompt_tokens: usize,
+    /// Fewest tokens left for a document to be sent truncated rather than
+    /// dropped.
+    pub min_document_tokens: usize,
+}
+
+impl Default for TruncationConfig {
+    fn default() -> Self {
+        Self {
+            prompt_tokens: 6000,
+            min_document_tokens: 150,
+        }
+    }
+}
+
+impl TruncationConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min_document_tokens == 0 {
+            return Err("truncation.min_document_tokens must be positive".to_string());
+        }
+        if self.prompt_tokens < self.min_document_tokens {
+            return Err(
+                "truncation.prompt_tokens must be at least min_document_tokens".to_string(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Byte ranges of the paragraphs of `text`, with the ones over `max_chars`
+/// split into sentences. The ranges cover `text` in order, separators
+/// included, except for leading blank lines.
+pub fn segments(text: &str, max_chars: usize) -> Vec<Range<usize>> {
+    let mut segments = Vec::new();
+    for paragraph in split(text, 0..text.len(), PARAGRAPH_BREAKS) {
+        if text[paragraph.clone()].chars().count() <= max_chars {
+            segments.push(paragraph);
+        } else {
+            segments.extend(split(text, paragraph, SENTENCE_ENDS));
+        }
+    }
+    segments
+}
+
+/// `range` of `text` cut after each of `separators`. Blank pieces join the
+/// piece before them.
+fn split(text: &str, range: Range<usize>, separators: &[&str]) -> Vec<Range<usize>> {
+    let mut pieces = Vec::new();
+    let mut start = range.start;
+    let mut i = range.start;
+    while i < range.end {
+        let rest = &text[i..range.end];
+        match separators
+            .iter()
+            .find(|separator| rest.starts_with(**separator))
+        {
+            Some(separator) => {
+                i += separator.len();
+                push(&mut pieces, text, start..i);
+                start = i;
+            }
+            None => i += rest.chars().next().map_or(1, char::len_utf8),
+        }
+    }
+    push(&mut pieces, text, start..range.end);
+    pieces
+}
+
+/// Adds `piece` to `pieces`, or to the last of them when it is blank.
+fn push(pieces: &mut Vec<Range<usize>>, text: &str, piece: Range<usize>) {
+    if !text[piece.clone()].trim().is_empty() {
+        pieces.push(piece);
+    } else if let Some(last) = pieces.last_mut() {
+        last.end = piece.end;
+    }
+}
+
+/// The byte range of `text` to keep: the best scoring of `segments`, grown
+/// one neighbour at a time towards the better scoring side while the whole
+/// stays within `max_chars`. A best segment over `max_chars` on its own is
+/// cut at its end.
+pub fn focus(
+    text: &str,
+    segments: &[Range<usize>],
+    scores: &[f64],
+    max_chars: usize,
+) -> Range<usize> {
+    let Some(best) = scores
+        .iter()
+        .enumerate()
+        // The first of equal scores
+        .max_by(|(i, a), (j, b)| a.total_cmp(b).then(j.cmp(i)))
+        .map(|(i, _)| i)
+    else {
+        return 0..0;
+    };
+    let chars = |range: Range<usize>| text[range].chars().count();
+    let segment = segments[best].clone();
+    if chars(segment.clone()) > max_chars {
+        let end = text[segment.clone()]
+            .char_indices()
+            .nth(max_chars)
+            .map_or(segment.end, |(i, _)| segment.start + i);
+        return segment.start..end;
+    }
+
+    let (mut first, mut last) = (best, best);
+    loop {
+        let before = first.checked_sub(1);
+        let after = Some(last + 1).filter(|after| *after < segments.len());
+        let next = match (before, after) {
+            (Some(before), Some(after)) if scores[before] >= scores[after] => before,
+            (_, Some(after)) => after,
+            (Some(before), None) => before,
+            (None, None) => break,
+        };
+        let (grown_first, grown_last) = (first.min(next), last.max(next));
+        if chars(segments[grown_first].start..segments[grown_last].end) > max_chars {
+            break;
+        }
+        (first, last) = (grown_first, grown_last);
+    }
+    segments[first].start..segments[last].end
+}
+
+/// `kept` of `text` as sent, with [ELLIPSIS] where text was left out and
+/// the [TRUNCATION_NOTE].
+pub fn render(text: &str, kept: Range<usize>) -> String {
+    let mut out = String::new();
+    if kept.start > 0 {
+        out.push_str(ELLIPSIS);
+        out.push_str("\n\n");
+    }
+    out.push_str(text[kept.clone()].trim());
+    if kept.end < text.len() {
+        out.push_str("\n\n");
+        out.push_str(ELLIPSIS);
+    }
+    out.push_str("\n\n");
+    out.push_str(TRUNCATION_NOTE);
+    out
+}
+
+/// Characters of a document's text that fit `tokens` once [render] added
+/// its markers and note, see
+/// [estimate_tokens](crate::history::estimate_tokens).
+pub fn max_chars(tokens: usize) -> usize {
+    let markers = 2 * (ELLIPSIS.chars().count() + 2) + 2 + TRUNCATION_NOTE.chars().count();
+    (tokens * 4).saturating_sub(markers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::estimate_tokens;
+
+    const TEXT: &str = "Intro paragraph.\n\nFees are paid in STRK. The paymaster covers them. \
+                        Sessions skip prompts.\n\nClosing words.";
+
+    #[test]
+    fn test_long_paragraphs_split_into_sentences() {
+        let pieces = segments(TEXT, 40)
+            .into_iter()
+            .map(|range| &TEXT[range])
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pieces,
+            [
+                "Intro paragraph.\n\n",
+                "Fees are paid in STRK. ",
+                "The paymaster covers them. ",
+                "Sessions skip prompts.\n\n",
+                "Closing words."
+            ]
+        );
+        // Pieces cover the text in order
+        assert_eq!(pieces.concat(), TEXT);
+        assert_eq!(segments(TEXT, 1000).len(), 3);
+        assert!(segments("\n\n  \n\n", 10).is_empty());
+    }
+
+    #[test]
+    fn test_focus_grows_towards_better_neighbours() {
+        let pieces = segments(TEXT, 40);
+        let scores = [0.1, 0.5, 0.9, 0.2, 0.0];
+
+        let kept = focus(TEXT, &pieces, &scores, 30);
+        assert_eq!(&TEXT[kept], "The paymaster covers them. ");
+        let kept = focus(TEXT, &pieces, &scores, 60);
+        assert_eq!(
+            &TEXT[kept],
+            "Fees are paid in STRK. The paymaster covers them. "
+        );
+        assert_eq!(focus(TEXT, &pieces, &scores, 1000), 0..TEXT.len());
+        // Cut when the best piece alone is too long
+        assert_eq!(&TEXT[focus(TEXT, &pieces, &scores, 7)], "The pay");
+        // The start without scores to go by
+        assert_eq!(focus(TEXT, &pieces, &[0.0; 5], 20), pieces[0]);
+    }
+
+    #[test]
+    fn test_render_marks_cuts() {
+        let kept = focus(TEXT, &segments(TEXT, 40), &[0.0, 1.0, 0.0, 0.0, 0.0], 25);
+        assert_eq!(
+            render(TEXT, kept),
+            format!("{ELLIPSIS}\n\nFees are paid in STRK.\n\n{ELLIPSIS}\n\n{TRUNCATION_NOTE}")
+        );
+        let start = 0..TEXT.find("\n\n").unwrap();
+        assert!(render(TEXT, start).starts_with("Intro paragraph.\n\n[…]"));
+
+        // Whatever is kept within max_chars fits the tokens
+        let tokens = 40;
+        let kept = focus(
+            TEXT,
+            &segments(TEXT, 40),
+            &[0.0, 1.0, 0.0, 0.0, 0.0],
+            max_chars(tokens),
+        );
+        assert!(kept != (0..TEXT.len()));
+        assert!(estimate_tokens(&render(TEXT, kept)) <= tokens);
+    }
+}
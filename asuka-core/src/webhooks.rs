@@ -0,0 +1,470 @@
+//! Posts [events](crate::events) to webhooks, so an ops dashboard is told
+//! about escalations, low confidence answers and the like as they happen
+//! instead of polling the database.
+//!
+//! Each `[[webhooks]]` destination gets the events it lists, or all of them,
+//! as a versioned JSON payload:
+//!
+//! ```json
+//! {"version":1,"created_at":"2024-11-05T09:30:00Z","event":"escalation_created","data":{...}}
+//! ```
+//!
+//! signed with the destination's secret in the `X-Asuka-Signature` header,
+//! `sha256=` and the hex HMAC-SHA256 of the body. Failed deliveries are
+//! retried with exponential backoff on network errors, 429 and 5xx
+//! responses; a payload that still fails is written to the dead letters of
+//! the knowledge base. Every destination has its own queue, so a slow one
+//! doesn't hold up the others, and a full queue drops events rather than
+//! holding up the bus.
+//!
+//! `error_burst` events come from the [reporter](crate::reporting), so they
+//! are only posted when `[reporting]` is set as well.
+//!
+//! ```toml
+//! [[webhooks]]
+//! url = "https://ops.example.com/hooks/asuka"
+//! secret = "..."
+//! events = ["escalation_created", "error_burst"]
+//! ```
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use rig::embeddings::EmbeddingModel;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::sync::{broadcast, mpsc};
+use tracing::{debug, error, warn};
+
+use crate::{
+    events::Event,
+    knowledge::{DeadLetter, KnowledgeBase},
+};
+
+pub const SIGNATURE_HEADER: &str = "X-Asuka-Signature";
+
+/// Header naming the event, so receivers can route without parsing.
+pub const EVENT_HEADER: &str = "X-Asuka-Event";
+
+/// Version of the payload, raised when fields are removed or change meaning.
+pub const PAYLOAD_VERSION: u32 = 1;
+
+/// Payloads waiting for a destination. Events beyond it are dropped.
+const QUEUE_SIZE: usize = 256;
+
+/// Longest wait between two attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A destination of `[[webhooks]]`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Key of the payload signature.
+    pub secret: String,
+    /// Names of the events posted, such as `escalation_created`, or every
+    /// event when empty.
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// Attempts at delivering a payload before it is dead-lettered.
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubled for each one after.
+    #[serde(default = "default_backoff_ms")]
+    pub backoff_ms: u64,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_max_attempts() -> u32 {
+    5
+}
+
+fn default_backoff_ms() -> u64 {
+    1000
+}
+
+fn default_timeout_secs() -> u64 {
+    10
+}
+
+impl WebhookConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.url.starts_with("https://") && !self.url.starts_with("http://") {
+            return Err(format!("webhooks.url: {:?} is not a URL", self.url));
+        }
+        if self.secret.is_empty() {
+            return Err(format!("webhooks: {} has no secret", self.url));
+        }
+        if let Some(event) = self
+            .events
+            .iter()
+            .find(|event| !Event::NAMES.contains(&event.as_str()))
+        {
+            return Err(format!(
+                "webhooks.events: {event:?} is not one of {}",
+                Event::NAMES.join(", ")
+            ));
+        }
+        if self.max_attempts == 0 {
+            return Err("webhooks.max_attempts must be positive".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn wants(&self, event: &Event) -> bool {
+        self.events.is_empty() || self.events.iter().any(|name| name == event.name())
+    }
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    version: u32,
+    created_at: DateTime<Utc>,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// The signature header value of `body`: `sha256=` and the hex HMAC-SHA256
+/// of the body keyed with `secret`.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body);
+    let digest = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+    format!("sha256={digest}")
+}
+
+/// An event of one destination's queue.
+struct Delivery {
+    event: &'static str,
+    body: String,
+}
+
+/// Posts the events of a knowledge base's [EventBus](crate::events::EventBus)
+/// to the configured destinations.
+pub struct WebhookDispatcher<E: EmbeddingModel + 'static> {
+    knowledge: KnowledgeBase<E>,
+    destinations: Vec<WebhookConfig>,
+    client: reqwest::Client,
+}
+
+impl<E: EmbeddingModel + 'static> WebhookDispatcher<E> {
+    pub fn new(knowledge: KnowledgeBase<E>, destinations: Vec<WebhookConfig>) -> Self {
+        Self {
+            knowledge,
+            destinations,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Starts delivering events published from now on, until the bus is
+    /// dropped.
+    pub fn spawn(self) -> tokio::task::JoinHandle<()> {
+        let mut events = self.knowledge.events().subscribe();
+        let queues = self
+            .destinations
+            .iter()
+            .map(|destination| {
+                let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+                tokio::spawn(deliver_queued(
+                    self.knowledge.clone(),
+                    self.client.clone(),
+                    destination.clone(),
+                    rx,
+                ));
+                (destination.clone(), tx)
+            })
+            .collect::<Vec<_>>();
+
+        tokio::spawn(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!(missed, "Webhooks fell behind, events were not delivered");
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                if !queues
+                    .iter()
+                    .any(|(destination, _)| destination.wants(&event))
+                {
+                    continue;
+                }
+
+                let payload = Payload {
+                    version: PAYLOAD_VERSION,
+                    created_at: self.knowledge.clock().now_utc(),
+                    event: &event,
+                };
+                let body = match serde_json::to_string(&payload) {
+                    Ok(body) => body,
+                    Err(err) => {
+                        error!(?err, event = event.name(), "Failed to serialize event");
+                        continue;
+                    }
+                };
+                for (destination, queue) in &queues {
+                    if !destination.wants(&event) {
+                        continue;
+                    }
+                    let delivery = Delivery {
+                        event: event.name(),
+                        body: body.clone(),
+                    };
+                    if queue.try_send(delivery).is_err() {
+                        warn!(
+                            url = destination.url,
+                            event = event.name(),
+                            "Webhook queue full, dropping event"
+                        );
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// Delivers a destination's queue in order, dead-lettering payloads that
+/// fail every attempt.
+async fn deliver_queued<E: EmbeddingModel + 'static>(
+    knowledge: KnowledgeBase<E>,
+    client: reqwest::Client,
+    destination: WebhookConfig,
+    mut queue: mpsc::Receiver<Delivery>,
+) {
+    while let Some(delivery) = queue.recv().await {
+        let (attempts, result) = deliver(&client, &destination, &delivery).await;
+        let Err(err) = result else {
+            debug!(
+                url = destination.url,
+                event = delivery.event,
+                attempts,
+                "Delivered webhook"
+            );
+            continue;
+        };
+        warn!(
+            url = destination.url,
+            event = delivery.event,
+            attempts,
+            %err,
+            "Failed to deliver webhook, dead-lettering it"
+        );
+        let letter = DeadLetter {
+            url: destination.url.clone(),
+            event: delivery.event.to_string(),
+            payload: delivery.body,
+            error: err,
+            attempts,
+            created_at: knowledge.clock().now_utc(),
+        };
+        if let Err(err) = knowledge.record_dead_letter(letter).await {
+            error!(?err, "Failed to record webhook dead letter");
+        }
+    }
+}
+
+/// Posts `delivery` until it is accepted, it is rejected for good or the
+/// attempts run out. Returns the attempts made and the last error.
+async fn deliver(
+    client: &reqwest::Client,
+    destination: &WebhookConfig,
+    delivery: &Delivery,
+) -> (u32, Result<(), String>) {
+    let signature = sign(&destination.secret, delivery.body.as_bytes());
+    let mut backoff = Duration::from_millis(destination.backoff_ms);
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let response = client
+            .post(&destination.url)
+            .timeout(Duration::from_secs(destination.timeout_secs))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, &signature)
+            .header(EVENT_HEADER, delivery.event)
+            .body(delivery.body.clone())
+            .send()
+            .await;
+        let (retry, err) = match response {
+            Ok(response) if response.status().is_success() => return (attempt, Ok(())),
+            Ok(response) => {
+                let status = response.status();
+                (
+                    status.is_server_error() || status.as_u16() == 429,
+                    format!("HTTP {status}"),
+                )
+            }
+            Err(err) => (true, err.to_string()),
+        };
+        if !retry || attempt >= destination.max_attempts {
+            return (attempt, Err(err));
+        }
+        debug!(url = destination.url, attempt, %err, "Retrying webhook");
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{self, FakeEmbeddingModel, HttpRequest, MockHttpServer};
+
+    /// Waits for `server` to receive `n` requests and returns them.
+    async fn requests(server: &MockHttpServer, n: usize) -> Vec<HttpRequest> {
+        for _ in 0..500 {
+            if server.requests().len() >= n {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        server.requests()
+    }
+
+    /// Waits for a payload to be dead-lettered and returns the dead letters.
+    async fn dead_letters(knowledge: &KnowledgeBase<FakeEmbeddingModel>) -> Vec<DeadLetter> {
+        for _ in 0..500 {
+            let letters = knowledge.dead_letters(10).await.unwrap();
+            if !letters.is_empty() {
+                return letters;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        Vec::new()
+    }
+
+    fn destination(url: &str, events: &[&str]) -> WebhookConfig {
+        WebhookConfig {
+            url: url.to_string(),
+            secret: "s3cret".to_string(),
+            events: events.iter().map(|event| event.to_string()).collect(),
+            max_attempts: 3,
+            backoff_ms: 10,
+            timeout_secs: 5,
+        }
+    }
+
+    fn escalation() -> Event {
+        Event::EscalationCreated {
+            id: 7,
+            channel_id: "c1".to_string(),
+            account_id: "alice".to_string(),
+            reason: "user_request".to_string(),
+        }
+    }
+
+    fn tool_call() -> Event {
+        Event::ToolExecuted {
+            interaction_id: 3,
+            tool_name: "ekubo_quote".to_string(),
+            duration_ms: 120,
+            success: true,
+        }
+    }
+
+    async fn dispatch(destinations: Vec<WebhookConfig>) -> KnowledgeBase<FakeEmbeddingModel> {
+        let knowledge = test_utils::knowledge_base().await;
+        WebhookDispatcher::new(knowledge.clone(), destinations).spawn();
+        knowledge
+    }
+
+    #[tokio::test]
+    async fn test_payloads_are_signed_and_filtered() {
+        let all = MockHttpServer::start([(200, ""), (200, "")]).await;
+        let escalations = MockHttpServer::start([(200, "")]).await;
+        let knowledge = dispatch(vec![
+            destination(&all.url(), &[]),
+            destination(&escalations.url(), &["escalation_created"]),
+        ])
+        .await;
+
+        knowledge.events().publish(tool_call());
+        knowledge.events().publish(escalation());
+        assert_eq!(requests(&all, 2).await.len(), 2);
+
+        let received = requests(&escalations, 1).await;
+        assert_eq!(received.len(), 1);
+        let request = &received[0];
+        let signature = &request.headers["x-asuka-signature"];
+        assert_eq!(signature, &sign("s3cret", request.body.as_bytes()));
+        assert_ne!(signature, &sign("other", request.body.as_bytes()));
+        assert_eq!(request.headers["x-asuka-event"], "escalation_created");
+        let payload: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(payload["version"], 1);
+        assert_eq!(payload["event"], "escalation_created");
+        assert_eq!(payload["data"]["reason"], "user_request");
+    }
+
+    #[tokio::test]
+    async fn test_server_errors_are_retried() {
+        let server = MockHttpServer::start([(500, ""), (429, ""), (200, "")]).await;
+        let knowledge = dispatch(vec![destination(&server.url(), &[])]).await;
+
+        knowledge.events().publish(escalation());
+        let received = requests(&server, 3).await;
+        assert_eq!(received.len(), 3);
+        // The same payload each time
+        assert!(received
+            .iter()
+            .all(|request| request.body == received[0].body));
+        assert!(knowledge.dead_letters(10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failing_destination_is_dead_lettered() {
+        // Answers 500 once its responses run out
+        let failing = MockHttpServer::start([]).await;
+        let healthy = MockHttpServer::start([(200, "")]).await;
+        let knowledge = dispatch(vec![
+            destination(&failing.url(), &[]),
+            destination(&healthy.url(), &[]),
+        ])
+        .await;
+
+        knowledge.events().publish(escalation());
+        // The healthy destination doesn't wait for the failing one
+        assert_eq!(requests(&healthy, 1).await.len(), 1);
+
+        let letters = dead_letters(&knowledge).await;
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].url, failing.url());
+        assert_eq!(letters[0].event, "escalation_created");
+        assert_eq!(letters[0].attempts, 3);
+        assert_eq!(letters[0].error, "HTTP 500 Internal Server Error");
+        assert_eq!(letters[0].payload, healthy.requests()[0].body);
+        // No more attempts after the last
+        assert_eq!(requests(&failing, 4).await.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_client_errors_are_not_retried() {
+        let server = MockHttpServer::start([(400, "")]).await;
+        let knowledge = dispatch(vec![destination(&server.url(), &[])]).await;
+
+        knowledge.events().publish(escalation());
+        let letters = dead_letters(&knowledge).await;
+        assert_eq!(letters[0].attempts, 1);
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(destination("https://ops.example.com", &["error_burst"])
+            .validate()
+            .is_ok());
+        let err = destination("https://ops.example.com", &["escalation"])
+            .validate()
+            .unwrap_err();
+        assert!(err.contains("\"escalation\" is not one of"), "{err}");
+        assert!(destination("ops.example.com", &[]).validate().is_err());
+    }
+}
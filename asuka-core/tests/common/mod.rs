@@ -0,0 +1,622 @@
+//! The bot's stack wired like a Discord client, against local fakes: a
+//! temp-file SQLite store with sqlite-vec, scripted models and a
+//! [RecordingClient] in place of Discord.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, Once,
+    },
+};
+
+use asuka_core::{
+    agent::Agent,
+    attachments::Attachment,
+    attention::{
+        Assessment, Attention, AttentionConfig, AttentionContext, ReplyRoute, RECENT_REPLIES,
+    },
+    character::Character,
+    clients::{
+        delivery::{DiscordHttp, Outbound, SendError},
+        discord::{amend_message, chunk_message, MAX_MESSAGE_LENGTH, MIN_CHUNK_LENGTH},
+        mentions::MentionPolicy,
+        reactions::ReplyOutcome,
+        recorded_tool::RecordedTool,
+        streaming::ReplySink,
+    },
+    clock::FixedClock,
+    corrections,
+    hooks::{MessageContext, ResponseDraft},
+    injection::InjectionConfig,
+    knowledge::{ChannelType, Document, KnowledgeBase, Message, Source},
+    pipeline::{BatchConfig, Debouncer},
+    templates,
+};
+use async_trait::async_trait;
+use chrono::TimeZone;
+use rig::{
+    completion::{
+        CompletionError, CompletionModel, CompletionRequest, CompletionResponse, ModelChoice,
+        Prompt, ToolDefinition,
+    },
+    embeddings::{Embedding, EmbeddingError, EmbeddingModel},
+    tool::Tool,
+};
+use serde_json::{json, Value};
+use serenity::model::id::{ChannelId, MessageId};
+use tokio_util::sync::CancellationToken;
+
+pub const BOT_ID: &str = "bot";
+
+const FAKE_DIMS: usize = 16;
+
+/// Deterministic embedding model: texts sharing words produce nearby vectors.
+#[derive(Clone, Default)]
+pub struct FakeEmbeddingModel;
+
+fn fake_vector(text: &str) -> Vec<f64> {
+    let mut vec = vec![0.0; FAKE_DIMS];
+    for word in text.split_whitespace() {
+        let word = word.to_lowercase();
+        let bucket = word.bytes().fold(0usize, |acc, b| {
+            acc.wrapping_mul(31).wrapping_add(b as usize)
+        });
+        vec[bucket % FAKE_DIMS] += 1.0;
+    }
+
+    let norm = vec.iter().map(|x| x * x).sum::<f64>().sqrt();
+    if norm > 0.0 {
+        vec.iter_mut().for_each(|x| *x /= norm);
+    }
+    vec
+}
+
+impl EmbeddingModel for FakeEmbeddingModel {
+    const MAX_DOCUMENTS: usize = 64;
+
+    fn ndims(&self) -> usize {
+        FAKE_DIMS
+    }
+
+    async fn embed_texts(
+        &self,
+        texts: impl IntoIterator<Item = String> + Send,
+    ) -> Result<Vec<Embedding>, EmbeddingError> {
+        Ok(texts
+            .into_iter()
+            .map(|text| Embedding {
+                vec: fake_vector(&text),
+                document: text,
+            })
+            .collect())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RecordedRequest {
+    /// Text of the context documents, static and retrieved.
+    pub documents: Vec<String>,
+}
+
+/// Completion model that answers with canned replies, in order, and records
+/// the requests it was sent.
+#[derive(Clone, Default)]
+pub struct ScriptedCompletionModel {
+    replies: Arc<Mutex<VecDeque<ModelChoice>>>,
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+}
+
+impl ScriptedCompletionModel {
+    pub fn then_reply(self, reply: &str) -> Self {
+        self.replies
+            .lock()
+            .unwrap()
+            .push_back(ModelChoice::Message(reply.to_string()));
+        self
+    }
+
+    /// Queues a call of the tool `name` after the replies given so far.
+    pub fn then_tool_call(self, name: &str, args: Value) -> Self {
+        self.replies
+            .lock()
+            .unwrap()
+            .push_back(ModelChoice::ToolCall(name.to_string(), args));
+        self
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl CompletionModel for ScriptedCompletionModel {
+    type Response = ();
+
+    async fn completion(
+        &self,
+        request: CompletionRequest,
+    ) -> Result<CompletionResponse<()>, CompletionError> {
+        self.requests.lock().unwrap().push(RecordedRequest {
+            documents: request.documents.into_iter().map(|doc| doc.text).collect(),
+        });
+
+        let choice =
+            self.replies.lock().unwrap().pop_front().ok_or_else(|| {
+                CompletionError::ProviderError("No scripted reply left".to_string())
+            })?;
+        Ok(CompletionResponse {
+            choice,
+            raw_response: (),
+        })
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("never")]
+pub struct Never;
+
+/// Price tool answering every pair with the same quote.
+#[derive(Clone, Default)]
+pub struct PriceLookup {
+    calls: Arc<AtomicUsize>,
+}
+
+impl PriceLookup {
+    pub fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+}
+
+impl Tool for PriceLookup {
+    const NAME: &'static str = "price_lookup";
+
+    type Error = Never;
+    type Args = Value;
+    type Output = String;
+
+    async fn definition(&self, _prompt: String) -> ToolDefinition {
+        ToolDefinition {
+            name: Self::NAME.to_string(),
+            description: "Looks up the price of a trading pair".to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {"pair": {"type": "string"}},
+            }),
+        }
+    }
+
+    async fn call(&self, args: Value) -> Result<String, Never> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        let pair = args["pair"].as_str().unwrap_or("ETH/USDC");
+        Ok(format!("{pair}: 3000"))
+    }
+}
+
+/// A message as Discord shows it after sends and edits.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sent {
+    pub channel_id: ChannelId,
+    pub message_id: MessageId,
+    pub text: String,
+}
+
+/// Discord stand-in keeping every message sent through it.
+#[derive(Default)]
+pub struct RecordingClient {
+    sent: Mutex<Vec<Sent>>,
+    last_id: AtomicU64,
+}
+
+impl RecordingClient {
+    pub fn sent(&self) -> Vec<Sent> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    /// Texts of the messages in `channel_id`, in the order they were sent.
+    pub fn texts(&self, channel_id: u64) -> Vec<String> {
+        self.sent()
+            .into_iter()
+            .filter(|sent| sent.channel_id == ChannelId::new(channel_id))
+            .map(|sent| sent.text)
+            .collect()
+    }
+
+    /// The channel `channel_id` as a place to stream replies to.
+    pub fn channel(self: &Arc<Self>, channel_id: u64) -> RecordingChannel {
+        RecordingChannel {
+            client: self.clone(),
+            channel_id: ChannelId::new(channel_id),
+        }
+    }
+}
+
+#[async_trait]
+impl DiscordHttp for RecordingClient {
+    async fn send(
+        &self,
+        channel_id: ChannelId,
+        text: &str,
+        _mentions: &MentionPolicy,
+    ) -> Result<MessageId, SendError> {
+        let message_id = MessageId::new(self.last_id.fetch_add(1, Ordering::SeqCst) + 1);
+        self.sent.lock().unwrap().push(Sent {
+            channel_id,
+            message_id,
+            text: text.to_string(),
+        });
+        Ok(message_id)
+    }
+
+    async fn edit(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        text: &str,
+        _mentions: &MentionPolicy,
+    ) -> Result<(), SendError> {
+        let mut sent = self.sent.lock().unwrap();
+        let message = sent
+            .iter_mut()
+            .find(|sent| sent.channel_id == channel_id && sent.message_id == message_id)
+            .ok_or_else(|| SendError::Permanent("Unknown message".to_string()))?;
+        message.text = text.to_string();
+        Ok(())
+    }
+
+    /// Kept as a message listing the file names after `text`.
+    async fn send_files(
+        &self,
+        channel_id: ChannelId,
+        text: &str,
+        files: &[Attachment],
+        mentions: &MentionPolicy,
+    ) -> Result<MessageId, SendError> {
+        let names = files.iter().map(|file| file.name.as_str());
+        let text = format!("{text}[{}]", names.collect::<Vec<_>>().join(", "));
+        DiscordHttp::send(self, channel_id, &text, mentions).await
+    }
+}
+
+pub struct RecordingChannel {
+    client: Arc<RecordingClient>,
+    channel_id: ChannelId,
+}
+
+#[async_trait]
+impl ReplySink for RecordingChannel {
+    type Handle = MessageId;
+    type Error = SendError;
+
+    async fn send(&self, text: &str) -> Result<MessageId, SendError> {
+        DiscordHttp::send(&*self.client, self.channel_id, text, &MentionPolicy::none()).await
+    }
+
+    async fn edit(&self, handle: &MessageId, text: &str) -> Result<(), SendError> {
+        DiscordHttp::edit(
+            &*self.client,
+            self.channel_id,
+            *handle,
+            text,
+            &MentionPolicy::none(),
+        )
+        .await
+    }
+}
+
+fn load_sqlite_vec() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| unsafe {
+        rusqlite::ffi::sqlite3_auto_extension(Some(std::mem::transmute::<
+            *const (),
+            unsafe extern "C" fn(
+                *mut rusqlite::ffi::sqlite3,
+                *mut *mut std::os::raw::c_char,
+                *const rusqlite::ffi::sqlite3_api_routines,
+            ) -> i32,
+        >(
+            sqlite_vec::sqlite3_vec_init as *const ()
+        )));
+    });
+}
+
+pub fn character() -> Character {
+    Character::new("shinobi", "You help with Cartridge.")
+}
+
+pub fn message(
+    id: &str,
+    channel_type: ChannelType,
+    channel_id: u64,
+    user: &str,
+    content: &str,
+) -> Message {
+    Message {
+        id: id.to_string(),
+        source: Source::Discord,
+        source_id: user.to_string(),
+        channel_type,
+        channel_id: channel_id.to_string(),
+        account_id: user.to_string(),
+        role: "user".to_string(),
+        content: content.to_string(),
+        created_at: chrono::Utc::now(),
+    }
+}
+
+/// What the stack did with one incoming message.
+#[derive(Debug, Default)]
+pub struct Turn {
+    /// Ids of the messages answered together, when the message closed a
+    /// batch.
+    pub batch: Vec<String>,
+    pub assessment: Option<Assessment>,
+    pub interaction_id: Option<i64>,
+    /// Chunks the reply was sent as.
+    pub chunks: Vec<String>,
+}
+
+/// Agent, attention and store wired as `DiscordClient::handle_message` wires
+/// them, sending through a [RecordingClient].
+pub struct Harness {
+    pub agent: Agent<ScriptedCompletionModel, FakeEmbeddingModel>,
+    pub attention: Attention<ScriptedCompletionModel>,
+    pub client: Arc<RecordingClient>,
+    pub tool: PriceLookup,
+    outbound: Outbound,
+    debouncer: Debouncer,
+    path: PathBuf,
+    _dir: tempfile::TempDir,
+}
+
+impl Harness {
+    /// `model` answers, `attention_model` decides whether to.
+    pub async fn new(
+        model: ScriptedCompletionModel,
+        attention_model: ScriptedCompletionModel,
+    ) -> Self {
+        load_sqlite_vec();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("asuka.db");
+        let conn = tokio_rusqlite::Connection::open(&path).await.unwrap();
+        let knowledge = KnowledgeBase::new(conn, FakeEmbeddingModel).await.unwrap();
+
+        let character = character();
+        let clock = chrono::Utc.with_ymd_and_hms(2025, 1, 15, 12, 0, 0).unwrap();
+        let attention = Attention::new(
+            AttentionConfig {
+                bot_names: vec![character.name.clone()],
+                ..Default::default()
+            },
+            attention_model,
+        );
+        let agent = Agent::new(character, model, knowledge).with_clock(Arc::new(FixedClock(clock)));
+        let client = Arc::new(RecordingClient::default());
+
+        Self {
+            agent,
+            attention,
+            outbound: Outbound::new(client.clone()),
+            client,
+            tool: PriceLookup::default(),
+            debouncer: Debouncer::new(BatchConfig {
+                window_ms: 50,
+                ..Default::default()
+            }),
+            path,
+            _dir: dir,
+        }
+    }
+
+    /// Guards the agent against prompt injection, see
+    /// [asuka_core::injection].
+    pub fn with_injection(mut self, config: InjectionConfig) -> Self {
+        self.agent = self.agent.with_injection(config);
+        self
+    }
+
+    pub async fn add_documents(&self, documents: Vec<Document>) {
+        let mut knowledge = self.agent.knowledge().clone();
+        knowledge.add_documents(documents).await.unwrap();
+    }
+
+    /// A second connection to the store, for asserting on its rows.
+    pub fn db(&self) -> rusqlite::Connection {
+        rusqlite::Connection::open(&self.path).unwrap()
+    }
+
+    /// Handles `message` like the Discord client: stores it, batches it,
+    /// decides whether to reply and sends the reply in chunks. `mentioned`
+    /// stands for a platform mention of the bot.
+    pub async fn receive(&self, message: Message, mentioned: bool) -> Turn {
+        let knowledge = self.agent.knowledge();
+        knowledge.create_message(message.clone()).await.unwrap();
+        self.agent
+            .conversations()
+            .update(&message.channel_id, |state| state.receive(&message.id))
+            .await;
+
+        let mentioned = mentioned
+            || self
+                .attention
+                .config()
+                .names()
+                .find_in_text(&message.content)
+                .is_some();
+        let Some(batch) = self.debouncer.push(message.clone(), mentioned).await else {
+            return Turn::default();
+        };
+        let content = batch.content();
+        let mut turn = Turn {
+            batch: batch.message_ids(),
+            ..Default::default()
+        };
+
+        let context = AttentionContext {
+            message_content: content.clone(),
+            mentioned_names: HashSet::new(),
+            history: knowledge
+                .channel_messages(
+                    &message.channel_id,
+                    self.attention.config().max_history_messages,
+                )
+                .await
+                .unwrap(),
+            recent_replies: knowledge
+                .recent_replies(&message.channel_id, RECENT_REPLIES)
+                .await
+                .unwrap(),
+            channel_id: message.channel_id.clone(),
+            channel_type: message.channel_type.clone(),
+            source: message.source.clone(),
+            topic_match: self.agent.character.topic_match(&content),
+            familiarity: self
+                .agent
+                .familiarity(&message.channel_id, &message.account_id)
+                .await,
+        };
+        let assessment = if batch.mentioned {
+            self.attention.addressed(&context)
+        } else {
+            self.attention.assess(&context).await
+        };
+        let message_ids = batch.message_ids();
+        self.agent
+            .conversations()
+            .update(&message.channel_id, |state| {
+                state.settle(&message_ids, assessment.decision)
+            })
+            .await;
+        turn.assessment = Some(assessment.clone());
+
+        let ReplyRoute::Answer(mode) = assessment.route(None, &message.channel_type) else {
+            return turn;
+        };
+
+        let interaction_id = knowledge
+            .create_interaction(
+                message.channel_id.clone(),
+                message.account_id.clone(),
+                batch.message_ids(),
+            )
+            .await
+            .unwrap();
+        turn.interaction_id = Some(interaction_id);
+
+        let channel_id = ChannelId::new(message.channel_id.parse().unwrap());
+        let response = match self
+            .agent
+            .quote_sources(&message.channel_id, &content)
+            .await
+        {
+            Some(quotes) => quotes,
+            None => {
+                let guard = self.agent.tool_guard(CancellationToken::new());
+                let tool = RecordedTool::new(
+                    guard.wrap(self.tool.clone()),
+                    knowledge.clone(),
+                    interaction_id,
+                );
+                let responder = self
+                    .agent
+                    .clone()
+                    .with_familiarity(context.familiarity.clone())
+                    .response_builder(&message.channel_id, &mode, &content)
+                    .await
+                    .tool(tool)
+                    .build();
+                let response = responder.prompt(&content).await.unwrap();
+                let response = self
+                    .agent
+                    .guard_preamble(&responder, &content, response)
+                    .await;
+                self.agent
+                    .process_response(
+                        ResponseDraft::new(response, Source::Discord),
+                        &MessageContext::from(&message),
+                    )
+                    .await
+                    .text
+            }
+        };
+
+        turn.chunks = chunk_message(&response, MAX_MESSAGE_LENGTH, MIN_CHUNK_LENGTH);
+        let delivery = self
+            .outbound
+            .send_chunks(channel_id, &turn.chunks, &MentionPolicy::none())
+            .await;
+        assert!(delivery.is_complete());
+        knowledge
+            .record_sent_messages(
+                interaction_id,
+                &Source::Discord,
+                &channel_id.to_string(),
+                &delivery
+                    .sent
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>(),
+            )
+            .await
+            .unwrap();
+        knowledge
+            .create_message(ReplyOutcome::Reply(response).to_message(&message, BOT_ID))
+            .await
+            .unwrap();
+        turn
+    }
+    /// Handles `message`, a reply to the bot's message `replying_to`, like
+    /// the Discord client handles corrections: edits the answer, thanks the
+    /// user and corrects the stored reply. Returns whether `message` was
+    /// handled as a correction.
+    pub async fn correct(&self, message: Message, replying_to: MessageId) -> bool {
+        let knowledge = self.agent.knowledge();
+        knowledge.create_message(message.clone()).await.unwrap();
+        if !corrections::is_correction(&message.content) {
+            return false;
+        }
+        let original = self
+            .client
+            .sent()
+            .into_iter()
+            .find(|sent| sent.message_id == replying_to)
+            .unwrap();
+        let Some(notice) = self
+            .agent
+            .correct_answer(&message.channel_id, &original.text, &message.content)
+            .await
+        else {
+            return false;
+        };
+
+        let reply = match amend_message(
+            &self.outbound,
+            original.channel_id,
+            replying_to,
+            &original.text,
+            &notice,
+        )
+        .await
+        {
+            Ok(()) => self
+                .agent
+                .character
+                .template(templates::CORRECTION_ACK, &[]),
+            Err(_) => notice.clone(),
+        };
+        self.outbound
+            .send(original.channel_id, &reply, &MentionPolicy::none())
+            .await
+            .unwrap();
+        knowledge
+            .correct_reply(&Source::Discord, &replying_to.to_string(), &notice)
+            .await
+            .unwrap();
+        knowledge
+            .create_message(ReplyOutcome::Reply(reply).to_message(&message, BOT_ID))
+            .await
+            .unwrap();
+        true
+    }
+}
@@ -0,0 +1,48 @@
+//! Checks that the crate compiles without its default features and with each
+//! integration on its own. Slow, as every combination is a separate build, so
+//! ignored by default:
+//!
+//! ```bash
+//! cargo test -p asuka-core --test features -- --ignored
+//! ```
+
+use std::{path::Path, process::Command};
+
+/// Feature sets checked on top of `--no-default-features`.
+const COMBINATIONS: &[&str] = &[
+    "",
+    "discord",
+    "telegram",
+    "twitter",
+    "mcp",
+    "git-loader",
+    "farcaster",
+];
+
+#[test]
+#[ignore]
+fn test_feature_combinations_compile() {
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    // A separate target dir, so the checks don't invalidate the main build.
+    let target_dir = manifest_dir.join("../target/features");
+
+    let failed = COMBINATIONS
+        .iter()
+        .copied()
+        .filter(|features| {
+            let status = Command::new(env!("CARGO"))
+                .current_dir(manifest_dir)
+                .env("CARGO_TARGET_DIR", &target_dir)
+                .args(["check", "--all-targets", "--no-default-features"])
+                .args(["--features", *features])
+                .status()
+                .expect("Failed to run cargo");
+            !status.success()
+        })
+        .collect::<Vec<_>>();
+
+    assert!(
+        failed.is_empty(),
+        "Failed to compile with features {failed:?}"
+    );
+}
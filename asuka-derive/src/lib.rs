@@ -0,0 +1,330 @@
+//! Derive macros for asuka.
+//!
+//! `#[derive(SqliteTable)]` implements `rig_sqlite::SqliteVectorStoreTable`
+//! and `TryFrom<&rusqlite::Row>` from a struct definition, so the schema, the
+//! inserted values and the row mapping cannot drift apart. Columns follow
+//! field order, rows are read by column name, and the generated `COLUMNS`
+//! constant lists the stored columns for `SELECT` statements.
+//!
+//! ```
+//! use asuka_derive::SqliteTable;
+//!
+//! #[derive(SqliteTable, Clone)]
+//! #[table(name = "notes")]
+//! struct Note {
+//!     #[column(primary_key)]
+//!     id: String,
+//!     #[column(indexed)]
+//!     author: String,
+//!     #[column(type = "TEXT NOT NULL")]
+//!     body: String,
+//!     created_at: chrono::DateTime<chrono::Utc>,
+//!     #[column(skip)]
+//!     tags: Vec<String>,
+//! }
+//!
+//! assert_eq!(Note::COLUMNS, "id, author, body, created_at");
+//! ```
+//!
+//! Supported field types are `String`, integers, floats and
+//! `chrono::DateTime`. Fields marked `#[column(as_str)]` are stored as text
+//! through an `as_str()` method and read back with a
+//! `from_str(&str) -> Option<Self>` function. Anything else is rejected:
+//!
+//! ```compile_fail
+//! use asuka_derive::SqliteTable;
+//!
+//! #[derive(SqliteTable, Clone)]
+//! #[table(name = "notes")]
+//! struct Note {
+//!     #[column(primary_key)]
+//!     id: String,
+//!     tags: Vec<String>,
+//! }
+//! ```
+//!
+//! ```compile_fail
+//! use asuka_derive::SqliteTable;
+//!
+//! #[derive(SqliteTable, Clone)]
+//! #[table(name = "notes")]
+//! struct Note {
+//!     #[column(primary_key)]
+//!     id: String,
+//!     title: Option<String>,
+//! }
+//! ```
+//!
+//! A table needs exactly one primary key:
+//!
+//! ```compile_fail
+//! use asuka_derive::SqliteTable;
+//!
+//! #[derive(SqliteTable, Clone)]
+//! #[table(name = "notes")]
+//! struct Note {
+//!     id: String,
+//! }
+//! ```
+//!
+//! Types that do not fit, e.g. with nullable columns, implement the traits by
+//! hand instead.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, LitStr, Type};
+
+#[proc_macro_derive(SqliteTable, attributes(table, column))]
+pub fn derive_sqlite_table(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// How a field is converted to and from its column.
+enum Kind {
+    Text,
+    Number,
+    Timestamp,
+    AsStr,
+}
+
+struct Column {
+    field: syn::Ident,
+    ty: Type,
+    name: String,
+    sql_type: String,
+    indexed: bool,
+    primary_key: bool,
+    kind: Kind,
+}
+
+fn kind_of(ty: &Type) -> Option<Kind> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let ident = path.path.segments.last()?.ident.to_string();
+    match ident.as_str() {
+        "String" => Some(Kind::Text),
+        "i8" | "i16" | "i32" | "i64" | "u8" | "u16" | "u32" | "f32" | "f64" => Some(Kind::Number),
+        "DateTime" => Some(Kind::Timestamp),
+        _ => None,
+    }
+}
+
+fn default_sql_type(ty: &Type, kind: &Kind) -> &'static str {
+    match kind {
+        Kind::Text | Kind::AsStr => "TEXT",
+        Kind::Timestamp => "TIMESTAMP DEFAULT CURRENT_TIMESTAMP",
+        Kind::Number => match ty {
+            Type::Path(path) if path.path.is_ident("f32") || path.path.is_ident("f64") => "REAL",
+            _ => "INTEGER",
+        },
+    }
+}
+
+fn table_name(input: &DeriveInput) -> syn::Result<String> {
+    let mut name = None;
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("table")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("name") {
+                name = Some(meta.value()?.parse::<LitStr>()?.value());
+                Ok(())
+            } else {
+                Err(meta.error("expected `name = \"...\"`"))
+            }
+        })?;
+    }
+    name.ok_or_else(|| {
+        syn::Error::new_spanned(&input.ident, "missing `#[table(name = \"...\")]` attribute")
+    })
+}
+
+/// Parses a field, returning `None` for skipped ones.
+fn column(field: &syn::Field) -> syn::Result<Option<Column>> {
+    let ident = field.ident.clone().expect("named field");
+    let mut sql_type = None;
+    let mut indexed = false;
+    let mut primary_key = false;
+    let mut as_str = false;
+    let mut skip = false;
+
+    for attr in field.attrs.iter().filter(|a| a.path().is_ident("column")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("type") {
+                sql_type = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else if meta.path.is_ident("indexed") {
+                indexed = true;
+            } else if meta.path.is_ident("primary_key") {
+                primary_key = true;
+            } else if meta.path.is_ident("as_str") {
+                as_str = true;
+            } else if meta.path.is_ident("skip") {
+                skip = true;
+            } else {
+                return Err(meta
+                    .error("expected one of `type`, `indexed`, `primary_key`, `as_str`, `skip`"));
+            }
+            Ok(())
+        })?;
+    }
+
+    if skip {
+        return Ok(None);
+    }
+
+    let kind = if as_str {
+        Kind::AsStr
+    } else {
+        kind_of(&field.ty).ok_or_else(|| {
+            syn::Error::new_spanned(
+                &field.ty,
+                "unsupported column type; use `#[column(as_str)]`, `#[column(skip)]` or implement SqliteVectorStoreTable by hand",
+            )
+        })?
+    };
+
+    let mut sql_type = sql_type.unwrap_or_else(|| default_sql_type(&field.ty, &kind).to_string());
+    if primary_key {
+        sql_type.push_str(" PRIMARY KEY");
+    }
+
+    Ok(Some(Column {
+        name: ident.to_string(),
+        field: ident,
+        ty: field.ty.clone(),
+        sql_type,
+        indexed,
+        primary_key,
+        kind,
+    }))
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let ident = &input.ident;
+    let table = table_name(&input)?;
+
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new_spanned(
+            ident,
+            "SqliteTable can only be derived for structs",
+        ));
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(syn::Error::new_spanned(
+            ident,
+            "SqliteTable requires named fields",
+        ));
+    };
+
+    let mut columns = Vec::new();
+    let mut skipped = Vec::new();
+    for field in &fields.named {
+        match column(field)? {
+            Some(column) => columns.push(column),
+            None => skipped.push(field.ident.clone().expect("named field")),
+        }
+    }
+
+    let mut primary_keys = columns.iter().filter(|c| c.primary_key);
+    let primary_key = match (primary_keys.next(), primary_keys.next()) {
+        (Some(column), None) => column,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "SqliteTable needs exactly one `#[column(primary_key)]` field",
+            ))
+        }
+    };
+
+    let id = {
+        let field = &primary_key.field;
+        match primary_key.kind {
+            Kind::Text => quote!(self.#field.clone()),
+            _ => quote!(self.#field.to_string()),
+        }
+    };
+
+    let schema = columns.iter().map(|c| {
+        let (name, sql_type) = (&c.name, &c.sql_type);
+        if c.indexed {
+            quote!(::rig_sqlite::Column::new(#name, #sql_type).indexed())
+        } else {
+            quote!(::rig_sqlite::Column::new(#name, #sql_type))
+        }
+    });
+
+    let values = columns.iter().map(|c| {
+        let (name, field) = (&c.name, &c.field);
+        let value = match c.kind {
+            Kind::Text => quote!(self.#field.clone()),
+            Kind::Number => quote!(self.#field.to_string()),
+            Kind::Timestamp => quote!(self.#field.to_rfc3339()),
+            Kind::AsStr => quote!(self.#field.as_str().to_string()),
+        };
+        quote!((#name, Box::new(#value) as Box<dyn ::rig_sqlite::ColumnValue>))
+    });
+
+    let reads = columns.iter().map(|c| {
+        let (name, field, ty) = (&c.name, &c.field, &c.ty);
+        match c.kind {
+            Kind::AsStr => quote! {
+                #field: {
+                    let value: String = row.get(#name)?;
+                    <#ty>::from_str(&value).ok_or_else(|| {
+                        ::rusqlite::Error::FromSqlConversionFailure(
+                            row.as_ref().column_index(#name).unwrap_or_default(),
+                            ::rusqlite::types::Type::Text,
+                            format!("Invalid {}: {value}", #name).into(),
+                        )
+                    })?
+                }
+            },
+            _ => quote!(#field: row.get(#name)?),
+        }
+    });
+
+    let column_list = columns
+        .iter()
+        .map(|c| c.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    Ok(quote! {
+        impl #ident {
+            /// Stored columns in schema order.
+            pub const COLUMNS: &'static str = #column_list;
+        }
+
+        impl ::rig_sqlite::SqliteVectorStoreTable for #ident {
+            fn name() -> &'static str {
+                #table
+            }
+
+            fn schema() -> Vec<::rig_sqlite::Column> {
+                vec![#(#schema),*]
+            }
+
+            fn id(&self) -> String {
+                #id
+            }
+
+            fn column_values(&self) -> Vec<(&'static str, Box<dyn ::rig_sqlite::ColumnValue>)> {
+                vec![#(#values),*]
+            }
+        }
+
+        impl TryFrom<&::rusqlite::Row<'_>> for #ident {
+            type Error = ::rusqlite::Error;
+
+            fn try_from(row: &::rusqlite::Row) -> Result<Self, Self::Error> {
+                Ok(Self {
+                    #(#reads,)*
+                    #(#skipped: Default::default(),)*
+                })
+            }
+        }
+    })
+}
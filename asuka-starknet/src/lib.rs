@@ -1,3 +1,4 @@
 pub mod add_token;
+pub mod simulator;
 pub mod swap;
 pub mod transfer;
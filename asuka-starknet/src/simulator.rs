@@ -0,0 +1,430 @@
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+use serde::Serialize;
+use starknet::{
+    core::types::{
+        BlockId, BlockTag, BroadcastedInvokeTransaction, BroadcastedInvokeTransactionV3,
+        BroadcastedTransaction, Call, DataAvailabilityMode, ExecuteInvocation, Felt, PriceUnit,
+        ResourceBounds, ResourceBoundsMapping, SimulatedTransaction, SimulationFlag,
+        TransactionTrace,
+    },
+    providers::{
+        jsonrpc::{HttpTransportError, JsonRpcClientError},
+        Provider, ProviderError,
+    },
+};
+
+/// JSON-RPC error code returned by nodes that do not implement a method.
+const METHOD_NOT_FOUND: i64 = -32601;
+
+pub type SimulationFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<SimulatedTransaction, ProviderError>> + Send + 'a>>;
+
+/// The single RPC the [Simulator] depends on. Implemented for every starknet
+/// [Provider] so tools can share one node connection, and for fakes in tests.
+pub trait SimulationBackend: Send + Sync {
+    fn simulate<'a>(&'a self, sender: Felt, calls: &'a [Call]) -> SimulationFuture<'a>;
+}
+
+impl<P> SimulationBackend for P
+where
+    P: Provider + Send + Sync,
+{
+    fn simulate<'a>(&'a self, sender: Felt, calls: &'a [Call]) -> SimulationFuture<'a> {
+        Box::pin(async move {
+            let block = BlockId::Tag(BlockTag::Pending);
+            let nonce = self.get_nonce(block, sender).await?;
+
+            let transaction = BroadcastedTransaction::Invoke(BroadcastedInvokeTransaction::V3(
+                BroadcastedInvokeTransactionV3 {
+                    sender_address: sender,
+                    calldata: encode_calls(calls),
+                    signature: vec![],
+                    nonce,
+                    resource_bounds: ResourceBoundsMapping {
+                        l1_gas: ResourceBounds {
+                            max_amount: 0,
+                            max_price_per_unit: 0,
+                        },
+                        l2_gas: ResourceBounds {
+                            max_amount: 0,
+                            max_price_per_unit: 0,
+                        },
+                    },
+                    tip: 0,
+                    paymaster_data: vec![],
+                    account_deployment_data: vec![],
+                    nonce_data_availability_mode: DataAvailabilityMode::L1,
+                    fee_data_availability_mode: DataAvailabilityMode::L1,
+                    is_query: true,
+                },
+            ));
+
+            // Unsigned and unfunded: skip both checks so only execution is simulated.
+            self.simulate_transaction(
+                block,
+                transaction,
+                [SimulationFlag::SkipValidate, SimulationFlag::SkipFeeCharge],
+            )
+            .await
+        })
+    }
+}
+
+/// Encodes calls the way Cairo 1 accounts expect `__execute__` calldata.
+fn encode_calls(calls: &[Call]) -> Vec<Felt> {
+    let mut calldata = vec![Felt::from(calls.len())];
+    for call in calls {
+        calldata.push(call.to);
+        calldata.push(call.selector);
+        calldata.push(Felt::from(call.calldata.len()));
+        calldata.extend_from_slice(&call.calldata);
+    }
+    calldata
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SimulationError {
+    #[error("The configured Starknet node does not support transaction simulation")]
+    Unsupported,
+    #[error("Simulation returned an unexpected {0} trace")]
+    UnexpectedTrace(&'static str),
+    #[error("Provider error: {0}")]
+    ProviderError(ProviderError),
+}
+
+impl From<ProviderError> for SimulationError {
+    fn from(err: ProviderError) -> Self {
+        if is_method_not_found(&err) {
+            SimulationError::Unsupported
+        } else {
+            SimulationError::ProviderError(err)
+        }
+    }
+}
+
+fn is_method_not_found(err: &ProviderError) -> bool {
+    match err {
+        ProviderError::Other(inner) => match inner
+            .as_any()
+            .downcast_ref::<JsonRpcClientError<HttpTransportError>>()
+        {
+            Some(JsonRpcClientError::JsonRpcError(rpc)) => rpc.code == METHOD_NOT_FOUND,
+            _ => inner.to_string().contains("Method not found"),
+        },
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StateChange {
+    pub contract: Felt,
+    pub key: Felt,
+    pub value: Felt,
+}
+
+/// What a transaction would do if it were submitted now.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SimulationReport {
+    pub estimated_fee: Felt,
+    pub fee_unit: &'static str,
+    pub state_changes: Vec<StateChange>,
+    pub revert_reason: Option<String>,
+}
+
+impl SimulationReport {
+    pub fn from_simulation(simulation: &SimulatedTransaction) -> Result<Self, SimulationError> {
+        let trace = match &simulation.transaction_trace {
+            TransactionTrace::Invoke(trace) => trace,
+            TransactionTrace::DeployAccount(_) => {
+                return Err(SimulationError::UnexpectedTrace("deploy account"))
+            }
+            TransactionTrace::L1Handler(_) => {
+                return Err(SimulationError::UnexpectedTrace("l1 handler"))
+            }
+            TransactionTrace::Declare(_) => {
+                return Err(SimulationError::UnexpectedTrace("declare"))
+            }
+        };
+
+        let revert_reason = match &trace.execute_invocation {
+            ExecuteInvocation::Success(_) => None,
+            ExecuteInvocation::Reverted(reverted) => {
+                Some(readable_revert_reason(&reverted.revert_reason))
+            }
+        };
+
+        let state_changes = trace
+            .state_diff
+            .iter()
+            .flat_map(|diff| &diff.storage_diffs)
+            .flat_map(|item| {
+                item.storage_entries.iter().map(|entry| StateChange {
+                    contract: item.address,
+                    key: entry.key,
+                    value: entry.value,
+                })
+            })
+            .collect();
+
+        Ok(Self {
+            estimated_fee: simulation.fee_estimation.overall_fee,
+            fee_unit: match simulation.fee_estimation.unit {
+                PriceUnit::Wei => "wei",
+                PriceUnit::Fri => "fri",
+            },
+            state_changes,
+            revert_reason,
+        })
+    }
+
+    pub fn would_revert(&self) -> bool {
+        self.revert_reason.is_some()
+    }
+}
+
+impl fmt::Display for SimulationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.revert_reason {
+            Some(reason) => writeln!(f, "Simulation: would revert ({})", reason)?,
+            None => writeln!(f, "Simulation: would succeed")?,
+        }
+        writeln!(
+            f,
+            "Estimated fee: {} {}",
+            self.estimated_fee.to_bigint(),
+            self.fee_unit
+        )?;
+
+        if self.state_changes.is_empty() {
+            write!(f, "State changes: none")
+        } else {
+            write!(f, "State changes:")?;
+            for change in &self.state_changes {
+                write!(
+                    f,
+                    "\n  {:#x} [{:#x}] = {:#x}",
+                    change.contract, change.key, change.value
+                )?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Known failure reasons and how to explain them to a user.
+const KNOWN_REVERTS: &[(&str, &str)] = &[
+    ("u256_sub Overflow", "insufficient token balance"),
+    ("insufficient balance", "insufficient token balance"),
+    ("insufficient allowance", "the token allowance is too low"),
+    (
+        "ENTRYPOINT_NOT_FOUND",
+        "the contract does not support this call",
+    ),
+    ("not deployed", "the target contract is not deployed"),
+    ("invalid-signature", "the account rejected the signature"),
+];
+
+/// Turns a raw VM revert trace into a short, readable reason. Unknown reasons
+/// fall back to the decoded failure message, or the first line of the trace.
+pub fn readable_revert_reason(raw: &str) -> String {
+    if let Some((_, message)) = KNOWN_REVERTS
+        .iter()
+        .find(|(pattern, _)| raw.to_lowercase().contains(&pattern.to_lowercase()))
+    {
+        return message.to_string();
+    }
+
+    // Failure reasons are usually reported as `0x... ('decoded message')`.
+    if let Some(start) = raw.rfind("('") {
+        if let Some(end) = raw[start..].find("')") {
+            return raw[start + 2..start + end].to_string();
+        }
+    }
+
+    raw.lines().next().unwrap_or_default().trim().to_string()
+}
+
+/// Shared dry-run entry point for the Starknet tools. Cheap to clone, so one
+/// node connection can back every tool.
+#[derive(Clone)]
+pub struct Simulator {
+    backend: Arc<dyn SimulationBackend>,
+    sender: Felt,
+}
+
+impl Simulator {
+    pub fn new(backend: impl SimulationBackend + 'static, sender: Felt) -> Self {
+        Self {
+            backend: Arc::new(backend),
+            sender,
+        }
+    }
+
+    pub async fn simulate(&self, calls: &[Call]) -> Result<SimulationReport, SimulationError> {
+        // Provider futures are only `Send`, but rig requires tool futures to be
+        // `Sync` as well. Holding the future behind a mutex satisfies both.
+        let mut simulation = Mutex::new(self.backend.simulate(self.sender, calls));
+        let simulation =
+            std::future::poll_fn(|cx| simulation.get_mut().unwrap().as_mut().poll(cx)).await?;
+        SimulationReport::from_simulation(&simulation)
+    }
+}
+
+/// Result of a tool that may either preview or submit a transaction.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum TransactionOutcome {
+    Simulated(SimulationReport),
+    /// Not produced yet: the tools refuse to submit until signing is wired.
+    Executed {
+        transaction_hash: Felt,
+    },
+}
+
+pub(crate) fn default_dry_run() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use starknet::providers::jsonrpc::JsonRpcError;
+
+    struct MockBackend {
+        response: fn() -> Result<SimulatedTransaction, ProviderError>,
+    }
+
+    impl SimulationBackend for MockBackend {
+        fn simulate<'a>(&'a self, _sender: Felt, _calls: &'a [Call]) -> SimulationFuture<'a> {
+            Box::pin(async move { (self.response)() })
+        }
+    }
+
+    fn simulated(execute_invocation: serde_json::Value) -> SimulatedTransaction {
+        serde_json::from_value(json!({
+            "transaction_trace": {
+                "type": "INVOKE",
+                "execute_invocation": execute_invocation,
+                "state_diff": {
+                    "storage_diffs": [{
+                        "address": "0x49d",
+                        "storage_entries": [
+                            { "key": "0x1", "value": "0x64" },
+                            { "key": "0x2", "value": "0x0" }
+                        ]
+                    }],
+                    "deprecated_declared_classes": [],
+                    "declared_classes": [],
+                    "deployed_contracts": [],
+                    "replaced_classes": [],
+                    "nonces": []
+                },
+                "execution_resources": {
+                    "steps": 10,
+                    "data_availability": { "l1_gas": 0, "l1_data_gas": 128 }
+                }
+            },
+            "fee_estimation": {
+                "gas_consumed": "0x10",
+                "gas_price": "0x2",
+                "data_gas_consumed": "0x0",
+                "data_gas_price": "0x1",
+                "overall_fee": "0x3e8",
+                "unit": "WEI"
+            }
+        }))
+        .unwrap()
+    }
+
+    fn success() -> Result<SimulatedTransaction, ProviderError> {
+        Ok(simulated(json!({
+            "contract_address": "0x123",
+            "entry_point_selector": "0x0",
+            "calldata": [],
+            "caller_address": "0x0",
+            "class_hash": "0x1",
+            "entry_point_type": "EXTERNAL",
+            "call_type": "CALL",
+            "result": [],
+            "calls": [],
+            "events": [],
+            "messages": [],
+            "execution_resources": { "steps": 10 }
+        })))
+    }
+
+    fn reverted() -> Result<SimulatedTransaction, ProviderError> {
+        Ok(simulated(json!({
+            "revert_reason": "Error in the called contract (0x49d):\nExecution failed. Failure reason: 0x753235365f737562204f766572666c6f77 ('u256_sub Overflow')."
+        })))
+    }
+
+    fn unsupported() -> Result<SimulatedTransaction, ProviderError> {
+        Err(
+            JsonRpcClientError::<HttpTransportError>::JsonRpcError(JsonRpcError {
+                code: METHOD_NOT_FOUND,
+                message: "Method not found".to_string(),
+                data: None,
+            })
+            .into(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_report_formatting() {
+        let simulator = Simulator::new(MockBackend { response: success }, Felt::ONE);
+        let report = simulator.simulate(&[]).await.unwrap();
+
+        assert!(!report.would_revert());
+        assert_eq!(
+            report.to_string(),
+            "Simulation: would succeed\n\
+             Estimated fee: 1000 wei\n\
+             State changes:\n  \
+             0x49d [0x1] = 0x64\n  \
+             0x49d [0x2] = 0x0"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_revert_reason_is_readable() {
+        let simulator = Simulator::new(MockBackend { response: reverted }, Felt::ONE);
+        let report = simulator.simulate(&[]).await.unwrap();
+
+        assert_eq!(
+            report.revert_reason.as_deref(),
+            Some("insufficient token balance")
+        );
+        assert!(report
+            .to_string()
+            .starts_with("Simulation: would revert (insufficient token balance)"));
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_node() {
+        let simulator = Simulator::new(
+            MockBackend {
+                response: unsupported,
+            },
+            Felt::ONE,
+        );
+        let err = simulator.simulate(&[]).await.unwrap_err();
+        assert!(matches!(err, SimulationError::Unsupported));
+    }
+
+    #[test]
+    fn test_unknown_revert_reason_is_decoded() {
+        assert_eq!(
+            readable_revert_reason("Execution failed. Failure reason: 0x1 ('Pool is locked')."),
+            "Pool is locked"
+        );
+        assert_eq!(readable_revert_reason("Out of gas\nat pc=12"), "Out of gas");
+    }
+}
@@ -1,27 +1,64 @@
 use rig::{completion::ToolDefinition, tool::Tool};
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use serde_json::json;
-use starknet::core::types::Felt;
+use starknet::core::{
+    types::{Call, Felt},
+    utils::get_selector_from_name,
+};
+
+use crate::simulator::{default_dry_run, SimulationError, Simulator, TransactionOutcome};
+
+/// Ekubo router on mainnet, which executes the quoted routes.
+const EKUBO_ROUTER: Felt =
+    Felt::from_hex_unchecked("0x0199741822c2dc722f6f605204f35e56dbc23bceed54818168c4c49e4fb8737e");
+
+/// Amount of token a to buy, hardcoded for example.
+const BUY_AMOUNT: u128 = 1_000_000_000;
 
 #[derive(Deserialize)]
 pub struct SwapArgs {
     a: Felt,
     b: Felt,
+    #[serde(default = "default_dry_run")]
+    dry_run: bool,
 }
 
 #[derive(Debug, thiserror::Error)]
-#[error("Swap error")]
-pub struct SwapError;
+pub enum SwapError {
+    #[error("Failed to fetch a quote")]
+    QuoteError,
+    #[error("Invalid quote: {0}")]
+    InvalidQuote(String),
+    #[error("Submitting transactions is not supported yet, only dry runs are")]
+    ExecutionUnsupported,
+    #[error("Dry run requested but no simulator is configured")]
+    NoSimulator,
+    #[error("Simulation failed: {0}")]
+    SimulationError(#[from] SimulationError),
+}
 
-#[derive(Deserialize, Serialize)]
-pub struct Swap;
+#[derive(Default)]
+pub struct Swap {
+    simulator: Option<Simulator>,
+}
+
+impl Swap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_simulator(mut self, simulator: Simulator) -> Self {
+        self.simulator = Some(simulator);
+        self
+    }
+}
 
 #[derive(Deserialize)]
 struct PoolKey {
     token0: String,
     token1: String,
     fee: String,
-    tick_spacing: i32,
+    tick_spacing: u128,
     extension: String,
 }
 
@@ -29,12 +66,11 @@ struct PoolKey {
 struct Route {
     pool_key: PoolKey,
     sqrt_ratio_limit: String,
-    skip_ahead: i32,
+    skip_ahead: u128,
 }
 
 #[derive(Deserialize)]
 struct Split {
-    amount: String,
     specified_amount: String,
     route: Vec<Route>,
 }
@@ -45,17 +81,97 @@ struct QuoteResponse {
     splits: Vec<Split>,
 }
 
+/// Parses a quote value, either hex or a signed decimal, into its magnitude
+/// and whether it is negative.
+fn parse_amount(value: &str) -> Result<(Felt, bool), SwapError> {
+    let (magnitude, negative) = match value.strip_prefix('-') {
+        Some(magnitude) => (magnitude, true),
+        None => (value, false),
+    };
+    let parsed = if magnitude.starts_with("0x") {
+        Felt::from_hex(magnitude)
+    } else {
+        Felt::from_dec_str(magnitude)
+    };
+    parsed
+        .map(|magnitude| (magnitude, negative))
+        .map_err(|_| SwapError::InvalidQuote(value.to_string()))
+}
+
+fn parse_felt(value: &str) -> Result<Felt, SwapError> {
+    parse_amount(value).map(|(magnitude, _)| magnitude)
+}
+
+/// A u256 as its (low, high) calldata words.
+fn u256(value: Felt) -> [Felt; 2] {
+    let bytes = value.to_bytes_be();
+    [
+        Felt::from_bytes_be_slice(&bytes[16..]),
+        Felt::from_bytes_be_slice(&bytes[..16]),
+    ]
+}
+
+/// The calls buying [BUY_AMOUNT] of `a` with `b` along the quoted routes:
+/// pay the router, swap, and clear both tokens back to the sender.
+fn router_calls(quote: &QuoteResponse, a: Felt, b: Felt) -> Result<Vec<Call>, SwapError> {
+    let (total, _) = parse_amount(&quote.total)?;
+
+    let mut swaps = vec![Felt::from(quote.splits.len())];
+    for split in &quote.splits {
+        swaps.push(Felt::from(split.route.len()));
+        for route in &split.route {
+            let key = &route.pool_key;
+            swaps.extend([
+                parse_felt(&key.token0)?,
+                parse_felt(&key.token1)?,
+                parse_felt(&key.fee)?,
+                Felt::from(key.tick_spacing),
+                parse_felt(&key.extension)?,
+            ]);
+            swaps.extend(u256(parse_felt(&route.sqrt_ratio_limit)?));
+            swaps.push(Felt::from(route.skip_ahead));
+        }
+        // The specified token amount, an i129 as (magnitude, sign)
+        let (amount, negative) = parse_amount(&split.specified_amount)?;
+        swaps.extend([a, amount, if negative { Felt::ONE } else { Felt::ZERO }]);
+    }
+
+    let selector = |name| get_selector_from_name(name).unwrap();
+    Ok(vec![
+        Call {
+            to: b,
+            selector: selector("transfer"),
+            calldata: [vec![EKUBO_ROUTER], u256(total).to_vec()].concat(),
+        },
+        Call {
+            to: EKUBO_ROUTER,
+            selector: selector("multi_multihop_swap"),
+            calldata: swaps,
+        },
+        Call {
+            to: EKUBO_ROUTER,
+            selector: selector("clear_minimum"),
+            calldata: [vec![a], u256(Felt::from(BUY_AMOUNT)).to_vec()].concat(),
+        },
+        Call {
+            to: EKUBO_ROUTER,
+            selector: selector("clear"),
+            calldata: vec![b],
+        },
+    ])
+}
+
 impl Tool for Swap {
     const NAME: &'static str = "swap";
 
     type Error = SwapError;
     type Args = SwapArgs;
-    type Output = Felt;
+    type Output = TransactionOutcome;
 
     async fn definition(&self, _prompt: String) -> ToolDefinition {
         ToolDefinition {
             name: "swap".to_string(),
-            description: "Swap token a for token b".to_string(),
+            description: "Swap token a for token b. Only dry runs are supported for now, which report the fee and effects of the swap.".to_string(),
             parameters: json!({
                 "type": "object",
                 "properties": {
@@ -66,6 +182,10 @@ impl Tool for Swap {
                     "b": {
                         "type": "string",
                         "description": "The token to sell"
+                    },
+                    "dry_run": {
+                        "type": "boolean",
+                        "description": "Simulate the swap without submitting it (default true)"
                     }
                 }
             }),
@@ -73,26 +193,78 @@ impl Tool for Swap {
     }
 
     async fn call(&self, args: Self::Args) -> Result<Self::Output, Self::Error> {
+        if !args.dry_run {
+            return Err(SwapError::ExecutionUnsupported);
+        }
+        let simulator = self.simulator.as_ref().ok_or(SwapError::NoSimulator)?;
+
         let url = format!(
-            "https://mainnet-api.ekubo.org/quote/{}/{}/{}",
-            "-1e9", // Hardcoded amount for example
+            "https://mainnet-api.ekubo.org/quote/-{}/{}/{}",
+            BUY_AMOUNT,
             args.a.to_string(),
             args.b.to_string()
         );
 
         let client = reqwest::Client::new();
-        let response = client
+        let quote = client
             .get(&url)
             .header("accept", "application/json")
             .send()
             .await
-            .map_err(|_| SwapError)?
+            .map_err(|_| SwapError::QuoteError)?
             .json::<QuoteResponse>()
             .await
-            .map_err(|_| SwapError)?;
+            .map_err(|_| SwapError::QuoteError)?;
 
-        let total = Felt::from_hex(&response.total).map_err(|_| SwapError)?;
+        let calls = router_calls(&quote, args.a, args.b)?;
+        let report = simulator.simulate(&calls).await?;
+        Ok(TransactionOutcome::Simulated(report))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_router_calls_follow_the_quote() {
+        let quote: QuoteResponse = serde_json::from_value(json!({
+            "total": "2500",
+            "splits": [{
+                "amount": "-1000000000",
+                "specified_amount": "-1000000000",
+                "route": [{
+                    "pool_key": {
+                        "token0": "0x1",
+                        "token1": "0x2",
+                        "fee": "0x20c49ba5e353f80000000000000000",
+                        "tick_spacing": 1000,
+                        "extension": "0x0"
+                    },
+                    "sqrt_ratio_limit": "0x100000000000000000000000000000000",
+                    "skip_ahead": 0
+                }]
+            }]
+        }))
+        .unwrap();
+
+        let calls = router_calls(&quote, Felt::ONE, Felt::TWO).unwrap();
+        assert_eq!(calls.len(), 4);
+        assert_eq!(calls[0].to, Felt::TWO);
+        assert_eq!(
+            calls[0].calldata,
+            vec![EKUBO_ROUTER, Felt::from(2500u32), Felt::ZERO]
+        );
 
-        Ok(total)
+        let swaps = &calls[1].calldata;
+        assert_eq!(swaps[..2], [Felt::ONE, Felt::ONE]);
+        // sqrt_ratio_limit is 2^128, so only the high word is set
+        assert_eq!(swaps[7..9], [Felt::ZERO, Felt::ONE]);
+        assert_eq!(
+            swaps[10..],
+            [Felt::ONE, Felt::from(1_000_000_000u32), Felt::ONE]
+        );
+        assert_eq!(calls[3].calldata, vec![Felt::TWO]);
     }
 }
@@ -1,9 +1,14 @@
 use rig::{completion::ToolDefinition, tool::Tool};
 use serde::Deserialize;
 use serde_json::json;
-use starknet::core::types::Felt;
+use starknet::core::{
+    types::{Call, Felt},
+    utils::get_selector_from_name,
+};
 use tokio_rusqlite::Connection;
 
+use crate::simulator::{default_dry_run, SimulationError, Simulator, TransactionOutcome};
+
 pub const INIT_SQL: &str = "
 BEGIN;
 -- Account table
@@ -30,6 +35,8 @@ pub struct TransferArgs {
     recipient: String,
     amount: Felt,
     token: String, // Changed to String to accept name/symbol
+    #[serde(default = "default_dry_run")]
+    dry_run: bool,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -40,15 +47,30 @@ pub enum TransferError {
     InvalidRecipient,
     #[error("Database error: {0}")]
     DatabaseError(#[from] tokio_rusqlite::Error),
+    #[error("Submitting transactions is not supported yet, only dry runs are")]
+    ExecutionUnsupported,
+    #[error("Dry run requested but no simulator is configured")]
+    NoSimulator,
+    #[error("Simulation failed: {0}")]
+    SimulationError(#[from] SimulationError),
 }
 
 pub struct Transfer {
     conn: Connection,
+    simulator: Option<Simulator>,
 }
 
 impl Transfer {
     pub fn new(conn: Connection) -> Self {
-        Self { conn }
+        Self {
+            conn,
+            simulator: None,
+        }
+    }
+
+    pub fn with_simulator(mut self, simulator: Simulator) -> Self {
+        self.simulator = Some(simulator);
+        self
     }
 
     async fn lookup_token(&self, token: &str) -> Result<Felt, TransferError> {
@@ -116,12 +138,12 @@ impl Tool for Transfer {
 
     type Error = TransferError;
     type Args = TransferArgs;
-    type Output = Felt;
+    type Output = TransactionOutcome;
 
     async fn definition(&self, _prompt: String) -> ToolDefinition {
         ToolDefinition {
             name: "transfer".to_string(),
-            description: "Transfer tokens to a recipient".to_string(),
+            description: "Transfer tokens to a recipient. Only dry runs are supported for now, which report the fee and effects of the transfer.".to_string(),
             parameters: json!({
                 "type": "object",
                 "properties": {
@@ -136,6 +158,10 @@ impl Tool for Transfer {
                     "token": {
                         "type": "string",
                         "description": "The token name, symbol or contract address"
+                    },
+                    "dry_run": {
+                        "type": "boolean",
+                        "description": "Simulate the transfer without submitting it (default true)"
                     }
                 }
             }),
@@ -143,11 +169,21 @@ impl Tool for Transfer {
     }
 
     async fn call(&self, args: Self::Args) -> Result<Self::Output, Self::Error> {
+        if !args.dry_run {
+            return Err(TransferError::ExecutionUnsupported);
+        }
+        let simulator = self.simulator.as_ref().ok_or(TransferError::NoSimulator)?;
+
         let token_address = self.lookup_token(&args.token).await?;
         let recipient_address = self.lookup_recipient(&args.recipient).await?;
 
-        // Here we would implement the actual transfer logic
-        // For now just return a dummy transaction hash
-        Ok(Felt::ZERO)
+        let call = Call {
+            to: token_address,
+            selector: get_selector_from_name("transfer").unwrap(),
+            // u256 amount as (low, high)
+            calldata: vec![recipient_address, args.amount, Felt::ZERO],
+        };
+        let report = simulator.simulate(&[call]).await?;
+        Ok(TransactionOutcome::Simulated(report))
     }
 }
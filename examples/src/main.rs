@@ -1,17 +1,47 @@
 use asuka_core::attention::{Attention, AttentionConfig};
-use asuka_core::knowledge::Document;
-use clap::{command, Parser};
-use rig::providers::{self, openai};
+use asuka_core::capabilities::Capabilities;
+use asuka_core::config::{Component, ConfigFile, Credentials};
+use asuka_core::deployment::{CharacterSource, EnvConfig};
+use asuka_core::knowledge::{
+    Cursor, DocumentFilter, EmbeddingService, ErasureOptions, MaintenanceOptions, Source,
+    VacuumMode,
+};
+use asuka_core::providers::boxed::BoxEmbeddingModel;
+use asuka_core::providers::ollama_embeddings::{OllamaConfig, OllamaEmbeddingModel};
+use asuka_core::providers::OpenAiClient;
+use clap::{command, Parser, Subcommand, ValueEnum};
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use rig::providers::openai;
 
 use asuka_core::character;
-use asuka_core::init_logging;
+use asuka_core::logging::{init_logging_with_reporter, LoggingConfig};
+use asuka_core::reporting::{ErrorReporter, ReportSink, WebhookReport};
+use asuka_core::memory::Memory;
+use asuka_core::injection::ModelClassifier;
+use asuka_core::rewrite::ModelRewriter;
+use asuka_core::attachments::CodeAttachments;
+use asuka_core::hooks::MarkdownGuardrails;
+use asuka_core::retention::RetentionJob;
+use asuka_core::digest::{DigestJob, DigestSink, LogDigest};
+use asuka_core::events::EventBus;
+use asuka_core::webhooks::WebhookDispatcher;
 use asuka_core::knowledge::KnowledgeBase;
-use asuka_core::loaders::github::GitLoader;
-use asuka_core::{agent::Agent, clients::discord::DiscordClient};
+use asuka_core::knowledge::{RefreshRegistry, RetryPolicy, ServiceLockConfig, SnapshotError};
+use asuka_core::sources::{KnowledgeSourceConfig, SourceManager};
+use asuka_core::startup::{self, ProgressSink, Readiness};
+use asuka_core::status::StatusBoard;
+use asuka_core::outage::OutageNotices;
+use asuka_core::{agent::Agent, clients::discord::{ChannelProgress, ChannelReport, DiscordClient, DmDigest}};
+use asuka_core::clients::telegram::{ChatDigest, ChatReport};
+use tokio::signal::unix::{signal, SignalKind};
 use sqlite_vec::sqlite3_vec_init;
 use tokio_rusqlite::ffi::sqlite3_auto_extension;
 use tokio_rusqlite::Connection;
 
+const SHARED_NAMESPACE: &str = "common";
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
@@ -19,47 +49,242 @@ struct Args {
     #[arg(long, default_value = "examples/src/characters/shinobi.toml")]
     character: String,
 
-    /// Path to database
-    #[arg(long, default_value = ":memory:")]
-    db_path: String,
+    /// Path to database, in memory by default and `/data/asuka.db` with
+    /// `--env-only`
+    #[arg(long)]
+    db_path: Option<String>,
+
+    /// Read the config file, character and database path from `ASUKA_*`
+    /// environment variables instead of files, as in a container. See
+    /// `asuka_core::deployment`.
+    #[arg(long, env = "ASUKA_ENV_ONLY", conflicts_with = "config")]
+    env_only: bool,
+
+    /// Print the resolved configuration, secrets redacted, and exit
+    #[arg(long, requires = "env_only")]
+    print_config: bool,
+
+    /// TOML file with credentials, attention and Discord settings.
+    /// Environment variables override its credentials and command line flags
+    /// override both. Settings are re-read on SIGHUP or `/reload-config`.
+    #[arg(long)]
+    config: Option<PathBuf>,
 
     /// Discord API token (can also be set via DISCORD_API_TOKEN env var)
-    #[arg(long, env)]
-    discord_api_token: String,
+    #[arg(long)]
+    discord_api_token: Option<String>,
 
-    /// XAI API token (can also be set via XAI_API_KEY env var)
-    #[arg(long, env = "XAI_API_KEY")]
-    xai_api_key: String,
+    /// Optional second character sharing the database and docs
+    #[arg(long)]
+    companion_character: Option<String>,
+
+    /// Discord API token of the second character
+    #[arg(long, env, requires = "companion_character")]
+    companion_discord_api_token: Option<String>,
+
+    /// Comma separated Discord user ids allowed to run admin commands
+    #[arg(long, env, value_delimiter = ',')]
+    discord_admins: Vec<String>,
 
     /// OpenAI API token (can also be set via OPENAI_API_KEY env var)
-    #[arg(long, env = "OPENAI_API_KEY")]
-    openai_api_key: String,
+    #[arg(long)]
+    openai_api_key: Option<String>,
 
-    /// GitHub repository URL
+    /// GitHub repository URL, when the config file lists no `[knowledge]` repos
     #[arg(long, default_value = "https://github.com/cartridge-gg/docs")]
     github_repo: String,
 
-    /// Local path to clone GitHub repository
+    /// Local path to clone GitHub repositories
     #[arg(long, default_value = ".repo")]
     github_path: String,
+
+    /// URL of a published docs snapshot to start from instead of embedding
+    /// every document
+    #[arg(long, env, requires = "bootstrap_checksum")]
+    bootstrap_url: Option<String>,
+
+    /// SHA-256 of the snapshot at `--bootstrap-url`
+    #[arg(long, env)]
+    bootstrap_checksum: Option<String>,
+
+    /// Start even though another instance is running on the database. It
+    /// stops at its next heartbeat.
+    #[arg(long)]
+    force_takeover: bool,
+
+    /// Embed with this model on a local Ollama server instead of OpenAI,
+    /// e.g. `nomic-embed-text`
+    #[arg(long, env)]
+    ollama_model: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Clean up the database at `--db-path` and exit instead of starting the
+    /// bots. Safe to run while they are live.
+    Maintenance {
+        /// Only report what would be fixed
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Embed documents and messages stored without an embedding
+        #[arg(long)]
+        reembed: bool,
+
+        #[arg(long, value_enum, default_value_t = Vacuum::Incremental)]
+        vacuum: Vacuum,
+    },
+    /// Inspect and manage the documents in the database at `--db-path` and
+    /// exit. Safe to run while the bots are live.
+    Knowledge {
+        /// Namespace holding the documents
+        #[arg(long, default_value = SHARED_NAMESPACE)]
+        namespace: String,
+
+        #[command(subcommand)]
+        command: KnowledgeCommand,
+    },
+    /// Erase what is stored about a user in the database at `--db-path` and
+    /// exit. Safe to run while the bots are live.
+    EraseUser {
+        /// The user's id on the platform
+        user_id: String,
+
+        /// Platform of the user, e.g. `telegram`
+        #[arg(long, default_value = "discord")]
+        source: String,
+
+        /// Namespace of the conversations, the character's name by default
+        #[arg(long)]
+        namespace: Option<String>,
+
+        /// Don't ask for confirmation
+        #[arg(long, short)]
+        yes: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum KnowledgeCommand {
+    /// List documents, oldest first
+    List {
+        /// Only documents from this source, e.g. `github`
+        #[arg(long)]
+        source: Option<String>,
+
+        #[arg(long, default_value_t = 50)]
+        limit: usize,
+
+        /// Cursor printed at the end of the previous page
+        #[arg(long)]
+        cursor: Option<String>,
+    },
+    /// Print a document with its metadata
+    Show { id: String },
+    /// Delete one document, or every document matching the filters
+    Delete {
+        #[arg(required_unless_present_any = ["source", "older_than"])]
+        id: Option<String>,
+
+        #[arg(long, conflicts_with = "id")]
+        source: Option<String>,
+
+        /// Only documents older than this age, e.g. `30d`, `12h` or `45m`
+        #[arg(long, conflicts_with = "id", value_parser = parse_age)]
+        older_than: Option<chrono::Duration>,
+
+        /// Only list what would be deleted
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Don't ask for confirmation
+        #[arg(long, short)]
+        yes: bool,
+    },
+    /// Embed documents again, replacing their embeddings
+    Reembed {
+        #[arg(required_unless_present = "all")]
+        id: Option<String>,
+
+        #[arg(long, conflicts_with = "id")]
+        all: bool,
+    },
+    /// Print the documents closest to a query with their distances
+    Search {
+        query: String,
+
+        #[arg(short, default_value_t = 5)]
+        k: usize,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Vacuum {
+    None,
+    Incremental,
+    Full,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    init_logging();
     dotenv::dotenv().ok();
+    let reporter = ErrorReporter::new();
+    init_logging_with_reporter(LoggingConfig::from_env()?, &reporter)?;
 
     let args = Args::parse();
 
-    let repo = GitLoader::new(args.github_repo, &args.github_path)?;
-
-    let character_content =
-        std::fs::read_to_string(&args.character).expect("Failed to read character file");
-    let character: character::Character =
-        toml::from_str(&character_content).expect("Failed to parse character TOML");
+    let components: &[Component] = match args.command {
+        Some(_) => &[Component::OpenAi],
+        None => &[Component::Discord, Component::OpenAi],
+    };
+    let flags = Credentials {
+        discord_api_token: args.discord_api_token,
+        openai_api_key: args.openai_api_key,
+        ..Default::default()
+    };
+    let (file, credentials, character, db_path) = if args.env_only {
+        let mut env = EnvConfig::from_env()?;
+        env.credentials = env.credentials.merge(flags);
+        if args.print_config {
+            println!("{}", env.redacted());
+            return Ok(());
+        }
+        // Every missing variable at once, before anything starts
+        env.require(components)?;
+        let character = env
+            .character
+            .clone()
+            .unwrap_or(CharacterSource::Path(args.character.clone().into()))
+            .load()
+            .await?;
+        let db_path = args.db_path.unwrap_or(env.db_path);
+        (env.file, env.credentials, character, db_path)
+    } else {
+        let file = match &args.config {
+            Some(path) => ConfigFile::from_file(path)?,
+            None => ConfigFile::default(),
+        };
+        let credentials = Credentials::load(args.config.as_deref(), flags)?;
+        credentials.require(components)?;
+        let db_path = args.db_path.unwrap_or(":memory:".to_string());
+        (file, credentials, load_character(&args.character), db_path)
+    };
+    let openai_api_key = credentials.openai_api_key.unwrap();
 
-    let oai = providers::openai::Client::new(&args.openai_api_key);
-    let embedding_model = oai.embedding_model(openai::TEXT_EMBEDDING_3_SMALL);
+    let oai = OpenAiClient::new(&openai_api_key, &file.openai)?;
+    // Picked at runtime, so boxed to give the knowledge base one type
+    let embedding_model: BoxEmbeddingModel = match &args.ollama_model {
+        Some(model) => OllamaEmbeddingModel::new(&OllamaConfig {
+            model: model.clone(),
+            ..Default::default()
+        })
+        .await?
+        .into(),
+        None => oai.embedding_model(openai::TEXT_EMBEDDING_3_SMALL).into(),
+    };
     let completion_model = oai.completion_model(openai::GPT_4O);
     let should_respond_completion_model = oai.completion_model(openai::GPT_35_TURBO_0125);
 
@@ -69,34 +294,424 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         sqlite3_auto_extension(Some(std::mem::transmute(sqlite3_vec_init as *const ())));
     }
 
-    let conn = Connection::open(args.db_path).await?;
-    let mut knowledge = KnowledgeBase::new(conn.clone(), embedding_model).await?;
-
-    knowledge
-        .add_documents(
-            repo.with_dir("src/pages/vrf")?
-                .read_with_path()
-                .ignore_errors()
-                .into_iter()
-                .map(|(path, content)| Document {
-                    id: path.to_string_lossy().to_string(),
-                    source_id: "github".to_string(),
-                    content,
-                    created_at: chrono::Utc::now(),
-                }),
-        )
+    // Where warming and a failing embedding provider are published, for the
+    // bot's presence
+    let status = StatusBoard::default();
+    // Escalations, low confidence answers and the like, for the webhooks
+    let events = EventBus::default();
+    let conn = Connection::open(db_path).await?;
+    let knowledge = KnowledgeBase::with_embedding_service(
+        conn.clone(),
+        EmbeddingService::new(embedding_model).with_status(status.clone()),
+    )
+    .await?
+    .with_events(events.clone());
+    let knowledge = match &file.embedding_policy {
+        Some(policy) => knowledge.with_embedding_policy(policy.compile()?),
+        None => knowledge,
+    };
+    let knowledge = match &file.chunking {
+        Some(config) => knowledge.with_chunking(config.clone()),
+        None => knowledge,
+    };
+
+    if let Some(Command::Maintenance {
+        dry_run,
+        reembed,
+        vacuum,
+    }) = args.command
+    {
+        let options = MaintenanceOptions {
+            dry_run,
+            reembed_missing: reembed,
+            vacuum: match vacuum {
+                Vacuum::None => VacuumMode::None,
+                Vacuum::Incremental => VacuumMode::Incremental,
+                Vacuum::Full => VacuumMode::Full,
+            },
+            ..Default::default()
+        };
+        println!("{}", knowledge.maintenance(&options).await?);
+        return Ok(());
+    }
+
+    if let Some(Command::Knowledge { namespace, command }) = args.command {
+        let knowledge = knowledge.with_namespace(namespace);
+        return run_knowledge_command(&knowledge, command).await;
+    }
+
+    if let Some(Command::EraseUser {
+        user_id,
+        source,
+        namespace,
+        yes,
+    }) = args.command
+    {
+        let source = Source::from_str(&source).ok_or(format!("unknown source {source}"))?;
+        let knowledge = knowledge.with_namespace(namespace.unwrap_or(character.name));
+        let preview = ErasureOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let report = knowledge.erase_user(&source, &user_id, &preview).await?;
+        println!("{report}");
+        if report.total() > 0 && (yes || confirm(&format!("Erase user {user_id}?"))?) {
+            let options = ErasureOptions::default();
+            println!("{}", knowledge.erase_user(&source, &user_id, &options).await?);
+        }
+        return Ok(());
+    }
+
+    // A second instance on the database would answer every message again
+    let lock = knowledge
+        .acquire_service_lock(&ServiceLockConfig::default(), args.force_takeover)
         .await?;
+    lock.spawn_heartbeat();
 
-    let agent = Agent::new(character, completion_model, knowledge);
+    let discord_api_token = credentials.discord_api_token.unwrap();
+    if let Some(config) = &file.reporting {
+        let sink: Arc<dyn ReportSink> = match (
+            &config.discord_channel,
+            config.telegram_chat,
+            &credentials.telegram_bot_token,
+            &config.webhook,
+        ) {
+            (Some(channel), _, _, _) => {
+                Arc::new(ChannelReport::new(&discord_api_token, channel.parse()?))
+            }
+            (None, Some(chat), Some(telegram), _) => Arc::new(ChatReport::new(telegram, chat)),
+            (_, _, _, Some(url)) => Arc::new(WebhookReport::new(url)),
+            _ => return Err("reporting: telegram_chat needs a Telegram bot token".into()),
+        };
+        reporter.publish_to(events.clone());
+        reporter.start(config.clone(), sink);
+    }
+    if !file.webhooks.is_empty() {
+        events.watch_status(&status);
+        WebhookDispatcher::new(knowledge.clone(), file.webhooks.clone()).spawn();
+    }
+    let repos = file.knowledge.clone().unwrap_or_else(|| {
+        KnowledgeSourceConfig::single("github", &args.github_repo, "src/pages/vrf")
+    });
 
-    let config = AttentionConfig {
-        bot_names: vec![agent.character.name.clone()],
-        ..Default::default()
+    // Docs are ingested once into a namespace every character retrieves from,
+    // while each character keeps its own conversations.
+    let docs = knowledge.clone().with_namespace(SHARED_NAMESPACE);
+    let sources = SourceManager::new(docs.clone(), &repos, &args.github_path);
+    let refresh = sources.register(RefreshRegistry::new(docs.clone()));
+    // Start from the snapshot and only embed what changed since.
+    if let (Some(url), Some(checksum)) = (&args.bootstrap_url, &args.bootstrap_checksum) {
+        match docs.bootstrap_from_url(url, checksum, false).await {
+            Ok(_) | Err(SnapshotError::NotEmpty(_)) => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    // Clients connect while the repositories are ingested, and tell users to
+    // come back until they are.
+    let readiness = Readiness::warming().with_status(status.clone());
+    let progress: Option<Arc<dyn ProgressSink>> = match &file.startup.discord_channel {
+        Some(channel) => Some(Arc::new(ChannelProgress::new(
+            &discord_api_token,
+            channel.parse()?,
+        ))),
+        None => None,
     };
-    let attention = Attention::new(config, should_respond_completion_model);
+    tokio::spawn({
+        let readiness = readiness.clone();
+        let config = file.startup.clone();
+        async move {
+            let results = startup::warm_up(&readiness, &config, progress, |hook| {
+                sources.sync_all_with_progress(hook)
+            })
+            .await;
+            for (name, result) in results {
+                if let Err(err) = result {
+                    eprintln!("Knowledge source {name} not synced: {err}");
+                }
+            }
+            sources.spawn();
+        }
+    });
+
+    let mut characters = vec![(character, discord_api_token)];
+    if let (Some(path), Some(token)) = (&args.companion_character, args.companion_discord_api_token)
+    {
+        characters.push((load_character(path), token));
+    }
 
-    let discord = DiscordClient::new(agent, attention);
-    discord.start(&args.discord_api_token).await?;
+    let mut bots = tokio::task::JoinSet::new();
+    let mut clients = Vec::new();
+    for (character, token) in characters {
+        let knowledge = knowledge
+            .clone()
+            .with_namespace(character.name.clone())
+            .with_shared_namespace(SHARED_NAMESPACE);
+        knowledge.spawn_pending_retries(RetryPolicy::default());
+        let mut agent = Agent::new(character, completion_model.clone(), knowledge)
+            .with_tool_config(file.tools.clone())
+            .with_history(file.history.clone())
+            .with_experiments(file.experiments.clone())
+            .with_locale(file.locale.clone())
+            .with_response_hook(MarkdownGuardrails::new(file.markdown.clone()))
+            .with_readiness(readiness.clone());
+        if let Some(config) = &file.attachments {
+            agent = agent.with_response_hook(CodeAttachments::new(config.clone()));
+        }
+        if let Some(config) = &file.confidence {
+            agent = agent.with_confidence(config.clone());
+        }
+        if let Some(config) = &file.localization {
+            agent = agent.with_localization(config.clone());
+        }
+        if let Some(config) = &file.diversity {
+            agent = agent.with_diversity(config.clone());
+        }
+        if let Some(config) = &file.truncation {
+            agent = agent.with_truncation(config.clone());
+        }
+        if let Some(config) = &file.capabilities {
+            agent = agent.with_capabilities(config.clone(), Capabilities::default());
+            // Documents are counted once the sources are ingested, and again
+            // on each config reload
+            tokio::spawn({
+                let agent = agent.clone();
+                let readiness = readiness.clone();
+                async move {
+                    readiness.wait_ready().await;
+                    if let Err(err) = agent.refresh_capabilities().await {
+                        eprintln!("Failed to count documents: {err}");
+                    }
+                }
+            });
+        }
+        if let Some(config) = &file.injection {
+            agent = agent.with_injection(config.clone());
+            if config.classifier {
+                agent = agent.with_injection_classifier(Arc::new(ModelClassifier::new(
+                    should_respond_completion_model.clone(),
+                )));
+            }
+        }
+        if let Some(config) = &file.query_rewrite {
+            agent = agent
+                .with_query_rewrite(config.clone())
+                .with_query_rewriter(Arc::new(ModelRewriter::new(
+                    should_respond_completion_model.clone(),
+                )));
+        }
+        if let Some(config) = &file.memory {
+            agent = agent.with_memory(config.clone());
+            Memory::new(agent.clone(), config.clone()).spawn();
+        }
+        if let Some(config) = &file.digest {
+            let sink: Arc<dyn DigestSink> = match (
+                &config.discord_owner,
+                config.telegram_chat,
+                &credentials.telegram_bot_token,
+            ) {
+                (Some(owner), _, _) => Arc::new(DmDigest::new(&token, owner.parse()?)),
+                (None, Some(chat), Some(telegram)) => Arc::new(ChatDigest::new(telegram, chat)),
+                _ => Arc::new(LogDigest),
+            };
+            DigestJob::new(agent.clone(), config.clone(), sink).spawn();
+        }
+        if let Some(config) = &file.retention {
+            RetentionJob::new(agent.knowledge().clone(), config.clone()).spawn();
+        }
 
+        let config = AttentionConfig {
+            bot_names: agent.character.names(),
+            ..file.attention.clone()
+        };
+        let attention = Attention::new(config, should_respond_completion_model.clone());
+
+        let mut discord = DiscordClient::new(agent, attention)
+            .with_admins(args.discord_admins.clone())
+            .with_config(file.discord.clone())
+            .with_refresh_registry(refresh.clone());
+        if let Some(path) = &args.config {
+            discord = discord.with_config_path(path);
+        }
+        if let Some(config) = &file.escalation {
+            discord = discord.with_escalation(config.clone());
+        }
+        if let Some(config) = &file.rate_limit {
+            discord = discord.with_rate_limit(config.clone());
+        }
+        if let Some(config) = &file.linking {
+            discord = discord.with_linking(config.clone());
+        }
+        if let Some(config) = &file.research {
+            discord = discord.with_research(config.clone());
+        }
+        if let Some(config) = &file.presence {
+            discord = discord.with_presence(config.clone(), status.clone());
+        }
+        if let Some(config) = &file.outage {
+            discord = discord.with_outage_notices(OutageNotices::new(config.clone(), status.clone()));
+        }
+        if let Some(config) = &file.batching {
+            discord = discord.with_batching(config.clone());
+        }
+        clients.push(discord.clone());
+        bots.spawn(async move { discord.start(&token).await });
+    }
+
+    if let Some(path) = args.config {
+        let mut hangup = signal(SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                for client in &clients {
+                    if let Err(err) = client.reload_config(&path) {
+                        eprintln!("Config not reloaded: {err}");
+                    }
+                }
+            }
+        });
+    }
+
+    let lost = lock.lost();
+    let result = tokio::select! {
+        result = async {
+            while let Some(result) = bots.join_next().await {
+                result??;
+            }
+            Ok::<_, Box<dyn std::error::Error>>(())
+        } => result,
+        _ = lost.cancelled() => Err("Another instance took over the database".into()),
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    };
+    lock.release().await?;
+    result
+}
+
+async fn run_knowledge_command<E: rig::embeddings::EmbeddingModel>(
+    knowledge: &KnowledgeBase<E>,
+    command: KnowledgeCommand,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        KnowledgeCommand::List {
+            source,
+            limit,
+            cursor,
+        } => {
+            let cursor = cursor.as_deref().map(Cursor::decode).transpose()?;
+            let page = match &source {
+                Some(source) => knowledge.list_documents_from(source, cursor, limit).await?,
+                None => knowledge.list_documents(cursor, limit).await?,
+            };
+            println!("{:<40} {:<12} {:<25} CONTENT", "ID", "SOURCE", "CREATED");
+            for document in &page.items {
+                let preview = document.content.lines().next().unwrap_or_default();
+                println!(
+                    "{:<40} {:<12} {:<25} {}",
+                    document.id,
+                    document.source_id,
+                    document.created_at.format("%Y-%m-%d %H:%M:%S"),
+                    preview.chars().take(60).collect::<String>()
+                );
+            }
+            if let (true, Some(cursor)) = (page.has_more, page.next_cursor) {
+                println!("More documents: --cursor {}", cursor.encode());
+            }
+        }
+        KnowledgeCommand::Show { id } => {
+            let Some(details) = knowledge.document_details(&id).await? else {
+                return Err(format!("No document {id}").into());
+            };
+            let document = details.document;
+            println!("id: {}", document.id);
+            println!("source: {}", document.source_id);
+            println!("created: {}", document.created_at.to_rfc3339());
+            println!("logical id: {}", details.logical_id);
+            println!("superseded: {}", details.superseded);
+            println!("embedded: {}", details.has_embedding);
+            println!("topics: {}", document.topics.join(", "));
+            println!("\n{}", document.content);
+        }
+        KnowledgeCommand::Delete {
+            id,
+            source,
+            older_than,
+            dry_run,
+            yes,
+        } => {
+            let ids = match id {
+                Some(id) => vec![id],
+                None => {
+                    let filter = DocumentFilter {
+                        source_id: source,
+                        created_before: older_than.map(|age| chrono::Utc::now() - age),
+                    };
+                    knowledge.matching_documents(&filter).await?
+                }
+            };
+            for id in &ids {
+                println!("{id}");
+            }
+            if dry_run {
+                println!("Dry run, {} documents would be deleted.", ids.len());
+                return Ok(());
+            }
+            if ids.is_empty() || !(yes || confirm(&format!("Delete {} documents?", ids.len()))?) {
+                return Ok(());
+            }
+            let deleted = knowledge.delete_documents(&ids).await?;
+            println!("Deleted {deleted} documents.");
+        }
+        KnowledgeCommand::Reembed { id, .. } => {
+            let ids = match id {
+                Some(id) => vec![id],
+                None => {
+                    let all = DocumentFilter::default();
+                    knowledge.matching_documents(&all).await?
+                }
+            };
+            let reembedded = knowledge.reembed_documents(&ids).await?;
+            println!("Re-embedded {reembedded} documents.");
+        }
+        KnowledgeCommand::Search { query, k } => {
+            for (distance, document) in knowledge.search_documents(&query, k).await? {
+                let preview = document.content.lines().next().unwrap_or_default();
+                println!(
+                    "{distance:.4}  {:<40} {}",
+                    document.id,
+                    preview.chars().take(60).collect::<String>()
+                );
+            }
+        }
+    }
     Ok(())
 }
+
+fn confirm(prompt: &str) -> std::io::Result<bool> {
+    print!("{prompt} [y/N] ");
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Parses ages like `30d`, `12h` or `45m`.
+fn parse_age(age: &str) -> Result<chrono::Duration, String> {
+    let (value, unit) = age.split_at(age.len().saturating_sub(1));
+    let value: i64 = value
+        .parse()
+        .map_err(|_| format!("invalid age {age}, expected e.g. 30d"))?;
+    match unit {
+        "d" => Ok(chrono::Duration::days(value)),
+        "h" => Ok(chrono::Duration::hours(value)),
+        "m" => Ok(chrono::Duration::minutes(value)),
+        _ => Err(format!("invalid age unit in {age}, expected d, h or m")),
+    }
+}
+
+fn load_character(path: &str) -> character::Character {
+    let content = std::fs::read_to_string(path).expect("Failed to read character file");
+    let character: character::Character =
+        toml::from_str(&content).expect("Failed to parse character TOML");
+    if let Err(err) = character.validate() {
+        panic!("Invalid character {path}: {err}");
+    }
+    character
+}